@@ -0,0 +1,177 @@
+//! Derive macro for `pgx_contrib_spiext::row::FromSpiRow`
+//!
+//! Use it through `pgx-contrib-spiext` with the `derive` feature, which documents it.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Field, Fields, GenericArgument, Lit, Meta, NestedMeta,
+    PathArguments, Type,
+};
+
+#[proc_macro_derive(FromSpiRow, attributes(spiext))]
+pub fn derive_from_spi_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+const COLUMN_EXPECTED: &str = "expected a string literal, such as `column = \"name\"`";
+const UNKNOWN_ATTRIBUTE: &str =
+    "unknown `spiext` attribute, expected `column = \"...\"`, `default` or `flatten`";
+
+/// Attributes of a field
+#[derive(Default)]
+struct FieldAttrs {
+    column: Option<String>,
+    default: bool,
+    flatten: bool,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(not_named(input)),
+        },
+        _ => return Err(not_named(input)),
+    };
+    let name = &input.ident;
+    let type_name = name.to_string();
+    // Report the errors of every field at once
+    let mut errors: Option<syn::Error> = None;
+    let mut inits = vec![];
+    for field in fields {
+        match field_init(&type_name, field) {
+            Ok(init) => inits.push(init),
+            Err(err) => match &mut errors {
+                Some(errors) => errors.combine(err),
+                None => errors = Some(err),
+            },
+        }
+    }
+    if let Some(errors) = errors {
+        return Err(errors);
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::pgx_contrib_spiext::row::FromSpiRow for #name #ty_generics
+            #where_clause
+        {
+            fn from_spi_row(
+                row: &::pgx_contrib_spiext::row::SpiRow,
+            ) -> ::core::result::Result<Self, ::pgx_contrib_spiext::row::RowConversionError> {
+                ::core::result::Result::Ok(Self { #(#inits,)* })
+            }
+        }
+    })
+}
+
+fn not_named(input: &DeriveInput) -> syn::Error {
+    syn::Error::new_spanned(
+        &input.ident,
+        "`FromSpiRow` can only be derived for structs with named fields",
+    )
+}
+
+/// Initializer of a field in the struct expression
+fn field_init(type_name: &str, field: &Field) -> syn::Result<TokenStream2> {
+    let ident = field.ident.as_ref().unwrap();
+    let ty = &field.ty;
+    let attrs = field_attrs(field)?;
+    if attrs.flatten {
+        return Ok(quote! {
+            #ident: <#ty as ::pgx_contrib_spiext::row::FromSpiRow>::from_spi_row(row)?
+        });
+    }
+    let field_name = ident.to_string();
+    let field_name = field_name.strip_prefix("r#").unwrap_or(&field_name);
+    let column = attrs.column.as_deref().unwrap_or(field_name);
+    let get = match option_inner(ty) {
+        Some(inner) => {
+            quote! { row.derived_get_nullable::<#inner>(#type_name, #field_name, #column)? }
+        }
+        None => quote! { row.derived_get::<#ty>(#type_name, #field_name, #column)? },
+    };
+    Ok(if attrs.default {
+        quote! {
+            #ident: if row.has_column(#column) {
+                #get
+            } else {
+                ::core::default::Default::default()
+            }
+        }
+    } else {
+        quote! { #ident: #get }
+    })
+}
+
+fn field_attrs(field: &Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs::default();
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("spiext"))
+    {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => {
+                return Err(syn::Error::new_spanned(
+                    meta,
+                    "expected a list of attributes, such as `#[spiext(column = \"name\")]`",
+                ))
+            }
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("column") => {
+                    let column = match value.lit {
+                        Lit::Str(column) => column,
+                        lit => return Err(syn::Error::new_spanned(lit, COLUMN_EXPECTED)),
+                    };
+                    if attrs.column.is_some() {
+                        return Err(syn::Error::new_spanned(
+                            column,
+                            "duplicate `column` attribute",
+                        ));
+                    }
+                    attrs.column = Some(column.value());
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("default") => {
+                    attrs.default = true
+                }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("flatten") => {
+                    attrs.flatten = true
+                }
+                nested => return Err(syn::Error::new_spanned(nested, UNKNOWN_ATTRIBUTE)),
+            }
+        }
+    }
+    if attrs.flatten && (attrs.column.is_some() || attrs.default) {
+        return Err(syn::Error::new_spanned(
+            field.ident.as_ref().unwrap(),
+            "`flatten` can't be combined with `column` or `default`",
+        ));
+    }
+    Ok(attrs)
+}
+
+/// `T` if `ty` is `Option<T>`
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let segment = match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,13 @@
+use pgx_contrib_spiext_derive::FromSpiRow;
+
+struct Inner {
+    value: i32,
+}
+
+#[derive(FromSpiRow)]
+struct Row {
+    #[spiext(flatten, column = "inner")]
+    inner: Inner,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use pgx_contrib_spiext_derive::FromSpiRow;
+
+#[derive(FromSpiRow)]
+struct Row(i32, String);
+
+fn main() {}
@@ -0,0 +1,9 @@
+use pgx_contrib_spiext_derive::FromSpiRow;
+
+#[derive(FromSpiRow)]
+struct Row {
+    #[spiext(rename = "other")]
+    value: i32,
+}
+
+fn main() {}
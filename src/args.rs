@@ -0,0 +1,161 @@
+//! Array and composite command arguments
+//!
+//! Arguments are given to commands as their type and value. These helpers build them for arrays of any element type
+//! that pgx can convert (such as `bigint[]` to use with `= ANY($1)`), and for values of composite types.
+
+use pgx::{pg_sys, IntoDatum, PgBuiltInOids, PgOid};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::rewrite;
+
+/// Argument building error, returned before anything is executed with the argument
+#[derive(Debug)]
+pub enum ArgError {
+    /// There is no type with this name
+    UnknownType(String),
+    /// The type is not a composite type
+    NotComposite(String),
+    /// The number of fields differs from the number of attributes of the type
+    FieldCountMismatch {
+        type_name: String,
+        expected: usize,
+        given: usize,
+    },
+    /// The type of a field's value differs from the type of the attribute
+    FieldTypeMismatch {
+        type_name: String,
+        attribute: String,
+        expected: PgOid,
+        given: PgOid,
+    },
+    /// Looking the type up failed
+    Query(Error),
+}
+
+impl Display for ArgError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgError::UnknownType(name) => write!(f, "type \"{}\" does not exist", name),
+            ArgError::NotComposite(name) => write!(f, "type \"{}\" is not composite", name),
+            ArgError::FieldCountMismatch {
+                type_name,
+                expected,
+                given,
+            } => write!(
+                f,
+                "type \"{}\" has {} attributes, but {} fields were given",
+                type_name, expected, given
+            ),
+            ArgError::FieldTypeMismatch {
+                type_name,
+                attribute,
+                expected,
+                given,
+            } => write!(
+                f,
+                "attribute \"{}\" of type \"{}\" has type with OID {}, but a value of type with OID {} was given",
+                attribute,
+                type_name,
+                expected.value(),
+                given.value()
+            ),
+            ArgError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for ArgError {
+    fn from(err: Error) -> Self {
+        ArgError::Query(err)
+    }
+}
+
+/// Array argument holding `values`, with `None` elements (of `Option<T>` values) as NULL elements
+///
+/// The array has the array type of `T`, such as `bigint[]` for `i64`.
+pub fn array_arg<T: IntoDatum + Clone>(values: &[T]) -> (PgOid, Option<pg_sys::Datum>) {
+    (
+        PgOid::from(Vec::<T>::type_oid()),
+        values.to_vec().into_datum(),
+    )
+}
+
+/// Argument of the composite type named `type_name` (possibly schema-qualified), holding `fields` in the order of
+/// its attributes
+///
+/// The type is looked up in the catalog, and every field must have the type of its attribute.
+pub fn composite_arg(
+    type_name: &str,
+    fields: Vec<(PgOid, Option<pg_sys::Datum>)>,
+) -> Result<(PgOid, Option<pg_sys::Datum>), ArgError> {
+    let (type_oid, relation) = rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "SELECT to_regtype($1)::oid, (SELECT typrelid FROM pg_type WHERE oid = to_regtype($1))",
+            Some(1),
+            Some(vec![(PgBuiltInOids::TEXTOID.oid(), type_name.into_datum())]),
+        )
+    })?
+    .first()
+    .get_two::<pg_sys::Oid, pg_sys::Oid>();
+    let type_oid = type_oid.ok_or_else(|| ArgError::UnknownType(type_name.to_string()))?;
+    let relation = relation
+        .filter(|relation| *relation != pg_sys::InvalidOid)
+        .ok_or_else(|| ArgError::NotComposite(type_name.to_string()))?;
+    // Dropped attributes still take their place in the tuple, as NULLs
+    let attributes: Vec<(String, pg_sys::Oid, bool)> = rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "SELECT attname::text, atttypid, attisdropped FROM pg_attribute \
+             WHERE attrelid = $1 AND attnum > 0 ORDER BY attnum",
+            None,
+            Some(vec![(PgBuiltInOids::OIDOID.oid(), relation.into_datum())]),
+        )
+    })?
+    .map(|row| {
+        (
+            row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+            row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+            row.by_ordinal(3).unwrap().value().unwrap_or_default(),
+        )
+    })
+    .collect();
+
+    let expected = attributes.iter().filter(|(_, _, dropped)| !dropped).count();
+    if fields.len() != expected {
+        return Err(ArgError::FieldCountMismatch {
+            type_name: type_name.to_string(),
+            expected,
+            given: fields.len(),
+        });
+    }
+    let mut fields = fields.into_iter();
+    let mut values = Vec::with_capacity(attributes.len());
+    let mut nulls = Vec::with_capacity(attributes.len());
+    for (name, attribute_type, dropped) in attributes {
+        let (field_type, value) = if dropped {
+            (PgOid::from(attribute_type), None)
+        } else {
+            fields.next().unwrap()
+        };
+        if field_type.value() != attribute_type {
+            return Err(ArgError::FieldTypeMismatch {
+                type_name: type_name.to_string(),
+                attribute: name,
+                expected: PgOid::from(attribute_type),
+                given: field_type,
+            });
+        }
+        values.push(value.unwrap_or_else(|| pg_sys::Datum::from(0usize)));
+        nulls.push(value.is_none());
+    }
+    let datum = unsafe {
+        let tupdesc = pg_sys::lookup_rowtype_tupdesc_copy(type_oid, -1);
+        let tuple = pg_sys::heap_form_tuple(tupdesc, values.as_mut_ptr(), nulls.as_mut_ptr());
+        let datum = pg_sys::heap_copy_tuple_as_datum(tuple, tupdesc);
+        pg_sys::heap_freetuple(tuple);
+        pg_sys::FreeTupleDesc(tupdesc);
+        datum
+    };
+    Ok((PgOid::from(type_oid), Some(datum)))
+}
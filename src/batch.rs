@@ -0,0 +1,269 @@
+//! Executing a command for many items, a batch of items per sub-transaction
+//!
+//! How many items a batch has is decided by a [`BatchPolicy`], which [`purge`](crate::purge) uses for its batches
+//! too.
+
+use pgx::{pg_sys::Datum, PgOid, SpiClient};
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+use crate::bgworker;
+use crate::checked::*;
+use crate::error::Error;
+use crate::subtxn::*;
+use crate::validate;
+
+/// How many items (or rows) a batch has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchPolicy {
+    /// Always this many
+    Fixed(usize),
+    /// Starting at `min`, adjusted after every batch so that batches take about `target_batch_duration`: halved
+    /// (down to `min`) when a batch takes more than twice as long or fails, and increased by a quarter (up to `max`)
+    /// when it takes less than half as long
+    Adaptive {
+        target_batch_duration: Duration,
+        min: usize,
+        max: usize,
+    },
+}
+
+/// What happened to a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchOutcome {
+    /// The batch was committed, without the items that failed (with [`ErrorIsolation::PerRow`])
+    Committed { failed: usize },
+    /// The batch failed and was rolled back; its items are retried in smaller batches
+    RolledBack,
+}
+
+/// Size and timing of a batch, as reported after the batches ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchInfo {
+    pub size: usize,
+    pub duration: Duration,
+    pub outcome: BatchOutcome,
+}
+
+/// Batch sizes chosen by a [`BatchPolicy`]
+pub(crate) struct BatchSizer {
+    policy: BatchPolicy,
+    size: usize,
+}
+
+impl BatchSizer {
+    /// Panics if the policy allows empty batches, or its minimum is above its maximum
+    pub(crate) fn new(policy: BatchPolicy) -> Self {
+        let size = match policy {
+            BatchPolicy::Fixed(size) => size,
+            BatchPolicy::Adaptive { min, max, .. } => {
+                assert!(min <= max, "minimum batch size is above the maximum");
+                min
+            }
+        };
+        assert!(size > 0, "batch size must be positive");
+        Self { policy, size }
+    }
+
+    /// Size of the next batch
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether a failed batch can be retried with a smaller size
+    pub(crate) fn can_shrink(&self) -> bool {
+        matches!(self.policy, BatchPolicy::Adaptive { min, .. } if self.size > min)
+    }
+
+    /// Adjust the size after a batch that took `duration`, and `failed` or not
+    pub(crate) fn record(&mut self, duration: Duration, failed: bool) {
+        if let BatchPolicy::Adaptive {
+            target_batch_duration,
+            min,
+            max,
+        } = self.policy
+        {
+            if failed || duration > target_batch_duration * 2 {
+                self.size = (self.size / 2).max(min);
+            } else if duration < target_batch_duration / 2 {
+                self.size = (self.size + (self.size / 4).max(1)).min(max);
+            }
+        }
+    }
+}
+
+/// How [`checked_execute_many`] isolates errors
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorIsolation {
+    /// An item that fails rolls its whole batch back
+    ///
+    /// With [`BatchPolicy::Adaptive`], the batch's items are retried in smaller batches until the batch size is at
+    /// its minimum, after which a failure is returned. With [`BatchPolicy::Fixed`], it is returned right away.
+    #[default]
+    PerBatch,
+    /// Every item executes in a sub-transaction of its own, so an item that fails is reported and skipped, and the
+    /// rest of its batch is committed
+    PerRow,
+}
+
+/// Report of [`checked_execute_many`]
+#[derive(Debug, Default)]
+pub struct ExecuteManyReport {
+    /// Items whose command was executed and committed
+    pub executed: u64,
+    /// Items that failed and were skipped (with [`ErrorIsolation::PerRow`]), by index
+    pub failed: Vec<(usize, Error)>,
+    /// Every batch, in order
+    pub batches: Vec<BatchInfo>,
+}
+
+/// Error returned by [`checked_execute_many`]
+#[derive(Debug)]
+pub enum ExecuteManyError {
+    /// Item `index` failed; the batches committed before it, as described by `report`, are kept
+    Failed {
+        index: usize,
+        error: Error,
+        report: ExecuteManyReport,
+    },
+    /// The query was rejected before executing anything
+    Query(Error),
+    /// The background worker was asked to shut down (see [`with_yield_points`](bgworker::with_yield_points)); the
+    /// batches committed before, as described by `report`, are kept
+    ShutdownRequested { report: ExecuteManyReport },
+}
+
+impl Display for ExecuteManyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecuteManyError::Failed { index, error, .. } => {
+                write!(f, "item {} failed: {}", index, error)
+            }
+            ExecuteManyError::Query(err) => write!(f, "{}", err),
+            ExecuteManyError::ShutdownRequested { .. } => {
+                write!(f, "{}", bgworker::ShutdownRequested)
+            }
+        }
+    }
+}
+
+impl From<Error> for ExecuteManyError {
+    fn from(err: Error) -> Self {
+        ExecuteManyError::Query(err)
+    }
+}
+
+/// Execute a mutable command once for every item of `items` (its arguments), in batches chosen by `policy`
+///
+/// Every batch executes in a sub-transaction of its own that is committed right away, so a failure keeps the
+/// batches committed before it. They are only made durable when the enclosing transaction commits, though.
+///
+/// Items with more than [`MAX_PARAMETERS`](validate::MAX_PARAMETERS) arguments are rejected with
+/// [`ExecuteManyError::Query`] before anything is executed.
+///
+/// Panics if `policy` allows empty batches.
+pub fn checked_execute_many(
+    client: &mut SpiClient,
+    query: &str,
+    items: &[Vec<(PgOid, Option<Datum>)>],
+    policy: BatchPolicy,
+    isolation: ErrorIsolation,
+) -> Result<ExecuteManyReport, ExecuteManyError> {
+    check_entry(query)?;
+    for args in items {
+        validate::param_count(args.len()).map_err(Error::from)?;
+    }
+    let mut sizer = BatchSizer::new(policy);
+    let mut report = ExecuteManyReport::default();
+    let mut next = 0;
+    while next < items.len() {
+        if bgworker::batch_yield_point().is_err() {
+            return Err(ExecuteManyError::ShutdownRequested { report });
+        }
+        let batch = &items[next..(next + sizer.size()).min(items.len())];
+        let started = Instant::now();
+        let result = match isolation {
+            ErrorIsolation::PerBatch => execute_batch(client, query, batch),
+            ErrorIsolation::PerRow => execute_rows(client, query, batch),
+        };
+        let duration = started.elapsed();
+        match result {
+            Ok(failed) => {
+                sizer.record(duration, !failed.is_empty());
+                report.batches.push(BatchInfo {
+                    size: batch.len(),
+                    duration,
+                    outcome: BatchOutcome::Committed {
+                        failed: failed.len(),
+                    },
+                });
+                report.executed += (batch.len() - failed.len()) as u64;
+                report.failed.extend(
+                    failed
+                        .into_iter()
+                        .map(|(index, error)| (next + index, error)),
+                );
+                next += batch.len();
+            }
+            Err((index, error)) => {
+                report.batches.push(BatchInfo {
+                    size: batch.len(),
+                    duration,
+                    outcome: BatchOutcome::RolledBack,
+                });
+                if !sizer.can_shrink() {
+                    return Err(ExecuteManyError::Failed {
+                        index: next + index,
+                        error,
+                        report,
+                    });
+                }
+                sizer.record(duration, true);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Execute `query` for a batch in one sub-transaction, returning the index of the item that failed
+fn execute_batch(
+    client: &mut SpiClient,
+    query: &str,
+    batch: &[Vec<(PgOid, Option<Datum>)>],
+) -> Result<Vec<(usize, Error)>, (usize, Error)> {
+    let done = AssertUnwindSafe(Cell::new(0));
+    checked_sub_transaction_of(client, |client| {
+        check_spi_refusal(query);
+        for args in batch {
+            client.update(query, None, Some(args.clone()));
+            done.set(done.get() + 1);
+        }
+        vec![]
+    })
+    .map_err(|error| (done.get(), error))
+}
+
+/// Execute `query` for a batch in one sub-transaction, every item in a sub-transaction of its own, returning the
+/// items that failed
+fn execute_rows(
+    client: &mut SpiClient,
+    query: &str,
+    batch: &[Vec<(PgOid, Option<Datum>)>],
+) -> Result<Vec<(usize, Error)>, (usize, Error)> {
+    let mut xact = client
+        .try_sub_transaction(|xact| xact.rollback_on_drop())
+        .map_err(|error| (0, error))?;
+    let failed = batch
+        .iter()
+        .enumerate()
+        .filter_map(|(index, args)| {
+            xact.update(query, None, Some(args.clone()))
+                .err()
+                .map(|error| (index, error))
+        })
+        .collect();
+    xact.commit();
+    Ok(failed)
+}
@@ -0,0 +1,236 @@
+//! Cooperative yielding for background workers executing long sequences of commands
+//!
+//! Commands process interrupts while they execute, but the Rust code between them doesn't, so a worker executing one
+//! command after another would only notice that it was asked to shut down or to reload its configuration once it is
+//! done. [`yield_point`] processes the worker's latch and signals, and is cheap enough to call between every command.
+//! [`with_yield_points`] makes the batch runners of this crate
+//! ([`checked_execute_many`](crate::batch::checked_execute_many),
+//! [`checked_delete_in_batches`](crate::purge::checked_delete_in_batches) and
+//! [`partitioned_run`](crate::keyspace::partitioned_run)) call it before every batch or partition, and stop once
+//! shutdown was requested.
+//!
+//! Signals are those received by the handlers pgx attaches with `BackgroundWorker::attach_signal_handlers`. pgx clears
+//! its flags when they are read, so a worker calling yield points must check for shutdown with [`shutdown_requested`]
+//! rather than `BackgroundWorker::sigterm_received`, and must not wait with `BackgroundWorker::wait_latch` (which reads
+//! the flag): either would miss a SIGTERM read by the other. With the `testing` feature, [`SimulatedWorker`] sends
+//! them without a worker.
+
+use pgx::bgworkers::BackgroundWorker;
+use pgx::pg_sys;
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+
+/// Returned by [`yield_point`] once the worker was asked to shut down, so that it can wind down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownRequested;
+
+impl Display for ShutdownRequested {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "background worker was asked to shut down")
+    }
+}
+
+impl std::error::Error for ShutdownRequested {}
+
+/// Signals received and not processed yet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Signals {
+    sighup: bool,
+    sigterm: bool,
+}
+
+thread_local! {
+    static YIELD_POINTS: Cell<bool> = const { Cell::new(false) };
+    /// Whether a SIGTERM was received, which is remembered so that every later yield point reports it
+    static SHUTDOWN_REQUESTED: Cell<bool> = const { Cell::new(false) };
+    /// Signals sent by a [`SimulatedWorker`], if one exists
+    #[cfg(feature = "testing")]
+    static SIMULATED: Cell<Option<Signals>> = const { Cell::new(None) };
+    /// Configuration reloads by yield points since the [`SimulatedWorker`] started
+    #[cfg(feature = "testing")]
+    static RELOADS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Process the latch and signals of a background worker, returning [`ShutdownRequested`] if it was asked to shut down
+///
+/// The process latch is waited on with a zero timeout (so it never sleeps) and reset, a pending SIGHUP reloads the
+/// configuration files, as Postgres' own processes do, so that the next command sees the new settings, and a SIGTERM
+/// is returned as [`ShutdownRequested`], by this call and every one after it. If the postmaster died, the process
+/// exits. Only a few flags are read unless one of them is set.
+///
+/// In a regular backend rather than a background worker, this does nothing and returns `Ok(())`: backends process
+/// their signals as interrupts.
+pub fn yield_point() -> Result<(), ShutdownRequested> {
+    if !is_worker() {
+        return Ok(());
+    }
+    if SHUTDOWN_REQUESTED.with(Cell::get) {
+        return Err(ShutdownRequested);
+    }
+    unsafe { wait_latch() };
+    let signals = take_signals();
+    if signals.sighup {
+        unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP) };
+        #[cfg(feature = "testing")]
+        RELOADS.with(|reloads| reloads.set(reloads.get() + 1));
+    }
+    if signals.sigterm {
+        SHUTDOWN_REQUESTED.with(|requested| requested.set(true));
+        return Err(ShutdownRequested);
+    }
+    Ok(())
+}
+
+/// Whether the worker was asked to shut down, by a SIGTERM read by this function or by a yield point
+///
+/// Workers calling yield points must use this instead of `BackgroundWorker::sigterm_received`, as pgx clears its flag
+/// when it is read: once a yield point read it, pgx no longer reports the SIGTERM, while this keeps reporting it.
+/// Doesn't process the latch or a SIGHUP. In a regular backend, always returns `false`.
+pub fn shutdown_requested() -> bool {
+    if SHUTDOWN_REQUESTED.with(Cell::get) {
+        return true;
+    }
+    if !is_worker() || !take_sigterm() {
+        return false;
+    }
+    SHUTDOWN_REQUESTED.with(|requested| requested.set(true));
+    true
+}
+
+#[cfg(not(feature = "pg11"))]
+unsafe fn wait_latch() {
+    pg_sys::WaitLatch(
+        pg_sys::MyLatch,
+        (pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_EXIT_ON_PM_DEATH) as i32,
+        0,
+        pg_sys::PG_WAIT_EXTENSION,
+    );
+    pg_sys::ResetLatch(pg_sys::MyLatch);
+}
+
+#[cfg(feature = "pg11")]
+unsafe fn wait_latch() {
+    // `WL_EXIT_ON_PM_DEATH` was added in Postgres 12
+    let events = pg_sys::WaitLatch(
+        pg_sys::MyLatch,
+        (pg_sys::WL_LATCH_SET | pg_sys::WL_TIMEOUT | pg_sys::WL_POSTMASTER_DEATH) as i32,
+        0,
+        pg_sys::PG_WAIT_EXTENSION,
+    );
+    if events & pg_sys::WL_POSTMASTER_DEATH as i32 != 0 {
+        pg_sys::proc_exit(1);
+    }
+    pg_sys::ResetLatch(pg_sys::MyLatch);
+}
+
+fn is_worker() -> bool {
+    #[cfg(feature = "testing")]
+    if SIMULATED.with(Cell::get).is_some() {
+        return true;
+    }
+    unsafe { pg_sys::IsBackgroundWorker }
+}
+
+fn take_signals() -> Signals {
+    #[cfg(feature = "testing")]
+    if let Some(signals) = SIMULATED.with(|simulated| simulated.replace(Some(Signals::default()))) {
+        return signals;
+    }
+    Signals {
+        sighup: BackgroundWorker::sighup_received(),
+        sigterm: BackgroundWorker::sigterm_received(),
+    }
+}
+
+/// Take a pending SIGTERM, leaving a SIGHUP pending
+fn take_sigterm() -> bool {
+    #[cfg(feature = "testing")]
+    if let Some(mut signals) = SIMULATED.with(Cell::get) {
+        let sigterm = std::mem::take(&mut signals.sigterm);
+        SIMULATED.with(|simulated| simulated.set(Some(signals)));
+        return sigterm;
+    }
+    BackgroundWorker::sigterm_received()
+}
+
+/// Make the batch runners of this crate call [`yield_point`] before every batch (or partition) until the returned
+/// guard is dropped
+#[must_use = "yield points are disabled again when the guard is dropped"]
+pub fn with_yield_points() -> YieldPointsGuard {
+    YieldPointsGuard {
+        previous: YIELD_POINTS.with(|enabled| enabled.replace(true)),
+    }
+}
+
+/// Restores whether the batch runners call [`yield_point`] when dropped, see [`with_yield_points`]
+#[derive(Debug)]
+pub struct YieldPointsGuard {
+    previous: bool,
+}
+
+impl Drop for YieldPointsGuard {
+    fn drop(&mut self) {
+        YIELD_POINTS.with(|enabled| enabled.set(self.previous));
+    }
+}
+
+/// [`yield_point`], if the batch runners are to call it
+pub(crate) fn batch_yield_point() -> Result<(), ShutdownRequested> {
+    if YIELD_POINTS.with(Cell::get) {
+        yield_point()
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes [`yield_point`] act as in a background worker until dropped, receiving the signals sent with
+/// [`SimulatedWorker::sighup`] and [`SimulatedWorker::sigterm`] instead of real ones
+///
+/// Dropping it forgets that shutdown was requested. Only compiled with the `testing` feature.
+#[cfg(feature = "testing")]
+#[derive(Debug)]
+pub struct SimulatedWorker {
+    _private: (),
+}
+
+#[cfg(feature = "testing")]
+impl SimulatedWorker {
+    /// Panics if one already exists
+    pub fn start() -> Self {
+        let previous = SIMULATED.with(|simulated| simulated.replace(Some(Signals::default())));
+        assert!(previous.is_none(), "a simulated worker already exists");
+        RELOADS.with(|reloads| reloads.set(0));
+        Self { _private: () }
+    }
+
+    /// Number of times yield points reloaded the configuration files since the worker started
+    pub fn reloads(&self) -> u64 {
+        RELOADS.with(Cell::get)
+    }
+
+    /// Send a SIGHUP, processed by the next yield point
+    pub fn sighup(&self) {
+        self.send(|signals| signals.sighup = true);
+    }
+
+    /// Send a SIGTERM, processed by the next yield point
+    pub fn sigterm(&self) {
+        self.send(|signals| signals.sigterm = true);
+    }
+
+    fn send(&self, f: impl FnOnce(&mut Signals)) {
+        SIMULATED.with(|simulated| {
+            let mut signals = simulated.get().unwrap_or_default();
+            f(&mut signals);
+            simulated.set(Some(signals));
+        });
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Drop for SimulatedWorker {
+    fn drop(&mut self) {
+        SIMULATED.with(|simulated| simulated.set(None));
+        SHUTDOWN_REQUESTED.with(|requested| requested.set(false));
+    }
+}
@@ -0,0 +1,230 @@
+//! Time budgets shared by a sequence of checked commands
+//!
+//! Commands executed through SPI are not subject to `statement_timeout`, which is only armed for statements sent by
+//! the client, so a budget arms a timeout of its own for every command instead. When it fires, the command is
+//! canceled as if by the user.
+
+use pgx::{pg_sys, PgOid, SpiClient, SpiTupleTable};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::checked::*;
+use crate::error::{Error, PostgresErrorExt};
+use crate::subtxn::SubTransaction;
+
+thread_local! {
+    static TIMEOUT_ID: Cell<Option<pg_sys::TimeoutId>> = Cell::new(None);
+}
+
+/// Whether the budget's timeout fired since it was last enabled, set by the signal handler
+static TIMEOUT_FIRED: AtomicBool = AtomicBool::new(false);
+
+/// Total time allowance of a sequence of checked commands
+///
+/// Clones share the allowance, so a budget can be handed to nested code (and sub-transactions, see
+/// [`SubTransaction::set_budget`]). It is backend-local and can't be sent to other threads.
+#[derive(Debug, Clone)]
+pub struct Budget(Rc<BudgetState>);
+
+#[derive(Debug)]
+struct BudgetState {
+    total: Duration,
+    // Nanoseconds; atomic rather than a `Cell` to keep sub-transactions holding a budget unwind-safe
+    elapsed: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(total: Duration) -> Self {
+        Budget(Rc::new(BudgetState {
+            total,
+            elapsed: AtomicU64::new(0),
+        }))
+    }
+
+    /// Total time allowance
+    pub fn total(&self) -> Duration {
+        self.0.total
+    }
+
+    /// Time spent executing commands within the budget so far
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.0.elapsed.load(Ordering::Relaxed))
+    }
+
+    /// Time left, zero if the budget is exhausted
+    pub fn remaining(&self) -> Duration {
+        self.0.total.saturating_sub(self.elapsed())
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    /// Return [`Error::BudgetExceeded`] if the budget is exhausted
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.is_exhausted() {
+            Err(self.exceeded())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Deduct time spent outside of [`Budget::run`]
+    pub(crate) fn charge(&self, spent: Duration) {
+        let spent = spent.as_nanos().min(u64::MAX as u128) as u64;
+        self.0.elapsed.fetch_add(spent, Ordering::Relaxed);
+    }
+
+    fn exceeded(&self) -> Error {
+        Error::BudgetExceeded {
+            elapsed: self.elapsed(),
+            budget: self.total(),
+        }
+    }
+
+    /// Run `f` with a timeout of the remaining time (at least a millisecond), then deduct the time it took
+    ///
+    /// If the budget is already exhausted, [`Error::BudgetExceeded`] is returned without running `f`. It's also
+    /// returned instead of the cancellation error if `f` was canceled as the budget ran out.
+    pub(crate) fn run<R, F: FnOnce() -> Result<R, Error>>(&self, f: F) -> Result<R, Error> {
+        self.check()?;
+        let start = Instant::now();
+        let result = {
+            let _timeout = BudgetTimeout::enable(self.remaining());
+            f()
+        };
+        self.charge(start.elapsed());
+        match result {
+            Err(err) if self.is_exhausted() && is_query_canceled(&err) => Err(self.exceeded()),
+            result => result,
+        }
+    }
+}
+
+fn is_query_canceled(err: &Error) -> bool {
+    err.report()
+        .map_or(false, |report| report.sqlstate().as_str() == "57014")
+}
+
+unsafe extern "C" fn budget_timeout_handler() {
+    // Same as what the SIGINT handler does; the timeout machinery sets the latch afterwards
+    TIMEOUT_FIRED.store(true, Ordering::Relaxed);
+    pg_sys::InterruptPending = true;
+    pg_sys::QueryCancelPending = true;
+}
+
+fn timeout_id() -> pg_sys::TimeoutId {
+    TIMEOUT_ID.with(|id| match id.get() {
+        Some(timeout) => timeout,
+        None => {
+            let timeout = unsafe {
+                pg_sys::RegisterTimeout(
+                    pg_sys::TimeoutId_USER_TIMEOUT,
+                    Some(budget_timeout_handler),
+                )
+            };
+            id.set(Some(timeout));
+            timeout
+        }
+    })
+}
+
+/// Budget's timeout, disabled when dropped
+///
+/// If the timeout is already active (a command within a budget is executed by another one), it's left as is.
+struct BudgetTimeout(Option<pg_sys::TimeoutId>);
+
+impl BudgetTimeout {
+    fn enable(remaining: Duration) -> Self {
+        let id = timeout_id();
+        if unsafe { pg_sys::get_timeout_active(id) } {
+            return BudgetTimeout(None);
+        }
+        let ms = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+        TIMEOUT_FIRED.store(false, Ordering::Relaxed);
+        unsafe { pg_sys::enable_timeout_after(id, ms) };
+        BudgetTimeout(Some(id))
+    }
+}
+
+impl Drop for BudgetTimeout {
+    fn drop(&mut self) {
+        if let Some(id) = self.0 {
+            unsafe {
+                pg_sys::disable_timeout(id, false);
+                // The timeout may have fired after the command last checked for interrupts, in which case the
+                // cancellation it requested is still pending and would cancel whatever comes next instead.
+                // `InterruptPending` is left set, as it may stand for other interrupts too; the next check for
+                // interrupts clears it if there are none
+                if TIMEOUT_FIRED.swap(false, Ordering::Relaxed) {
+                    pg_sys::QueryCancelPending = false;
+                }
+            }
+        }
+    }
+}
+
+/// Checked commands executed within a [`Budget`]
+pub trait BudgetCommands {
+    /// Execute a read-only command within `budget`, returning an error if one occurred.
+    ///
+    /// Returns [`Error::BudgetExceeded`] without executing the command if the budget is exhausted, or if the
+    /// command was canceled as it ran out.
+    fn checked_select_within(
+        &self,
+        budget: &Budget,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error>;
+
+    /// Execute a mutable command within `budget`, returning an error if one occurred.
+    ///
+    /// Returns [`Error::BudgetExceeded`] without executing the command if the budget is exhausted, or if the
+    /// command was canceled as it ran out.
+    fn checked_update_within(
+        &mut self,
+        budget: &Budget,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error>;
+}
+
+impl BudgetCommands for SpiClient {
+    fn checked_select_within(
+        &self,
+        budget: &Budget,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        budget.run(|| self.checked_select(query, limit, args))
+    }
+
+    fn checked_update_within(
+        &mut self,
+        budget: &Budget,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        budget.run(|| self.checked_update(query, limit, args))
+    }
+}
+
+impl<Parent, const COMMIT: bool> SubTransaction<Parent, COMMIT> {
+    /// Execute the commands of [`SubTransaction::select`] and [`SubTransaction::update`] within `budget`
+    ///
+    /// Sub-transactions nested in this one share it.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = Some(budget);
+    }
+
+    /// Budget attached to this sub-transaction, if any
+    pub fn budget(&self) -> Option<&Budget> {
+        self.budget.as_ref()
+    }
+}
@@ -0,0 +1,328 @@
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{pg_sys, FromDatum, IntoDatum, PgBuiltInOids, PgOid, SpiClient, SpiTupleTable};
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::{Error, PostgresErrorExt};
+use crate::quote::quote_identifier;
+use crate::rewrite;
+use crate::row::column_names;
+
+/// Error returned by [`CallCommands::checked_call`] and [`CallCommands::checked_call_procedure`]
+#[derive(Debug)]
+pub enum CallError {
+    /// No function or procedure accepts the given argument types
+    UnknownFunction {
+        name: String,
+        /// Signature that was looked up
+        signature: String,
+        /// Signatures of the functions or procedures with the same name that exist
+        candidates: Vec<String>,
+    },
+    /// More than one function or procedure could accept the given argument types
+    AmbiguousFunction {
+        name: String,
+        candidates: Vec<String>,
+    },
+    /// The procedure tried to commit or roll back the transaction, which can't be done under SPI
+    TransactionControl(CaughtError),
+    /// Looking the function up or calling it failed
+    Query(Error),
+}
+
+impl Display for CallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallError::UnknownFunction { signature, .. } => {
+                write!(f, "function {} does not exist", signature)
+            }
+            CallError::AmbiguousFunction { name, candidates } => write!(
+                f,
+                "function name \"{}\" is ambiguous, candidates are: {}",
+                name,
+                candidates.join(", ")
+            ),
+            CallError::TransactionControl(err) => {
+                write!(
+                    f,
+                    "transaction control is not allowed in a checked call: {:?}",
+                    err
+                )
+            }
+            CallError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for CallError {
+    fn from(err: Error) -> Self {
+        CallError::Query(err)
+    }
+}
+
+/// Result of a checked call
+///
+/// Its fields are the output columns of the function (the fields of its composite result or its OUT parameters, or
+/// the single value it returns). For set-returning functions, only the first row is exposed.
+pub struct CallResult {
+    table: SpiTupleTable,
+    fields: Vec<(String, PgOid)>,
+}
+
+impl CallResult {
+    /// Fields of the result
+    pub fn fields(&self) -> Vec<CallField<'_>> {
+        (1..=self.fields.len())
+            .map(|ordinal| CallField {
+                result: self,
+                ordinal,
+            })
+            .collect()
+    }
+
+    /// Look a field up by name
+    pub fn field(&self, name: &str) -> Option<CallField<'_>> {
+        self.fields
+            .iter()
+            .position(|(field, _)| field == name)
+            .map(|index| CallField {
+                result: self,
+                ordinal: index + 1,
+            })
+    }
+
+    /// Value returned by a function with a scalar result, or `None` if it's NULL
+    ///
+    /// Panics if the result does not consist of a single field.
+    pub fn single<T: FromDatum + IntoDatum>(&self) -> Option<T> {
+        assert_eq!(
+            1,
+            self.fields.len(),
+            "result has {} fields, expected a single one",
+            self.fields.len()
+        );
+        self.fields()[0].value()
+    }
+}
+
+/// Field of a [`CallResult`]
+pub struct CallField<'a> {
+    result: &'a CallResult,
+    ordinal: usize,
+}
+
+impl<'a> CallField<'a> {
+    pub fn name(&self) -> &'a str {
+        &self.result.fields[self.ordinal - 1].0
+    }
+
+    pub fn type_oid(&self) -> PgOid {
+        self.result.fields[self.ordinal - 1].1
+    }
+
+    /// Value of the field, or `None` if it's NULL (or the function returned no rows)
+    pub fn value<T: FromDatum + IntoDatum>(&self) -> Option<T> {
+        if self.result.table.is_empty() {
+            return None;
+        }
+        self.result.table.get_datum(self.ordinal)
+    }
+}
+
+/// Commands calling functions and procedures
+pub trait CallCommands {
+    /// Call a function, checking that it exists and accepts the arguments' types first
+    ///
+    /// The function is called as a mutable command, so it may modify data.
+    ///
+    /// `function` may be schema-qualified and is parsed like an SQL identifier (so it has to be quoted if it's
+    /// not lowercase).
+    fn checked_call(
+        &mut self,
+        function: &str,
+        args: Vec<(PgOid, Option<pg_sys::Datum>)>,
+    ) -> Result<CallResult, CallError>;
+
+    /// Call a procedure with `CALL`, checking that it exists and accepts the arguments' types first
+    ///
+    /// Since the procedure runs in a sub-transaction and through SPI (which is always atomic), it can't commit or
+    /// roll back the transaction: attempting to do so fails with [`CallError::TransactionControl`]. The fields of
+    /// the result are the procedure's INOUT (and OUT) parameters. Note that starting with Postgres 14, arguments
+    /// for OUT parameters must be passed as well (typically as NULL).
+    fn checked_call_procedure(
+        &mut self,
+        procedure: &str,
+        args: Vec<(PgOid, Option<pg_sys::Datum>)>,
+    ) -> Result<CallResult, CallError>;
+}
+
+impl CallCommands for SpiClient {
+    fn checked_call(
+        &mut self,
+        function: &str,
+        args: Vec<(PgOid, Option<pg_sys::Datum>)>,
+    ) -> Result<CallResult, CallError> {
+        call(self, function, args, false)
+    }
+
+    fn checked_call_procedure(
+        &mut self,
+        procedure: &str,
+        args: Vec<(PgOid, Option<pg_sys::Datum>)>,
+    ) -> Result<CallResult, CallError> {
+        call(self, procedure, args, true)
+    }
+}
+
+/// Candidate found by [`resolve`]
+struct Routine {
+    schema: String,
+    name: String,
+    arg_types: Vec<pg_sys::Oid>,
+    signature: String,
+}
+
+fn call(
+    client: &mut SpiClient,
+    name: &str,
+    args: Vec<(PgOid, Option<pg_sys::Datum>)>,
+    procedure: bool,
+) -> Result<CallResult, CallError> {
+    let arg_types: Vec<pg_sys::Oid> = args.iter().map(|(oid, _)| oid.value()).collect();
+    let routine = resolve(client, name, &arg_types, procedure)?;
+    let params = routine
+        .arg_types
+        .iter()
+        .enumerate()
+        .map(|(i, oid)| format!("${}::{}", i + 1, type_name(*oid)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let target = format!(
+        "{}.{}",
+        quote_identifier(&routine.schema),
+        quote_identifier(&routine.name)
+    );
+    let query = if procedure {
+        format!("CALL {}({})", target, params)
+    } else {
+        format!("SELECT * FROM {}({})", target, params)
+    };
+    let table = match client.checked_update(&query, None, Some(args)) {
+        Err(Error::Caught(CaughtError::PostgresError(report), _))
+            if report.sqlstate().as_str() == "2D000" =>
+        {
+            return Err(CallError::TransactionControl(CaughtError::PostgresError(
+                report,
+            )))
+        }
+        result => result?,
+    };
+    let fields = column_names(&table)
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let oid = table.column_type_oid(i + 1).unwrap_or(PgOid::InvalidOid);
+            (name, oid)
+        })
+        .collect();
+    Ok(CallResult {
+        table: table.first(),
+        fields,
+    })
+}
+
+/// Look up the function or procedure to call
+///
+/// An exact match of the argument types is preferred. Otherwise, a single candidate with the same number of
+/// arguments is used, relying on Postgres to coerce the arguments.
+fn resolve(
+    client: &SpiClient,
+    name: &str,
+    arg_types: &[pg_sys::Oid],
+    procedure: bool,
+) -> Result<Routine, CallError> {
+    let candidates: Vec<Routine> = rewrite::exempt(|| {
+        client.checked_select(
+            "WITH name AS (SELECT parse_ident($1) AS parts) \
+             SELECT n.nspname::text, p.proname::text, array_to_string(p.proargtypes::oid[], ','), \
+                    p.oid::regprocedure::text \
+             FROM pg_proc p JOIN pg_namespace n ON n.oid = p.pronamespace, name \
+             WHERE p.proname = parts[array_length(parts, 1)] \
+               AND CASE WHEN array_length(parts, 1) > 1 THEN n.nspname = parts[array_length(parts, 1) - 1] \
+                        ELSE pg_function_is_visible(p.oid) END \
+               AND (p.prokind = 'p') = $2 \
+             ORDER BY p.oid",
+            None,
+            Some(vec![
+                (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+                (PgBuiltInOids::BOOLOID.oid(), procedure.into_datum()),
+            ]),
+        )
+    })?
+    .filter_map(|row| {
+        Some(Routine {
+            schema: row.by_ordinal(1).ok()?.value::<String>()?,
+            name: row.by_ordinal(2).ok()?.value::<String>()?,
+            arg_types: row
+                .by_ordinal(3)
+                .ok()?
+                .value::<String>()?
+                .split(',')
+                .filter(|oid| !oid.is_empty())
+                .map(|oid| oid.parse().unwrap())
+                .collect(),
+            signature: row.by_ordinal(4).ok()?.value::<String>()?,
+        })
+    })
+    .collect();
+    let signatures = |routines: &[&Routine]| {
+        routines
+            .iter()
+            .map(|routine| routine.signature.clone())
+            .collect()
+    };
+    let all: Vec<&Routine> = candidates.iter().collect();
+    let same_arity: Vec<&Routine> = candidates
+        .iter()
+        .filter(|routine| routine.arg_types.len() == arg_types.len())
+        .collect();
+    if let Some(index) = candidates
+        .iter()
+        .position(|routine| routine.arg_types == arg_types)
+    {
+        return Ok(candidates.into_iter().nth(index).unwrap());
+    }
+    match same_arity.len() {
+        0 => Err(CallError::UnknownFunction {
+            name: name.to_string(),
+            signature: format!(
+                "{}({})",
+                name,
+                arg_types
+                    .iter()
+                    .map(|oid| type_name(*oid))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            candidates: signatures(&all),
+        }),
+        1 => {
+            let signature = same_arity[0].signature.clone();
+            Ok(candidates
+                .into_iter()
+                .find(|routine| routine.signature == signature)
+                .unwrap())
+        }
+        _ => Err(CallError::AmbiguousFunction {
+            name: name.to_string(),
+            candidates: signatures(&same_arity),
+        }),
+    }
+}
+
+fn type_name(oid: pg_sys::Oid) -> String {
+    unsafe { CStr::from_ptr(pg_sys::format_type_be(oid)) }
+        .to_string_lossy()
+        .into_owned()
+}
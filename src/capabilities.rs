@@ -0,0 +1,206 @@
+//! What this build of the crate supports, for code built on top of it to adapt to at runtime
+//!
+//! [`current`] reflects the optional features the crate was compiled with. The version of the server it runs against
+//! is queried separately, with [`server_version_num`], as querying requires a transaction.
+
+use pgx::{pg_sys, SpiClient};
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::rewrite;
+
+/// Optional capability of the crate, enabled by a cargo feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Remote execution (`remote`)
+    Dblink,
+    /// Conversion of numerics to `rust_decimal::Decimal`
+    Decimal,
+    /// `#[derive(FromSpiRow)]`
+    Derive,
+    /// Plan assertions (`plan_asserts`), JSON outbox payloads and [`Capabilities::to_json`]
+    Json,
+    /// Test support (`time::FrozenTime`, `faults`)
+    Testing,
+}
+
+impl Capability {
+    /// Cargo feature enabling the capability
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Capability::Dblink => "dblink",
+            Capability::Decimal => "decimal",
+            Capability::Derive => "derive",
+            Capability::Json => "json",
+            Capability::Testing => "testing",
+        }
+    }
+}
+
+/// Capability the crate was built without, see [`require`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingCapability(pub Capability);
+
+impl Display for MissingCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pgx-contrib-spiext was built without {:?} support; enable its \"{}\" cargo feature",
+            self.0,
+            self.0.feature()
+        )
+    }
+}
+
+impl std::error::Error for MissingCapability {}
+
+/// What the crate was built with, see [`current`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub dblink: bool,
+    pub decimal: bool,
+    pub derive: bool,
+    pub json: bool,
+    pub testing: bool,
+    /// Version of the crate
+    pub version: &'static str,
+    /// Version of the Postgres headers the crate was built against, such as `150002`; the server it runs against has
+    /// the same major version
+    pub pg_version_num: u32,
+    /// Maximum number of parameters of a single command
+    pub max_parameters: usize,
+    /// Maximum number of arguments of a function
+    pub max_function_args: usize,
+}
+
+impl Capabilities {
+    /// Whether the crate was built with `capability`
+    pub fn has(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Dblink => self.dblink,
+            Capability::Decimal => self.decimal,
+            Capability::Derive => self.derive,
+            Capability::Json => self.json,
+            Capability::Testing => self.testing,
+        }
+    }
+
+    /// Check that the crate was built with `capability`
+    pub fn require(&self, capability: Capability) -> Result<(), MissingCapability> {
+        if self.has(capability) {
+            Ok(())
+        } else {
+            Err(MissingCapability(capability))
+        }
+    }
+
+    /// Capabilities as a JSON object, with the fields of this struct (and `features`, the enabled cargo features) as
+    /// keys
+    ///
+    /// Requires the `json` feature.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> pgx::Json {
+        use pgx::{FromDatum, IntoDatum};
+        let features = self
+            .features()
+            .iter()
+            .map(|feature| format!("\"{}\"", feature))
+            .collect::<Vec<_>>()
+            .join(",");
+        let json = format!(
+            "{{\"dblink\":{},\"decimal\":{},\"derive\":{},\"json\":{},\"testing\":{},\"features\":[{}],\
+             \"version\":\"{}\",\"pg_version_num\":{},\"max_parameters\":{},\
+             \"max_function_args\":{}}}",
+            self.dblink,
+            self.decimal,
+            self.derive,
+            self.json,
+            self.testing,
+            features,
+            self.version,
+            self.pg_version_num,
+            self.max_parameters,
+            self.max_function_args
+        );
+        // `json` values are stored as text
+        unsafe { pgx::Json::from_datum(json.as_str().into_datum().unwrap(), false) }.unwrap()
+    }
+
+    /// Enabled cargo features
+    fn features(&self) -> Vec<&'static str> {
+        [
+            Capability::Dblink,
+            Capability::Decimal,
+            Capability::Derive,
+            Capability::Json,
+            Capability::Testing,
+        ]
+        .into_iter()
+        .filter(|capability| self.has(*capability))
+        .map(|capability| capability.feature())
+        .collect()
+    }
+}
+
+/// A single line, such as to log when an extension is loaded
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let features = self.features();
+        write!(
+            f,
+            "pgx-contrib-spiext {} (PostgreSQL {}), features: {}",
+            self.version,
+            self.pg_version_num,
+            if features.is_empty() {
+                "none".to_string()
+            } else {
+                features.join(", ")
+            }
+        )
+    }
+}
+
+/// Capabilities of this build of the crate
+pub fn current() -> Capabilities {
+    Capabilities {
+        dblink: cfg!(feature = "dblink"),
+        decimal: cfg!(feature = "decimal"),
+        derive: cfg!(feature = "derive"),
+        json: cfg!(feature = "json"),
+        testing: cfg!(feature = "testing"),
+        version: env!("CARGO_PKG_VERSION"),
+        pg_version_num: pg_sys::PG_VERSION_NUM,
+        max_parameters: crate::validate::MAX_PARAMETERS,
+        max_function_args: pg_sys::FUNC_MAX_ARGS as usize,
+    }
+}
+
+/// Check that this build of the crate has `capability`
+pub fn require(capability: Capability) -> Result<(), MissingCapability> {
+    current().require(capability)
+}
+
+thread_local! {
+    static SERVER_VERSION_NUM: Cell<Option<i32>> = const { Cell::new(None) };
+}
+
+/// Version of the server, such as `150002`, queried the first time it's requested in this backend
+pub fn server_version_num(client: &SpiClient) -> Result<i32, Error> {
+    if let Some(version) = SERVER_VERSION_NUM.with(Cell::get) {
+        return Ok(version);
+    }
+    let version = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT current_setting('server_version_num')::int",
+            None,
+            None,
+        )
+    })?
+    .first()
+    .get_one::<i32>()
+    .unwrap_or_default();
+    SERVER_VERSION_NUM.with(|cached| cached.set(Some(version)));
+    Ok(version)
+}
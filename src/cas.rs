@@ -0,0 +1,98 @@
+//! Optimistic locking with a version column
+//!
+//! Table names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier), column names are quoted with
+//! [`quote_identifier`](crate::quote::quote_identifier). Values are always passed as parameters.
+
+use pgx::{pg_sys::Datum, IntoDatum, PgBuiltInOids, PgOid, SpiClient};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::quote::*;
+
+/// Outcome of [`cas_update`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CasOutcome {
+    /// The row had the expected version and was updated, its version is now this one
+    Updated(i64),
+    /// The row has another version than the expected one, so it was left as it is
+    Conflict(i64),
+    /// There is no row with the key
+    NotFound,
+}
+
+/// Set `set_columns` of the row of `table` identified by `key_columns`, if its `version_column` is
+/// `expected_version`, incrementing the version
+///
+/// Every column is given along with its value (and the value's type). The update and, if it matched no row, the
+/// lookup telling a conflict from a missing row execute in one sub-transaction, which is rolled back if an error is
+/// caught. A `NULL` key value matches no row, and a row whose version is `NULL` is reported as not found.
+///
+/// Panics if there are no key columns.
+pub fn cas_update(
+    client: &mut SpiClient,
+    table: &str,
+    key_columns: &[(&str, (PgOid, Option<Datum>))],
+    version_column: &str,
+    expected_version: i64,
+    set_columns: &[(&str, (PgOid, Option<Datum>))],
+) -> Result<CasOutcome, Error> {
+    assert!(
+        !key_columns.is_empty(),
+        "there must be at least one key column"
+    );
+    let table = quote_qualified_identifier(table);
+    let version_column = quote_identifier(version_column);
+    let mut args = vec![];
+    let mut assignments = vec![format!("{} = {} + 1", version_column, version_column)];
+    for (column, value) in set_columns {
+        args.push(*value);
+        assignments.push(format!("{} = ${}", quote_identifier(column), args.len()));
+    }
+    let key_offset = args.len();
+    let key = key_columns
+        .iter()
+        .enumerate()
+        .map(|(i, (column, _))| format!("{} = ${}", quote_identifier(column), key_offset + i + 1))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let key_args: Vec<_> = key_columns.iter().map(|(_, value)| *value).collect();
+    args.extend(key_args.iter().copied());
+    args.push((PgBuiltInOids::INT8OID.oid(), expected_version.into_datum()));
+    let update = format!(
+        "UPDATE {} SET {} WHERE {} AND {} = ${} RETURNING {}::bigint",
+        table,
+        assignments.join(", "),
+        key,
+        version_column,
+        args.len(),
+        version_column
+    );
+    // Key parameters are numbered from 1 in the lookup
+    let key = key_columns
+        .iter()
+        .enumerate()
+        .map(|(i, (column, _))| format!("{} = ${}", quote_identifier(column), i + 1))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let lookup = format!(
+        "SELECT {}::bigint FROM {} WHERE {}",
+        version_column, table, key
+    );
+    checked_sub_transaction_of(client, move |client| {
+        let updated = client
+            .update(&update, None, Some(args))
+            .map(|row| row.by_ordinal(1).unwrap().value::<i64>())
+            .next()
+            .flatten();
+        if let Some(version) = updated {
+            return CasOutcome::Updated(version);
+        }
+        let actual = client
+            .select(&lookup, Some(1), Some(key_args))
+            .map(|row| row.by_ordinal(1).unwrap().value::<i64>())
+            .next()
+            .flatten();
+        actual.map_or(CasOutcome::NotFound, CasOutcome::Conflict)
+    })
+}
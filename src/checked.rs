@@ -1,22 +1,42 @@
 use pgx::pg_sys::panic::CaughtError;
 use pgx::PgTryBuilder;
-use pgx::{pg_sys::Datum, PgOid, SpiClient, SpiTupleTable};
+use pgx::{
+    pg_sys, pg_sys::Datum, FromDatum, PgList, PgMemoryContexts, PgOid, SpiClient, SpiHeapTupleData,
+    SpiTupleTable,
+};
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
-use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::os::raw::c_char;
+use std::panic::{AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+use std::time::Instant;
 
+use crate::error::{
+    capture_sqlstate, handled, report, spi_error_panic, Error, PostgresErrorExt, SpiErrorCode,
+};
+use crate::metrics::{self, Metric};
+use crate::owned::OwnedRows;
+use crate::rewrite;
+use crate::row::SqlValue;
+use crate::scan::{is_empty_query, transaction_control_kind};
 use crate::subtxn::*;
+use crate::timeline::{self, EventKind};
 
 /// Read-only commands for SPI interface
 pub trait CheckedCommands {
     type Result<A>;
 
     /// Execute a read-only command, returning an error if one occurred.
+    ///
+    /// Empty queries are rejected with [`Error::EmptyQuery`], and transaction control statements with
+    /// [`Error::TransactionControlNotAllowed`] (see [`allow_transaction_control`]).
     fn checked_select(
         self,
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError>;
+    ) -> Result<Self::Result<SpiTupleTable>, Error>;
 }
 
 /// Mutable commands for SPI interface
@@ -24,12 +44,15 @@ pub trait CheckedMutCommands {
     type Result<A>;
 
     /// Execute a mutable command, returning an error if one occurred.
+    ///
+    /// Empty queries are rejected with [`Error::EmptyQuery`], and transaction control statements with
+    /// [`Error::TransactionControlNotAllowed`] (see [`allow_transaction_control`]).
     fn checked_update(
         self,
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError>;
+    ) -> Result<Self::Result<SpiTupleTable>, Error>;
 }
 
 impl<Parent: Deref<Target = SpiClient> + UnwindSafe + RefUnwindSafe> CheckedCommands
@@ -42,11 +65,47 @@ impl<Parent: Deref<Target = SpiClient> + UnwindSafe + RefUnwindSafe> CheckedComm
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError> {
-        PgTryBuilder::new(move || Ok((self.select(query, limit, args), self)))
-            .catch_others(|e| Err(e))
-            .execute()
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
+        checked_select_captured(self, query, limit, args).map(|(table, _, xact)| (table, xact))
+    }
+}
+
+/// Execute a read-only command in `xact` as [`CheckedCommands::checked_select`] does, also returning SPI's pointer to
+/// the tuple table it returned
+pub(crate) fn checked_select_captured<
+    Parent: Deref<Target = SpiClient> + UnwindSafe + RefUnwindSafe,
+>(
+    xact: SubTransaction<Parent, false>,
+    query: &str,
+    limit: Option<i64>,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+) -> Result<Executed<SubTransaction<Parent, false>>, Error> {
+    check_query(query)?;
+    let rewritten = rewrite::apply(query);
+    // Tagged last, so that hooks can't remove the tag
+    #[cfg(feature = "full")]
+    let rewritten = crate::config::apply_statement_tag(rewritten);
+    #[cfg(feature = "full")]
+    let watch = crate::config::watch_duration(args.as_deref());
+    #[cfg(feature = "full")]
+    let shadow_args = crate::shadow::is_enabled().then(|| args.clone());
+    let result = execute_checked_select(xact, &rewritten, limit, args).map_err(|mut err| {
+        if rewritten != query {
+            rewrite::record_original(&mut err, query);
+        }
+        err
+    });
+    #[cfg(feature = "full")]
+    if let (Some(watch), Ok(_)) = (watch, &result) {
+        watch.finish(&rewritten);
+    }
+    #[cfg(feature = "full")]
+    if let Some(args) = shadow_args {
+        return result.map(|(table, raw, xact)| {
+            (crate::shadow::compare(query, limit, args, table), raw, xact)
+        });
     }
+    result
 }
 
 impl<Parent: Deref<Target = SpiClient> + UnwindSafe + RefUnwindSafe> CheckedCommands
@@ -59,7 +118,7 @@ impl<Parent: Deref<Target = SpiClient> + UnwindSafe + RefUnwindSafe> CheckedComm
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError> {
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
         self.rollback_on_drop()
             .checked_select(query, limit, args)
             .map(|(res, xact)| (res, xact.commit_on_drop()))
@@ -72,17 +131,45 @@ impl<Parent: DerefMut<Target = SpiClient> + UnwindSafe + RefUnwindSafe> CheckedM
     type Result<A> = (A, SubTransaction<Parent, false>);
 
     fn checked_update(
-        mut self,
+        self,
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError> {
-        PgTryBuilder::new(move || Ok((self.update(query, limit, args), self)))
-            .catch_others(|e| Err(e))
-            .execute()
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
+        checked_update_captured(self, query, limit, args).map(|(table, _, xact)| (table, xact))
     }
 }
 
+/// Execute a mutable command in `xact` as [`CheckedMutCommands::checked_update`] does, also returning SPI's pointer to
+/// the tuple table it returned
+pub(crate) fn checked_update_captured<
+    Parent: DerefMut<Target = SpiClient> + UnwindSafe + RefUnwindSafe,
+>(
+    xact: SubTransaction<Parent, false>,
+    query: &str,
+    limit: Option<i64>,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+) -> Result<Executed<SubTransaction<Parent, false>>, Error> {
+    check_query(query)?;
+    let rewritten = rewrite::apply(query);
+    // Tagged last, so that hooks can't remove the tag
+    #[cfg(feature = "full")]
+    let rewritten = crate::config::apply_statement_tag(rewritten);
+    #[cfg(feature = "full")]
+    let watch = crate::config::watch_duration(args.as_deref());
+    let result = execute_checked_update(xact, &rewritten, limit, args).map_err(|mut err| {
+        if rewritten != query {
+            rewrite::record_original(&mut err, query);
+        }
+        err
+    });
+    #[cfg(feature = "full")]
+    if let (Some(watch), Ok(_)) = (watch, &result) {
+        watch.finish(&rewritten);
+    }
+    result
+}
+
 impl<Parent: DerefMut<Target = SpiClient> + UnwindSafe + RefUnwindSafe> CheckedMutCommands
     for SubTransaction<Parent, true>
 {
@@ -93,7 +180,7 @@ impl<Parent: DerefMut<Target = SpiClient> + UnwindSafe + RefUnwindSafe> CheckedM
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError> {
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
         self.rollback_on_drop()
             .checked_update(query, limit, args)
             .map(|(res, xact)| (res, xact.commit_on_drop()))
@@ -108,7 +195,8 @@ impl CheckedCommands for SpiClient {
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError> {
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
+        check_entry(query)?;
         self.sub_transaction(|xact| xact.checked_select(query, limit, args))
             .map(|(table, xact)| (table, xact.commit().into_inner()))
     }
@@ -122,12 +210,16 @@ impl<'a> CheckedCommands for &'a SpiClient {
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError> {
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
         // Here we rely on the fact that `SpiClient` can be created at any time. This may not hold true in the future
         // However, we need the client to be consumed by `sub_transaction`, so we do this for now.
+        check_entry(query)?;
         SpiClient
             .sub_transaction(|xact| xact.checked_select(query, limit, args))
-            .map(|(table, _xact): (_, SubTransaction<_, true>)| table)
+            .map(|(table, xact): (_, SubTransaction<_, true>)| {
+                xact.commit();
+                table
+            })
     }
 }
 
@@ -139,7 +231,8 @@ impl CheckedMutCommands for SpiClient {
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError> {
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
+        check_entry(query)?;
         self.sub_transaction(|xact| xact.checked_update(query, limit, args))
             .map(|(table, xact)| (table, xact.commit().into_inner()))
     }
@@ -153,11 +246,751 @@ impl<'a> CheckedMutCommands for &'a mut SpiClient {
         query: &str,
         limit: Option<i64>,
         args: Option<Vec<(PgOid, Option<Datum>)>>,
-    ) -> Result<Self::Result<SpiTupleTable>, CaughtError> {
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
         // Here we rely on the fact that `SpiClient` can be created at any time. This may not hold true in the future
         // However, we need the client to be consumed by `sub_transaction`, so we do this for now.
+        check_entry(query)?;
         SpiClient
             .sub_transaction(|xact| xact.checked_update(query, limit, args))
-            .map(|(table, _xact): (_, SubTransaction<_, true>)| table)
+            .map(|(table, xact): (_, SubTransaction<_, true>)| {
+                xact.commit();
+                table
+            })
+    }
+}
+
+/// Tuple table returned by a checked command, with SPI's pointer to it, captured right after the command executed (as
+/// more commands may execute before the checked command returns), and the sub-transaction it executed in
+pub(crate) type Executed<X> = (SpiTupleTable, *mut pg_sys::SPITupleTable, X);
+
+/// Execute a read-only command in `xact` as it is, catching errors
+///
+/// `xact` is rolled back without executing the command if it's not Postgres' current sub-transaction or the SPI
+/// connection stack is not as it was when it began.
+pub(crate) fn execute_checked_select<
+    Parent: Deref<Target = SpiClient> + UnwindSafe + RefUnwindSafe,
+>(
+    xact: SubTransaction<Parent, false>,
+    query: &str,
+    limit: Option<i64>,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+) -> Result<Executed<SubTransaction<Parent, false>>, Error> {
+    let started = Instant::now();
+    timeline::record_start(query);
+    let result = xact.check_state().and_then(|_| {
+        capture_sqlstate(|| {
+            PgTryBuilder::new(move || {
+                #[cfg(feature = "testing")]
+                crate::faults::raise_injected(query);
+                #[cfg(feature = "full")]
+                if let Some(table) = crate::config::execute_cached(query, limit, args.as_deref()) {
+                    #[cfg(feature = "testing")]
+                    count_tuple_table();
+                    return Ok((table, unsafe { pg_sys::SPI_tuptable }, xact));
+                }
+                let table = xact.select_unchecked(query, limit, args);
+                #[cfg(feature = "testing")]
+                count_tuple_table();
+                Ok((table, unsafe { pg_sys::SPI_tuptable }, xact))
+            })
+            .catch_others(|e| Err(e))
+            .execute()
+        })
+        .map_err(handled)
+    });
+    metrics::record_checked(Metric::CheckedSelects, started, &result);
+    record_finish(started, &result);
+    result
+}
+
+/// Execute a mutable command in `xact` as it is, catching errors
+///
+/// `xact` is rolled back without executing the command if it's not Postgres' current sub-transaction or the SPI
+/// connection stack is not as it was when it began.
+pub(crate) fn execute_checked_update<
+    Parent: DerefMut<Target = SpiClient> + UnwindSafe + RefUnwindSafe,
+>(
+    mut xact: SubTransaction<Parent, false>,
+    query: &str,
+    limit: Option<i64>,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+) -> Result<Executed<SubTransaction<Parent, false>>, Error> {
+    let started = Instant::now();
+    timeline::record_start(query);
+    let result = xact.check_state().and_then(|_| {
+        capture_sqlstate(|| {
+            PgTryBuilder::new(move || {
+                #[cfg(feature = "testing")]
+                crate::faults::raise_injected(query);
+                #[cfg(feature = "full")]
+                if let Some(table) = crate::config::execute_cached(query, limit, args.as_deref()) {
+                    #[cfg(feature = "testing")]
+                    count_tuple_table();
+                    return Ok((table, unsafe { pg_sys::SPI_tuptable }, xact));
+                }
+                let table = xact.update_unchecked(query, limit, args);
+                #[cfg(feature = "testing")]
+                count_tuple_table();
+                Ok((table, unsafe { pg_sys::SPI_tuptable }, xact))
+            })
+            .catch_others(|e| Err(e))
+            .execute()
+        })
+        .map_err(handled)
+    });
+    metrics::record_checked(Metric::CheckedUpdates, started, &result);
+    record_finish(started, &result);
+    result
+}
+
+/// Record the end of a checked command begun at `started` in the timeline, along with the error it caught
+fn record_finish<T>(started: Instant, result: &Result<T, Error>) {
+    timeline::record(|| EventKind::StatementFinish {
+        duration: started.elapsed(),
+        succeeded: result.is_ok(),
+    });
+    if let Some(sqlstate) = result.as_ref().err().and_then(Error::sqlstate) {
+        timeline::record(|| EventKind::ErrorCaught { sqlstate });
+    }
+}
+
+/// Check whether a checked command starting a sub-transaction can execute `query`
+pub(crate) fn check_entry(query: &str) -> Result<(), Error> {
+    check_query(query)?;
+    check_can_begin()
+}
+
+/// Check whether a checked command can execute `query`
+fn check_query(query: &str) -> Result<(), Error> {
+    if is_empty_query(query) {
+        return Err(Error::EmptyQuery);
+    }
+    if !TRANSACTION_CONTROL_ALLOWED.with(Cell::get) {
+        if let Some(statement_kind) = transaction_control_kind(query) {
+            return Err(Error::TransactionControlNotAllowed { statement_kind });
+        }
+    }
+    Ok(())
+}
+
+thread_local! {
+    static TRANSACTION_CONTROL_ALLOWED: Cell<bool> = const { Cell::new(false) };
+}
+
+#[cfg(feature = "testing")]
+thread_local! {
+    static TUPLE_TABLES_BUILT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Number of tuple tables SPI built for checked commands in this backend, to test which commands build one
+///
+/// Only compiled with the `testing` feature.
+#[cfg(feature = "testing")]
+pub fn tuple_tables_built() -> u64 {
+    TUPLE_TABLES_BUILT.with(Cell::get)
+}
+
+/// Count the tuple table SPI built for the command just executed, if it built one
+#[cfg(feature = "testing")]
+fn count_tuple_table() {
+    if unsafe { !pg_sys::SPI_tuptable.is_null() } {
+        TUPLE_TABLES_BUILT.with(|built| built.set(built.get() + 1));
+    }
+}
+
+/// Let checked commands execute transaction control statements, until the returned guard is dropped
+///
+/// Checked commands reject queries containing transaction control statements (`BEGIN`, `START TRANSACTION`, `COMMIT`,
+/// `END`, `ROLLBACK`, `ABORT`, `SAVEPOINT`, `RELEASE` and `PREPARE TRANSACTION`, including `COMMIT PREPARED` and
+/// `ROLLBACK PREPARED`) with [`Error::TransactionControlNotAllowed`] before starting a sub-transaction. While allowed,
+/// they are passed to SPI as they are, which is unsupported: SPI refuses most of them (returning
+/// [`Error::Spi`]), and any that would execute could end the sub-transactions this crate relies on.
+#[must_use = "transaction control statements are rejected again when the guard is dropped"]
+pub fn allow_transaction_control() -> TransactionControlGuard {
+    TransactionControlGuard {
+        previous: TRANSACTION_CONTROL_ALLOWED.with(|allowed| allowed.replace(true)),
+    }
+}
+
+/// Restores whether transaction control statements are allowed when dropped, see [`allow_transaction_control`]
+#[derive(Debug)]
+pub struct TransactionControlGuard {
+    previous: bool,
+}
+
+impl Drop for TransactionControlGuard {
+    fn drop(&mut self) {
+        TRANSACTION_CONTROL_ALLOWED.with(|allowed| allowed.set(self.previous));
+    }
+}
+
+/// Run `f` in a new sub-transaction, committing it if `f` succeeds and rolling it back if it raises an error
+pub(crate) fn checked_sub_transaction<R, F: FnOnce(&mut SpiClient) -> R + UnwindSafe>(
+    f: F,
+) -> Result<R, Error> {
+    checked_sub_transaction_of(SpiClient, f)
+}
+
+/// Run `f` in a new sub-transaction of `client`, as [`checked_sub_transaction`] does
+pub(crate) fn checked_sub_transaction_of<C, R, F>(client: C, f: F) -> Result<R, Error>
+where
+    C: SubTransactionExt<T = SpiClientWrapper>,
+    F: FnOnce(&mut SpiClient) -> R + UnwindSafe,
+{
+    check_can_begin()?;
+    client.sub_transaction(|xact| {
+        let mut xact = xact.rollback_on_drop();
+        capture_sqlstate(|| {
+            PgTryBuilder::new(move || Ok((f(&mut xact), xact)))
+                .catch_others(|e| Err(e))
+                .execute()
+        })
+        .map(|(result, xact)| {
+            xact.commit();
+            result
+        })
+        .map_err(handled)
+    })
+}
+
+/// Read-only commands that can execute during a parallel operation
+pub trait ParallelSafeCommands {
+    /// Execute a read-only command, falling back to executing it without a sub-transaction during a parallel
+    /// operation
+    ///
+    /// Outside of a parallel operation, this is the same as `checked_select`. During one (e.g. in a parallel
+    /// worker), no sub-transaction can be started, so the command is executed directly: **errors are raised rather
+    /// than returned** and abort the enclosing statement, as with `SpiClient::select`.
+    fn checked_select_no_subtxn(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<SpiTupleTable, Error>;
+}
+
+impl ParallelSafeCommands for SpiClient {
+    fn checked_select_no_subtxn(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        check_query(query)?;
+        if unsafe { pg_sys::IsInParallelMode() } {
+            Ok(self.select(query, None, args))
+        } else {
+            self.checked_select(query, None, args)
+        }
+    }
+}
+
+/// Read-only commands that don't advance the command counter
+pub trait NoCciCommands {
+    /// Execute a read-only command without advancing the command counter or taking a new snapshot, returning an
+    /// error if one occurred.
+    ///
+    /// Commands executed through pgx (including all checked commands) advance the command counter and take a new
+    /// snapshot first, so they see every change made before them. This one is executed in SPI's read-only mode
+    /// instead, using the snapshot that was active when the calling function was entered, so none of the changes
+    /// made since are visible to it, regardless of [`SubTransaction::advance_command_counter`]. This works the same
+    /// in all supported Postgres versions.
+    ///
+    /// Read-only mode rejects commands that make changes, so mutable commands can't skip advancing the command
+    /// counter.
+    fn checked_select_no_cci(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<OwnedRows, Error>;
+}
+
+impl NoCciCommands for SpiClient {
+    fn checked_select_no_cci(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<OwnedRows, Error> {
+        check_entry(query)?;
+        let query = CString::new(query).expect("query contains a NUL byte");
+        let (mut arg_types, mut values, nulls) = raw_args(args);
+        checked_sub_transaction(move |_| unsafe {
+            #[cfg(feature = "testing")]
+            crate::faults::raise_injected(query.to_str().unwrap());
+            let status = pg_sys::SPI_execute_with_args(
+                query.as_ptr(),
+                arg_types.len() as i32,
+                arg_types.as_mut_ptr(),
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                true,
+                limit.unwrap_or(0),
+            );
+            if status < 0 {
+                spi_error_panic("SPI_execute_with_args", status);
+            }
+            let rows = OwnedRows::from_raw(pg_sys::SPI_tuptable, pg_sys::SPI_processed);
+            pg_sys::SPI_freetuptable(pg_sys::SPI_tuptable);
+            pg_sys::SPI_tuptable = std::ptr::null_mut();
+            rows
+        })
+    }
+}
+
+/// Snapshot a command executed with [`SelectOptsCommands::checked_select_opts`] sees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotMode {
+    /// Advance the command counter and take a new snapshot, seeing every change made before the command, as
+    /// `checked_select` does
+    #[default]
+    Fresh,
+    /// Use the snapshot that was active when the calling function was entered, as
+    /// [`NoCciCommands::checked_select_no_cci`] does
+    ///
+    /// Only possible in SPI's read-only mode.
+    Caller,
+}
+
+/// How [`SelectOptsCommands::checked_select_opts`] executes a command
+///
+/// The default executes it as `checked_select` does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SelectOpts {
+    /// Execute the command in SPI's read-only mode, which rejects commands that make changes with
+    /// [`Error::ReadOnlyViolation`]
+    ///
+    /// `checked_select` (like pgx's `select`) doesn't use read-only mode, so commands that are conceptually reads but
+    /// make changes (such as a `SELECT` from a data-modifying `WITH` query) work, as they do when this is unset.
+    pub read_only: bool,
+    pub snapshot: SnapshotMode,
+}
+
+/// Read-only commands with explicit SPI options
+pub trait SelectOptsCommands {
+    /// Execute a command returning rows with `opts`, returning an error if one occurred.
+    ///
+    /// Without [`SelectOpts::read_only`], this is the same as `checked_select`. With it, the command must be a single
+    /// statement returning rows, as it is opened as a cursor (in a sub-transaction of its own, like other checked
+    /// commands) to return a tuple table; rewrite rules and statement tags don't apply to it.
+    ///
+    /// Panics if `opts.snapshot` is [`SnapshotMode::Caller`] without `opts.read_only`.
+    fn checked_select_opts(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+        opts: SelectOpts,
+    ) -> Result<SpiTupleTable, Error>;
+}
+
+impl SelectOptsCommands for SpiClient {
+    fn checked_select_opts(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+        opts: SelectOpts,
+    ) -> Result<SpiTupleTable, Error> {
+        if !opts.read_only {
+            assert!(
+                opts.snapshot == SnapshotMode::Fresh,
+                "the caller's snapshot can only be used in read-only mode"
+            );
+            return self.checked_select(query, limit, args);
+        }
+        check_entry(query)?;
+        let query = CString::new(query).expect("query contains a NUL byte");
+        let (mut arg_types, mut values, nulls) = raw_args(args);
+        checked_sub_transaction(move |_| unsafe {
+            #[cfg(feature = "testing")]
+            crate::faults::raise_injected(query.to_str().unwrap());
+            // In read-only mode, SPI uses the active snapshot as it is
+            let fresh = opts.snapshot == SnapshotMode::Fresh;
+            if fresh {
+                pg_sys::CommandCounterIncrement();
+                pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot());
+            }
+            let portal = pg_sys::SPI_cursor_open_with_args(
+                std::ptr::null(),
+                query.as_ptr(),
+                arg_types.len() as i32,
+                arg_types.as_mut_ptr(),
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                true,
+                0,
+            );
+            // The portal keeps a copy of the snapshot
+            if fresh {
+                pg_sys::PopActiveSnapshot();
+            }
+            let name = CStr::from_ptr((*portal).name)
+                .to_string_lossy()
+                .into_owned();
+            let count = limit.filter(|limit| *limit > 0).unwrap_or(i64::MAX);
+            // The portal is closed explicitly once fetched from. Don't let an error close it while it is active, as
+            // rolling back the sub-transaction drops it
+            let mut cursor = ManuallyDrop::new(SpiClient.find_cursor(&name));
+            let table = cursor.fetch(count);
+            #[cfg(feature = "testing")]
+            count_tuple_table();
+            pg_sys::SPI_cursor_close(portal);
+            table
+        })
+        .map_err(|err| match err {
+            Error::Caught(err, captured) if is_read_only_violation(&err) => {
+                Error::ReadOnlyViolation(err, captured)
+            }
+            err => err,
+        })
+    }
+}
+
+/// Whether SPI rejected the error's command for making changes in read-only mode
+///
+/// Identified by where it was raised rather than by its message, which is translated. The other errors SPI raises
+/// there (such as for a query that can't be opened as a cursor) have different SQLSTATEs.
+fn is_read_only_violation(err: &CaughtError) -> bool {
+    let report = report(err);
+    report.sqlstate().as_str() == "0A000"
+        && report.file().ends_with("spi.c")
+        && matches!(
+            report.function_name(),
+            Some("SPI_cursor_open_internal" | "_SPI_execute_plan")
+        )
+}
+
+/// Commands returning a single value, freeing the rows SPI returned before returning it
+pub trait GetOneCommands {
+    /// Execute a command and return the first column of its first row, converted in place, returning an error if one
+    /// occurred.
+    ///
+    /// Returns `None` if the command returned no rows, and `Some(None)` if the value is NULL (see
+    /// [`checked_get_one_tristate`](Self::checked_get_one_tristate) for a type naming them). The command is executed
+    /// with a limit of 1 in a sub-transaction of its own, as `checked_select` does, but its rows are read directly from
+    /// SPI and freed before returning. Values of types passed by reference (which are detoasted) are copied into the
+    /// calling memory context before being converted, so they outlive the command. Rewrite rules, statement tags and
+    /// the plan cache don't apply to the command.
+    fn checked_get_one_fast<T: FromDatum>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<Option<Option<T>>, Error>;
+
+    /// Execute a command and return the first column of its first row as a [`SqlValue`], returning an error if one
+    /// occurred.
+    ///
+    /// Executed as [`checked_get_one_fast`](Self::checked_get_one_fast) is, reporting NULL as [`SqlValue::Null`]
+    /// and no rows as [`SqlValue::NoRow`].
+    fn checked_get_one_tristate<T: FromDatum>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<SqlValue<T>, Error> {
+        self.checked_get_one_fast(query, args).map(SqlValue::from)
+    }
+}
+
+impl GetOneCommands for SpiClient {
+    fn checked_get_one_fast<T: FromDatum>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<Option<Option<T>>, Error> {
+        check_entry(query)?;
+        let query = CString::new(query).expect("query contains a NUL byte");
+        let (mut arg_types, mut values, nulls) = raw_args(args);
+        let caller = PgMemoryContexts::CurrentMemoryContext.value();
+        checked_sub_transaction(move |_| unsafe {
+            #[cfg(feature = "testing")]
+            crate::faults::raise_injected(query.to_str().unwrap());
+            let status = pg_sys::SPI_execute_with_args(
+                query.as_ptr(),
+                arg_types.len() as i32,
+                arg_types.as_mut_ptr(),
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                false,
+                1,
+            );
+            if status < 0 {
+                spi_error_panic("SPI_execute_with_args", status);
+            }
+            #[cfg(feature = "testing")]
+            count_tuple_table();
+            let table = pg_sys::SPI_tuptable;
+            if table.is_null() {
+                return None;
+            }
+            let value = (pg_sys::SPI_processed > 0).then(|| {
+                let desc = (*table).tupdesc;
+                let mut is_null = false;
+                let datum = pg_sys::SPI_getbinval(*(*table).vals, desc, 1, &mut is_null);
+                if is_null {
+                    return None;
+                }
+                let mut typlen = 0;
+                let mut typbyval = false;
+                pg_sys::get_typlenbyval(pg_sys::SPI_gettypeid(desc, 1), &mut typlen, &mut typbyval);
+                // The tuple table is freed below, so values passed by reference must be copied out of it first
+                PgMemoryContexts::For(caller).switch_to(|_| {
+                    let datum = match (typbyval, typlen) {
+                        (true, _) => datum,
+                        (false, -1) => {
+                            Datum::from(pg_sys::pg_detoast_datum_copy(datum.cast_mut_ptr()))
+                        }
+                        (false, typlen) => pg_sys::datumCopy(datum, false, typlen as i32),
+                    };
+                    T::from_datum(datum, false)
+                })
+            });
+            pg_sys::SPI_freetuptable(table);
+            pg_sys::SPI_tuptable = std::ptr::null_mut();
+            value
+        })
+    }
+}
+
+/// Panic with the error code SPI would return for `query` rather than execute it, before pgx executes it (pgx panics
+/// with the code's name only)
+///
+/// Once connected, SPI only refuses to execute transaction control statements and `COPY` from or to the client, which
+/// it tells from the parsed statements, so `query` is parsed here as well.
+pub(crate) fn check_spi_refusal(query: &str) {
+    let c_query = CString::new(query).expect("query contains a NUL byte");
+    let statements =
+        unsafe { PgList::<pg_sys::RawStmt>::from_pg(pg_sys::pg_parse_query(c_query.as_ptr())) };
+    for raw in statements.iter_ptr() {
+        let refused = unsafe {
+            let stmt = (*raw).stmt;
+            match (*stmt).type_ {
+                pg_sys::NodeTag::T_TransactionStmt => Some(SpiErrorCode::Transaction),
+                pg_sys::NodeTag::T_CopyStmt
+                    if (*(stmt as *mut pg_sys::CopyStmt)).filename.is_null() =>
+                {
+                    Some(SpiErrorCode::Copy)
+                }
+                _ => None,
+            }
+        };
+        if let Some(code) = refused {
+            std::panic::panic_any(code);
+        }
+    }
+}
+
+/// Split arguments into the arrays SPI takes: their types, their values, and whether each is NULL (`n`) or not
+pub(crate) fn raw_args(
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+) -> (Vec<pg_sys::Oid>, Vec<Datum>, Vec<c_char>) {
+    let args = args.unwrap_or_default();
+    let arg_types = args.iter().map(|(oid, _)| oid.value()).collect();
+    let values = args
+        .iter()
+        .map(|(_, datum)| datum.unwrap_or_else(|| Datum::from(0)))
+        .collect();
+    let nulls = args
+        .iter()
+        .map(|(_, datum)| if datum.is_some() { b' ' } else { b'n' } as c_char)
+        .collect();
+    (arg_types, values, nulls)
+}
+
+/// Read-only commands whose rows never leave their sub-transaction
+pub trait FoldCommands {
+    /// Execute a read-only command and fold its rows into `init` with `f`, returning only the result.
+    ///
+    /// The command executes in a sub-transaction that is rolled back once the rows are folded (or if an error is
+    /// raised or `f` panics), releasing the tuple table along with everything else the command acquired. The rows
+    /// are only valid while `f` runs: the accumulator must be a plain Rust value (hence the `'static` bound), and
+    /// copying anything that points into a row (such as a by-reference datum) into it is the caller's
+    /// responsibility and leads to undefined behavior once the sub-transaction is gone.
+    fn checked_select_fold<Acc: 'static, F: FnMut(Acc, &SpiHeapTupleData) -> Acc>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+        init: Acc,
+        f: F,
+    ) -> Result<Acc, Error>;
+}
+
+impl FoldCommands for SpiClient {
+    fn checked_select_fold<Acc: 'static, F: FnMut(Acc, &SpiHeapTupleData) -> Acc>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+        init: Acc,
+        f: F,
+    ) -> Result<Acc, Error> {
+        check_entry(query)?;
+        // Panics are propagated past `init` and `f`, so neither is observed in a broken state
+        let captured = AssertUnwindSafe((init, f));
+        SpiClient.sub_transaction(|xact| {
+            let xact = xact.rollback_on_drop();
+            capture_sqlstate(|| {
+                PgTryBuilder::new(move || {
+                    let captured = captured;
+                    let AssertUnwindSafe((init, mut f)) = captured;
+                    #[cfg(feature = "testing")]
+                    crate::faults::raise_injected(query);
+                    let table = xact.select_unchecked(query, None, args);
+                    #[cfg(feature = "testing")]
+                    count_tuple_table();
+                    let acc = table.fold(init, |acc, row| f(acc, &row));
+                    xact.rollback();
+                    Ok(acc)
+                })
+                .catch_others(|e| Err(e))
+                .execute()
+            })
+            .map_err(handled)
+        })
+    }
+}
+
+/// Commands returning tuple tables that are freed when dropped
+pub trait FreedCommands {
+    /// Execute a read-only command as [`CheckedCommands::checked_select`] does, returning a tuple table that is freed
+    /// when dropped
+    fn checked_select_freed(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<CheckedTupleTable, Error>;
+
+    /// Execute a mutable command as [`CheckedMutCommands::checked_update`] does, returning a tuple table (of the rows
+    /// it returned) that is freed when dropped
+    fn checked_update_freed(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<CheckedTupleTable, Error>;
+}
+
+impl FreedCommands for SpiClient {
+    fn checked_select_freed(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<CheckedTupleTable, Error> {
+        check_entry(query)?;
+        SpiClient
+            .sub_transaction(|xact| {
+                checked_select_captured(xact.rollback_on_drop(), query, limit, args)
+            })
+            .map(|(table, raw, xact)| {
+                xact.commit();
+                CheckedTupleTable::new(table, raw)
+            })
+    }
+
+    fn checked_update_freed(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<CheckedTupleTable, Error> {
+        check_entry(query)?;
+        SpiClient
+            .sub_transaction(|xact| {
+                checked_update_captured(xact.rollback_on_drop(), query, limit, args)
+            })
+            .map(|(table, raw, xact)| {
+                xact.commit();
+                CheckedTupleTable::new(table, raw)
+            })
+    }
+}
+
+/// Tuple table that is freed when dropped, see [`FreedCommands`]
+///
+/// Tuple tables returned by pgx are only freed when the SPI connection is closed (or the sub-transaction they were
+/// returned in is rolled back), so the ones built in a loop accumulate until the calling function returns. This one
+/// is freed as soon as it is dropped, unless SPI has already freed it: if the sub-transaction it was returned in is
+/// no longer active (it was committed or rolled back since), or the SPI connection or the transaction it was returned
+/// in has ended, dropping it leaves it to SPI.
+///
+/// It dereferences to [`SpiTupleTable`], and iterates over its rows as it does.
+pub struct CheckedTupleTable {
+    table: Option<SpiTupleTable>,
+    raw: *mut pg_sys::SPITupleTable,
+    returned_in: pg_sys::SubTransactionId,
+    transaction: pg_sys::LocalTransactionId,
+    spi_marker: SpiMarker,
+}
+
+impl CheckedTupleTable {
+    /// `raw` is SPI's pointer to `table`, as captured right after the command returning it executed
+    fn new(table: SpiTupleTable, raw: *mut pg_sys::SPITupleTable) -> Self {
+        Self {
+            table: Some(table),
+            raw,
+            returned_in: unsafe { pg_sys::GetCurrentSubTransactionId() },
+            transaction: unsafe { (*pg_sys::MyProc).lxid },
+            spi_marker: SpiMarker::current(),
+        }
+    }
+
+    /// Position the table on its first row, as [`SpiTupleTable::first`] does
+    pub fn first(mut self) -> Self {
+        self.table = self.table.take().map(SpiTupleTable::first);
+        self
+    }
+
+    /// Copy the rows out of the table, freeing it
+    pub fn into_owned_rows(mut self) -> OwnedRows {
+        OwnedRows::from_table(
+            self.table
+                .take()
+                .expect("tuple table is present until dropped"),
+        )
+    }
+
+    /// Whether SPI still holds the table, which is only checked for the SPI connection it was returned in
+    fn is_held(&self) -> bool {
+        !self.raw.is_null()
+            && unsafe {
+                (*pg_sys::MyProc).lxid == self.transaction
+                    && pg_sys::SubTransactionIsActive(self.returned_in)
+            }
+            && SpiMarker::current() == self.spi_marker
+    }
+}
+
+impl Deref for CheckedTupleTable {
+    type Target = SpiTupleTable;
+
+    fn deref(&self) -> &Self::Target {
+        self.table
+            .as_ref()
+            .expect("tuple table is present until dropped")
+    }
+}
+
+impl DerefMut for CheckedTupleTable {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.table
+            .as_mut()
+            .expect("tuple table is present until dropped")
+    }
+}
+
+impl Iterator for CheckedTupleTable {
+    type Item = SpiHeapTupleData;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.deref_mut().next()
+    }
+}
+
+impl Drop for CheckedTupleTable {
+    fn drop(&mut self) {
+        self.table = None;
+        if self.is_held() {
+            unsafe { pg_sys::SPI_freetuptable(self.raw) };
+        }
     }
 }
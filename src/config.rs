@@ -0,0 +1,663 @@
+//! Backend-wide configuration of checked commands
+//!
+//! # Plan cache
+//!
+//! With [`enable_plan_cache`], [`checked_select`](crate::checked::CheckedCommands::checked_select) and
+//! [`checked_update`](crate::checked::CheckedMutCommands::checked_update) keep the plans of the queries they execute
+//! (with `SPI_keepplan`), so executing the same query text again with the same argument types reuses its plan rather
+//! than parsing and planning it anew. Plans are kept in `CacheMemoryContext`, so they outlive the sub-transactions and
+//! transactions they were prepared in, and the least recently used one is freed when the cache is full.
+//!
+//! Only queries consisting of a single `SELECT` statement are cached: pgx only wraps the rows of saved plans in
+//! tuple tables when they are fetched through a cursor, which other commands (such as `INSERT` or `CREATE TABLE`)
+//! can't be executed with, and preparing several statements at once would analyze the later ones before the earlier
+//! ones executed. Other queries are executed as they are without the cache.
+//!
+//! The search path and the current user are part of the key, so changing either makes queries resolve names anew,
+//! with a different entry. Entries are freed when an invalidation of a relation they use is observed, when any
+//! function, type or schema is invalidated, and when their plan was invalidated by other means (such as
+//! `DISCARD ALL`).
+//!
+//! # Automatic EXPLAIN
+//!
+//! With [`auto_explain`], the plan of every `checked_select` or `checked_update` that succeeds but takes longer than
+//! a threshold is captured right away, as the plan chosen when the query is investigated later is often different.
+//! The query is explained (never analyzed, so it isn't executed twice) with the same arguments in a sub-transaction
+//! of the command's that is always rolled back, and the plan is logged or inserted into a table. Failing to do so
+//! doesn't fail the command: the error is reported as a warning. While automatic EXPLAIN is off, commands don't
+//! measure their duration at all.
+//!
+//! # Statement tags
+//!
+//! With [`set_statement_tag`] (or [`SubTransaction::set_tag`] for the commands of a sub-transaction), checked
+//! commands prepend a `/* spiext:<tag> */` comment to their text, after [`rewrite`](crate::rewrite) hooks apply so
+//! that they can't remove it, and report the tagged text as the backend's activity. Until the client's next
+//! statement, `pg_stat_activity` thus shows the last tagged command executed through this crate rather than the
+//! statement that called it, which tells them apart during incidents; [`tagged_activity`] lists the backends doing
+//! so. Commands executed with [`RawCommands`](crate::rewrite::RawCommands) are not tagged.
+//!
+//! # Long-held sub-transactions
+//!
+//! With [`set_subtxn_duration_warning`], a sub-transaction that was open for longer than a threshold emits a warning
+//! when it ends, with (in debug builds) the backtrace of where it was begun, which catches slow work done outside the
+//! database while its locks and snapshot are held. With [`set_subtxn_no_progress_warning`], so does one whose last
+//! [`SubTransaction::heartbeat`] (or beginning) is older than a threshold. Either way, sub-transactions only record
+//! when they began, and check nothing else while the warnings are off.
+
+use pgx::{
+    pg_guard, pg_sys, pg_sys::Datum, IntoDatum, PgBuiltInOids, PgList, PgOid, SpiClient,
+    SpiTupleTable, TimestampWithTimeZone,
+};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::metrics::{self, Metric};
+use crate::quote::quote_qualified_identifier;
+use crate::rewrite::RawCommands;
+use crate::scan::is_single_statement;
+use crate::subtxn::SubTransaction;
+
+/// Counters of the plan cache, see [`plan_cache_stats`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PlanCacheStats {
+    /// Executions that reused a cached plan
+    pub hits: u64,
+    /// Executions that prepared a plan, whether it was cached or not
+    pub misses: u64,
+    /// Entries freed because they were invalidated
+    pub invalidations: u64,
+    /// Entries currently cached, including those of queries that can't be cached
+    pub entries: usize,
+    /// Estimate of the memory used by the cached plans, in bytes (`None` before Postgres 13, which doesn't track it)
+    pub memory_bytes: Option<usize>,
+}
+
+#[derive(PartialEq, Eq)]
+struct Key {
+    query: String,
+    arg_types: Vec<pg_sys::Oid>,
+    search_path: String,
+    user: pg_sys::Oid,
+}
+
+struct Entry {
+    key: Key,
+    /// Saved plan, or `None` if the query can't be cached
+    plan: Option<pg_sys::SPIPlanPtr>,
+    /// Relations the plan uses
+    relations: Vec<pg_sys::Oid>,
+}
+
+impl Entry {
+    unsafe fn is_valid(&self) -> bool {
+        self.plan.map_or(true, |plan| {
+            plan_sources(plan)
+                .iter_ptr()
+                .all(|source| (*source).is_valid)
+        })
+    }
+
+    fn free(self) {
+        if let Some(plan) = self.plan {
+            unsafe { pg_sys::SPI_freeplan(plan) };
+        }
+    }
+}
+
+struct PlanCache {
+    max_entries: usize,
+    /// Least recently used first
+    entries: Vec<Entry>,
+    stats: PlanCacheStats,
+}
+
+impl PlanCache {
+    /// Free the entries invalidated since the last call
+    fn apply_invalidations(&mut self) {
+        let all = INVALIDATE_ALL.with(|all| all.replace(false));
+        let relations = INVALIDATED_RELATIONS.with(|relations| relations.take());
+        let entries = std::mem::take(&mut self.entries);
+        for entry in entries {
+            let invalidated = all
+                || entry
+                    .relations
+                    .iter()
+                    .any(|relation| relations.contains(relation))
+                || !unsafe { entry.is_valid() };
+            if invalidated {
+                self.stats.invalidations += 1;
+                entry.free();
+            } else {
+                self.entries.push(entry);
+            }
+        }
+    }
+
+    fn trim(&mut self) {
+        let excess = self.entries.len().saturating_sub(self.max_entries);
+        self.entries.drain(..excess).for_each(Entry::free);
+    }
+}
+
+thread_local! {
+    static PLAN_CACHE: RefCell<Option<PlanCache>> = RefCell::new(None);
+    static INVALIDATE_ALL: Cell<bool> = const { Cell::new(false) };
+    static INVALIDATED_RELATIONS: RefCell<Vec<pg_sys::Oid>> = RefCell::new(Vec::new());
+}
+
+static REGISTER_CALLBACKS: Once = Once::new();
+
+/// Catalog caches whose invalidation invalidates all cached plans
+const SYSCACHES: [pg_sys::SysCacheIdentifier; 3] = [
+    pg_sys::SysCacheIdentifier_PROCOID,
+    pg_sys::SysCacheIdentifier_TYPEOID,
+    pg_sys::SysCacheIdentifier_NAMESPACEOID,
+];
+
+#[pg_guard]
+unsafe extern "C" fn syscache_callback(_arg: pg_sys::Datum, _cacheid: i32, _hashvalue: u32) {
+    INVALIDATE_ALL.with(|all| all.set(true));
+}
+
+#[pg_guard]
+unsafe extern "C" fn relcache_callback(_arg: pg_sys::Datum, relid: pg_sys::Oid) {
+    if relid == pg_sys::InvalidOid {
+        INVALIDATE_ALL.with(|all| all.set(true));
+    } else {
+        INVALIDATED_RELATIONS.with(|relations| match relations.try_borrow_mut() {
+            Ok(mut relations) => {
+                if !relations.contains(&relid) {
+                    relations.push(relid);
+                }
+            }
+            // The list is borrowed elsewhere, and invalidating all plans is never wrong
+            Err(_) => INVALIDATE_ALL.with(|all| all.set(true)),
+        });
+    }
+}
+
+fn register_callbacks() {
+    REGISTER_CALLBACKS.call_once(|| unsafe {
+        for cache in SYSCACHES {
+            pg_sys::CacheRegisterSyscacheCallback(
+                cache as i32,
+                Some(syscache_callback),
+                pg_sys::Datum::from(0usize),
+            );
+        }
+        pg_sys::CacheRegisterRelcacheCallback(Some(relcache_callback), pg_sys::Datum::from(0usize));
+    });
+}
+
+/// Cache the plans of up to `max_entries` queries executed by checked commands in this backend
+///
+/// If the cache is already enabled, only its size changes, freeing the least recently used entries that no longer
+/// fit. Panics if `max_entries` is 0.
+pub fn enable_plan_cache(max_entries: usize) {
+    assert!(max_entries > 0, "plan cache size must be positive");
+    register_callbacks();
+    PLAN_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let cache = cache.get_or_insert_with(|| PlanCache {
+            max_entries,
+            entries: Vec::new(),
+            stats: PlanCacheStats::default(),
+        });
+        cache.max_entries = max_entries;
+        cache.trim();
+    });
+}
+
+/// Stop caching plans, freeing the cached ones and resetting the counters
+pub fn disable_plan_cache() {
+    if let Some(cache) = PLAN_CACHE.with(|cache| cache.borrow_mut().take()) {
+        cache.entries.into_iter().for_each(Entry::free);
+    }
+}
+
+/// Counters of the plan cache, or `None` if it isn't enabled
+///
+/// Invalidations observed since the last checked command are applied first.
+pub fn plan_cache_stats() -> Option<PlanCacheStats> {
+    PLAN_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let cache = cache.as_mut()?;
+        cache.apply_invalidations();
+        let mut stats = cache.stats;
+        stats.entries = cache.entries.len();
+        stats.memory_bytes = memory_bytes(&cache.entries);
+        Some(stats)
+    })
+}
+
+#[cfg(any(feature = "pg11", feature = "pg12"))]
+fn memory_bytes(_entries: &[Entry]) -> Option<usize> {
+    None
+}
+
+#[cfg(not(any(feature = "pg11", feature = "pg12")))]
+fn memory_bytes(entries: &[Entry]) -> Option<usize> {
+    let bytes = entries
+        .iter()
+        .filter_map(|entry| entry.plan)
+        .flat_map(|plan| unsafe { plan_sources(plan) }.iter_ptr().collect::<Vec<_>>())
+        .map(|source| unsafe {
+            let mut bytes = pg_sys::MemoryContextMemAllocated((*source).context, true);
+            if !(*source).gplan.is_null() {
+                bytes += pg_sys::MemoryContextMemAllocated((*(*source).gplan).context, true);
+            }
+            bytes
+        })
+        .sum();
+    Some(bytes)
+}
+
+unsafe fn plan_sources(plan: pg_sys::SPIPlanPtr) -> PgList<pg_sys::CachedPlanSource> {
+    PgList::from_pg(pg_sys::SPI_plan_get_plan_sources(plan))
+}
+
+/// Execute `query` with its cached plan, preparing and caching it if needed
+///
+/// Returns `None` without executing anything if the cache isn't enabled or the query can't be cached.
+pub(crate) fn execute_cached(
+    query: &str,
+    limit: Option<i64>,
+    args: Option<&[(PgOid, Option<Datum>)]>,
+) -> Option<SpiTupleTable> {
+    if PLAN_CACHE.with(|cache| cache.borrow().is_none()) || !is_single_statement(query) {
+        return None;
+    }
+    let args = args.unwrap_or_default();
+    let key = Key {
+        query: query.to_string(),
+        arg_types: args.iter().map(|(oid, _)| oid.value()).collect(),
+        search_path: unsafe { CStr::from_ptr(pg_sys::namespace_search_path) }
+            .to_string_lossy()
+            .into_owned(),
+        user: unsafe { pg_sys::GetUserId() },
+    };
+    // Observe the invalidations caused by the changes made so far, as executing the query would
+    unsafe { pg_sys::CommandCounterIncrement() };
+    let plan = match lookup(&key) {
+        Some(plan) => plan?,
+        None => unsafe { prepare(key)? },
+    };
+
+    let mut values: Vec<Datum> = args
+        .iter()
+        .map(|(_, datum)| datum.unwrap_or_else(|| Datum::from(0)))
+        .collect();
+    let nulls: Vec<c_char> = args
+        .iter()
+        .map(|(_, datum)| if datum.is_some() { b' ' } else { b'n' } as c_char)
+        .collect();
+    let name = unsafe {
+        let portal = pg_sys::SPI_cursor_open(
+            std::ptr::null(),
+            plan,
+            values.as_mut_ptr(),
+            nulls.as_ptr(),
+            false,
+        );
+        CStr::from_ptr((*portal).name)
+            .to_string_lossy()
+            .into_owned()
+    };
+    let count = limit.filter(|limit| *limit > 0).unwrap_or(i64::MAX);
+    // The cursor is closed once the rows are fetched
+    Some(SpiClient.find_cursor(&name).fetch(count))
+}
+
+/// Plan of a cached query (`None` if it can't be cached), counting a hit if there is one
+fn lookup(key: &Key) -> Option<Option<pg_sys::SPIPlanPtr>> {
+    PLAN_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let cache = cache.as_mut()?;
+        cache.apply_invalidations();
+        let index = cache.entries.iter().position(|entry| entry.key == *key)?;
+        let entry = cache.entries.remove(index);
+        let plan = entry.plan;
+        cache.entries.push(entry);
+        if plan.is_some() {
+            cache.stats.hits += 1;
+            metrics::add(Metric::PlanCacheHits, 1);
+        }
+        Some(plan)
+    })
+}
+
+/// Prepare the query of `key` and cache its plan, returning it if it can be cached
+unsafe fn prepare(key: Key) -> Option<pg_sys::SPIPlanPtr> {
+    let query = CString::new(key.query.as_str()).expect("query contains a NUL byte");
+    let mut arg_types = key.arg_types.clone();
+    let plan = pg_sys::SPI_prepare(
+        query.as_ptr(),
+        arg_types.len() as i32,
+        arg_types.as_mut_ptr(),
+    );
+    if plan.is_null() {
+        // Executing the query reports the error
+        return None;
+    }
+    let sources = plan_sources(plan);
+    let relations = sources
+        .iter_ptr()
+        .flat_map(|source| {
+            PgList::<pg_sys::Oid>::from_pg((*source).relationOids)
+                .iter_oid()
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let cacheable = sources.len() == 1
+        && sources.iter_ptr().all(|source| {
+            let queries = PgList::<pg_sys::Query>::from_pg((*source).query_list);
+            queries.len() == 1
+                && queries.iter_ptr().all(|query| {
+                    (*query).commandType == pg_sys::CmdType_CMD_SELECT
+                        && (*query).utilityStmt.is_null()
+                        && !(*query).hasModifyingCTE
+                })
+        });
+    let plan = if cacheable && pg_sys::SPI_keepplan(plan) == 0 {
+        Some(plan)
+    } else {
+        pg_sys::SPI_freeplan(plan);
+        None
+    };
+    let entry = Entry {
+        key,
+        plan,
+        relations,
+    };
+    PLAN_CACHE.with(|cache| match cache.borrow_mut().as_mut() {
+        Some(cache) => {
+            cache.stats.misses += 1;
+            metrics::add(Metric::PlanCacheMisses, 1);
+            cache.entries.push(entry);
+            cache.trim();
+            plan
+        }
+        // Disabled while the query was prepared
+        None => {
+            entry.free();
+            None
+        }
+    })
+}
+
+/// Where [`auto_explain`] reports the plans of slow commands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AutoExplainTarget {
+    /// The server log, at `LOG` level
+    ServerLog,
+    /// A table, possibly schema-qualified (`schema.name`), with the columns `(logged_at timestamptz, query text,
+    /// duration interval, plan text)` in this order
+    ///
+    /// Rows are inserted in the command's transaction, so they are only kept if it commits.
+    Table { name: String },
+}
+
+struct AutoExplain {
+    threshold: Duration,
+    target: AutoExplainTarget,
+}
+
+thread_local! {
+    static AUTO_EXPLAIN: RefCell<Option<AutoExplain>> = RefCell::new(None);
+    /// Whether a plan is being reported, so that the commands doing so are not explained themselves
+    static EXPLAINING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Report the plans of the checked commands of this backend that take longer than `threshold` to `target`
+///
+/// Only queries consisting of a single statement are explained, so that no statement is executed again.
+pub fn auto_explain(threshold: Duration, target: AutoExplainTarget) {
+    AUTO_EXPLAIN.with(|explain| *explain.borrow_mut() = Some(AutoExplain { threshold, target }));
+}
+
+/// Stop reporting the plans of slow checked commands
+pub fn disable_auto_explain() {
+    AUTO_EXPLAIN.with(|explain| explain.borrow_mut().take());
+}
+
+/// Execution of a checked command whose plan is reported if it's slow, see [`watch_duration`]
+pub(crate) struct Watch {
+    started: Instant,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+}
+
+/// Start measuring the duration of a checked command executed with `args`, if automatic EXPLAIN is on
+pub(crate) fn watch_duration(args: Option<&[(PgOid, Option<Datum>)]>) -> Option<Watch> {
+    let on = AUTO_EXPLAIN.with(|explain| explain.borrow().is_some()) && !EXPLAINING.with(Cell::get);
+    on.then(|| Watch {
+        started: Instant::now(),
+        args: args.map(<[_]>::to_vec),
+    })
+}
+
+impl Watch {
+    /// Report the plan of `query` if it took longer than the threshold, which it executed in the current
+    /// sub-transaction
+    pub(crate) fn finish(self, query: &str) {
+        let duration = self.started.elapsed();
+        let target = AUTO_EXPLAIN.with(|explain| {
+            explain
+                .borrow()
+                .as_ref()
+                .filter(|explain| duration > explain.threshold)
+                .map(|explain| explain.target.clone())
+        });
+        let target = match target {
+            Some(target) if is_single_statement(query) => target,
+            _ => return,
+        };
+        let previous = EXPLAINING.with(|explaining| explaining.replace(true));
+        if let Err(err) = report_plan(query, self.args, duration, &target) {
+            pgx::warning!("failed to report the plan of a slow command: {}", err);
+        }
+        EXPLAINING.with(|explaining| explaining.set(previous));
+    }
+}
+
+fn report_plan(
+    query: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+    duration: Duration,
+    target: &AutoExplainTarget,
+) -> Result<(), Error> {
+    // The sub-transaction of the fold is rolled back, whatever EXPLAIN does
+    let plan = SpiClient.checked_select_fold(
+        &format!("EXPLAIN (FORMAT TEXT) {}", query),
+        args,
+        String::new(),
+        |mut plan, row| {
+            if !plan.is_empty() {
+                plan.push('\n');
+            }
+            plan.push_str(
+                &row.by_ordinal(1)
+                    .unwrap()
+                    .value::<String>()
+                    .unwrap_or_default(),
+            );
+            plan
+        },
+    )?;
+    match target {
+        AutoExplainTarget::ServerLog => {
+            pgx::log!("slow command ({:?}): {}\n{}", duration, query, plan);
+        }
+        AutoExplainTarget::Table { name } => {
+            (&mut SpiClient).checked_update(
+                &format!(
+                    "INSERT INTO {} VALUES (clock_timestamp(), $1, make_interval(secs => $2), $3)",
+                    quote_qualified_identifier(name)
+                ),
+                None,
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), query.into_datum()),
+                    (
+                        PgBuiltInOids::FLOAT8OID.oid(),
+                        duration.as_secs_f64().into_datum(),
+                    ),
+                    (PgBuiltInOids::TEXTOID.oid(), plan.into_datum()),
+                ]),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Longest tag, in characters
+const MAX_TAG_LEN: usize = 64;
+
+/// Prefix of the comment tagging commands
+const TAG_PREFIX: &str = "/* spiext:";
+
+thread_local! {
+    static STATEMENT_TAG: RefCell<Option<String>> = RefCell::new(None);
+    /// Tags of sub-transactions, as (sub-transaction, local id of its transaction, tag), innermost last
+    static TAG_OVERRIDES: RefCell<Vec<(pg_sys::SubTransactionId, pg_sys::LocalTransactionId, String)>> =
+        RefCell::new(Vec::new());
+}
+
+/// Tag the checked commands of this backend with `tag`
+///
+/// Only ASCII letters, digits, `_`, `-`, `.` and `:` are kept, up to 64 of them, so the tag can't end its comment.
+/// A tag with none of them removes the tag.
+pub fn set_statement_tag(tag: &str) {
+    STATEMENT_TAG.with(|current| *current.borrow_mut() = sanitize_tag(tag));
+}
+
+/// Stop tagging the checked commands of this backend, except those of sub-transactions with a tag of their own
+pub fn clear_statement_tag() {
+    STATEMENT_TAG.with(|current| *current.borrow_mut() = None);
+}
+
+/// Tag of the checked commands executed now, as sanitized
+pub fn statement_tag() -> Option<String> {
+    let overridden = TAG_OVERRIDES.with(|overrides| {
+        let mut overrides = overrides.borrow_mut();
+        overrides.retain(|(id, transaction, _)| unsafe {
+            (*pg_sys::MyProc).lxid == *transaction && pg_sys::SubTransactionIsActive(*id)
+        });
+        overrides.last().map(|(_, _, tag)| tag.clone())
+    });
+    overridden.or_else(|| STATEMENT_TAG.with(|current| current.borrow().clone()))
+}
+
+fn sanitize_tag(tag: &str) -> Option<String> {
+    let tag: String = tag
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+        .take(MAX_TAG_LEN)
+        .collect();
+    (!tag.is_empty()).then_some(tag)
+}
+
+impl<Parent, const COMMIT: bool> SubTransaction<Parent, COMMIT> {
+    /// Tag the checked commands executed while this sub-transaction is open with `tag` rather than the backend's
+    /// tag (see [`set_statement_tag`])
+    ///
+    /// The tag of the innermost sub-transaction that has one applies. A tag with no allowed characters removes this
+    /// sub-transaction's tag.
+    pub fn set_tag(&self, tag: &str) {
+        let transaction = unsafe { (*pg_sys::MyProc).lxid };
+        TAG_OVERRIDES.with(|overrides| {
+            let mut overrides = overrides.borrow_mut();
+            overrides.retain(|(id, _, _)| *id != self.id());
+            if let Some(tag) = sanitize_tag(tag) {
+                overrides.push((self.id(), transaction, tag));
+            }
+        });
+    }
+}
+
+/// Prepend the tag to `query` and report it as the backend's activity, if there is a tag
+pub(crate) fn apply_statement_tag(query: Cow<'_, str>) -> Cow<'_, str> {
+    match statement_tag() {
+        Some(tag) => {
+            let tagged = format!("{}{} */ {}", TAG_PREFIX, tag, query);
+            let activity = CString::new(tagged.as_str()).expect("query contains a NUL byte");
+            unsafe {
+                pg_sys::pgstat_report_activity(
+                    pg_sys::BackendState_STATE_RUNNING,
+                    activity.as_ptr(),
+                )
+            };
+            Cow::Owned(tagged)
+        }
+        None => query,
+    }
+}
+
+/// Backend whose activity is a tagged command, see [`tagged_activity`]
+#[derive(Debug, Clone)]
+pub struct ActivityRow {
+    pub pid: i32,
+    /// State, such as `active` or `idle in transaction`
+    pub state: Option<String>,
+    /// Start of the client's current statement (or last one, if idle)
+    pub query_start: Option<TimestampWithTimeZone>,
+    pub wait_event: Option<String>,
+    /// Text of the tagged command, including the tag
+    pub query: String,
+    pub tag: String,
+}
+
+/// Backends whose activity in `pg_stat_activity` is a tagged command, ordered by pid
+///
+/// This backend is included if its last checked command was tagged. A new snapshot of the activity is taken, and the
+/// query reading it is not tagged itself.
+pub fn tagged_activity(client: &SpiClient) -> Result<Vec<ActivityRow>, Error> {
+    unsafe { pg_sys::pgstat_clear_snapshot() };
+    let rows = client
+        .checked_select_raw(
+            "SELECT pid, state, query_start, wait_event, query FROM pg_stat_activity \
+             WHERE starts_with(query, $1) ORDER BY pid",
+            None,
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                TAG_PREFIX.into_datum(),
+            )]),
+        )?
+        .filter_map(|row| {
+            let query: String = row.by_ordinal(5).ok()?.value()?;
+            let tag = query[TAG_PREFIX.len()..].split(" */").next()?.to_string();
+            Some(ActivityRow {
+                pid: row.by_ordinal(1).ok()?.value()?,
+                state: row.by_ordinal(2).ok()?.value(),
+                query_start: row.by_ordinal(3).ok()?.value(),
+                wait_event: row.by_ordinal(4).ok()?.value(),
+                query,
+                tag,
+            })
+        })
+        .collect();
+    Ok(rows)
+}
+
+/// Warn when a sub-transaction of this backend ends after being open for longer than `threshold`
+///
+/// Only sub-transactions begun once this is called capture the backtrace of where they began (in debug builds).
+pub fn set_subtxn_duration_warning(threshold: Duration) {
+    crate::subtxn::set_held_warning(Some(threshold));
+}
+
+/// Warn when a sub-transaction of this backend ends more than `threshold` after its last
+/// [`SubTransaction::heartbeat`], or after it began if there was none
+pub fn set_subtxn_no_progress_warning(threshold: Duration) {
+    crate::subtxn::set_no_progress_warning(Some(threshold));
+}
+
+/// Stop warning about sub-transactions held open for long or making no progress
+pub fn disable_subtxn_duration_warnings() {
+    crate::subtxn::set_held_warning(None);
+    crate::subtxn::set_no_progress_warning(None);
+}
@@ -0,0 +1,436 @@
+//! Clients applying a shared configuration to every checked command
+//!
+//! A [`ConfiguredClient`] executes `checked_select` and `checked_update` with the behaviors of its [`SpiExtConfig`],
+//! in this order:
+//!
+//! 1. the [`Budget`], if exhausted, fails the command with [`Error::BudgetExceeded`] without executing it;
+//! 2. the [`Throttle`] waits for a token;
+//! 3. the command is logged at the configured level;
+//! 4. the command is attempted until it succeeds, fails with an error the [`RetryPolicy`] doesn't retry, or runs out
+//!    of attempts, each attempt in a sub-transaction of its own;
+//! 5. each attempt is canceled after the timeout, or once the budget runs out (whichever comes first), and its time is
+//!    deducted from the budget;
+//! 6. the command is executed, with the default limit unless one is given, read-only ones with the [`SelectOpts`].
+//!
+//! Unless the [`PanicPolicy`] says otherwise, errors are returned as by any checked command. A client with the default
+//! configuration executes commands exactly as `SpiClient`'s checked commands do.
+
+use pgx::{pg_sys, PgOid, SpiClient, SpiTupleTable};
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::budget::Budget;
+use crate::checked::*;
+use crate::error::{Error, SeverityClass};
+use crate::scan::is_empty_query;
+use crate::session::CheckedSession;
+use crate::throttle::Throttle;
+use crate::timeline::{self, EventKind};
+
+/// Entry point to configured clients
+pub struct SpiExt;
+
+impl SpiExt {
+    /// Start a configuration, with no behaviors
+    pub fn builder() -> SpiExtConfig {
+        SpiExtConfig::default()
+    }
+
+    /// Client executing the checked commands of `client` with `config`
+    pub fn with(client: &SpiClient, config: SpiExtConfig) -> ConfiguredClient {
+        ConfiguredClient { client, config }
+    }
+}
+
+/// Level commands are logged at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Debug1,
+    Log,
+    Info,
+    Notice,
+}
+
+impl LogLevel {
+    fn log(self, message: &str) {
+        match self {
+            LogLevel::Debug1 => pgx::debug1!("{}", message),
+            LogLevel::Log => pgx::log!("{}", message),
+            LogLevel::Info => pgx::info!("{}", message),
+            LogLevel::Notice => pgx::notice!("{}", message),
+        }
+    }
+}
+
+/// What a [`ConfiguredClient`] does with the errors of its commands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Return them, as checked commands do
+    #[default]
+    Return,
+    /// Raise them, aborting the enclosing statement as unchecked commands do
+    Raise,
+}
+
+/// Which failed commands are attempted again, and how many times at most
+///
+/// Errors whose [`SeverityClass`] is [`ResourceExhaustion`](SeverityClass::ResourceExhaustion) (such as a full disk)
+/// are not retried, whatever the predicate, unless [`retry_resource_exhaustion`](Self::retry_resource_exhaustion) is
+/// called: retrying them adds load to a server that is already short of the resource. Every attempt is rolled back
+/// before the next one. Under `REPEATABLE READ` and `SERIALIZABLE`, the snapshot is the
+/// transaction's, so errors caused by concurrent changes (such as serialization failures) recur; retrying helps with
+/// those only under `READ COMMITTED`, and with errors such as deadlocks and lock timeouts under any isolation level.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    predicate: Rc<dyn Fn(&Error) -> bool>,
+    resource_exhaustion: bool,
+}
+
+impl RetryPolicy {
+    /// Attempt commands up to `max_attempts` times in total (at least once), retrying errors `predicate` accepts
+    pub fn new<F: Fn(&Error) -> bool + 'static>(max_attempts: u32, predicate: F) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            predicate: Rc::new(predicate),
+            resource_exhaustion: false,
+        }
+    }
+
+    /// Retry errors whose severity class is [`SeverityClass::Transient`] (serialization failures, deadlocks and lock
+    /// timeouts)
+    pub fn on_transient(max_attempts: u32) -> Self {
+        Self::new(max_attempts, |err| {
+            err.severity_class() == Some(SeverityClass::Transient)
+        })
+    }
+
+    /// Also retry errors whose severity class is [`SeverityClass::ResourceExhaustion`], if the predicate accepts them
+    pub fn retry_resource_exhaustion(mut self) -> Self {
+        self.resource_exhaustion = true;
+        self
+    }
+
+    /// Retry serialization failures (SQLSTATE `40001`) and deadlocks (`40P01`)
+    pub fn on_serialization_failure(max_attempts: u32) -> Self {
+        Self::new(max_attempts, |err| {
+            err.sqlstate().map_or(false, |sqlstate| {
+                matches!(sqlstate.as_str(), "40001" | "40P01")
+            })
+        })
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    fn retries(&self, err: &Error) -> bool {
+        if !self.resource_exhaustion
+            && err.severity_class() == Some(SeverityClass::ResourceExhaustion)
+        {
+            return false;
+        }
+        (self.predicate)(err)
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("resource_exhaustion", &self.resource_exhaustion)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Behaviors applied to every checked command of a [`ConfiguredClient`]
+///
+/// Clones share the budget and the throttle.
+#[derive(Debug, Clone, Default)]
+pub struct SpiExtConfig {
+    default_limit: Option<i64>,
+    log_level: Option<LogLevel>,
+    retry: Option<RetryPolicy>,
+    budget: Option<Budget>,
+    throttle: Option<Rc<Throttle>>,
+    timeout: Option<Duration>,
+    panic_policy: PanicPolicy,
+    select_opts: SelectOpts,
+}
+
+impl SpiExtConfig {
+    /// Limit the rows of read-only commands executed without a limit
+    pub fn with_default_limit(mut self, limit: i64) -> Self {
+        self.default_limit = Some(limit);
+        self
+    }
+
+    /// Log every command, and every retry, at `level`
+    pub fn with_log_level(mut self, level: LogLevel) -> Self {
+        self.log_level = Some(level);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Execute all commands within `budget`
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    pub fn with_throttle(mut self, throttle: Rc<Throttle>) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Cancel every attempt that runs for longer than `timeout`, returning [`Error::BudgetExceeded`]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Execute read-only commands with `opts` (see [`SelectOptsCommands::checked_select_opts`])
+    pub fn with_select_opts(mut self, opts: SelectOpts) -> Self {
+        self.select_opts = opts;
+        self
+    }
+
+    fn execute(
+        &self,
+        client: &SpiClient,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        mutable: bool,
+    ) -> Result<SpiTupleTable, Error> {
+        let result = self.execute_configured(client, query, limit, args, mutable);
+        match result {
+            Err(err) if self.panic_policy == PanicPolicy::Raise => panic!("{}", err),
+            result => result,
+        }
+    }
+
+    fn execute_configured(
+        &self,
+        client: &SpiClient,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        mutable: bool,
+    ) -> Result<SpiTupleTable, Error> {
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery);
+        }
+        if let Some(budget) = &self.budget {
+            budget.check()?;
+        }
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire()?;
+        }
+        if let Some(level) = self.log_level {
+            level.log(&format!("executing: {}", query));
+        }
+        let limit = if mutable {
+            limit
+        } else {
+            limit.or(self.default_limit)
+        };
+        let mut attempt = 1;
+        loop {
+            let result = self.attempt(|| {
+                if mutable {
+                    (&mut SpiClient).checked_update(query, limit, args.clone())
+                } else {
+                    client.checked_select_opts(query, limit, args.clone(), self.select_opts)
+                }
+            });
+            match (result, &self.retry) {
+                (Err(err), Some(retry)) if attempt < retry.max_attempts && retry.retries(&err) => {
+                    if let Some(level) = self.log_level {
+                        level.log(&format!("retrying after attempt {}: {}", attempt, err));
+                    }
+                    attempt += 1;
+                    timeline::record(|| EventKind::Retry { attempt });
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    /// Run `f` subject to the timeout and the budget
+    fn attempt<F: FnOnce() -> Result<SpiTupleTable, Error>>(
+        &self,
+        f: F,
+    ) -> Result<SpiTupleTable, Error> {
+        let budget = match &self.budget {
+            Some(budget) => {
+                budget.check()?;
+                Some(budget)
+            }
+            None => None,
+        };
+        let remaining = budget.map(Budget::remaining);
+        let limit = match (self.timeout, remaining) {
+            (Some(timeout), Some(remaining)) => timeout.min(remaining),
+            (Some(limit), None) | (None, Some(limit)) => limit,
+            (None, None) => return f(),
+        };
+        let start = Instant::now();
+        // A budget of its own arms the timeout, and reports it as exceeded when it fires
+        let result = Budget::new(limit).run(f);
+        if let Some(budget) = budget {
+            budget.charge(start.elapsed());
+            // The shared budget is reported if it's what ran out
+            if matches!(result, Err(Error::BudgetExceeded { .. })) {
+                budget.check()?;
+            }
+        }
+        result
+    }
+}
+
+/// Client executing checked commands with a [`SpiExtConfig`], created with [`SpiExt::with`]
+///
+/// Its `checked_select` and `checked_update` apply the configuration; [`ConfiguredClient::call`] overrides it for a
+/// single command.
+#[derive(Clone)]
+pub struct ConfiguredClient<'a> {
+    client: &'a SpiClient,
+    config: SpiExtConfig,
+}
+
+impl<'a> ConfiguredClient<'a> {
+    pub fn config(&self) -> &SpiExtConfig {
+        &self.config
+    }
+
+    /// Command whose configuration can be overridden
+    pub fn call(&self) -> ConfiguredCall<'a> {
+        ConfiguredCall {
+            client: self.client,
+            config: self.config.clone(),
+        }
+    }
+
+    /// Run `f` in a new sub-transaction with a client sharing this one's configuration, committing it on `Ok` and
+    /// rolling it back on `Err`
+    ///
+    /// Errors raised (and panics) in `f` are caught and returned, rolling the sub-transaction back as well.
+    pub fn sub_transaction<R, F: FnOnce(&mut ConfiguredClient<'a>) -> Result<R, Error>>(
+        &mut self,
+        f: F,
+    ) -> Result<R, Error> {
+        let mut client = self.clone();
+        CheckedSession::scoped(move |_| f(&mut client))
+    }
+}
+
+impl Debug for ConfiguredClient<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConfiguredClient")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, 'b> CheckedCommands for &'a ConfiguredClient<'b> {
+    type Result<A> = A;
+
+    fn checked_select(
+        self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
+        self.config.execute(self.client, query, limit, args, false)
+    }
+}
+
+impl<'a, 'b> CheckedMutCommands for &'a mut ConfiguredClient<'b> {
+    type Result<A> = A;
+
+    fn checked_update(
+        self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<Self::Result<SpiTupleTable>, Error> {
+        self.config.execute(self.client, query, limit, args, true)
+    }
+}
+
+/// Single command of a [`ConfiguredClient`], with overrides of its configuration
+#[derive(Clone)]
+pub struct ConfiguredCall<'a> {
+    client: &'a SpiClient,
+    config: SpiExtConfig,
+}
+
+impl Debug for ConfiguredCall<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConfiguredCall")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> ConfiguredCall<'a> {
+    /// Limit the rows of a read-only command
+    pub fn override_limit(mut self, limit: Option<i64>) -> Self {
+        self.config.default_limit = limit;
+        self
+    }
+
+    pub fn override_log_level(mut self, level: Option<LogLevel>) -> Self {
+        self.config.log_level = level;
+        self
+    }
+
+    pub fn override_retry(mut self, retry: Option<RetryPolicy>) -> Self {
+        self.config.retry = retry;
+        self
+    }
+
+    pub fn override_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    pub fn override_panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.config.panic_policy = policy;
+        self
+    }
+
+    pub fn override_select_opts(mut self, opts: SelectOpts) -> Self {
+        self.config.select_opts = opts;
+        self
+    }
+
+    /// Execute a read-only command, returning an error if one occurred
+    pub fn select(
+        self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        self.config.execute(self.client, query, None, args, false)
+    }
+
+    /// Execute a mutable command, returning an error if one occurred
+    pub fn update(
+        self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        self.config.execute(self.client, query, None, args, true)
+    }
+}
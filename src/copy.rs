@@ -0,0 +1,392 @@
+//! Exporting tables and query results with `COPY ... TO STDOUT` to a Rust writer
+//!
+//! The rows are formatted by the server's own `COPY` implementation, in any of its formats. While the command executes,
+//! the data it would send to the client is written to the writer instead, a row at a time, so nothing accumulates in
+//! memory. Other messages (such as notices) still go to the client.
+
+use pgx::{pg_guard, pg_sys, PgList, SpiClient};
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::os::raw::{c_char, c_int};
+use std::panic::AssertUnwindSafe;
+
+use crate::checked::{check_entry, checked_sub_transaction};
+use crate::error::Error;
+use crate::quote::*;
+
+/// Format of the data, see [`CopyOptions`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CopyFormat {
+    #[default]
+    Text,
+    Csv,
+    /// Postgres' binary format: a header, then every row as its number of fields followed by each field's length
+    /// and binary representation, then a trailer
+    Binary,
+}
+
+/// Options of [`CopyCommands::checked_copy_out`], passed to `COPY` as they are
+///
+/// The default is the text format with the server's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CopyOptions {
+    pub format: CopyFormat,
+    /// Delimiter of the fields (text and CSV formats only)
+    pub delimiter: Option<char>,
+    /// String representing NULL (text and CSV formats only)
+    pub null: Option<String>,
+    /// Whether to begin with a line of column names (CSV format, and text format in Postgres 15 and later)
+    pub header: bool,
+}
+
+impl CopyOptions {
+    pub fn text() -> Self {
+        Self::default()
+    }
+
+    pub fn csv() -> Self {
+        Self {
+            format: CopyFormat::Csv,
+            ..Default::default()
+        }
+    }
+
+    pub fn binary() -> Self {
+        Self {
+            format: CopyFormat::Binary,
+            ..Default::default()
+        }
+    }
+
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = Some(delimiter);
+        self
+    }
+
+    pub fn null(mut self, null: &str) -> Self {
+        self.null = Some(null.to_string());
+        self
+    }
+
+    pub fn header(mut self, header: bool) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Options of the `COPY` command
+    fn sql(&self) -> String {
+        let format = match self.format {
+            CopyFormat::Text => "text",
+            CopyFormat::Csv => "csv",
+            CopyFormat::Binary => "binary",
+        };
+        let mut options = vec![format!("FORMAT {}", format)];
+        if let Some(delimiter) = self.delimiter {
+            options.push(format!(
+                "DELIMITER {}",
+                quote_literal(&delimiter.to_string())
+            ));
+        }
+        if let Some(null) = &self.null {
+            options.push(format!("NULL {}", quote_literal(null)));
+        }
+        if self.header {
+            options.push("HEADER".to_string());
+        }
+        options.join(", ")
+    }
+}
+
+/// What [`CopyCommands`] export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopySource<'a> {
+    /// Query, executed as `COPY (query) TO STDOUT`, without parameters
+    Query(&'a str),
+    /// Table, possibly schema-qualified (`schema.name`) and quoted with
+    /// [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier)
+    Table(&'a str),
+}
+
+/// Statistics of an export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyOutStats {
+    /// Rows exported
+    pub rows: u64,
+    /// Bytes written to the writer
+    pub bytes: u64,
+}
+
+/// Error returned by [`CopyCommands`]
+///
+/// If the command failed, the sub-transaction it executed in was rolled back.
+#[derive(Debug)]
+pub enum CopyError {
+    /// The command failed before anything was written to the writer
+    Query(Error),
+    /// The command failed after `bytes` bytes were written to the writer, which holds a partial output: a number of
+    /// whole rows (preceded by the header of the binary format, or the CSV header line), but no trailer
+    PartialOutput { error: Error, bytes: u64 },
+    /// Writing to the writer failed after `bytes` bytes were written, aborting the command
+    Io { error: std::io::Error, bytes: u64 },
+    /// The query doesn't remain a single query when wrapped in `COPY (...) TO STDOUT`
+    InvalidQuery,
+}
+
+impl Display for CopyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CopyError::Query(err) => write!(f, "{}", err),
+            CopyError::PartialOutput { error, bytes } => {
+                write!(f, "COPY failed after writing {} bytes: {}", bytes, error)
+            }
+            CopyError::Io { error, bytes } => {
+                write!(
+                    f,
+                    "failed to write COPY data after {} bytes: {}",
+                    bytes, error
+                )
+            }
+            CopyError::InvalidQuery => write!(f, "query is not a single query to copy from"),
+        }
+    }
+}
+
+impl From<Error> for CopyError {
+    fn from(err: Error) -> Self {
+        CopyError::Query(err)
+    }
+}
+
+/// Commands exporting data with `COPY`
+pub trait CopyCommands {
+    /// Export `source` to `writer` with `COPY ... TO STDOUT`, in a sub-transaction
+    ///
+    /// The writer receives exactly what a client would, in the given format, and is flushed once the command
+    /// completes. If the command fails, the writer is left with whatever was written before, as reported by
+    /// [`CopyError::PartialOutput`].
+    fn checked_copy_out<W: Write>(
+        &self,
+        source: CopySource<'_>,
+        options: &CopyOptions,
+        writer: &mut W,
+    ) -> Result<CopyOutStats, CopyError>;
+
+    /// Export `source` to `writer` in the binary format, see [`checked_copy_out`](Self::checked_copy_out)
+    fn checked_copy_out_binary<W: Write>(
+        &self,
+        source: CopySource<'_>,
+        writer: &mut W,
+    ) -> Result<CopyOutStats, CopyError> {
+        self.checked_copy_out(source, &CopyOptions::binary(), writer)
+    }
+}
+
+impl CopyCommands for SpiClient {
+    fn checked_copy_out<W: Write>(
+        &self,
+        source: CopySource<'_>,
+        options: &CopyOptions,
+        writer: &mut W,
+    ) -> Result<CopyOutStats, CopyError> {
+        let statement = match source {
+            CopySource::Query(query) => {
+                check_entry(query)?;
+                // A newline ends a trailing line comment of the query
+                format!("COPY ({}\n) TO STDOUT ({})", query, options.sql())
+            }
+            CopySource::Table(table) => format!(
+                "COPY {} TO STDOUT ({})",
+                quote_qualified_identifier(table),
+                options.sql()
+            ),
+        };
+        let sink = AssertUnwindSafe(&mut *writer as &mut dyn Write);
+        let result = checked_sub_transaction(move |_| unsafe {
+            let sink = sink;
+            let _redirect = Redirect::install(sink.0);
+            execute(&statement)
+        });
+        let (bytes, io_error) = SINK
+            .with(|sink| sink.borrow_mut().take())
+            .map(|sink| (sink.bytes, sink.error))
+            .unwrap_or_default();
+        match result {
+            Ok(Ok(rows)) => {
+                writer
+                    .flush()
+                    .map_err(|error| CopyError::Io { error, bytes })?;
+                Ok(CopyOutStats { rows, bytes })
+            }
+            Ok(Err(err)) => Err(err),
+            Err(error) => Err(match io_error {
+                Some(error) => CopyError::Io { error, bytes },
+                None if bytes > 0 => CopyError::PartialOutput { error, bytes },
+                None => CopyError::Query(error),
+            }),
+        }
+    }
+}
+
+/// Execute `statement`, which must be a single `COPY ... TO STDOUT`, returning the number of rows it exported
+unsafe fn execute(statement: &str) -> Result<u64, CopyError> {
+    let c_statement = CString::new(statement).expect("query contains a NUL byte");
+    let statements =
+        PgList::<pg_sys::RawStmt>::from_pg(pg_sys::pg_parse_query(c_statement.as_ptr()));
+    // A query can close the parentheses it's wrapped in, and go on with a destination of its own or other statements
+    let raw = match statements.get_ptr(0) {
+        Some(raw)
+            if statements.len() == 1 && (*(*raw).stmt).type_ == pg_sys::NodeTag::T_CopyStmt =>
+        {
+            raw
+        }
+        _ => return Err(CopyError::InvalidQuery),
+    };
+    let copy = (*raw).stmt as *mut pg_sys::CopyStmt;
+    if (*copy).is_from || !(*copy).filename.is_null() {
+        return Err(CopyError::InvalidQuery);
+    }
+    // See the changes made before, as commands executed through SPI do
+    pg_sys::CommandCounterIncrement();
+    pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot());
+    let pstate = pg_sys::make_parsestate(std::ptr::null_mut());
+    (*pstate).p_sourcetext = c_statement.as_ptr();
+    let mut processed = 0;
+    pg_sys::DoCopy(
+        pstate,
+        copy,
+        (*raw).stmt_location,
+        (*raw).stmt_len,
+        &mut processed,
+    );
+    pg_sys::free_parsestate(pstate);
+    pg_sys::PopActiveSnapshot();
+    Ok(processed)
+}
+
+/// Where the data of the `COPY` being executed goes
+struct Sink {
+    /// Borrowed for as long as the [`Redirect`] is installed
+    writer: Option<*mut (dyn Write + 'static)>,
+    bytes: u64,
+    error: Option<std::io::Error>,
+    original: *const pg_sys::PQcommMethods,
+    /// Whether other messages go to the client
+    forward: bool,
+}
+
+thread_local! {
+    static SINK: RefCell<Option<Sink>> = RefCell::new(None);
+}
+
+/// Redirection of the data of `COPY ... TO STDOUT` to the [`Sink`], undone when dropped
+struct Redirect {
+    // Referenced by `PqCommMethods` while installed
+    _methods: Box<pg_sys::PQcommMethods>,
+    original: *const pg_sys::PQcommMethods,
+    destination: pg_sys::CommandDest,
+    #[cfg(any(feature = "pg11", feature = "pg12", feature = "pg13"))]
+    protocol: pg_sys::ProtocolVersion,
+}
+
+impl Redirect {
+    unsafe fn install(writer: &mut dyn Write) -> Self {
+        let original = pg_sys::PqCommMethods;
+        let mut methods = Box::new(*original);
+        methods.putmessage = Some(put_message);
+        let destination = pg_sys::whereToSendOutput;
+        // The lifetime of the writer ends after the redirect is dropped, which forgets it
+        let writer: *mut (dyn Write + '_) = writer;
+        SINK.with(|sink| {
+            *sink.borrow_mut() = Some(Sink {
+                writer: Some(std::mem::transmute(writer)),
+                bytes: 0,
+                error: None,
+                original,
+                forward: destination == pg_sys::CommandDest_DestRemote,
+            })
+        });
+        pg_sys::PqCommMethods = &*methods;
+        // Unless the client is the destination (as in background workers), `COPY ... TO STDOUT` writes to the
+        // backend's standard output
+        pg_sys::whereToSendOutput = pg_sys::CommandDest_DestRemote;
+        // and before Postgres 14, without a client speaking version 3 of the protocol, to the old protocol's stream
+        #[cfg(any(feature = "pg11", feature = "pg12", feature = "pg13"))]
+        let protocol = std::mem::replace(&mut pg_sys::FrontendProtocol, 3 << 16);
+        Self {
+            _methods: methods,
+            original,
+            destination,
+            #[cfg(any(feature = "pg11", feature = "pg12", feature = "pg13"))]
+            protocol,
+        }
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        unsafe {
+            pg_sys::PqCommMethods = self.original;
+            pg_sys::whereToSendOutput = self.destination;
+            #[cfg(any(feature = "pg11", feature = "pg12", feature = "pg13"))]
+            {
+                pg_sys::FrontendProtocol = self.protocol;
+            }
+        }
+        SINK.with(|sink| {
+            if let Some(sink) = sink.borrow_mut().as_mut() {
+                sink.writer = None;
+            }
+        });
+    }
+}
+
+enum Delivery {
+    Done,
+    Forward(*const pg_sys::PQcommMethods),
+    Failed(String),
+}
+
+/// `putmessage` of the redirected methods: copy data (`d`) is written to the sink, the messages beginning and ending
+/// it (`H` and `c`) are dropped, and other messages are forwarded to the client, if there is one
+#[pg_guard]
+unsafe extern "C" fn put_message(msgtype: c_char, s: *const c_char, len: usize) -> c_int {
+    let delivery = SINK.with(|sink| {
+        let mut sink = sink.borrow_mut();
+        let sink = match sink.as_mut() {
+            Some(sink) => sink,
+            None => return Delivery::Done,
+        };
+        match msgtype as u8 {
+            b'd' => {
+                let writer = match (sink.writer, &sink.error) {
+                    (Some(writer), None) => writer,
+                    _ => return Delivery::Done,
+                };
+                let data = std::slice::from_raw_parts(s as *const u8, len);
+                match (*writer).write_all(data) {
+                    Ok(()) => {
+                        sink.bytes += len as u64;
+                        Delivery::Done
+                    }
+                    Err(err) => {
+                        let message = err.to_string();
+                        sink.error = Some(err);
+                        Delivery::Failed(message)
+                    }
+                }
+            }
+            b'H' | b'c' => Delivery::Done,
+            _ if sink.forward => Delivery::Forward(sink.original),
+            _ => Delivery::Done,
+        }
+    });
+    match delivery {
+        Delivery::Done => 0,
+        Delivery::Forward(original) => {
+            (*original).putmessage.expect("no putmessage method")(msgtype, s, len)
+        }
+        Delivery::Failed(message) => pgx::error!("could not write COPY data: {}", message),
+    }
+}
@@ -0,0 +1,473 @@
+use pgx::{pg_sys, PgOid, SpiClient, SpiTupleTable};
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::mem::ManuallyDrop;
+use std::ops::ControlFlow;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+use crate::checked::{check_entry, checked_sub_transaction, checked_sub_transaction_of, raw_args};
+use crate::error::Error;
+use crate::owned::OwnedRows;
+use crate::scan::is_empty_query;
+
+/// A cursor whose operations are checked
+///
+/// Opening the cursor and every fetch run in sub-transactions of their own, so an error rolls back only the failed
+/// operation. After a failed fetch, the cursor can only be closed.
+///
+/// Every cursor has a portal of its own, so several can be open and fetched from in turn, as [`merge::sorted`]
+/// does. If not closed explicitly, the cursor is closed when dropped.
+///
+/// [`merge::sorted`]: crate::merge::sorted
+#[derive(Debug)]
+pub struct CheckedCursor {
+    name: Option<String>,
+}
+
+impl CheckedCursor {
+    /// Open a cursor for a query
+    pub fn open(
+        client: &SpiClient,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<Self, Error> {
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery);
+        }
+        let args = AssertUnwindSafe(args);
+        checked_sub_transaction_of(client, move |client| {
+            let args = args;
+            client.open_cursor(query, args.0).detach_into_name()
+        })
+        .map(|name| Self { name: Some(name) })
+    }
+
+    /// Portal name of the cursor
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap()
+    }
+
+    /// Fetch up to `count` rows
+    pub fn fetch(&mut self, count: i64) -> Result<SpiTupleTable, Error> {
+        let name = self.name();
+        checked_sub_transaction(move |client| {
+            // The portal is closed explicitly, don't let an error close it while it is active
+            let mut cursor = ManuallyDrop::new(client.find_cursor(name));
+            cursor.fetch(count)
+        })
+    }
+
+    /// Fetch up to `count` rows, passing them to `f`
+    ///
+    /// The fetched tuple table is released once `f` returns, even if `f` fetched from other cursors meanwhile.
+    pub fn fetch_with<R, F: FnOnce(SpiTupleTable) -> R>(
+        &mut self,
+        count: i64,
+        f: F,
+    ) -> Result<R, Error> {
+        let table = self.fetch(count)?;
+        let raw = unsafe { pg_sys::SPI_tuptable };
+        let result = f(table);
+        unsafe {
+            pg_sys::SPI_freetuptable(raw);
+            if pg_sys::SPI_tuptable == raw {
+                pg_sys::SPI_tuptable = std::ptr::null_mut();
+            }
+        }
+        Ok(result)
+    }
+
+    /// Fetch up to `count` rows, only counting them
+    ///
+    /// The fetched tuple table is released right away.
+    pub fn fetch_count(&mut self, count: i64) -> Result<usize, Error> {
+        self.fetch_with(count, |table| table.len())
+    }
+
+    /// Close the cursor
+    pub fn close(mut self) {
+        self.internal_close();
+    }
+
+    fn internal_close(&mut self) {
+        if let Some(name) = self.name.take() {
+            drop(SpiClient.find_cursor(&name));
+        }
+    }
+}
+
+impl Drop for CheckedCursor {
+    fn drop(&mut self) {
+        // Closing a portal while an error is being handled may fail; it will be
+        // cleaned up when the (sub-)transaction ends instead
+        if !std::thread::panicking() {
+            self.internal_close();
+        }
+    }
+}
+
+/// Progress of [`CursorCommands::checked_select_progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressInfo {
+    /// Rows fetched so far
+    pub rows_so_far: u64,
+    /// Batches fetched so far
+    pub batches: u64,
+    /// Time elapsed since the query started
+    pub elapsed: Duration,
+}
+
+/// How [`CursorCommands::checked_select_progress`] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectOutcome {
+    /// All rows were fetched
+    Completed,
+    /// Progress callback requested to stop
+    Cancelled,
+}
+
+/// Summary of [`CursorCommands::checked_select_progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectSummary {
+    pub rows: u64,
+    pub batches: u64,
+    pub elapsed: Duration,
+    pub outcome: SelectOutcome,
+}
+
+/// Error returned by [`CursorCommands::checked_select_bounded`]
+#[derive(Debug)]
+pub enum BoundedSelectError {
+    /// The query returned more rows than allowed
+    RowLimitExceeded {
+        /// Rows fetched when the limit was exceeded
+        rows: u64,
+        /// Bytes fetched when the limit was exceeded
+        bytes: u64,
+    },
+    /// The query returned more bytes than allowed
+    ByteLimitExceeded {
+        /// Rows fetched when the limit was exceeded
+        rows: u64,
+        /// Bytes fetched when the limit was exceeded
+        bytes: u64,
+    },
+    /// Query failed
+    Query(Error),
+}
+
+impl From<Error> for BoundedSelectError {
+    fn from(err: Error) -> Self {
+        BoundedSelectError::Query(err)
+    }
+}
+
+/// Number of rows fetched at a time by [`CursorCommands::checked_select_bounded`]
+const BOUNDED_BATCH: u64 = 1000;
+
+/// Cursor-based commands
+pub trait CursorCommands {
+    /// Execute a read-only command, fetching `batch` rows at a time and reporting progress after every batch
+    ///
+    /// Returning `ControlFlow::Break` from `progress` closes the cursor and stops fetching. The callback runs outside
+    /// of any sub-transaction created by this call, so checked commands it executes are not affected by it.
+    ///
+    /// Panics if `batch` is 0.
+    fn checked_select_progress<F: FnMut(ProgressInfo) -> ControlFlow<()>>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        batch: usize,
+        progress: F,
+    ) -> Result<SelectSummary, Error>;
+
+    /// Execute a read-only command, failing as soon as the result exceeds `max_rows` rows or `max_bytes` bytes
+    ///
+    /// Rows are fetched in batches and copied into [`OwnedRows`], releasing each batch's tuple table right away.
+    /// Bytes are estimated as the total size of the values. A limit of 0 means unlimited.
+    fn checked_select_bounded(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        max_rows: u64,
+        max_bytes: u64,
+    ) -> Result<OwnedRows, BoundedSelectError>;
+}
+
+impl CursorCommands for SpiClient {
+    fn checked_select_progress<F: FnMut(ProgressInfo) -> ControlFlow<()>>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        batch: usize,
+        mut progress: F,
+    ) -> Result<SelectSummary, Error> {
+        assert!(batch > 0, "batch size must be positive");
+        let started = Instant::now();
+        let mut cursor = CheckedCursor::open(self, query, args)?;
+        let mut info = ProgressInfo {
+            rows_so_far: 0,
+            batches: 0,
+            elapsed: Duration::ZERO,
+        };
+        let outcome = loop {
+            let rows = cursor.fetch_count(batch as i64)?;
+            if rows == 0 {
+                break SelectOutcome::Completed;
+            }
+            info.rows_so_far += rows as u64;
+            info.batches += 1;
+            info.elapsed = started.elapsed();
+            if progress(info).is_break() {
+                break SelectOutcome::Cancelled;
+            }
+            if rows < batch {
+                break SelectOutcome::Completed;
+            }
+        };
+        cursor.close();
+        Ok(SelectSummary {
+            rows: info.rows_so_far,
+            batches: info.batches,
+            elapsed: started.elapsed(),
+            outcome,
+        })
+    }
+
+    fn checked_select_bounded(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        max_rows: u64,
+        max_bytes: u64,
+    ) -> Result<OwnedRows, BoundedSelectError> {
+        let mut cursor = CheckedCursor::open(self, query, args)?;
+        let mut rows: Option<OwnedRows> = None;
+        loop {
+            // Fetch no more than one row past the limit
+            let count = match max_rows {
+                0 => BOUNDED_BATCH,
+                max_rows => {
+                    BOUNDED_BATCH.min(max_rows + 1 - rows.as_ref().map_or(0, |r| r.len() as u64))
+                }
+            };
+            let fetched = cursor.fetch_with(count as i64, |table| {
+                let rows = rows.get_or_insert_with(|| OwnedRows::with_columns_of(&table));
+                let fetched = table.len();
+                for tuple in table {
+                    rows.push(&tuple);
+                    let (count, bytes) = (rows.len() as u64, rows.bytes());
+                    if max_rows != 0 && count > max_rows {
+                        return Err(BoundedSelectError::RowLimitExceeded { rows: count, bytes });
+                    }
+                    if max_bytes != 0 && bytes > max_bytes {
+                        return Err(BoundedSelectError::ByteLimitExceeded { rows: count, bytes });
+                    }
+                }
+                Ok(fetched)
+            })??;
+            if (fetched as u64) < count {
+                break;
+            }
+        }
+        cursor.close();
+        Ok(rows.unwrap_or_default())
+    }
+}
+
+/// Error of [`NamedCursorCommands`]
+#[derive(Debug)]
+pub enum NamedCursorError {
+    /// The name is empty, longer than 63 bytes or has characters other than ASCII letters, digits and `_`
+    InvalidName(String),
+    /// There is already a portal with this name
+    PortalNameInUse(String),
+    /// There is no portal with this name, as it was closed or the sub-transaction it was opened in was rolled back
+    PortalNotFound(String),
+    /// The fetch needs to move the portal with this name backward, but its plan only supports scanning forward
+    ScrollNotSupported(String),
+    /// A command failed
+    Query(Error),
+}
+
+impl Display for NamedCursorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NamedCursorError::InvalidName(name) => write!(f, "invalid portal name \"{}\"", name),
+            NamedCursorError::PortalNameInUse(name) => {
+                write!(f, "portal \"{}\" already exists", name)
+            }
+            NamedCursorError::PortalNotFound(name) => {
+                write!(f, "portal \"{}\" does not exist", name)
+            }
+            NamedCursorError::ScrollNotSupported(name) => {
+                write!(f, "portal \"{}\" can only scan forward", name)
+            }
+            NamedCursorError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for NamedCursorError {
+    fn from(err: Error) -> Self {
+        NamedCursorError::Query(err)
+    }
+}
+
+/// Rows fetched by [`NamedCursorCommands::checked_fetch_named`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchDirection {
+    /// The next rows, at most this many
+    Forward(i64),
+    /// The prior rows, at most this many, in reverse order
+    Backward(i64),
+    /// The row at this (1-based) position, counting from the end if negative
+    Absolute(i64),
+}
+
+impl FetchDirection {
+    fn raw(self) -> (pg_sys::FetchDirection, i64) {
+        match self {
+            FetchDirection::Forward(count) => (pg_sys::FetchDirection_FETCH_FORWARD, count),
+            FetchDirection::Backward(count) => (pg_sys::FetchDirection_FETCH_BACKWARD, count),
+            FetchDirection::Absolute(position) => (pg_sys::FetchDirection_FETCH_ABSOLUTE, position),
+        }
+    }
+
+    /// Whether fetching moves a portal that is at `position` backward
+    fn moves_backward(self, position: u64) -> bool {
+        match self {
+            FetchDirection::Forward(_) => false,
+            FetchDirection::Backward(_) => true,
+            FetchDirection::Absolute(target) => target <= 0 || target as u64 <= position,
+        }
+    }
+}
+
+/// Cursors identified by a name chosen by the caller
+///
+/// Unlike [`CheckedCursor`], the portal isn't tied to a Rust value: it is opened by one call and can be fetched from
+/// and closed by later ones (such as later calls of an extension's functions) in the same transaction, by its name.
+/// Every operation runs in a sub-transaction of its own, so the portal lives until it is closed, the sub-transaction
+/// it was opened in is rolled back, or the transaction ends (unless it is held).
+pub trait NamedCursorCommands {
+    /// Open a portal named `name` for a command
+    ///
+    /// With `hold`, the portal remains usable after the transaction commits, as with `DECLARE ... WITH HOLD`. The
+    /// portal can be scrolled backward if the command's plan supports it.
+    fn checked_open_named_cursor(
+        &self,
+        name: &str,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        hold: bool,
+    ) -> Result<(), NamedCursorError>;
+
+    /// Fetch rows from the portal named `name`, copying them into [`OwnedRows`]
+    ///
+    /// A fetch that would move a portal that can't scroll backward fails with
+    /// [`NamedCursorError::ScrollNotSupported`] before fetching anything, so the portal remains usable. After a failed
+    /// fetch, the portal can only be closed.
+    fn checked_fetch_named(
+        &self,
+        name: &str,
+        direction: FetchDirection,
+    ) -> Result<OwnedRows, NamedCursorError>;
+
+    /// Close the portal named `name`
+    fn checked_close_named(&self, name: &str) -> Result<(), NamedCursorError>;
+}
+
+impl NamedCursorCommands for SpiClient {
+    fn checked_open_named_cursor(
+        &self,
+        name: &str,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        hold: bool,
+    ) -> Result<(), NamedCursorError> {
+        let portal_name = portal_name(name)?;
+        check_entry(query)?;
+        if !find_portal(&portal_name).is_null() {
+            return Err(NamedCursorError::PortalNameInUse(name.to_string()));
+        }
+        let query = CString::new(query).expect("query contains a NUL byte");
+        let (mut arg_types, mut values, nulls) = raw_args(args);
+        let options = if hold {
+            pg_sys::CURSOR_OPT_HOLD as i32
+        } else {
+            0
+        };
+        checked_sub_transaction(move |_| unsafe {
+            pg_sys::SPI_cursor_open_with_args(
+                portal_name.as_ptr(),
+                query.as_ptr(),
+                arg_types.len() as i32,
+                arg_types.as_mut_ptr(),
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                false,
+                options,
+            );
+        })?;
+        Ok(())
+    }
+
+    fn checked_fetch_named(
+        &self,
+        name: &str,
+        direction: FetchDirection,
+    ) -> Result<OwnedRows, NamedCursorError> {
+        let portal_name = portal_name(name)?;
+        let portal = find_portal(&portal_name);
+        if portal.is_null() {
+            return Err(NamedCursorError::PortalNotFound(name.to_string()));
+        }
+        let (scrollable, position) = unsafe {
+            (
+                (*portal).cursorOptions & pg_sys::CURSOR_OPT_SCROLL as i32 != 0,
+                (*portal).portalPos,
+            )
+        };
+        if !scrollable && direction.moves_backward(position) {
+            return Err(NamedCursorError::ScrollNotSupported(name.to_string()));
+        }
+        let (direction, count) = direction.raw();
+        let rows = checked_sub_transaction(move |_| unsafe {
+            pg_sys::SPI_scroll_cursor_fetch(find_portal(&portal_name), direction, count);
+            let rows = OwnedRows::from_raw(pg_sys::SPI_tuptable, pg_sys::SPI_processed);
+            pg_sys::SPI_freetuptable(pg_sys::SPI_tuptable);
+            pg_sys::SPI_tuptable = std::ptr::null_mut();
+            rows
+        })?;
+        Ok(rows)
+    }
+
+    fn checked_close_named(&self, name: &str) -> Result<(), NamedCursorError> {
+        let portal_name = portal_name(name)?;
+        if find_portal(&portal_name).is_null() {
+            return Err(NamedCursorError::PortalNotFound(name.to_string()));
+        }
+        checked_sub_transaction(move |_| unsafe {
+            pg_sys::SPI_cursor_close(find_portal(&portal_name));
+        })?;
+        Ok(())
+    }
+}
+
+/// Validate a portal name, which must fit in a `name` and have only ASCII letters, digits and `_`
+fn portal_name(name: &str) -> Result<CString, NamedCursorError> {
+    let valid = !name.is_empty()
+        && name.len() < pg_sys::NAMEDATALEN as usize
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+    if valid {
+        Ok(CString::new(name).unwrap())
+    } else {
+        Err(NamedCursorError::InvalidName(name.to_string()))
+    }
+}
+
+fn find_portal(name: &CStr) -> pg_sys::Portal {
+    unsafe { pg_sys::SPI_cursor_find(name.as_ptr()) }
+}
@@ -0,0 +1,765 @@
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{pg_sys, IntoDatum, PgBuiltInOids, SpiClient};
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use crate::checked::*;
+use crate::error::{report, Error, PostgresErrorExt};
+use crate::guc::with_local;
+use crate::limits::timeout_setting;
+use crate::quote::*;
+use crate::rewrite;
+use crate::subtxn::*;
+use crate::validate::{self, IdentError, PreflightError};
+
+/// A function that only becomes durable once persisted
+///
+/// The function is created in a dedicated sub-transaction owned by this value. [`TransientFunction::persist`] commits
+/// it, while dropping the value rolls it back, as if the function never existed.
+///
+/// As the sub-transaction remains the current one until then, it must be persisted or dropped before any
+/// sub-transaction enclosing it ends.
+pub struct TransientFunction {
+    name: String,
+    xid: pg_sys::TransactionId,
+    xact: Option<SubTransaction<SpiClientWrapper, false>>,
+}
+
+impl TransientFunction {
+    /// Create a function
+    ///
+    /// `name` and `language` are quoted, `args_sql` and `returns_sql` are used verbatim and `body` is dollar-quoted.
+    /// `name`, `language` and `body` are checked with [`validate`] first.
+    pub fn create(
+        client: &mut SpiClient,
+        name: &str,
+        args_sql: &str,
+        returns_sql: &str,
+        language: &str,
+        body: &str,
+    ) -> Result<Self, Error> {
+        validate::identifier(name)?;
+        validate::identifier(language)?;
+        validate::literal(body)?;
+        let query = format!(
+            "CREATE FUNCTION {}({}) RETURNS {} LANGUAGE {} AS {}",
+            quote_identifier(name),
+            args_sql,
+            returns_sql,
+            quote_identifier(language),
+            dollar_quote(body)
+        );
+        let xact = client.sub_transaction(|xact| xact.rollback_on_drop());
+        let (_, xact) = xact.checked_update(&query, None, None)?;
+        Ok(Self {
+            name: name.to_string(),
+            xid: unsafe { pg_sys::GetCurrentTransactionIdIfAny() },
+            xact: Some(xact),
+        })
+    }
+
+    /// Function's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Look up the function's OID
+    pub fn oid(&self) -> Result<Option<pg_sys::Oid>, Error> {
+        let client: &SpiClient = self.xact.as_ref().unwrap();
+        rewrite::exempt(|| {
+            client.checked_select(
+                "SELECT oid FROM pg_proc WHERE proname = $1 AND xmin::text = $2",
+                Some(1),
+                Some(vec![
+                    (
+                        PgBuiltInOids::TEXTOID.oid(),
+                        self.name.as_str().into_datum(),
+                    ),
+                    (
+                        PgBuiltInOids::TEXTOID.oid(),
+                        self.xid.to_string().into_datum(),
+                    ),
+                ]),
+            )
+        })
+        .map(|table| table.first().get_one::<pg_sys::Oid>())
+    }
+
+    /// Make the function durable
+    pub fn persist(mut self) {
+        self.xact.take().unwrap().commit();
+    }
+}
+
+/// Kind of object a comment or security label is put on
+///
+/// Names are possibly schema-qualified: tables, views and indexes are named `schema.name`, columns
+/// `schema.table.column` and functions by their signature, as in `schema.name(integer, text)` (the argument types are
+/// used verbatim).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Table,
+    Column,
+    Function,
+    Index,
+    View,
+}
+
+impl ObjectKind {
+    fn keyword(self) -> &'static str {
+        match self {
+            ObjectKind::Table => "TABLE",
+            ObjectKind::Column => "COLUMN",
+            ObjectKind::Function => "FUNCTION",
+            ObjectKind::Index => "INDEX",
+            ObjectKind::View => "VIEW",
+        }
+    }
+
+    /// Check the name of an object of this kind with [`validate`], the way it is quoted
+    fn validate_name(self, name: &str) -> Result<(), IdentError> {
+        match self {
+            ObjectKind::Column => {
+                let (relation, column) = split_column(name)?;
+                validate::qualified_identifier(relation)?;
+                validate::identifier(column)?;
+            }
+            ObjectKind::Function => {
+                let name = name.find('(').map_or(name, |args| name[..args].trim_end());
+                validate::qualified_identifier(name)?;
+            }
+            _ => {
+                validate::qualified_identifier(name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Quote the name of an object of this kind
+    fn quote_name(self, name: &str) -> String {
+        match self {
+            ObjectKind::Column => {
+                // Checked by `validate_name`
+                let (relation, column) = split_column(name).unwrap();
+                format!(
+                    "{}.{}",
+                    quote_qualified_identifier(relation),
+                    quote_identifier(column)
+                )
+            }
+            ObjectKind::Function => match name.find('(') {
+                Some(args) => format!(
+                    "{}{}",
+                    quote_qualified_identifier(name[..args].trim_end()),
+                    &name[args..]
+                ),
+                None => quote_qualified_identifier(name),
+            },
+            _ => quote_qualified_identifier(name),
+        }
+    }
+}
+
+/// Split a column name into its relation's name and its own
+fn split_column(name: &str) -> Result<(&str, &str), IdentError> {
+    name.rsplit_once('.')
+        .ok_or_else(|| IdentError::UnqualifiedColumn {
+            name: name.to_string(),
+        })
+}
+
+/// Set the comment of an object, replacing the one it has
+///
+/// `COMMENT ON` doesn't take parameters, so the comment is quoted as a literal by the server, which takes care of
+/// `standard_conforming_strings`. The name and the comment are checked with [`validate`] first.
+pub fn checked_comment_on(
+    client: &mut SpiClient,
+    kind: ObjectKind,
+    name: &str,
+    comment: &str,
+) -> Result<(), Error> {
+    let query = comment_query(kind, name, comment)?;
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// Set the security label of an object for `provider`, replacing the one it has
+///
+/// The provider must be loaded (as with any `SECURITY LABEL`). The label is quoted as a literal by the server. The
+/// provider, the name and the label are checked with [`validate`] first.
+pub fn checked_security_label(
+    client: &mut SpiClient,
+    provider: &str,
+    kind: ObjectKind,
+    name: &str,
+    label: &str,
+) -> Result<(), Error> {
+    let query = security_label_query(provider, kind, name, label)?;
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// Comment of an object, `None` if it has none
+pub fn get_comment(
+    client: &SpiClient,
+    kind: ObjectKind,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    kind.validate_name(name)?;
+    let (query, args) = match kind {
+        ObjectKind::Column => {
+            let (relation, column) = split_column(name)?;
+            (
+                "SELECT col_description(attrelid, attnum) FROM pg_attribute \
+                 WHERE attrelid = $1::regclass AND attname = $2 AND NOT attisdropped",
+                vec![
+                    (
+                        PgBuiltInOids::TEXTOID.oid(),
+                        quote_qualified_identifier(relation).into_datum(),
+                    ),
+                    (PgBuiltInOids::TEXTOID.oid(), column.into_datum()),
+                ],
+            )
+        }
+        ObjectKind::Function => (
+            "SELECT obj_description($1::regprocedure, 'pg_proc')",
+            vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                kind.quote_name(name).into_datum(),
+            )],
+        ),
+        ObjectKind::Table | ObjectKind::Index | ObjectKind::View => (
+            "SELECT obj_description($1::regclass, 'pg_class')",
+            vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                kind.quote_name(name).into_datum(),
+            )],
+        ),
+    };
+    Ok(
+        rewrite::exempt(|| client.checked_select(query, Some(1), Some(args)))?
+            .map(|row| row.by_ordinal(1).unwrap().value::<String>())
+            .next()
+            .flatten(),
+    )
+}
+
+fn comment_query(kind: ObjectKind, name: &str, comment: &str) -> Result<String, Error> {
+    kind.validate_name(name)?;
+    validate::literal(comment)?;
+    Ok(format!(
+        "COMMENT ON {} {} IS {}",
+        kind.keyword(),
+        kind.quote_name(name),
+        quote_literal_on_server(comment)?
+    ))
+}
+
+fn security_label_query(
+    provider: &str,
+    kind: ObjectKind,
+    name: &str,
+    label: &str,
+) -> Result<String, Error> {
+    validate::identifier(provider)?;
+    kind.validate_name(name)?;
+    validate::literal(label)?;
+    Ok(format!(
+        "SECURITY LABEL FOR {} ON {} {} IS {}",
+        quote_identifier(provider),
+        kind.keyword(),
+        kind.quote_name(name),
+        quote_literal_on_server(label)?
+    ))
+}
+
+fn quote_literal_on_server(literal: &str) -> Result<String, Error> {
+    Ok(rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "SELECT quote_literal($1)",
+            Some(1),
+            Some(vec![(PgBuiltInOids::TEXTOID.oid(), literal.into_datum())]),
+        )
+    })?
+    .first()
+    .get_one::<String>()
+    .unwrap_or_default())
+}
+
+/// Creation of a table or an index along with its comments and security labels
+///
+/// [`CheckedCreate::execute`] runs all the commands in one sub-transaction, so the object is only created if it
+/// could be annotated as well. Names, comments and labels are checked with [`validate`] as they are given, and the
+/// first one rejected is returned by [`CheckedCreate::execute`] before anything is executed.
+#[derive(Debug, Clone)]
+pub struct CheckedCreate {
+    kind: ObjectKind,
+    name: String,
+    query: String,
+    annotations: Vec<Annotation>,
+    rejected: Option<PreflightError>,
+}
+
+#[derive(Debug, Clone)]
+enum Annotation {
+    Comment {
+        kind: ObjectKind,
+        name: String,
+        comment: String,
+    },
+    SecurityLabel {
+        provider: String,
+        label: String,
+    },
+}
+
+impl CheckedCreate {
+    /// Create table `name` (possibly schema-qualified), where `columns_sql` is the column and constraint list used
+    /// verbatim, without the parentheses
+    pub fn table(name: &str, columns_sql: &str) -> Self {
+        let rejected = validate::qualified_identifier(name).err();
+        Self::new(
+            ObjectKind::Table,
+            name,
+            format!(
+                "CREATE TABLE {} ({})",
+                quote_qualified_identifier(name),
+                columns_sql
+            ),
+        )
+        .reject(rejected)
+    }
+
+    /// Create index `name` on `table`, where `definition` is what follows the table, used verbatim, such as `(a, b)`
+    ///
+    /// The index is created in the schema of the table, so `name` is not schema-qualified.
+    pub fn index(name: &str, table: &str, definition: &str) -> Self {
+        let schema = table.rsplit_once('.').map(|(schema, _)| schema);
+        let rejected = validate::identifier(name)
+            .and_then(|_| validate::qualified_identifier(table))
+            .err();
+        Self::new(
+            ObjectKind::Index,
+            &schema.map_or(name.to_string(), |schema| format!("{}.{}", schema, name)),
+            format!(
+                "CREATE INDEX {} ON {} {}",
+                quote_identifier(name),
+                quote_qualified_identifier(table),
+                definition
+            ),
+        )
+        .reject(rejected)
+    }
+
+    fn new(kind: ObjectKind, name: &str, query: String) -> Self {
+        Self {
+            kind,
+            name: name.to_string(),
+            query,
+            annotations: vec![],
+            rejected: None,
+        }
+    }
+
+    /// Remember the first value rejected
+    fn reject(mut self, rejected: Option<impl Into<PreflightError>>) -> Self {
+        if self.rejected.is_none() {
+            self.rejected = rejected.map(Into::into);
+        }
+        self
+    }
+
+    /// Comment the object
+    pub fn with_comment(mut self, comment: &str) -> Self {
+        let rejected = validate::literal(comment).err();
+        self.annotations.push(Annotation::Comment {
+            kind: self.kind,
+            name: self.name.clone(),
+            comment: comment.to_string(),
+        });
+        self.reject(rejected)
+    }
+
+    /// Comment a column of the table
+    pub fn with_column_comment(mut self, column: &str, comment: &str) -> Self {
+        let rejected = validate::identifier(column)
+            .map_err(PreflightError::from)
+            .and_then(|_| validate::literal(comment).map_err(PreflightError::from))
+            .err();
+        self.annotations.push(Annotation::Comment {
+            kind: ObjectKind::Column,
+            name: format!("{}.{}", self.name, column),
+            comment: comment.to_string(),
+        });
+        self.reject(rejected)
+    }
+
+    /// Label the object for `provider`
+    pub fn with_security_label(mut self, provider: &str, label: &str) -> Self {
+        let rejected = validate::identifier(provider)
+            .map_err(PreflightError::from)
+            .and_then(|_| validate::literal(label).map_err(PreflightError::from))
+            .err();
+        self.annotations.push(Annotation::SecurityLabel {
+            provider: provider.to_string(),
+            label: label.to_string(),
+        });
+        self.reject(rejected)
+    }
+
+    /// Create and annotate the object, or do neither if an error is caught
+    pub fn execute(self, client: &mut SpiClient) -> Result<(), Error> {
+        if let Some(rejected) = self.rejected {
+            return Err(Error::Preflight(rejected));
+        }
+        let mut queries = vec![self.query];
+        for annotation in &self.annotations {
+            queries.push(match annotation {
+                Annotation::Comment {
+                    kind,
+                    name,
+                    comment,
+                } => comment_query(*kind, name, comment)?,
+                Annotation::SecurityLabel { provider, label } => {
+                    security_label_query(provider, self.kind, &self.name, label)?
+                }
+            });
+        }
+        checked_sub_transaction_of(client, move |client| {
+            for query in &queries {
+                client.update(query, None, None);
+            }
+        })
+    }
+}
+
+/// Error of the commands creating, altering and dropping domains and composite types
+#[derive(Debug)]
+pub enum TypeDdlError {
+    /// A type (or, for [`checked_add_composite_field`], a field) with this name already exists
+    AlreadyExists(String),
+    /// There is no type with this name
+    DoesNotExist(String),
+    /// Postgres couldn't parse this type name, or there is no such type
+    InvalidType { name: String, error: CaughtError },
+    /// Other objects depend on the type, so it can't be dropped with [`DropBehavior::Restrict`]
+    DependentObjectsExist(CaughtError),
+    /// Objects that would be dropped along with the type by [`DropBehavior::Cascade`], returned by
+    /// [`DropBehavior::ListDependentsFirst`] instead of dropping anything
+    WouldDrop { objects: Vec<DependentObject> },
+    /// The type's lock couldn't be acquired within the lock timeout
+    LockTimeout(CaughtError),
+    /// The command failed for another reason
+    Query(Error),
+}
+
+impl Display for TypeDdlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypeDdlError::AlreadyExists(name) => write!(f, "\"{}\" already exists", name),
+            TypeDdlError::DoesNotExist(name) => write!(f, "type \"{}\" does not exist", name),
+            TypeDdlError::InvalidType { name, error } => {
+                write!(f, "invalid type \"{}\": {}", name, report(error).message())
+            }
+            TypeDdlError::DependentObjectsExist(err) | TypeDdlError::LockTimeout(err) => {
+                write!(f, "{}", report(err).message())
+            }
+            TypeDdlError::WouldDrop { objects } => write!(
+                f,
+                "dropping the type would drop {} other objects",
+                objects.len()
+            ),
+            TypeDdlError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for TypeDdlError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Caught(err, captured) => match report(&err).sqlstate().as_str() {
+                "2BP01" => TypeDdlError::DependentObjectsExist(err),
+                "55P03" => TypeDdlError::LockTimeout(err),
+                _ => TypeDdlError::Query(Error::Caught(err, captured)),
+            },
+            err => TypeDdlError::Query(err),
+        }
+    }
+}
+
+/// Object depending on a type, see [`DropBehavior::ListDependentsFirst`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependentObject {
+    /// Kind of the object, as named by `pg_identify_object` (`table`, `table column`, `view`, ...)
+    pub kind: String,
+    /// Schema-qualified name of the object, as given by `pg_identify_object`
+    pub identity: String,
+    /// Description of the object, as in the notices of `DROP ... CASCADE` (such as `table public.t`)
+    pub description: String,
+}
+
+/// How [`checked_drop_type`] treats objects that depend on the type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropBehavior {
+    /// Fail with [`TypeDdlError::DependentObjectsExist`] if other objects depend on the type
+    Restrict,
+    /// Drop the objects that depend on the type along with it (recursively)
+    Cascade,
+    /// Return the objects [`Cascade`](DropBehavior::Cascade) would drop along with the type as
+    /// [`TypeDdlError::WouldDrop`] without dropping anything, or drop the type if there are none
+    ListDependentsFirst,
+}
+
+/// Report of [`checked_drop_type`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DropReport {
+    /// Objects dropped along with the type (only with [`DropBehavior::Cascade`])
+    pub dropped: Vec<DependentObject>,
+}
+
+/// Options of [`checked_add_composite_field`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlterTypeOpts {
+    /// How long to wait for the locks of the type and the tables using it before giving up with
+    /// [`TypeDdlError::LockTimeout`] (rounded up to a millisecond), instead of the `lock_timeout` setting
+    pub lock_timeout: Option<Duration>,
+    /// Add the field to the typed tables of the type (`CREATE TABLE ... OF`) as well, which is refused otherwise
+    pub cascade: bool,
+}
+
+/// Create domain `name` over `base_type`, with a default and constraints
+///
+/// `name` is possibly schema-qualified and quoted, and `base_type` is parsed by Postgres (as in a cast, such as
+/// `numeric(10, 2)` or `text[]`) before the command is built. `constraints` (such as `CHECK (VALUE > 0)` or
+/// `NOT NULL`) and `default` are SQL used verbatim. Returns [`TypeDdlError::AlreadyExists`] without executing anything
+/// if a type of that name is visible already.
+pub fn checked_create_domain(
+    client: &mut SpiClient,
+    name: &str,
+    base_type: &str,
+    constraints: &[&str],
+    default: Option<&str>,
+) -> Result<(), TypeDdlError> {
+    check_type_absent(name)?;
+    let mut query = format!(
+        "CREATE DOMAIN {} AS {}",
+        quote_qualified_identifier(name),
+        resolve_type(base_type)?
+    );
+    if let Some(default) = default {
+        query.push_str(" DEFAULT ");
+        query.push_str(default);
+    }
+    for constraint in constraints {
+        query.push(' ');
+        query.push_str(constraint);
+    }
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// Create composite type `name` with `fields`, given as (name, type) in order
+///
+/// `name` and the names of the fields are quoted, and their types are parsed by Postgres as by
+/// [`checked_create_domain`]. Returns [`TypeDdlError::AlreadyExists`] without executing anything if a type of that
+/// name is visible already.
+pub fn checked_create_composite(
+    client: &mut SpiClient,
+    name: &str,
+    fields: &[(&str, &str)],
+) -> Result<(), TypeDdlError> {
+    check_type_absent(name)?;
+    let fields = fields
+        .iter()
+        .map(|(field, field_type)| {
+            validate::identifier(field).map_err(Error::from)?;
+            Ok(format!(
+                "{} {}",
+                quote_identifier(field),
+                resolve_type(field_type)?
+            ))
+        })
+        .collect::<Result<Vec<_>, TypeDdlError>>()?;
+    let query = format!(
+        "CREATE TYPE {} AS ({})",
+        quote_qualified_identifier(name),
+        fields.join(", ")
+    );
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// Add field `field` of type `field_type` to composite type `name`, after its other fields
+///
+/// Values of the type stored in tables aren't rewritten: they have the new field as NULL. With
+/// [`AlterTypeOpts::cascade`], however, the field is added as a column to every typed table of the type, and if its
+/// type is a domain with constraints (or a volatile default), each of them is rewritten, holding an `ACCESS EXCLUSIVE`
+/// lock on it until the transaction ends. The command runs in a sub-transaction of its own, with `lock_timeout` set for
+/// its duration if `opts` has one.
+pub fn checked_add_composite_field(
+    client: &mut SpiClient,
+    name: &str,
+    field: &str,
+    field_type: &str,
+    opts: AlterTypeOpts,
+) -> Result<(), TypeDdlError> {
+    validate::identifier(field).map_err(Error::from)?;
+    check_type_exists(name)?;
+    let exists = rewrite::exempt(|| {
+        (&*client).checked_select(
+            "SELECT EXISTS (SELECT FROM pg_attribute WHERE attrelid = \
+             (SELECT typrelid FROM pg_type WHERE oid = $1::regtype) AND attname = $2 AND NOT attisdropped)",
+            Some(1),
+            Some(vec![
+                (
+                    PgBuiltInOids::TEXTOID.oid(),
+                    quote_qualified_identifier(name).into_datum(),
+                ),
+                (PgBuiltInOids::TEXTOID.oid(), field.into_datum()),
+            ]),
+        )
+    })?
+    .first()
+    .get_one::<bool>()
+    .unwrap_or_default();
+    if exists {
+        return Err(TypeDdlError::AlreadyExists(format!("{}.{}", name, field)));
+    }
+    let query = format!(
+        "ALTER TYPE {} ADD ATTRIBUTE {} {} {}",
+        quote_qualified_identifier(name),
+        quote_identifier(field),
+        resolve_type(field_type)?,
+        if opts.cascade { "CASCADE" } else { "RESTRICT" }
+    );
+    let lock_timeout = opts.lock_timeout.map(timeout_setting);
+    checked_sub_transaction(move |client| {
+        let alter = |client: &mut SpiClient| {
+            client.update(&query, None, None);
+        };
+        match lock_timeout {
+            Some(timeout) => with_local(client, "lock_timeout", &timeout, alter),
+            None => alter(client),
+        }
+    })?;
+    Ok(())
+}
+
+/// Drop type `name` (possibly schema-qualified), domains included
+///
+/// Objects depending on the type are looked up in `pg_depend` as `DROP ... CASCADE` does, following the objects that
+/// would be dropped along with it: those that would be reported by its notices are returned. Objects that are dropped
+/// silently, such as a domain's constraints or a table's indexes, aren't. Returns [`TypeDdlError::DoesNotExist`]
+/// without executing anything if there is no such type.
+pub fn checked_drop_type(
+    client: &mut SpiClient,
+    name: &str,
+    behavior: DropBehavior,
+) -> Result<DropReport, TypeDdlError> {
+    check_type_exists(name)?;
+    let dropped = match behavior {
+        DropBehavior::Restrict => vec![],
+        DropBehavior::Cascade | DropBehavior::ListDependentsFirst => dependent_objects(name)?,
+    };
+    if behavior == DropBehavior::ListDependentsFirst && !dropped.is_empty() {
+        return Err(TypeDdlError::WouldDrop { objects: dropped });
+    }
+    let query = format!(
+        "DROP TYPE {} {}",
+        quote_qualified_identifier(name),
+        if behavior == DropBehavior::Cascade {
+            "CASCADE"
+        } else {
+            "RESTRICT"
+        }
+    );
+    client.checked_update(&query, None, None)?;
+    Ok(DropReport { dropped })
+}
+
+/// Objects `DROP TYPE ... CASCADE` would report dropping along with type `name`, ordered by description
+fn dependent_objects(name: &str) -> Result<Vec<DependentObject>, Error> {
+    // Objects reached through internal dependencies (such as a composite type's relation or a type's array type) are
+    // dropped along with the object they depend on, so they are followed but not reported, as are automatic
+    // dependencies; a dependency on a column only follows what depends on that column
+    let table = rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "WITH RECURSIVE dependents(classid, objid, objsubid, normal) AS ( \
+                 SELECT 'pg_type'::regclass::oid, $1::regtype::oid, 0, false \
+                 UNION \
+                 SELECT d.classid, d.objid, d.objsubid, d.deptype = 'n' FROM pg_depend d \
+                 JOIN dependents o ON d.refclassid = o.classid AND d.refobjid = o.objid \
+                 AND (o.objsubid = 0 OR d.refobjsubid = o.objsubid) \
+                 WHERE d.deptype IN ('n', 'a', 'i') \
+             ) \
+             SELECT DISTINCT i.type, i.identity, pg_describe_object(classid, objid, objsubid) \
+             FROM dependents, pg_identify_object(classid, objid, objsubid) i WHERE normal ORDER BY 3",
+            None,
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                quote_qualified_identifier(name).into_datum(),
+            )]),
+        )
+    })?;
+    Ok(table
+        .map(|row| DependentObject {
+            kind: row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+            identity: row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+            description: row.by_ordinal(3).unwrap().value().unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// Whether a type named `name` (possibly schema-qualified) is visible, validating the name
+fn type_exists(name: &str) -> Result<bool, Error> {
+    validate::qualified_identifier(name)?;
+    Ok(rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "SELECT to_regtype($1) IS NOT NULL",
+            Some(1),
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                quote_qualified_identifier(name).into_datum(),
+            )]),
+        )
+    })?
+    .first()
+    .get_one::<bool>()
+    .unwrap_or_default())
+}
+
+fn check_type_absent(name: &str) -> Result<(), TypeDdlError> {
+    if type_exists(name)? {
+        return Err(TypeDdlError::AlreadyExists(name.to_string()));
+    }
+    Ok(())
+}
+
+fn check_type_exists(name: &str) -> Result<(), TypeDdlError> {
+    if !type_exists(name)? {
+        return Err(TypeDdlError::DoesNotExist(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Parse a type name as Postgres does, returning it as formatted by Postgres (with its modifiers, and quoted)
+fn resolve_type(name: &str) -> Result<String, TypeDdlError> {
+    validate::literal(name).map_err(Error::from)?;
+    let type_name = CString::new(name).unwrap();
+    checked_sub_transaction(move |_| unsafe {
+        let (mut type_oid, mut typmod) = (pg_sys::InvalidOid, -1);
+        pg_sys::parseTypeString(type_name.as_ptr(), &mut type_oid, &mut typmod, false);
+        let formatted = pg_sys::format_type_with_typemod(type_oid, typmod);
+        let resolved = CStr::from_ptr(formatted).to_string_lossy().into_owned();
+        pg_sys::pfree(formatted.cast());
+        resolved
+    })
+    .map_err(|err| match err {
+        Error::Caught(error, _) => TypeDdlError::InvalidType {
+            name: name.to_string(),
+            error,
+        },
+        err => TypeDdlError::Query(err),
+    })
+}
@@ -0,0 +1,141 @@
+//! Work deferred until a sub-transaction commits
+
+use pgx::{pg_sys::Datum, PgOid, PgTryBuilder, SpiClient};
+use std::fmt::{Display, Formatter};
+use std::panic::{AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+
+use crate::error::{capture_sqlstate, handled, Error};
+use crate::subtxn::SubTransaction;
+
+/// Item deferred with [`SubTransaction::defer`] or [`SubTransaction::defer_fn`]
+enum Deferred {
+    Query {
+        query: String,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    },
+    Fn {
+        label: String,
+        f: Box<dyn FnOnce(&mut SpiClient) -> Result<(), Error>>,
+    },
+}
+
+impl Deferred {
+    /// Query text or label of the item
+    fn label(&self) -> &str {
+        match self {
+            Deferred::Query { query, .. } => query,
+            Deferred::Fn { label, .. } => label,
+        }
+    }
+
+    /// Run the item directly in the current sub-transaction, catching errors
+    fn run(self) -> Result<(), Error> {
+        let item = AssertUnwindSafe(self);
+        capture_sqlstate(|| {
+            PgTryBuilder::new(move || {
+                let item = item;
+                match item.0 {
+                    Deferred::Query { query, args } => {
+                        SpiClient.update(&query, None, args);
+                        Ok(Ok(()))
+                    }
+                    Deferred::Fn { f, .. } => Ok(f(&mut SpiClient)),
+                }
+            })
+            .catch_others(|e| Err(e))
+            .execute()
+        })
+        .map_err(handled)?
+    }
+}
+
+/// Items deferred until a sub-transaction commits, in registration order
+#[derive(Default)]
+pub(crate) struct DeferredQueue(Vec<Deferred>);
+
+// Deferred items only run when the sub-transaction commits. A sub-transaction that a panic unwound through is rolled
+// back, dropping them without running them.
+impl UnwindSafe for DeferredQueue {}
+impl RefUnwindSafe for DeferredQueue {}
+
+impl DeferredQueue {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run all the items in order, stopping at the first one that fails
+    pub(crate) fn run(&mut self) -> Result<(), CommitError> {
+        for (index, item) in std::mem::take(&mut self.0).into_iter().enumerate() {
+            let label = item.label().to_string();
+            if let Err(error) = item.run() {
+                return Err(CommitError::Deferred {
+                    index,
+                    item: label,
+                    error,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by [`SubTransaction::checked_commit`], which rolled the sub-transaction back instead
+#[derive(Debug)]
+pub enum CommitError {
+    /// The deferred item at this (0-based) index failed; `item` is its query text or label
+    Deferred {
+        index: usize,
+        item: String,
+        error: Error,
+    },
+    /// The sub-transaction couldn't be committed, such as because the SPI connection stack changed
+    Query(Error),
+    /// The sub-transaction was already rolled back by [`recovery_scope`](crate::subtxn::recovery_scope)
+    Poisoned,
+}
+
+impl Display for CommitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommitError::Deferred { index, item, error } => {
+                write!(f, "deferred item {} ({}) failed: {}", index, item, error)
+            }
+            CommitError::Query(err) => write!(f, "{}", err),
+            CommitError::Poisoned => write!(f, "it was already rolled back by recovery_scope"),
+        }
+    }
+}
+
+impl From<Error> for CommitError {
+    fn from(err: Error) -> Self {
+        CommitError::Query(err)
+    }
+}
+
+impl<Parent, const COMMIT: bool> SubTransaction<Parent, COMMIT> {
+    /// Execute a mutable command right before the sub-transaction commits, after those deferred before it
+    ///
+    /// Deferred commands execute directly in the sub-transaction. If one fails, the sub-transaction is rolled back
+    /// instead of committed (see [`SubTransaction::checked_commit`]). Nothing deferred runs if it is rolled back.
+    pub fn defer(&mut self, query: &str, args: Option<Vec<(PgOid, Option<Datum>)>>) {
+        self.deferred.0.push(Deferred::Query {
+            query: query.to_string(),
+            args,
+        });
+    }
+
+    /// Run `f` right before the sub-transaction commits, after what was deferred before it
+    ///
+    /// As with [`SubTransaction::defer`], except that `f` failing is an error being raised or returned. `label`
+    /// identifies it in [`CommitError::Deferred`].
+    pub fn defer_fn<F: FnOnce(&mut SpiClient) -> Result<(), Error> + 'static>(
+        &mut self,
+        label: &str,
+        f: F,
+    ) {
+        self.deferred.0.push(Deferred::Fn {
+            label: label.to_string(),
+            f: Box::new(f),
+        });
+    }
+}
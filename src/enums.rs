@@ -0,0 +1,149 @@
+//! Checked enum type management
+//!
+//! Type names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier). Labels are passed as parameters and
+//! quoted by the server, so they may contain any characters.
+
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{IntoDatum, PgBuiltInOids, SpiClient};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::{report, Error, PostgresErrorExt};
+use crate::quote::*;
+use crate::rewrite;
+
+/// Position of a label added by [`checked_add_enum_value`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnumPosition<'a> {
+    /// Right before an existing label
+    Before(&'a str),
+    /// Right after an existing label
+    After(&'a str),
+    /// After all the existing labels
+    Last,
+}
+
+/// Enum management error
+#[derive(Debug)]
+pub enum EnumError {
+    /// The label already exists in the enum (or is given more than once)
+    DuplicateLabel(CaughtError),
+    /// Adding a value is not possible in a transaction block on Postgres 11, which SPI commands always run in
+    CannotRunInTransaction(CaughtError),
+    /// The command failed for another reason
+    Query(Error),
+}
+
+impl Display for EnumError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnumError::DuplicateLabel(err) | EnumError::CannotRunInTransaction(err) => {
+                write!(f, "{}", report(err).message())
+            }
+            EnumError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for EnumError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Caught(err, captured) => {
+                let report = report(&err);
+                // These SQLSTATEs cover other errors as well, so the message is checked too (it may be translated,
+                // in which case the error is reported as is)
+                match report.sqlstate().as_str() {
+                    "42710" if report.message().contains("enum label") => {
+                        EnumError::DuplicateLabel(err)
+                    }
+                    "23505" if report.message().contains("pg_enum") => {
+                        EnumError::DuplicateLabel(err)
+                    }
+                    "25001" => EnumError::CannotRunInTransaction(err),
+                    _ => EnumError::Query(Error::Caught(err, captured)),
+                }
+            }
+            err => EnumError::Query(err),
+        }
+    }
+}
+
+/// Create an enum type with `labels`, in order
+pub fn checked_create_enum(
+    client: &mut SpiClient,
+    name: &str,
+    labels: &[&str],
+) -> Result<(), EnumError> {
+    let labels = quote_labels(labels)?;
+    let query = format!(
+        "CREATE TYPE {} AS ENUM ({})",
+        quote_qualified_identifier(name),
+        labels.join(", ")
+    );
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// Add `label` to the enum type `name`
+///
+/// On Postgres 12 and later, the new label can't be used until the transaction that added it commits.
+pub fn checked_add_enum_value(
+    client: &mut SpiClient,
+    name: &str,
+    label: &str,
+    position: EnumPosition,
+) -> Result<(), EnumError> {
+    let (position, neighbor) = match position {
+        EnumPosition::Before(neighbor) => (" BEFORE ", Some(neighbor)),
+        EnumPosition::After(neighbor) => (" AFTER ", Some(neighbor)),
+        EnumPosition::Last => ("", None),
+    };
+    let labels = quote_labels(
+        &[Some(label), neighbor]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>(),
+    )?;
+    let query = format!(
+        "ALTER TYPE {} ADD VALUE {}{}{}",
+        quote_qualified_identifier(name),
+        labels[0],
+        position,
+        labels.get(1).map_or("", String::as_str)
+    );
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// Labels of the enum type `name`, in their sort order
+pub fn enum_labels(client: &SpiClient, name: &str) -> Result<Vec<String>, Error> {
+    let table = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT enumlabel::text FROM pg_enum WHERE enumtypid = $1::regtype ORDER BY enumsortorder",
+            None,
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                quote_qualified_identifier(name).into_datum(),
+            )]),
+        )
+    })?;
+    Ok(table
+        .map(|row| row.by_ordinal(1).unwrap().value().unwrap_or_default())
+        .collect())
+}
+
+/// Quote labels as literals on the server, which takes care of `standard_conforming_strings`
+fn quote_labels(labels: &[&str]) -> Result<Vec<String>, Error> {
+    let labels: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+    let table = rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "SELECT quote_literal(label) FROM unnest($1::text[]) WITH ORDINALITY AS t(label, n) ORDER BY n",
+            None,
+            Some(vec![(PgBuiltInOids::TEXTARRAYOID.oid(), labels.into_datum())]),
+        )
+    })?;
+    Ok(table
+        .map(|row| row.by_ordinal(1).unwrap().value().unwrap_or_default())
+        .collect())
+}
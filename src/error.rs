@@ -0,0 +1,1075 @@
+use pgx::pg_sys::errcodes::PgSqlErrorCode;
+use pgx::pg_sys::panic::{CaughtError, ErrorReportWithLevel};
+use pgx::{pg_guard, pg_sys, PgMemoryContexts};
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::os::raw::c_char;
+use std::time::Duration;
+
+use context::ContextFrame;
+
+pub mod context;
+pub mod hints;
+
+/// Error returned by checked commands
+///
+/// Variants are added as the crate grows, and some only exist with the `full` feature (such as `Preflight`), which
+/// another crate in the same build may enable: matches must have a wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Error caught while executing the command, along with what was captured as it was raised
+    Caught(CaughtError, Captured),
+    /// Error caught while executing a command in SPI's read-only mode, because the command makes changes (such as a
+    /// `SELECT` with a data-modifying `WITH` query, or `SELECT ... FOR UPDATE`)
+    ///
+    /// Only returned by [`checked_select_opts`](crate::checked::SelectOptsCommands::checked_select_opts) with
+    /// [`SelectOpts::read_only`](crate::checked::SelectOpts::read_only) set, which can be unset to allow the changes.
+    ReadOnlyViolation(CaughtError, Captured),
+    /// The query contains no statements (only whitespace, comments or semicolons)
+    ///
+    /// Such queries are rejected before any sub-transaction is started.
+    EmptyQuery,
+    /// The query contains a transaction control statement (such as `COMMIT` or `SAVEPOINT`), of the given kind
+    ///
+    /// Such queries are rejected before any sub-transaction is started, unless
+    /// [`allow_transaction_control`](crate::checked::allow_transaction_control) is in effect.
+    TransactionControlNotAllowed { statement_kind: &'static str },
+    /// A sub-transaction can't be started because a parallel operation is in progress
+    ParallelModeActive,
+    /// SPI refused to execute the command, returning an error code instead of raising an error
+    ///
+    /// The sub-transaction the command was executed in is rolled back, as with caught errors.
+    Spi(SpiErrorCode),
+    /// The time budget the command was executed within ran out (see [`Budget`](crate::budget::Budget))
+    BudgetExceeded { elapsed: Duration, budget: Duration },
+    /// The SPI connection stack is not as deep as when the sub-transaction began, as something connected to SPI (or
+    /// finished a connection) without undoing it
+    ///
+    /// The command was not executed. See [`SubTransaction`](crate::subtxn::SubTransaction#spi-stack).
+    SpiStackCorruption { expected: u32, actual: u32 },
+    /// Postgres' current sub-transaction is not the one the command was executed in, as another one was begun on top of
+    /// it by other means (and not ended), or it was ended by other means
+    ///
+    /// The command was not executed. See [`SubTransaction`](crate::subtxn::SubTransaction#foreign-sub-transactions).
+    SubTransactionMismatch {
+        expected: pg_sys::SubTransactionId,
+        actual: pg_sys::SubTransactionId,
+    },
+    /// An identifier, literal or list of arguments was rejected before anything was executed (see
+    /// [`validate::identifier`](crate::validate::identifier) for example)
+    #[cfg(feature = "full")]
+    Preflight(crate::validate::PreflightError),
+}
+
+impl Error {
+    /// Report of the caught error, if any
+    ///
+    /// The report is produced by pgx, which converts the error's text to UTF-8 lossily (so characters of a
+    /// non-UTF-8 server encoding may be replaced) and doesn't retain the error's context or internal query. For errors
+    /// caught by checked commands, [`Error::message`], [`Error::detail`] and [`Error::hint`] convert the text from the
+    /// server encoding instead.
+    pub fn report(&self) -> Option<&ErrorReportWithLevel> {
+        match self {
+            Error::Caught(err, _) | Error::ReadOnlyViolation(err, _) => Some(report(err)),
+            Error::EmptyQuery
+            | Error::TransactionControlNotAllowed { .. }
+            | Error::ParallelModeActive
+            | Error::Spi(_)
+            | Error::BudgetExceeded { .. }
+            | Error::SpiStackCorruption { .. }
+            | Error::SubTransactionMismatch { .. } => None,
+            #[cfg(feature = "full")]
+            Error::Preflight(_) => None,
+        }
+    }
+
+    /// What was captured about the caught error as it was raised, if any
+    pub fn captured(&self) -> Option<&Captured> {
+        match self {
+            Error::Caught(_, captured) | Error::ReadOnlyViolation(_, captured) => Some(captured),
+            _ => None,
+        }
+    }
+
+    /// SQLSTATE of the caught error, if any
+    ///
+    /// For errors caught by checked commands, this is the SQLSTATE exactly as the error was raised with, even if
+    /// Postgres doesn't define it (such as one set with PL/pgSQL's `RAISE ... USING ERRCODE`). Otherwise, it is the
+    /// one pgx reports (see [`PostgresErrorExt::sqlstate`]).
+    pub fn sqlstate(&self) -> Option<SqlState> {
+        self.captured()
+            .and_then(|captured| captured.sqlstate)
+            .or_else(|| self.report().map(PostgresErrorExt::sqlstate))
+    }
+
+    /// Whether the error was caught by this crate's checked machinery
+    ///
+    /// Errors returned by checked commands were caught in a sub-transaction that was then rolled back, so they left no
+    /// state behind. Errors converted from a [`CaughtError`] caught by other means (such as a `PgTryBuilder` of one's
+    /// own) are not handled.
+    pub fn was_handled(&self) -> bool {
+        self.captured().map_or(false, |captured| captured.handled)
+    }
+
+    /// Context the caught error was raised in, one frame per line, innermost first
+    ///
+    /// Only captured for errors caught by checked commands, and made of the frames within the command.
+    pub fn context(&self) -> Option<&str> {
+        self.captured()?
+            .context()
+            .map(|context| context.value.as_str())
+    }
+
+    /// Message of the caught error, if any
+    pub fn message(&self) -> Option<&str> {
+        match self.text() {
+            Some(text) => Some(&text.message),
+            None => self.report().map(ErrorReportWithLevel::message),
+        }
+    }
+
+    /// Query the caught error was raised in, when it isn't the command itself (such as the query of a PL/pgSQL
+    /// `EXECUTE`)
+    ///
+    /// Only captured for errors caught by checked commands.
+    pub fn internal_query(&self) -> Option<&str> {
+        self.captured()?
+            .internal_query()
+            .map(|query| query.value.as_str())
+    }
+
+    /// Detail of the caught error only written to the server log
+    ///
+    /// Only captured for errors caught by checked commands.
+    pub fn detail_log(&self) -> Option<&str> {
+        self.captured()?
+            .detail_log()
+            .map(|detail| detail.value.as_str())
+    }
+
+    /// Detail of the caught error, as set with `RAISE ... USING DETAIL` for example
+    pub fn detail(&self) -> Option<&str> {
+        match self.text() {
+            Some(text) => text.detail.as_deref(),
+            None => self.report().and_then(ErrorReportWithLevel::detail),
+        }
+    }
+
+    /// Hint of the caught error, as set with `RAISE ... USING HINT` for example
+    pub fn hint(&self) -> Option<&str> {
+        match self.text() {
+            Some(text) => text.hint.as_deref(),
+            None => self.report().and_then(ErrorReportWithLevel::hint),
+        }
+    }
+
+    fn text(&self) -> Option<&CapturedText> {
+        self.captured()?.text.as_ref()
+    }
+
+    /// Frames of the context, innermost first (see [`context`](mod@context))
+    pub fn context_frames(&self) -> Vec<ContextFrame> {
+        self.context().map_or(vec![], context::parse)
+    }
+
+    /// Innermost PL/pgSQL or SQL function frame of the context
+    pub fn deepest_user_function(&self) -> Option<ContextFrame> {
+        context::deepest_user_function(&self.context_frames()).cloned()
+    }
+
+    /// Severity class of the caught error, if any (see [`SqlState::severity_class`])
+    pub fn severity_class(&self) -> Option<SeverityClass> {
+        self.sqlstate().map(|sqlstate| sqlstate.severity_class())
+    }
+
+    /// Format the error along with its detail and hint
+    ///
+    /// If the error has no hint of its own, a remediation hint for its SQLSTATE is used instead, if there is
+    /// one (see [`hints`]).
+    pub fn display_with_hints(&self) -> DisplayWithHints<'_> {
+        DisplayWithHints(self)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Caught(..) => {
+                write!(f, "{}", self.message().unwrap_or_default())?;
+                if let Some(detail) = self.detail() {
+                    write!(f, "\nDETAIL: {}", detail)?;
+                }
+                if let Some(hint) = self.hint() {
+                    write!(f, "\nHINT: {}", hint)?;
+                }
+                Ok(())
+            }
+            Error::ReadOnlyViolation(err, _) => write!(
+                f,
+                "{} (the command was executed in SPI's read-only mode, which SelectOpts::read_only sets)",
+                report(err).message()
+            ),
+            Error::EmptyQuery => write!(f, "empty query"),
+            Error::TransactionControlNotAllowed { statement_kind } => {
+                write!(f, "{} is not allowed in checked commands", statement_kind)
+            }
+            Error::ParallelModeActive => {
+                write!(
+                    f,
+                    "cannot start subtransactions during a parallel operation"
+                )
+            }
+            Error::Spi(code) => write!(f, "{}", code),
+            Error::BudgetExceeded { elapsed, budget } => {
+                write!(
+                    f,
+                    "time budget of {:?} exceeded after {:?}",
+                    budget, elapsed
+                )
+            }
+            Error::SpiStackCorruption { expected, actual } => {
+                write!(
+                    f,
+                    "SPI connection stack is {} levels deep instead of {}",
+                    actual, expected
+                )
+            }
+            Error::SubTransactionMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "current sub-transaction is {} instead of {}",
+                    actual, expected
+                )
+            }
+            #[cfg(feature = "full")]
+            Error::Preflight(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<CaughtError> for Error {
+    /// Nothing is known of how the error was caught, so it is not recorded as handled (see [`Error::was_handled`])
+    ///
+    /// Panics carrying an SPI error code (raised instead of pgx's panics on those, see [`spi_error_panic`]) are
+    /// converted to [`Error::Spi`].
+    fn from(err: CaughtError) -> Self {
+        Error::with_captured(err, Captured::default())
+    }
+}
+
+impl Error {
+    fn with_captured(err: CaughtError, captured: Captured) -> Self {
+        let code = match &err {
+            CaughtError::RustPanic { payload, .. } => {
+                payload.downcast_ref::<SpiErrorCode>().copied()
+            }
+            _ => None,
+        };
+        match code {
+            Some(code) => Error::Spi(code),
+            None => Error::Caught(err, captured),
+        }
+    }
+}
+
+/// What was captured about a caught error as it was raised, which pgx's report of it doesn't retain
+///
+/// Only errors caught by checked commands have anything captured; see the accessors of [`Error`]. Their text is
+/// converted from the server encoding to UTF-8, and the context, internal query and detail for the server log are
+/// truncated to [`set_field_cap`]'s number of bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Captured {
+    handled: bool,
+    sqlstate: Option<SqlState>,
+    text: Option<CapturedText>,
+    /// Text of the failed command before it was rewritten (see [`rewrite`](crate::rewrite))
+    pub(crate) original_query: Option<String>,
+}
+
+impl Captured {
+    /// Context the error was raised in (see [`Error::context`])
+    pub fn context(&self) -> Option<&MaybeTruncated<String>> {
+        self.text.as_ref()?.context.as_ref()
+    }
+
+    /// Query the error was raised in (see [`Error::internal_query`])
+    pub fn internal_query(&self) -> Option<&MaybeTruncated<String>> {
+        self.text.as_ref()?.internal_query.as_ref()
+    }
+
+    /// Detail only written to the server log (see [`Error::detail_log`])
+    pub fn detail_log(&self) -> Option<&MaybeTruncated<String>> {
+        self.text.as_ref()?.detail_log.as_ref()
+    }
+
+    /// Fields whose text isn't valid in the server encoding (or has characters UTF-8 can't represent), and was
+    /// therefore converted lossily, such as `"message"` or `"context"`
+    pub fn lossy_fields(&self) -> &[&'static str] {
+        self.text
+            .as_ref()
+            .map_or(&[], |text| text.lossy_fields.as_slice())
+    }
+}
+
+/// Text fields of a caught error, converted to UTF-8
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CapturedText {
+    message: String,
+    detail: Option<String>,
+    hint: Option<String>,
+    context: Option<MaybeTruncated<String>>,
+    internal_query: Option<MaybeTruncated<String>>,
+    detail_log: Option<MaybeTruncated<String>>,
+    lossy_fields: Vec<&'static str>,
+}
+
+/// Value that may have been cut short, such as a field of a caught error (see [`set_field_cap`])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaybeTruncated<T> {
+    pub value: T,
+    /// Whether the end of the value was cut off
+    pub truncated: bool,
+}
+
+/// Number of bytes the context, internal query and detail for the server log of caught errors are truncated to, unless
+/// set otherwise with [`set_field_cap`]
+pub const DEFAULT_FIELD_CAP: usize = 64 * 1024;
+
+/// Set the number of bytes the context, internal query and detail for the server log of errors caught from now on in
+/// this backend are truncated to
+///
+/// Truncation happens at a character boundary, so fields may be a few bytes shorter.
+pub fn set_field_cap(bytes: usize) {
+    FIELD_CAP.with(|cap| cap.set(bytes));
+}
+
+/// Error code returned by an SPI function (the `SPI_ERROR_*` constants)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpiErrorCode {
+    /// `SPI_ERROR_CONNECT`
+    Connect,
+    /// `SPI_ERROR_COPY`: `COPY` to or from the client
+    Copy,
+    /// `SPI_ERROR_OPUNKNOWN`: unknown command type
+    OpUnknown,
+    /// `SPI_ERROR_UNCONNECTED`
+    Unconnected,
+    /// `SPI_ERROR_CURSOR`
+    Cursor,
+    /// `SPI_ERROR_ARGUMENT`: invalid argument, such as a negative row limit
+    Argument,
+    /// `SPI_ERROR_PARAM`
+    Param,
+    /// `SPI_ERROR_TRANSACTION`: a transaction control statement (`BEGIN`, `COMMIT`, `ROLLBACK`, ...)
+    Transaction,
+    /// `SPI_ERROR_NOATTRIBUTE`
+    NoAttribute,
+    /// `SPI_ERROR_NOOUTFUNC`
+    NoOutFunc,
+    /// `SPI_ERROR_TYPUNKNOWN`
+    TypUnknown,
+    /// `SPI_ERROR_REL_DUPLICATE`
+    RelDuplicate,
+    /// `SPI_ERROR_REL_NOT_FOUND`
+    RelNotFound,
+}
+
+impl SpiErrorCode {
+    const ALL: [SpiErrorCode; 13] = [
+        SpiErrorCode::Connect,
+        SpiErrorCode::Copy,
+        SpiErrorCode::OpUnknown,
+        SpiErrorCode::Unconnected,
+        SpiErrorCode::Cursor,
+        SpiErrorCode::Argument,
+        SpiErrorCode::Param,
+        SpiErrorCode::Transaction,
+        SpiErrorCode::NoAttribute,
+        SpiErrorCode::NoOutFunc,
+        SpiErrorCode::TypUnknown,
+        SpiErrorCode::RelDuplicate,
+        SpiErrorCode::RelNotFound,
+    ];
+
+    /// Error code for an SPI function's result, if it's negative and known
+    pub fn from_code(code: i32) -> Option<Self> {
+        Self::ALL.into_iter().find(|error| error.code() == code)
+    }
+
+    /// Value of the `SPI_ERROR_*` constant
+    pub fn code(&self) -> i32 {
+        (match self {
+            SpiErrorCode::Connect => pg_sys::SPI_ERROR_CONNECT,
+            SpiErrorCode::Copy => pg_sys::SPI_ERROR_COPY,
+            SpiErrorCode::OpUnknown => pg_sys::SPI_ERROR_OPUNKNOWN,
+            SpiErrorCode::Unconnected => pg_sys::SPI_ERROR_UNCONNECTED,
+            SpiErrorCode::Cursor => pg_sys::SPI_ERROR_CURSOR,
+            SpiErrorCode::Argument => pg_sys::SPI_ERROR_ARGUMENT,
+            SpiErrorCode::Param => pg_sys::SPI_ERROR_PARAM,
+            SpiErrorCode::Transaction => pg_sys::SPI_ERROR_TRANSACTION,
+            SpiErrorCode::NoAttribute => pg_sys::SPI_ERROR_NOATTRIBUTE,
+            SpiErrorCode::NoOutFunc => pg_sys::SPI_ERROR_NOOUTFUNC,
+            SpiErrorCode::TypUnknown => pg_sys::SPI_ERROR_TYPUNKNOWN,
+            SpiErrorCode::RelDuplicate => pg_sys::SPI_ERROR_REL_DUPLICATE,
+            SpiErrorCode::RelNotFound => pg_sys::SPI_ERROR_REL_NOT_FOUND,
+        }) as i32
+    }
+
+    /// Name of the `SPI_ERROR_*` constant
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpiErrorCode::Connect => "SPI_ERROR_CONNECT",
+            SpiErrorCode::Copy => "SPI_ERROR_COPY",
+            SpiErrorCode::OpUnknown => "SPI_ERROR_OPUNKNOWN",
+            SpiErrorCode::Unconnected => "SPI_ERROR_UNCONNECTED",
+            SpiErrorCode::Cursor => "SPI_ERROR_CURSOR",
+            SpiErrorCode::Argument => "SPI_ERROR_ARGUMENT",
+            SpiErrorCode::Param => "SPI_ERROR_PARAM",
+            SpiErrorCode::Transaction => "SPI_ERROR_TRANSACTION",
+            SpiErrorCode::NoAttribute => "SPI_ERROR_NOATTRIBUTE",
+            SpiErrorCode::NoOutFunc => "SPI_ERROR_NOOUTFUNC",
+            SpiErrorCode::TypUnknown => "SPI_ERROR_TYPUNKNOWN",
+            SpiErrorCode::RelDuplicate => "SPI_ERROR_REL_DUPLICATE",
+            SpiErrorCode::RelNotFound => "SPI_ERROR_REL_NOT_FOUND",
+        }
+    }
+}
+
+/// Panic on an SPI function's negative result, with the error code as the payload so that checked commands report it
+/// as [`Error::Spi`]
+pub(crate) fn spi_error_panic(function: &str, code: i32) -> ! {
+    match SpiErrorCode::from_code(code) {
+        Some(error) => std::panic::panic_any(error),
+        None => panic!("{} failed with code {}", function, code),
+    }
+}
+
+/// Raise an error as Postgres raises errors, with a detail (for the client and for the server log) and a hint unlike
+/// pgx's `ereport!`
+pub(crate) fn raise(
+    sqlstate: SqlState,
+    message: &str,
+    detail: Option<&str>,
+    detail_log: Option<&str>,
+    hint: Option<&str>,
+) -> ! {
+    let text = |text: &str| CString::new(text.replace('\0', "")).unwrap();
+    let message = text(message);
+    let detail = detail.map(text);
+    let detail_log = detail_log.map(text);
+    let hint = hint.map(text);
+    unsafe {
+        let mut data: pg_sys::ErrorData = std::mem::zeroed();
+        data.elevel = pg_sys::ERROR as i32;
+        data.sqlerrcode = sqlstate.to_raw();
+        // The source location is referred to rather than copied, so it must be static
+        data.filename = b"error.rs\0".as_ptr() as *const std::os::raw::c_char;
+        data.funcname = b"raise\0".as_ptr() as *const std::os::raw::c_char;
+        // The rest is copied
+        data.message = message.as_ptr() as *mut std::os::raw::c_char;
+        data.detail = detail
+            .as_ref()
+            .map_or(std::ptr::null_mut(), |detail| detail.as_ptr() as *mut _);
+        data.detail_log = detail_log
+            .as_ref()
+            .map_or(std::ptr::null_mut(), |detail| detail.as_ptr() as *mut _);
+        data.hint = hint
+            .as_ref()
+            .map_or(std::ptr::null_mut(), |hint| hint.as_ptr() as *mut _);
+        pg_sys::ThrowErrorData(&mut data);
+    }
+    unreachable!("ThrowErrorData returned for an error")
+}
+
+impl Display for SpiErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            SpiErrorCode::Connect => "could not connect to SPI",
+            SpiErrorCode::Copy => "COPY to or from the client is not allowed here",
+            SpiErrorCode::OpUnknown => "unknown command type",
+            SpiErrorCode::Unconnected => "not connected to SPI",
+            SpiErrorCode::Cursor => "cursor operation failed",
+            SpiErrorCode::Argument => "invalid argument",
+            SpiErrorCode::Param => "invalid parameter",
+            SpiErrorCode::Transaction => "transaction control statements are not allowed here",
+            SpiErrorCode::NoAttribute => "no such attribute",
+            SpiErrorCode::NoOutFunc => "no output function",
+            SpiErrorCode::TypUnknown => "unknown type",
+            SpiErrorCode::RelDuplicate => "duplicate relation",
+            SpiErrorCode::RelNotFound => "relation not found",
+        };
+        write!(f, "{} ({})", message, self.name())
+    }
+}
+
+thread_local! {
+    static RAISED_SQLSTATE: Cell<Option<SqlState>> = Cell::new(None);
+    static RAISED_DATA: Cell<*mut pg_sys::ErrorData> = Cell::new(std::ptr::null_mut());
+    static FIELD_CAP: Cell<usize> = Cell::new(DEFAULT_FIELD_CAP);
+}
+
+/// Text fields of an error as it was raised, in the server encoding
+///
+/// The context, internal query and detail for the server log are truncated as they are copied, along with whether they
+/// were.
+struct RaisedText {
+    message: Vec<u8>,
+    detail: Option<Vec<u8>>,
+    hint: Option<Vec<u8>>,
+    context: Option<(Vec<u8>, bool)>,
+    internal_query: Option<(Vec<u8>, bool)>,
+    detail_log: Option<(Vec<u8>, bool)>,
+}
+
+impl RaisedText {
+    /// Convert the fields to UTF-8
+    ///
+    /// Not done as the error is raised, as the conversion may raise errors of its own.
+    fn convert(self) -> CapturedText {
+        let mut lossy_fields = vec![];
+        let mut convert = |name: &'static str, raw: Vec<u8>| {
+            let (text, lossy) = server_to_utf8(raw);
+            if lossy {
+                lossy_fields.push(name);
+            }
+            text
+        };
+        let message = convert("message", self.message);
+        let detail = self.detail.map(|raw| convert("detail", raw));
+        let hint = self.hint.map(|raw| convert("hint", raw));
+        let mut truncated =
+            |name: &'static str, (raw, truncated): (Vec<u8>, bool)| MaybeTruncated {
+                value: convert(name, raw),
+                truncated,
+            };
+        let context = self.context.map(|raw| truncated("context", raw));
+        let internal_query = self
+            .internal_query
+            .map(|raw| truncated("internal_query", raw));
+        let detail_log = self.detail_log.map(|raw| truncated("detail_log", raw));
+        CapturedText {
+            message,
+            detail,
+            hint,
+            context,
+            internal_query,
+            detail_log,
+            lossy_fields,
+        }
+    }
+}
+
+/// Convert text in the server encoding to UTF-8, returning whether it had to be converted lossily
+fn server_to_utf8(raw: Vec<u8>) -> (String, bool) {
+    let encoding = unsafe { pg_sys::GetDatabaseEncoding() };
+    // SQL_ASCII doesn't tell what the bytes mean, so they are taken as UTF-8
+    if encoding == pg_sys::pg_enc_PG_UTF8 as i32 || encoding == pg_sys::pg_enc_PG_SQL_ASCII as i32 {
+        return match String::from_utf8(raw) {
+            Ok(text) => (text, false),
+            Err(err) => (String::from_utf8_lossy(err.as_bytes()).into_owned(), true),
+        };
+    }
+    let valid = unsafe {
+        pg_sys::pg_verify_mbstr(
+            encoding,
+            raw.as_ptr() as *const c_char,
+            raw.len() as i32,
+            true,
+        )
+    };
+    match valid.then(|| convert_to_utf8(&raw, encoding)).flatten() {
+        Some(text) => (text, false),
+        None => (String::from_utf8_lossy(&raw).into_owned(), true),
+    }
+}
+
+/// Convert text valid in the server encoding to UTF-8, `None` if it has characters UTF-8 can't represent
+///
+/// The bytes are converted directly, without SPI, stopping at the first character that can't be converted rather than
+/// raising an error.
+#[cfg(any(feature = "pg14", feature = "pg15"))]
+fn convert_to_utf8(raw: &[u8], encoding: i32) -> Option<String> {
+    let utf8 = pg_sys::pg_enc_PG_UTF8 as i32;
+    unsafe {
+        let proc = pg_sys::FindDefaultConversionProc(encoding, utf8);
+        if proc == pg_sys::InvalidOid {
+            return None;
+        }
+        // Conversions grow text by at most `MAX_CONVERSION_GROWTH` (4) times, and terminate it with a NUL
+        let mut converted = vec![0u8; raw.len() * 4 + 1];
+        let len = pg_sys::pg_do_encoding_conversion_buf(
+            proc,
+            encoding,
+            utf8,
+            raw.as_ptr() as *mut u8,
+            raw.len() as i32,
+            converted.as_mut_ptr(),
+            converted.len() as i32,
+            true,
+        );
+        if len as usize != raw.len() {
+            return None;
+        }
+        let text = CStr::from_ptr(converted.as_ptr() as *const c_char);
+        text.to_str().ok().map(str::to_string)
+    }
+}
+
+/// Convert text valid in the server encoding to UTF-8
+///
+/// The bytes are converted directly, without SPI. Conversions can't stop at a character they can't convert before
+/// Postgres 14, so one without a Unicode mapping raises an error.
+#[cfg(not(any(feature = "pg14", feature = "pg15")))]
+fn convert_to_utf8(raw: &[u8], encoding: i32) -> Option<String> {
+    unsafe {
+        let src = raw.as_ptr() as *mut u8;
+        let converted = pg_sys::pg_do_encoding_conversion(
+            src,
+            raw.len() as i32,
+            encoding,
+            pg_sys::pg_enc_PG_UTF8 as i32,
+        );
+        let text = CStr::from_ptr(converted as *const c_char)
+            .to_str()
+            .map(str::to_string);
+        if converted != src {
+            pg_sys::pfree(converted.cast());
+        }
+        text.ok()
+    }
+}
+
+/// Convert an error caught by this crate's checked machinery, which rolled back whatever it was raised in
+///
+/// The error is recorded as handled, and the SQLSTATE and text captured by [`capture_sqlstate`] are attributed to it
+/// if it was raised by Postgres.
+pub(crate) fn handled(err: CaughtError) -> Error {
+    let sqlstate = RAISED_SQLSTATE.with(Cell::take);
+    let text = unsafe { take_raised_text() };
+    let (sqlstate, text) = match err {
+        CaughtError::PostgresError(_) => (sqlstate, text.map(RaisedText::convert)),
+        _ => (None, None),
+    };
+    Error::with_captured(
+        err,
+        Captured {
+            handled: true,
+            sqlstate,
+            text,
+            original_query: None,
+        },
+    )
+}
+
+/// Run `f`, capturing the SQLSTATE of the last error raised in it exactly as it was raised, along with its text
+///
+/// pgx only knows the SQLSTATEs defined by Postgres, so it can't report others (such as those raised by PL/pgSQL's
+/// `RAISE ... USING ERRCODE`), converts the text lossily, and drops the context and internal query. An error context
+/// callback, which Postgres calls for every report before throwing it, records them instead. Callbacks pushed later
+/// (such as those of the PL/pgSQL functions called in `f`) are called first, so the context it sees is made of the
+/// frames within `f`.
+pub(crate) fn capture_sqlstate<R, F: FnOnce() -> R>(f: F) -> R {
+    RAISED_SQLSTATE.with(|raised| raised.set(None));
+    unsafe { drop(take_raised_text()) };
+    let _callback = SqlStateCallback::push();
+    f()
+}
+
+/// Error context callback recording the SQLSTATE and text of errors, removed from the stack when dropped
+struct SqlStateCallback(Box<pg_sys::ErrorContextCallback>);
+
+impl SqlStateCallback {
+    fn push() -> Self {
+        let mut callback = Box::new(pg_sys::ErrorContextCallback {
+            previous: unsafe { pg_sys::error_context_stack },
+            callback: Some(record_sqlstate),
+            arg: std::ptr::null_mut(),
+        });
+        unsafe { pg_sys::error_context_stack = &mut *callback };
+        SqlStateCallback(callback)
+    }
+}
+
+impl Drop for SqlStateCallback {
+    fn drop(&mut self) {
+        unsafe { pg_sys::error_context_stack = self.0.previous };
+    }
+}
+
+/// Record the SQLSTATE and a copy of the error data, leaving reading the text out of it to [`take_raised_text`]
+///
+/// The error data is only reachable through a copy, which can't be made in the error memory context the callbacks are
+/// called in. Nothing else is allocated, and the copy of a previous error is only swapped out of the thread-local.
+#[pg_guard]
+unsafe extern "C" fn record_sqlstate(_arg: *mut std::os::raw::c_void) {
+    let sqlstate = SqlState::from_raw(pg_sys::geterrcode());
+    // Notices and warnings (such as those emitted while rolling back) are not errors
+    if !matches!(&sqlstate.as_str()[..2], "00" | "01" | "02") {
+        RAISED_SQLSTATE.with(|raised| raised.set(Some(sqlstate)));
+        let mut previous = PgMemoryContexts::TopMemoryContext.set_as_current();
+        let data = pg_sys::CopyErrorData();
+        previous.set_as_current();
+        let replaced = RAISED_DATA.with(|raised| raised.replace(data));
+        if !replaced.is_null() {
+            pg_sys::FreeErrorData(replaced);
+        }
+    }
+}
+
+/// Text of the last error recorded by [`record_sqlstate`], with the context built by the callbacks called before it
+///
+/// The copy of the error data is freed.
+unsafe fn take_raised_text() -> Option<RaisedText> {
+    let data = RAISED_DATA.with(|raised| raised.replace(std::ptr::null_mut()));
+    if data.is_null() {
+        return None;
+    }
+    let copy =
+        |ptr: *const c_char| (!ptr.is_null()).then(|| CStr::from_ptr(ptr).to_bytes().to_vec());
+    let cap = FIELD_CAP.with(Cell::get);
+    let truncated = |ptr: *const c_char| {
+        (!ptr.is_null()).then(|| {
+            let bytes = CStr::from_ptr(ptr).to_bytes();
+            if bytes.len() <= cap {
+                return (bytes.to_vec(), false);
+            }
+            // Cut at a character boundary of the server encoding
+            let len = pg_sys::pg_mbcliplen(ptr, bytes.len() as i32, cap as i32) as usize;
+            (bytes[..len].to_vec(), true)
+        })
+    };
+    let text = RaisedText {
+        message: copy((*data).message).unwrap_or_default(),
+        detail: copy((*data).detail),
+        hint: copy((*data).hint),
+        context: truncated((*data).context),
+        internal_query: truncated((*data).internalquery),
+        detail_log: truncated((*data).detail_log),
+    };
+    pg_sys::FreeErrorData(data);
+    Some(text)
+}
+
+/// Whether the error was caught by this crate's checked machinery (see [`Error::was_handled`])
+pub fn was_handled(err: &Error) -> bool {
+    err.was_handled()
+}
+
+/// Self-contained copy of a caught error's report
+///
+/// Unlike [`Error`], it holds no panic payload, so it can be cloned and sent to other threads, and kept for as long as
+/// needed (for example, in a per-backend error history). Checked commands' errors convert into it with `?`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedPostgresError {
+    pub sqlstate: SqlState,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+    /// Source file that raised the error (empty if the error didn't come from Postgres)
+    pub file: String,
+    pub line: u32,
+    /// Function that raised the error, if known
+    pub function: Option<String>,
+    /// Context the error was raised in, one frame per line (see [`context`](mod@context))
+    pub context: Option<String>,
+    /// Detail only written to the server log, such as the [`timeline`](crate::timeline) attached to the error
+    pub detail_log: Option<String>,
+}
+
+impl OwnedPostgresError {
+    /// Frames of the context, innermost first
+    pub fn context_frames(&self) -> Vec<ContextFrame> {
+        self.context.as_deref().map_or(vec![], context::parse)
+    }
+
+    /// Innermost PL/pgSQL or SQL function frame of the context
+    pub fn deepest_user_function(&self) -> Option<ContextFrame> {
+        context::deepest_user_function(&self.context_frames()).cloned()
+    }
+
+    /// Severity class of the error (see [`SqlState::severity_class`])
+    pub fn severity_class(&self) -> SeverityClass {
+        self.sqlstate.severity_class()
+    }
+
+    /// Raise the error again, with its SQLSTATE, message, detail, detail for the server log and hint
+    pub fn rethrow(&self) -> ! {
+        raise(
+            self.sqlstate,
+            &self.message,
+            self.detail.as_deref(),
+            self.detail_log.as_deref(),
+            self.hint.as_deref(),
+        )
+    }
+}
+
+impl Display for OwnedPostgresError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for OwnedPostgresError {}
+
+impl From<Error> for OwnedPostgresError {
+    /// Copies the caught error's report
+    ///
+    /// Errors that are not caught Postgres errors are reported with the SQLSTATE Postgres would use for them (`42601`
+    /// for an empty query, `25000` for an active parallel operation, `2D000` for transaction control statements
+    /// (whether rejected or refused by SPI), `0A000` for `COPY` to or from the client, `XX000` for other SPI error
+    /// codes, a corrupted SPI stack and a sub-transaction mismatch, `57014` for an exceeded time budget, and for
+    /// rejected values `42622` for a long identifier, `22021` for a NUL byte, `54023` for too many parameters and
+    /// `42601` for an empty identifier or an unqualified column name).
+    fn from(err: Error) -> Self {
+        let sqlstate = match &err {
+            Error::Caught(err, captured) | Error::ReadOnlyViolation(err, captured) => {
+                let mut owned = report(err).to_owned_error();
+                if let Some(sqlstate) = captured.sqlstate {
+                    owned.sqlstate = sqlstate;
+                }
+                if let Some(text) = &captured.text {
+                    owned.message = text.message.clone();
+                    owned.detail = text.detail.clone();
+                    owned.hint = text.hint.clone();
+                    owned.context = text.context.as_ref().map(|context| context.value.clone());
+                    owned.detail_log = text.detail_log.as_ref().map(|detail| detail.value.clone());
+                }
+                return owned;
+            }
+            Error::EmptyQuery => PgSqlErrorCode::ERRCODE_SYNTAX_ERROR,
+            Error::ParallelModeActive => PgSqlErrorCode::ERRCODE_INVALID_TRANSACTION_STATE,
+            Error::TransactionControlNotAllowed { .. } | Error::Spi(SpiErrorCode::Transaction) => {
+                PgSqlErrorCode::ERRCODE_INVALID_TRANSACTION_TERMINATION
+            }
+            Error::Spi(SpiErrorCode::Copy) => PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+            Error::Spi(_) => PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+            Error::BudgetExceeded { .. } => PgSqlErrorCode::ERRCODE_QUERY_CANCELED,
+            Error::SpiStackCorruption { .. } | Error::SubTransactionMismatch { .. } => {
+                PgSqlErrorCode::ERRCODE_INTERNAL_ERROR
+            }
+            #[cfg(feature = "full")]
+            Error::Preflight(err) => {
+                use crate::validate::{IdentError, PreflightError};
+                match err {
+                    PreflightError::Identifier(
+                        IdentError::Empty | IdentError::UnqualifiedColumn { .. },
+                    ) => PgSqlErrorCode::ERRCODE_SYNTAX_ERROR,
+                    PreflightError::Identifier(IdentError::WouldTruncate { .. }) => {
+                        PgSqlErrorCode::ERRCODE_NAME_TOO_LONG
+                    }
+                    PreflightError::Identifier(IdentError::NotInServerEncoding { .. }) => {
+                        PgSqlErrorCode::ERRCODE_UNTRANSLATABLE_CHARACTER
+                    }
+                    PreflightError::Identifier(IdentError::Nul { .. })
+                    | PreflightError::Literal(_) => {
+                        PgSqlErrorCode::ERRCODE_CHARACTER_NOT_IN_REPERTOIRE
+                    }
+                    PreflightError::TooManyParams(_) => PgSqlErrorCode::ERRCODE_TOO_MANY_ARGUMENTS,
+                }
+            }
+        };
+        OwnedPostgresError {
+            sqlstate: SqlState::from_code(sqlstate),
+            message: err.to_string(),
+            detail: None,
+            hint: None,
+            file: String::new(),
+            line: 0,
+            function: None,
+            context: None,
+            detail_log: None,
+        }
+    }
+}
+
+/// Formatter returned by [`Error::display_with_hints`]
+pub struct DisplayWithHints<'a>(&'a Error);
+
+impl<'a> Display for DisplayWithHints<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let report = match self.0.report() {
+            Some(report) => report,
+            None => return write!(f, "{}", self.0),
+        };
+        write!(f, "{}", report.message())?;
+        if let Some(detail) = report.detail() {
+            write!(f, "\nDETAIL: {}", detail)?;
+        }
+        if let Some(hint) = report
+            .hint()
+            .or_else(|| self.0.sqlstate().and_then(hints::lookup))
+        {
+            write!(f, "\nHINT: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Report of a caught error, regardless of its kind
+pub(crate) fn report(err: &CaughtError) -> &ErrorReportWithLevel {
+    match err {
+        CaughtError::PostgresError(report) | CaughtError::ErrorReport(report) => report,
+        CaughtError::RustPanic { ereport, .. } => ereport,
+    }
+}
+
+/// Classes of SQLSTATEs defined by Postgres (see "PostgreSQL Error Codes" in its documentation)
+const STANDARD_CLASSES: [&str; 43] = [
+    "00", "01", "02", "03", "08", "09", "0A", "0B", "0F", "0L", "0P", "0Z", "20", "21", "22", "23",
+    "24", "25", "26", "27", "28", "2B", "2D", "2F", "34", "38", "39", "3B", "3D", "3F", "40", "42",
+    "44", "53", "54", "55", "57", "58", "72", "F0", "HV", "P0", "XX",
+];
+
+/// Five-character SQLSTATE code, such as `23505`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SqlState([u8; 5]);
+
+impl SqlState {
+    /// Parse a SQLSTATE, which must consist of five digits or uppercase letters
+    pub fn parse(sqlstate: &str) -> Option<Self> {
+        let bytes: [u8; 5] = sqlstate.as_bytes().try_into().ok()?;
+        bytes
+            .iter()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+            .then_some(SqlState(bytes))
+    }
+
+    /// SQLSTATE of an error code
+    pub fn from_code(code: PgSqlErrorCode) -> Self {
+        Self::from_raw(code as i32)
+    }
+
+    /// SQLSTATE of a raw error code, as in `ErrorData::sqlerrcode`
+    pub fn from_raw(sqlerrcode: i32) -> Self {
+        // Error codes are SQLSTATEs packed into six bits per character (see `MAKE_SQLSTATE`)
+        let code = sqlerrcode as u32;
+        let mut bytes = [0; 5];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = ((code >> (6 * i)) & 0x3F) as u8 + b'0';
+        }
+        SqlState(bytes)
+    }
+
+    /// Raw error code of the SQLSTATE, as in `ErrorData::sqlerrcode`
+    pub fn to_raw(&self) -> i32 {
+        self.0.iter().enumerate().fold(0, |code, (i, byte)| {
+            code | (((byte - b'0') as u32 & 0x3F) << (6 * i))
+        }) as i32
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).unwrap()
+    }
+
+    /// Class of the SQLSTATE (its first two characters), such as `23` for integrity constraint violations
+    pub fn class(&self) -> &str {
+        &self.as_str()[..2]
+    }
+
+    /// Whether the SQLSTATE's class is not one of the classes defined by Postgres
+    ///
+    /// Codes that users define in standard classes (such as `P0T01` in PL/pgSQL's `P0` class) are not.
+    pub fn is_user_defined(&self) -> bool {
+        !STANDARD_CLASSES.contains(&self.class())
+    }
+
+    /// What retrying the command that raised an error with this SQLSTATE is expected to do
+    ///
+    /// User-defined codes are [`SeverityClass::Permanent`] unless they are in class `40`, `53` or `54`.
+    pub fn severity_class(&self) -> SeverityClass {
+        match self.class() {
+            // A transaction integrity violation or an unknown statement outcome doesn't go away by retrying
+            "40" if matches!(self.as_str(), "40002" | "40003") => SeverityClass::Permanent,
+            "40" => SeverityClass::Transient,
+            "53" | "54" => SeverityClass::ResourceExhaustion,
+            _ if self.as_str() == "55P03" => SeverityClass::Transient,
+            _ => SeverityClass::Permanent,
+        }
+    }
+
+    /// Whether the SQLSTATE matches `pattern`, five characters where `_` matches any character (as in `P0___` or
+    /// `23___`)
+    pub fn matches(&self, pattern: &str) -> bool {
+        pattern.len() == 5
+            && pattern
+                .bytes()
+                .zip(self.0)
+                .all(|(pattern, c)| pattern == b'_' || pattern == c)
+    }
+}
+
+/// What retrying the command that raised an error is expected to do, by the error's SQLSTATE
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SeverityClass {
+    /// The error was caused by concurrent activity and may not recur: serialization failures and deadlocks (class
+    /// `40`, except for `40002` and `40003`), and lock timeouts (`55P03`)
+    Transient,
+    /// The server ran out of a resource, such as disk space (`53100`), memory (`53200`) or connections (`53300`)
+    /// (class `53`), or a program limit was exceeded (class `54`)
+    ///
+    /// Retrying is unlikely to help until the resource is freed, and adds to the load.
+    ResourceExhaustion,
+    /// Any other error, such as syntax errors, undefined objects and constraint violations, which retrying the same
+    /// command won't fix
+    ///
+    /// This includes transaction integrity constraint violations (`40002`), and statements whose completion is unknown
+    /// (`40003`), which may have taken effect.
+    Permanent,
+}
+
+impl Display for SqlState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Additional functionality for reports of caught Postgres errors
+pub trait PostgresErrorExt {
+    /// SQLSTATE of the error, as pgx reports it
+    ///
+    /// pgx only knows the SQLSTATEs defined by Postgres. [`Error::sqlstate`] reports the one errors caught by checked
+    /// commands were raised with.
+    fn sqlstate(&self) -> SqlState;
+
+    /// Remediation hint for the error's SQLSTATE, if there is one (see [`hints`])
+    fn remediation(&self) -> Option<&'static str>;
+
+    /// Severity class of the error (see [`SqlState::severity_class`])
+    fn severity_class(&self) -> SeverityClass {
+        self.sqlstate().severity_class()
+    }
+
+    /// Copy the report into an [`OwnedPostgresError`]
+    ///
+    /// The report has no context. Converting an [`Error`] copies what was captured as well.
+    fn to_owned_error(&self) -> OwnedPostgresError;
+}
+
+impl PostgresErrorExt for ErrorReportWithLevel {
+    fn sqlstate(&self) -> SqlState {
+        SqlState::from_code(self.sql_error_code())
+    }
+
+    fn remediation(&self) -> Option<&'static str> {
+        hints::lookup(self.sqlstate())
+    }
+
+    fn to_owned_error(&self) -> OwnedPostgresError {
+        OwnedPostgresError {
+            sqlstate: self.sqlstate(),
+            message: self.message().to_string(),
+            detail: self.detail().map(str::to_string),
+            hint: self.hint().map(str::to_string),
+            file: self.file().to_string(),
+            line: self.line_number(),
+            function: self.function_name().map(str::to_string),
+            context: None,
+            detail_log: None,
+        }
+    }
+}
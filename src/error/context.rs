@@ -0,0 +1,135 @@
+//! Frames of an error's context
+//!
+//! Postgres reports where an error happened as lines of context, innermost first: the PL/pgSQL function and line
+//! that raised it, the SQL statement that called that function, and so on. Lines are recognized in their standard
+//! (English) formats only; others, including translated ones, are kept as [`ContextFrame::Raw`].
+
+/// Line of an error's context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContextFrame {
+    /// `PL/pgSQL function f(integer) line 3 at RAISE`
+    PlPgSqlFunction {
+        /// Function signature, such as `f(integer)`, or `inline_code_block` for `DO` blocks
+        name: String,
+        line: Option<u32>,
+        raw: String,
+    },
+    /// `SQL function "f" statement 1`
+    SqlFunction {
+        name: String,
+        /// 1-based statement of the function's body
+        statement: Option<u32>,
+        raw: String,
+    },
+    /// `SQL statement "SELECT f(1)"` (or `SQL expression "..."`)
+    SqlStatement { statement: String, raw: String },
+    /// `COPY t, line 3, column a: "x"`
+    Copy {
+        table: String,
+        line: Option<u32>,
+        raw: String,
+    },
+    /// Any other line
+    Raw(String),
+}
+
+impl ContextFrame {
+    /// The line as Postgres reported it
+    pub fn raw(&self) -> &str {
+        match self {
+            ContextFrame::PlPgSqlFunction { raw, .. }
+            | ContextFrame::SqlFunction { raw, .. }
+            | ContextFrame::SqlStatement { raw, .. }
+            | ContextFrame::Copy { raw, .. }
+            | ContextFrame::Raw(raw) => raw,
+        }
+    }
+
+    /// Whether the frame is a PL/pgSQL or SQL function
+    pub fn is_user_function(&self) -> bool {
+        matches!(
+            self,
+            ContextFrame::PlPgSqlFunction { .. } | ContextFrame::SqlFunction { .. }
+        )
+    }
+}
+
+/// Parse the context of an error into frames, innermost first
+pub fn parse(context: &str) -> Vec<ContextFrame> {
+    let mut frames = vec![];
+    let mut lines = context.lines();
+    while let Some(line) = lines.next() {
+        let mut raw = line.to_string();
+        // Statements may span several lines
+        if is_statement_start(line) {
+            while !raw.ends_with('"') {
+                match lines.next() {
+                    Some(next) => {
+                        raw.push('\n');
+                        raw.push_str(next);
+                    }
+                    None => break,
+                }
+            }
+        }
+        frames.push(parse_frame(raw));
+    }
+    frames
+}
+
+/// Innermost PL/pgSQL or SQL function frame of a context
+pub fn deepest_user_function(frames: &[ContextFrame]) -> Option<&ContextFrame> {
+    frames.iter().find(|frame| frame.is_user_function())
+}
+
+fn is_statement_start(line: &str) -> bool {
+    line.starts_with("SQL statement \"") || line.starts_with("SQL expression \"")
+}
+
+fn parse_frame(raw: String) -> ContextFrame {
+    if let Some(rest) = raw.strip_prefix("PL/pgSQL function ") {
+        let name_len = match rest.find('(') {
+            // Argument types may contain spaces, so the name ends with the closing parenthesis
+            Some(open) if !rest[..open].contains(' ') => rest.find(')').map(|close| close + 1),
+            _ => rest.find(' ').or(Some(rest.len())),
+        };
+        if let Some(name_len) = name_len {
+            let name = rest[..name_len].to_string();
+            let line = number_after(&rest[name_len..], " line ");
+            return ContextFrame::PlPgSqlFunction { name, line, raw };
+        }
+    } else if let Some(rest) = raw.strip_prefix("SQL function \"") {
+        if let Some(end) = rest.find('"') {
+            let name = rest[..end].to_string();
+            let statement = number_after(&rest[end..], " statement ");
+            return ContextFrame::SqlFunction {
+                name,
+                statement,
+                raw,
+            };
+        }
+    } else if is_statement_start(&raw)
+        && raw.ends_with('"')
+        && raw.len() > raw.find('"').unwrap() + 1
+    {
+        let start = raw.find('"').unwrap() + 1;
+        let statement = raw[start..raw.len() - 1].to_string();
+        return ContextFrame::SqlStatement { statement, raw };
+    } else if let Some(rest) = raw.strip_prefix("COPY ") {
+        if let Some(end) = rest.find(", line ") {
+            let table = rest[..end].to_string();
+            let line = number_after(&rest[end..], ", line ");
+            return ContextFrame::Copy { table, line, raw };
+        }
+    }
+    ContextFrame::Raw(raw)
+}
+
+/// Number right after the first occurrence of `prefix` in `text`
+fn number_after(text: &str, prefix: &str) -> Option<u32> {
+    let start = text.find(prefix)? + prefix.len();
+    let digits = text[start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len() - start);
+    text[start..start + digits].parse().ok()
+}
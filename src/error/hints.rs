@@ -0,0 +1,89 @@
+//! Remediation hints for common SQLSTATEs
+//!
+//! Built-in hints can be overridden, and hints for extension-specific codes added, with [`register`].
+
+use std::sync::Mutex;
+
+use super::SqlState;
+
+/// Built-in hints, sorted by SQLSTATE
+static BUILTIN: &[(&str, &str)] = &[
+    ("0A000", "the feature is not supported by this server version or configuration"),
+    ("21000", "a subquery or ON CONFLICT DO UPDATE affected more than one row; make it return a single row"),
+    ("22001", "the value is too long for the column's type; widen the column or shorten the value"),
+    ("22003", "the value is out of range for its type; use a wider type"),
+    ("22004", "a NULL was supplied where it is not allowed; supply a value"),
+    ("22007", "the date/time value is malformed; check its format against DateStyle"),
+    ("22008", "a date/time field is out of range; check the value"),
+    ("22012", "guard the divisor, e.g. with NULLIF(divisor, 0)"),
+    ("22023", "a parameter has an invalid value; check the function's documentation"),
+    ("22P02", "the input can't be parsed as the target type; validate it before casting"),
+    ("23502", "the column does not accept NULL; supply a value or a column default"),
+    ("23503", "the referenced row does not exist (or is still referenced); check the related table"),
+    ("23505", "a row with the same key already exists; consider INSERT ... ON CONFLICT"),
+    ("23514", "the row violates a CHECK constraint; check the values against the constraint"),
+    ("23P01", "the row conflicts with an existing one under an exclusion constraint"),
+    ("25001", "the command cannot run inside a transaction block"),
+    ("25006", "the transaction is read-only; run the command in a read-write transaction"),
+    ("25P02", "an earlier command failed; roll back the (sub-)transaction before issuing more commands"),
+    ("28000", "authentication failed; check the role and pg_hba.conf"),
+    ("2BP01", "other objects depend on this one; drop them first or use CASCADE"),
+    ("3D000", "the database does not exist; check its name"),
+    ("3F000", "the schema does not exist; check its name or create it"),
+    ("40001", "retry the transaction"),
+    ("40P01", "retry the transaction; acquire locks in a consistent order to avoid deadlocks"),
+    ("42501", "grant the required privilege to the role, or run as a role that has it"),
+    ("42601", "the statement is malformed; check the syntax near the reported position"),
+    ("42702", "qualify the column reference with its table name or alias"),
+    ("42703", "the column does not exist; check its name and the table it's taken from"),
+    ("42704", "the object does not exist; check its name and search_path"),
+    ("42710", "the object already exists; use IF NOT EXISTS or pick another name"),
+    ("42723", "a function with the same signature already exists; use CREATE OR REPLACE"),
+    ("42725", "the call matches more than one function; add explicit casts to the arguments"),
+    ("42803", "the column must appear in GROUP BY or be used in an aggregate"),
+    ("42804", "the value's type does not match the expected one; add an explicit cast"),
+    ("42809", "the object is of the wrong type for this command"),
+    ("42846", "there is no cast between these types; convert the value differently"),
+    ("42883", "check the function name and argument types; an explicit cast may be needed"),
+    ("42P01", "check the table name and search_path, or create the table first"),
+    ("42P02", "the query references a parameter that was not supplied"),
+    ("42P07", "the table already exists; use CREATE TABLE IF NOT EXISTS or pick another name"),
+    ("42P18", "the parameter's type can't be determined; add an explicit cast"),
+    ("53100", "the disk is full; free up space"),
+    ("53200", "the server ran out of memory; reduce work_mem or the size of the operation"),
+    ("54000", "an implementation limit was exceeded; reduce the size of the operation"),
+    ("55000", "the object is not in the state required by the command"),
+    ("55006", "another session is using the object; retry once it is released"),
+    ("55P03", "another session holds a conflicting lock; consider lock_timeout"),
+    ("57014", "the statement was canceled, possibly by statement_timeout; raise it or speed up the statement"),
+    ("57P01", "the server is shutting down; reconnect and retry"),
+    ("XX000", "an internal error occurred; check the server log"),
+];
+
+/// Hints registered at runtime, taking precedence over the built-in ones
+static OVERRIDES: Mutex<Vec<(SqlState, &'static str)>> = Mutex::new(Vec::new());
+
+/// Register a hint for a SQLSTATE, replacing any previously registered or built-in one
+///
+/// Panics if `sqlstate` is not a valid SQLSTATE.
+pub fn register(sqlstate: &str, text: &'static str) {
+    let sqlstate = SqlState::parse(sqlstate)
+        .unwrap_or_else(|| panic!("\"{}\" is not a valid SQLSTATE", sqlstate));
+    let mut overrides = OVERRIDES.lock().unwrap_or_else(|e| e.into_inner());
+    match overrides.iter_mut().find(|(code, _)| *code == sqlstate) {
+        Some(entry) => entry.1 = text,
+        None => overrides.push((sqlstate, text)),
+    }
+}
+
+/// Look up the hint for a SQLSTATE
+pub fn lookup(sqlstate: SqlState) -> Option<&'static str> {
+    let overrides = OVERRIDES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, text)) = overrides.iter().find(|(code, _)| *code == sqlstate) {
+        return Some(text);
+    }
+    BUILTIN
+        .binary_search_by(|(code, _)| code.cmp(&sqlstate.as_str()))
+        .ok()
+        .map(|index| BUILTIN[index].1)
+}
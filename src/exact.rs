@@ -0,0 +1,308 @@
+//! Exact values of `numeric`, `interval` and `money` columns
+//!
+//! Converting these to `f64` loses precision (or, for intervals, mixes up months and days), so they are read into
+//! [`PgNumeric`], [`PgInterval`] and [`PgMoney`] instead, which work with every typed accessor (`strict_get`,
+//! [`FromSpiRow`](crate::row::FromSpiRow), `checked_get_one_fast`, ...) and can be passed as arguments. Their
+//! conversions report values that don't fit; converting to `f64` anyway is spelled `lossy()`, so that the loss is
+//! visible where it happens.
+
+use pgx::{pg_sys, FromDatum, IntoDatum, PgBuiltInOids};
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Value of a `numeric` column, as the text Postgres outputs for it, which holds all of its digits
+///
+/// The text is either a decimal number (`-123.4500`, keeping the trailing zeros of the scale) or, for values that aren't
+/// finite, `NaN`, `Infinity` or `-Infinity` (the latter two on Postgres 14 and later).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PgNumeric(String);
+
+impl PgNumeric {
+    /// Numeric with the text `text`, which is not checked
+    ///
+    /// Postgres parses the text when the value is converted to a datum, which happens when the arguments are built,
+    /// before (and outside of) any checked command: an invalid numeric raises an error that aborts the transaction
+    /// rather than being returned by the command.
+    pub fn new(text: impl Into<String>) -> Self {
+        Self(text.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the value is a finite number
+    pub fn is_finite(&self) -> bool {
+        self.0.bytes().any(|b| b.is_ascii_digit())
+    }
+
+    /// The value as a mantissa and a scale (its number of decimal digits), such that the value is
+    /// `mantissa / 10^scale`
+    ///
+    /// Returns [`NumericOverflow`] if the value isn't finite or the mantissa doesn't fit in 128 bits.
+    pub fn to_mantissa_scale(&self) -> Result<(i128, u32), NumericOverflow> {
+        let overflow = || NumericOverflow {
+            value: self.0.clone(),
+        };
+        let (negative, digits) = match self.0.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, self.0.as_str()),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if integer.is_empty() || !self.is_finite() {
+            return Err(overflow());
+        }
+        let mut mantissa = 0i128;
+        for digit in integer.bytes().chain(fraction.bytes()) {
+            if !digit.is_ascii_digit() {
+                return Err(overflow());
+            }
+            let digit = (digit - b'0') as i128;
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|mantissa| {
+                    if negative {
+                        mantissa.checked_sub(digit)
+                    } else {
+                        mantissa.checked_add(digit)
+                    }
+                })
+                .ok_or_else(overflow)?;
+        }
+        Ok((mantissa, fraction.len() as u32))
+    }
+
+    /// The value as a `rust_decimal::Decimal`
+    ///
+    /// Returns [`NumericOverflow`] if the value isn't finite or doesn't fit in a decimal's 96-bit mantissa and scale of
+    /// at most 28. Requires the `decimal` feature.
+    #[cfg(feature = "decimal")]
+    pub fn to_decimal(&self) -> Result<rust_decimal::Decimal, NumericOverflow> {
+        let (mantissa, scale) = self.to_mantissa_scale()?;
+        rust_decimal::Decimal::try_from_i128_with_scale(mantissa, scale).map_err(|_| {
+            NumericOverflow {
+                value: self.0.clone(),
+            }
+        })
+    }
+
+    /// The value as the closest `f64`, losing the digits that don't fit (`NaN` and infinities are kept)
+    pub fn lossy(&self) -> f64 {
+        self.0.parse().unwrap_or(f64::NAN)
+    }
+}
+
+impl Display for PgNumeric {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromDatum for PgNumeric {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+        let (mut function, mut is_varlena) = (pg_sys::InvalidOid, false);
+        pg_sys::getTypeOutputInfo(
+            PgBuiltInOids::NUMERICOID.value(),
+            &mut function,
+            &mut is_varlena,
+        );
+        let text = pg_sys::OidOutputFunctionCall(function, datum);
+        let value = CStr::from_ptr(text).to_string_lossy().into_owned();
+        pg_sys::pfree(text.cast());
+        Some(Self(value))
+    }
+}
+
+impl IntoDatum for PgNumeric {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        let text = CString::new(self.0).expect("numeric contains a NUL byte");
+        let (mut function, mut io_param) = (pg_sys::InvalidOid, pg_sys::InvalidOid);
+        unsafe {
+            pg_sys::getTypeInputInfo(
+                PgBuiltInOids::NUMERICOID.value(),
+                &mut function,
+                &mut io_param,
+            );
+            Some(pg_sys::OidInputFunctionCall(
+                function,
+                text.as_ptr() as *mut _,
+                io_param,
+                -1,
+            ))
+        }
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        PgBuiltInOids::NUMERICOID.value()
+    }
+}
+
+/// A numeric that doesn't fit the type it was converted to, or isn't finite
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumericOverflow {
+    /// Text of the numeric
+    pub value: String,
+}
+
+impl Display for NumericOverflow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "numeric {} is out of range", self.value)
+    }
+}
+
+impl std::error::Error for NumericOverflow {}
+
+/// Value of an `interval` column, made of its three fields as Postgres stores them
+///
+/// Months and days are kept apart from the rest because their length varies: a month is 28 to 31 days, and a day
+/// across a daylight saving time change isn't 24 hours.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PgInterval {
+    pub months: i32,
+    pub days: i32,
+    pub micros: i64,
+}
+
+impl PgInterval {
+    /// The interval as a `Duration`, counting days as 24 hours
+    ///
+    /// Returns [`NotDurationConvertible`] if it has months, which have no fixed length, or is negative.
+    pub fn to_duration(&self) -> Result<Duration, NotDurationConvertible> {
+        if self.months != 0 {
+            return Err(NotDurationConvertible::Months(*self));
+        }
+        let micros = self.days as i128 * 86_400_000_000 + self.micros as i128;
+        u64::try_from(micros)
+            .map(Duration::from_micros)
+            .map_err(|_| NotDurationConvertible::Negative(*self))
+    }
+
+    /// The interval in seconds, counting a year as 365.25 days and other months as 30 days, as
+    /// `EXTRACT(EPOCH FROM interval)` does
+    pub fn lossy(&self) -> f64 {
+        let years = (self.months / 12) as f64;
+        let days = years * 365.25 + (self.months % 12) as f64 * 30.0 + self.days as f64;
+        days * 86_400.0 + self.micros as f64 / 1_000_000.0
+    }
+}
+
+impl From<Duration> for PgInterval {
+    /// Panics if the duration is longer than an interval's microseconds can hold (about 292 thousand years)
+    fn from(duration: Duration) -> Self {
+        Self {
+            months: 0,
+            days: 0,
+            micros: i64::try_from(duration.as_micros())
+                .expect("duration is too long for an interval"),
+        }
+    }
+}
+
+impl FromDatum for PgInterval {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        if is_null {
+            return None;
+        }
+        let interval = &*datum.cast_mut_ptr::<pg_sys::Interval>();
+        Some(Self {
+            months: interval.month,
+            days: interval.day,
+            micros: interval.time,
+        })
+    }
+}
+
+impl IntoDatum for PgInterval {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        unsafe {
+            let interval =
+                pg_sys::palloc(std::mem::size_of::<pg_sys::Interval>()).cast::<pg_sys::Interval>();
+            (*interval).time = self.micros;
+            (*interval).day = self.days;
+            (*interval).month = self.months;
+            Some(pg_sys::Datum::from(interval))
+        }
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        PgBuiltInOids::INTERVALOID.value()
+    }
+}
+
+/// An interval that can't be converted to a `Duration`, see [`PgInterval::to_duration`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotDurationConvertible {
+    /// The interval has months
+    Months(PgInterval),
+    /// The interval is negative
+    Negative(PgInterval),
+}
+
+impl Display for NotDurationConvertible {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotDurationConvertible::Months(interval) => write!(
+                f,
+                "interval of {} months has no fixed duration",
+                interval.months
+            ),
+            NotDurationConvertible::Negative(_) => write!(f, "interval is negative"),
+        }
+    }
+}
+
+impl std::error::Error for NotDurationConvertible {}
+
+/// Value of a `money` column, in the smallest unit of the currency
+///
+/// `money` is stored as a 64-bit integer of the smallest unit of the currency of the `lc_monetary` setting in effect,
+/// which is cents for most locales (such as `en_US`) but not all: with zero fractional digits (as in `ja_JP`), it's
+/// whole units. Since the same stored value is displayed differently as `lc_monetary` changes, this value is only as
+/// meaningful as the locale it was stored with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PgMoney(pub i64);
+
+impl PgMoney {
+    /// The value in cents (the smallest unit of the currency)
+    pub fn cents(&self) -> i64 {
+        self.0
+    }
+
+    /// The value in units of the currency, assuming two fractional digits
+    pub fn lossy(&self) -> f64 {
+        self.0 as f64 / 100.0
+    }
+}
+
+impl FromDatum for PgMoney {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        _typoid: pg_sys::Oid,
+    ) -> Option<Self> {
+        // `money` is passed by value
+        (!is_null).then(|| Self(datum.value() as i64))
+    }
+}
+
+impl IntoDatum for PgMoney {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        Some(pg_sys::Datum::from(self.0))
+    }
+
+    fn type_oid() -> pg_sys::Oid {
+        PgBuiltInOids::CASHOID.value()
+    }
+}
@@ -0,0 +1,138 @@
+//! Injecting errors into checked commands, to test how their callers handle failures
+//!
+//! While a fault is injected, checked commands whose query it matches raise its error instead of executing, in the
+//! sub-transaction they would execute in, so the error is caught and the sub-transaction rolled back as for any error
+//! Postgres raises. Queries are matched as they would be executed, after [`rewrite`](crate::rewrite) rules apply.
+//! Faults are local to the backend, and only compiled with the `testing` feature.
+
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+
+use crate::error::{raise, SqlState};
+
+/// Queries a fault is injected into
+#[derive(Clone)]
+pub enum QueryMatcher {
+    /// Queries containing the text
+    Contains(String),
+    /// Queries the predicate accepts
+    Predicate(Rc<dyn Fn(&str) -> bool>),
+}
+
+impl QueryMatcher {
+    pub fn contains(text: &str) -> Self {
+        QueryMatcher::Contains(text.to_string())
+    }
+
+    pub fn predicate<F: Fn(&str) -> bool + 'static>(predicate: F) -> Self {
+        QueryMatcher::Predicate(Rc::new(predicate))
+    }
+
+    fn matches(&self, query: &str) -> bool {
+        match self {
+            QueryMatcher::Contains(text) => query.contains(text.as_str()),
+            QueryMatcher::Predicate(predicate) => predicate(query),
+        }
+    }
+}
+
+impl Debug for QueryMatcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryMatcher::Contains(text) => f.debug_tuple("Contains").field(text).finish(),
+            QueryMatcher::Predicate(_) => f.debug_tuple("Predicate").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Error raised by an injected fault
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InjectedError {
+    pub sqlstate: SqlState,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+}
+
+impl InjectedError {
+    pub fn new(sqlstate: SqlState, message: &str) -> Self {
+        Self {
+            sqlstate,
+            message: message.to_string(),
+            detail: None,
+            hint: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn with_hint(mut self, hint: &str) -> Self {
+        self.hint = Some(hint.to_string());
+        self
+    }
+}
+
+struct Fault {
+    matcher: QueryMatcher,
+    error: InjectedError,
+    remaining: usize,
+}
+
+thread_local! {
+    static FAULTS: RefCell<Vec<Fault>> = RefCell::new(Vec::new());
+}
+
+/// Make the next `times` checked commands whose query `matcher` matches fail with `error`
+///
+/// Faults are tried in the order they were injected, and are removed once they were raised `times` times.
+pub fn inject(matcher: QueryMatcher, error: InjectedError, times: usize) {
+    if times > 0 {
+        FAULTS.with(|faults| {
+            faults.borrow_mut().push(Fault {
+                matcher,
+                error,
+                remaining: times,
+            })
+        });
+    }
+}
+
+/// Remove all injected faults
+pub fn reset() {
+    FAULTS.with(|faults| faults.borrow_mut().clear());
+}
+
+/// Number of times injected faults will still be raised
+pub fn pending() -> usize {
+    FAULTS.with(|faults| faults.borrow().iter().map(|fault| fault.remaining).sum())
+}
+
+/// Raise the error of the first fault matching `query`, if any
+pub(crate) fn raise_injected(query: &str) {
+    let error = FAULTS.with(|faults| {
+        let mut faults = faults.borrow_mut();
+        let index = faults
+            .iter()
+            .position(|fault| fault.matcher.matches(query))?;
+        let fault = &mut faults[index];
+        fault.remaining -= 1;
+        let error = fault.error.clone();
+        if fault.remaining == 0 {
+            faults.remove(index);
+        }
+        Some(error)
+    });
+    if let Some(error) = error {
+        raise(
+            error.sqlstate,
+            &error.message,
+            error.detail.as_deref(),
+            None,
+            error.hint.as_deref(),
+        )
+    }
+}
@@ -0,0 +1,147 @@
+use pgx::{IntoDatum, PgBuiltInOids, SpiClient};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::rewrite;
+use crate::subtxn::SubTransaction;
+
+/// Error returned by [`SubTransaction::guard_gucs`]
+#[derive(Debug)]
+pub enum GucError {
+    /// Reading a setting failed
+    Query(Error),
+    /// There is no setting with this name
+    UnknownSetting(String),
+}
+
+impl Display for GucError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GucError::Query(err) => write!(f, "{}", err),
+            GucError::UnknownSetting(name) => {
+                write!(f, "unrecognized configuration parameter \"{}\"", name)
+            }
+        }
+    }
+}
+
+impl From<Error> for GucError {
+    fn from(err: Error) -> Self {
+        GucError::Query(err)
+    }
+}
+
+/// Restores session settings to the values they had when the guard was created
+///
+/// Created by [`SubTransaction::guard_gucs`]. Settings that changed are restored with `set_config(name, value,
+/// false)` when the guard is dropped, including while a panic is unwinding and after the sub-transaction was rolled
+/// back. Restoring is best-effort: failures are reported as warnings.
+#[derive(Debug)]
+pub struct GucGuard {
+    settings: Vec<(String, String)>,
+}
+
+impl GucGuard {
+    fn capture(names: &[&str]) -> Result<Self, GucError> {
+        let settings = names
+            .iter()
+            .map(|name| match current_setting(name)? {
+                Some(value) => Ok((name.to_string(), value)),
+                None => Err(GucError::UnknownSetting(name.to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { settings })
+    }
+
+    /// Settings that changed since the guard was created, as (name, old value, new value)
+    pub fn all_changed(&self) -> Vec<(String, String, String)> {
+        self.settings
+            .iter()
+            .filter_map(|(name, old)| match current_setting(name) {
+                Ok(Some(new)) if &new != old => Some((name.clone(), old.clone(), new)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Drop for GucGuard {
+    fn drop(&mut self) {
+        for (name, old, _) in self.all_changed() {
+            let result = rewrite::exempt(|| {
+                (&SpiClient).checked_select(
+                    "SELECT set_config($1, $2, false)",
+                    None,
+                    Some(vec![
+                        (PgBuiltInOids::TEXTOID.oid(), name.as_str().into_datum()),
+                        (PgBuiltInOids::TEXTOID.oid(), old.as_str().into_datum()),
+                    ]),
+                )
+            });
+            if let Err(err) = result {
+                pgx::warning!("failed to restore setting \"{}\": {}", name, err);
+            }
+        }
+    }
+}
+
+/// Current value of a setting, or `None` if there is no such setting
+fn current_setting(name: &str) -> Result<Option<String>, Error> {
+    Ok(rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "SELECT current_setting($1, true)",
+            Some(1),
+            Some(vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())]),
+        )
+    })?
+    .first()
+    .get_one::<String>())
+}
+
+/// Run `f` with setting `name` set to `value` for the current transaction, setting its previous value back once `f`
+/// returns
+///
+/// Settings set with `set_config(name, value, true)` (as by `SET LOCAL`) outlive the sub-transaction they were set in
+/// once it commits, so the previous value is set back explicitly; if `f` raises an error, the setting is reverted along
+/// with the sub-transaction. The setting must exist.
+pub(crate) fn with_local<R, F: FnOnce(&mut SpiClient) -> R>(
+    client: &mut SpiClient,
+    name: &str,
+    value: &str,
+    f: F,
+) -> R {
+    let previous = client
+        .select(
+            "SELECT current_setting($1)",
+            None,
+            Some(vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())]),
+        )
+        .first()
+        .get_one::<String>()
+        .unwrap();
+    set_local(client, name, value);
+    let result = f(client);
+    set_local(client, name, &previous);
+    result
+}
+
+fn set_local(client: &mut SpiClient, name: &str, value: &str) {
+    client.update(
+        "SELECT set_config($1, $2, true)",
+        None,
+        Some(vec![
+            (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), value.into_datum()),
+        ]),
+    );
+}
+
+impl<Parent, const COMMIT: bool> SubTransaction<Parent, COMMIT> {
+    /// Capture the current values of the named settings, restoring them when the returned guard is dropped
+    ///
+    /// Fails if any of the settings does not exist. See [`GucGuard`].
+    pub fn guard_gucs(&self, names: &[&str]) -> Result<GucGuard, GucError> {
+        GucGuard::capture(names)
+    }
+}
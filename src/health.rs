@@ -0,0 +1,127 @@
+//! Probing whether the backend can still execute commands, to tell whether retrying failed ones is sensible
+//!
+//! When a command fails with a [`ResourceExhaustion`](crate::error::SeverityClass::ResourceExhaustion) error, such as
+//! a full disk, [`probe`] runs a few cheap checked commands to find out whether the condition persists.
+
+use pgx::{pg_sys, SpiClient};
+use std::cell::Cell;
+
+use crate::checked::*;
+use crate::error::{Error, SqlState};
+use crate::rewrite;
+use crate::subtxn::*;
+
+thread_local! {
+    static MAX_DATABASE_SIZE: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Report the current database as unhealthy from [`probe`] once its size reaches `max_bytes`, or never if `None`
+/// (the default)
+pub fn set_max_database_size(max_bytes: Option<u64>) {
+    MAX_DATABASE_SIZE.with(|max| max.set(max_bytes));
+}
+
+/// Outcome of [`probe`]
+#[derive(Debug)]
+pub struct HealthReport {
+    /// Whether a trivial select succeeded
+    pub can_select: bool,
+    /// Whether a row could be written to a table, or `None` if writes weren't attempted, as the transaction is
+    /// read-only or the server is a standby, or the table couldn't be created for lack of privileges
+    pub can_write: Option<bool>,
+    /// Size of the current database in bytes, if it could be measured
+    pub database_size: Option<u64>,
+    /// Bytes left before the database reaches the size set with [`set_max_database_size`] (negative once it's over),
+    /// if one is set and the size could be measured
+    pub size_headroom: Option<i64>,
+    /// Errors of the checks that failed, in order
+    pub errors: Vec<Error>,
+}
+
+impl HealthReport {
+    /// Whether every check succeeded and the database is below its maximum size, so that retrying failed commands is
+    /// sensible
+    pub fn is_healthy(&self) -> bool {
+        self.errors.is_empty()
+            && self.can_select
+            && self.can_write != Some(false)
+            && self.size_headroom.map_or(true, |headroom| headroom > 0)
+    }
+}
+
+/// Check that the backend can execute commands
+///
+/// Each check is a checked command, so a failing one is reported rather than raised:
+///
+/// 1. `SELECT 1`;
+/// 2. creating a table and inserting a row into it, in a sub-transaction that is rolled back (skipped in read-only
+///    transactions and on standbys);
+/// 3. measuring the current database with `pg_database_size`, compared to the size set with
+///    [`set_max_database_size`].
+///
+/// The table is created in the first schema of the search path, and isn't temporary: a transaction that used a
+/// temporary table, even in a rolled-back sub-transaction, can't be prepared with `PREPARE TRANSACTION`. Lacking the
+/// privilege to create it skips the check.
+pub fn probe(client: &SpiClient) -> HealthReport {
+    let mut errors = Vec::new();
+    let can_select = match client.checked_select("SELECT 1", None, None) {
+        Ok(_) => true,
+        Err(err) => {
+            errors.push(err);
+            false
+        }
+    };
+    let read_only = unsafe { pg_sys::XactReadOnly || pg_sys::RecoveryInProgress() };
+    let can_write = if read_only {
+        None
+    } else {
+        match write_row() {
+            Ok(()) => Some(true),
+            Err(err) if is_not_permitted(&err) => None,
+            Err(err) => {
+                errors.push(err);
+                Some(false)
+            }
+        }
+    };
+    let database_size = match rewrite::exempt(|| {
+        client.checked_select("SELECT pg_database_size(current_database())", None, None)
+    }) {
+        Ok(table) => table.first().get_one::<i64>().map(|size| size as u64),
+        Err(err) => {
+            errors.push(err);
+            None
+        }
+    };
+    let size_headroom = MAX_DATABASE_SIZE
+        .with(Cell::get)
+        .zip(database_size)
+        .map(|(max, size)| max as i64 - size as i64);
+    HealthReport {
+        can_select,
+        can_write,
+        database_size,
+        size_headroom,
+        errors,
+    }
+}
+
+fn write_row() -> Result<(), Error> {
+    SpiClient.try_sub_transaction(|xact| {
+        // Nothing written is kept
+        let mut xact = xact.rollback_on_drop();
+        // Named after the backend, so that concurrent probes don't wait for each other
+        let table = format!("spiext_health_probe_{}", unsafe { pg_sys::MyProcPid });
+        xact.update(&format!("CREATE TABLE {} (v int)", table), None, None)?;
+        xact.update(&format!("INSERT INTO {} VALUES (1)", table), None, None)?;
+        Ok(())
+    })?
+}
+
+/// Whether the table of [`write_row`] couldn't be created for lack of privileges (or of a schema to create it in)
+fn is_not_permitted(err: &Error) -> bool {
+    matches!(
+        err.sqlstate().as_ref().map(SqlState::as_str),
+        Some("42501" | "3F000")
+    )
+}
@@ -0,0 +1,240 @@
+//! Before and after images of the rows changed by a command
+//!
+//! Images are captured for single-table `UPDATE` and `DELETE` commands. The command is parsed to find its target table
+//! and `WHERE` clause, the rows it will change are selected `FOR UPDATE` with the same clause (the old images), and the
+//! command is executed with `RETURNING *` (the new images), all in one sub-transaction. Locking the rows first keeps
+//! other transactions from changing them in between, but the clause is evaluated twice: if it calls volatile functions
+//! (such as `random()`), or if rows matching it are inserted concurrently under `READ COMMITTED`, the two images may
+//! not cover the same rows.
+
+use pgx::{pg_sys, PgList, PgOid, SpiClient, SpiTupleTable};
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+use std::panic::AssertUnwindSafe;
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::owned::OwnedRows;
+use crate::quote::*;
+use crate::scan::{is_empty_query, Scanner, Token};
+
+/// Rows changed by a command, as they were before and after it
+#[derive(Debug, Default)]
+pub struct RowImages {
+    /// Rows before the command
+    pub old: OwnedRows,
+    /// Rows after the command, empty (with no columns) for `DELETE`
+    pub new: OwnedRows,
+}
+
+/// Row image capture error
+#[derive(Debug)]
+pub enum ImageError {
+    /// Images can't be captured for the command, for the given reason; nothing was executed
+    UnsupportedStatement(String),
+    /// The command failed
+    Query(Error),
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::UnsupportedStatement(reason) => {
+                write!(f, "can't capture row images: {}", reason)
+            }
+            ImageError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for ImageError {
+    fn from(err: Error) -> Self {
+        ImageError::Query(err)
+    }
+}
+
+/// Checked commands capturing the rows they change
+pub trait ImageCommands {
+    /// Execute a single-table `UPDATE` or `DELETE`, returning its output along with the images of the rows it
+    /// changed, or an error if one occurred.
+    ///
+    /// The command is executed with `RETURNING *` (`UPDATE` only), so its output is the new images. With
+    /// `key_hint`, a column of the table, both images are ordered by it, so that the old and new images of a row
+    /// (whose key the command doesn't change) are at the same index.
+    ///
+    /// Commands joining other tables (`UPDATE ... FROM`, `DELETE ... USING`), with a `WITH` or `RETURNING` clause,
+    /// or using `WHERE CURRENT OF` return [`ImageError::UnsupportedStatement`] without executing anything.
+    fn checked_update_with_images(
+        &mut self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        key_hint: Option<&str>,
+    ) -> Result<(SpiTupleTable, RowImages), ImageError>;
+}
+
+impl ImageCommands for SpiClient {
+    fn checked_update_with_images(
+        &mut self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        key_hint: Option<&str>,
+    ) -> Result<(SpiTupleTable, RowImages), ImageError> {
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery.into());
+        }
+        let args = AssertUnwindSafe(args);
+        checked_sub_transaction(move |client| {
+            let args = args;
+            let target = unsafe { analyze(query) }?;
+            let order_by = key_hint.map_or(String::new(), |key| {
+                format!(" ORDER BY {}", quote_identifier(key))
+            });
+            let old = OwnedRows::from_table(client.select(
+                &format!(
+                    "SELECT {}.* FROM {}{}{} FOR UPDATE",
+                    target.reference,
+                    target.relation,
+                    target.predicate.map_or(String::new(), |predicate| format!(
+                        " WHERE {}\n",
+                        predicate
+                    )),
+                    order_by
+                ),
+                None,
+                args.0.clone(),
+            ));
+            if !target.update {
+                let table = client.update(target.body, None, args.0);
+                return Ok((
+                    table,
+                    RowImages {
+                        old,
+                        ..Default::default()
+                    },
+                ));
+            }
+            // A newline ends a trailing line comment
+            let statement = format!("{}\nRETURNING *", target.body);
+            let statement = if key_hint.is_some() {
+                format!(
+                    "WITH changed AS ({}\n) SELECT * FROM changed{}",
+                    statement, order_by
+                )
+            } else {
+                statement
+            };
+            let mut table = client.update(&statement, None, args.0).first();
+            let mut new = OwnedRows::with_columns_of(&table);
+            for tuple in table.by_ref() {
+                new.push(&tuple);
+            }
+            Ok((table.first(), RowImages { old, new }))
+        })?
+    }
+}
+
+/// Target of a command images can be captured for
+struct Target<'a> {
+    update: bool,
+    /// Qualified, quoted table, with `ONLY` and the alias
+    relation: String,
+    /// Name the table is referenced by in the command (its alias, if any)
+    reference: String,
+    /// Text of the `WHERE` clause, without the keyword
+    predicate: Option<&'a str>,
+    /// The command without trailing semicolons
+    body: &'a str,
+}
+
+/// Parse `query`, finding what images can be captured for
+unsafe fn analyze(query: &str) -> Result<Target<'_>, ImageError> {
+    let unsupported = |reason: &str| Err(ImageError::UnsupportedStatement(reason.to_string()));
+    let c_query = CString::new(query).expect("query contains a NUL byte");
+    let statements = PgList::<pg_sys::RawStmt>::from_pg(pg_sys::pg_parse_query(c_query.as_ptr()));
+    if statements.len() != 1 {
+        return unsupported("only a single statement is supported");
+    }
+    let stmt = (*statements.get_ptr(0).unwrap()).stmt;
+    let (update, relation, where_clause) = match (*stmt).type_ {
+        pg_sys::NodeTag::T_UpdateStmt => {
+            let stmt = stmt as *mut pg_sys::UpdateStmt;
+            if !(*stmt).fromClause.is_null() {
+                return unsupported("UPDATE ... FROM joins other tables");
+            }
+            if !(*stmt).withClause.is_null() || !(*stmt).returningList.is_null() {
+                return unsupported("WITH and RETURNING clauses are not supported");
+            }
+            (true, (*stmt).relation, (*stmt).whereClause)
+        }
+        pg_sys::NodeTag::T_DeleteStmt => {
+            let stmt = stmt as *mut pg_sys::DeleteStmt;
+            if !(*stmt).usingClause.is_null() {
+                return unsupported("DELETE ... USING joins other tables");
+            }
+            if !(*stmt).withClause.is_null() || !(*stmt).returningList.is_null() {
+                return unsupported("WITH and RETURNING clauses are not supported");
+            }
+            (false, (*stmt).relation, (*stmt).whereClause)
+        }
+        _ => return unsupported("only UPDATE and DELETE are supported"),
+    };
+    if !where_clause.is_null() && (*where_clause).type_ == pg_sys::NodeTag::T_CurrentOfExpr {
+        return unsupported("WHERE CURRENT OF is not supported");
+    }
+
+    let name = |ptr: *const std::os::raw::c_char| {
+        (!ptr.is_null()).then(|| quote_identifier(&CStr::from_ptr(ptr).to_string_lossy()))
+    };
+    let table = name((*relation).relname).unwrap();
+    let alias = if (*relation).alias.is_null() {
+        None
+    } else {
+        name((*(*relation).alias).aliasname)
+    };
+    let relation_sql = format!(
+        "{}{}{}{}",
+        if (*relation).inh { "" } else { "ONLY " },
+        name((*relation).schemaname).map_or(String::new(), |schema| format!("{}.", schema)),
+        table,
+        alias
+            .as_ref()
+            .map_or(String::new(), |alias| format!(" AS {}", alias))
+    );
+
+    // With no joined tables and no RETURNING clause, the WHERE clause is the last one, and the first WHERE keyword
+    // outside of parentheses starts it
+    let tokens: Vec<(usize, Token)> = Scanner::new(query).collect();
+    let body_end = tokens
+        .iter()
+        .rposition(|(_, token)| *token != Token::Semicolon)
+        .and_then(|last| tokens.get(last + 1))
+        .map_or(query.len(), |(offset, _)| *offset);
+    let body = &query[..body_end];
+    let predicate = if where_clause.is_null() {
+        None
+    } else {
+        let mut depth = 0;
+        let start = tokens.iter().find_map(|(offset, token)| {
+            match token {
+                Token::Symbol("(") => depth += 1,
+                Token::Symbol(")") => depth -= 1,
+                Token::Word(word) if depth == 0 && word.eq_ignore_ascii_case("where") => {
+                    return Some(offset + word.len());
+                }
+                _ => {}
+            }
+            None
+        });
+        match start {
+            Some(start) if start <= body.len() => Some(body[start..].trim()),
+            _ => return unsupported("the WHERE clause could not be located"),
+        }
+    };
+    Ok(Target {
+        update,
+        relation: relation_sql,
+        reference: alias.unwrap_or(table),
+        predicate,
+        body,
+    })
+}
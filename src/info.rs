@@ -0,0 +1,192 @@
+//! Execution details of checked commands
+
+use pgx::{pg_sys::Datum, PgOid, SpiClient, SpiTupleTable};
+use std::time::{Duration, Instant};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::scan::is_empty_query;
+
+/// Details of an executed command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecutionInfo {
+    /// Query identifier, as reported by `pg_stat_statements`
+    ///
+    /// It is only available on Postgres 14 and later, when `compute_query_id` is enabled. If the command consists of
+    /// multiple statements, this is the identifier of the first one.
+    pub query_id: Option<u64>,
+    /// Number of rows processed
+    pub rows: u64,
+    /// Time it took to execute the command, including its sub-transaction
+    pub duration: Duration,
+    /// WAL and buffers used to execute the command
+    ///
+    /// It is only available on Postgres 13 and later.
+    pub resources: Option<ResourceUsage>,
+}
+
+/// WAL and buffer usage of an executed command, as in `EXPLAIN (ANALYZE, WAL, BUFFERS)`
+///
+/// These are the differences of the backend's own counters (`pgWalUsage` and `pgBufferUsage`) across the command and
+/// its sub-transaction, so they are not affected by other backends or background processes. Starting and committing
+/// the sub-transaction uses no buffers and writes no WAL of its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub wal_records: u64,
+    pub wal_bytes: u64,
+    pub shared_blks_hit: u64,
+    pub shared_blks_read: u64,
+    pub temp_blks_written: u64,
+}
+
+#[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15"))]
+impl ResourceUsage {
+    /// Counters of the backend so far
+    fn current() -> Self {
+        let (wal, buffers) = unsafe { (pgx::pg_sys::pgWalUsage, pgx::pg_sys::pgBufferUsage) };
+        Self {
+            wal_records: wal.wal_records as u64,
+            wal_bytes: wal.wal_bytes as u64,
+            shared_blks_hit: buffers.shared_blks_hit as u64,
+            shared_blks_read: buffers.shared_blks_read as u64,
+            temp_blks_written: buffers.temp_blks_written as u64,
+        }
+    }
+
+    /// Usage since the counters were `before`
+    fn since(before: Self) -> Self {
+        let after = Self::current();
+        Self {
+            wal_records: after.wal_records - before.wal_records,
+            wal_bytes: after.wal_bytes - before.wal_bytes,
+            shared_blks_hit: after.shared_blks_hit - before.shared_blks_hit,
+            shared_blks_read: after.shared_blks_read - before.shared_blks_read,
+            temp_blks_written: after.temp_blks_written - before.temp_blks_written,
+        }
+    }
+}
+
+/// Run `f`, measuring the resources it used
+#[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15"))]
+fn measure<R, F: FnOnce() -> R>(f: F) -> (R, Option<ResourceUsage>) {
+    let before = ResourceUsage::current();
+    let result = f();
+    (result, Some(ResourceUsage::since(before)))
+}
+
+#[cfg(not(any(feature = "pg13", feature = "pg14", feature = "pg15")))]
+fn measure<R, F: FnOnce() -> R>(f: F) -> (R, Option<ResourceUsage>) {
+    (f(), None)
+}
+
+/// Checked commands that report their [`ExecutionInfo`]
+///
+/// The query identifier is obtained by preparing the command before executing it, so the command is parsed and
+/// analyzed twice.
+pub trait InfoCommands {
+    /// Execute a read-only command, returning an error if one occurred.
+    fn checked_select_with_info(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<(SpiTupleTable, ExecutionInfo), Error>;
+
+    /// Execute a mutable command, returning an error if one occurred.
+    fn checked_update_with_info(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<(SpiTupleTable, ExecutionInfo), Error>;
+}
+
+impl InfoCommands for SpiClient {
+    fn checked_select_with_info(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<(SpiTupleTable, ExecutionInfo), Error> {
+        let query_id = query_id(query, args.as_deref())?;
+        let start = Instant::now();
+        let (table, resources) = measure(|| self.checked_select(query, limit, args));
+        let table = table?;
+        let info = ExecutionInfo {
+            query_id,
+            rows: table.len() as u64,
+            duration: start.elapsed(),
+            resources,
+        };
+        Ok((table, info))
+    }
+
+    fn checked_update_with_info(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<(SpiTupleTable, ExecutionInfo), Error> {
+        let query_id = query_id(query, args.as_deref())?;
+        let start = Instant::now();
+        let (table, resources) = measure(|| self.checked_update(query, limit, args));
+        let table = table?;
+        let info = ExecutionInfo {
+            query_id,
+            rows: table.len() as u64,
+            duration: start.elapsed(),
+            resources,
+        };
+        Ok((table, info))
+    }
+}
+
+#[cfg(any(feature = "pg14", feature = "pg15"))]
+fn query_id(query: &str, args: Option<&[(PgOid, Option<Datum>)]>) -> Result<Option<u64>, Error> {
+    if is_empty_query(query) {
+        return Err(Error::EmptyQuery);
+    }
+    use pgx::pg_sys;
+
+    let query = std::ffi::CString::new(query).expect("query contains a NUL byte");
+    let arg_types: Vec<pg_sys::Oid> = args
+        .unwrap_or_default()
+        .iter()
+        .map(|(oid, _)| oid.value())
+        .collect();
+    checked_sub_transaction(move |_| unsafe { prepared_query_id(&query, arg_types) })
+}
+
+#[cfg(not(any(feature = "pg14", feature = "pg15")))]
+fn query_id(query: &str, _args: Option<&[(PgOid, Option<Datum>)]>) -> Result<Option<u64>, Error> {
+    if is_empty_query(query) {
+        return Err(Error::EmptyQuery);
+    }
+    Ok(None)
+}
+
+/// Returns the identifier of the first statement of the query, computed when it is analyzed
+#[cfg(any(feature = "pg14", feature = "pg15"))]
+unsafe fn prepared_query_id(
+    query: &std::ffi::CStr,
+    mut arg_types: Vec<pgx::pg_sys::Oid>,
+) -> Option<u64> {
+    use pgx::{pg_sys, PgList};
+
+    let plan = pg_sys::SPI_prepare(
+        query.as_ptr(),
+        arg_types.len() as i32,
+        arg_types.as_mut_ptr(),
+    );
+    if plan.is_null() {
+        crate::error::spi_error_panic("SPI_prepare", pg_sys::SPI_result);
+    }
+    let query_id =
+        PgList::<pg_sys::CachedPlanSource>::from_pg(pg_sys::SPI_plan_get_plan_sources(plan))
+            .head()
+            .and_then(|source| PgList::<pg_sys::Query>::from_pg((*source).query_list).head())
+            .map(|query| (*query).queryId)
+            .filter(|query_id| *query_id != 0);
+    pg_sys::SPI_freeplan(plan);
+    query_id
+}
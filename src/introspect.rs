@@ -0,0 +1,230 @@
+//! Typed metadata of tables, such as for generating code
+//!
+//! Schema and table names are given as they are stored in the catalogs (not quoted), while the names of referenced
+//! tables are qualified if they're not visible in the search path, as `regclass` prints them.
+
+use pgx::{pg_sys, IntoDatum, PgBuiltInOids, SpiClient};
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::rewrite;
+
+/// Columns and constraints of a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableSchema {
+    /// Columns in order, without dropped and system columns
+    pub columns: Vec<ColumnDef>,
+    /// Check constraints, ordered by name
+    pub checks: Vec<CheckDef>,
+    /// Foreign keys, ordered by name
+    pub foreign_keys: Vec<FkDef>,
+}
+
+/// Column of a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub type_oid: pg_sys::Oid,
+    /// Type modifier (`pg_attribute.atttypmod`), -1 if the type has none
+    pub typmod: i32,
+    pub not_null: bool,
+    /// Default expression, or generation expression of a generated column
+    pub default_expr: Option<String>,
+    /// Whether the expression calls volatile functions (such as `clock_timestamp()` or `nextval()`), so it may
+    /// give a different value for every row
+    ///
+    /// Stable functions, such as `now()`, are not volatile.
+    pub default_is_volatile: bool,
+    /// Whether the column is generated (`GENERATED ALWAYS AS (...) STORED`), which it never is before Postgres 12
+    pub generated: bool,
+}
+
+/// Check constraint of a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckDef {
+    pub name: String,
+    /// Definition, as in `CHECK ((v > 0))`
+    pub definition: String,
+}
+
+/// What a foreign key does with referencing rows when the referenced row is deleted or updated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FkAction {
+    NoAction,
+    Restrict,
+    Cascade,
+    SetNull,
+    SetDefault,
+}
+
+impl FkAction {
+    /// Action of a code of `pg_constraint.confdeltype` or `confupdtype`
+    fn from_code(code: &str) -> Self {
+        match code {
+            "r" => FkAction::Restrict,
+            "c" => FkAction::Cascade,
+            "n" => FkAction::SetNull,
+            "d" => FkAction::SetDefault,
+            _ => FkAction::NoAction,
+        }
+    }
+}
+
+/// Foreign key of a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FkDef {
+    pub name: String,
+    /// Referencing columns, in the order of the key
+    pub columns: Vec<String>,
+    /// Referenced table
+    pub ref_table: String,
+    /// Referenced columns, matching `columns`
+    pub ref_columns: Vec<String>,
+    pub on_delete: FkAction,
+    pub on_update: FkAction,
+}
+
+/// Introspection error
+#[derive(Debug)]
+pub enum IntrospectError {
+    /// There is no table, view or foreign table with that name
+    TableNotFound { schema: String, table: String },
+    /// A catalog query failed
+    Query(Error),
+}
+
+impl Display for IntrospectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntrospectError::TableNotFound { schema, table } => {
+                write!(f, "table \"{}\".\"{}\" does not exist", schema, table)
+            }
+            IntrospectError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for IntrospectError {
+    fn from(err: Error) -> Self {
+        IntrospectError::Query(err)
+    }
+}
+
+#[cfg(feature = "pg11")]
+const GENERATED: &str = "false";
+#[cfg(not(feature = "pg11"))]
+const GENERATED: &str = "a.attgenerated = 's'";
+
+/// Columns, check constraints and foreign keys of `schema.table`
+pub fn table_schema(
+    client: &SpiClient,
+    schema: &str,
+    table: &str,
+) -> Result<TableSchema, IntrospectError> {
+    let oid = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT c.oid FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+             WHERE n.nspname = $1 AND c.relname = $2 AND c.relkind IN ('r', 'p', 'v', 'm', 'f')",
+            Some(1),
+            Some(vec![
+                (PgBuiltInOids::TEXTOID.oid(), schema.into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), table.into_datum()),
+            ]),
+        )
+    })?
+    .first()
+    .get_one::<pg_sys::Oid>()
+    .ok_or_else(|| IntrospectError::TableNotFound {
+        schema: schema.to_string(),
+        table: table.to_string(),
+    })?;
+    let args = || Some(vec![(PgBuiltInOids::OIDOID.oid(), oid.into_datum())]);
+
+    let columns: Vec<(ColumnDef, Option<String>)> = rewrite::exempt(|| {
+        client.checked_select(
+            &format!(
+                "SELECT a.attname::text, a.atttypid, a.atttypmod, a.attnotnull, pg_get_expr(d.adbin, d.adrelid), \
+                 d.adbin::text, {} \
+                 FROM pg_attribute a LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum \
+                 WHERE a.attrelid = $1 AND a.attnum > 0 AND NOT a.attisdropped ORDER BY a.attnum",
+                GENERATED
+            ),
+            None,
+            args(),
+        )
+    })?
+    .map(|row| {
+        let column = ColumnDef {
+            name: row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+            type_oid: row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+            typmod: row.by_ordinal(3).unwrap().value().unwrap_or_default(),
+            not_null: row.by_ordinal(4).unwrap().value().unwrap_or_default(),
+            default_expr: row.by_ordinal(5).unwrap().value(),
+            default_is_volatile: false,
+            generated: row.by_ordinal(7).unwrap().value().unwrap_or_default(),
+        };
+        (column, row.by_ordinal(6).unwrap().value())
+    })
+    .collect();
+    let columns = columns
+        .into_iter()
+        .map(|(mut column, tree)| {
+            column.default_is_volatile =
+                tree.map_or(false, |tree| unsafe { contains_volatile_functions(&tree) });
+            column
+        })
+        .collect();
+
+    let checks = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT conname::text, pg_get_constraintdef(oid) FROM pg_constraint \
+             WHERE conrelid = $1 AND contype = 'c' ORDER BY conname",
+            None,
+            args(),
+        )
+    })?
+    .map(|row| CheckDef {
+        name: row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+        definition: row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+    })
+    .collect();
+
+    let foreign_keys = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT c.conname::text, \
+             ARRAY(SELECT a.attname::text FROM unnest(c.conkey) WITH ORDINALITY k(attnum, i) \
+             JOIN pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = k.attnum ORDER BY k.i), \
+             c.confrelid::regclass::text, \
+             ARRAY(SELECT a.attname::text FROM unnest(c.confkey) WITH ORDINALITY k(attnum, i) \
+             JOIN pg_attribute a ON a.attrelid = c.confrelid AND a.attnum = k.attnum ORDER BY k.i), \
+             c.confdeltype::text, c.confupdtype::text \
+             FROM pg_constraint c WHERE c.conrelid = $1 AND c.contype = 'f' ORDER BY c.conname",
+            None,
+            args(),
+        )
+    })?
+    .map(|row| FkDef {
+        name: row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+        columns: row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+        ref_table: row.by_ordinal(3).unwrap().value().unwrap_or_default(),
+        ref_columns: row.by_ordinal(4).unwrap().value().unwrap_or_default(),
+        on_delete: FkAction::from_code(&row.by_ordinal(5).unwrap().value::<String>().unwrap_or_default()),
+        on_update: FkAction::from_code(&row.by_ordinal(6).unwrap().value::<String>().unwrap_or_default()),
+    })
+    .collect();
+
+    Ok(TableSchema {
+        columns,
+        checks,
+        foreign_keys,
+    })
+}
+
+/// Whether an expression, as stored in `pg_attrdef.adbin`, calls volatile functions (going by `pg_proc.provolatile`)
+unsafe fn contains_volatile_functions(tree: &str) -> bool {
+    let tree = CString::new(tree).expect("expression contains a NUL byte");
+    let node = pg_sys::stringToNode(tree.as_ptr()) as *mut pg_sys::Node;
+    pg_sys::contain_volatile_functions(node)
+}
@@ -0,0 +1,305 @@
+//! Joining rows in Rust
+//!
+//! For rows that can't be joined in SQL, such as rows computed in Rust or fetched from another database.
+
+use pgx::{pg_sys, FromDatum, IntoDatum, PgBuiltInOids, PgOid};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use crate::owned::{OwnedRow, OwnedRows};
+
+/// Kind of join performed by [`hash_join`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKind {
+    /// Only pairs of matching rows
+    Inner,
+    /// Pairs of matching rows, and left rows that match none on their own
+    LeftOuter,
+}
+
+/// Side of a join
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Join error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// There is no key column with this name
+    NoSuchColumn(Side, String),
+    /// The sides have different numbers of key columns
+    KeyLengthMismatch { left: usize, right: usize },
+    /// Values of the key column's type can't be compared
+    UnsupportedKeyType {
+        side: Side,
+        column: String,
+        type_oid: PgOid,
+    },
+    /// Values of the key columns at this (0-based) position can't be compared with each other
+    IncompatibleKeyTypes {
+        position: usize,
+        left: PgOid,
+        right: PgOid,
+    },
+}
+
+impl Display for JoinError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::NoSuchColumn(side, column) => {
+                write!(f, "no column named \"{}\" on the {:?} side", column, side)
+            }
+            JoinError::KeyLengthMismatch { left, right } => {
+                write!(
+                    f,
+                    "{} left key columns, but {} right key columns",
+                    left, right
+                )
+            }
+            JoinError::UnsupportedKeyType {
+                side,
+                column,
+                type_oid,
+            } => write!(
+                f,
+                "key column \"{}\" on the {:?} side has unsupported type with OID {}",
+                column,
+                side,
+                type_oid.value()
+            ),
+            JoinError::IncompatibleKeyTypes {
+                position,
+                left,
+                right,
+            } => write!(
+                f,
+                "key columns at position {} have incompatible types with OIDs {} and {}",
+                position,
+                left.value(),
+                right.value()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// Row of the result of [`hash_join`]
+#[derive(Clone)]
+pub struct JoinedRow {
+    left: Rc<OwnedRows>,
+    left_index: usize,
+    right: Rc<OwnedRows>,
+    right_index: Option<usize>,
+}
+
+impl JoinedRow {
+    pub fn left(&self) -> OwnedRow<'_> {
+        self.left.row(self.left_index).unwrap()
+    }
+
+    /// Right row, `None` for left rows that matched none in a left outer join
+    pub fn right(&self) -> Option<OwnedRow<'_>> {
+        self.right_index.map(|index| self.right.row(index).unwrap())
+    }
+
+    /// Get a column's value by name from one side, returning `None` if it is NULL or there is no row on that side
+    ///
+    /// Panics if there is no such column.
+    pub fn get<T: FromDatum + IntoDatum>(&self, side: Side, column: &str) -> Option<T> {
+        match side {
+            Side::Left => self.left().get(column),
+            Side::Right => self.right()?.get(column),
+        }
+    }
+}
+
+/// Key value of a row, compared by value
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum KeyValue {
+    Integer(i64),
+    Text(String),
+    Uuid([u8; 16]),
+    Date(i32),
+    Timestamp(i64),
+    TimestampWithTimeZone(i64),
+}
+
+/// Type of key values, which can only be compared with values of the same kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeyKind {
+    Int2,
+    Int4,
+    Int8,
+    Text,
+    Uuid,
+    Date,
+    Timestamp,
+    TimestampWithTimeZone,
+}
+
+impl KeyKind {
+    pub(crate) fn of(type_oid: PgOid) -> Option<Self> {
+        Some(match type_oid {
+            PgOid::BuiltIn(PgBuiltInOids::INT2OID) => KeyKind::Int2,
+            PgOid::BuiltIn(PgBuiltInOids::INT4OID) => KeyKind::Int4,
+            PgOid::BuiltIn(PgBuiltInOids::INT8OID) => KeyKind::Int8,
+            PgOid::BuiltIn(PgBuiltInOids::TEXTOID | PgBuiltInOids::VARCHAROID) => KeyKind::Text,
+            PgOid::BuiltIn(PgBuiltInOids::UUIDOID) => KeyKind::Uuid,
+            PgOid::BuiltIn(PgBuiltInOids::DATEOID) => KeyKind::Date,
+            PgOid::BuiltIn(PgBuiltInOids::TIMESTAMPOID) => KeyKind::Timestamp,
+            PgOid::BuiltIn(PgBuiltInOids::TIMESTAMPTZOID) => KeyKind::TimestampWithTimeZone,
+            _ => return None,
+        })
+    }
+
+    fn is_integer(self) -> bool {
+        matches!(self, KeyKind::Int2 | KeyKind::Int4 | KeyKind::Int8)
+    }
+
+    pub(crate) fn is_compatible(self, other: Self) -> bool {
+        self == other || (self.is_integer() && other.is_integer())
+    }
+
+    pub(crate) fn value(self, row: &OwnedRow, ordinal: usize) -> Option<KeyValue> {
+        Some(match self {
+            KeyKind::Int2 => KeyValue::Integer(row.get_by_ordinal::<i16>(ordinal)?.into()),
+            KeyKind::Int4 => KeyValue::Integer(row.get_by_ordinal::<i32>(ordinal)?.into()),
+            KeyKind::Int8 => KeyValue::Integer(row.get_by_ordinal::<i64>(ordinal)?),
+            KeyKind::Text => KeyValue::Text(row.get_by_ordinal::<String>(ordinal)?),
+            KeyKind::Uuid => {
+                let datum = row.get_by_ordinal::<pg_sys::Datum>(ordinal)?;
+                // `pg_uuid_t` is 16 bytes with no alignment requirements
+                KeyValue::Uuid(unsafe { *datum.cast_mut_ptr::<[u8; 16]>() })
+            }
+            KeyKind::Date => KeyValue::Date(row.get_by_ordinal::<i32>(ordinal)?),
+            KeyKind::Timestamp => KeyValue::Timestamp(row.get_by_ordinal::<i64>(ordinal)?),
+            KeyKind::TimestampWithTimeZone => {
+                KeyValue::TimestampWithTimeZone(row.get_by_ordinal::<i64>(ordinal)?)
+            }
+        })
+    }
+}
+
+/// Key columns of one side: their kinds and (1-based) ordinals
+struct Key(Vec<(KeyKind, usize)>);
+
+impl Key {
+    fn resolve(rows: &OwnedRows, side: Side, columns: &[&str]) -> Result<Self, JoinError> {
+        columns
+            .iter()
+            .map(|&name| {
+                let (index, column) = rows
+                    .columns()
+                    .iter()
+                    .enumerate()
+                    .find(|(_, column)| column.name == name)
+                    .ok_or_else(|| JoinError::NoSuchColumn(side, name.to_string()))?;
+                let kind =
+                    KeyKind::of(column.type_oid).ok_or_else(|| JoinError::UnsupportedKeyType {
+                        side,
+                        column: name.to_string(),
+                        type_oid: column.type_oid,
+                    })?;
+                Ok((kind, index + 1))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Key)
+    }
+
+    /// Key values of a row, `None` if any of them is NULL
+    fn values(&self, row: &OwnedRow) -> Option<Vec<KeyValue>> {
+        self.0
+            .iter()
+            .map(|(kind, ordinal)| kind.value(row, *ordinal))
+            .collect()
+    }
+}
+
+/// Join `left` and `right` on equal values of `left_key` and `right_key` columns
+///
+/// A hash table is built from the smaller side, and the other side is looked up in it. Key columns may be of types
+/// `int2`, `int4`, `int8` (which compare with each other), `text`, `varchar`, `uuid`, `date`, `timestamp` and
+/// `timestamptz`; values are compared by their binary representation (so `text` is compared byte by byte, regardless
+/// of collation). As in SQL, a NULL key value matches nothing.
+///
+/// Rows are ordered by their left row, then by their right row.
+pub fn hash_join(
+    left: OwnedRows,
+    right: OwnedRows,
+    left_key: &[&str],
+    right_key: &[&str],
+    kind: JoinKind,
+) -> Result<Vec<JoinedRow>, JoinError> {
+    if left_key.len() != right_key.len() {
+        return Err(JoinError::KeyLengthMismatch {
+            left: left_key.len(),
+            right: right_key.len(),
+        });
+    }
+    let left_columns = Key::resolve(&left, Side::Left, left_key)?;
+    let right_columns = Key::resolve(&right, Side::Right, right_key)?;
+    for (position, ((left_kind, left_ordinal), (right_kind, right_ordinal))) in
+        left_columns.0.iter().zip(&right_columns.0).enumerate()
+    {
+        if !left_kind.is_compatible(*right_kind) {
+            return Err(JoinError::IncompatibleKeyTypes {
+                position,
+                left: left.columns()[left_ordinal - 1].type_oid,
+                right: right.columns()[right_ordinal - 1].type_oid,
+            });
+        }
+    }
+
+    let build_left = left.len() <= right.len();
+    let (build, build_key, probe, probe_key) = if build_left {
+        (&left, &left_columns, &right, &right_columns)
+    } else {
+        (&right, &right_columns, &left, &left_columns)
+    };
+    let mut table: HashMap<Vec<KeyValue>, Vec<usize>> = HashMap::new();
+    for row in build.iter() {
+        if let Some(values) = build_key.values(&row) {
+            table.entry(values).or_default().push(row.index());
+        }
+    }
+    let mut pairs = vec![];
+    for row in probe.iter() {
+        let matches = probe_key.values(&row).and_then(|values| table.get(&values));
+        for &index in matches.into_iter().flatten() {
+            pairs.push(if build_left {
+                (index, Some(row.index()))
+            } else {
+                (row.index(), Some(index))
+            });
+        }
+    }
+    if kind == JoinKind::LeftOuter {
+        let mut matched = vec![false; left.len()];
+        for (index, _) in &pairs {
+            matched[*index] = true;
+        }
+        pairs.extend(
+            matched
+                .into_iter()
+                .enumerate()
+                .filter(|(_, matched)| !matched)
+                .map(|(index, _)| (index, None)),
+        );
+    }
+    pairs.sort_unstable();
+
+    let (left, right) = (Rc::new(left), Rc::new(right));
+    Ok(pairs
+        .into_iter()
+        .map(|(left_index, right_index)| JoinedRow {
+            left: left.clone(),
+            left_index,
+            right: right.clone(),
+            right_index,
+        })
+        .collect())
+}
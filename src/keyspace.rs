@@ -0,0 +1,89 @@
+//! Processing a keyspace in hash partitions, each in a sub-transaction of its own
+//!
+//! This sits between processing every key in a sub-transaction of its own, which is slow, and processing all of them
+//! in a single one, where one bad key loses the whole run.
+
+use pgx::{pg_sys::Datum, PgOid, SpiClient};
+
+use crate::bgworker;
+use crate::checked::*;
+use crate::error::Error;
+use crate::owned::OwnedRows;
+use crate::subtxn::*;
+
+/// Outcome of [`partitioned_run`]
+#[derive(Debug)]
+pub struct RunReport {
+    /// Partitions whose sub-transaction was committed
+    pub succeeded_partitions: u32,
+    /// Partitions whose sub-transaction was rolled back, in order, with the error that rolled it back
+    pub failed: Vec<(u32, Error)>,
+    /// Keys of the partitions that succeeded
+    pub keys_processed: u64,
+    /// Whether the run stopped before processing every partition because the background worker was asked to shut
+    /// down (see [`with_yield_points`](bgworker::with_yield_points))
+    pub shutdown_requested: bool,
+}
+
+/// Split the keys returned by `key_query` into `partitions` partitions by their hash, and process each partition with
+/// `per_partition` in a sub-transaction of its own
+///
+/// The keys are fetched with a checked select and copied up front, so that they all come from the same snapshot,
+/// whatever the partitions do. Partitions are processed in order, including empty ones (so `per_partition` is called
+/// exactly `partitions` times, unless the run stops because of a shutdown request), and the same keys always end up in
+/// the same partition. A partition's sub-transaction is committed if `per_partition` returns `Ok`, and rolled back if
+/// it returns `Err` or an error is raised, in which case the partition is reported as failed and the next one is
+/// processed regardless. Panics are caught and reported like errors.
+///
+/// Returns an error without processing any partition if the keys can't be fetched. Panics if `partitions` is 0.
+pub fn partitioned_run<F>(
+    client: &mut SpiClient,
+    key_query: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+    partitions: u32,
+    mut per_partition: F,
+) -> Result<RunReport, Error>
+where
+    F: FnMut(&mut SubTransaction<SpiClientWrapper, false>, OwnedRows) -> Result<(), Error>,
+{
+    assert!(partitions > 0, "number of partitions must be positive");
+    let keys = OwnedRows::from_table((&*client).checked_select(key_query, None, args)?);
+    let mut report = RunReport {
+        succeeded_partitions: 0,
+        failed: Vec::new(),
+        keys_processed: 0,
+        shutdown_requested: false,
+    };
+    for (partition, keys) in (0..partitions).zip(keys.split_by_hash(partitions as usize)) {
+        if bgworker::batch_yield_point().is_err() {
+            report.shutdown_requested = true;
+            break;
+        }
+        let len = keys.len() as u64;
+        match run_partition(&mut per_partition, keys) {
+            Ok(()) => {
+                report.succeeded_partitions += 1;
+                report.keys_processed += len;
+            }
+            Err(err) => report.failed.push((partition, err)),
+        }
+    }
+    Ok(report)
+}
+
+fn run_partition<F>(per_partition: &mut F, keys: OwnedRows) -> Result<(), Error>
+where
+    F: FnMut(&mut SubTransaction<SpiClientWrapper, false>, OwnedRows) -> Result<(), Error>,
+{
+    let xact = SpiClient.try_sub_transaction(|xact| xact.rollback_on_drop())?;
+    // A panic fails the partition, and `per_partition` is still called for the next ones
+    let (result, xact) = shield(
+        xact,
+        true,
+        assert_shield_safe(move |xact| per_partition(xact, keys)),
+    )?;
+    // Dropping the sub-transaction rolls it back
+    result?;
+    xact.commit();
+    Ok(())
+}
@@ -0,0 +1,322 @@
+//! Large objects (`pg_largeobject`), read and written in bounded chunks
+//!
+//! Every operation calls the server-side `lo_*` functions in a sub-transaction of its own, so a failure rolls back
+//! only that operation. Reads and writes are split into chunks of at most [`DEFAULT_CHUNK_SIZE`] bytes (see
+//! [`LargeObject::with_chunk_size`]), so objects are never materialized as a whole.
+
+use pgx::{pg_sys, pg_sys::Datum, FromDatum, IntoDatum, PgBuiltInOids, PgOid, SpiClient};
+use std::fmt::{Display, Formatter};
+use std::io::{SeekFrom, Write};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::subtxn::SubTransaction;
+
+/// Bytes read or written per command, unless set otherwise
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+const INV_WRITE: i32 = 0x20000;
+const INV_READ: i32 = 0x40000;
+
+/// What a large object is opened for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LargeObjectMode {
+    /// Reading, as of the snapshot taken when the object is opened
+    Read,
+    Write,
+    /// Reading and writing, reading what was written
+    ReadWrite,
+}
+
+impl LargeObjectMode {
+    fn flags(self) -> i32 {
+        match self {
+            LargeObjectMode::Read => INV_READ,
+            LargeObjectMode::Write => INV_WRITE,
+            LargeObjectMode::ReadWrite => INV_READ | INV_WRITE,
+        }
+    }
+}
+
+/// Large object error
+#[derive(Debug)]
+pub enum LargeObjectError {
+    /// There is no large object with this OID
+    NotFound(pg_sys::Oid),
+    /// The current user isn't allowed to open the large object with this OID in the requested mode
+    PermissionDenied(pg_sys::Oid),
+    /// The descriptor was used after the sub-transaction it was opened in ended
+    Closed(pg_sys::Oid),
+    /// Writing exported data failed
+    Io(std::io::Error),
+    /// A command failed
+    Query(Error),
+}
+
+impl LargeObjectError {
+    fn from_query(err: Error, oid: pg_sys::Oid) -> Self {
+        match err.sqlstate().as_ref().map(|sqlstate| sqlstate.as_str()) {
+            Some("42704") => LargeObjectError::NotFound(oid),
+            Some("42501") => LargeObjectError::PermissionDenied(oid),
+            _ => LargeObjectError::Query(err),
+        }
+    }
+}
+
+impl Display for LargeObjectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LargeObjectError::NotFound(oid) => write!(f, "large object {} does not exist", oid),
+            LargeObjectError::PermissionDenied(oid) => {
+                write!(f, "permission denied for large object {}", oid)
+            }
+            LargeObjectError::Closed(oid) => write!(
+                f,
+                "descriptor of large object {} was used after its sub-transaction ended",
+                oid
+            ),
+            LargeObjectError::Io(err) => write!(f, "{}", err),
+            LargeObjectError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for LargeObjectError {
+    fn from(err: Error) -> Self {
+        LargeObjectError::Query(err)
+    }
+}
+
+/// Open descriptor of a large object
+///
+/// The descriptor belongs to the sub-transaction it was opened in: Postgres closes it when that sub-transaction rolls
+/// back or the transaction ends, so it can only be used while that sub-transaction is open, and every use afterwards
+/// (even in another transaction) returns [`LargeObjectError::Closed`]. If not closed explicitly, it is closed when
+/// dropped, warning about errors.
+#[derive(Debug)]
+pub struct LargeObject {
+    oid: pg_sys::Oid,
+    fd: i32,
+    chunk_size: usize,
+    /// Sub-transaction the descriptor was opened in
+    opened_in: pg_sys::SubTransactionId,
+    /// Local id of the transaction the descriptor was opened in, as sub-transaction ids are reused by every transaction
+    transaction: pg_sys::LocalTransactionId,
+    closed: bool,
+}
+
+impl LargeObject {
+    /// Create an empty large object in `xact` and open it for reading and writing
+    pub fn create<Parent, const COMMIT: bool>(
+        xact: &mut SubTransaction<Parent, COMMIT>,
+    ) -> Result<Self, LargeObjectError> {
+        let oid = xact
+            .update("SELECT lo_create(0)", None, None)?
+            .first()
+            .get_one::<pg_sys::Oid>()
+            .expect("lo_create returned no OID");
+        Self::open(xact, oid, LargeObjectMode::ReadWrite)
+    }
+
+    /// Open the large object `oid` in `xact`
+    pub fn open<Parent, const COMMIT: bool>(
+        xact: &mut SubTransaction<Parent, COMMIT>,
+        oid: pg_sys::Oid,
+        mode: LargeObjectMode,
+    ) -> Result<Self, LargeObjectError> {
+        let fd = xact
+            .update(
+                "SELECT lo_open($1, $2)",
+                None,
+                Some(vec![
+                    (PgBuiltInOids::OIDOID.oid(), oid.into_datum()),
+                    (PgBuiltInOids::INT4OID.oid(), mode.flags().into_datum()),
+                ]),
+            )
+            .map_err(|err| LargeObjectError::from_query(err, oid))?
+            .first()
+            .get_one::<i32>()
+            .expect("lo_open returned no descriptor");
+        Ok(Self {
+            oid,
+            fd,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            opened_in: xact.id(),
+            transaction: unsafe { (*pg_sys::MyProc).lxid },
+            closed: false,
+        })
+    }
+
+    /// Create a large object in `xact` with the concatenation of `chunks` as its contents, returning its OID
+    pub fn import_from_bytes<Parent, const COMMIT: bool, I>(
+        xact: &mut SubTransaction<Parent, COMMIT>,
+        chunks: I,
+    ) -> Result<pg_sys::Oid, LargeObjectError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut object = Self::create(xact)?;
+        for chunk in chunks {
+            object.write(chunk.as_ref())?;
+        }
+        let oid = object.oid();
+        object.close()?;
+        Ok(oid)
+    }
+
+    /// Read and write at most `chunk_size` bytes per command
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size must be positive");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// OID of the large object
+    pub fn oid(&self) -> pg_sys::Oid {
+        self.oid
+    }
+
+    /// Write `data` at the current position, returning the number of bytes written
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, LargeObjectError> {
+        let mut written = 0;
+        for chunk in data.chunks(self.chunk_size) {
+            written += self
+                .call::<i32>(
+                    "SELECT lowrite($1, $2)",
+                    vec![(PgBuiltInOids::BYTEAOID.oid(), chunk.into_datum())],
+                )?
+                .unwrap_or_default() as usize;
+        }
+        Ok(written)
+    }
+
+    /// Read into `buf` from the current position, returning the number of bytes read, which is less than its length
+    /// only at the end of the large object
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, LargeObjectError> {
+        let mut read = 0;
+        for chunk in buf.chunks_mut(self.chunk_size) {
+            let data = self.read_chunk(chunk.len())?;
+            chunk[..data.len()].copy_from_slice(&data);
+            read += data.len();
+            if data.len() < chunk.len() {
+                break;
+            }
+        }
+        Ok(read)
+    }
+
+    /// Write the rest of the large object, from the current position, to `writer`, returning the number of bytes
+    /// written
+    pub fn export_to_writer<W: Write>(&mut self, writer: &mut W) -> Result<u64, LargeObjectError> {
+        let mut exported = 0;
+        loop {
+            let data = self.read_chunk(self.chunk_size)?;
+            if data.is_empty() {
+                return Ok(exported);
+            }
+            writer.write_all(&data).map_err(LargeObjectError::Io)?;
+            exported += data.len() as u64;
+        }
+    }
+
+    /// Move the current position, returning the new position from the start of the large object
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, LargeObjectError> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => (offset as i64, 0),
+            SeekFrom::Current(offset) => (offset, 1),
+            SeekFrom::End(offset) => (offset, 2),
+        };
+        let position = self.call::<i64>(
+            "SELECT lo_lseek64($1, $2, $3)",
+            vec![
+                (PgBuiltInOids::INT8OID.oid(), offset.into_datum()),
+                (PgBuiltInOids::INT4OID.oid(), whence.into_datum()),
+            ],
+        )?;
+        Ok(position.unwrap_or_default() as u64)
+    }
+
+    /// Current position, from the start of the large object
+    pub fn tell(&self) -> Result<u64, LargeObjectError> {
+        let position = self.call::<i64>("SELECT lo_tell64($1)", vec![])?;
+        Ok(position.unwrap_or_default() as u64)
+    }
+
+    /// Truncate (or extend with zeroes) the large object to `len` bytes, keeping the current position
+    pub fn truncate(&mut self, len: u64) -> Result<(), LargeObjectError> {
+        self.call::<i32>(
+            "SELECT lo_truncate64($1, $2)",
+            vec![(PgBuiltInOids::INT8OID.oid(), (len as i64).into_datum())],
+        )?;
+        Ok(())
+    }
+
+    /// Close the descriptor, returning an error if one occurred
+    pub fn close(mut self) -> Result<(), LargeObjectError> {
+        self.closed = true;
+        self.call::<i32>("SELECT lo_close($1)", vec![])?;
+        Ok(())
+    }
+
+    /// Close the descriptor and delete the large object
+    pub fn unlink(mut self) -> Result<(), LargeObjectError> {
+        self.closed = true;
+        self.call::<i32>("SELECT lo_close($1)", vec![])?;
+        (&mut SpiClient)
+            .checked_update(
+                "SELECT lo_unlink($1)",
+                None,
+                Some(vec![(PgBuiltInOids::OIDOID.oid(), self.oid.into_datum())]),
+            )
+            .map_err(|err| LargeObjectError::from_query(err, self.oid))?;
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, len: usize) -> Result<Vec<u8>, LargeObjectError> {
+        let data = self.call::<Vec<u8>>(
+            "SELECT loread($1, $2)",
+            vec![(PgBuiltInOids::INT4OID.oid(), (len as i32).into_datum())],
+        )?;
+        Ok(data.unwrap_or_default())
+    }
+
+    fn is_open(&self) -> bool {
+        unsafe {
+            (*pg_sys::MyProc).lxid == self.transaction
+                && pg_sys::SubTransactionIsActive(self.opened_in)
+        }
+    }
+
+    /// Call a function with the descriptor as its first argument, followed by `args`
+    fn call<T: FromDatum>(
+        &self,
+        query: &str,
+        args: Vec<(PgOid, Option<Datum>)>,
+    ) -> Result<Option<T>, LargeObjectError> {
+        if !self.is_open() {
+            return Err(LargeObjectError::Closed(self.oid));
+        }
+        let args = std::iter::once((PgBuiltInOids::INT4OID.oid(), self.fd.into_datum()))
+            .chain(args)
+            .collect();
+        Ok((&mut SpiClient)
+            .checked_update(query, None, Some(args))
+            .map_err(|err| LargeObjectError::from_query(err, self.oid))?
+            .first()
+            .get_one::<T>())
+    }
+}
+
+impl Drop for LargeObject {
+    fn drop(&mut self) {
+        if self.closed || std::thread::panicking() || !self.is_open() {
+            return;
+        }
+        if let Err(err) = self.call::<i32>("SELECT lo_close($1)", vec![]) {
+            pgx::warning!("failed to close large object {}: {}", self.oid, err);
+        }
+    }
+}
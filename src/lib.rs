@@ -5,11 +5,173 @@
 //! ```rust
 //! use pgx_contrib_spiext::prelude::*;
 //! ```
+//!
+//! For simple jobs, [`run_checked`] is the recommended entry point.
+//!
+//! Sub-transactions, checked commands and what they depend on (errors, budgets, deferred work, owned and typed rows,
+//! GUC guards, lock diagnostics and statement rewriting) are always compiled, with the same API; everything else is
+//! behind the `full` feature, enabled by default.
 
+use pgx::{Spi, SpiClient};
+use std::panic::AssertUnwindSafe;
+
+#[cfg(feature = "full")]
+pub mod args;
+#[cfg(feature = "full")]
+pub mod batch;
+#[cfg(feature = "full")]
+pub mod bgworker;
+pub mod budget;
+#[cfg(feature = "full")]
+pub mod call;
+#[cfg(feature = "full")]
+pub mod capabilities;
+#[cfg(feature = "full")]
+pub mod cas;
 pub mod checked;
+#[cfg(feature = "full")]
+pub mod config;
+#[cfg(feature = "full")]
+pub mod configured;
+#[cfg(feature = "full")]
+pub mod copy;
+#[cfg(feature = "full")]
+pub mod cursor;
+#[cfg(feature = "full")]
+pub mod ddl;
+pub mod deferred;
+#[cfg(feature = "full")]
+pub mod enums;
+pub mod error;
+#[cfg(feature = "full")]
+pub mod exact;
+#[cfg(feature = "testing")]
+pub mod faults;
+pub mod guc;
+#[cfg(feature = "full")]
+pub mod health;
+#[cfg(feature = "full")]
+pub mod images;
+#[cfg(feature = "full")]
+pub mod info;
+#[cfg(feature = "full")]
+pub mod introspect;
+#[cfg(feature = "full")]
+pub mod join;
+#[cfg(feature = "full")]
+pub mod keyspace;
+#[cfg(feature = "full")]
+pub mod largeobject;
+#[cfg(feature = "full")]
+pub mod limits;
+pub mod locks;
+#[cfg(feature = "full")]
+pub mod memo;
+#[cfg(feature = "full")]
+pub mod merge;
+pub mod metrics;
+#[cfg(feature = "full")]
+pub mod model;
+#[cfg(feature = "full")]
+pub mod names;
+#[cfg(feature = "full")]
+pub mod once;
+#[cfg(feature = "full")]
+pub mod outbox;
+pub mod owned;
+#[cfg(feature = "full")]
+pub mod partitions;
+#[cfg(all(feature = "json", feature = "full"))]
+pub mod plan_asserts;
+#[cfg(feature = "full")]
+pub mod purge;
+#[cfg(feature = "full")]
+pub mod quote;
+#[cfg(feature = "full")]
+pub mod reconcile;
+#[cfg(all(feature = "dblink", feature = "full"))]
+pub mod remote;
+#[cfg(feature = "full")]
+pub mod replication;
+#[cfg(feature = "full")]
+pub mod requirements;
+pub mod rewrite;
+#[cfg(feature = "full")]
+pub mod rls;
+pub mod row;
+#[cfg(feature = "full")]
+pub mod sample;
+// Only part of the scanner is used by the modules compiled without the `full` feature
+#[cfg_attr(not(feature = "full"), allow(dead_code))]
+mod scan;
+#[cfg(feature = "full")]
+pub mod script;
+#[cfg(feature = "full")]
+pub mod search_path;
+#[cfg(feature = "full")]
+pub mod session;
+#[cfg(feature = "full")]
+pub mod shadow;
+#[cfg(feature = "full")]
+pub mod singleton;
+#[cfg(feature = "full")]
+pub mod stream;
 pub mod subtxn;
+#[cfg(feature = "full")]
+pub mod temp_index;
+#[cfg(feature = "full")]
+pub mod throttle;
+#[cfg(feature = "full")]
+pub mod time;
+pub mod timeline;
+#[cfg(feature = "full")]
+pub mod triggers;
+#[cfg(feature = "full")]
+pub mod unlogged;
+#[cfg(feature = "full")]
+pub mod upsert;
+#[cfg(feature = "full")]
+pub mod validate;
+#[cfg(feature = "full")]
+pub mod verify;
 
 pub mod prelude {
     pub use crate::checked::*;
+    #[cfg(feature = "full")]
+    pub use crate::configured::*;
+    #[cfg(feature = "full")]
+    pub use crate::cursor::*;
+    pub use crate::deferred::*;
+    pub use crate::error::*;
+    pub use crate::row::*;
+    pub use crate::run;
+    #[cfg(feature = "full")]
+    pub use crate::run_checked;
+    #[cfg(feature = "full")]
+    pub use crate::session::*;
     pub use crate::subtxn::*;
 }
+
+/// Connect to SPI and run `f`
+pub fn run<R, F: FnOnce(&mut SpiClient) -> R>(f: F) -> R {
+    let mut result = None;
+    // Panics are propagated past `result` and `f`, so neither is observed in a broken state
+    let captured = AssertUnwindSafe((&mut result, f));
+    Spi::execute(move |mut client| {
+        let captured = captured;
+        let AssertUnwindSafe((result, f)) = captured;
+        *result = Some(f(&mut client));
+    });
+    result.unwrap()
+}
+
+/// Connect to SPI and run `f` with a [`CheckedSession`](session::CheckedSession)
+///
+/// All the work done through the session is committed if `f` returns `Ok` and rolled back if it returns `Err`,
+/// raises an error or panics.
+#[cfg(feature = "full")]
+pub fn run_checked<R, F: FnOnce(&mut session::CheckedSession) -> Result<R, error::Error>>(
+    f: F,
+) -> Result<R, error::Error> {
+    run(|_| session::CheckedSession::scoped(f))
+}
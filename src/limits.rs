@@ -0,0 +1,168 @@
+//! Resource limits scoped to a single command
+//!
+//! Limits are set with `set_config(name, value, true)` in the command's sub-transaction and set back to their previous
+//! values once it completes; if it fails, they are reverted along with the sub-transaction. Setting
+//! `temp_file_limit` requires superuser privileges (or, from Postgres 15, a `SET` privilege granted on it).
+
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{pg_sys, PgOid, SpiClient, SpiTupleTable};
+use std::fmt::{Display, Formatter};
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use crate::checked::*;
+use crate::error::{report, Error, PostgresErrorExt};
+use crate::guc::with_local;
+use crate::scan::is_empty_query;
+
+/// Units memory settings can be given in, as accepted by Postgres
+const MEMORY_UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+
+/// Limits applied to a command by [`LimitedCommands::checked_select_limited`], `None` leaving a setting as it is
+///
+/// Memory amounts are given as in `SET`, such as `4MB` (or a plain number, in the setting's default unit).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits<'a> {
+    pub work_mem: Option<&'a str>,
+    pub temp_file_limit: Option<&'a str>,
+    pub max_parallel_workers_per_gather: Option<i32>,
+}
+
+impl<'a> ResourceLimits<'a> {
+    /// Settings to apply, as (name, value), or the first invalid one
+    fn settings(&self) -> Result<Vec<(&'static str, String)>, LimitsError> {
+        let mut settings = vec![];
+        for (name, value) in [
+            ("work_mem", self.work_mem),
+            ("temp_file_limit", self.temp_file_limit),
+        ] {
+            if let Some(value) = value {
+                if !is_memory_amount(value) {
+                    return Err(LimitsError::InvalidLimit(name, value.to_string()));
+                }
+                settings.push((name, value.trim().to_string()));
+            }
+        }
+        if let Some(workers) = self.max_parallel_workers_per_gather {
+            if workers < 0 {
+                return Err(LimitsError::InvalidLimit(
+                    "max_parallel_workers_per_gather",
+                    workers.to_string(),
+                ));
+            }
+            settings.push(("max_parallel_workers_per_gather", workers.to_string()));
+        }
+        Ok(settings)
+    }
+}
+
+/// Whether `value` is a non-negative number, optionally followed by a memory unit
+///
+/// The server still checks that the amount is in the setting's range.
+fn is_memory_amount(value: &str) -> bool {
+    let value = value.trim();
+    let number_len = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(number_len);
+    number.parse::<f64>().is_ok()
+        && (unit.trim().is_empty() || MEMORY_UNITS.contains(&unit.trim_start()))
+}
+
+/// Limited command error
+#[derive(Debug)]
+pub enum LimitsError {
+    /// The value of the named limit is not valid, nothing was executed
+    InvalidLimit(&'static str, String),
+    /// The command's temporary files outgrew `temp_file_limit`
+    TempFileLimitExceeded(CaughtError),
+    /// The command failed otherwise
+    Query(Error),
+}
+
+impl Display for LimitsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitsError::InvalidLimit(name, value) => {
+                write!(f, "invalid value for \"{}\": \"{}\"", name, value)
+            }
+            LimitsError::TempFileLimitExceeded(err) => write!(f, "{}", report(err).message()),
+            LimitsError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for LimitsError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Caught(err, captured) => {
+                let report = report(&err);
+                // The SQLSTATE covers other limits as well, so the message is checked too (it may be translated, in
+                // which case the error is reported as is)
+                if report.sqlstate().as_str() == "53400"
+                    && report.message().contains("temp_file_limit")
+                {
+                    LimitsError::TempFileLimitExceeded(err)
+                } else {
+                    LimitsError::Query(Error::Caught(err, captured))
+                }
+            }
+            err => LimitsError::Query(err),
+        }
+    }
+}
+
+/// Checked commands under resource limits
+pub trait LimitedCommands {
+    /// Execute a read-only command under `limits`, returning an error if one occurred.
+    ///
+    /// The limits are validated before anything is executed, and only apply to this command.
+    fn checked_select_limited(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        limits: ResourceLimits,
+    ) -> Result<SpiTupleTable, LimitsError>;
+}
+
+impl LimitedCommands for SpiClient {
+    fn checked_select_limited(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        limits: ResourceLimits,
+    ) -> Result<SpiTupleTable, LimitsError> {
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery.into());
+        }
+        let settings = limits.settings()?;
+        let args = AssertUnwindSafe(args);
+        Ok(checked_sub_transaction(move |client| {
+            let args = args;
+            with_settings(client, &settings, |client| {
+                check_spi_refusal(query);
+                client.select(query, None, args.0)
+            })
+        })?)
+    }
+}
+
+/// Run `f` with `settings` set, as by [`with_local`]
+fn with_settings<R, F: FnOnce(&mut SpiClient) -> R>(
+    client: &mut SpiClient,
+    settings: &[(&'static str, String)],
+    f: F,
+) -> R {
+    match settings.split_first() {
+        Some(((name, value), rest)) => {
+            with_local(client, name, value, |client| with_settings(client, rest, f))
+        }
+        None => f(client),
+    }
+}
+
+/// Value of a timeout setting (such as `lock_timeout`) for `timeout`, rounded up to a millisecond
+pub(crate) fn timeout_setting(timeout: Duration) -> String {
+    // A timeout of zero would disable it
+    format!("{}ms", ((timeout.as_micros() + 999) / 1000).max(1))
+}
@@ -0,0 +1,174 @@
+//! Lock diagnostics
+//!
+//! Everything here only reads `pg_locks` and `pg_stat_activity`, so it takes no locks beyond what any query does.
+//! Row locks don't appear in `pg_locks` individually: a sub-transaction that locked rows holds a `RowShare` lock (or
+//! stronger) on their relation, along with a lock on its own transaction id.
+
+use pgx::{pg_sys, SpiClient};
+use std::str::FromStr;
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::rewrite;
+use crate::subtxn::SubTransaction;
+
+/// Lock mode, as in `pg_locks.mode`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockMode {
+    AccessShare,
+    RowShare,
+    RowExclusive,
+    ShareUpdateExclusive,
+    Share,
+    ShareRowExclusive,
+    Exclusive,
+    AccessExclusive,
+    /// Other modes, such as the `SIReadLock` predicate locks of serializable transactions
+    Other(String),
+}
+
+impl LockMode {
+    /// Whether the mode conflicts with itself, so two holders can't share it
+    pub fn is_self_exclusive(&self) -> bool {
+        matches!(
+            self,
+            LockMode::ShareUpdateExclusive
+                | LockMode::ShareRowExclusive
+                | LockMode::Exclusive
+                | LockMode::AccessExclusive
+        )
+    }
+}
+
+impl FromStr for LockMode {
+    type Err = std::convert::Infallible;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        Ok(match mode {
+            "AccessShareLock" => LockMode::AccessShare,
+            "RowShareLock" => LockMode::RowShare,
+            "RowExclusiveLock" => LockMode::RowExclusive,
+            "ShareUpdateExclusiveLock" => LockMode::ShareUpdateExclusive,
+            "ShareLock" => LockMode::Share,
+            "ShareRowExclusiveLock" => LockMode::ShareRowExclusive,
+            "ExclusiveLock" => LockMode::Exclusive,
+            "AccessExclusiveLock" => LockMode::AccessExclusive,
+            other => LockMode::Other(other.to_string()),
+        })
+    }
+}
+
+/// Lock held (or awaited) by this backend, as reported by [`SubTransaction::held_locks`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeldLock {
+    /// Kind of the locked object, as in `pg_locks.locktype` (`relation`, `transactionid`, `virtualxid`, ...)
+    pub lock_type: String,
+    /// Locked relation, if any
+    pub relation: Option<String>,
+    pub mode: LockMode,
+    pub granted: bool,
+    /// Locked transaction id, for `transactionid` locks
+    pub transaction_id: Option<pg_sys::TransactionId>,
+    /// Locked virtual transaction id, for `virtualxid` locks
+    pub virtual_xid: Option<String>,
+    /// Whether the locked transaction id is the sub-transaction's, or one of its committed children's
+    pub of_sub_transaction: bool,
+}
+
+/// Edge of [`wait_graph`]: `waiting` waits for a lock held (or requested first) by `blocking`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitEdge {
+    pub waiting: i32,
+    pub blocking: i32,
+}
+
+impl<Parent, const COMMIT: bool> SubTransaction<Parent, COMMIT> {
+    /// Transaction id assigned to this sub-transaction, if any
+    ///
+    /// Ids are assigned when a sub-transaction first writes or locks rows. `None` as well if a child of this
+    /// sub-transaction is open, as only the current sub-transaction's id can be read.
+    pub fn xid_if_assigned(&self) -> Option<pg_sys::TransactionId> {
+        if unsafe { pg_sys::GetCurrentSubTransactionId() } != self.id() {
+            return None;
+        }
+        let xid = unsafe { pg_sys::GetCurrentTransactionIdIfAny() };
+        (xid != pg_sys::InvalidTransactionId).then_some(xid)
+    }
+
+    /// Locks held or awaited by this backend, including those of the enclosing transaction
+    ///
+    /// Locks on transaction ids assigned to this sub-transaction or its committed children are marked with
+    /// [`HeldLock::of_sub_transaction`] (which requires it to be the current sub-transaction). The lock taken on
+    /// `pg_locks` by the query reading it is left out.
+    pub fn held_locks(&self) -> Result<Vec<HeldLock>, Error> {
+        let own_xids = self.own_xids();
+        let table = rewrite::exempt(|| {
+            self.select(
+                "SELECT locktype, relation::regclass::text, mode, granted, transactionid::text::bigint, virtualxid \
+                 FROM pg_locks \
+                 WHERE pid = pg_backend_pid() AND relation IS DISTINCT FROM 'pg_catalog.pg_locks'::regclass \
+                 ORDER BY locktype, relation, mode",
+                None,
+                None,
+            )
+        })?;
+        Ok(table
+            .map(|row| {
+                let transaction_id = row
+                    .by_ordinal(5)
+                    .unwrap()
+                    .value::<i64>()
+                    .map(|xid| xid as pg_sys::TransactionId);
+                HeldLock {
+                    lock_type: row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+                    relation: row.by_ordinal(2).unwrap().value(),
+                    mode: row
+                        .by_ordinal(3)
+                        .unwrap()
+                        .value::<String>()
+                        .unwrap_or_default()
+                        .parse()
+                        .unwrap(),
+                    granted: row.by_ordinal(4).unwrap().value().unwrap_or_default(),
+                    virtual_xid: row.by_ordinal(6).unwrap().value(),
+                    of_sub_transaction: transaction_id.map_or(false, |xid| own_xids.contains(&xid)),
+                    transaction_id,
+                }
+            })
+            .collect())
+    }
+
+    /// Transaction ids of this sub-transaction and its committed children, if it is the current one
+    fn own_xids(&self) -> Vec<pg_sys::TransactionId> {
+        let xid = match self.xid_if_assigned() {
+            Some(xid) => xid,
+            None => return vec![],
+        };
+        let mut children = std::ptr::null_mut();
+        let count = unsafe { pg_sys::xactGetCommittedChildren(&mut children) };
+        let mut xids = vec![xid];
+        if count > 0 {
+            xids.extend_from_slice(unsafe { std::slice::from_raw_parts(children, count as usize) });
+        }
+        xids
+    }
+}
+
+/// Lock waits this backend is involved in, waiting or blocking
+pub fn wait_graph(client: &SpiClient) -> Result<Vec<WaitEdge>, Error> {
+    let table = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT a.pid, b.pid FROM pg_stat_activity a, unnest(pg_blocking_pids(a.pid)) b(pid) \
+             WHERE a.pid = pg_backend_pid() OR b.pid = pg_backend_pid() \
+             ORDER BY 1, 2",
+            None,
+            None,
+        )
+    })?;
+    Ok(table
+        .map(|row| WaitEdge {
+            waiting: row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+            blocking: row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+        })
+        .collect())
+}
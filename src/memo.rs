@@ -0,0 +1,114 @@
+use pgx::{pg_guard, pg_sys, PgMemoryContexts, SpiClient};
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Once;
+
+use crate::error::Error;
+
+/// Incremented whenever a relevant catalog invalidation is observed
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+static REGISTER_CALLBACKS: Once = Once::new();
+
+/// Catalog caches whose invalidation invalidates memoized values
+const SYSCACHES: [pg_sys::SysCacheIdentifier; 5] = [
+    pg_sys::SysCacheIdentifier_RELOID,
+    pg_sys::SysCacheIdentifier_RELNAMENSP,
+    pg_sys::SysCacheIdentifier_PROCOID,
+    pg_sys::SysCacheIdentifier_TYPEOID,
+    pg_sys::SysCacheIdentifier_NAMESPACEOID,
+];
+
+#[pg_guard]
+unsafe extern "C" fn syscache_callback(_arg: pg_sys::Datum, _cacheid: i32, _hashvalue: u32) {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+#[pg_guard]
+unsafe extern "C" fn relcache_callback(_arg: pg_sys::Datum, _relid: pg_sys::Oid) {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+fn register_callbacks() {
+    REGISTER_CALLBACKS.call_once(|| unsafe {
+        for cache in SYSCACHES {
+            pg_sys::CacheRegisterSyscacheCallback(
+                cache as i32,
+                Some(syscache_callback),
+                pg_sys::Datum::from(0usize),
+            );
+        }
+        pg_sys::CacheRegisterRelcacheCallback(Some(relcache_callback), pg_sys::Datum::from(0usize));
+    });
+}
+
+type Compute<T> = Box<dyn Fn(&SpiClient) -> Result<T, Error>>;
+
+struct Entry<T> {
+    generation: u64,
+    value: Rc<T>,
+}
+
+/// Clears the memoized value when the top-level transaction's memory context goes away
+struct ClearOnTransactionEnd<T>(Weak<RefCell<Option<Entry<T>>>>);
+
+impl<T> Drop for ClearOnTransactionEnd<T> {
+    fn drop(&mut self) {
+        if let Some(cell) = self.0.upgrade() {
+            cell.borrow_mut().take();
+        }
+    }
+}
+
+/// Value derived from SQL, computed at most once per top-level transaction
+///
+/// The value is recomputed when a catalog invalidation (such as one caused by DDL) has been observed since it was
+/// computed. It is discarded when the top-level transaction ends, whether it commits or rolls back. Rolling back a
+/// sub-transaction doesn't discard it by itself, even if the sub-transaction's changes were used to compute it;
+/// only the catalog invalidations such a rollback causes do.
+pub struct TxnMemo<T> {
+    compute: Compute<T>,
+    cell: Rc<RefCell<Option<Entry<T>>>>,
+}
+
+impl<T: 'static> TxnMemo<T> {
+    pub fn new<F: Fn(&SpiClient) -> Result<T, Error> + 'static>(compute: F) -> Self {
+        register_callbacks();
+        Self {
+            compute: Box::new(compute),
+            cell: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// Get the value, computing it if necessary
+    ///
+    /// The value is shared rather than borrowed, as it may be discarded by a later call or at the end of the
+    /// transaction.
+    pub fn get(&self, client: &SpiClient) -> Result<Rc<T>, Error> {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if let Some(entry) = &*self.cell.borrow() {
+            if entry.generation == generation {
+                return Ok(entry.value.clone());
+            }
+        }
+        let was_computed = self.cell.borrow_mut().take().is_some();
+        let value = Rc::new((self.compute)(client)?);
+        *self.cell.borrow_mut() = Some(Entry {
+            // Invalidations caused while computing the value (e.g. by creating temporary objects) are
+            // already reflected in it
+            generation: GENERATION.load(Ordering::Relaxed),
+            value: value.clone(),
+        });
+        if !was_computed {
+            PgMemoryContexts::TopTransactionContext
+                .leak_and_drop_on_delete(ClearOnTransactionEnd(Rc::downgrade(&self.cell)));
+        }
+        Ok(value)
+    }
+
+    /// Discard the value, so that it is recomputed by the next call to [`TxnMemo::get`]
+    pub fn invalidate(&self) {
+        self.cell.borrow_mut().take();
+    }
+}
@@ -0,0 +1,301 @@
+//! Merging cursors whose rows are sorted by a key
+//!
+//! For ordered results that can't be merged in SQL, such as those of queries that have to run separately. Both cursors
+//! are fetched from in turn, a batch at a time, so at most one batch of each is held in memory.
+
+use pgx::PgOid;
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::ops::ControlFlow;
+
+use crate::cursor::{CheckedCursor, SelectOutcome};
+use crate::error::Error;
+pub use crate::join::Side;
+use crate::join::{KeyKind, KeyValue};
+use crate::owned::{OwnedRow, OwnedRows};
+
+/// Number of rows fetched at a time from each cursor by [`sorted`]
+const MERGE_BATCH: i64 = 1000;
+
+/// Order the rows of both cursors are sorted in by their key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// As by `ORDER BY key`, with NULL keys last
+    Ascending,
+    /// As by `ORDER BY key DESC`, with NULL keys first
+    Descending,
+}
+
+impl SortOrder {
+    fn compare(self, a: &Option<KeyValue>, b: &Option<KeyValue>) -> Ordering {
+        // NULL sorts after every value, as in Postgres
+        let ordering = match (a, b) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(a), Some(b)) => a.cmp(b),
+        };
+        match self {
+            SortOrder::Ascending => ordering,
+            SortOrder::Descending => ordering.reverse(),
+        }
+    }
+}
+
+/// Merge error
+#[derive(Debug)]
+pub enum MergeError {
+    /// There is no key column with this name
+    NoSuchColumn(Side, String),
+    /// Values of the key column's type can't be compared
+    UnsupportedKeyType {
+        side: Side,
+        column: String,
+        type_oid: PgOid,
+    },
+    /// Values of the key columns can't be compared with each other
+    IncompatibleKeyTypes { left: PgOid, right: PgOid },
+    /// The row with this (1-based) number of one side is out of order, its key sorting before the previous row's
+    InputNotSorted { side: Side, row: u64 },
+    /// A fetch failed
+    Query(Error),
+}
+
+impl Display for MergeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeError::NoSuchColumn(side, column) => {
+                write!(f, "no column named \"{}\" on the {:?} side", column, side)
+            }
+            MergeError::UnsupportedKeyType {
+                side,
+                column,
+                type_oid,
+            } => write!(
+                f,
+                "key column \"{}\" on the {:?} side has unsupported type with OID {}",
+                column,
+                side,
+                type_oid.value()
+            ),
+            MergeError::IncompatibleKeyTypes { left, right } => write!(
+                f,
+                "key columns have incompatible types with OIDs {} and {}",
+                left.value(),
+                right.value()
+            ),
+            MergeError::InputNotSorted { side, row } => {
+                write!(f, "row {} on the {:?} side is out of order", row, side)
+            }
+            MergeError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for MergeError {
+    fn from(err: Error) -> Self {
+        MergeError::Query(err)
+    }
+}
+
+/// Row passed to the sink of [`sorted`]
+#[derive(Clone, Copy)]
+pub struct MergedRow<'a> {
+    /// Cursor the row was fetched from
+    pub side: Side,
+    pub row: OwnedRow<'a>,
+}
+
+/// Summary of [`sorted`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeStats {
+    /// Rows of the left cursor passed to the sink
+    pub left_rows: u64,
+    /// Rows of the right cursor passed to the sink
+    pub right_rows: u64,
+    /// Batches fetched from both cursors
+    pub batches: u64,
+    /// Whether all rows were merged or the sink requested to stop
+    pub outcome: SelectOutcome,
+}
+
+/// One of the cursors, with its current batch
+struct Input<'a> {
+    side: Side,
+    column: &'a str,
+    /// `None` once all rows were fetched
+    cursor: Option<CheckedCursor>,
+    rows: OwnedRows,
+    keys: Vec<Option<KeyValue>>,
+    /// Index of the next row of the batch to merge
+    next: usize,
+    /// Rows fetched before the current batch
+    before: u64,
+    /// Kind and (1-based) ordinal of the key column, resolved from the first batch
+    key: Option<(KeyKind, usize)>,
+}
+
+impl<'a> Input<'a> {
+    fn new(side: Side, column: &'a str, cursor: CheckedCursor) -> Self {
+        Self {
+            side,
+            column,
+            cursor: Some(cursor),
+            rows: OwnedRows::default(),
+            keys: vec![],
+            next: 0,
+            before: 0,
+            key: None,
+        }
+    }
+
+    /// Fetch the next batch if the current one was merged
+    fn fill(&mut self, order: SortOrder, batches: &mut u64) -> Result<(), MergeError> {
+        if self.next == self.rows.len() {
+            self.fetch(order, batches)?;
+        }
+        Ok(())
+    }
+
+    /// Key of the next row to merge, `None` once all rows were merged
+    fn head(&self) -> Option<&Option<KeyValue>> {
+        self.keys.get(self.next)
+    }
+
+    fn fetch(&mut self, order: SortOrder, batches: &mut u64) -> Result<(), MergeError> {
+        let cursor = match self.cursor.as_mut() {
+            Some(cursor) => cursor,
+            None => return Ok(()),
+        };
+        let rows = cursor.fetch_with(MERGE_BATCH, OwnedRows::from_table)?;
+        *batches += 1;
+        if (rows.len() as i64) < MERGE_BATCH {
+            self.cursor.take().unwrap().close();
+        }
+        let (kind, ordinal) = match self.key {
+            Some(key) => key,
+            None => {
+                let key = self.resolve(&rows)?;
+                self.key = Some(key);
+                key
+            }
+        };
+        let keys: Vec<_> = rows.iter().map(|row| kind.value(&row, ordinal)).collect();
+        // The first key of the batch is checked against the last one of the previous batch
+        let mut previous = self.keys.last();
+        for (index, key) in keys.iter().enumerate() {
+            if let Some(previous) = previous {
+                if order.compare(previous, key) == Ordering::Greater {
+                    return Err(MergeError::InputNotSorted {
+                        side: self.side,
+                        row: self.before + self.rows.len() as u64 + index as u64 + 1,
+                    });
+                }
+            }
+            previous = Some(key);
+        }
+        self.before += self.rows.len() as u64;
+        self.rows = rows;
+        self.keys = keys;
+        self.next = 0;
+        Ok(())
+    }
+
+    fn resolve(&self, rows: &OwnedRows) -> Result<(KeyKind, usize), MergeError> {
+        let (index, column) = rows
+            .columns()
+            .iter()
+            .enumerate()
+            .find(|(_, column)| column.name == self.column)
+            .ok_or_else(|| MergeError::NoSuchColumn(self.side, self.column.to_string()))?;
+        let kind = KeyKind::of(column.type_oid).ok_or_else(|| MergeError::UnsupportedKeyType {
+            side: self.side,
+            column: self.column.to_string(),
+            type_oid: column.type_oid,
+        })?;
+        Ok((kind, index + 1))
+    }
+
+    fn type_oid(&self) -> Option<PgOid> {
+        let (_, ordinal) = self.key?;
+        Some(self.rows.columns()[ordinal - 1].type_oid)
+    }
+
+    fn close(&mut self) {
+        if let Some(cursor) = self.cursor.take() {
+            cursor.close();
+        }
+    }
+}
+
+/// Merge the rows of two cursors sorted by a key column, passing them to `sink` in the same `order`
+///
+/// `key_columns` names the key column of the left and the right cursor. Key columns may be of the types
+/// [`hash_join`](crate::join::hash_join) supports (integers, which compare with each other, text, UUIDs, dates and
+/// timestamps), and are compared by value: `text` byte by byte, as with `COLLATE "C"`. Rows with equal keys are passed
+/// left ones first, each side's in the order they were fetched.
+///
+/// Each cursor is fetched from (a batch at a time) only once its previous batch was merged, and every batch is checked
+/// to be sorted before any of its rows is passed to `sink`: a row whose key sorts before the previous row's of the same
+/// cursor fails the merge with [`MergeError::InputNotSorted`] rather than producing out-of-order rows. Returning
+/// `ControlFlow::Break` from `sink` stops the merge. Both cursors are closed when the merge completes, stops or fails.
+pub fn sorted<F: FnMut(MergedRow) -> ControlFlow<()>>(
+    left: CheckedCursor,
+    right: CheckedCursor,
+    key_columns: (&str, &str),
+    order: SortOrder,
+    mut sink: F,
+) -> Result<MergeStats, MergeError> {
+    let mut left = Input::new(Side::Left, key_columns.0, left);
+    let mut right = Input::new(Side::Right, key_columns.1, right);
+    let mut stats = MergeStats {
+        left_rows: 0,
+        right_rows: 0,
+        batches: 0,
+        outcome: SelectOutcome::Completed,
+    };
+    let mut checked_types = false;
+    loop {
+        left.fill(order, &mut stats.batches)?;
+        right.fill(order, &mut stats.batches)?;
+        if !checked_types {
+            // Both key columns are resolved by the first fetch from each cursor
+            if let (Some((left_kind, _)), Some((right_kind, _))) = (left.key, right.key) {
+                if !left_kind.is_compatible(right_kind) {
+                    return Err(MergeError::IncompatibleKeyTypes {
+                        left: left.type_oid().unwrap(),
+                        right: right.type_oid().unwrap(),
+                    });
+                }
+                checked_types = true;
+            }
+        }
+        let side = match (left.head(), right.head()) {
+            (None, None) => break,
+            (Some(_), None) => Side::Left,
+            (None, Some(_)) => Side::Right,
+            (Some(left_key), Some(right_key)) => match order.compare(left_key, right_key) {
+                Ordering::Greater => Side::Right,
+                _ => Side::Left,
+            },
+        };
+        let (input, count) = match side {
+            Side::Left => (&mut left, &mut stats.left_rows),
+            Side::Right => (&mut right, &mut stats.right_rows),
+        };
+        let index = input.next;
+        input.next += 1;
+        *count += 1;
+        let row = MergedRow {
+            side,
+            row: input.rows.row(index).unwrap(),
+        };
+        if sink(row).is_break() {
+            stats.outcome = SelectOutcome::Cancelled;
+            break;
+        }
+    }
+    left.close();
+    right.close();
+    Ok(stats)
+}
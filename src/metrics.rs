@@ -0,0 +1,131 @@
+//! Counters of what this crate does in the backend, for exporting to monitoring
+//!
+//! Checked commands (`checked_select` and `checked_update`, whichever client or sub-transaction executes them),
+//! sub-transactions begun by this crate and the plan cache (see
+//! [`enable_plan_cache`](crate::config::enable_plan_cache)) update the counters as they go. [`srf_rows`] lists them,
+//! for a set-returning function such as:
+//!
+//! ```ignore
+//! #[pg_extern]
+//! fn my_metrics() -> TableIterator<'static, (name!(name, String), name!(value, i64))> {
+//!     TableIterator::new(metrics::srf_rows().map(|row| (row.name.to_string(), row.value)))
+//! }
+//! ```
+//!
+//! The counters are those of the backend since it started or since [`reset`], as backends are single-threaded: each
+//! is a thread-local `Cell`, so updating one is a thread-local access and an addition, without locks, atomics or
+//! allocations. Timing checked commands reads the monotonic clock twice per command, which is negligible next to the
+//! sub-transaction it executes in.
+
+use std::cell::Cell;
+use std::time::Instant;
+
+use crate::error::{Error, SeverityClass};
+
+/// A counter, listed by [`srf_rows`] under its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Metric {
+    CheckedSelects,
+    CheckedUpdates,
+    CheckedMicros,
+    TransientErrors,
+    ResourceExhaustionErrors,
+    PermanentErrors,
+    SubTransactionsBegun,
+    SubTransactionsCommitted,
+    SubTransactionsRolledBack,
+    PeakSubTransactionDepth,
+    PlanCacheHits,
+    PlanCacheMisses,
+}
+
+const COUNT: usize = Metric::PlanCacheMisses as usize + 1;
+
+/// Names of the counters, in the order of [`Metric`]
+const NAMES: [&str; COUNT] = [
+    "checked_select_calls",
+    "checked_update_calls",
+    "checked_execution_us",
+    "errors_transient",
+    "errors_resource_exhaustion",
+    "errors_permanent",
+    "subtransactions_begun",
+    "subtransactions_committed",
+    "subtransactions_rolled_back",
+    "subtransaction_peak_depth",
+    "plan_cache_hits",
+    "plan_cache_misses",
+];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: Cell<u64> = Cell::new(0);
+
+thread_local! {
+    static COUNTERS: [Cell<u64>; COUNT] = const { [ZERO; COUNT] };
+}
+
+/// Value of a counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricRow {
+    pub name: &'static str,
+    pub value: i64,
+}
+
+/// Every counter, always in the same order:
+///
+/// - `checked_select_calls` and `checked_update_calls`: checked commands executed, whether they failed or not;
+/// - `checked_execution_us`: microseconds spent executing them;
+/// - `errors_transient`, `errors_resource_exhaustion` and `errors_permanent`: errors they caught, by
+///   [`SeverityClass`];
+/// - `subtransactions_begun`, `subtransactions_committed` and `subtransactions_rolled_back`: sub-transactions this
+///   crate began and ended (those Postgres ends along with the top-level transaction are counted as neither);
+/// - `subtransaction_peak_depth`: most sub-transactions begun by this crate that were open at once;
+/// - `plan_cache_hits` and `plan_cache_misses`: executions that reused a cached plan, and that prepared one, while the
+///   plan cache is enabled.
+pub fn srf_rows() -> impl Iterator<Item = MetricRow> {
+    let values = COUNTERS.with(|counters| counters.clone());
+    NAMES
+        .into_iter()
+        .zip(values)
+        .map(|(name, value)| MetricRow {
+            name,
+            value: value.get() as i64,
+        })
+}
+
+/// Set every counter back to zero
+pub fn reset() {
+    COUNTERS.with(|counters| counters.iter().for_each(|counter| counter.set(0)));
+}
+
+/// Add `n` to a counter
+pub(crate) fn add(metric: Metric, n: u64) {
+    COUNTERS.with(|counters| {
+        let counter = &counters[metric as usize];
+        counter.set(counter.get().saturating_add(n));
+    });
+}
+
+/// Raise a counter to `value` if it's lower
+pub(crate) fn raise(metric: Metric, value: u64) {
+    COUNTERS.with(|counters| {
+        let counter = &counters[metric as usize];
+        counter.set(counter.get().max(value));
+    });
+}
+
+/// Count a checked command (`metric`) begun at `started`, along with its error
+pub(crate) fn record_checked<T>(metric: Metric, started: Instant, result: &Result<T, Error>) {
+    add(metric, 1);
+    add(Metric::CheckedMicros, started.elapsed().as_micros() as u64);
+    if let Some(class) = result.as_ref().err().and_then(Error::severity_class) {
+        add(
+            match class {
+                SeverityClass::Transient => Metric::TransientErrors,
+                SeverityClass::ResourceExhaustion => Metric::ResourceExhaustionErrors,
+                SeverityClass::Permanent => Metric::PermanentErrors,
+            },
+            1,
+        );
+    }
+}
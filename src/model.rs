@@ -0,0 +1,76 @@
+//! Loading Rust-side models from several queries under one snapshot
+//!
+//! A model lists the queries it is loaded from with [`LoadModel::queries`], each filling a named slot, and is built
+//! from their rows by [`LoadModel::build`]. [`checked_load_model`] executes the queries with
+//! [`NoCciCommands::checked_select_no_cci`], in SPI's read-only mode. Rather than each taking a new snapshot, as
+//! checked commands do (which under `READ COMMITTED` lets each see changes committed concurrently before it), they
+//! all use the active snapshot, which is the calling statement's. They therefore see the same state of every table
+//! under any isolation level, but not the changes made by the calling function itself. Read-only mode also rejects
+//! queries that make changes, so none are made in between.
+
+use pgx::SpiClient;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::checked::NoCciCommands;
+use crate::error::Error;
+use crate::owned::OwnedRows;
+use crate::subtxn::SubTransactionExt;
+
+/// Model loaded by [`checked_load_model`]
+pub trait LoadModel: Sized {
+    /// Slot names and the queries filling them, in the order they are executed
+    ///
+    /// Slot names should be unique, as the rows of a later query replace those of an earlier one with the same name.
+    fn queries() -> Vec<(&'static str, &'static str)>;
+
+    /// Build the model from the rows of every slot, or describe why they don't make a valid one
+    fn build(results: HashMap<&'static str, OwnedRows>) -> Result<Self, String>;
+}
+
+/// Model loading error
+#[derive(Debug)]
+pub enum LoadError {
+    /// The query of the named slot failed
+    Slot { slot: &'static str, error: Error },
+    /// [`LoadModel::build`] rejected the rows
+    Build(String),
+    /// Loading failed otherwise, such as because no sub-transaction could be started
+    Query(Error),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Slot { slot, error } => {
+                write!(f, "query of slot \"{}\" failed: {}", slot, error)
+            }
+            LoadError::Build(message) => write!(f, "invalid model: {}", message),
+            LoadError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for LoadError {
+    fn from(err: Error) -> Self {
+        LoadError::Query(err)
+    }
+}
+
+/// Load a model, executing all its queries in a sub-transaction that is rolled back afterwards
+///
+/// Rows are copied out with [`OwnedRows`] before it is rolled back. The first query that fails stops the loading.
+pub fn checked_load_model<M: LoadModel>(client: &SpiClient) -> Result<M, LoadError> {
+    let results = SpiClient.try_sub_transaction(|xact| -> Result<_, LoadError> {
+        let _xact = xact.rollback_on_drop();
+        let mut results = HashMap::new();
+        for (slot, query) in M::queries() {
+            let rows = client
+                .checked_select_no_cci(query, None, None)
+                .map_err(|error| LoadError::Slot { slot, error })?;
+            results.insert(slot, rows);
+        }
+        Ok(results)
+    })??;
+    M::build(results).map_err(LoadError::Build)
+}
@@ -0,0 +1,193 @@
+//! Generated names of transient objects, unique across backends and transactions
+//!
+//! A name generated by a [`NameGenerator`] is made of a prefix, the kind of object and a suffix that makes it unique:
+//!
+//! ```text
+//! <prefix>_<kind>_<pid>_<transaction>_<sub-transaction>_<sequence>_<check>
+//! ```
+//!
+//! where the numbers are in base 36. The transaction is the top-level transaction id (`x` followed by it), or, if none
+//! is assigned, a per-backend epoch (`e` followed by it) that starts from the backend's start time and advances with
+//! each such transaction, so that backends reusing the pid of one that exited don't reuse its names. The sequence
+//! increases with every name generated by the backend, and the check is a hash of the rest of the name, which
+//! [`was_generated_by_us`] verifies so that cleanup never mistakes another object for a generated one.
+
+use pgx::pg_sys;
+use std::cell::Cell;
+
+use crate::subtxn::SubTransaction;
+use crate::validate::{self, ValidIdent, MAX_IDENTIFIER_BYTES};
+
+/// Prefix of generated names, unless set with [`NameGenerator::with_prefix`]
+pub const DEFAULT_PREFIX: &str = "spiext";
+
+thread_local! {
+    static NEXT_SEQUENCE: Cell<u64> = const { Cell::new(0) };
+    /// Epoch of the last transaction without a transaction id that generated names, along with its local id
+    static EPOCH: Cell<Option<(pg_sys::LocalTransactionId, u64)>> = const { Cell::new(None) };
+}
+
+/// Part of the suffix telling the transaction apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transaction {
+    Id(pg_sys::TransactionId),
+    Epoch(u64),
+}
+
+impl Transaction {
+    fn current() -> Self {
+        let xid = unsafe { pg_sys::GetTopTransactionIdIfAny() };
+        if xid != pg_sys::InvalidTransactionId {
+            return Transaction::Id(xid);
+        }
+        let lxid = unsafe { (*pg_sys::MyProc).lxid };
+        let epoch = EPOCH.with(|epoch| match epoch.get() {
+            Some((transaction, current)) if transaction == lxid => current,
+            last => {
+                let start = (unsafe { pg_sys::MyStartTime } as u64) << 24;
+                let next = last.map_or(start, |(_, last)| (last + 1).max(start));
+                epoch.set(Some((lxid, next)));
+                next
+            }
+        });
+        Transaction::Epoch(epoch)
+    }
+}
+
+/// Generator of names of transient objects created in a sub-transaction
+#[derive(Debug, Clone)]
+pub struct NameGenerator {
+    prefix: String,
+    pid: i32,
+    transaction: Transaction,
+    subtxn: pg_sys::SubTransactionId,
+}
+
+impl NameGenerator {
+    /// Generator of names of objects created in `xact`, prefixed with [`DEFAULT_PREFIX`]
+    pub fn for_subtxn<Parent, const COMMIT: bool>(xact: &SubTransaction<Parent, COMMIT>) -> Self {
+        Self {
+            prefix: DEFAULT_PREFIX.to_string(),
+            pid: unsafe { pg_sys::MyProcPid },
+            transaction: Transaction::current(),
+            subtxn: xact.id(),
+        }
+    }
+
+    /// Prefix the names with `prefix` instead
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Generate a name for an object of the given kind, such as `idx`
+    ///
+    /// The name is at most [`MAX_IDENTIFIER_BYTES`] long, so Postgres uses it as it is: if it would be longer, the
+    /// prefix is truncated, then the kind, but never the suffix. Panics if the prefix or the kind contains a NUL byte.
+    pub fn reserve(&mut self, kind: &str) -> ValidIdent {
+        let sequence = NEXT_SEQUENCE.with(|next| next.replace(next.get() + 1));
+        let transaction = match self.transaction {
+            Transaction::Id(xid) => format!("x{}", base36(xid as u64)),
+            Transaction::Epoch(epoch) => format!("e{}", base36(epoch)),
+        };
+        let suffix = format!(
+            "_{}_{}_{}_{}",
+            base36(self.pid as u64),
+            transaction,
+            base36(self.subtxn as u64),
+            base36(sequence)
+        );
+        // The suffix along with its check fits with room to spare
+        let budget = MAX_IDENTIFIER_BYTES - suffix.len() - CHECK_LEN - 1;
+        let kind = truncate(kind, budget - 1);
+        let prefix = truncate(&self.prefix, budget - 1 - kind.len());
+        let name = format!("{}_{}{}", prefix, kind, suffix);
+        let name = format!(
+            "{}_{:0>width$}",
+            name,
+            base36(check(&name)),
+            width = CHECK_LEN
+        );
+        validate::identifier(&name).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+/// Whether `name` was generated by a [`NameGenerator`], in any backend
+///
+/// The suffix of the name must be well-formed and its check must match the rest of the name.
+pub fn was_generated_by_us(name: &str) -> bool {
+    let (rest, check_part) = match name.rsplit_once('_') {
+        Some(split) => split,
+        None => return false,
+    };
+    let mut parts = rest.rsplitn(5, '_');
+    let (sequence, subtxn, transaction, pid) = match (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) {
+        (Some(sequence), Some(subtxn), Some(transaction), Some(pid), Some(_)) => {
+            (sequence, subtxn, transaction, pid)
+        }
+        _ => return false,
+    };
+    let transaction = match transaction
+        .strip_prefix('x')
+        .or_else(|| transaction.strip_prefix('e'))
+    {
+        Some(transaction) => transaction,
+        None => return false,
+    };
+    check_part.len() == CHECK_LEN
+        && [check_part, sequence, subtxn, transaction, pid]
+            .into_iter()
+            .all(is_base36)
+        && u64::from_str_radix(check_part, 36) == Ok(check(rest))
+}
+
+/// Whether `s` is a number as formatted by [`base36`], at most 64 bits long
+fn is_base36(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 13
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || b.is_ascii_lowercase())
+}
+
+/// Length of the check, in base 36 digits (zero-padded)
+const CHECK_LEN: usize = 7;
+
+/// 32-bit FNV-1a hash of the name, seeded so that other FNV-1a hashes don't match it
+fn check(name: &str) -> u64 {
+    "spiext-names:"
+        .bytes()
+        .chain(name.bytes())
+        .fold(0x811c_9dc5u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        }) as u64
+}
+
+fn base36(mut n: u64) -> String {
+    let mut digits = vec![];
+    loop {
+        digits.push(std::char::from_digit((n % 36) as u32, 36).unwrap());
+        n /= 36;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+/// Longest prefix of `s` at most `len` bytes long, on a character boundary
+fn truncate(s: &str, len: usize) -> &str {
+    if s.len() <= len {
+        return s;
+    }
+    let mut end = len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
@@ -0,0 +1,167 @@
+//! One-time initialization per backend through checked commands
+//!
+//! [`per_backend`] runs an initialization function (creating temporary objects, loading configuration into Rust
+//! statics, ...) the first time it is called for a key in this backend, whichever of the extension's entry points calls
+//! it first. The registry of keys is backend-local, and is only transactional in one way: a successful initialization
+//! is forgotten if the transaction or sub-transaction it ran in is rolled back, along with what it did through SQL, so
+//! that it runs again.
+
+use pgx::{pg_guard, pg_sys, PgXactCallbackEvent, SpiClient};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::sync::Once;
+
+use crate::error::Error;
+use crate::subtxn::*;
+
+/// Initialization error
+#[derive(Debug)]
+pub enum InitError {
+    /// The initialization failed with this error
+    Failed(Rc<Error>),
+    /// The initialization failed with this error in an earlier call, and wasn't run again (see [`retry_failed`])
+    PreviouslyFailed(Rc<Error>),
+    /// [`per_backend`] was called for this key by its own initialization
+    Reentrant(&'static str),
+}
+
+impl Display for InitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::Failed(err) => write!(f, "initialization failed: {}", err),
+            InitError::PreviouslyFailed(err) => {
+                write!(f, "initialization failed previously: {}", err)
+            }
+            InitError::Reentrant(key) => {
+                write!(f, "initialization of \"{}\" is already running", key)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+enum State {
+    Running,
+    /// Done in this sub-transaction (or in one that committed into it), which undoes it if rolled back
+    Done(pg_sys::SubTransactionId),
+    Failed(Rc<Error>),
+}
+
+thread_local! {
+    static REGISTRY: RefCell<HashMap<&'static str, State>> = RefCell::new(HashMap::new());
+}
+
+static REGISTER_CALLBACK: Once = Once::new();
+
+/// Forgets initializations done in a sub-transaction that is rolled back, and hands them over to the parent of one that
+/// commits
+#[pg_guard]
+unsafe extern "C" fn subxact_callback(
+    event: pg_sys::SubXactEvent,
+    subid: pg_sys::SubTransactionId,
+    parent: pg_sys::SubTransactionId,
+    _arg: *mut c_void,
+) {
+    let committed = match event {
+        pg_sys::SubXactEvent_SUBXACT_EVENT_COMMIT_SUB => true,
+        pg_sys::SubXactEvent_SUBXACT_EVENT_ABORT_SUB => false,
+        _ => return,
+    };
+    REGISTRY.with(|registry| {
+        // The registry is never borrowed across calls into Postgres, but a callback must not panic if it is
+        if let Ok(mut registry) = registry.try_borrow_mut() {
+            registry.retain(|_, state| match state {
+                State::Done(owner) if *owner == subid => {
+                    *owner = parent;
+                    committed
+                }
+                _ => true,
+            })
+        }
+    });
+}
+
+fn register_callback() {
+    REGISTER_CALLBACK.call_once(|| unsafe {
+        pg_sys::RegisterSubXactCallback(Some(subxact_callback), std::ptr::null_mut());
+    });
+}
+
+fn set_state(key: &'static str, state: Option<State>) {
+    REGISTRY.with(|registry| match state {
+        Some(state) => registry.borrow_mut().insert(key, state),
+        None => registry.borrow_mut().remove(key),
+    });
+}
+
+/// Run `init` in a sub-transaction unless it already ran successfully for `key` in this backend
+///
+/// The sub-transaction is committed if `init` returns `Ok`, and rolled back if it returns `Err` or an error is raised
+/// (panics are caught and reported like errors). A failure is remembered: later calls for `key` return
+/// [`InitError::PreviouslyFailed`] with its error, without running anything, until [`retry_failed`] is called. A call
+/// for `key` made by `init` itself returns [`InitError::Reentrant`]. A successful initialization runs again if the
+/// transaction or sub-transaction `per_backend` was called in is rolled back.
+pub fn per_backend<F>(key: &'static str, init: F) -> Result<(), InitError>
+where
+    F: FnOnce(&mut SubTransaction<SpiClientWrapper, false>) -> Result<(), Error>,
+{
+    match REGISTRY.with(|registry| registry.borrow().get(key).cloned()) {
+        Some(State::Done(_)) => return Ok(()),
+        Some(State::Running) => return Err(InitError::Reentrant(key)),
+        Some(State::Failed(err)) => return Err(InitError::PreviouslyFailed(err)),
+        None => {}
+    }
+    register_callback();
+    let owner = unsafe { pg_sys::GetCurrentSubTransactionId() };
+    // Not being able to begin a sub-transaction is not a failure of the initialization
+    let xact = SpiClient
+        .try_sub_transaction(|xact| xact.rollback_on_drop())
+        .map_err(|err| InitError::Failed(Rc::new(err)))?;
+    set_state(key, Some(State::Running));
+    match run(init, xact) {
+        Ok(()) => {
+            set_state(key, Some(State::Done(owner)));
+            pgx::register_xact_callback(PgXactCallbackEvent::Abort, move || {
+                REGISTRY.with(|registry| {
+                    let mut registry = registry.borrow_mut();
+                    if matches!(registry.get(key), Some(State::Done(_))) {
+                        registry.remove(key);
+                    }
+                })
+            });
+            Ok(())
+        }
+        Err(err) => {
+            let err = Rc::new(err);
+            set_state(key, Some(State::Failed(err.clone())));
+            Err(InitError::Failed(err))
+        }
+    }
+}
+
+/// Forget that the initialization for `key` failed, so that the next [`per_backend`] call runs it again, returning
+/// whether it had failed
+pub fn retry_failed(key: &'static str) -> bool {
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        let failed = matches!(registry.get(key), Some(State::Failed(_)));
+        if failed {
+            registry.remove(key);
+        }
+        failed
+    })
+}
+
+fn run<F>(init: F, xact: SubTransaction<SpiClientWrapper, false>) -> Result<(), Error>
+where
+    F: FnOnce(&mut SubTransaction<SpiClientWrapper, false>) -> Result<(), Error>,
+{
+    let (result, xact) = shield(xact, true, assert_shield_safe(init))?;
+    // Dropping the sub-transaction rolls it back
+    result?;
+    xact.commit();
+    Ok(())
+}
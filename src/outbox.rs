@@ -0,0 +1,227 @@
+//! Transactional outbox: messages enqueued in the same sub-transaction as the work they are about
+//!
+//! A message is a row of the outbox table, so it is discarded along with the work if the sub-transaction it was
+//! enqueued in is rolled back, and consumers only see it once the enclosing transaction commits. The table name is
+//! possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier). Topics and payloads are always passed as
+//! parameters.
+
+#[cfg(feature = "json")]
+use pgx::Json;
+use pgx::{pg_sys, IntoDatum, PgBuiltInOids, SpiClient, TimestampWithTimeZone};
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::{Error, SqlState};
+use crate::quote::*;
+use crate::subtxn::*;
+
+/// Outbox error
+#[derive(Debug)]
+pub enum OutboxError {
+    /// The payload is not valid JSON, as reported by the server's JSON parser
+    InvalidPayload(Error),
+    /// A command failed
+    Query(Error),
+}
+
+impl Display for OutboxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboxError::InvalidPayload(err) => write!(f, "invalid outbox payload: {}", err),
+            OutboxError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for OutboxError {
+    fn from(err: Error) -> Self {
+        OutboxError::Query(err)
+    }
+}
+
+/// Message claimed from an outbox with [`Outbox::claim`]
+#[derive(Debug, Clone)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub created_at: TimestampWithTimeZone,
+    pub topic: String,
+    /// Payload as JSON text (normalized by `jsonb`, so whitespace and key order may differ from the enqueued one)
+    pub payload: String,
+}
+
+/// Outbox table
+///
+/// Producers [`enqueue`](Self::enqueue) messages in their sub-transactions. Consumers [`claim`](Self::claim) pending
+/// messages, which locks them until the end of the transaction, and [`mark_processed`](Self::mark_processed) them once
+/// they are handled, in the same transaction.
+#[derive(Debug)]
+pub struct Outbox {
+    table: String,
+    /// Local id of the transaction messages were claimed in, and their ids
+    claimed: RefCell<(pg_sys::LocalTransactionId, Vec<i64>)>,
+}
+
+impl Outbox {
+    /// Outbox stored in `table`
+    pub fn new(table: &str) -> Self {
+        Self {
+            table: table.to_string(),
+            claimed: RefCell::new((pg_sys::InvalidLocalTransactionId, vec![])),
+        }
+    }
+
+    /// Create the outbox table, and an index of its pending messages, if they don't exist
+    ///
+    /// The table has the columns `id bigint` (an identity), `created_at timestamptz`, `topic text`, `payload jsonb`
+    /// and `processed_at timestamptz` (`NULL` while the message is pending). Both commands execute in one
+    /// sub-transaction, which is rolled back if an error is caught.
+    pub fn ensure_schema(&self, client: &mut SpiClient) -> Result<(), Error> {
+        let name = self.table.rsplit('.').next().unwrap_or(&self.table);
+        let create_table = format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+             id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY, \
+             created_at timestamptz NOT NULL DEFAULT now(), \
+             topic text NOT NULL, \
+             payload jsonb NOT NULL, \
+             processed_at timestamptz)",
+            quote_qualified_identifier(&self.table)
+        );
+        let create_index = format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {} (topic, id) WHERE processed_at IS NULL",
+            quote_identifier(&format!("{}_pending_idx", name)),
+            quote_qualified_identifier(&self.table)
+        );
+        checked_sub_transaction_of(client, move |client| {
+            client.update(&create_table, None, None);
+            client.update(&create_index, None, None);
+        })
+    }
+
+    /// Enqueue a message with `payload` (JSON text) on `topic` in `xact`, returning its id
+    ///
+    /// The message is inserted in a child sub-transaction of `xact`, so it is discarded if `xact` is rolled back.
+    /// Returns [`OutboxError::InvalidPayload`] if the payload is not valid JSON, without inserting anything.
+    pub fn enqueue<Parent, const COMMIT: bool>(
+        &self,
+        xact: &mut SubTransaction<Parent, COMMIT>,
+        topic: &str,
+        payload: &str,
+    ) -> Result<i64, OutboxError> {
+        self.insert(xact, topic, PgBuiltInOids::TEXTOID, payload.into_datum())
+    }
+
+    /// Enqueue a message with a JSON `payload` on `topic` in `xact`, returning its id (see [`enqueue`](Self::enqueue))
+    ///
+    /// Requires the `json` feature.
+    #[cfg(feature = "json")]
+    pub fn enqueue_json<Parent, const COMMIT: bool>(
+        &self,
+        xact: &mut SubTransaction<Parent, COMMIT>,
+        topic: &str,
+        payload: Json,
+    ) -> Result<i64, OutboxError> {
+        self.insert(xact, topic, PgBuiltInOids::JSONOID, payload.into_datum())
+    }
+
+    fn insert<Parent, const COMMIT: bool>(
+        &self,
+        xact: &mut SubTransaction<Parent, COMMIT>,
+        topic: &str,
+        payload_type: PgBuiltInOids,
+        payload: Option<pg_sys::Datum>,
+    ) -> Result<i64, OutboxError> {
+        let insert = format!(
+            "INSERT INTO {} (topic, payload) VALUES ($1, $2::jsonb) RETURNING id",
+            quote_qualified_identifier(&self.table)
+        );
+        let id = xact
+            .update(
+                &insert,
+                None,
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), topic.into_datum()),
+                    (payload_type.oid(), payload),
+                ]),
+            )
+            .map_err(|err| {
+                // Only the payload's cast parses text in this command
+                if err.sqlstate() == SqlState::parse("22P02") {
+                    OutboxError::InvalidPayload(err)
+                } else {
+                    OutboxError::Query(err)
+                }
+            })?
+            .first()
+            .get_one::<i64>()
+            .expect("insert returned no id");
+        Ok(id)
+    }
+
+    /// Claim at most `batch` pending messages on `topic`, oldest first
+    ///
+    /// Claimed messages are locked with `FOR UPDATE SKIP LOCKED`, so messages claimed by other transactions are
+    /// skipped, and the lock is held until the end of the transaction. Messages this outbox claimed earlier in the
+    /// same transaction are skipped too (a transaction's own locks don't make it skip them), so each message is claimed
+    /// at most once per transaction.
+    pub fn claim(
+        &self,
+        client: &mut SpiClient,
+        topic: &str,
+        batch: i64,
+    ) -> Result<Vec<OutboxMessage>, Error> {
+        let transaction = unsafe { (*pg_sys::MyProc).lxid };
+        let mut claimed = self.claimed.borrow_mut();
+        if claimed.0 != transaction {
+            *claimed = (transaction, vec![]);
+        }
+        let query = format!(
+            "SELECT id, created_at, topic, payload::text FROM {} \
+             WHERE topic = $1 AND processed_at IS NULL AND id <> ALL($2) \
+             ORDER BY id LIMIT $3 FOR UPDATE SKIP LOCKED",
+            quote_qualified_identifier(&self.table)
+        );
+        let messages: Vec<_> = (&mut *client)
+            .checked_update(
+                &query,
+                None,
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), topic.into_datum()),
+                    (
+                        PgBuiltInOids::INT8ARRAYOID.oid(),
+                        claimed.1.clone().into_datum(),
+                    ),
+                    (PgBuiltInOids::INT8OID.oid(), batch.into_datum()),
+                ]),
+            )?
+            .filter_map(|row| {
+                Some(OutboxMessage {
+                    id: row.by_ordinal(1).ok()?.value()?,
+                    created_at: row.by_ordinal(2).ok()?.value()?,
+                    topic: row.by_ordinal(3).ok()?.value()?,
+                    payload: row.by_ordinal(4).ok()?.value()?,
+                })
+            })
+            .collect();
+        claimed.1.extend(messages.iter().map(|message| message.id));
+        Ok(messages)
+    }
+
+    /// Mark the messages with `ids` as processed, so they are no longer claimed, returning how many were pending
+    pub fn mark_processed(&self, client: &mut SpiClient, ids: &[i64]) -> Result<u64, Error> {
+        let update = format!(
+            "UPDATE {} SET processed_at = now() WHERE id = ANY($1) AND processed_at IS NULL",
+            quote_qualified_identifier(&self.table)
+        );
+        let table = (&mut *client).checked_update(
+            &update,
+            None,
+            Some(vec![(
+                PgBuiltInOids::INT8ARRAYOID.oid(),
+                ids.to_vec().into_datum(),
+            )]),
+        )?;
+        Ok(table.len() as u64)
+    }
+}
@@ -0,0 +1,368 @@
+use pgx::{pg_sys, FromDatum, IntoDatum, PgOid, SpiHeapTupleData, SpiTupleTable};
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::CStr;
+use std::hash::{Hash, Hasher};
+
+use crate::row::{column_names, ColumnError, ColumnOutOfRange, SqlValue};
+
+/// Column of [`OwnedRows`]
+#[derive(Debug, Clone)]
+pub struct OwnedColumn {
+    pub name: String,
+    pub type_oid: PgOid,
+    typlen: i16,
+    typbyval: bool,
+}
+
+impl OwnedColumn {
+    fn new(name: String, type_oid: PgOid) -> Self {
+        let (mut typlen, mut typbyval) = (0, false);
+        unsafe { pg_sys::get_typlenbyval(type_oid.value(), &mut typlen, &mut typbyval) };
+        OwnedColumn {
+            name,
+            type_oid,
+            typlen,
+            typbyval,
+        }
+    }
+}
+
+/// Value copied out of a tuple table
+#[derive(Debug)]
+enum OwnedDatum {
+    ByValue(pg_sys::Datum),
+    // Stored as `u64`s to keep the value aligned
+    ByReference(Box<[u64]>),
+}
+
+impl OwnedDatum {
+    fn datum(&self) -> pg_sys::Datum {
+        match self {
+            OwnedDatum::ByValue(datum) => *datum,
+            OwnedDatum::ByReference(bytes) => pg_sys::Datum::from(bytes.as_ptr() as *mut u64),
+        }
+    }
+
+    /// Size of the value of `column`, as counted when it was copied
+    fn size(&self, column: &OwnedColumn) -> u64 {
+        let size = match (self, column.typlen) {
+            (OwnedDatum::ByValue(_), len) => len.max(0) as usize,
+            (OwnedDatum::ByReference(bytes), -1) => unsafe {
+                pgx::varsize_any(bytes.as_ptr() as *const pg_sys::varlena)
+            },
+            (OwnedDatum::ByReference(bytes), -2) => {
+                unsafe { CStr::from_ptr(bytes.as_ptr() as *const std::os::raw::c_char) }
+                    .to_bytes_with_nul()
+                    .len()
+            }
+            (OwnedDatum::ByReference(_), len) => len as usize,
+        };
+        size as u64
+    }
+}
+
+/// Rows copied out of tuple tables into Rust-owned memory
+///
+/// Unlike `SpiTupleTable`, they remain valid regardless of the sub-transaction or SPI connection they were fetched
+/// in. Variable-length values are detoasted while copying.
+#[derive(Debug, Default)]
+pub struct OwnedRows {
+    columns: Vec<OwnedColumn>,
+    rows: Vec<Vec<Option<OwnedDatum>>>,
+    bytes: u64,
+}
+
+impl OwnedRows {
+    /// Create empty rows with the columns of a tuple table
+    pub fn with_columns_of(table: &SpiTupleTable) -> Self {
+        let columns = column_names(table)
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| {
+                OwnedColumn::new(
+                    name,
+                    table
+                        .column_type_oid(index + 1)
+                        .unwrap_or(PgOid::InvalidOid),
+                )
+            })
+            .collect();
+        Self {
+            columns,
+            ..Default::default()
+        }
+    }
+
+    /// Copy all the rows of a tuple table
+    pub fn from_table(table: SpiTupleTable) -> Self {
+        let mut rows = Self::with_columns_of(&table);
+        for tuple in table.first() {
+            rows.push(&tuple);
+        }
+        rows
+    }
+
+    /// Copy a row, returning the number of bytes its values take
+    ///
+    /// The row must have the same columns as the ones these rows were created with.
+    pub fn push(&mut self, tuple: &SpiHeapTupleData) -> u64 {
+        self.push_datums(|ordinal| {
+            tuple
+                .by_ordinal(ordinal)
+                .ok()
+                .and_then(|entry| entry.value::<pg_sys::Datum>())
+        })
+    }
+
+    /// Replace the row at (0-based) `index` with a copy of a row, returning the number of bytes its values take
+    ///
+    /// Panics if there is no row at `index`.
+    pub(crate) fn replace(&mut self, index: usize, tuple: &SpiHeapTupleData) -> u64 {
+        assert!(index < self.rows.len(), "no row at index {}", index);
+        let bytes = self.push(tuple);
+        let replaced = self.rows.swap_remove(index);
+        self.bytes -= replaced
+            .iter()
+            .zip(&self.columns)
+            .filter_map(|(value, column)| Some(value.as_ref()?.size(column)))
+            .sum::<u64>();
+        bytes
+    }
+
+    /// Copy all the rows of a raw tuple table
+    ///
+    /// # Safety
+    ///
+    /// `table` must be null or point to a tuple table with at least `rows` rows.
+    pub(crate) unsafe fn from_raw(table: *mut pg_sys::SPITupleTable, rows: u64) -> Self {
+        if table.is_null() {
+            return Self::default();
+        }
+        let desc = (*table).tupdesc;
+        let columns = (1..=(*desc).natts)
+            .map(|i| {
+                OwnedColumn::new(
+                    CStr::from_ptr(pg_sys::SPI_fname(desc, i))
+                        .to_string_lossy()
+                        .into_owned(),
+                    PgOid::from(pg_sys::SPI_gettypeid(desc, i)),
+                )
+            })
+            .collect();
+        let mut owned = Self {
+            columns,
+            ..Default::default()
+        };
+        for i in 0..rows as usize {
+            let tuple = *(*table).vals.add(i);
+            owned.push_datums(|ordinal| {
+                let mut is_null = false;
+                let datum = pg_sys::SPI_getbinval(tuple, desc, ordinal as i32, &mut is_null);
+                (!is_null).then_some(datum)
+            });
+        }
+        owned
+    }
+
+    /// Copy a row from the datums of its columns, given their (1-based) ordinals
+    fn push_datums(&mut self, datum: impl Fn(usize) -> Option<pg_sys::Datum>) -> u64 {
+        let mut bytes = 0;
+        let row = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| {
+                let datum = datum(index + 1)?;
+                let (value, size) = unsafe { copy_datum(column, datum) };
+                bytes += size as u64;
+                Some(value)
+            })
+            .collect();
+        self.rows.push(row);
+        self.bytes += bytes;
+        bytes
+    }
+
+    pub fn columns(&self) -> &[OwnedColumn] {
+        &self.columns
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Total number of bytes taken by the values
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Get the row at (0-based) `index`
+    pub fn row(&self, index: usize) -> Option<OwnedRow<'_>> {
+        (index < self.rows.len()).then_some(OwnedRow { rows: self, index })
+    }
+
+    /// Iterate over the rows, in the order they were copied (for rows copied from a tuple table, the order the server
+    /// returned them in)
+    pub fn iter(&self) -> impl Iterator<Item = OwnedRow<'_>> {
+        (0..self.rows.len()).map(move |index| OwnedRow { rows: self, index })
+    }
+
+    /// Distribute the rows into `buckets` sets of rows with the same columns, by a hash of their values
+    ///
+    /// Rows with the same values end up in the same bucket. Panics if `buckets` is 0.
+    pub(crate) fn split_by_hash(self, buckets: usize) -> Vec<OwnedRows> {
+        assert!(buckets > 0, "number of buckets must be positive");
+        let mut split: Vec<_> = (0..buckets)
+            .map(|_| OwnedRows {
+                columns: self.columns.clone(),
+                ..Default::default()
+            })
+            .collect();
+        for row in self.rows {
+            let mut hasher = DefaultHasher::new();
+            for value in &row {
+                match value {
+                    None => hasher.write_u8(0),
+                    Some(OwnedDatum::ByValue(datum)) => {
+                        hasher.write_u8(1);
+                        hasher.write_usize(datum.cast_mut_ptr::<u8>() as usize);
+                    }
+                    Some(OwnedDatum::ByReference(bytes)) => {
+                        hasher.write_u8(2);
+                        bytes.hash(&mut hasher);
+                    }
+                }
+            }
+            let bucket = &mut split[(hasher.finish() % buckets as u64) as usize];
+            bucket.bytes += row
+                .iter()
+                .zip(&self.columns)
+                .filter_map(|(value, column)| Some(value.as_ref()?.size(column)))
+                .sum::<u64>();
+            bucket.rows.push(row);
+        }
+        split
+    }
+}
+
+/// Row of [`OwnedRows`]
+///
+/// As with [`SpiRow`](crate::row::SpiRow), columns accessed by position name the convention:
+/// [`by_ordinal_1based`](Self::by_ordinal_1based) or [`by_index_0based`](Self::by_index_0based).
+#[derive(Clone, Copy)]
+pub struct OwnedRow<'a> {
+    rows: &'a OwnedRows,
+    index: usize,
+}
+
+impl<'a> OwnedRow<'a> {
+    /// Zero-based index of the row
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Names of the columns, in order
+    pub fn columns(&self) -> Vec<&'a str> {
+        self.rows.columns.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    /// Get the value of the column with the 1-based `ordinal`, returning `None` if it is NULL
+    pub fn by_ordinal_1based<T: FromDatum + IntoDatum>(
+        &self,
+        ordinal: usize,
+    ) -> Result<Option<T>, ColumnOutOfRange> {
+        ColumnOutOfRange::check(ordinal, ordinal, self.rows.columns.len())?;
+        Ok(self.get_by_ordinal(ordinal))
+    }
+
+    /// Get the value of the column with the 0-based `index`, returning `None` if it is NULL
+    pub fn by_index_0based<T: FromDatum + IntoDatum>(
+        &self,
+        index: usize,
+    ) -> Result<Option<T>, ColumnOutOfRange> {
+        let ordinal = index.saturating_add(1);
+        ColumnOutOfRange::check(ordinal, index, self.rows.columns.len())?;
+        Ok(self.get_by_ordinal(ordinal))
+    }
+
+    /// Get a column's value by its (1-based) ordinal, returning `None` if it is NULL
+    ///
+    /// Panics if there is no such column (see [`by_ordinal_1based`](Self::by_ordinal_1based)).
+    pub fn get_by_ordinal<T: FromDatum + IntoDatum>(&self, ordinal: usize) -> Option<T> {
+        let value = self.rows.rows[self.index]
+            .get(ordinal.wrapping_sub(1))
+            .unwrap_or_else(|| panic!("no column with ordinal {}", ordinal))
+            .as_ref()?;
+        unsafe { T::from_datum(value.datum(), false) }
+    }
+
+    /// Get a column's value by name, returning `None` if it is NULL
+    ///
+    /// Panics if there is no such column (see [`try_get`](Self::try_get)).
+    pub fn get<T: FromDatum + IntoDatum>(&self, column: &str) -> Option<T> {
+        let ordinal = self
+            .rows
+            .columns
+            .iter()
+            .position(|c| c.name == column)
+            .unwrap_or_else(|| panic!("no column named \"{}\"", column))
+            + 1;
+        self.get_by_ordinal(ordinal)
+    }
+
+    /// Get a column's value by name, as [`SqlValue::Value`] or [`SqlValue::Null`]
+    pub fn try_get<T: FromDatum + IntoDatum>(
+        &self,
+        column: &str,
+    ) -> Result<SqlValue<T>, ColumnError> {
+        let ordinal = self
+            .rows
+            .columns
+            .iter()
+            .position(|c| c.name == column)
+            .ok_or_else(|| ColumnError {
+                column: column.to_string(),
+            })?
+            + 1;
+        Ok(self
+            .get_by_ordinal(ordinal)
+            .map_or(SqlValue::Null, SqlValue::Value))
+    }
+}
+
+/// Copy a datum, returning the copy and its size
+unsafe fn copy_datum(column: &OwnedColumn, datum: pg_sys::Datum) -> (OwnedDatum, usize) {
+    if column.typbyval {
+        return (OwnedDatum::ByValue(datum), column.typlen.max(0) as usize);
+    }
+    let (source, size, detoasted) = match column.typlen {
+        -1 => {
+            let original = datum.cast_mut_ptr::<pg_sys::varlena>();
+            let detoasted = pg_sys::pg_detoast_datum(original);
+            (
+                detoasted as *const u8,
+                pgx::varsize_any(detoasted),
+                (detoasted != original).then_some(detoasted),
+            )
+        }
+        -2 => {
+            let cstr = datum.cast_mut_ptr::<std::os::raw::c_char>();
+            (
+                cstr as *const u8,
+                CStr::from_ptr(cstr).to_bytes_with_nul().len(),
+                None,
+            )
+        }
+        len => (datum.cast_mut_ptr::<u8>() as *const u8, len as usize, None),
+    };
+    let mut bytes = vec![0u64; (size + 7) / 8].into_boxed_slice();
+    std::ptr::copy_nonoverlapping(source, bytes.as_mut_ptr() as *mut u8, size);
+    if let Some(detoasted) = detoasted {
+        pg_sys::pfree(detoasted.cast());
+    }
+    (OwnedDatum::ByReference(bytes), size)
+}
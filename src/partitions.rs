@@ -0,0 +1,180 @@
+//! Checked partition management
+//!
+//! Table names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier), bound values are quoted as literals.
+
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{IntoDatum, PgBuiltInOids, SpiClient};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::{report, Error, PostgresErrorExt};
+use crate::quote::*;
+use crate::rewrite;
+
+/// Bounds of a partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionBounds {
+    /// Range partition, from (inclusive) and to (exclusive) values of each partition key column
+    Range { from: Vec<String>, to: Vec<String> },
+    /// List partition, values of the partition key
+    List(Vec<String>),
+    /// Default partition
+    Default,
+}
+
+impl PartitionBounds {
+    fn to_sql(&self) -> String {
+        let values = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| quote_literal(value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            PartitionBounds::Range { from, to } => {
+                format!("FOR VALUES FROM ({}) TO ({})", values(from), values(to))
+            }
+            PartitionBounds::List(list) => format!("FOR VALUES IN ({})", values(list)),
+            PartitionBounds::Default => "DEFAULT".to_string(),
+        }
+    }
+}
+
+/// Partition of a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Name, qualified if it's not visible in the search path
+    pub name: String,
+    /// Bound specification, as in `FOR VALUES FROM (1) TO (10)`
+    pub bounds: String,
+    /// Whether the partition is partitioned itself
+    pub partitioned: bool,
+}
+
+/// Partition management error
+#[derive(Debug)]
+pub enum PartitionError {
+    /// Bounds of the partition overlap those of an existing one
+    OverlappingBounds(CaughtError),
+    /// The parent table is not partitioned
+    NotPartitionedTable(CaughtError),
+    /// Detaching concurrently is not possible in a transaction block, which SPI commands always run in
+    ConcurrentlyInTransaction(CaughtError),
+    /// The command failed for another reason
+    Query(Error),
+}
+
+impl Display for PartitionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PartitionError::OverlappingBounds(err)
+            | PartitionError::NotPartitionedTable(err)
+            | PartitionError::ConcurrentlyInTransaction(err) => {
+                write!(f, "{}", report(err).message())
+            }
+            PartitionError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for PartitionError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Caught(err, captured) => {
+                let report = report(&err);
+                // These SQLSTATEs cover other errors as well, so the message is checked too (it may be translated,
+                // in which case the error is reported as is)
+                match report.sqlstate().as_str() {
+                    "42P17" if report.message().contains("overlap") => {
+                        PartitionError::OverlappingBounds(err)
+                    }
+                    "42809" if report.message().contains("is not partitioned") => {
+                        PartitionError::NotPartitionedTable(err)
+                    }
+                    "25001" => PartitionError::ConcurrentlyInTransaction(err),
+                    _ => PartitionError::Query(Error::Caught(err, captured)),
+                }
+            }
+            err => PartitionError::Query(err),
+        }
+    }
+}
+
+/// Create a partition of `parent`
+pub fn checked_create_partition(
+    client: &mut SpiClient,
+    parent: &str,
+    name: &str,
+    bounds: &PartitionBounds,
+) -> Result<(), PartitionError> {
+    let query = format!(
+        "CREATE TABLE {} PARTITION OF {} {}",
+        quote_qualified_identifier(name),
+        quote_qualified_identifier(parent),
+        bounds.to_sql()
+    );
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// Attach an existing table as a partition of `parent`
+pub fn checked_attach(
+    client: &mut SpiClient,
+    parent: &str,
+    child: &str,
+    bounds: &PartitionBounds,
+) -> Result<(), PartitionError> {
+    let query = format!(
+        "ALTER TABLE {} ATTACH PARTITION {} {}",
+        quote_qualified_identifier(parent),
+        quote_qualified_identifier(child),
+        bounds.to_sql()
+    );
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// Detach a partition from `parent`
+///
+/// Postgres 14 added detaching concurrently, which can't be done in a transaction block. As SPI commands always run
+/// in one, it fails with [`PartitionError::ConcurrentlyInTransaction`] (and with a syntax error on earlier versions).
+pub fn checked_detach(
+    client: &mut SpiClient,
+    parent: &str,
+    child: &str,
+    concurrently: bool,
+) -> Result<(), PartitionError> {
+    let query = format!(
+        "ALTER TABLE {} DETACH PARTITION {}{}",
+        quote_qualified_identifier(parent),
+        quote_qualified_identifier(child),
+        if concurrently { " CONCURRENTLY" } else { "" }
+    );
+    client.checked_update(&query, None, None)?;
+    Ok(())
+}
+
+/// List the partitions of `parent`, ordered by name
+pub fn list_partitions(client: &SpiClient, parent: &str) -> Result<Vec<PartitionInfo>, Error> {
+    let table = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT c.oid::regclass::text, pg_get_expr(c.relpartbound, c.oid), c.relkind = 'p' \
+         FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid \
+         WHERE i.inhparent = $1::regclass ORDER BY 1",
+            None,
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                quote_qualified_identifier(parent).into_datum(),
+            )]),
+        )
+    })?;
+    Ok(table
+        .map(|row| PartitionInfo {
+            name: row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+            bounds: row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+            partitioned: row.by_ordinal(3).unwrap().value().unwrap_or_default(),
+        })
+        .collect())
+}
@@ -0,0 +1,212 @@
+//! Assertions on query plans
+//!
+//! Queries are explained with `EXPLAIN (FORMAT JSON, VERBOSE)` in a sub-transaction that is always rolled back, and
+//! never executed. Table and index names are compared with the plan's (unquoted) names, optionally qualified with
+//! the schema (`schema.name`).
+//!
+//! Requires the `json` feature.
+
+use pgx::{pg_sys::Datum, Json, PgOid, SpiClient};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::subtxn::*;
+
+/// Plan of a query that failed an assertion
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanSummary {
+    /// Plan in JSON format, pretty-printed
+    pub text: String,
+    /// Estimated startup cost of the top plan node
+    pub startup_cost: f64,
+    /// Estimated total cost of the top plan node
+    pub total_cost: f64,
+    /// Estimated number of rows returned by the top plan node
+    pub rows: f64,
+}
+
+/// Plan assertion error
+#[derive(Debug)]
+pub enum PlanAssertError {
+    /// The plan doesn't scan the index
+    IndexNotUsed { index: String, plan: PlanSummary },
+    /// The plan scans the table sequentially
+    SeqScan { table: String, plan: PlanSummary },
+    /// Explaining the query failed
+    Query(Error),
+}
+
+impl Display for PlanAssertError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanAssertError::IndexNotUsed { index, plan } => {
+                write!(
+                    f,
+                    "query does not use index {}, plan:\n{}",
+                    index, plan.text
+                )
+            }
+            PlanAssertError::SeqScan { table, plan } => {
+                write!(
+                    f,
+                    "query scans table {} sequentially, plan:\n{}",
+                    table, plan.text
+                )
+            }
+            PlanAssertError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for PlanAssertError {
+    fn from(err: Error) -> Self {
+        PlanAssertError::Query(err)
+    }
+}
+
+/// Assert that the plan of the query scans `index` (with an index, index-only or bitmap index scan)
+pub fn assert_uses_index(
+    client: &SpiClient,
+    query: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+    index: &str,
+) -> Result<(), PlanAssertError> {
+    let plan = explain(client, query, args)?;
+    let (summary, nodes) = walk(&plan);
+    if nodes.iter().any(|node| {
+        matches!(
+            node.node_type.as_str(),
+            "Index Scan" | "Index Only Scan" | "Bitmap Index Scan"
+        ) && node.matches(node.index.as_deref(), index)
+    }) {
+        Ok(())
+    } else {
+        Err(PlanAssertError::IndexNotUsed {
+            index: index.to_string(),
+            plan: summary,
+        })
+    }
+}
+
+/// Assert that the plan of the query doesn't scan `table` sequentially
+pub fn assert_no_seqscan(
+    client: &SpiClient,
+    query: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+    table: &str,
+) -> Result<(), PlanAssertError> {
+    let plan = explain(client, query, args)?;
+    let (summary, nodes) = walk(&plan);
+    if nodes
+        .iter()
+        .any(|node| node.node_type == "Seq Scan" && node.matches(node.relation.as_deref(), table))
+    {
+        Err(PlanAssertError::SeqScan {
+            table: table.to_string(),
+            plan: summary,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Assert a property of a query's plan, panicking with the plan if it doesn't hold
+///
+/// ```rust,ignore
+/// assert_plan!(client, "SELECT * FROM t WHERE id = 1", uses_index = "t_pkey");
+/// assert_plan!(client, "SELECT * FROM t WHERE id = $1", args, no_seqscan = "t");
+/// ```
+#[macro_export]
+macro_rules! assert_plan {
+    ($client:expr, $query:expr, uses_index = $index:expr) => {
+        $crate::assert_plan!($client, $query, None, uses_index = $index)
+    };
+    ($client:expr, $query:expr, no_seqscan = $table:expr) => {
+        $crate::assert_plan!($client, $query, None, no_seqscan = $table)
+    };
+    ($client:expr, $query:expr, $args:expr, uses_index = $index:expr) => {
+        if let Err(err) = $crate::plan_asserts::assert_uses_index($client, $query, $args, $index) {
+            panic!("{}", err)
+        }
+    };
+    ($client:expr, $query:expr, $args:expr, no_seqscan = $table:expr) => {
+        if let Err(err) = $crate::plan_asserts::assert_no_seqscan($client, $query, $args, $table) {
+            panic!("{}", err)
+        }
+    };
+}
+
+fn explain(
+    client: &SpiClient,
+    query: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+) -> Result<Json, Error> {
+    // Utility commands can't be executed as read-only, even if they make no changes
+    let xact = client.sub_transaction(|xact| xact.rollback_on_drop());
+    let (table, xact) = xact.checked_update(
+        &format!("EXPLAIN (FORMAT JSON, VERBOSE) {}", query),
+        None,
+        args,
+    )?;
+    let plan = table.first().get_one::<Json>();
+    drop(xact);
+    Ok(plan.expect("EXPLAIN returned no plan"))
+}
+
+struct PlanNode {
+    node_type: String,
+    schema: Option<String>,
+    relation: Option<String>,
+    index: Option<String>,
+}
+
+impl PlanNode {
+    /// Whether `name` (possibly schema-qualified) is the name of the node's relation or index
+    fn matches(&self, node_name: Option<&str>, name: &str) -> bool {
+        match (node_name, &self.schema) {
+            (Some(node_name), _) if node_name == name => true,
+            (Some(node_name), Some(schema)) => format!("{}.{}", schema, node_name) == name,
+            _ => false,
+        }
+    }
+}
+
+fn walk(plan: &Json) -> (PlanSummary, Vec<PlanNode>) {
+    let root = plan
+        .0
+        .as_array()
+        .and_then(|plans| plans.first())
+        .and_then(|plan| plan.get("Plan"));
+    let number = |key: &str| {
+        root.and_then(|node| node.get(key))
+            .and_then(|value| value.as_f64())
+            .unwrap_or_default()
+    };
+    let summary = PlanSummary {
+        text: format!("{:#}", plan.0),
+        startup_cost: number("Startup Cost"),
+        total_cost: number("Total Cost"),
+        rows: number("Plan Rows"),
+    };
+
+    let mut nodes = vec![];
+    let mut pending: Vec<_> = root.into_iter().collect();
+    while let Some(node) = pending.pop() {
+        let string = |key: &str| {
+            node.get(key)
+                .and_then(|value| value.as_str())
+                .map(str::to_string)
+        };
+        nodes.push(PlanNode {
+            node_type: string("Node Type").unwrap_or_default(),
+            schema: string("Schema"),
+            relation: string("Relation Name"),
+            index: string("Index Name"),
+        });
+        if let Some(children) = node.get("Plans").and_then(|plans| plans.as_array()) {
+            pending.extend(children);
+        }
+    }
+    (summary, nodes)
+}
@@ -0,0 +1,192 @@
+//! Deleting rows in bounded batches
+//!
+//! Table names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier). The predicate, on the other hand, is
+//! inserted into the commands as it is: it must be trusted SQL, with any untrusted values passed as parameters.
+
+use pgx::{pg_sys::Datum, IntoDatum, PgBuiltInOids, PgOid, SpiClient};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use crate::batch::*;
+use crate::bgworker;
+use crate::checked::*;
+use crate::error::Error;
+use crate::quote::*;
+use crate::rewrite;
+
+/// Progress of [`checked_delete_in_batches`], reported after every batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchProgress {
+    /// Rows deleted so far
+    pub deleted_so_far: u64,
+    /// Batches executed so far
+    pub batches: u64,
+    /// Rows deleted by the last batch
+    pub last_batch: u64,
+}
+
+/// How [`checked_delete_in_batches`] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteOutcome {
+    /// A batch found no more rows to delete
+    Completed,
+    /// The callback requested to stop
+    Cancelled,
+    /// The background worker was asked to shut down, see [`with_yield_points`](bgworker::with_yield_points)
+    ShutdownRequested,
+}
+
+/// Summary of [`checked_delete_in_batches`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeleteSummary {
+    pub deleted: u64,
+    /// Batches that deleted rows
+    pub batches: u64,
+    /// Every batch executed (including the last one, which found no rows to delete, and failed ones), in order, with
+    /// the number of rows it was limited to as its size
+    pub history: Vec<BatchInfo>,
+    pub elapsed: Duration,
+    pub outcome: DeleteOutcome,
+    /// Whether more rows were deleted than it takes for autovacuum to vacuum the table (as configured server-wide)
+    ///
+    /// Autovacuum will get to it eventually; running `VACUUM` right away makes the space reusable sooner.
+    pub vacuum_recommended: bool,
+}
+
+/// Delete the rows of `table` matching `predicate` (the text of a `WHERE` clause, without the keyword), at most
+/// `batch_size` of them per command, until none are left or `between` returns `ControlFlow::Break`
+///
+/// Every batch is a command of the form `DELETE FROM t WHERE ctid = ANY(ARRAY(SELECT ctid FROM t WHERE <predicate>
+/// LIMIT <batch_size>)) AND (<predicate>)`, executed with `args` in a sub-transaction of its own that is committed
+/// right away. The predicate is checked again by the outer command, so rows of different partitions that share a
+/// `ctid` are only deleted if they match it too (a batch of a partitioned table may thus delete more than
+/// `batch_size` rows), and it must not call volatile functions. `between` is called after every batch that deleted
+/// rows, outside of any sub-transaction.
+///
+/// Since batches are committed as they go, an error (which is returned right away) or a `Break` keeps the rows
+/// deleted by earlier batches deleted. They are only made durable when the enclosing transaction commits, though:
+/// nothing is if it rolls back, and the locks of every batch are held until it ends.
+///
+/// Panics if `batch_size` is 0.
+pub fn checked_delete_in_batches<F: FnMut(BatchProgress) -> ControlFlow<()>>(
+    client: &mut SpiClient,
+    table: &str,
+    predicate: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+    batch_size: usize,
+    between: F,
+) -> Result<DeleteSummary, Error> {
+    checked_delete_with_policy(
+        client,
+        table,
+        predicate,
+        args,
+        BatchPolicy::Fixed(batch_size),
+        between,
+    )
+}
+
+/// Delete rows as [`checked_delete_in_batches`] does, with batch sizes chosen by `policy`
+///
+/// With [`BatchPolicy::Adaptive`], a batch that fails is retried with half as many rows, and the error is only
+/// returned once the batch size is at its minimum.
+///
+/// Panics if `policy` allows empty batches.
+pub fn checked_delete_with_policy<F: FnMut(BatchProgress) -> ControlFlow<()>>(
+    client: &mut SpiClient,
+    table: &str,
+    predicate: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+    policy: BatchPolicy,
+    mut between: F,
+) -> Result<DeleteSummary, Error> {
+    let mut sizer = BatchSizer::new(policy);
+    let started = Instant::now();
+    let table = quote_qualified_identifier(table);
+    let mut progress = BatchProgress {
+        deleted_so_far: 0,
+        batches: 0,
+        last_batch: 0,
+    };
+    let mut history = vec![];
+    let outcome = loop {
+        if bgworker::batch_yield_point().is_err() {
+            break DeleteOutcome::ShutdownRequested;
+        }
+        let batch_size = sizer.size();
+        // A newline ends a trailing line comment of the predicate
+        let query = format!(
+            "DELETE FROM {table} WHERE ctid = ANY(ARRAY(SELECT ctid FROM {table} WHERE {predicate}\n LIMIT \
+             {batch_size})) AND ({predicate}\n)",
+            table = table,
+            predicate = predicate,
+            batch_size = batch_size,
+        );
+        let batch_started = Instant::now();
+        let result = (&mut *client).checked_update(&query, None, args.clone());
+        let duration = batch_started.elapsed();
+        let deleted = match result {
+            Ok(rows) => rows.len() as u64,
+            Err(err) => {
+                history.push(BatchInfo {
+                    size: batch_size,
+                    duration,
+                    outcome: BatchOutcome::RolledBack,
+                });
+                if !sizer.can_shrink() {
+                    return Err(err);
+                }
+                sizer.record(duration, true);
+                continue;
+            }
+        };
+        sizer.record(duration, false);
+        history.push(BatchInfo {
+            size: batch_size,
+            duration,
+            outcome: BatchOutcome::Committed { failed: 0 },
+        });
+        if deleted == 0 {
+            break DeleteOutcome::Completed;
+        }
+        progress.deleted_so_far += deleted;
+        progress.batches += 1;
+        progress.last_batch = deleted;
+        if between(progress).is_break() {
+            break DeleteOutcome::Cancelled;
+        }
+    };
+    let vacuum_recommended = progress.deleted_so_far > 0
+        && exceeds_vacuum_threshold(client, &table, progress.deleted_so_far)?;
+    Ok(DeleteSummary {
+        deleted: progress.deleted_so_far,
+        batches: progress.batches,
+        history,
+        elapsed: started.elapsed(),
+        outcome,
+        vacuum_recommended,
+    })
+}
+
+/// Whether `deleted` dead rows make autovacuum vacuum `table`, going by its estimated size before they were deleted
+fn exceeds_vacuum_threshold(client: &SpiClient, table: &str, deleted: u64) -> Result<bool, Error> {
+    Ok(rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT $2 > current_setting('autovacuum_vacuum_threshold')::float8 \
+             + current_setting('autovacuum_vacuum_scale_factor')::float8 * greatest(reltuples, 0) \
+             FROM pg_class WHERE oid = $1::regclass",
+            Some(1),
+            Some(vec![
+                (PgBuiltInOids::TEXTOID.oid(), table.into_datum()),
+                (
+                    PgBuiltInOids::FLOAT8OID.oid(),
+                    (deleted as f64).into_datum(),
+                ),
+            ]),
+        )
+    })?
+    .first()
+    .get_one::<bool>()
+    .unwrap_or_default())
+}
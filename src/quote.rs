@@ -0,0 +1,54 @@
+//! Quoting helpers for building SQL text
+//!
+//! The `try_` variants check their input with [`validate`](crate::validate) first, rejecting identifiers Postgres would
+//! truncate and NUL bytes instead of producing SQL that references another object or can't be executed.
+
+use crate::validate::{self, IdentError, LiteralError};
+
+/// Quote an identifier, doubling any embedded double quotes
+pub fn quote_identifier(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Quote a possibly schema-qualified name (`schema.name`), quoting each part separately
+pub fn quote_qualified_identifier(name: &str) -> String {
+    name.split('.')
+        .map(quote_identifier)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Check an identifier with [`validate::identifier`] and quote it
+pub fn try_quote_identifier(ident: &str) -> Result<String, IdentError> {
+    Ok(validate::identifier(ident)?.quote())
+}
+
+/// Check every part of a possibly schema-qualified name with [`validate::qualified_identifier`] and quote it
+pub fn try_quote_qualified_identifier(name: &str) -> Result<String, IdentError> {
+    validate::qualified_identifier(name)?;
+    Ok(quote_qualified_identifier(name))
+}
+
+/// Quote a string literal, doubling any embedded single quotes
+///
+/// Always produces a standard-conforming literal; backslashes are not treated specially.
+pub fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
+
+/// Check a literal with [`validate::literal`] and quote it
+pub fn try_quote_literal(literal: &str) -> Result<String, LiteralError> {
+    validate::literal(literal)?;
+    Ok(quote_literal(literal))
+}
+
+/// Dollar-quote a string, picking a tag that does not occur in it
+pub fn dollar_quote(body: &str) -> String {
+    let mut tag = "$spiext$".to_string();
+    let mut counter = 0;
+    while body.contains(&tag) {
+        counter += 1;
+        tag = format!("$spiext{}$", counter);
+    }
+    format!("{tag}{body}{tag}")
+}
@@ -0,0 +1,283 @@
+//! Reconciling database objects with their desired definitions
+//!
+//! Every object is applied in a sub-transaction of its own, so an object that fails to apply doesn't prevent the
+//! others from being applied.
+
+use pgx::{IntoDatum, PgBuiltInOids, SpiClient};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::error::Error;
+use crate::rewrite;
+use crate::subtxn::*;
+
+/// Kind of a [`DesiredObject`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Function,
+    View,
+    Index,
+    Table,
+}
+
+/// Object that must exist with a specific definition
+#[derive(Debug, Clone)]
+pub struct DesiredObject {
+    pub kind: ObjectKind,
+    /// Name, as in SQL: functions are named by their signature (`schema.name(int, text)`), other objects by their
+    /// possibly qualified name
+    pub name: String,
+    /// SQL creating the object, or replacing it if it exists (`CREATE OR REPLACE`, `CREATE ... IF NOT EXISTS`)
+    ///
+    /// Tables that exist are left as they are, so their definition is only executed if they don't.
+    pub definition: String,
+    /// Names of objects that must be applied before this one
+    ///
+    /// Names that are not among the objects being applied are assumed to refer to objects that exist already.
+    pub depends_on: Vec<String>,
+}
+
+impl DesiredObject {
+    pub fn new(kind: ObjectKind, name: &str, definition: &str) -> Self {
+        Self {
+            kind,
+            name: name.to_string(),
+            definition: definition.to_string(),
+            depends_on: vec![],
+        }
+    }
+
+    /// Add objects this one depends on
+    pub fn with_dependencies(mut self, names: &[&str]) -> Self {
+        self.depends_on
+            .extend(names.iter().map(|name| name.to_string()));
+        self
+    }
+}
+
+/// Outcome of applying an object
+#[derive(Debug)]
+pub enum Outcome {
+    /// The object didn't exist
+    Created,
+    /// The object existed with a different definition
+    Replaced,
+    /// The object already existed with the same definition, so applying it was rolled back
+    Unchanged,
+    /// The index exists with a different definition, which was left as it is
+    ///
+    /// Indexes are not replaced, as that would rebuild them: `desired` is the definition the index would have, as
+    /// reconstructed by Postgres, and `current` the one it has.
+    Drifted { current: String, desired: String },
+    /// Applying the object failed and was rolled back
+    Failed(Error),
+}
+
+/// Outcome of each object, in the order they were applied
+#[derive(Debug)]
+pub struct ReconcileReport {
+    pub outcomes: Vec<(String, Outcome)>,
+}
+
+impl ReconcileReport {
+    /// Outcome of an object
+    pub fn outcome(&self, name: &str) -> Option<&Outcome> {
+        self.outcomes
+            .iter()
+            .find(|(object, _)| object == name)
+            .map(|(_, outcome)| outcome)
+    }
+
+    /// Objects that failed to apply, with their errors
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &Error)> {
+        self.outcomes
+            .iter()
+            .filter_map(|(name, outcome)| match outcome {
+                Outcome::Failed(err) => Some((name.as_str(), err)),
+                _ => None,
+            })
+    }
+
+    /// Indexes that exist with a different definition, with their current and desired definitions
+    pub fn drifted(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.outcomes
+            .iter()
+            .filter_map(|(name, outcome)| match outcome {
+                Outcome::Drifted { current, desired } => {
+                    Some((name.as_str(), current.as_str(), desired.as_str()))
+                }
+                _ => None,
+            })
+    }
+
+    /// Whether all the objects were applied, none having failed or drifted
+    pub fn is_success(&self) -> bool {
+        self.failed().next().is_none() && self.drifted().next().is_none()
+    }
+}
+
+/// Error preventing reconciliation from starting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconcileError {
+    /// The objects depend on each other in a cycle (the objects that are part of it or depend on it)
+    DependencyCycle(Vec<String>),
+    /// More than one object has this name
+    DuplicateName(String),
+}
+
+impl Display for ReconcileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReconcileError::DependencyCycle(names) => {
+                write!(f, "dependency cycle among {}", names.join(", "))
+            }
+            ReconcileError::DuplicateName(name) => write!(f, "duplicate object \"{}\"", name),
+        }
+    }
+}
+
+impl std::error::Error for ReconcileError {}
+
+/// Apply `objects`, dependencies first, reporting the outcome of each
+///
+/// Whether an existing object is unchanged is determined by comparing its definition, as reconstructed by
+/// Postgres (`pg_get_functiondef`, `pg_get_viewdef`, `pg_get_indexdef`) with whitespace normalized, before and
+/// after applying it.
+///
+/// As `CREATE INDEX IF NOT EXISTS` leaves an existing index as it is, an existing index is dropped and its definition
+/// applied in the sub-transaction, which is then rolled back: an index whose definition differs is reported as
+/// [`Outcome::Drifted`]. This builds the index, so it takes as long as creating it would.
+///
+/// Fails before applying any object if the dependencies can't be ordered.
+pub fn apply(
+    client: &mut SpiClient,
+    objects: Vec<DesiredObject>,
+) -> Result<ReconcileReport, ReconcileError> {
+    let objects = dependency_order(objects)?;
+    Ok(ReconcileReport {
+        outcomes: objects
+            .into_iter()
+            .map(|object| {
+                let outcome = apply_object(client, &object).unwrap_or_else(Outcome::Failed);
+                (object.name, outcome)
+            })
+            .collect(),
+    })
+}
+
+fn apply_object(client: &mut SpiClient, object: &DesiredObject) -> Result<Outcome, Error> {
+    client.sub_transaction(|xact| {
+        let mut xact = xact.rollback_on_drop();
+        let before = current_definition(&xact, object)?;
+        if object.kind == ObjectKind::Table && before.is_some() {
+            return Ok(Outcome::Unchanged);
+        }
+        let existing_index = object.kind == ObjectKind::Index && before.is_some();
+        if existing_index {
+            xact.update(&format!("DROP INDEX {}", object.name), None, None)?;
+        }
+        xact.update(&object.definition, None, None)?;
+        let after = current_definition(&xact, object)?;
+        let outcome = match (before, after) {
+            (Some(before), Some(after)) if normalize(&before) == normalize(&after) => {
+                Outcome::Unchanged
+            }
+            (Some(current), Some(desired)) if existing_index => {
+                Outcome::Drifted { current, desired }
+            }
+            (Some(_), _) => Outcome::Replaced,
+            (None, _) => Outcome::Created,
+        };
+        // Otherwise, dropping the sub-transaction rolls it back
+        if matches!(outcome, Outcome::Created | Outcome::Replaced) {
+            xact.commit();
+        }
+        Ok(outcome)
+    })
+}
+
+/// Definition of the object as reconstructed by Postgres (empty for tables), or `None` if it doesn't exist
+fn current_definition<Parent>(
+    xact: &SubTransaction<Parent, false>,
+    object: &DesiredObject,
+) -> Result<Option<String>, Error> {
+    let query = match object.kind {
+        ObjectKind::Function => {
+            "SELECT pg_get_functiondef(p.oid) FROM pg_proc p WHERE p.oid = to_regprocedure($1)"
+        }
+        ObjectKind::View => {
+            "SELECT pg_get_viewdef(c.oid) FROM pg_class c \
+             WHERE c.oid = to_regclass($1) AND c.relkind IN ('v', 'm')"
+        }
+        ObjectKind::Index => {
+            "SELECT pg_get_indexdef(c.oid) FROM pg_class c \
+             WHERE c.oid = to_regclass($1) AND c.relkind IN ('i', 'I')"
+        }
+        ObjectKind::Table => {
+            "SELECT '' FROM pg_class c WHERE c.oid = to_regclass($1) AND c.relkind IN ('r', 'p')"
+        }
+    };
+    let table = rewrite::exempt(|| {
+        xact.select(
+            query,
+            Some(1),
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                object.name.as_str().into_datum(),
+            )]),
+        )
+    })?;
+    Ok(table
+        .map(|row| row.by_ordinal(1).unwrap().value::<String>())
+        .next()
+        .flatten())
+}
+
+fn normalize(definition: &str) -> String {
+    definition.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Order objects so that every object comes after the objects it depends on, keeping the given order otherwise
+fn dependency_order(objects: Vec<DesiredObject>) -> Result<Vec<DesiredObject>, ReconcileError> {
+    let mut index = HashMap::new();
+    for (i, object) in objects.iter().enumerate() {
+        if index.insert(object.name.as_str(), i).is_some() {
+            return Err(ReconcileError::DuplicateName(object.name.clone()));
+        }
+    }
+    let dependencies: Vec<Vec<usize>> = objects
+        .iter()
+        .map(|object| {
+            object
+                .depends_on
+                .iter()
+                .filter_map(|name| index.get(name.as_str()).copied())
+                .collect()
+        })
+        .collect();
+    let mut order = Vec::with_capacity(objects.len());
+    let mut placed = vec![false; objects.len()];
+    while order.len() < objects.len() {
+        let next = (0..objects.len())
+            .find(|&i| !placed[i] && dependencies[i].iter().all(|&dependency| placed[dependency]));
+        match next {
+            Some(i) => {
+                placed[i] = true;
+                order.push(i);
+            }
+            None => {
+                return Err(ReconcileError::DependencyCycle(
+                    (0..objects.len())
+                        .filter(|&i| !placed[i])
+                        .map(|i| objects[i].name.clone())
+                        .collect(),
+                ))
+            }
+        }
+    }
+    let mut objects: Vec<_> = objects.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .map(|i| objects[i].take().unwrap())
+        .collect())
+}
@@ -0,0 +1,184 @@
+//! Checked execution on remote servers through `dblink`
+//!
+//! Requires the `dblink` feature, and the `dblink` extension to be installed in the database.
+
+use pgx::{pg_sys::Datum, IntoDatum, PgBuiltInOids, PgOid, SpiClient, SpiTupleTable};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::checked::*;
+use crate::error::{Error, PostgresErrorExt, SqlState};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Error returned by remote commands
+#[derive(Debug)]
+pub enum RemoteError {
+    /// The remote server reported an error
+    ///
+    /// `dblink` raises it locally with the remote SQLSTATE, the remote message is the one the connection reported.
+    Remote {
+        local: Error,
+        remote_sqlstate: SqlState,
+        remote_message: String,
+    },
+    /// The command failed locally (or the connection reported no error)
+    Query(Error),
+}
+
+impl RemoteError {
+    /// SQLSTATE reported by the remote server, if the error came from it
+    pub fn remote_sqlstate(&self) -> Option<SqlState> {
+        match self {
+            RemoteError::Remote {
+                remote_sqlstate, ..
+            } => Some(*remote_sqlstate),
+            RemoteError::Query(_) => None,
+        }
+    }
+
+    /// Message reported by the remote server, if the error came from it
+    pub fn remote_message(&self) -> Option<&str> {
+        match self {
+            RemoteError::Remote { remote_message, .. } => Some(remote_message),
+            RemoteError::Query(_) => None,
+        }
+    }
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteError::Remote {
+                remote_sqlstate,
+                remote_message,
+                ..
+            } => write!(f, "remote error {}: {}", remote_sqlstate, remote_message),
+            RemoteError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for RemoteError {
+    fn from(err: Error) -> Self {
+        RemoteError::Query(err)
+    }
+}
+
+/// Named `dblink` connection, disconnected when dropped
+///
+/// If it's dropped while panicking (such as when an error is raised), the connection is left open, as no commands can
+/// be executed at that point; it is closed when the backend exits.
+pub struct RemoteSession {
+    name: String,
+}
+
+impl RemoteSession {
+    /// Connect to a remote server
+    pub fn connect(client: &mut SpiClient, connstr: &str) -> Result<Self, Error> {
+        let name = format!("spiext_remote_{}", NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        client.checked_update(
+            "SELECT dblink_connect($1, $2)",
+            None,
+            Some(vec![
+                (PgBuiltInOids::TEXTOID.oid(), name.as_str().into_datum()),
+                (PgBuiltInOids::TEXTOID.oid(), connstr.into_datum()),
+            ]),
+        )?;
+        Ok(Self { name })
+    }
+
+    /// Name of the connection
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Execute a query on the remote server
+    ///
+    /// `columns` is the column definition list of the result (as in `id int, name text`), used verbatim.
+    pub fn checked_remote_select(
+        &self,
+        query: &str,
+        columns: &str,
+    ) -> Result<SpiTupleTable, RemoteError> {
+        (&mut SpiClient)
+            .checked_update(
+                &format!("SELECT * FROM dblink($1, $2) AS t({})", columns),
+                None,
+                Some(self.args(query)),
+            )
+            .map_err(|err| self.remote_error(err))
+    }
+
+    /// Execute a command that returns no rows on the remote server, returning its status (as in `INSERT 0 1`)
+    pub fn checked_remote_exec(&self, query: &str) -> Result<String, RemoteError> {
+        let table = (&mut SpiClient)
+            .checked_update("SELECT dblink_exec($1, $2)", None, Some(self.args(query)))
+            .map_err(|err| self.remote_error(err))?;
+        Ok(table.first().get_one::<String>().unwrap_or_default())
+    }
+
+    fn args(&self, query: &str) -> Vec<(PgOid, Option<Datum>)> {
+        vec![
+            (
+                PgBuiltInOids::TEXTOID.oid(),
+                self.name.as_str().into_datum(),
+            ),
+            (PgBuiltInOids::TEXTOID.oid(), query.into_datum()),
+        ]
+    }
+
+    /// Tell whether the error came from the remote server, by checking the connection's last error
+    ///
+    /// This is best effort: if the command failed locally after a remote error, the remote error is reported.
+    fn remote_error(&self, err: Error) -> RemoteError {
+        let message = (&SpiClient)
+            .checked_select(
+                "SELECT dblink_error_message($1)",
+                None,
+                Some(vec![(
+                    PgBuiltInOids::TEXTOID.oid(),
+                    self.name.as_str().into_datum(),
+                )]),
+            )
+            .ok()
+            .and_then(|table| table.first().get_one::<String>());
+        let message = match message.as_deref().map(str::trim) {
+            Some(message) if !message.is_empty() && message != "OK" => message,
+            _ => return RemoteError::Query(err),
+        };
+        let remote_sqlstate = match err.report() {
+            Some(report) => report.sqlstate(),
+            None => return RemoteError::Query(err),
+        };
+        // The message comes prefixed with its severity, such as `ERROR:  `
+        let remote_message = message
+            .split_once(":  ")
+            .map_or(message, |(_, message)| message)
+            .to_string();
+        RemoteError::Remote {
+            local: err,
+            remote_sqlstate,
+            remote_message,
+        }
+    }
+}
+
+impl Drop for RemoteSession {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        let result = (&mut SpiClient).checked_update(
+            "SELECT dblink_disconnect($1)",
+            None,
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                self.name.as_str().into_datum(),
+            )]),
+        );
+        if let Err(err) = result {
+            pgx::warning!("failed to disconnect \"{}\": {}", self.name, err);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Replication state of the server, such as to hold off write-heavy work while standbys are lagging
+//!
+//! On a primary, standbys are listed from `pg_stat_replication`, which only shows the details of other roles' standbys
+//! to roles with the privileges of `pg_read_all_stats`. Every query is a checked command.
+
+use pgx::SpiClient;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::rewrite;
+use crate::throttle::sleep;
+
+/// Location in the write-ahead log, as in `pg_lsn`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lsn(pub u64);
+
+/// Text that is not an LSN in the `X/Y` form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidLsn(pub String);
+
+impl Display for InvalidLsn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid LSN \"{}\"", self.0)
+    }
+}
+
+impl std::error::Error for InvalidLsn {}
+
+impl FromStr for Lsn {
+    type Err = InvalidLsn;
+
+    /// Parse the `X/Y` form Postgres shows LSNs in, both halves being up to 8 hexadecimal digits
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let half = |half: &str| {
+            (!half.is_empty() && half.len() <= 8 && half.bytes().all(|b| b.is_ascii_hexdigit()))
+                .then(|| u64::from_str_radix(half, 16).ok())
+                .flatten()
+        };
+        s.split_once('/')
+            .and_then(|(high, low)| Some(Lsn(half(high)? << 32 | half(low)?)))
+            .ok_or_else(|| InvalidLsn(s.to_string()))
+    }
+}
+
+impl Display for Lsn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:X}/{:X}", self.0 >> 32, self.0 & 0xFFFF_FFFF)
+    }
+}
+
+/// Standby connected to this server, from `pg_stat_replication`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StandbyStatus {
+    pub pid: i32,
+    pub application_name: Option<String>,
+    pub client_addr: Option<String>,
+    /// Such as `streaming` or `catchup`
+    pub state: Option<String>,
+    /// Such as `async` or `sync`
+    pub sync_state: Option<String>,
+    pub sent_lsn: Option<Lsn>,
+    pub write_lsn: Option<Lsn>,
+    pub flush_lsn: Option<Lsn>,
+    pub replay_lsn: Option<Lsn>,
+    /// `None` once the standby has caught up and has been idle for a while, as well as when it's not shown
+    pub write_lag: Option<Duration>,
+    pub flush_lag: Option<Duration>,
+    pub replay_lag: Option<Duration>,
+}
+
+impl StandbyStatus {
+    /// Largest of the standby's lags, or zero if none is reported
+    pub fn worst_lag(&self) -> Duration {
+        [self.write_lag, self.flush_lag, self.replay_lag]
+            .into_iter()
+            .flatten()
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Replication state of the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationStatus {
+    /// The server is a primary, with these standbys connected to it
+    Primary {
+        current_lsn: Lsn,
+        standbys: Vec<StandbyStatus>,
+    },
+    /// The server is a standby
+    Standby {
+        /// Last location received and flushed, or `None` if it doesn't stream from a primary
+        receive_lsn: Option<Lsn>,
+        /// Last location replayed
+        replay_lsn: Option<Lsn>,
+        /// Time since the last replayed transaction was committed on the primary, if any was replayed
+        replay_lag: Option<Duration>,
+    },
+}
+
+/// Get the replication state of the server
+pub fn status(client: &SpiClient) -> Result<ReplicationStatus, Error> {
+    let in_recovery =
+        rewrite::exempt(|| client.checked_select("SELECT pg_is_in_recovery()", None, None))?
+            .first()
+            .get_one::<bool>()
+            .unwrap_or(false);
+    if in_recovery {
+        let table = rewrite::exempt(|| {
+            client.checked_select(
+                "SELECT pg_last_wal_receive_lsn()::text, pg_last_wal_replay_lsn()::text, \
+             extract(epoch FROM now() - pg_last_xact_replay_timestamp())::float8",
+                None,
+                None,
+            )
+        })?;
+        let (receive_lsn, replay_lsn, replay_lag) =
+            table.first().get_three::<String, String, f64>();
+        return Ok(ReplicationStatus::Standby {
+            receive_lsn: receive_lsn.and_then(|lsn| lsn.parse().ok()),
+            replay_lsn: replay_lsn.and_then(|lsn| lsn.parse().ok()),
+            replay_lag: replay_lag.map(seconds),
+        });
+    }
+    let current_lsn =
+        rewrite::exempt(|| client.checked_select("SELECT pg_current_wal_lsn()::text", None, None))?
+            .first()
+            .get_one::<String>()
+            .and_then(|lsn| lsn.parse().ok())
+            .unwrap_or(Lsn(0));
+    let standbys = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT pid, application_name, client_addr::text, state, sync_state, sent_lsn::text, \
+             write_lsn::text, flush_lsn::text, replay_lsn::text, extract(epoch FROM write_lag)::float8, \
+             extract(epoch FROM flush_lag)::float8, extract(epoch FROM replay_lag)::float8 \
+             FROM pg_stat_replication ORDER BY pid",
+            None,
+            None,
+        )
+    })?
+    .filter_map(|row| {
+        let text = |ordinal| row.by_ordinal(ordinal).ok()?.value::<String>();
+        let lsn = |ordinal| text(ordinal)?.parse().ok();
+        let lag = |ordinal| Some(seconds(row.by_ordinal(ordinal).ok()?.value::<f64>()?));
+        Some(StandbyStatus {
+            pid: row.by_ordinal(1).ok()?.value()?,
+            application_name: text(2),
+            client_addr: text(3),
+            state: text(4),
+            sync_state: text(5),
+            sent_lsn: lsn(6),
+            write_lsn: lsn(7),
+            flush_lsn: lsn(8),
+            replay_lsn: lsn(9),
+            write_lag: lag(10),
+            flush_lag: lag(11),
+            replay_lag: lag(12),
+        })
+    })
+    .collect();
+    Ok(ReplicationStatus::Primary {
+        current_lsn,
+        standbys,
+    })
+}
+
+/// Duration of `seconds`, or zero if it's negative (such as when clocks are skewed)
+fn seconds(seconds: f64) -> Duration {
+    if seconds > 0.0 && seconds.is_finite() {
+        Duration::from_secs_f64(seconds)
+    } else {
+        Duration::ZERO
+    }
+}
+
+/// Error returned by [`wait_for_lag_below`]
+#[derive(Debug)]
+pub enum WaitError {
+    /// The timeout elapsed while a standby still lagged this much
+    Timeout { worst_lag: Duration },
+    /// The server is a standby
+    NotPrimary,
+    /// A query failed, or the wait was canceled
+    Query(Error),
+}
+
+impl Display for WaitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitError::Timeout { worst_lag } => {
+                write!(
+                    f,
+                    "timed out waiting for standbys, which lag up to {:?}",
+                    worst_lag
+                )
+            }
+            WaitError::NotPrimary => write!(f, "server is not a primary"),
+            WaitError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for WaitError {
+    fn from(err: Error) -> Self {
+        WaitError::Query(err)
+    }
+}
+
+/// Wait until no standby lags `threshold` or more, checking every `poll`, for at most `timeout`
+///
+/// Returns right away if no standby is connected. Interrupts are checked while sleeping between checks, so the wait
+/// can be canceled: a query cancel or a shutdown request is raised again, and other errors interrupting the sleep are
+/// returned as [`WaitError::Query`].
+pub fn wait_for_lag_below(
+    client: &SpiClient,
+    threshold: Duration,
+    timeout: Duration,
+    poll: Duration,
+) -> Result<(), WaitError> {
+    let started = Instant::now();
+    loop {
+        let worst_lag = match status(client)? {
+            ReplicationStatus::Primary { standbys, .. } => standbys
+                .iter()
+                .map(StandbyStatus::worst_lag)
+                .max()
+                .unwrap_or_default(),
+            ReplicationStatus::Standby { .. } => return Err(WaitError::NotPrimary),
+        };
+        if worst_lag < threshold {
+            return Ok(());
+        }
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            return Err(WaitError::Timeout { worst_lag });
+        }
+        sleep(poll.min(remaining))?;
+    }
+}
@@ -0,0 +1,263 @@
+//! Checking the server configuration an extension needs before it does any work
+//!
+//! Requirements are all evaluated, with checked commands, so that every unmet one can be reported at once rather
+//! than failing later with obscure errors.
+
+use pgx::{IntoDatum, PgBuiltInOids, SpiClient};
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::{raise, Error, SqlState};
+use crate::rewrite;
+
+/// Server configuration required by [`check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requirement<'a> {
+    /// The setting with this name has this value (compared case-insensitively, as shown by `current_setting`)
+    GucEquals(&'a str, &'a str),
+    /// The setting with this name is at least this value
+    ///
+    /// Values can have units, such as `64MB` or `30s`: memory units are converted to bytes and time units to
+    /// microseconds before comparing (as `current_setting` shows settings with units in the unit that suits their
+    /// value). Values with units are only compared with values with units of the same kind.
+    GucAtLeast(&'a str, &'a str),
+    /// The extension with this name is installed in the current database, in at least this version if one is given
+    ///
+    /// Versions are compared component by component (split on `.`), numerically where both components are numbers.
+    ExtensionInstalled(&'a str, Option<&'a str>),
+    /// The library with this name is in `shared_preload_libraries` (with or without a `$libdir/` prefix)
+    SharedPreloadLibrary(&'a str),
+    /// The server's version, as in `server_version_num` (such as `140005` for 14.5), is at least this one
+    ServerVersionAtLeast(i32),
+}
+
+impl<'a> Display for Requirement<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Requirement::GucEquals(name, value) => write!(f, "{} = {}", name, value),
+            Requirement::GucAtLeast(name, minimum) => write!(f, "{} >= {}", name, minimum),
+            Requirement::ExtensionInstalled(name, None) => write!(f, "extension \"{}\"", name),
+            Requirement::ExtensionInstalled(name, Some(version)) => {
+                write!(f, "extension \"{}\" >= {}", name, version)
+            }
+            Requirement::SharedPreloadLibrary(name) => {
+                write!(f, "\"{}\" in shared_preload_libraries", name)
+            }
+            Requirement::ServerVersionAtLeast(version) => {
+                write!(f, "server_version_num >= {}", version)
+            }
+        }
+    }
+}
+
+/// What was observed when a requirement was evaluated
+#[derive(Debug)]
+pub enum Observed {
+    /// The setting or extension doesn't exist
+    Missing,
+    /// The setting's value, the extension's version, `shared_preload_libraries` or `server_version_num`
+    Value(String),
+    /// The command evaluating the requirement failed
+    Failed(Error),
+}
+
+/// Requirement that is not met, along with what was observed instead
+#[derive(Debug)]
+pub struct RequirementFailure<'a> {
+    pub requirement: Requirement<'a>,
+    pub observed: Observed,
+}
+
+impl<'a> RequirementFailure<'a> {
+    /// How to meet the requirement
+    pub fn remediation(&self) -> String {
+        match (&self.requirement, &self.observed) {
+            (_, Observed::Failed(_)) => format!("check that {} can be evaluated", self.requirement),
+            (Requirement::GucEquals(name, value), _) => format!(
+                "set {} to '{}' (e.g. with ALTER SYSTEM) and reload or restart the server",
+                name, value
+            ),
+            (Requirement::GucAtLeast(name, minimum), _) => format!(
+                "set {} to at least '{}' (e.g. with ALTER SYSTEM) and reload or restart the server",
+                name, minimum
+            ),
+            (Requirement::ExtensionInstalled(name, _), Observed::Missing) => {
+                format!("run CREATE EXTENSION {}", name)
+            }
+            (Requirement::ExtensionInstalled(name, _), _) => {
+                format!("run ALTER EXTENSION {} UPDATE", name)
+            }
+            (Requirement::SharedPreloadLibrary(name), _) => format!(
+                "add '{}' to shared_preload_libraries and restart the server",
+                name
+            ),
+            (Requirement::ServerVersionAtLeast(version), _) => {
+                format!("upgrade the server to version {} or later", version)
+            }
+        }
+    }
+}
+
+impl<'a> Display for RequirementFailure<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is required", self.requirement)?;
+        match &self.observed {
+            Observed::Missing => match self.requirement {
+                Requirement::ExtensionInstalled(..) => write!(f, ", but it is not installed"),
+                _ => write!(f, ", but the setting does not exist"),
+            },
+            Observed::Value(value) => write!(f, ", but found {}", value),
+            Observed::Failed(err) => write!(f, ", but checking failed: {}", err),
+        }
+    }
+}
+
+/// Evaluate all of `reqs`, returning those that aren't met, in order
+pub fn check<'a>(
+    client: &SpiClient,
+    reqs: &[Requirement<'a>],
+) -> Result<(), Vec<RequirementFailure<'a>>> {
+    let failures: Vec<_> = reqs
+        .iter()
+        .filter_map(|requirement| {
+            let observed = match observe(client, requirement) {
+                Ok(Some(value)) if is_met(requirement, &value) => return None,
+                Ok(Some(value)) => Observed::Value(value),
+                Ok(None) => Observed::Missing,
+                Err(err) => Observed::Failed(err),
+            };
+            Some(RequirementFailure {
+                requirement: *requirement,
+                observed,
+            })
+        })
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+/// Evaluate all of `reqs`, raising a single error listing those that aren't met, with a hint on how to meet them
+///
+/// The error's SQLSTATE is `55000` (object not in prerequisite state).
+pub fn assert_or_error(client: &SpiClient, reqs: &[Requirement]) {
+    if let Err(failures) = check(client, reqs) {
+        let list = |line: &dyn Fn(&RequirementFailure) -> String| {
+            failures.iter().map(line).collect::<Vec<_>>().join("\n")
+        };
+        raise(
+            SqlState::parse("55000").unwrap(),
+            &format!("{} requirement(s) not met", failures.len()),
+            Some(&list(&|failure| failure.to_string())),
+            None,
+            Some(&list(&|failure| failure.remediation())),
+        )
+    }
+}
+
+/// Observe the value the requirement is evaluated against, if it exists
+fn observe(client: &SpiClient, requirement: &Requirement) -> Result<Option<String>, Error> {
+    let (query, arg) = match requirement {
+        Requirement::GucEquals(name, _) | Requirement::GucAtLeast(name, _) => {
+            ("SELECT current_setting($1, true)", Some(*name))
+        }
+        Requirement::ExtensionInstalled(name, _) => (
+            "SELECT extversion FROM pg_extension WHERE extname = $1",
+            Some(*name),
+        ),
+        Requirement::SharedPreloadLibrary(_) => (
+            "SELECT setting FROM pg_settings WHERE name = 'shared_preload_libraries'",
+            None,
+        ),
+        Requirement::ServerVersionAtLeast(_) => {
+            ("SELECT current_setting('server_version_num')", None)
+        }
+    };
+    let args = arg.map(|arg| vec![(PgBuiltInOids::TEXTOID.oid(), arg.into_datum())]);
+    Ok(
+        rewrite::exempt(|| client.checked_select(query, None, args))?
+            .first()
+            .get_one::<String>(),
+    )
+}
+
+fn is_met(requirement: &Requirement, value: &str) -> bool {
+    match requirement {
+        Requirement::GucEquals(_, expected) => value.eq_ignore_ascii_case(expected),
+        Requirement::GucAtLeast(_, minimum) => {
+            match (parse_quantity(value), parse_quantity(minimum)) {
+                (Some((value, value_unit)), Some((minimum, minimum_unit))) => {
+                    value_unit == minimum_unit && value >= minimum
+                }
+                _ => false,
+            }
+        }
+        Requirement::ExtensionInstalled(_, None) => true,
+        Requirement::ExtensionInstalled(_, Some(minimum)) => {
+            compare_versions(value, minimum) != Ordering::Less
+        }
+        Requirement::SharedPreloadLibrary(name) => value.split(',').any(|library| {
+            let library = library.trim().trim_matches('"');
+            library.strip_prefix("$libdir/").unwrap_or(library) == *name
+        }),
+        Requirement::ServerVersionAtLeast(minimum) => value
+            .parse::<i32>()
+            .map_or(false, |version| version >= *minimum),
+    }
+}
+
+/// Kind of unit of a setting's value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnitKind {
+    None,
+    Memory,
+    Time,
+}
+
+/// Parse a setting's value, with an optional unit, into a number of bytes, microseconds or no unit
+fn parse_quantity(value: &str) -> Option<(f64, UnitKind)> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(value.len());
+    let number: f64 = value[..split].trim().parse().ok()?;
+    let (factor, kind) = match &value[split..] {
+        "" => (1.0, UnitKind::None),
+        "B" => (1.0, UnitKind::Memory),
+        "kB" => (1024.0, UnitKind::Memory),
+        "MB" => (1024.0 * 1024.0, UnitKind::Memory),
+        "GB" => (1024.0 * 1024.0 * 1024.0, UnitKind::Memory),
+        "TB" => (1024.0 * 1024.0 * 1024.0 * 1024.0, UnitKind::Memory),
+        "us" => (1.0, UnitKind::Time),
+        "ms" => (1_000.0, UnitKind::Time),
+        "s" => (1_000_000.0, UnitKind::Time),
+        "min" => (60_000_000.0, UnitKind::Time),
+        "h" => (3_600_000_000.0, UnitKind::Time),
+        "d" => (86_400_000_000.0, UnitKind::Time),
+        _ => return None,
+    };
+    Some((number * factor, kind))
+}
+
+/// Compare extension versions component by component
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a = a.split('.');
+    let mut b = b.split('.');
+    loop {
+        let ordering = match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (Some(_), None) => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            },
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Statement rewriting hooks
+//!
+//! Hooks registered with [`register`] rewrite the text of every command executed with `checked_select` and
+//! `checked_update` (including [`SubTransaction::select`](crate::subtxn::SubTransaction::select) and
+//! [`SubTransaction::update`](crate::subtxn::SubTransaction::update)), in the order they were registered.
+//! [`RawCommands`] execute commands as they are, as do the catalog queries this crate executes on its own behalf,
+//! whose results it relies on.
+//!
+//! Hooks are backend-local. Postgres reports errors with the rewritten text, which is what was executed; the original
+//! text of a failed command is available with [`Error::original_query`].
+
+use pgx::{pg_sys::Datum, PgOid, SpiClient, SpiTupleTable};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+
+use crate::checked::{execute_checked_select, execute_checked_update};
+use crate::error::Error;
+use crate::scan::is_empty_query;
+use crate::subtxn::{check_can_begin, SubTransactionExt};
+
+/// Statement rewriting hook, returning the text to execute instead of the given one
+pub type Hook = Box<dyn Fn(&str) -> Cow<'_, str>>;
+
+/// Identifies a registered hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(u64);
+
+thread_local! {
+    static HOOKS: RefCell<Vec<(HookId, Rc<Hook>)>> = RefCell::new(Vec::new());
+    static NEXT_ID: Cell<u64> = Cell::new(1);
+    static EXEMPT: Cell<bool> = Cell::new(false);
+}
+
+/// Register a hook, applied after those registered before it
+pub fn register(hook: Hook) -> HookId {
+    let id = HookId(NEXT_ID.with(|next| next.replace(next.get() + 1)));
+    HOOKS.with(|hooks| hooks.borrow_mut().push((id, Rc::new(hook))));
+    id
+}
+
+/// Unregister a hook, returning whether it was registered
+pub fn unregister(id: HookId) -> bool {
+    HOOKS.with(|hooks| {
+        let mut hooks = hooks.borrow_mut();
+        let len = hooks.len();
+        hooks.retain(|(hook_id, _)| *hook_id != id);
+        hooks.len() != len
+    })
+}
+
+/// Pass `query` through the registered hooks
+///
+/// A hook that panics is unregistered with a warning, and the text is passed on to the next hook as it was.
+pub(crate) fn apply(query: &str) -> Cow<'_, str> {
+    if EXEMPT.with(Cell::get) {
+        return Cow::Borrowed(query);
+    }
+    // Hooks may register or unregister hooks themselves
+    let hooks = HOOKS.with(|hooks| hooks.borrow().clone());
+    let mut query = Cow::Borrowed(query);
+    for (id, hook) in hooks {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| (*hook)(&query).into_owned())) {
+            Ok(rewritten) => {
+                if rewritten != *query {
+                    query = Cow::Owned(rewritten);
+                }
+            }
+            Err(_) => {
+                unregister(id);
+                pgx::warning!("statement rewriting hook panicked and was unregistered");
+            }
+        }
+    }
+    query
+}
+
+/// Run `f` without hooks rewriting the commands it executes, for catalog queries this crate executes on its own behalf
+pub(crate) fn exempt<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = ExemptGuard {
+        previous: EXEMPT.with(|exempt| exempt.replace(true)),
+    };
+    f()
+}
+
+/// Restores whether hooks apply when dropped, even if an error unwinds through [`exempt`]
+struct ExemptGuard {
+    previous: bool,
+}
+
+impl Drop for ExemptGuard {
+    fn drop(&mut self) {
+        EXEMPT.with(|exempt| exempt.set(self.previous));
+    }
+}
+
+/// Attach the original text of a rewritten command that failed with `err` to it
+pub(crate) fn record_original(err: &mut Error, original: &str) {
+    if let Error::Caught(_, captured) | Error::ReadOnlyViolation(_, captured) = err {
+        captured.original_query = Some(original.to_string());
+    }
+}
+
+impl Error {
+    /// Text of the failed command as given, before it was rewritten by hooks
+    ///
+    /// `None` if the command was not rewritten (or failed before it was executed).
+    pub fn original_query(&self) -> Option<&str> {
+        self.captured()?.original_query.as_deref()
+    }
+}
+
+/// Checked commands that bypass statement rewriting hooks
+pub trait RawCommands {
+    /// Execute a read-only command as it is, returning an error if one occurred.
+    fn checked_select_raw(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<SpiTupleTable, Error>;
+
+    /// Execute a mutable command as it is, returning an error if one occurred.
+    fn checked_update_raw(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<SpiTupleTable, Error>;
+}
+
+impl RawCommands for SpiClient {
+    fn checked_select_raw(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery);
+        }
+        check_can_begin()?;
+        SpiClient
+            .sub_transaction(|xact| {
+                execute_checked_select(xact.rollback_on_drop(), query, limit, args)
+            })
+            .map(|(table, _, xact)| {
+                xact.commit();
+                table
+            })
+    }
+
+    fn checked_update_raw(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery);
+        }
+        check_can_begin()?;
+        SpiClient
+            .sub_transaction(|xact| {
+                execute_checked_update(xact.rollback_on_drop(), query, limit, args)
+            })
+            .map(|(table, _, xact)| {
+                xact.commit();
+                table
+            })
+    }
+}
@@ -0,0 +1,174 @@
+//! Row-level security aware execution
+//!
+//! Whether row security policies apply to a query depends on the current role as well as on `row_security`: superusers
+//! and roles with `BYPASSRLS` are never subject to them, and neither are table owners unless the table has
+//! `FORCE ROW LEVEL SECURITY`. Table names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier).
+
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{pg_sys, IntoDatum, PgBuiltInOids, PgOid, SpiClient, SpiTupleTable};
+use std::fmt::{Display, Formatter};
+use std::panic::AssertUnwindSafe;
+
+use crate::checked::*;
+use crate::error::{report, Error, PostgresErrorExt};
+use crate::guc::with_local;
+use crate::quote::*;
+use crate::rewrite;
+use crate::scan::is_empty_query;
+
+/// How [`RlsCommands::checked_select_rls`] treats row security policies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RlsMode {
+    /// Apply policies (`row_security = on`), failing if the current role bypasses them regardless
+    Force,
+    /// Don't apply policies (`row_security = off`), failing if the current role is subject to any of them
+    Bypass,
+    /// Leave `row_security` as it is
+    Default,
+}
+
+/// Row-level security aware execution error
+#[derive(Debug)]
+pub enum RlsError {
+    /// Policies can't be forced, as the current role is a superuser or has `BYPASSRLS`
+    BypassNotPossible,
+    /// The current role lacks a privilege (SQLSTATE `42501`), such as bypassing the policies of a table under
+    /// [`RlsMode::Bypass`]
+    InsufficientPrivilege(CaughtError),
+    /// The command failed otherwise
+    Query(Error),
+}
+
+impl Display for RlsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RlsError::BypassNotPossible => {
+                write!(
+                    f,
+                    "row security can't be forced, the current role bypasses it"
+                )
+            }
+            RlsError::InsufficientPrivilege(err) => write!(f, "{}", report(err).message()),
+            RlsError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for RlsError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Caught(err, _) if report(&err).sqlstate().as_str() == "42501" => {
+                RlsError::InsufficientPrivilege(err)
+            }
+            err => RlsError::Query(err),
+        }
+    }
+}
+
+/// Row security policy, as listed in `pg_policies`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyInfo {
+    pub name: String,
+    /// Whether the policy is permissive (combined with others with `OR`) rather than restrictive (with `AND`)
+    pub permissive: bool,
+    /// Command the policy applies to: `ALL`, `SELECT`, `INSERT`, `UPDATE` or `DELETE`
+    pub command: String,
+    /// Roles the policy applies to, `public` for all of them
+    pub roles: Vec<String>,
+    /// `USING` expression, if any
+    pub using: Option<String>,
+    /// `WITH CHECK` expression, if any
+    pub with_check: Option<String>,
+}
+
+/// Checked commands with control over row security policies
+pub trait RlsCommands {
+    /// Execute a read-only command with row security policies applied or bypassed according to `mode`, returning an
+    /// error if one occurred.
+    ///
+    /// `row_security` is set for the duration of the command only, in the command's sub-transaction. Under
+    /// [`RlsMode::Force`], table owners still see all rows of their tables unless they have
+    /// `FORCE ROW LEVEL SECURITY`.
+    fn checked_select_rls(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        mode: RlsMode,
+    ) -> Result<SpiTupleTable, RlsError>;
+}
+
+impl RlsCommands for SpiClient {
+    fn checked_select_rls(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        mode: RlsMode,
+    ) -> Result<SpiTupleTable, RlsError> {
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery.into());
+        }
+        let row_security = match mode {
+            RlsMode::Force if bypasses_rls()? => return Err(RlsError::BypassNotPossible),
+            RlsMode::Force => Some("on"),
+            RlsMode::Bypass => Some("off"),
+            RlsMode::Default => None,
+        };
+        let args = AssertUnwindSafe(args);
+        Ok(checked_sub_transaction(move |client| {
+            let args = args;
+            match row_security {
+                Some(row_security) => with_local(client, "row_security", row_security, |client| {
+                    client.select(query, None, args.0)
+                }),
+                None => client.select(query, None, args.0),
+            }
+        })?)
+    }
+}
+
+/// Whether the current role bypasses all row security policies
+fn bypasses_rls() -> Result<bool, Error> {
+    Ok(rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "SELECT rolsuper OR rolbypassrls FROM pg_roles WHERE rolname = current_user",
+            Some(1),
+            None,
+        )
+    })?
+    .first()
+    .get_one::<bool>()
+    .unwrap_or_default())
+}
+
+/// Policies on `table` that apply to the current role, directly or through a role it is a member of, ordered by name
+///
+/// Policies are listed whether or not row-level security is enabled on the table.
+pub fn policies_applied(client: &SpiClient, table: &str) -> Result<Vec<PolicyInfo>, Error> {
+    let table = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT p.policyname::text, p.permissive = 'PERMISSIVE', p.cmd, p.roles::text[], p.qual, p.with_check \
+             FROM pg_policies p \
+             JOIN pg_namespace n ON n.nspname = p.schemaname \
+             JOIN pg_class c ON c.relnamespace = n.oid AND c.relname = p.tablename \
+             WHERE c.oid = $1::regclass \
+             AND EXISTS (SELECT FROM unnest(p.roles) r WHERE r = 'public' OR pg_has_role(current_user, r, 'MEMBER')) \
+             ORDER BY 1",
+            None,
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                quote_qualified_identifier(table).into_datum(),
+            )]),
+        )
+    })?;
+    Ok(table
+        .map(|row| PolicyInfo {
+            name: row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+            permissive: row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+            command: row.by_ordinal(3).unwrap().value().unwrap_or_default(),
+            roles: row.by_ordinal(4).unwrap().value().unwrap_or_default(),
+            using: row.by_ordinal(5).unwrap().value(),
+            with_check: row.by_ordinal(6).unwrap().value(),
+        })
+        .collect())
+}
@@ -0,0 +1,511 @@
+use pgx::{pg_sys, FromDatum, IntoDatum, PgOid, SpiClient, SpiHeapTupleData, SpiTupleTable};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+
+/// Unexpected NULL encountered during strict extraction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NullViolation {
+    /// Name of the column that contained NULL
+    pub column: String,
+    /// Zero-based index of the row in the result set
+    pub row: usize,
+    /// Query text, if extraction was done through a checked call
+    pub query: Option<String>,
+}
+
+impl Display for NullViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unexpected NULL in column \"{}\" at row {}",
+            self.column, self.row
+        )?;
+        if let Some(query) = &self.query {
+            write!(f, " (query: {})", query)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NullViolation {}
+
+/// Row that couldn't be converted with [`FromSpiRow`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowConversionError {
+    /// Unexpected NULL encountered during strict extraction
+    Null(NullViolation),
+    /// The column a field of a type deriving `FromSpiRow` is read from is NULL, and the field isn't an `Option`
+    NullField {
+        /// Name of the type the row was converted into
+        type_name: &'static str,
+        field: &'static str,
+        column: String,
+        /// Zero-based index of the row in the result set
+        row: usize,
+    },
+    /// The row has no column a field of a type deriving `FromSpiRow` is read from, and the field has no default
+    MissingColumn {
+        /// Name of the type the row was converted into
+        type_name: &'static str,
+        field: &'static str,
+        column: String,
+        /// Zero-based index of the row in the result set
+        row: usize,
+    },
+}
+
+impl Display for RowConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RowConversionError::Null(err) => write!(f, "{}", err),
+            RowConversionError::NullField {
+                type_name,
+                field,
+                column,
+                row,
+            } => write!(
+                f,
+                "unexpected NULL in column \"{}\" at row {} for field {}::{}",
+                column, row, type_name, field
+            ),
+            RowConversionError::MissingColumn {
+                type_name,
+                field,
+                column,
+                row,
+            } => write!(
+                f,
+                "no column \"{}\" at row {} for field {}::{}",
+                column, row, type_name, field
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RowConversionError {}
+
+impl From<NullViolation> for RowConversionError {
+    fn from(err: NullViolation) -> Self {
+        RowConversionError::Null(err)
+    }
+}
+
+/// Column requested by position that the row doesn't have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnOutOfRange {
+    /// Position of the requested column, as it was given: 1-based with `by_ordinal_1based`, 0-based with
+    /// `by_index_0based`
+    pub requested: usize,
+    /// Number of columns of the row
+    pub available: usize,
+}
+
+impl ColumnOutOfRange {
+    /// Check that the row has a column with the (1-based) `ordinal`, reporting `requested` otherwise
+    pub(crate) fn check(ordinal: usize, requested: usize, available: usize) -> Result<(), Self> {
+        if (1..=available).contains(&ordinal) {
+            Ok(())
+        } else {
+            Err(Self {
+                requested,
+                available,
+            })
+        }
+    }
+}
+
+impl Display for ColumnOutOfRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "column {} is out of range, the row has {} columns",
+            self.requested, self.available
+        )
+    }
+}
+
+impl std::error::Error for ColumnOutOfRange {}
+
+/// Value of a column that tells a NULL value from a missing row
+///
+/// Unlike `Option<T>`, which the `get_one`-style helpers return for both, `SqlValue` keeps apart what SQL keeps apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlValue<T> {
+    /// The column has this value
+    Value(T),
+    /// The column is NULL
+    Null,
+    /// There is no row to take the column from
+    NoRow,
+}
+
+impl<T> SqlValue<T> {
+    /// The value, `default` if there is no row, or `None` if the value is NULL
+    ///
+    /// Only a missing row is defaulted: a row that is there with a NULL value stays NULL.
+    pub fn or_default(self, default: T) -> Option<T> {
+        match self {
+            SqlValue::Value(value) => Some(value),
+            SqlValue::Null => None,
+            SqlValue::NoRow => Some(default),
+        }
+    }
+
+    /// The value, failing if it is NULL or there is no row
+    pub fn require(self) -> Result<T, MissingValue> {
+        match self {
+            SqlValue::Value(value) => Ok(value),
+            SqlValue::Null => Err(MissingValue::Null),
+            SqlValue::NoRow => Err(MissingValue::NoRow),
+        }
+    }
+}
+
+impl<T> From<Option<Option<T>>> for SqlValue<T> {
+    /// Convert from the outer `None` for no row and the inner one for NULL, as `checked_get_one_fast` returns
+    fn from(value: Option<Option<T>>) -> Self {
+        match value {
+            Some(Some(value)) => SqlValue::Value(value),
+            Some(None) => SqlValue::Null,
+            None => SqlValue::NoRow,
+        }
+    }
+}
+
+impl<T> From<SqlValue<T>> for Option<Option<T>> {
+    /// Convert to the outer `None` for no row and the inner one for NULL
+    fn from(value: SqlValue<T>) -> Self {
+        match value {
+            SqlValue::Value(value) => Some(Some(value)),
+            SqlValue::Null => Some(None),
+            SqlValue::NoRow => None,
+        }
+    }
+}
+
+/// Value required with [`SqlValue::require`] that is missing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingValue {
+    /// The column is NULL
+    Null,
+    /// There is no row
+    NoRow,
+}
+
+impl Display for MissingValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MissingValue::Null => write!(f, "value is NULL"),
+            MissingValue::NoRow => write!(f, "no row was returned"),
+        }
+    }
+}
+
+impl std::error::Error for MissingValue {}
+
+/// Column requested by name that the row doesn't have
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnError {
+    /// Name of the requested column
+    pub column: String,
+}
+
+impl Display for ColumnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no column named \"{}\"", self.column)
+    }
+}
+
+impl std::error::Error for ColumnError {}
+
+/// A row of a result set along with its position in it
+///
+/// Columns are best accessed by name. When accessed by position, the method names the convention:
+/// [`by_ordinal_1based`](Self::by_ordinal_1based) counts from 1, as SQL and pgx's `by_ordinal` do, and
+/// [`by_index_0based`](Self::by_index_0based) from 0, as [`columns`](Self::columns) does.
+pub struct SpiRow<'a> {
+    tuple: &'a SpiHeapTupleData,
+    columns: &'a [String],
+    index: usize,
+}
+
+impl<'a> SpiRow<'a> {
+    pub(crate) fn new(tuple: &'a SpiHeapTupleData, columns: &'a [String], index: usize) -> Self {
+        Self {
+            tuple,
+            columns,
+            index,
+        }
+    }
+
+    /// Zero-based index of the row in the result set
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Names of the columns, in order
+    pub fn columns(&self) -> &[String] {
+        self.columns
+    }
+
+    /// Get a column's value by name, returning `None` if it is NULL
+    ///
+    /// Panics if there is no such column (see [`try_get`](Self::try_get)).
+    pub fn get<T: FromDatum + IntoDatum>(&self, column: &str) -> Option<T> {
+        self.get_by_ordinal(self.ordinal(column))
+    }
+
+    /// Get a column's value by name, as [`SqlValue::Value`] or [`SqlValue::Null`]
+    pub fn try_get<T: FromDatum + IntoDatum>(
+        &self,
+        column: &str,
+    ) -> Result<SqlValue<T>, ColumnError> {
+        let ordinal = self
+            .columns
+            .iter()
+            .position(|name| name == column)
+            .ok_or_else(|| ColumnError {
+                column: column.to_string(),
+            })?
+            + 1;
+        Ok(self
+            .get_by_ordinal(ordinal)
+            .map_or(SqlValue::Null, SqlValue::Value))
+    }
+
+    /// Get a column's value by name, failing if it is NULL
+    ///
+    /// Panics if there is no such column.
+    pub fn strict_get<T: FromDatum + IntoDatum>(&self, column: &str) -> Result<T, NullViolation> {
+        self.strict_get_by_ordinal(self.ordinal(column))
+    }
+
+    /// Get the value of the column with the 1-based `ordinal`, returning `None` if it is NULL
+    pub fn by_ordinal_1based<T: FromDatum + IntoDatum>(
+        &self,
+        ordinal: usize,
+    ) -> Result<Option<T>, ColumnOutOfRange> {
+        ColumnOutOfRange::check(ordinal, ordinal, self.columns.len())?;
+        Ok(self.get_by_ordinal(ordinal))
+    }
+
+    /// Get the value of the column with the 0-based `index`, returning `None` if it is NULL
+    pub fn by_index_0based<T: FromDatum + IntoDatum>(
+        &self,
+        index: usize,
+    ) -> Result<Option<T>, ColumnOutOfRange> {
+        let ordinal = index.saturating_add(1);
+        ColumnOutOfRange::check(ordinal, index, self.columns.len())?;
+        Ok(self.get_by_ordinal(ordinal))
+    }
+
+    /// Get a column's value by its (1-based) ordinal, returning `None` if it is NULL
+    ///
+    /// Panics if there is no such column (see [`by_ordinal_1based`](Self::by_ordinal_1based)).
+    pub fn get_by_ordinal<T: FromDatum + IntoDatum>(&self, ordinal: usize) -> Option<T> {
+        self.tuple
+            .by_ordinal(ordinal)
+            .unwrap_or_else(|_| panic!("no column with ordinal {}", ordinal))
+            .value::<T>()
+    }
+
+    /// Get a column's value by its (1-based) ordinal, failing if it is NULL
+    ///
+    /// Panics if there is no such column.
+    pub fn strict_get_by_ordinal<T: FromDatum + IntoDatum>(
+        &self,
+        ordinal: usize,
+    ) -> Result<T, NullViolation> {
+        self.get_by_ordinal(ordinal)
+            .ok_or_else(|| self.violation(ordinal))
+    }
+
+    /// Whether the row has a column named `column`
+    pub fn has_column(&self, column: &str) -> bool {
+        self.columns.iter().any(|name| name == column)
+    }
+
+    /// Get the value of `column` for `field` of `type_name`, failing if it is NULL or missing
+    #[doc(hidden)]
+    pub fn derived_get<T: FromDatum + IntoDatum>(
+        &self,
+        type_name: &'static str,
+        field: &'static str,
+        column: &str,
+    ) -> Result<T, RowConversionError> {
+        self.derived_get_nullable(type_name, field, column)?
+            .ok_or_else(|| RowConversionError::NullField {
+                type_name,
+                field,
+                column: column.to_string(),
+                row: self.index,
+            })
+    }
+
+    /// Get the value of `column` for `field` of `type_name`, returning `None` if it is NULL and failing if it is
+    /// missing
+    #[doc(hidden)]
+    pub fn derived_get_nullable<T: FromDatum + IntoDatum>(
+        &self,
+        type_name: &'static str,
+        field: &'static str,
+        column: &str,
+    ) -> Result<Option<T>, RowConversionError> {
+        match self.try_get(column) {
+            Ok(SqlValue::Value(value)) => Ok(Some(value)),
+            Ok(_) => Ok(None),
+            Err(_) => Err(RowConversionError::MissingColumn {
+                type_name,
+                field,
+                column: column.to_string(),
+                row: self.index,
+            }),
+        }
+    }
+
+    pub(crate) fn is_null(&self, ordinal: usize) -> bool {
+        self.get_by_ordinal::<pg_sys::Datum>(ordinal).is_none()
+    }
+
+    fn ordinal(&self, column: &str) -> usize {
+        self.columns
+            .iter()
+            .position(|name| name == column)
+            .map(|pos| pos + 1)
+            .unwrap_or_else(|| panic!("no column named \"{}\"", column))
+    }
+
+    fn violation(&self, ordinal: usize) -> NullViolation {
+        NullViolation {
+            column: self.columns[ordinal - 1].clone(),
+            row: self.index,
+            query: None,
+        }
+    }
+}
+
+/// Conversion of a result set's row into a Rust value
+///
+/// With the `derive` feature, it can be derived for structs with named fields, reading each field from the column with
+/// its name.
+pub trait FromSpiRow: Sized {
+    fn from_spi_row(row: &SpiRow) -> Result<Self, RowConversionError>;
+}
+
+/// Derive [`FromSpiRow`](trait@FromSpiRow) for a struct with named fields
+///
+/// Every field is read from the column with its name, by name. Fields of type `Option<T>` are `None` for NULL, NULL
+/// in other fields is reported as [`RowConversionError::NullField`], and a missing column as
+/// [`RowConversionError::MissingColumn`]. Fields accept these attributes:
+///
+/// - `#[spiext(column = "name")]` reads the field from the column `name`;
+/// - `#[spiext(default)]` sets the field to `Default::default()` if the row has no such column;
+/// - `#[spiext(flatten)]` converts the same row into the field's type, which must implement `FromSpiRow` too.
+///
+/// Requires the `derive` feature.
+#[cfg(feature = "derive")]
+pub use pgx_contrib_spiext_derive::FromSpiRow;
+
+macro_rules! impl_from_spi_row_for_tuple {
+    ($($t:ident => $ordinal:literal),+) => {
+        impl<$($t: FromDatum + IntoDatum),+> FromSpiRow for ($($t,)+) {
+            fn from_spi_row(row: &SpiRow) -> Result<Self, RowConversionError> {
+                Ok(($(row.strict_get_by_ordinal::<$t>($ordinal)?,)+))
+            }
+        }
+    };
+}
+
+impl_from_spi_row_for_tuple!(A => 1);
+impl_from_spi_row_for_tuple!(A => 1, B => 2);
+impl_from_spi_row_for_tuple!(A => 1, B => 2, C => 3);
+impl_from_spi_row_for_tuple!(A => 1, B => 2, C => 3, D => 4);
+
+/// Names of the columns of a tuple table, in order
+pub(crate) fn column_names(table: &SpiTupleTable) -> Vec<String> {
+    (1..=table.columns())
+        .map(|ordinal| table.column_name(ordinal).unwrap_or_default())
+        .collect()
+}
+
+/// Additional functionality for `SpiTupleTable`
+pub trait SpiTupleTableExt: Sized {
+    /// Ensure none of the given columns contain NULL in any of the rows
+    ///
+    /// Returns the table rewound to its first row.
+    fn assert_no_nulls(self, columns: &[&str]) -> Result<Self, NullViolation>;
+}
+
+impl SpiTupleTableExt for SpiTupleTable {
+    fn assert_no_nulls(self, columns: &[&str]) -> Result<Self, NullViolation> {
+        let names = column_names(&self);
+        let mut table = self.first();
+        for (index, tuple) in (&mut table).enumerate() {
+            let row = SpiRow::new(&tuple, &names, index);
+            for column in columns {
+                let ordinal = row.ordinal(column);
+                if row.is_null(ordinal) {
+                    return Err(row.violation(ordinal));
+                }
+            }
+        }
+        Ok(table.first())
+    }
+}
+
+/// Error returned by [`StrictCommands::checked_select_strict`]
+#[derive(Debug)]
+pub enum StrictSelectError {
+    /// Query failed
+    Query(Error),
+    /// Unexpected NULL in the result set
+    Null(NullViolation),
+    /// A row couldn't be converted otherwise, such as by a derived [`FromSpiRow`](trait@FromSpiRow)
+    Conversion(RowConversionError),
+}
+
+impl From<Error> for StrictSelectError {
+    fn from(err: Error) -> Self {
+        StrictSelectError::Query(err)
+    }
+}
+
+/// Read-only commands with strict typed extraction
+pub trait StrictCommands {
+    /// Execute a read-only command, converting every row with [`FromSpiRow`]
+    ///
+    /// Stops at the first unexpected NULL, reporting it along with the query.
+    fn checked_select_strict<T: FromSpiRow>(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<Vec<T>, StrictSelectError>;
+}
+
+impl StrictCommands for SpiClient {
+    fn checked_select_strict<T: FromSpiRow>(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<Vec<T>, StrictSelectError> {
+        let table = self.checked_select(query, limit, args)?;
+        let names = column_names(&table);
+        let mut result = Vec::with_capacity(table.len());
+        for (index, tuple) in table.first().enumerate() {
+            let value =
+                T::from_spi_row(&SpiRow::new(&tuple, &names, index)).map_err(|err| match err {
+                    RowConversionError::Null(err) => StrictSelectError::Null(NullViolation {
+                        query: Some(query.to_string()),
+                        ..err
+                    }),
+                    err => StrictSelectError::Conversion(err),
+                })?;
+            result.push(value);
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,230 @@
+//! Sampling the rows of large tables
+//!
+//! Table names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier).
+
+use pgx::{pg_sys::Datum, IntoDatum, PgBuiltInOids, PgOid, SpiClient};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::cursor::CheckedCursor;
+use crate::error::Error;
+use crate::owned::OwnedRows;
+use crate::quote::*;
+use crate::rewrite;
+
+/// Rows fetched at a time by [`SampleMethod::Reservoir`]
+const RESERVOIR_BATCH: i64 = 1000;
+
+/// How [`rows`] samples a table
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleMethod {
+    /// `TABLESAMPLE BERNOULLI` with this percentage: every row is picked with that probability, reading the whole
+    /// table
+    Bernoulli(f64),
+    /// `TABLESAMPLE SYSTEM` with this percentage: every page is picked with that probability, reading only the pages
+    /// picked, so rows that are stored together are picked together
+    System(f64),
+    /// The first rows the table returns, which is fast but biased (usually towards the rows inserted first)
+    TopN,
+    /// Reservoir sampling over all of the table's rows, fetched with a cursor and only holding the sample in memory
+    ///
+    /// Reads the whole table, but every set of rows of the requested size is equally likely to be picked, and it
+    /// works for views, which `TABLESAMPLE` doesn't.
+    Reservoir,
+}
+
+/// Error returned by [`rows`]
+#[derive(Debug)]
+pub enum SampleError {
+    /// There is no table with this name
+    NoSuchTable(String),
+    /// `TABLESAMPLE` was requested on a view, which only [`SampleMethod::TopN`] and [`SampleMethod::Reservoir`] can
+    /// sample
+    View(String),
+    Query(Error),
+}
+
+impl Display for SampleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SampleError::NoSuchTable(table) => write!(f, "table {} does not exist", table),
+            SampleError::View(table) => write!(
+                f,
+                "{} is a view, which TABLESAMPLE can't be applied to; sample it with SampleMethod::Reservoir",
+                table
+            ),
+            SampleError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for SampleError {
+    fn from(err: Error) -> Self {
+        SampleError::Query(err)
+    }
+}
+
+/// Sample up to `n` rows of `table`
+///
+/// Samples differ from one call to the next; see [`rows_repeatable`] for samples that can be repeated. The percentage
+/// of [`SampleMethod::Bernoulli`] and [`SampleMethod::System`] bounds the rows considered, of which the first `n` are
+/// returned, so fewer may be returned if it is too low.
+pub fn rows(
+    client: &SpiClient,
+    table: &str,
+    n: usize,
+    method: SampleMethod,
+) -> Result<OwnedRows, SampleError> {
+    sample(client, table, n, method, None)
+}
+
+/// Sample up to `n` rows of `table` as [`rows`] does, picking the same rows every time the same `seed` is given
+///
+/// `TABLESAMPLE` methods only pick the same rows as long as the table is unchanged, which holds within a transaction
+/// with a repeatable snapshot (such as `REPEATABLE READ`), as well as when no one writes to it. Reservoir sampling
+/// also depends on the order the rows are returned in, which may change as the table is updated or scanned
+/// concurrently (see `synchronize_seqscans`). [`SampleMethod::TopN`] ignores the seed.
+pub fn rows_repeatable(
+    client: &SpiClient,
+    table: &str,
+    n: usize,
+    method: SampleMethod,
+    seed: i64,
+) -> Result<OwnedRows, SampleError> {
+    sample(client, table, n, method, Some(seed))
+}
+
+fn sample(
+    client: &SpiClient,
+    table: &str,
+    n: usize,
+    method: SampleMethod,
+    seed: Option<i64>,
+) -> Result<OwnedRows, SampleError> {
+    let quoted = quote_qualified_identifier(table);
+    let is_view = rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT c.relkind = 'v' FROM pg_class c WHERE c.oid = to_regclass($1)",
+            Some(1),
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                quoted.as_str().into_datum(),
+            )]),
+        )
+    })?
+    .first()
+    .get_one::<bool>()
+    .ok_or_else(|| SampleError::NoSuchTable(table.to_string()))?;
+    let limit = (n.min(i64::MAX as usize) as i64).into_datum();
+    let (query, args) = match method {
+        SampleMethod::Bernoulli(percent) | SampleMethod::System(percent) => {
+            if is_view {
+                return Err(SampleError::View(table.to_string()));
+            }
+            let name = if matches!(method, SampleMethod::Bernoulli(_)) {
+                "BERNOULLI"
+            } else {
+                "SYSTEM"
+            };
+            // The percentage is a `real`, which a `double precision` isn't implicitly cast to
+            let mut args: Vec<(PgOid, Option<Datum>)> = vec![
+                (PgBuiltInOids::INT8OID.oid(), limit),
+                (
+                    PgBuiltInOids::FLOAT4OID.oid(),
+                    (percent as f32).into_datum(),
+                ),
+            ];
+            let repeatable = match seed {
+                Some(seed) => {
+                    args.push((PgBuiltInOids::INT8OID.oid(), seed.into_datum()));
+                    " REPEATABLE ($3)"
+                }
+                None => "",
+            };
+            let query = format!(
+                "SELECT * FROM {} TABLESAMPLE {} ($2){} LIMIT $1",
+                quoted, name, repeatable
+            );
+            (query, args)
+        }
+        SampleMethod::TopN => {
+            pgx::warning!(
+                "sampling the first {} rows of {}, which are biased towards the ones stored first",
+                n,
+                table
+            );
+            let query = format!("SELECT * FROM {} LIMIT $1", quoted);
+            (query, vec![(PgBuiltInOids::INT8OID.oid(), limit)])
+        }
+        SampleMethod::Reservoir => {
+            let seed = match seed {
+                Some(seed) => seed,
+                // Seeded from the session's generator, so that `setseed` makes it repeatable too
+                None => client
+                    .checked_select("SELECT (random() * 9007199254740992)::int8", None, None)?
+                    .first()
+                    .get_one::<i64>()
+                    .unwrap_or_default(),
+            };
+            return reservoir(client, &quoted, n, seed);
+        }
+    };
+    let table = client.checked_select(&query, None, Some(args))?;
+    Ok(OwnedRows::from_table(table))
+}
+
+/// Pick `n` of the rows of `table` uniformly with Algorithm R, replacing a row picked so far with the row at
+/// (0-based) position `i` with probability `n / (i + 1)`
+fn reservoir(
+    client: &SpiClient,
+    table: &str,
+    n: usize,
+    seed: i64,
+) -> Result<OwnedRows, SampleError> {
+    let mut cursor = CheckedCursor::open(client, &format!("SELECT * FROM {}", table), None)?;
+    let mut random = SplitMix64(seed as u64);
+    let mut sample: Option<OwnedRows> = None;
+    let mut seen = 0u64;
+    loop {
+        let fetched = cursor.fetch_with(RESERVOIR_BATCH, |table| {
+            let sample = sample.get_or_insert_with(|| OwnedRows::with_columns_of(&table));
+            let fetched = table.len();
+            for tuple in table {
+                if sample.len() < n {
+                    sample.push(&tuple);
+                } else {
+                    let index = random.below(seen + 1);
+                    if index < n as u64 {
+                        sample.replace(index as usize, &tuple);
+                    }
+                }
+                seen += 1;
+            }
+            fetched
+        })?;
+        if (fetched as i64) < RESERVOIR_BATCH {
+            break;
+        }
+    }
+    cursor.close();
+    Ok(sample.unwrap_or_default())
+}
+
+/// SplitMix64 generator, enough to pick rows with
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Number below `bound`
+    fn below(&mut self, bound: u64) -> u64 {
+        ((self.next() as u128 * bound as u128) >> 64) as u64
+    }
+}
@@ -0,0 +1,268 @@
+//! Minimal lexical scanner for SQL text
+//!
+//! It is only meant to answer simple questions about a query (where are its comments, what's its first keyword,
+//! which parameters does it reference) without calling into the Postgres parser.
+
+/// Token produced by [`Scanner`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Token<'a> {
+    /// Keyword or unquoted identifier
+    Word(&'a str),
+    /// Double-quoted identifier, including the quotes
+    QuotedIdent(&'a str),
+    /// String literal (standard, escape or dollar-quoted), including the quotes
+    Literal(&'a str),
+    /// Numeric literal
+    Number(&'a str),
+    /// Positional parameter (`$1`)
+    Param(u32),
+    /// Statement separator
+    Semicolon,
+    /// Any other character
+    Symbol(&'a str),
+}
+
+/// Scanner over SQL text, yielding tokens along with their byte offsets
+///
+/// Whitespace and comments (including nested block comments) are skipped.
+pub(crate) struct Scanner<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    pub(crate) fn new(input: &'a str) -> Self {
+        Self { input, pos: 0 }
+    }
+
+    fn bytes(&self) -> &'a [u8] {
+        self.input.as_bytes()
+    }
+
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.bytes().get(self.pos + offset).copied()
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        loop {
+            match (self.peek(0), self.peek(1)) {
+                (Some(c), _) if c.is_ascii_whitespace() => self.pos += 1,
+                (Some(b'-'), Some(b'-')) => {
+                    while !matches!(self.peek(0), None | Some(b'\n')) {
+                        self.pos += 1;
+                    }
+                }
+                (Some(b'/'), Some(b'*')) => {
+                    self.pos += 2;
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match (self.peek(0), self.peek(1)) {
+                            (None, _) => break,
+                            (Some(b'/'), Some(b'*')) => {
+                                depth += 1;
+                                self.pos += 2;
+                            }
+                            (Some(b'*'), Some(b'/')) => {
+                                depth -= 1;
+                                self.pos += 2;
+                            }
+                            _ => self.pos += 1,
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn is_ident_start(c: u8) -> bool {
+        c.is_ascii_alphabetic() || c == b'_' || c >= 0x80
+    }
+
+    fn is_ident_char(c: u8) -> bool {
+        Self::is_ident_start(c) || c.is_ascii_digit() || c == b'$'
+    }
+
+    fn skip_quoted(&mut self, quote: u8, backslash_escapes: bool) {
+        // Opening quote
+        self.pos += 1;
+        while let Some(c) = self.peek(0) {
+            self.pos += 1;
+            if backslash_escapes && c == b'\\' {
+                self.pos += 1;
+            } else if c == quote {
+                if self.peek(0) == Some(quote) {
+                    self.pos += 1;
+                } else {
+                    return;
+                }
+            }
+        }
+        self.pos = self.input.len();
+    }
+
+    /// Returns the length of a dollar-quote tag (`$tag$`) starting at the current position, if any
+    fn dollar_tag_len(&self) -> Option<usize> {
+        let bytes = &self.bytes()[self.pos..];
+        if bytes.get(1).map_or(false, |c| c.is_ascii_digit()) {
+            return None;
+        }
+        let end = bytes[1..]
+            .iter()
+            .position(|c| !Self::is_ident_char(*c) || *c == b'$')?;
+        (bytes[1 + end] == b'$').then_some(end + 2)
+    }
+
+    fn slice(&self, start: usize) -> &'a str {
+        &self.input[start..self.pos.min(self.input.len())]
+    }
+}
+
+impl<'a> Iterator for Scanner<'a> {
+    type Item = (usize, Token<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.skip_whitespace_and_comments();
+        let start = self.pos;
+        let c = self.peek(0)?;
+        let token = match c {
+            b';' => {
+                self.pos += 1;
+                Token::Semicolon
+            }
+            b'\'' => {
+                self.skip_quoted(b'\'', false);
+                Token::Literal(self.slice(start))
+            }
+            b'"' => {
+                self.skip_quoted(b'"', false);
+                Token::QuotedIdent(self.slice(start))
+            }
+            b'e' | b'E' if self.peek(1) == Some(b'\'') => {
+                self.pos += 1;
+                self.skip_quoted(b'\'', true);
+                Token::Literal(self.slice(start))
+            }
+            b'$' if self.peek(1).map_or(false, |c| c.is_ascii_digit()) => {
+                self.pos += 1;
+                while self.peek(0).map_or(false, |c| c.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                Token::Param(self.input[start + 1..self.pos].parse().unwrap_or(u32::MAX))
+            }
+            b'$' if self.dollar_tag_len().is_some() => {
+                let tag_len = self.dollar_tag_len().unwrap();
+                let tag = &self.input[start..start + tag_len];
+                self.pos += tag_len;
+                match self.input[self.pos..].find(tag) {
+                    Some(end) => self.pos += end + tag_len,
+                    None => self.pos = self.input.len(),
+                }
+                Token::Literal(self.slice(start))
+            }
+            c if Self::is_ident_start(c) => {
+                while self.peek(0).map_or(false, Self::is_ident_char) {
+                    self.pos += 1;
+                }
+                Token::Word(self.slice(start))
+            }
+            c if c.is_ascii_digit() => {
+                while self
+                    .peek(0)
+                    .map_or(false, |c| c.is_ascii_alphanumeric() || c == b'.')
+                {
+                    self.pos += 1;
+                }
+                Token::Number(self.slice(start))
+            }
+            _ => {
+                // Advance by a whole character to stay on a UTF-8 boundary
+                self.pos += self.input[start..].chars().next().map_or(1, char::len_utf8);
+                Token::Symbol(self.slice(start))
+            }
+        };
+        Some((start, token))
+    }
+}
+
+/// Returns true if the query contains no statements: only whitespace, comments and semicolons
+pub(crate) fn is_empty_query(query: &str) -> bool {
+    Scanner::new(query).all(|(_, token)| token == Token::Semicolon)
+}
+
+/// Whether the query has at most one statement
+///
+/// Semicolons always end statements here, even within the `BEGIN ATOMIC ... END` body of a SQL function.
+pub(crate) fn is_single_statement(query: &str) -> bool {
+    Scanner::new(query)
+        .map(|(_, token)| token)
+        .skip_while(|token| *token != Token::Semicolon)
+        .all(|token| token == Token::Semicolon)
+}
+
+/// Kind of the first transaction control statement of the query (such as `COMMIT` or `START TRANSACTION`), if any
+///
+/// Every statement is classified by its first keywords. Semicolons within the `BEGIN ATOMIC ... END` body of a SQL
+/// function don't end statements. Statements executed indirectly (such as `COMMIT` in a `DO` block) are not detected.
+pub(crate) fn transaction_control_kind(query: &str) -> Option<&'static str> {
+    let mut tokens = Scanner::new(query).map(|(_, token)| token).peekable();
+    let mut at_start = true;
+    let mut atomic_depth = 0;
+    while let Some(token) = tokens.next() {
+        let next = match tokens.peek() {
+            Some(Token::Word(next)) => Some(*next),
+            _ => None,
+        };
+        let is = |word: &str, keyword: &str| word.eq_ignore_ascii_case(keyword);
+        match token {
+            Token::Semicolon if atomic_depth == 0 => {
+                at_start = true;
+                continue;
+            }
+            Token::Word(word) if at_start => {
+                if let Some(kind) = transaction_control_keyword(word, next) {
+                    return Some(kind);
+                }
+            }
+            Token::Word(word)
+                if is(word, "begin") && next.map_or(false, |next| is(next, "atomic")) =>
+            {
+                atomic_depth += 1;
+            }
+            Token::Word(word) if atomic_depth > 0 && is(word, "case") => atomic_depth += 1,
+            Token::Word(word) if atomic_depth > 0 && is(word, "end") => atomic_depth -= 1,
+            _ => {}
+        }
+        at_start = false;
+    }
+    None
+}
+
+/// Kind of the transaction control statement starting with `word` and `next`, if it is one
+fn transaction_control_keyword(word: &str, next: Option<&str>) -> Option<&'static str> {
+    let next_is = |keyword: &str| next.map_or(false, |next| next.eq_ignore_ascii_case(keyword));
+    let kind = match word.to_ascii_lowercase().as_str() {
+        "begin" => "BEGIN",
+        "start" if next_is("transaction") => "START TRANSACTION",
+        "commit" if next_is("prepared") => "COMMIT PREPARED",
+        "commit" => "COMMIT",
+        "end" => "END",
+        "rollback" if next_is("prepared") => "ROLLBACK PREPARED",
+        "rollback" => "ROLLBACK",
+        "abort" => "ABORT",
+        "savepoint" => "SAVEPOINT",
+        "release" => "RELEASE",
+        "prepare" if next_is("transaction") => "PREPARE TRANSACTION",
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// Maps a byte offset in `input` to a 1-based line and column (in characters)
+pub(crate) fn line_column(input: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(input.len());
+    let before = &input[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
@@ -0,0 +1,180 @@
+//! Scripts of labeled statements executed in order, all or nothing
+//!
+//! Every statement of a [`Script`] executes in a child sub-transaction of the script's, so a best-effort statement
+//! (see [`Script::continue_on_error`]) that fails only rolls back its own work.
+
+use pgx::{pg_sys::Datum, PgOid, SpiClient};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::subtxn::*;
+
+struct Statement {
+    label: String,
+    sql: String,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+}
+
+/// Sequence of labeled statements
+///
+/// Arguments are kept as they are given, so values passed by reference must outlive the script.
+#[derive(Default)]
+pub struct Script {
+    statements: Vec<Statement>,
+    best_effort: HashSet<String>,
+}
+
+/// Outcome of a statement of a script, see [`ScriptReport`]
+#[derive(Debug)]
+pub struct StatementOutcome {
+    pub label: String,
+    pub duration: Duration,
+    /// Number of rows the statement processed, zero if it failed
+    pub rows: u64,
+    /// Error of a best-effort statement that failed
+    pub error: Option<Error>,
+}
+
+/// Outcomes of the statements of a script that ran to completion, in order
+#[derive(Debug, Default)]
+pub struct ScriptReport {
+    pub statements: Vec<StatementOutcome>,
+}
+
+impl ScriptReport {
+    /// Best-effort statements that failed
+    pub fn failures(&self) -> impl Iterator<Item = &StatementOutcome> {
+        self.statements
+            .iter()
+            .filter(|outcome| outcome.error.is_some())
+    }
+
+    /// Total duration of the statements
+    pub fn duration(&self) -> Duration {
+        self.statements.iter().map(|outcome| outcome.duration).sum()
+    }
+}
+
+/// Script error, after which nothing the script did is kept
+#[derive(Debug)]
+pub enum ScriptError {
+    /// Statement `index` (zero-based) failed
+    Statement {
+        label: String,
+        index: usize,
+        sql: String,
+        error: Error,
+    },
+    /// Several statements have this label; nothing was executed
+    DuplicateLabel(String),
+    /// No statement has this label, given to [`Script::continue_on_error`]; nothing was executed
+    UnknownLabel(String),
+    /// The script's sub-transaction couldn't be begun; nothing was executed
+    Query(Error),
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::Statement {
+                label,
+                index,
+                error,
+                ..
+            } => write!(f, "statement {} (\"{}\") failed: {}", index, label, error),
+            ScriptError::DuplicateLabel(label) => {
+                write!(f, "several statements are labeled \"{}\"", label)
+            }
+            ScriptError::UnknownLabel(label) => write!(f, "no statement is labeled \"{}\"", label),
+            ScriptError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for ScriptError {
+    fn from(err: Error) -> Self {
+        ScriptError::Query(err)
+    }
+}
+
+impl Script {
+    /// Create an empty script
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a statement
+    pub fn stmt(self, label: &str, sql: &str) -> Self {
+        self.push(label, sql, None)
+    }
+
+    /// Add a statement with parameters
+    pub fn stmt_with_args(self, label: &str, sql: &str, args: Vec<(PgOid, Option<Datum>)>) -> Self {
+        self.push(label, sql, Some(args))
+    }
+
+    fn push(mut self, label: &str, sql: &str, args: Option<Vec<(PgOid, Option<Datum>)>>) -> Self {
+        self.statements.push(Statement {
+            label: label.to_string(),
+            sql: sql.to_string(),
+            args,
+        });
+        self
+    }
+
+    /// Make the statements with these labels best-effort: if one fails, its own work is rolled back and its error is
+    /// reported, and the script goes on
+    pub fn continue_on_error(mut self, labels: &[&str]) -> Self {
+        self.best_effort
+            .extend(labels.iter().map(|label| label.to_string()));
+        self
+    }
+
+    /// Execute the statements in order in a sub-transaction, committing it if they all succeed (or are
+    /// best-effort), and rolling it back at the first other failure
+    pub fn run(&self, client: &mut SpiClient) -> Result<ScriptReport, ScriptError> {
+        let mut labels = HashSet::new();
+        for statement in &self.statements {
+            if !labels.insert(statement.label.as_str()) {
+                return Err(ScriptError::DuplicateLabel(statement.label.clone()));
+            }
+        }
+        if let Some(label) = self
+            .best_effort
+            .iter()
+            .find(|label| !labels.contains(label.as_str()))
+        {
+            return Err(ScriptError::UnknownLabel(label.clone()));
+        }
+        let mut xact = client.try_sub_transaction(|xact| xact.rollback_on_drop())?;
+        let mut report = ScriptReport::default();
+        for (index, statement) in self.statements.iter().enumerate() {
+            let started = Instant::now();
+            let result = xact.update(&statement.sql, None, statement.args.clone());
+            let duration = started.elapsed();
+            let (rows, error) = match result {
+                Ok(table) => (table.len() as u64, None),
+                Err(error) if self.best_effort.contains(&statement.label) => (0, Some(error)),
+                // Dropping the sub-transaction rolls it back
+                Err(error) => {
+                    return Err(ScriptError::Statement {
+                        label: statement.label.clone(),
+                        index,
+                        sql: statement.sql.clone(),
+                        error,
+                    })
+                }
+            };
+            report.statements.push(StatementOutcome {
+                label: statement.label.clone(),
+                duration,
+                rows,
+                error,
+            });
+        }
+        xact.commit();
+        Ok(report)
+    }
+}
@@ -0,0 +1,183 @@
+//! Running commands against specific schemas, without building `SET search_path` commands by hand
+//!
+//! Schema names are given as they are stored in the catalogs (not quoted); they are quoted when the search path is
+//! set, and the search path is set with a parameter rather than by splicing it into a command.
+
+use pgx::{pg_sys, pg_sys::Datum, IntoDatum, PgBuiltInOids, PgOid, SpiClient, SpiTupleTable};
+use std::ffi::CStr;
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::quote::quote_identifier;
+use crate::rewrite;
+use crate::subtxn::*;
+
+/// Schema scope error
+#[derive(Debug)]
+pub enum SchemaScopeError {
+    /// There is no schema with this name
+    SchemaNotFound(String),
+    /// A command failed
+    Query(Error),
+}
+
+impl Display for SchemaScopeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaScopeError::SchemaNotFound(schema) => {
+                write!(f, "schema \"{}\" does not exist", schema)
+            }
+            SchemaScopeError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for SchemaScopeError {
+    fn from(err: Error) -> Self {
+        SchemaScopeError::Query(err)
+    }
+}
+
+/// Search path set for the rest of the transaction, restored when dropped
+///
+/// Created by [`SchemaScope::enter`]. When dropped, the search path is set back to what it was when the scope was
+/// entered, unless it is no longer the one the scope set: rolling back the sub-transaction the scope was entered in
+/// already restored it, and a search path set by other means since is kept. Nested scopes must thus be dropped in the
+/// reverse order they were entered in. Restoring is best-effort: failures are reported as warnings.
+#[derive(Debug)]
+pub struct SchemaScope {
+    previous: String,
+    applied: String,
+}
+
+impl SchemaScope {
+    /// Set the search path to `schemas`, in order, until the scope is dropped
+    ///
+    /// Returns [`SchemaScopeError::SchemaNotFound`] with the first schema that doesn't exist, without changing the
+    /// search path. The search path is set in a child sub-transaction of `xact` with `set_config(..., true)`, so it
+    /// lasts until the end of the transaction unless `xact` is rolled back.
+    pub fn enter<Parent, const COMMIT: bool>(
+        xact: &mut SubTransaction<Parent, COMMIT>,
+        schemas: &[&str],
+    ) -> Result<Self, SchemaScopeError> {
+        let names = || {
+            Some(vec![(
+                PgBuiltInOids::TEXTARRAYOID.oid(),
+                schemas.to_vec().into_datum(),
+            )])
+        };
+        let missing = rewrite::exempt(|| {
+            xact.select(
+                "SELECT s.name FROM unnest($1::text[]) WITH ORDINALITY s(name, i) \
+                 WHERE NOT EXISTS (SELECT FROM pg_namespace WHERE nspname = s.name) ORDER BY s.i LIMIT 1",
+                None,
+                names(),
+            )
+        })?
+        .first()
+        .get_one::<String>();
+        if let Some(schema) = missing {
+            return Err(SchemaScopeError::SchemaNotFound(schema));
+        }
+        let path = schemas
+            .iter()
+            .map(|schema| quote_identifier(schema))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let previous = current_value();
+        let applied = rewrite::exempt(|| {
+            xact.update(
+                "SELECT set_config('search_path', $1, true)",
+                None,
+                Some(vec![(PgBuiltInOids::TEXTOID.oid(), path.into_datum())]),
+            )
+        })?
+        .first()
+        .get_one::<String>()
+        .unwrap_or_default();
+        Ok(Self { previous, applied })
+    }
+}
+
+impl Drop for SchemaScope {
+    fn drop(&mut self) {
+        if current_value() != self.applied {
+            return;
+        }
+        let result = rewrite::exempt(|| {
+            (&SpiClient).checked_select(
+                "SELECT set_config('search_path', $1, true)",
+                None,
+                Some(vec![(
+                    PgBuiltInOids::TEXTOID.oid(),
+                    self.previous.as_str().into_datum(),
+                )]),
+            )
+        });
+        if let Err(err) = result {
+            pgx::warning!("failed to restore search path: {}", err);
+        }
+    }
+}
+
+/// Execute a read-only command with `schema` as the only schema of the search path
+///
+/// The command runs in a sub-transaction of its own, in which the search path is set and restored.
+pub fn checked_select_in_schema(
+    client: &SpiClient,
+    schema: &str,
+    query: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+) -> Result<SpiTupleTable, SchemaScopeError> {
+    client.try_sub_transaction(|xact| {
+        let mut xact = xact.rollback_on_drop();
+        let scope = SchemaScope::enter(&mut xact, &[schema])?;
+        let table = xact.select(query, None, args)?;
+        drop(scope);
+        xact.commit();
+        Ok(table)
+    })?
+}
+
+/// Schemas of the active search path, as Postgres parses it (unquoted names are folded to lower case)
+///
+/// The names are not resolved: `$user` is returned as it is, and schemas that don't exist are included.
+pub fn current() -> Vec<String> {
+    split_identifiers(&current_value())
+}
+
+fn current_value() -> String {
+    unsafe { CStr::from_ptr(pg_sys::namespace_search_path) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Split a comma-separated list of possibly quoted identifiers
+fn split_identifiers(list: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut chars = list.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut name = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                if c == '"' && chars.next_if_eq(&'"').is_none() {
+                    break;
+                }
+                name.push(c);
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',' && !c.is_whitespace()) {
+                name.push(c.to_ascii_lowercase());
+            }
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if !name.is_empty() {
+            names.push(name);
+        }
+        if chars.next().is_none() {
+            return names;
+        }
+    }
+}
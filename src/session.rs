@@ -0,0 +1,85 @@
+use pgx::{pg_sys, FromDatum, IntoDatum, PgOid, PgTryBuilder, SpiClient, SpiTupleTable};
+use std::panic::AssertUnwindSafe;
+
+use crate::checked::*;
+use crate::error::{capture_sqlstate, handled, Error};
+use crate::subtxn::*;
+
+/// Facade over checked commands, used by [`run_checked`](crate::run_checked)
+///
+/// Every command executes in a sub-transaction of its own. The work done through a session is itself wrapped in a
+/// sub-transaction, which is committed if it results in `Ok` and rolled back if it results in `Err`.
+pub struct CheckedSession {
+    _private: (),
+}
+
+impl CheckedSession {
+    /// Run `f` with a session in a new sub-transaction, committing it on `Ok` and rolling it back on `Err`
+    ///
+    /// Errors raised (and panics) in `f` are caught and returned, rolling the sub-transaction back as well.
+    pub(crate) fn scoped<R, F: FnOnce(&mut CheckedSession) -> Result<R, Error>>(
+        f: F,
+    ) -> Result<R, Error> {
+        check_can_begin()?;
+        let f = AssertUnwindSafe(f);
+        SpiClient.sub_transaction(|xact| {
+            let xact = xact.rollback_on_drop();
+            capture_sqlstate(|| {
+                PgTryBuilder::new(move || {
+                    let f = f;
+                    Ok(((f.0)(&mut CheckedSession { _private: () }), xact))
+                })
+                .catch_others(|e| Err(e))
+                .execute()
+            })
+            .map_err(handled)
+            .and_then(|(result, xact)| {
+                if result.is_ok() {
+                    xact.commit();
+                }
+                // Otherwise, dropping the sub-transaction rolls it back
+                result
+            })
+        })
+    }
+
+    /// Execute a read-only command
+    pub fn select(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        (&SpiClient).checked_select(query, limit, args)
+    }
+
+    /// Execute a mutable command
+    pub fn update(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        (&mut SpiClient).checked_update(query, limit, args)
+    }
+
+    /// Execute a read-only command, returning the first column of its first row
+    ///
+    /// Returns `None` both if the value is NULL and if there are no rows; to tell them apart, use
+    /// [`GetOneCommands::checked_get_one_tristate`](crate::checked::GetOneCommands::checked_get_one_tristate).
+    pub fn get_one<T: FromDatum + IntoDatum>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<Option<T>, Error> {
+        Ok(self.select(query, Some(1), args)?.first().get_one::<T>())
+    }
+
+    /// Run `f` in a nested sub-transaction, committing it on `Ok` and rolling it back on `Err`
+    pub fn sub_transaction<R, F: FnOnce(&mut CheckedSession) -> Result<R, Error>>(
+        &mut self,
+        f: F,
+    ) -> Result<R, Error> {
+        Self::scoped(f)
+    }
+}
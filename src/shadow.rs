@@ -0,0 +1,177 @@
+//! Shadowing reads against a second version of a schema, reporting where their results diverge
+//!
+//! Once [`enable`]d, every `checked_select` that succeeds (including those of
+//! [`SubTransaction::select`](crate::subtxn::SubTransaction::select)) is executed a second time, with its text
+//! rewritten, in a child sub-transaction that is always rolled back. Both results are compared by row count and by a
+//! hash of every row (of the text of its values, as their types' output functions produce it), and differences are
+//! passed to the sink as a [`Divergence`]. The caller only ever gets the primary result: a failing shadow command is
+//! reported to the sink as well. Commands executed while shadowing, by the shadow command or the sink, aren't
+//! shadowed themselves.
+//!
+//! Shadowing is backend-local. While it's disabled, checked commands only check a thread-local flag.
+
+use pgx::{pg_sys, pg_sys::Datum, PgOid, SpiClient, SpiTupleTable};
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::CStr;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::subtxn::*;
+
+/// Difference between the results of a command and of its shadow command, or failure of the shadow command
+#[derive(Debug)]
+pub struct Divergence {
+    /// Text of the command, as it was given
+    pub query: String,
+    /// Text of the shadow command
+    pub rewritten: String,
+    /// Number of rows of the command's result
+    pub left_rows: u64,
+    /// Number of rows of the shadow command's result, or `None` if it failed
+    pub right_rows: Option<u64>,
+    /// Zero-based index of the first row that differs (in the order of the hashes of the rows when comparing without
+    /// order, see [`set_order_insensitive`]), or `None` if the shadow command failed
+    pub first_mismatch: Option<usize>,
+    /// Error of the shadow command, if it failed
+    pub error: Option<Error>,
+}
+
+struct Shadow {
+    rewrite: Box<dyn Fn(&str) -> String>,
+    sink: Box<dyn Fn(Divergence)>,
+}
+
+thread_local! {
+    static SHADOW: RefCell<Option<Rc<Shadow>>> = RefCell::new(None);
+    static ORDER_INSENSITIVE: Cell<bool> = Cell::new(false);
+    /// Whether a command is being shadowed
+    static SHADOWING: Cell<bool> = Cell::new(false);
+}
+
+/// Shadow every `checked_select` with the command `rewrite` turns its text into, passing differences to `sink`
+///
+/// Replaces the functions of an earlier call.
+pub fn enable(rewrite: impl Fn(&str) -> String + 'static, sink: impl Fn(Divergence) + 'static) {
+    let shadow = Shadow {
+        rewrite: Box::new(rewrite),
+        sink: Box::new(sink),
+    };
+    SHADOW.with(|current| *current.borrow_mut() = Some(Rc::new(shadow)));
+}
+
+/// Stop shadowing commands
+pub fn disable() {
+    SHADOW.with(|current| current.borrow_mut().take());
+}
+
+/// Compare results without regard to the order of their rows (or, by default, in order)
+pub fn set_order_insensitive(order_insensitive: bool) {
+    ORDER_INSENSITIVE.with(|current| current.set(order_insensitive));
+}
+
+/// Whether the next `checked_select` will be shadowed
+pub fn is_enabled() -> bool {
+    !SHADOWING.with(Cell::get) && SHADOW.with(|current| current.borrow().is_some())
+}
+
+/// Resets [`SHADOWING`] when dropped
+struct ShadowingGuard;
+
+impl Drop for ShadowingGuard {
+    fn drop(&mut self) {
+        SHADOWING.with(|shadowing| shadowing.set(false));
+    }
+}
+
+/// Shadow `query`, whose result is `table`, returning `table` rewound to its first row
+pub(crate) fn compare(
+    query: &str,
+    limit: Option<i64>,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+    table: SpiTupleTable,
+) -> SpiTupleTable {
+    let shadow = match SHADOW.with(|current| current.borrow().clone()) {
+        Some(shadow) => shadow,
+        None => return table,
+    };
+    SHADOWING.with(|shadowing| shadowing.set(true));
+    let _guard = ShadowingGuard;
+    let mut table = table.first();
+    let mut left = row_hashes(&mut table);
+    let rewritten = (shadow.rewrite)(query);
+    let right = SpiClient.try_sub_transaction(|xact| {
+        // Nothing the shadow command does is kept
+        let xact = xact.rollback_on_drop();
+        xact.checked_select(&rewritten, limit, args)
+            .map(|(mut table, _xact)| row_hashes(&mut table))
+    });
+    let divergence = match right.and_then(|right| right) {
+        Ok(mut right) => {
+            if ORDER_INSENSITIVE.with(Cell::get) {
+                left.sort_unstable();
+                right.sort_unstable();
+            }
+            let first_mismatch = left
+                .iter()
+                .zip(&right)
+                .position(|(left, right)| left != right)
+                .or_else(|| (left.len() != right.len()).then(|| left.len().min(right.len())));
+            first_mismatch.map(|index| Divergence {
+                query: query.to_string(),
+                rewritten,
+                left_rows: left.len() as u64,
+                right_rows: Some(right.len() as u64),
+                first_mismatch: Some(index),
+                error: None,
+            })
+        }
+        Err(err) => Some(Divergence {
+            query: query.to_string(),
+            rewritten,
+            left_rows: left.len() as u64,
+            right_rows: None,
+            first_mismatch: None,
+            error: Some(err),
+        }),
+    };
+    if let Some(divergence) = divergence {
+        (shadow.sink)(divergence);
+    }
+    table.first()
+}
+
+/// Hash every remaining row of `table`, from the text of its values
+fn row_hashes(table: &mut SpiTupleTable) -> Vec<u64> {
+    let output_functions: Vec<_> = (1..=table.columns())
+        .map(|ordinal| {
+            let type_oid = table.column_type_oid(ordinal).ok()?;
+            let (mut function, mut is_varlena) = (pg_sys::InvalidOid, false);
+            unsafe { pg_sys::getTypeOutputInfo(type_oid.value(), &mut function, &mut is_varlena) };
+            Some(function)
+        })
+        .collect();
+    table
+        .map(|tuple| {
+            let mut hasher = DefaultHasher::new();
+            for (index, function) in output_functions.iter().enumerate() {
+                let datum = tuple
+                    .by_ordinal(index + 1)
+                    .ok()
+                    .and_then(|entry| entry.value::<Datum>());
+                match (datum, function) {
+                    (Some(datum), Some(function)) => unsafe {
+                        let text = pg_sys::OidOutputFunctionCall(*function, datum);
+                        hasher.write_u8(1);
+                        CStr::from_ptr(text).to_bytes().hash(&mut hasher);
+                        pg_sys::pfree(text.cast());
+                    },
+                    _ => hasher.write_u8(0),
+                }
+            }
+            hasher.finish()
+        })
+        .collect()
+}
@@ -0,0 +1,299 @@
+//! Runs that only one backend at a time may perform, such as periodic maintenance
+//!
+//! A [`Singleton`] is held with a session-level advisory lock, keyed by [`lock_key`] of its name, and described by a
+//! row of the unlogged table [`TABLE`] (created by [`Singleton::ensure_schema`]) so that backends that find it held can
+//! tell who holds it. As the row is written in the caller's transaction, other backends only see it once that
+//! transaction commits; until then (or if it rolls back) they only see the holder's backend.
+
+use pgx::{IntoDatum, PgBuiltInOids, SpiClient, TimestampWithTimeZone};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::rewrite;
+
+/// Table describing the holders of singletons, resolved through the search path
+pub const TABLE: &str = "spiext_singletons";
+
+/// Rows of `pg_locks` of the advisory lock of the key given as `$2`, held in the current database
+const HELD_LOCK: &str = "l.locktype = 'advisory' AND l.granted AND l.objsubid = 1 \
+    AND l.database = (SELECT oid FROM pg_database WHERE datname = current_database()) \
+    AND l.classid::int8 = ($2 >> 32) & 4294967295 AND l.objid::int8 = $2 & 4294967295";
+
+/// Whether the row `s` describes the backend of `l` and `a`: backend start times tell backends that reused a pid
+/// apart, when known (they aren't for other users' backends, without the privilege to see them)
+const SAME_BACKEND: &str = "s.pid = l.pid \
+    AND (a.backend_start IS NULL OR s.backend_start IS NULL OR a.backend_start = s.backend_start)";
+
+thread_local! {
+    /// Keys of the singletons held by this backend, as a session can take an advisory lock it holds again
+    static HELD: RefCell<HashSet<i64>> = RefCell::new(HashSet::new());
+}
+
+/// Advisory lock key of the singleton `name`: the 64-bit FNV-1a hash of its UTF-8 bytes, as a `bigint`
+///
+/// Different names may share a key, in which case they exclude each other.
+pub fn lock_key(name: &str) -> i64 {
+    name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    }) as i64
+}
+
+/// Backend holding a singleton, see [`SingletonBusy`]
+#[derive(Debug, Clone)]
+pub struct Holder {
+    pub pid: i32,
+    pub backend_start: Option<TimestampWithTimeZone>,
+    pub user: Option<String>,
+    pub application_name: Option<String>,
+    /// Metadata given by the holder, unless the row describing it isn't visible yet
+    pub metadata: Option<String>,
+    /// When the holder acquired the singleton, unless the row describing it isn't visible yet
+    pub acquired_at: Option<TimestampWithTimeZone>,
+}
+
+/// The singleton is held, by `holder` if the backend holding its lock could be found
+#[derive(Debug, Clone)]
+pub struct SingletonBusy {
+    pub name: String,
+    pub holder: Option<Holder>,
+}
+
+impl Display for SingletonBusy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "singleton \"{}\" is held", self.name)?;
+        if let Some(holder) = &self.holder {
+            write!(f, " by backend {}", holder.pid)?;
+            if let Some(user) = &holder.user {
+                write!(f, " of user {}", user)?;
+            }
+            if let Some(application_name) = holder
+                .application_name
+                .as_deref()
+                .filter(|name| !name.is_empty())
+            {
+                write!(f, " ({})", application_name)?;
+            }
+            if let Some(metadata) = &holder.metadata {
+                write!(f, ": {}", metadata)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Singleton error
+#[derive(Debug)]
+pub enum SingletonError {
+    /// The singleton is held by another run
+    Busy(SingletonBusy),
+    Query(Error),
+}
+
+impl Display for SingletonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SingletonError::Busy(busy) => write!(f, "{}", busy),
+            SingletonError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for SingletonError {
+    fn from(err: Error) -> Self {
+        SingletonError::Query(err)
+    }
+}
+
+/// Runs excluding each other across backends
+pub struct Singleton;
+
+impl Singleton {
+    /// Create the [`TABLE`] describing holders if it doesn't exist
+    ///
+    /// The table has the columns `name text` (the primary key), `lock_key bigint`, `pid int`, `backend_start
+    /// timestamptz`, `metadata text` and `acquired_at timestamptz`.
+    pub fn ensure_schema(client: &mut SpiClient) -> Result<(), Error> {
+        let create_table = format!(
+            "CREATE UNLOGGED TABLE IF NOT EXISTS {} (\
+             name text PRIMARY KEY, \
+             lock_key bigint NOT NULL, \
+             pid int NOT NULL, \
+             backend_start timestamptz, \
+             metadata text NOT NULL, \
+             acquired_at timestamptz NOT NULL DEFAULT now())",
+            TABLE
+        );
+        client.checked_update(&create_table, None, None)?;
+        Ok(())
+    }
+
+    /// Acquire the singleton `name`, describing the run with `metadata`, or return who holds it
+    ///
+    /// Rows of the table whose backend doesn't hold the lock (left behind by backends that exited, or by
+    /// transactions that rolled back after releasing it) are deleted first. Acquiring a singleton that this backend
+    /// already holds reports it as busy too.
+    pub fn acquire(
+        client: &mut SpiClient,
+        name: &str,
+        metadata: &str,
+    ) -> Result<SingletonGuard, SingletonError> {
+        let key = lock_key(name);
+        rewrite::exempt(|| {
+            (&mut *client).checked_update(
+                &format!(
+                    "DELETE FROM {} s WHERE s.name = $1 AND NOT EXISTS (\
+                     SELECT FROM pg_locks l LEFT JOIN pg_stat_activity a ON a.pid = l.pid WHERE {} AND {})",
+                    TABLE, HELD_LOCK, SAME_BACKEND
+                ),
+                None,
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+                    (PgBuiltInOids::INT8OID.oid(), key.into_datum()),
+                ]),
+            )
+        })?;
+        let held_here = HELD.with(|held| held.borrow().contains(&key));
+        let acquired = !held_here
+            && rewrite::exempt(|| {
+                (&*client).checked_select(
+                    "SELECT pg_try_advisory_lock($1)",
+                    None,
+                    Some(vec![(PgBuiltInOids::INT8OID.oid(), key.into_datum())]),
+                )
+            })?
+            .first()
+            .get_one::<bool>()
+            .unwrap_or_default();
+        if !acquired {
+            return Err(SingletonError::Busy(SingletonBusy {
+                name: name.to_string(),
+                holder: holder(client, name, key)?,
+            }));
+        }
+        let guard = SingletonGuard {
+            name: name.to_string(),
+            key,
+        };
+        HELD.with(|held| held.borrow_mut().insert(key));
+        // Dropping the guard releases the lock if this fails
+        rewrite::exempt(|| {
+            (&mut *client).checked_update(
+                &format!(
+                    "INSERT INTO {} (name, lock_key, pid, backend_start, metadata) \
+                     SELECT $1, $2, pg_backend_pid(), backend_start, $3 \
+                     FROM pg_stat_activity WHERE pid = pg_backend_pid() \
+                     ON CONFLICT (name) DO UPDATE SET lock_key = excluded.lock_key, pid = excluded.pid, \
+                     backend_start = excluded.backend_start, metadata = excluded.metadata, acquired_at = now()",
+                    TABLE
+                ),
+                None,
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+                    (PgBuiltInOids::INT8OID.oid(), key.into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), metadata.into_datum()),
+                ]),
+            )
+        })?;
+        Ok(guard)
+    }
+}
+
+/// Backend holding the lock of `key`, described by the row of `name` if it's its own
+fn holder(client: &SpiClient, name: &str, key: i64) -> Result<Option<Holder>, Error> {
+    let holder = rewrite::exempt(|| {
+        client.checked_select(
+            &format!(
+                "SELECT l.pid, a.backend_start, a.usename::text, a.application_name, s.metadata, s.acquired_at \
+                 FROM pg_locks l LEFT JOIN pg_stat_activity a ON a.pid = l.pid \
+                 LEFT JOIN {} s ON s.name = $1 AND {} \
+                 WHERE {}",
+                TABLE, SAME_BACKEND, HELD_LOCK
+            ),
+            Some(1),
+            Some(vec![
+                (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+                (PgBuiltInOids::INT8OID.oid(), key.into_datum()),
+            ]),
+        )
+    })?
+    .filter_map(|row| {
+        Some(Holder {
+            pid: row.by_ordinal(1).ok()?.value()?,
+            backend_start: row.by_ordinal(2).ok()?.value(),
+            user: row.by_ordinal(3).ok()?.value(),
+            application_name: row.by_ordinal(4).ok()?.value(),
+            metadata: row.by_ordinal(5).ok()?.value(),
+            acquired_at: row.by_ordinal(6).ok()?.value(),
+        })
+    })
+    .next();
+    Ok(holder)
+}
+
+/// Held singleton, released when dropped
+///
+/// Dropping it deletes the row describing it and releases the lock, warning about errors. If it is dropped while
+/// unwinding, neither is attempted: the lock is held until the backend exits (or calls `pg_advisory_unlock_all`), and
+/// the row is deleted by the next acquisition. [`release`](Self::release) returns errors instead.
+#[derive(Debug)]
+pub struct SingletonGuard {
+    name: String,
+    key: i64,
+}
+
+impl SingletonGuard {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Advisory lock key, see [`lock_key`]
+    pub fn key(&self) -> i64 {
+        self.key
+    }
+
+    /// Release the singleton
+    pub fn release(mut self) -> Result<(), Error> {
+        self.internal_release()
+    }
+
+    fn internal_release(&mut self) -> Result<(), Error> {
+        if !HELD.with(|held| held.borrow_mut().remove(&self.key)) {
+            return Ok(());
+        }
+        let delete = (&mut SpiClient).checked_update(
+            &format!(
+                "DELETE FROM {} WHERE name = $1 AND pid = pg_backend_pid()",
+                TABLE
+            ),
+            None,
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                self.name.as_str().into_datum(),
+            )]),
+        );
+        let unlock = rewrite::exempt(|| {
+            (&SpiClient).checked_select(
+                "SELECT pg_advisory_unlock($1)",
+                None,
+                Some(vec![(PgBuiltInOids::INT8OID.oid(), self.key.into_datum())]),
+            )
+        });
+        delete?;
+        unlock?;
+        Ok(())
+    }
+}
+
+impl Drop for SingletonGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        if let Err(err) = self.internal_release() {
+            pgx::warning!("failed to release singleton \"{}\": {}", self.name, err);
+        }
+    }
+}
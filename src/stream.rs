@@ -0,0 +1,297 @@
+use pgx::{pg_sys, PgOid, SpiClient, SpiHeapTupleData, SpiTupleTable};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::panic::AssertUnwindSafe;
+
+use crate::checked::checked_sub_transaction;
+use crate::cursor::CheckedCursor;
+use crate::error::Error;
+use crate::scan::is_empty_query;
+use crate::subtxn::*;
+
+/// Default size of the chunks streamed by [`StreamCommands::checked_select_column_stream`]
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Statistics of a streamed column
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    /// Rows returned by the query
+    pub rows: u64,
+    /// Rows where the column was NULL (and therefore skipped)
+    pub nulls: u64,
+    /// Total bytes written
+    pub bytes: u64,
+}
+
+/// Error returned by [`StreamCommands::checked_select_column_stream`]
+#[derive(Debug)]
+pub enum StreamError {
+    /// Query failed, or an error was caught while detoasting a value
+    Query(Error),
+    /// The column is not of a variable-length type
+    NotVarlena { column: usize, type_oid: PgOid },
+    /// The column ordinal is out of range
+    NoSuchColumn { column: usize },
+    /// Writing to the writer failed
+    Io(std::io::Error),
+}
+
+impl Display for StreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::Query(err) => write!(f, "{}", err),
+            StreamError::NotVarlena { column, type_oid } => write!(
+                f,
+                "column {} is of type {:?}, which is not variable-length",
+                column, type_oid
+            ),
+            StreamError::NoSuchColumn { column } => write!(f, "no column with ordinal {}", column),
+            StreamError::Io(err) => write!(f, "failed to write column value: {}", err),
+        }
+    }
+}
+
+impl From<Error> for StreamError {
+    fn from(err: Error) -> Self {
+        StreamError::Query(err)
+    }
+}
+
+/// Commands streaming column values
+pub trait StreamCommands {
+    /// Execute a read-only command, writing the value of its (1-based) column `col` of every row to `writer`
+    ///
+    /// Values are detoasted and written in chunks of [`DEFAULT_CHUNK_SIZE`] bytes, so that no more than a chunk is
+    /// held in memory at once. NULL values are skipped but counted. Compressed values are decompressed up to the end of
+    /// each chunk while it is read, so only values stored uncompressed (`STORAGE EXTERNAL`) are read in constant memory.
+    fn checked_select_column_stream<W: Write>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        col: usize,
+        writer: &mut W,
+    ) -> Result<StreamStats, StreamError> {
+        self.checked_select_column_stream_chunked(query, args, col, writer, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Same as [`StreamCommands::checked_select_column_stream`], with a custom chunk size
+    fn checked_select_column_stream_chunked<W: Write>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        col: usize,
+        writer: &mut W,
+        chunk_size: usize,
+    ) -> Result<StreamStats, StreamError>;
+
+    /// Execute a read-only command, returning a stream of its rows fetched `batch` rows at a time
+    ///
+    /// See [`RowStream`].
+    fn checked_select_stream(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        batch: usize,
+    ) -> Result<RowStream, Error>;
+}
+
+impl StreamCommands for SpiClient {
+    fn checked_select_column_stream_chunked<W: Write>(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        col: usize,
+        writer: &mut W,
+        chunk_size: usize,
+    ) -> Result<StreamStats, StreamError> {
+        assert!(chunk_size > 0, "chunk size must be positive");
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery.into());
+        }
+        let args = AssertUnwindSafe(args);
+        let writer = AssertUnwindSafe(writer);
+        checked_sub_transaction(move |client| {
+            let (args, mut writer) = (args, writer);
+            let table = client.select(query, None, args.0);
+            if col == 0 || col > table.columns() {
+                return Err(StreamError::NoSuchColumn { column: col });
+            }
+            let type_oid = table.column_type_oid(col).unwrap();
+            if unsafe { pg_sys::get_typlen(type_oid.value()) } != -1 {
+                return Err(StreamError::NotVarlena {
+                    column: col,
+                    type_oid,
+                });
+            }
+            let mut stats = StreamStats::default();
+            for tuple in table {
+                stats.rows += 1;
+                let datum = tuple
+                    .by_ordinal(col)
+                    .ok()
+                    .and_then(|entry| entry.value::<pg_sys::Datum>());
+                match datum {
+                    None => stats.nulls += 1,
+                    Some(datum) => {
+                        stats.bytes += unsafe { write_chunked(datum, &mut *writer, chunk_size) }
+                            .map_err(StreamError::Io)?;
+                    }
+                }
+            }
+            Ok(stats)
+        })?
+    }
+
+    fn checked_select_stream(
+        &self,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+        batch: usize,
+    ) -> Result<RowStream, Error> {
+        assert!(batch > 0, "batch size must be positive");
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery);
+        }
+        check_can_begin()?;
+        let xact = SpiClient.sub_transaction(|xact| xact.rollback_on_drop());
+        let cursor = CheckedCursor::open(self, query, args)?;
+        Ok(RowStream {
+            table: None,
+            cursor: Some(cursor),
+            xact: Some(xact),
+            batch: batch.min(i64::MAX as usize) as i64,
+            exhausted: false,
+            rows: 0,
+            commit_on_drop: false,
+        })
+    }
+}
+
+/// Rows of a query, fetched from a cursor a batch at a time
+///
+/// Only the current batch is held in memory: its tuple table is freed before the next batch is fetched. Rows are
+/// borrowed from the stream by [`RowStream::next_row`], so none can be held past the next call.
+///
+/// The cursor is opened in a sub-transaction owned by the stream, which is rolled back when the stream is dropped
+/// (or committed, see [`RowStream::set_commit_on_drop`]), after closing the cursor. As the sub-transaction remains the
+/// current one until then, the stream must be dropped before any sub-transaction enclosing it ends.
+pub struct RowStream {
+    // Dropped in this order: the tuple table, then the cursor, then the sub-transaction
+    table: Option<(SpiTupleTable, *mut pg_sys::SPITupleTable)>,
+    cursor: Option<CheckedCursor>,
+    xact: Option<SubTransaction<SpiClientWrapper, false>>,
+    batch: i64,
+    exhausted: bool,
+    rows: u64,
+    commit_on_drop: bool,
+}
+
+impl RowStream {
+    /// Next row, fetching the next batch if the current one is exhausted
+    ///
+    /// After an error, the stream returns no more rows.
+    pub fn next_row(&mut self) -> Result<Option<RowRef<'_>>, Error> {
+        loop {
+            if let Some(tuple) = self.table.as_mut().and_then(|(table, _)| table.next()) {
+                self.rows += 1;
+                return Ok(Some(RowRef {
+                    tuple,
+                    _stream: PhantomData,
+                }));
+            }
+            self.free_batch();
+            if self.exhausted {
+                return Ok(None);
+            }
+            // After a failed fetch, the cursor can only be closed
+            self.exhausted = true;
+            let table = self.cursor.as_mut().unwrap().fetch(self.batch)?;
+            self.exhausted = (table.len() as i64) < self.batch;
+            self.table = Some((table, unsafe { pg_sys::SPI_tuptable }));
+        }
+    }
+
+    /// Rows returned so far
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Commit the stream's sub-transaction when dropped instead of rolling it back
+    pub fn set_commit_on_drop(&mut self, commit: bool) {
+        self.commit_on_drop = commit;
+    }
+
+    fn free_batch(&mut self) {
+        if let Some((table, raw)) = self.table.take() {
+            drop(table);
+            unsafe {
+                pg_sys::SPI_freetuptable(raw);
+                if pg_sys::SPI_tuptable == raw {
+                    pg_sys::SPI_tuptable = std::ptr::null_mut();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RowStream {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            // The tuple table and the portal are released as the sub-transaction rolls back
+            return;
+        }
+        self.free_batch();
+        if let Some(cursor) = self.cursor.take() {
+            cursor.close();
+        }
+        if let Some(xact) = self.xact.take() {
+            if self.commit_on_drop {
+                xact.commit();
+            }
+        }
+    }
+}
+
+/// Row borrowed from a [`RowStream`]
+pub struct RowRef<'a> {
+    tuple: SpiHeapTupleData,
+    _stream: PhantomData<&'a mut RowStream>,
+}
+
+impl<'a> Deref for RowRef<'a> {
+    type Target = SpiHeapTupleData;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tuple
+    }
+}
+
+/// Write a varlena datum's contents in chunks of up to `chunk_size` bytes, returning the number of bytes written
+unsafe fn write_chunked<W: Write>(
+    datum: pg_sys::Datum,
+    writer: &mut W,
+    chunk_size: usize,
+) -> std::io::Result<u64> {
+    let len = pg_sys::toast_raw_datum_size(datum) - pg_sys::VARHDRSZ;
+    let mut offset = 0;
+    while offset < len {
+        let count = chunk_size.min(len - offset);
+        let slice =
+            pg_sys::pg_detoast_datum_slice(datum.cast_mut_ptr(), offset as i32, count as i32);
+        let data = std::slice::from_raw_parts(
+            pgx::vardata_any(slice) as *const u8,
+            pgx::varsize_any_exhdr(slice),
+        );
+        let result = writer.write_all(data);
+        let written = data.len();
+        pg_sys::pfree(slice.cast());
+        result?;
+        if written == 0 {
+            break;
+        }
+        offset += written;
+    }
+    Ok(len as u64)
+}
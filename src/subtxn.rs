@@ -1,18 +1,459 @@
-use pgx::{pg_sys, PgMemoryContexts, SpiClient};
-use std::fmt::{Debug, Formatter};
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{pg_sys, PgMemoryContexts, PgOid, PgTryBuilder, SpiClient, SpiTupleTable};
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt::{Debug, Display, Formatter};
 use std::ops::{Deref, DerefMut};
+use std::panic::{AssertUnwindSafe, UnwindSafe};
+use std::time::{Duration, Instant};
+
+use crate::budget::*;
+use crate::checked::*;
+use crate::deferred::*;
+use crate::error::{capture_sqlstate, handled, Error};
+use crate::metrics::{self, Metric};
+use crate::timeline::{self, EventKind};
 
 /// Sub-transaction
 ///
 /// Unless rolled back or committed explicitly, it'll commit if `COMMIT` generic parameter is `true`
-/// (default) or roll back if it is `false`.
+/// (default) or roll back if it is `false`. The implicit commit can be reported or refused with
+/// [`set_drop_policy`].
+///
+/// # SPI stack
+///
+/// The depth of the SPI connection stack is recorded when the sub-transaction begins (see [`spi_depth`]), along with
+/// its innermost connection. If code run in the sub-transaction connects to SPI without finishing the connection (or
+/// the other way around), commands would execute on another connection than the expected one. Such a mismatch, which
+/// changes the innermost connection, is checked for before every command without counting the whole stack again,
+/// which returns [`Error::SpiStackCorruption`] without executing (unchecked commands raise it), and when the
+/// sub-transaction ends: it is rolled back instead of committed, which also undoes connections made in it, and
+/// [`SubTransaction::commit`] raises the error afterwards.
+///
+/// # Foreign sub-transactions
+///
+/// Sub-transactions can also be begun by other means, such as `BeginInternalSubTransaction` or other libraries'
+/// APIs. If one is begun on top of this sub-transaction and not ended, commands would execute in it instead, and
+/// ending this sub-transaction would end it instead. Before every command, and when the sub-transaction ends, Postgres'
+/// current sub-transaction is checked to be this one, which returns [`Error::SubTransactionMismatch`] without executing
+/// (unchecked commands raise it). The sub-transaction is then rolled back instead of committed, along with the ones
+/// begun on top of it; if it was already ended by other means, nothing is rolled back. Ownership of a sub-transaction
+/// can be handed over explicitly with [`SubTransaction::into_raw`] and [`SubTransaction::from_raw`].
 pub struct SubTransaction<Parent, const COMMIT: bool = true> {
+    id: pg_sys::SubTransactionId,
+    /// Depth of the SPI connection stack when the sub-transaction began
+    spi_depth: u32,
+    /// Innermost SPI connections when the sub-transaction began
+    spi_marker: SpiMarker,
     memory_context: pg_sys::MemoryContext,
     resource_owner: pg_sys::ResourceOwner,
     // Should the the transaction be dropped, or was it already
     // committed or rolled back? True if it should be dropped.
     drop: bool,
     parent: Option<Parent>,
+    pub(crate) budget: Option<Budget>,
+    pub(crate) deferred: DeferredQueue,
+    /// When the sub-transaction began
+    began: Instant,
+}
+
+/// Raw state of a sub-transaction that is being started
+///
+/// Passed to the setup closure of [`SubTransaction::begin_with`].
+#[derive(Debug)]
+pub struct SubTransactionRaw {
+    memory_context: pg_sys::MemoryContext,
+    resource_owner: pg_sys::ResourceOwner,
+    id: pg_sys::SubTransactionId,
+}
+
+impl SubTransactionRaw {
+    /// State of sub-transaction `id`, begun while `memory_context` and `resource_owner` were current
+    pub fn new(
+        memory_context: pg_sys::MemoryContext,
+        resource_owner: pg_sys::ResourceOwner,
+        id: pg_sys::SubTransactionId,
+    ) -> Self {
+        Self {
+            memory_context,
+            resource_owner,
+            id,
+        }
+    }
+
+    /// Memory context that was current before the sub-transaction started
+    pub fn memory_context(&self) -> pg_sys::MemoryContext {
+        self.memory_context
+    }
+
+    /// Resource owner that was current before the sub-transaction started
+    pub fn resource_owner(&self) -> pg_sys::ResourceOwner {
+        self.resource_owner
+    }
+
+    /// Id of the new sub-transaction
+    pub fn id(&self) -> pg_sys::SubTransactionId {
+        self.id
+    }
+}
+
+/// Sub-transaction opened by this crate that is still open
+struct OpenSubTransaction {
+    id: pg_sys::SubTransactionId,
+    nest_level: i32,
+    commit_on_drop: bool,
+    memory_context: pg_sys::MemoryContext,
+    resource_owner: pg_sys::ResourceOwner,
+    /// Where it was begun, captured unless the drop policy is [`DropPolicy::Silent`], or in debug builds while
+    /// warning about sub-transactions held open for long
+    backtrace: Option<Backtrace>,
+    /// Last call to [`SubTransaction::heartbeat`]
+    heartbeat: Option<Instant>,
+}
+
+thread_local! {
+    static OPEN_SUB_TRANSACTIONS: RefCell<Vec<OpenSubTransaction>> = const { RefCell::new(Vec::new()) };
+    static DROP_POLICY: Cell<DropPolicy> = const { Cell::new(DropPolicy::Silent) };
+    static HELD_WARNING: Cell<Option<Duration>> = const { Cell::new(None) };
+    static NO_PROGRESS_WARNING: Cell<Option<Duration>> = const { Cell::new(None) };
+    /// Sub-transactions rolled back by [`recovery_scope`] whose handles haven't been ended or dropped yet
+    static POISONED: RefCell<Vec<pg_sys::SubTransactionId>> = const { RefCell::new(Vec::new()) };
+    /// Sub-transaction this crate is committing or rolling back, 0 if none
+    static ENDING: Cell<pg_sys::SubTransactionId> = const { Cell::new(0) };
+}
+
+/// Whether the sub-transaction being ended was begun by this crate, rather than being a savepoint cycle the timeline
+/// records
+pub(crate) fn is_ending_own(id: pg_sys::SubTransactionId) -> bool {
+    ENDING.with(Cell::get) == id || POISONED.with(|poisoned| poisoned.borrow().contains(&id))
+}
+
+/// Run `end`, which ends the sub-transaction `id` begun by this crate
+fn ending_own(id: pg_sys::SubTransactionId, end: impl FnOnce()) {
+    let previous = ENDING.with(|ending| ending.replace(id));
+    end();
+    ENDING.with(|ending| ending.set(previous));
+}
+
+/// Forgets all open sub-transactions when the top-level transaction ends, as Postgres ends them too
+struct ForgetOpenSubTransactions;
+
+impl Drop for ForgetOpenSubTransactions {
+    fn drop(&mut self) {
+        OPEN_SUB_TRANSACTIONS.with(|open| open.borrow_mut().clear());
+        POISONED.with(|poisoned| poisoned.borrow_mut().clear());
+    }
+}
+
+/// Record Postgres' current sub-transaction as opened by this crate, returning its id
+fn track_open(commit_on_drop: bool) -> pg_sys::SubTransactionId {
+    let entry = unsafe {
+        OpenSubTransaction {
+            id: pg_sys::GetCurrentSubTransactionId(),
+            nest_level: pg_sys::GetCurrentTransactionNestLevel(),
+            commit_on_drop,
+            memory_context: pg_sys::CurTransactionContext,
+            resource_owner: pg_sys::CurrentResourceOwner,
+            backtrace: (drop_policy() != DropPolicy::Silent
+                || cfg!(debug_assertions) && HELD_WARNING.with(Cell::get).is_some())
+            .then(Backtrace::force_capture),
+            heartbeat: None,
+        }
+    };
+    let id = entry.id;
+    timeline::record(|| EventKind::SubTransactionBegin {
+        id,
+        depth: entry.nest_level,
+    });
+    let depth = OPEN_SUB_TRANSACTIONS.with(|open| {
+        let mut open = open.borrow_mut();
+        open.push(entry);
+        open.len()
+    });
+    metrics::add(Metric::SubTransactionsBegun, 1);
+    metrics::raise(Metric::PeakSubTransactionDepth, depth as u64);
+    if depth == 1 {
+        PgMemoryContexts::TopTransactionContext.leak_and_drop_on_delete(ForgetOpenSubTransactions);
+    }
+    id
+}
+
+/// Forget the sub-transaction, returning its transaction nesting level
+fn track_closed(id: pg_sys::SubTransactionId) -> Option<i32> {
+    OPEN_SUB_TRANSACTIONS.with(|open| {
+        let mut open = open.borrow_mut();
+        let index = open.iter().rposition(|entry| entry.id == id)?;
+        Some(open.remove(index).nest_level)
+    })
+}
+
+/// Where the sub-transaction was begun, if it was captured
+fn take_backtrace(id: pg_sys::SubTransactionId) -> Option<Backtrace> {
+    OPEN_SUB_TRANSACTIONS.with(|open| {
+        open.borrow_mut()
+            .iter_mut()
+            .find(|entry| entry.id == id)
+            .and_then(|entry| entry.backtrace.take())
+    })
+}
+
+/// Whether the sub-transaction's handle was poisoned by [`recovery_scope`], forgetting it if so
+fn take_poisoned(id: pg_sys::SubTransactionId) -> bool {
+    POISONED.with(|poisoned| {
+        let mut poisoned = poisoned.borrow_mut();
+        let len = poisoned.len();
+        poisoned.retain(|&poisoned| poisoned != id);
+        poisoned.len() != len
+    })
+}
+
+fn track_drop_mode(id: pg_sys::SubTransactionId, commit_on_drop: bool) {
+    OPEN_SUB_TRANSACTIONS.with(|open| {
+        if let Some(entry) = open.borrow_mut().iter_mut().find(|entry| entry.id == id) {
+            entry.commit_on_drop = commit_on_drop;
+        }
+    });
+}
+
+/// Warn when sub-transactions held open for longer than `threshold` end, see
+/// [`config::set_subtxn_duration_warning`](crate::config::set_subtxn_duration_warning)
+pub(crate) fn set_held_warning(threshold: Option<Duration>) {
+    HELD_WARNING.with(|warning| warning.set(threshold));
+}
+
+/// Warn when sub-transactions without a heartbeat for longer than `threshold` end, see
+/// [`config::set_subtxn_no_progress_warning`](crate::config::set_subtxn_no_progress_warning)
+pub(crate) fn set_no_progress_warning(threshold: Option<Duration>) {
+    NO_PROGRESS_WARNING.with(|warning| warning.set(threshold));
+}
+
+/// What happens when a sub-transaction that commits on drop is dropped without being committed or rolled back
+///
+/// Relying on the implicit commit can hide logic errors, such as an early return committing half-done work. Explicit
+/// [`SubTransaction::commit`] and [`SubTransaction::rollback`], sub-transactions that roll back on drop and those
+/// dropped while panicking are not affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Commit (the default)
+    #[default]
+    Silent,
+    /// Commit, emitting a warning with the backtrace of where the sub-transaction was begun
+    Warn,
+    /// Roll back instead, emitting the same warning
+    Error,
+}
+
+/// Current drop policy of this backend
+pub fn drop_policy() -> DropPolicy {
+    DROP_POLICY.with(Cell::get)
+}
+
+/// Set the drop policy of this backend until the returned guard is dropped, which restores the previous one
+///
+/// Backtraces are only captured for sub-transactions begun while the policy is not [`DropPolicy::Silent`].
+#[must_use = "the previous policy is restored when the guard is dropped"]
+pub fn set_drop_policy(policy: DropPolicy) -> DropPolicyGuard {
+    DropPolicyGuard {
+        previous: DROP_POLICY.with(|current| current.replace(policy)),
+    }
+}
+
+/// Restores the previous drop policy when dropped, see [`set_drop_policy`]
+#[derive(Debug)]
+pub struct DropPolicyGuard {
+    previous: DropPolicy,
+}
+
+impl Drop for DropPolicyGuard {
+    fn drop(&mut self) {
+        DROP_POLICY.with(|current| current.set(self.previous));
+    }
+}
+
+/// Whether the sub-transaction state is the one this crate expects
+///
+/// That is, Postgres' current sub-transaction is the innermost one opened by this crate that is still open (if
+/// any). It isn't if a sub-transaction was started or ended by other means, such as when an error raised in a
+/// sub-transaction started directly was caught without rolling it back. Errors returned by checked commands always
+/// leave it clean (see [`was_handled`](crate::error::was_handled)).
+///
+/// Whether resources were released properly can't be verified.
+pub fn state_is_clean() -> bool {
+    OPEN_SUB_TRANSACTIONS.with(|open| match open.borrow().last() {
+        Some(innermost) => unsafe {
+            innermost.id == pg_sys::GetCurrentSubTransactionId()
+                && innermost.nest_level == pg_sys::GetCurrentTransactionNestLevel()
+        },
+        None => true,
+    })
+}
+
+/// State of a sub-transaction opened by this crate, as reported by [`debug_info`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubTxnDebugInfo {
+    pub id: pg_sys::SubTransactionId,
+    /// Transaction nesting level (the top-level transaction is at 1)
+    pub depth: i32,
+    /// Whether it commits (rather than rolls back) if dropped
+    pub commit_on_drop: bool,
+    /// Address of the sub-transaction's memory context (`CurTransactionContext`), for identification only
+    pub memory_context: usize,
+    /// Address of the sub-transaction's resource owner, for identification only
+    pub resource_owner: usize,
+    /// Whether it's Postgres' current sub-transaction
+    pub current: bool,
+    /// Whether Postgres considers it active (neither committed nor rolled back)
+    pub active: bool,
+}
+
+/// State of the sub-transactions opened by this crate that are still open, innermost first
+///
+/// Safe to call at any point, including while an error is being handled: nothing is allocated in or read from
+/// Postgres memory.
+pub fn debug_info() -> Vec<SubTxnDebugInfo> {
+    let current = unsafe { pg_sys::GetCurrentSubTransactionId() };
+    OPEN_SUB_TRANSACTIONS.with(|open| {
+        open.borrow()
+            .iter()
+            .rev()
+            .map(|entry| SubTxnDebugInfo {
+                id: entry.id,
+                depth: entry.nest_level,
+                commit_on_drop: entry.commit_on_drop,
+                memory_context: entry.memory_context as usize,
+                resource_owner: entry.resource_owner as usize,
+                current: entry.id == current,
+                active: unsafe { pg_sys::SubTransactionIsActive(entry.id) },
+            })
+            .collect()
+    })
+}
+
+/// [`debug_info`] formatted one line per sub-transaction, innermost first
+///
+/// Empty if there are no open sub-transactions opened by this crate.
+pub fn debug_dump() -> String {
+    debug_info()
+        .iter()
+        .map(|info| {
+            format!(
+                "subxid={} depth={} on_drop={} memory_context={:#x} resource_owner={:#x} current={} active={}\n",
+                info.id,
+                info.depth,
+                if info.commit_on_drop { "commit" } else { "rollback" },
+                info.memory_context,
+                info.resource_owner,
+                info.current,
+                info.active
+            )
+        })
+        .collect()
+}
+
+/// Panic caught by [`recovery_scope`], once recovered from
+#[derive(Debug)]
+pub struct RecoveredPanic {
+    /// Payload of the panic, as `std::panic::catch_unwind` returns it
+    pub payload: Box<dyn Any + Send>,
+    /// Sub-transactions that were rolled back, whether they were opened by this crate or not
+    pub rolled_back_levels: u32,
+}
+
+impl Display for RecoveredPanic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let message = match self.payload.downcast_ref::<&str>() {
+            Some(message) => Some(*message),
+            None => self.payload.downcast_ref::<String>().map(String::as_str),
+        };
+        write!(
+            f,
+            "recovered from a panic ({}) by rolling back {} sub-transactions",
+            message.unwrap_or("no message"),
+            self.rolled_back_levels
+        )
+    }
+}
+
+/// Run `f`, recovering from a panic unwinding out of it by rolling back the sub-transactions it left open
+///
+/// Meant for code whose errors unwind as panics through frames holding sub-transactions, such as an embedded scripting
+/// runtime: their drops then run in whatever order the runtime cleans up (or not at all, if it keeps them), which can
+/// leave the wrong sub-transaction current. Once `f` panicked, every sub-transaction begun since `recovery_scope` was
+/// called that is still open is rolled back, innermost first: those opened by this crate along with whatever was begun
+/// on top of them, then the ones begun by other means. The handles of those opened by this crate are poisoned:
+/// dropping them later does nothing, while [`SubTransaction::checked_commit`] returns [`CommitError::Poisoned`] and
+/// [`SubTransaction::commit`] and [`SubTransaction::rollback`] panic. The resource owner and memory context that were
+/// current on entry are made current again, and the panic is returned.
+///
+/// Scopes can be nested, each recovering to the state it was entered in. If `f` ended the sub-transaction that was
+/// current on entry, there is nothing to recover to: the panic keeps unwinding, for an enclosing scope to recover.
+/// Postgres errors unwind as panics too, but they aren't recovered from this way: the sub-transactions are rolled back
+/// all the same, then the error is raised again, as one raised outside of them would need the sub-transaction current
+/// on entry to be rolled back too; checked commands and [`SubTransaction::shielded`] catch those.
+pub fn recovery_scope<R, F: FnOnce() -> R + UnwindSafe>(f: F) -> Result<R, RecoveredPanic> {
+    let (id, nest_level) = unsafe {
+        (
+            pg_sys::GetCurrentSubTransactionId(),
+            pg_sys::GetCurrentTransactionNestLevel(),
+        )
+    };
+    let depth = OPEN_SUB_TRANSACTIONS.with(|open| open.borrow().len());
+    let memory_context = PgMemoryContexts::CurrentMemoryContext.value();
+    let resource_owner = unsafe { pg_sys::CurrentResourceOwner };
+    let caught = PgTryBuilder::new(move || Ok(f()))
+        .catch_others(|e| Err(e))
+        .execute();
+    let caught = match caught {
+        Ok(result) => return Ok(result),
+        Err(caught) => caught,
+    };
+    let opened = OPEN_SUB_TRANSACTIONS.with(|open| {
+        let mut open = open.borrow_mut();
+        let at = depth.min(open.len());
+        open.split_off(at)
+    });
+    POISONED.with(|poisoned| {
+        poisoned
+            .borrow_mut()
+            .extend(opened.iter().map(|entry| entry.id))
+    });
+    let mut rolled_back_levels = 0;
+    unsafe {
+        for entry in opened.iter().rev() {
+            if entry.nest_level > nest_level && pg_sys::SubTransactionIsActive(entry.id) {
+                metrics::add(Metric::SubTransactionsRolledBack, 1);
+                timeline::record(|| EventKind::SubTransactionRollback {
+                    id: entry.id,
+                    depth: entry.nest_level,
+                });
+                while pg_sys::GetCurrentTransactionNestLevel() >= entry.nest_level {
+                    pg_sys::RollbackAndReleaseCurrentSubTransaction();
+                    rolled_back_levels += 1;
+                }
+            }
+        }
+        while pg_sys::GetCurrentTransactionNestLevel() > nest_level {
+            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+            rolled_back_levels += 1;
+        }
+        if pg_sys::GetCurrentSubTransactionId() != id {
+            std::panic::resume_unwind(Box::new(caught));
+        }
+        pg_sys::CurrentResourceOwner = resource_owner;
+    }
+    PgMemoryContexts::For(memory_context).set_as_current();
+    let payload = match caught {
+        CaughtError::RustPanic { payload, .. } => payload,
+        // Rolling back doesn't handle the error as far as Postgres is concerned, so it's raised again
+        caught => std::panic::resume_unwind(Box::new(caught)),
+    };
+    Err(RecoveredPanic {
+        payload,
+        rolled_back_levels,
+    })
 }
 
 impl<Parent, const COMMIT: bool> Debug for SubTransaction<Parent, COMMIT> {
@@ -30,50 +471,442 @@ impl<Parent, const COMMIT: bool> SubTransaction<Parent, COMMIT> {
         let ctx = PgMemoryContexts::CurrentMemoryContext.value();
         // Remember resource owner before starting the sub-transaction
         let resource_owner = unsafe { pg_sys::CurrentResourceOwner };
+        let began = Instant::now();
         unsafe {
             pg_sys::BeginInternalSubTransaction(std::ptr::null());
         }
+        let id = track_open(COMMIT);
         // Switch to the outer memory context so that all allocations remain
         // there instead of the sub-transaction's context
         PgMemoryContexts::For(ctx).set_as_current();
         Self {
+            id,
+            spi_depth: spi_depth(),
+            spi_marker: SpiMarker::current(),
             memory_context: ctx,
             drop: true,
             resource_owner,
             parent: Some(parent),
+            budget: None,
+            deferred: DeferredQueue::default(),
+            began,
+        }
+    }
+
+    /// Create a new sub-transaction, running `setup` right after it has begun
+    ///
+    /// `setup` runs before the memory context is switched back to the outer one, so the current
+    /// memory context and resource owner are those of the new sub-transaction. If `setup` raises an
+    /// error, the new sub-transaction is rolled back, `parent` is dropped and the error is returned.
+    pub fn begin_with<F: FnOnce(&SubTransactionRaw) + UnwindSafe>(
+        parent: Parent,
+        setup: F,
+    ) -> Result<Self, Error> {
+        let ctx = PgMemoryContexts::CurrentMemoryContext.value();
+        let resource_owner = unsafe { pg_sys::CurrentResourceOwner };
+        let began = Instant::now();
+        unsafe {
+            pg_sys::BeginInternalSubTransaction(std::ptr::null());
         }
+        let raw = SubTransactionRaw {
+            memory_context: ctx,
+            resource_owner,
+            id: unsafe { pg_sys::GetCurrentSubTransactionId() },
+        };
+        let raw_ref = &raw;
+        let result = capture_sqlstate(|| {
+            PgTryBuilder::new(move || {
+                setup(raw_ref);
+                Ok(())
+            })
+            .catch_others(|e| Err(e))
+            .execute()
+        });
+        if result.is_err() {
+            unsafe {
+                ending_own(raw.id, || pg_sys::RollbackAndReleaseCurrentSubTransaction());
+                pg_sys::CurrentResourceOwner = resource_owner;
+            }
+        }
+        PgMemoryContexts::For(ctx).set_as_current();
+        result.map_err(handled).map(|_| Self {
+            id: track_open(COMMIT),
+            spi_depth: spi_depth(),
+            spi_marker: SpiMarker::current(),
+            memory_context: ctx,
+            drop: true,
+            resource_owner,
+            parent: Some(parent),
+            budget: None,
+            deferred: DeferredQueue::default(),
+            began,
+        })
     }
 
     /// Commit the transaction, returning its parent
-    pub fn commit(mut self) -> Parent {
-        self.internal_commit();
+    ///
+    /// Work deferred with [`SubTransaction::defer`] and [`SubTransaction::defer_fn`] runs first. If the SPI
+    /// connection stack is not as deep as when the sub-transaction began, or deferred work fails, it is rolled back
+    /// instead and the error is raised; [`SubTransaction::checked_commit`] returns it instead.
+    pub fn commit(self) -> Parent {
+        let id = self.id;
+        match self.checked_commit() {
+            (parent, Ok(())) => parent,
+            (_, Err(err)) => panic!("sub-transaction {} was rolled back: {}", id, err),
+        }
+    }
+
+    /// Commit the transaction, returning its parent, or roll it back and return why it couldn't be committed
+    ///
+    /// Returns [`CommitError::Poisoned`] if [`recovery_scope`] already rolled it back.
+    pub fn checked_commit(mut self) -> (Parent, Result<(), CommitError>) {
+        if take_poisoned(self.id) {
+            self.drop = false;
+            return (self.parent.take().unwrap(), Err(CommitError::Poisoned));
+        }
+        let result = self
+            .check_state()
+            .map_err(CommitError::from)
+            .and_then(|_| self.deferred.run());
+        if result.is_ok() {
+            self.internal_commit();
+        } else {
+            self.internal_rollback();
+        }
         self.drop = false;
-        self.parent.take().unwrap()
+        (self.parent.take().unwrap(), result)
     }
 
     /// Rollback the transaction, returning its parent
+    ///
+    /// Panics if [`recovery_scope`] already rolled it back, as the caller's idea of what is rolled back is then wrong.
     pub fn rollback(mut self) -> Parent {
+        if take_poisoned(self.id) {
+            self.drop = false;
+            panic!(
+                "sub-transaction {} was already rolled back by recovery_scope",
+                self.id
+            );
+        }
         self.internal_rollback();
         self.drop = false;
         self.parent.take().unwrap()
     }
 
+    /// Take ownership of Postgres' current sub-transaction, begun by other means, as described by `raw`
+    ///
+    /// Returns [`Error::SubTransactionMismatch`] (and drops `parent`) if it is not the current one.
+    ///
+    /// # Safety
+    ///
+    /// Whatever began the sub-transaction must not end it: from now on, only the returned sub-transaction does.
+    pub unsafe fn from_raw(parent: Parent, raw: SubTransactionRaw) -> Result<Self, Error> {
+        let current = pg_sys::GetCurrentSubTransactionId();
+        if current != raw.id {
+            return Err(Error::SubTransactionMismatch {
+                expected: raw.id,
+                actual: current,
+            });
+        }
+        Ok(Self {
+            id: track_open(COMMIT),
+            spi_depth: spi_depth(),
+            spi_marker: SpiMarker::current(),
+            memory_context: raw.memory_context,
+            drop: true,
+            resource_owner: raw.resource_owner,
+            parent: Some(parent),
+            budget: None,
+            deferred: DeferredQueue::default(),
+            // What happened before it was taken over isn't known
+            began: Instant::now(),
+        })
+    }
+
+    /// Give up ownership of the sub-transaction without ending it, returning its parent along with what it takes to
+    /// end it by other means
+    ///
+    /// After ending it, the memory context and resource owner of the returned [`SubTransactionRaw`] should be made
+    /// current again. Panics, rolling the sub-transaction back, if it is not Postgres' current sub-transaction (see
+    /// [`Error::SubTransactionMismatch`] and [`Error::SpiStackCorruption`]) or if work is deferred to its commit.
+    pub fn into_raw(mut self) -> (Parent, SubTransactionRaw) {
+        if let Err(err) = self.check_state() {
+            panic!("{}", err);
+        }
+        assert!(
+            self.deferred.is_empty(),
+            "sub-transaction {} has deferred work",
+            self.id
+        );
+        track_closed(self.id);
+        self.drop = false;
+        let raw = SubTransactionRaw::new(self.memory_context, self.resource_owner, self.id);
+        (self.parent.take().unwrap(), raw)
+    }
+
+    /// Same as [`SubTransaction::from_raw`]: the pgx version this crate is built against has no sub-transaction type
+    /// of its own to take over
+    ///
+    /// # Safety
+    ///
+    /// See [`SubTransaction::from_raw`].
+    #[deprecated(note = "use `SubTransaction::from_raw`")]
+    pub unsafe fn from_pgx(parent: Parent, raw: SubTransactionRaw) -> Result<Self, Error> {
+        Self::from_raw(parent, raw)
+    }
+
+    /// Same as [`SubTransaction::into_raw`]: the pgx version this crate is built against has no sub-transaction type
+    /// of its own to hand over to
+    #[deprecated(note = "use `SubTransaction::into_raw`")]
+    pub fn into_pgx(self) -> (Parent, SubTransactionRaw) {
+        self.into_raw()
+    }
+
+    /// Id of the sub-transaction
+    pub fn id(&self) -> pg_sys::SubTransactionId {
+        self.id
+    }
+
+    /// Time since the sub-transaction began (or was taken over with [`SubTransaction::from_raw`])
+    pub fn elapsed(&self) -> Duration {
+        self.began.elapsed()
+    }
+
+    /// Record that work done while the sub-transaction is open is making progress, and process pending interrupts
+    ///
+    /// Code doing long work outside the database while holding the sub-transaction open (along with its locks and
+    /// snapshot) should call it regularly: the no-progress warning (see
+    /// [`config::set_subtxn_no_progress_warning`](crate::config::set_subtxn_no_progress_warning)) is counted from the
+    /// last call, and a query cancel or termination request is acted upon by raising an error, as Postgres' own
+    /// long-running loops do.
+    pub fn heartbeat(&self) {
+        let now = Instant::now();
+        OPEN_SUB_TRANSACTIONS.with(|open| {
+            if let Some(entry) = open
+                .borrow_mut()
+                .iter_mut()
+                .find(|entry| entry.id == self.id)
+            {
+                entry.heartbeat = Some(now);
+            }
+        });
+        pgx::check_for_interrupts!();
+    }
+
+    /// Time since the last [`SubTransaction::heartbeat`], or since the sub-transaction began if there was none
+    pub fn since_heartbeat(&self) -> Duration {
+        self.last_heartbeat().unwrap_or(self.began).elapsed()
+    }
+
+    fn last_heartbeat(&self) -> Option<Instant> {
+        OPEN_SUB_TRANSACTIONS.with(|open| {
+            open.borrow()
+                .iter()
+                .find(|entry| entry.id == self.id)
+                .and_then(|entry| entry.heartbeat)
+        })
+    }
+
+    /// Warn if the sub-transaction was held open for longer than configured, right before it ends
+    fn warn_if_held_long(&self) {
+        let (held, no_progress) = (
+            HELD_WARNING.with(Cell::get),
+            NO_PROGRESS_WARNING.with(Cell::get),
+        );
+        if held.is_none() && no_progress.is_none() || std::thread::panicking() {
+            return;
+        }
+        let elapsed = self.elapsed();
+        if let Some(threshold) = held.filter(|threshold| elapsed > *threshold) {
+            let begun_at = OPEN_SUB_TRANSACTIONS.with(|open| {
+                open.borrow()
+                    .iter()
+                    .find(|entry| entry.id == self.id)
+                    .and_then(|entry| entry.backtrace.as_ref())
+                    .map_or(String::new(), |backtrace| {
+                        format!("; begun at:\n{}", backtrace)
+                    })
+            });
+            pgx::warning!(
+                "sub-transaction {} was held open for {:?}, longer than {:?}{}",
+                self.id,
+                elapsed,
+                threshold,
+                begun_at
+            );
+        }
+        let since_heartbeat = self.since_heartbeat();
+        if let Some(threshold) = no_progress.filter(|threshold| since_heartbeat > *threshold) {
+            pgx::warning!(
+                "sub-transaction {} made no progress for {:?} before ending, longer than {:?}",
+                self.id,
+                since_heartbeat,
+                threshold
+            );
+        }
+    }
+
     /// Returns the memory context this transaction is in
     pub fn memory_context(&self) -> PgMemoryContexts {
         PgMemoryContexts::For(self.memory_context)
     }
 
+    /// Check that this is Postgres' current sub-transaction, and that the SPI connection stack is as deep as when it
+    /// began
+    pub(crate) fn check_state(&self) -> Result<(), Error> {
+        let current = unsafe { pg_sys::GetCurrentSubTransactionId() };
+        if current != self.id {
+            return Err(Error::SubTransactionMismatch {
+                expected: self.id,
+                actual: current,
+            });
+        }
+        // Only the innermost connections are compared, the stack is only counted to report a mismatch
+        if SpiMarker::current() == self.spi_marker {
+            Ok(())
+        } else {
+            Err(Error::SpiStackCorruption {
+                expected: self.spi_depth,
+                actual: spi_depth(),
+            })
+        }
+    }
+
+    /// Execute a read-only command in a child sub-transaction, returning an error if one occurred
+    ///
+    /// An error rolls back the child sub-transaction only, leaving this one usable. This shadows `SpiClient::select`,
+    /// use [`SubTransaction::select_unchecked`] to execute a command directly in this sub-transaction.
+    pub fn select(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        self.check_state()?;
+        match &self.budget {
+            Some(budget) => SpiClient.checked_select_within(budget, query, limit, args),
+            None => (&SpiClient).checked_select(query, limit, args),
+        }
+    }
+
+    /// Execute a mutable command in a child sub-transaction, returning an error if one occurred
+    ///
+    /// An error rolls back the child sub-transaction only, leaving this one usable. This shadows `SpiClient::update`,
+    /// use [`SubTransaction::update_unchecked`] to execute a command directly in this sub-transaction.
+    pub fn update(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        self.check_state()?;
+        match &self.budget {
+            Some(budget) => SpiClient.checked_update_within(budget, query, limit, args),
+            None => (&mut SpiClient).checked_update(query, limit, args),
+        }
+    }
+
+    /// Execute a read-only command directly in this sub-transaction
+    ///
+    /// Any error aborts the enclosing statement, bypassing this sub-transaction's error handling.
+    pub fn select_unchecked(
+        &self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> SpiTupleTable {
+        if let Err(err) = self.check_state() {
+            panic!("{}", err);
+        }
+        check_spi_refusal(query);
+        SpiClient.select(query, limit, args)
+    }
+
+    /// Execute a mutable command directly in this sub-transaction
+    ///
+    /// Any error aborts the enclosing statement, bypassing this sub-transaction's error handling.
+    pub fn update_unchecked(
+        &mut self,
+        query: &str,
+        limit: Option<i64>,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> SpiTupleTable {
+        if let Err(err) = self.check_state() {
+            panic!("{}", err);
+        }
+        check_spi_refusal(query);
+        SpiClient.update(query, limit, args)
+    }
+
+    /// Run `f` in a child sub-transaction, returning any error raised in it (through this crate or not) as a value
+    ///
+    /// Errors Postgres raises anywhere in `f`, including by calling pgx or `pg_sys` functions directly, are caught:
+    /// the child sub-transaction is rolled back, leaving this one usable, and the error is returned. Otherwise, the
+    /// child is committed into this one and the result of `f` is returned. Rust panics in `f` roll the child back as
+    /// well but keep propagating, so that bugs aren't taken for database errors. `f` gets the child sub-transaction,
+    /// as this one isn't Postgres' current sub-transaction until `f` returns (its commands would fail).
+    ///
+    /// # Unwind safety
+    ///
+    /// A caught error interrupts `f` wherever it was raised, like a panic, so whatever `f` had partly changed outside
+    /// the database (such as a registry it captured) stays partly changed; that is why `f` must be `UnwindSafe`.
+    /// Closures capturing `&mut` state aren't, and can be wrapped with [`assert_shield_safe`] once the state is known
+    /// to be consistent at every point an error can be raised (for instance, by only changing it after the last call
+    /// that can raise one).
+    ///
+    /// Panics, without running `f`, if this is not Postgres' current sub-transaction or the SPI connection stack is
+    /// not as it was when it began, as unchecked commands do.
+    pub fn shielded<R, F>(&mut self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut SubTransaction<SpiClientWrapper, false>) -> R + UnwindSafe,
+    {
+        if let Err(err) = self.check_state() {
+            panic!("{}", err);
+        }
+        let child = SpiClient.sub_transaction(|xact| xact.rollback_on_drop());
+        let (result, child) = shield(child, false, f)?;
+        child.commit();
+        Ok(result)
+    }
+
+    /// Advance the command counter, making the changes made so far visible to commands that take a new snapshot
+    ///
+    /// Commands executed through pgx advance it themselves before taking their snapshot, so this is only needed for
+    /// changes made directly (not through SPI) to be visible to code that runs before the next command.
+    pub fn advance_command_counter(&self) {
+        unsafe { pg_sys::CommandCounterIncrement() }
+    }
+
     fn internal_rollback(&self) {
+        self.warn_if_held_long();
+        let depth = track_closed(self.id).unwrap_or_default();
+        metrics::add(Metric::SubTransactionsRolledBack, 1);
+        timeline::record(|| EventKind::SubTransactionRollback { id: self.id, depth });
         unsafe {
-            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+            // Sub-transactions begun on top of this one by other means are rolled back along with it, while nothing is
+            // if it was ended by other means
+            if pg_sys::GetCurrentSubTransactionId() == self.id
+                || pg_sys::SubTransactionIsActive(self.id)
+            {
+                while pg_sys::GetCurrentSubTransactionId() != self.id {
+                    pg_sys::RollbackAndReleaseCurrentSubTransaction();
+                }
+                ending_own(self.id, || {
+                    pg_sys::RollbackAndReleaseCurrentSubTransaction()
+                });
+            }
             pg_sys::CurrentResourceOwner = self.resource_owner;
         }
         PgMemoryContexts::For(self.memory_context).set_as_current();
     }
 
     fn internal_commit(&self) {
+        self.warn_if_held_long();
+        let depth = track_closed(self.id).unwrap_or_default();
+        metrics::add(Metric::SubTransactionsCommitted, 1);
+        timeline::record(|| EventKind::SubTransactionCommit { id: self.id, depth });
         unsafe {
-            pg_sys::ReleaseCurrentSubTransaction();
+            ending_own(self.id, || pg_sys::ReleaseCurrentSubTransaction());
             pg_sys::CurrentResourceOwner = self.resource_owner;
         }
         PgMemoryContexts::For(self.memory_context).set_as_current();
@@ -97,13 +930,20 @@ impl<Parent> SubTransaction<Parent, false> {
 impl<Parent> Into<SubTransaction<Parent, false>> for SubTransaction<Parent, true> {
     fn into(mut self) -> SubTransaction<Parent, false> {
         let result = SubTransaction {
+            id: self.id,
+            spi_depth: self.spi_depth,
+            spi_marker: self.spi_marker,
             memory_context: self.memory_context,
             resource_owner: self.resource_owner,
             drop: self.drop,
             parent: self.parent.take(),
+            budget: self.budget.take(),
+            deferred: std::mem::take(&mut self.deferred),
+            began: self.began,
         };
         // Make sure original sub-transaction won't commit
         self.drop = false;
+        track_drop_mode(result.id, false);
         result
     }
 }
@@ -111,24 +951,86 @@ impl<Parent> Into<SubTransaction<Parent, false>> for SubTransaction<Parent, true
 impl<Parent> Into<SubTransaction<Parent, true>> for SubTransaction<Parent, false> {
     fn into(mut self) -> SubTransaction<Parent, true> {
         let result = SubTransaction {
+            id: self.id,
+            spi_depth: self.spi_depth,
+            spi_marker: self.spi_marker,
             memory_context: self.memory_context,
             resource_owner: self.resource_owner,
             drop: self.drop,
             parent: self.parent.take(),
+            budget: self.budget.take(),
+            deferred: std::mem::take(&mut self.deferred),
+            began: self.began,
         };
         // Make sure original sub-transaction won't roll back
         self.drop = false;
+        track_drop_mode(result.id, true);
         result
     }
 }
 
 impl<Parent, const COMMIT: bool> Drop for SubTransaction<Parent, COMMIT> {
     fn drop(&mut self) {
+        // Poisoned sub-transactions were already rolled back by `recovery_scope`
+        if self.drop && take_poisoned(self.id) {
+            return;
+        }
         if self.drop {
-            if COMMIT {
+            if !COMMIT {
+                self.internal_rollback();
+                return;
+            }
+            if let Err(err) = self.check_state() {
+                if !std::thread::panicking() {
+                    pgx::warning!(
+                        "sub-transaction {} was rolled back instead of committed: {}",
+                        self.id,
+                        err
+                    );
+                }
+                self.internal_rollback();
+                return;
+            }
+            // Deferred work isn't run while panicking, so the sub-transaction can't commit without it
+            if std::thread::panicking() && !self.deferred.is_empty() {
+                self.internal_rollback();
+                return;
+            }
+            let policy = drop_policy();
+            if policy != DropPolicy::Error && !std::thread::panicking() {
+                if let Err(err) = self.deferred.run() {
+                    pgx::warning!(
+                        "sub-transaction {} was rolled back instead of committed: {}",
+                        self.id,
+                        err
+                    );
+                    self.internal_rollback();
+                    return;
+                }
+            }
+            if policy == DropPolicy::Silent || std::thread::panicking() {
                 self.internal_commit();
+                return;
+            }
+            let action = if policy == DropPolicy::Error {
+                "rolled back instead of committed"
             } else {
+                "committed"
+            };
+            let begun_at = match take_backtrace(self.id) {
+                Some(backtrace) => format!("begun at:\n{}", backtrace),
+                None => "begun while the drop policy was silent".to_string(),
+            };
+            pgx::warning!(
+                "sub-transaction {} was dropped without being committed or rolled back, so it was {}; {}",
+                self.id,
+                action,
+                begun_at
+            );
+            if policy == DropPolicy::Error {
                 self.internal_rollback();
+            } else {
+                self.internal_commit();
             }
         }
     }
@@ -157,6 +1059,12 @@ impl SpiClientWrapper {
     }
 }
 
+impl From<SpiClient> for SpiClientWrapper {
+    fn from(client: SpiClient) -> Self {
+        SpiClientWrapper(client)
+    }
+}
+
 impl Deref for SpiClientWrapper {
     type Target = SpiClient;
     fn deref(&self) -> &Self::Target {
@@ -179,6 +1087,45 @@ pub trait SubTransactionExt {
     fn sub_transaction<F: FnOnce(SubTransaction<Self::T>) -> R, R>(self, f: F) -> R
     where
         Self: Sized;
+
+    /// Consume `self` and return a sub-transaction, unless one can't be started
+    ///
+    /// Sub-transactions can't be started during a parallel operation, in which case
+    /// [`Error::ParallelModeActive`] is returned (and `self` is dropped) instead of raising an error.
+    fn try_sub_transaction<F: FnOnce(SubTransaction<Self::T>) -> R, R>(
+        self,
+        f: F,
+    ) -> Result<R, Error>
+    where
+        Self: Sized,
+    {
+        check_can_begin()?;
+        Ok(self.sub_transaction(f))
+    }
+
+    /// Consume `self` and return a sub-transaction, if the current transaction meets `requirements`
+    ///
+    /// Otherwise, the unmet requirement is returned (and `self` is dropped) before the sub-transaction is begun.
+    fn sub_transaction_requiring<F: FnOnce(SubTransaction<Self::T>) -> R, R>(
+        self,
+        requirements: TxnRequirements,
+        f: F,
+    ) -> Result<R, RequirementError>
+    where
+        Self: Sized,
+    {
+        requirements.check()?;
+        Ok(self.sub_transaction(f))
+    }
+}
+
+/// Check whether a sub-transaction can be started
+pub(crate) fn check_can_begin() -> Result<(), Error> {
+    if unsafe { pg_sys::IsInParallelMode() } {
+        Err(Error::ParallelModeActive)
+    } else {
+        Ok(())
+    }
 }
 
 impl SubTransactionExt for SpiClient {
@@ -192,13 +1139,457 @@ impl SubTransactionExt for SpiClient {
     }
 }
 
+impl<'a> SubTransactionExt for &'a SpiClient {
+    type T = SpiClientWrapper;
+    fn sub_transaction<F: FnOnce(SubTransaction<Self::T>) -> R, R>(self, f: F) -> R
+    where
+        Self: Sized,
+    {
+        // As with `CheckedCommands`, we rely on the fact that `SpiClient` can be created at any time, as it has to be
+        // consumed by the sub-transaction
+        SpiClient.sub_transaction(f)
+    }
+}
+
+impl<'a> SubTransactionExt for &'a mut SpiClient {
+    type T = SpiClientWrapper;
+    fn sub_transaction<F: FnOnce(SubTransaction<Self::T>) -> R, R>(self, f: F) -> R
+    where
+        Self: Sized,
+    {
+        SpiClient.sub_transaction(f)
+    }
+}
+
 impl<Parent> SubTransactionExt for SubTransaction<Parent> {
     type T = SubTransaction<Parent>;
     fn sub_transaction<F: FnOnce(SubTransaction<Self::T>) -> R, R>(self, f: F) -> R
     where
         Self: Sized,
     {
-        let sub_xact = SubTransaction::new(self);
+        let budget = self.budget.clone();
+        let mut sub_xact = SubTransaction::new(self);
+        sub_xact.budget = budget;
         f(sub_xact)
     }
 }
+
+/// Wrap `f` so that it can be passed to [`SubTransaction::shielded`] even if what it captures isn't `UnwindSafe`
+///
+/// Like `AssertUnwindSafe`, this asserts that what `f` captures is consistent whenever an error interrupts it.
+pub fn assert_shield_safe<R, F>(
+    f: F,
+) -> impl FnOnce(&mut SubTransaction<SpiClientWrapper, false>) -> R + UnwindSafe
+where
+    F: FnOnce(&mut SubTransaction<SpiClientWrapper, false>) -> R,
+{
+    let f = AssertUnwindSafe(f);
+    move |xact| {
+        let f = f;
+        let AssertUnwindSafe(f) = f;
+        f(xact)
+    }
+}
+
+/// Run `f` with `xact`, returning the result of `f` along with `xact`, or any error raised in it (which rolls `xact`
+/// back)
+///
+/// Rust panics in `f` are returned as errors if `catch_panics`, and otherwise keep propagating with their original
+/// payload once `xact` is rolled back.
+pub(crate) fn shield<R, F>(
+    xact: SubTransaction<SpiClientWrapper, false>,
+    catch_panics: bool,
+    f: F,
+) -> Result<(R, SubTransaction<SpiClientWrapper, false>), Error>
+where
+    F: FnOnce(&mut SubTransaction<SpiClientWrapper, false>) -> R + UnwindSafe,
+{
+    capture_sqlstate(|| {
+        PgTryBuilder::new(move || {
+            // Dropped, and so rolled back, when an error is raised
+            let mut xact = xact;
+            Ok((f(&mut xact), xact))
+        })
+        .catch_others(|e| match e {
+            CaughtError::RustPanic { payload, .. } if !catch_panics => {
+                std::panic::resume_unwind(payload)
+            }
+            e => Err(e),
+        })
+        .execute()
+    })
+    .map_err(handled)
+}
+
+/// Heuristic that detected a write in [`assert_no_writes`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteHeuristic {
+    /// A transaction id was assigned to the sub-transaction
+    TransactionIdAssigned,
+    /// The command counter was advanced
+    CommandCounterAdvanced,
+}
+
+/// Writes detected by [`assert_no_writes`]
+#[derive(Debug)]
+pub struct WriteViolation<R> {
+    /// Value produced by the closure
+    pub value: R,
+    /// Heuristics that tripped
+    pub heuristics: Vec<WriteHeuristic>,
+    /// True if all the relations written to are temporary
+    pub temp_only: bool,
+}
+
+/// Configuration of [`assert_no_writes_with`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteCheck {
+    /// Don't treat writes to temporary tables as violations
+    pub allow_temp_tables: bool,
+}
+
+/// Run `f` and verify it made no writes, denying writes to temporary tables as well
+///
+/// See [`assert_no_writes_with`].
+pub fn assert_no_writes<R, F: FnOnce(&mut SpiClient) -> R>(
+    client: &SpiClient,
+    f: F,
+) -> Result<R, WriteViolation<R>> {
+    assert_no_writes_with(client, WriteCheck::default(), f)
+}
+
+/// Run `f` in a sub-transaction that is always rolled back, and verify it made no writes
+///
+/// Writes are detected by checking whether a transaction id got assigned to the sub-transaction and whether the
+/// command counter was advanced. Relation locks stronger than `ROW SHARE` newly taken by the sub-transaction tell
+/// whether only temporary tables were written to.
+pub fn assert_no_writes_with<R, F: FnOnce(&mut SpiClient) -> R>(
+    client: &SpiClient,
+    check: WriteCheck,
+    f: F,
+) -> Result<R, WriteViolation<R>> {
+    let locked_before = written_relations(client);
+    let (value, heuristics, written) = SpiClient.sub_transaction(|xact| {
+        let mut xact = xact.rollback_on_drop();
+        let command_id = unsafe { pg_sys::GetCurrentCommandId(false) };
+        let value = f(&mut xact);
+        let mut heuristics = vec![];
+        if unsafe { pg_sys::GetCurrentTransactionIdIfAny() } != pg_sys::InvalidTransactionId {
+            heuristics.push(WriteHeuristic::TransactionIdAssigned);
+        }
+        if unsafe { pg_sys::GetCurrentCommandId(false) } != command_id {
+            heuristics.push(WriteHeuristic::CommandCounterAdvanced);
+        }
+        let written: Vec<_> = written_relations(&xact)
+            .into_iter()
+            .filter(|rel| !locked_before.contains(rel))
+            .collect();
+        (value, heuristics, written)
+    });
+    if heuristics.is_empty() {
+        return Ok(value);
+    }
+    let temp_only = !written.is_empty() && written.iter().all(|(_, _, temp)| *temp);
+    if temp_only && check.allow_temp_tables {
+        Ok(value)
+    } else {
+        Err(WriteViolation {
+            value,
+            heuristics,
+            temp_only,
+        })
+    }
+}
+
+/// Relations locked by this backend in modes used for writing, along with the lock mode and whether they are
+/// temporary
+fn written_relations(client: &SpiClient) -> Vec<(pg_sys::Oid, String, bool)> {
+    client
+        .select(
+            "SELECT l.relation, l.mode, c.relpersistence = 't' FROM pg_locks l JOIN pg_class c ON c.oid = l.relation \
+             WHERE l.pid = pg_backend_pid() AND l.locktype = 'relation' \
+             AND l.mode NOT IN ('AccessShareLock', 'RowShareLock')",
+            None,
+            None,
+        )
+        .filter_map(|row| {
+            Some((
+                row.by_ordinal(1).ok()?.value::<pg_sys::Oid>()?,
+                row.by_ordinal(2).ok()?.value::<String>()?,
+                row.by_ordinal(3).ok()?.value::<bool>()?,
+            ))
+        })
+        .collect()
+}
+
+/// Failure of a phase of [`stage_and_verify`]
+#[derive(Debug)]
+pub enum PhaseError<E> {
+    /// The phase returned an error
+    Failed(E),
+    /// An error was caught while running the phase
+    Caught(Error),
+}
+
+/// Error returned by [`stage_and_verify`], telling which phase failed
+#[derive(Debug)]
+pub enum StageError<E> {
+    Stage(PhaseError<E>),
+    Verify(PhaseError<E>),
+}
+
+/// Stage changes in a sub-transaction and verify them before committing it
+///
+/// `stage` runs in a new sub-transaction, which is kept open for `verify` to inspect the staged (still uncommitted)
+/// changes through it. The sub-transaction is committed only if both phases succeed, otherwise it is rolled back,
+/// including when an error is caught or a panic occurs in either phase.
+pub fn stage_and_verify<S, E, Stage, Verify>(
+    client: &mut SpiClient,
+    stage: Stage,
+    verify: Verify,
+) -> Result<S, StageError<E>>
+where
+    Stage: FnOnce(&mut SubTransaction<SpiClientWrapper, false>) -> Result<S, E> + UnwindSafe,
+    Verify: FnOnce(&S, &SubTransaction<SpiClientWrapper, false>) -> Result<(), E> + UnwindSafe,
+{
+    let xact = client.sub_transaction(|xact| xact.rollback_on_drop());
+    let (staged, xact) = capture_sqlstate(|| {
+        PgTryBuilder::new(move || {
+            let mut xact = xact;
+            Ok((stage(&mut xact), xact))
+        })
+        .catch_others(|e| Err(e))
+        .execute()
+    })
+    .map_err(|e| StageError::Stage(PhaseError::Caught(handled(e))))?;
+    // Dropping the sub-transaction rolls it back
+    let staged = staged.map_err(|e| StageError::Stage(PhaseError::Failed(e)))?;
+
+    let staged = std::panic::AssertUnwindSafe(staged);
+    let (verified, staged, xact) = capture_sqlstate(|| {
+        PgTryBuilder::new(move || {
+            let staged = staged;
+            Ok((verify(&staged, &xact), staged, xact))
+        })
+        .catch_others(|e| Err(e))
+        .execute()
+    })
+    .map_err(|e| StageError::Verify(PhaseError::Caught(handled(e))))?;
+    verified.map_err(|e| StageError::Verify(PhaseError::Failed(e)))?;
+    xact.commit();
+    Ok(staged.0)
+}
+
+/// Transaction isolation level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// Characteristics of the current transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxnInfo {
+    pub isolation: IsolationLevel,
+    pub read_only: bool,
+    pub deferrable: bool,
+    /// True if the server is in recovery (a hot standby)
+    pub in_recovery: bool,
+}
+
+/// Characteristics of the current transaction
+///
+/// Doesn't require an SPI connection.
+pub fn transaction_info() -> TxnInfo {
+    let (iso_level, read_only, deferrable) = unsafe {
+        (
+            pg_sys::XactIsoLevel,
+            pg_sys::XactReadOnly,
+            pg_sys::XactDeferrable,
+        )
+    };
+    let isolation = match iso_level as u32 {
+        pg_sys::XACT_READ_UNCOMMITTED => IsolationLevel::ReadUncommitted,
+        pg_sys::XACT_READ_COMMITTED => IsolationLevel::ReadCommitted,
+        pg_sys::XACT_REPEATABLE_READ => IsolationLevel::RepeatableRead,
+        pg_sys::XACT_SERIALIZABLE => IsolationLevel::Serializable,
+        level => unreachable!("unknown isolation level {}", level),
+    };
+    TxnInfo {
+        isolation,
+        read_only,
+        deferrable,
+        in_recovery: unsafe { pg_sys::RecoveryInProgress() },
+    }
+}
+
+/// Current command id, for diagnostics
+///
+/// Doesn't require an SPI connection.
+pub fn current_command_id() -> u32 {
+    unsafe { pg_sys::GetCurrentCommandId(false) }
+}
+
+/// Depth of the SPI connection stack, for diagnostics
+///
+/// Postgres doesn't expose it, so connections are counted from their `SPI Proc` memory contexts, children of the
+/// transaction's context (or, for non-atomic connections, of the portal's).
+pub fn spi_depth() -> u32 {
+    unsafe {
+        let mut depth = count_spi_contexts(pg_sys::TopTransactionContext);
+        if pg_sys::PortalContext != pg_sys::TopTransactionContext {
+            depth += count_spi_contexts(pg_sys::PortalContext);
+        }
+        depth
+    }
+}
+
+unsafe fn count_spi_contexts(parent: pg_sys::MemoryContext) -> u32 {
+    if parent.is_null() {
+        return 0;
+    }
+    let mut count = 0;
+    let mut child = (*parent).firstchild;
+    while !child.is_null() {
+        if is_spi_context(child) {
+            count += 1;
+        }
+        child = (*child).nextchild;
+    }
+    count
+}
+
+/// Innermost SPI connections, as their `SPI Proc` memory contexts: the atomic one (a child of the transaction's
+/// context) and the non-atomic one (a child of the portal's)
+///
+/// Memory contexts are prepended to their parent's children, so the first `SPI Proc` child is the last connection
+/// made, and finding it only takes looking at the contexts created after it. Connecting to SPI, or finishing the
+/// connection, changes it, which is cheaper to check for than counting every connection with [`spi_depth`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SpiMarker {
+    atomic: pg_sys::MemoryContext,
+    non_atomic: pg_sys::MemoryContext,
+}
+
+impl SpiMarker {
+    pub(crate) fn current() -> Self {
+        unsafe {
+            Self {
+                atomic: first_spi_context(pg_sys::TopTransactionContext),
+                non_atomic: if pg_sys::PortalContext != pg_sys::TopTransactionContext {
+                    first_spi_context(pg_sys::PortalContext)
+                } else {
+                    std::ptr::null_mut()
+                },
+            }
+        }
+    }
+}
+
+unsafe fn first_spi_context(parent: pg_sys::MemoryContext) -> pg_sys::MemoryContext {
+    if parent.is_null() {
+        return std::ptr::null_mut();
+    }
+    let mut child = (*parent).firstchild;
+    while !child.is_null() && !is_spi_context(child) {
+        child = (*child).nextchild;
+    }
+    child
+}
+
+unsafe fn is_spi_context(context: pg_sys::MemoryContext) -> bool {
+    !(*context).name.is_null() && CStr::from_ptr((*context).name).to_bytes() == b"SPI Proc"
+}
+
+/// Requirements checked by [`SubTransactionExt::sub_transaction_requiring`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TxnRequirements {
+    /// Minimum isolation level
+    pub min_isolation: Option<IsolationLevel>,
+    /// Transaction must not be read-only
+    pub read_write: bool,
+    /// Server must not be in recovery
+    pub not_in_recovery: bool,
+}
+
+impl TxnRequirements {
+    /// Check the requirements against the current transaction
+    pub fn check(&self) -> Result<(), RequirementError> {
+        let info = transaction_info();
+        match self.min_isolation {
+            Some(required) if info.isolation < required => {
+                return Err(RequirementError::IsolationTooLow {
+                    required,
+                    actual: info.isolation,
+                })
+            }
+            _ => {}
+        }
+        if self.read_write && info.read_only {
+            return Err(RequirementError::ReadOnly);
+        }
+        if self.not_in_recovery && info.in_recovery {
+            return Err(RequirementError::InRecovery);
+        }
+        Ok(())
+    }
+}
+
+/// Unmet requirement reported by [`SubTransactionExt::sub_transaction_requiring`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequirementError {
+    IsolationTooLow {
+        required: IsolationLevel,
+        actual: IsolationLevel,
+    },
+    ReadOnly,
+    InRecovery,
+}
+
+/// Recursion limit reached by [`recursion_guard`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecursionLimit {
+    pub key: &'static str,
+    /// Depth reached before the call that was refused
+    pub depth: u32,
+}
+
+thread_local! {
+    static RECURSION_DEPTHS: RefCell<HashMap<&'static str, u32>> = RefCell::new(HashMap::new());
+}
+
+struct RecursionDepth(&'static str);
+
+impl Drop for RecursionDepth {
+    fn drop(&mut self) {
+        RECURSION_DEPTHS.with(|depths| {
+            if let Some(depth) = depths.borrow_mut().get_mut(self.0) {
+                *depth -= 1;
+            }
+        });
+    }
+}
+
+/// Run `f` unless it is already running `max_depth` times for `key` in this backend
+///
+/// Guards code that may be re-entered through the SQL it executes (such as triggers firing themselves), which would
+/// otherwise keep nesting sub-transactions. The depth is decreased when `f` returns, and also when it unwinds.
+///
+/// The stack depth is checked on entry as well, raising Postgres' regular `max_stack_depth` error (which checked
+/// commands can catch) before any sub-transaction is started as the stack is exhausted.
+pub fn recursion_guard<R, F: FnOnce() -> R>(
+    key: &'static str,
+    max_depth: u32,
+    f: F,
+) -> Result<R, RecursionLimit> {
+    unsafe { pg_sys::check_stack_depth() };
+    let depth = RECURSION_DEPTHS.with(|depths| *depths.borrow().get(key).unwrap_or(&0));
+    if depth >= max_depth {
+        return Err(RecursionLimit { key, depth });
+    }
+    RECURSION_DEPTHS.with(|depths| depths.borrow_mut().insert(key, depth + 1));
+    let _depth = RecursionDepth(key);
+    Ok(f())
+}
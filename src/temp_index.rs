@@ -0,0 +1,171 @@
+//! Temporary indexes accelerating a single operation
+//!
+//! Table names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier). Index names are generated by a
+//! [`NameGenerator`] with the prefix `spiext_tmp`.
+
+use pgx::{pg_sys, IntoDatum, PgBuiltInOids, SpiClient};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::names::{was_generated_by_us, NameGenerator};
+use crate::quote::*;
+use crate::rewrite;
+use crate::subtxn::SubTransaction;
+
+/// Temporary index creation error
+#[derive(Debug)]
+pub enum TempIndexError {
+    /// Indexes can't be created concurrently in a transaction block, which SPI commands always run in
+    Concurrently,
+    /// The command failed
+    Query(Error),
+}
+
+impl Display for TempIndexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TempIndexError::Concurrently => {
+                write!(f, "temporary indexes can't be created concurrently")
+            }
+            TempIndexError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for TempIndexError {
+    fn from(err: Error) -> Self {
+        TempIndexError::Query(err)
+    }
+}
+
+/// Index that exists until dropped, or until the sub-transaction that created it is rolled back
+///
+/// If the sub-transaction that created the index rolls back, so does the index, and dropping the guard does nothing.
+/// Otherwise, dropping it drops the index (in a sub-transaction of its own, with `DROP INDEX IF EXISTS`), warning
+/// about errors; [`TempIndex::drop_index`] returns them instead.
+#[derive(Debug)]
+pub struct TempIndex {
+    name: String,
+    schema: String,
+    /// Sub-transaction the index was created in
+    created_in: pg_sys::SubTransactionId,
+    dropped: bool,
+}
+
+impl TempIndex {
+    /// Create an index on `table` in `xact`, where `definition` is what follows the table in `CREATE INDEX`, such as
+    /// `(a, b)` or `USING gin (tags)`
+    ///
+    /// The index is created in the schema of the table. Returns [`TempIndexError::Concurrently`] without executing
+    /// anything if `definition` asks for the index to be created concurrently.
+    pub fn create<Parent, const COMMIT: bool>(
+        xact: &mut SubTransaction<Parent, COMMIT>,
+        table: &str,
+        definition: &str,
+    ) -> Result<Self, TempIndexError> {
+        if definition
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .any(|word| word.eq_ignore_ascii_case("concurrently"))
+        {
+            return Err(TempIndexError::Concurrently);
+        }
+        let table = quote_qualified_identifier(table);
+        let schema = rewrite::exempt(|| {
+            xact.select(
+                "SELECT relnamespace::regnamespace::text FROM pg_class WHERE oid = $1::regclass",
+                Some(1),
+                Some(vec![(
+                    PgBuiltInOids::TEXTOID.oid(),
+                    table.as_str().into_datum(),
+                )]),
+            )
+        })?
+        .map(|row| row.by_ordinal(1).unwrap().value::<String>())
+        .next()
+        .flatten()
+        .expect("table has no schema");
+        let name = NameGenerator::for_subtxn(xact)
+            .with_prefix("spiext_tmp")
+            .reserve("idx")
+            .into_string();
+        xact.update(
+            &format!(
+                "CREATE INDEX {} ON {} {}",
+                quote_identifier(&name),
+                table,
+                definition
+            ),
+            None,
+            None,
+        )?;
+        Ok(Self {
+            name,
+            schema,
+            created_in: xact.id(),
+            dropped: false,
+        })
+    }
+
+    /// Name of the index
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Schema-qualified and quoted name of the index
+    pub fn qualified_name(&self) -> String {
+        // `regnamespace` output is already quoted where needed
+        format!("{}.{}", self.schema, quote_identifier(&self.name))
+    }
+
+    /// Drop the index, returning an error if one occurred
+    pub fn drop_index(mut self) -> Result<(), Error> {
+        self.dropped = true;
+        self.drop_if_exists()
+    }
+
+    fn drop_if_exists(&self) -> Result<(), Error> {
+        // Never drop an index whose name isn't one we generated
+        if !was_generated_by_us(&self.name) {
+            return Ok(());
+        }
+        // Once the creating sub-transaction is over, the index only exists if it was committed
+        if !unsafe { pg_sys::SubTransactionIsActive(self.created_in) } && !self.exists()? {
+            return Ok(());
+        }
+        (&mut SpiClient).checked_update(
+            &format!("DROP INDEX IF EXISTS {}", self.qualified_name()),
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+
+    fn exists(&self) -> Result<bool, Error> {
+        Ok(rewrite::exempt(|| {
+            (&SpiClient).checked_select(
+                "SELECT to_regclass($1) IS NOT NULL",
+                Some(1),
+                Some(vec![(
+                    PgBuiltInOids::TEXTOID.oid(),
+                    self.qualified_name().into_datum(),
+                )]),
+            )
+        })?
+        .first()
+        .get_one::<bool>()
+        .unwrap_or_default())
+    }
+}
+
+impl Drop for TempIndex {
+    fn drop(&mut self) {
+        if self.dropped || std::thread::panicking() {
+            return;
+        }
+        if let Err(err) = self.drop_if_exists() {
+            pgx::warning!("failed to drop temporary index \"{}\": {}", self.name, err);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Rate limiting of checked commands
+//!
+//! A [`Throttle`] is a token bucket: every command takes a token, and tokens are refilled at a fixed rate up to the
+//! bucket's capacity (the burst). Commands that find the bucket empty wait for a token before they start, outside of
+//! the sub-transaction they are executed in, so the wait holds no locks of its own and can be canceled.
+
+use pgx::{pg_sys, PgOid, SpiClient, SpiTupleTable};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::scan::is_empty_query;
+
+/// Longest single sleep while waiting for a token, between which interrupts are checked
+const SLEEP_SLICE: Duration = Duration::from_millis(10);
+
+/// Token bucket limiting the rate of checked commands
+///
+/// Shared by reference between calls. It is backend-local: it's not thread-safe and can't be sent to other threads.
+#[derive(Debug)]
+pub struct Throttle {
+    ops_per_second: f64,
+    burst: u32,
+    /// Available tokens, negative while commands are waiting for them
+    tokens: Cell<f64>,
+    refilled_at: Cell<Instant>,
+    stats: Cell<ThrottleStats>,
+}
+
+/// Operations that went through a [`Throttle`], and how long they waited for it in total
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThrottleStats {
+    pub ops: u64,
+    pub total_wait: Duration,
+}
+
+impl Throttle {
+    /// Allow `ops_per_second` operations per second on average, and up to `burst` operations at once
+    ///
+    /// The bucket starts full. Panics if `ops_per_second` is not a positive number or `burst` is zero.
+    pub fn new(ops_per_second: f64, burst: u32) -> Self {
+        assert!(
+            ops_per_second.is_finite() && ops_per_second > 0.0,
+            "operations per second must be positive"
+        );
+        assert!(burst > 0, "burst must be at least 1");
+        Self {
+            ops_per_second,
+            burst,
+            tokens: Cell::new(burst as f64),
+            refilled_at: Cell::new(Instant::now()),
+            stats: Cell::new(ThrottleStats::default()),
+        }
+    }
+
+    pub fn ops_per_second(&self) -> f64 {
+        self.ops_per_second
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        self.stats.get()
+    }
+
+    /// Take a token, waiting until one is available
+    ///
+    /// If the wait is interrupted by an error, no token is taken: a query cancel or a shutdown request is raised again,
+    /// and other errors are returned.
+    pub(crate) fn acquire(&self) -> Result<(), Error> {
+        self.refill();
+        let tokens = self.tokens.get() - 1.0;
+        self.tokens.set(tokens);
+        let wait = if tokens < 0.0 {
+            Duration::from_secs_f64(-tokens / self.ops_per_second)
+        } else {
+            Duration::ZERO
+        };
+        let start = Instant::now();
+        let result = sleep_in_sub_transaction(wait);
+        let mut stats = self.stats.get();
+        stats.total_wait += start.elapsed();
+        match result {
+            Ok(()) => stats.ops += 1,
+            Err(_) => self.tokens.set(self.tokens.get() + 1.0),
+        }
+        self.stats.set(stats);
+        raise_if_canceled(result)
+    }
+
+    fn refill(&self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at.get());
+        self.refilled_at.set(now);
+        let tokens = self.tokens.get() + elapsed.as_secs_f64() * self.ops_per_second;
+        self.tokens.set(tokens.min(self.burst as f64));
+    }
+}
+
+/// Sleep for `duration` in short slices, checking for interrupts between them
+///
+/// The wait runs in a sub-transaction of its own, so that an error raised by an interrupt is caught once it was rolled
+/// back. A query cancel or a shutdown request (`57014`, `57P01`) is then raised again rather than returned, so that
+/// the statement is canceled even if the caller goes on; other errors are returned.
+pub(crate) fn sleep(duration: Duration) -> Result<(), Error> {
+    raise_if_canceled(sleep_in_sub_transaction(duration))
+}
+
+fn sleep_in_sub_transaction(duration: Duration) -> Result<(), Error> {
+    checked_sub_transaction(|_| {
+        let deadline = Instant::now() + duration;
+        loop {
+            pg_sys::check_for_interrupts!();
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let slice = remaining.min(SLEEP_SLICE);
+            unsafe { pg_sys::pg_usleep(slice.as_micros() as _) };
+        }
+    })
+}
+
+/// Raise a caught query cancel or shutdown request again, returning any other result
+fn raise_if_canceled(result: Result<(), Error>) -> Result<(), Error> {
+    let canceled = result
+        .as_ref()
+        .err()
+        .and_then(Error::sqlstate)
+        .map_or(false, |sqlstate| {
+            matches!(sqlstate.as_str(), "57014" | "57P01")
+        });
+    match result {
+        Err(Error::Caught(err, _)) if canceled => err.rethrow(),
+        result => result,
+    }
+}
+
+/// Checked commands rate-limited by a [`Throttle`]
+pub trait ThrottleCommands {
+    /// Wait for `throttle` to allow another operation, then execute a mutable command, returning an error if one
+    /// occurred.
+    ///
+    /// If the wait is canceled, the cancellation is raised again without executing the command; other errors
+    /// interrupting the wait are returned.
+    fn checked_update_throttled(
+        &mut self,
+        throttle: &Throttle,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error>;
+}
+
+impl ThrottleCommands for SpiClient {
+    fn checked_update_throttled(
+        &mut self,
+        throttle: &Throttle,
+        query: &str,
+        args: Option<Vec<(PgOid, Option<pg_sys::Datum>)>>,
+    ) -> Result<SpiTupleTable, Error> {
+        if is_empty_query(query) {
+            return Err(Error::EmptyQuery);
+        }
+        throttle.acquire()?;
+        self.checked_update(query, None, args)
+    }
+}
@@ -0,0 +1,170 @@
+//! Typed access to the current time
+//!
+//! `now()` is stable within a transaction, while `clock_timestamp()` changes during a statement. With the `testing`
+//! feature, [`SubTransaction::freeze_time`](crate::subtxn::SubTransaction::freeze_time) makes both return a fixed
+//! value in SQL executed through a sub-transaction.
+
+use pgx::{SpiClient, TimestampWithTimeZone};
+
+use crate::checked::*;
+use crate::error::Error;
+
+/// Start time of the current transaction, as returned by `now()`
+pub fn txn_now(client: &SpiClient) -> Result<TimestampWithTimeZone, Error> {
+    timestamp(client, "SELECT now()")
+}
+
+/// Actual current time, as returned by `clock_timestamp()`
+pub fn clock_now(client: &SpiClient) -> Result<TimestampWithTimeZone, Error> {
+    timestamp(client, "SELECT clock_timestamp()")
+}
+
+fn timestamp(client: &SpiClient, query: &str) -> Result<TimestampWithTimeZone, Error> {
+    Ok(client
+        .checked_select(query, Some(1), None)?
+        .first()
+        .get_one::<TimestampWithTimeZone>()
+        .expect("current time is NULL"))
+}
+
+#[cfg(feature = "testing")]
+pub use frozen::FrozenTime;
+
+#[cfg(feature = "testing")]
+mod frozen {
+    use pgx::{pg_sys, IntoDatum, PgBuiltInOids, SpiClient, TimestampWithTimeZone};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::checked::*;
+    use crate::error::Error;
+    use crate::quote::*;
+    use crate::rewrite;
+    use crate::subtxn::SubTransaction;
+
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    /// Functions returning the current time that are overridden
+    const FUNCTIONS: [&str; 4] = [
+        "now",
+        "transaction_timestamp",
+        "statement_timestamp",
+        "clock_timestamp",
+    ];
+
+    /// Makes SQL see a fixed current time until dropped
+    ///
+    /// Created by [`SubTransaction::freeze_time`]. A schema of its own holds functions overriding `now()`,
+    /// `transaction_timestamp()`, `statement_timestamp()` and `clock_timestamp()`, and is put ahead of `pg_catalog`
+    /// in `search_path` with `SET LOCAL`, so the sub-transaction and those nested in it call them instead. Dropping
+    /// the guard drops the schema and restores `search_path`; rolling back the sub-transaction undoes both as well.
+    ///
+    /// Only calls resolved through `search_path` after the freeze are affected. The `CURRENT_TIMESTAMP` and
+    /// `LOCALTIMESTAMP` keywords, column defaults (which are bound to the functions when the table is created),
+    /// schema-qualified calls and already prepared plans keep seeing the actual time.
+    #[derive(Debug)]
+    pub struct FrozenTime {
+        schema: String,
+        search_path: String,
+    }
+
+    impl FrozenTime {
+        fn create(at: TimestampWithTimeZone) -> Result<Self, Error> {
+            let schema = format!(
+                "spiext_frozen_time_{}_{}",
+                unsafe { pg_sys::MyProcPid },
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            );
+            let search_path = rewrite::exempt(|| {
+                (&SpiClient).checked_select("SELECT current_setting('search_path')", Some(1), None)
+            })?
+            .first()
+            .get_one::<String>()
+            .unwrap_or_default();
+            // ISO 8601 in UTC is read back the same regardless of `DateStyle` and `TimeZone`
+            let at = rewrite::exempt(|| {
+                (&SpiClient).checked_select(
+                    "SELECT to_char($1 AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"')",
+                    Some(1),
+                    Some(vec![(PgBuiltInOids::TIMESTAMPTZOID.oid(), at.into_datum())]),
+                )
+            })?
+            .first()
+            .get_one::<String>()
+            .expect("frozen time is NULL");
+            let quoted_schema = quote_identifier(&schema);
+            let mut statements = vec![format!("CREATE SCHEMA {}", quoted_schema)];
+            statements.extend(FUNCTIONS.iter().map(|function| {
+                format!(
+                    "CREATE FUNCTION {}.{}() RETURNS timestamptz LANGUAGE sql STABLE AS {}",
+                    quoted_schema,
+                    quote_identifier(function),
+                    dollar_quote(&format!("SELECT {}::timestamptz", quote_literal(&at)))
+                )
+            }));
+            let mut new_search_path = format!("{}, pg_catalog", quoted_schema);
+            if !search_path.trim().is_empty() {
+                new_search_path = format!("{}, {}", new_search_path, search_path);
+            }
+            checked_sub_transaction(move |client| {
+                for statement in &statements {
+                    client.update(statement, None, None);
+                }
+                client.update(
+                    "SELECT set_config('search_path', $1, true)",
+                    None,
+                    Some(vec![(
+                        PgBuiltInOids::TEXTOID.oid(),
+                        new_search_path.into_datum(),
+                    )]),
+                );
+            })?;
+            Ok(Self {
+                schema,
+                search_path,
+            })
+        }
+
+        /// Name of the schema holding the overriding functions
+        pub fn schema(&self) -> &str {
+            &self.schema
+        }
+    }
+
+    impl Drop for FrozenTime {
+        fn drop(&mut self) {
+            if std::thread::panicking() {
+                return;
+            }
+            let schema = quote_identifier(&self.schema);
+            let search_path = self.search_path.clone();
+            let result = checked_sub_transaction(move |client| {
+                client.update(
+                    "SELECT set_config('search_path', $1, true)",
+                    None,
+                    Some(vec![(
+                        PgBuiltInOids::TEXTOID.oid(),
+                        search_path.into_datum(),
+                    )]),
+                );
+                client.update(
+                    &format!("DROP SCHEMA IF EXISTS {} CASCADE", schema),
+                    None,
+                    None,
+                );
+            });
+            if let Err(err) = result {
+                pgx::warning!("failed to unfreeze time in \"{}\": {}", self.schema, err);
+            }
+        }
+    }
+
+    impl<Parent, const COMMIT: bool> SubTransaction<Parent, COMMIT> {
+        /// Make SQL executed through this sub-transaction (and those nested in it) see `at` as the current time,
+        /// until the returned guard is dropped
+        ///
+        /// See [`FrozenTime`].
+        pub fn freeze_time(&self, at: TimestampWithTimeZone) -> Result<FrozenTime, Error> {
+            FrozenTime::create(at)
+        }
+    }
+}
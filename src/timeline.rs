@@ -0,0 +1,323 @@
+//! Timeline of what this crate did in the backend, for post-mortems of errors
+//!
+//! Once [`enable`]d, sub-transactions begun, committed and rolled back by this crate, checked commands (with their
+//! duration and outcome), the errors they caught, the attempts [`configured`](crate::configured) clients retry and the
+//! savepoint cycles of sub-transactions begun by other means (such as PL/pgSQL exception blocks) are recorded as
+//! [`Event`]s, with increasing sequence numbers and the time they happened at, into a ring buffer that keeps the most
+//! recent ones. [`snapshot`] copies them, and [`attach_to`] appends the last of them to an [`OwnedPostgresError`]'s
+//! detail for the server log, so that they are logged along with the error when it is rethrown.
+//!
+//! The buffer is allocated by [`enable`]: recording an event copies it into the buffer without allocating, with the
+//! text of commands truncated to [`STATEMENT_TEXT_LEN`] bytes. While the timeline is disabled (the default), recording
+//! is a thread-local access.
+
+use pgx::{pg_guard, pg_sys};
+use std::cell::RefCell;
+use std::fmt::{Debug, Display, Formatter};
+use std::os::raw::c_void;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+use crate::error::{OwnedPostgresError, SqlState};
+use crate::subtxn;
+
+/// Bytes of a command's text that are recorded
+pub const STATEMENT_TEXT_LEN: usize = 64;
+
+/// Events [`attach_to`] appends to an error
+pub const ATTACHED_EVENTS: usize = 32;
+
+/// Text of a command, truncated to [`STATEMENT_TEXT_LEN`] bytes (on a character boundary)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StatementText {
+    bytes: [u8; STATEMENT_TEXT_LEN],
+    len: u8,
+    truncated: bool,
+}
+
+impl StatementText {
+    fn new(query: &str) -> Self {
+        let mut len = query.len().min(STATEMENT_TEXT_LEN);
+        while !query.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; STATEMENT_TEXT_LEN];
+        bytes[..len].copy_from_slice(&query.as_bytes()[..len]);
+        Self {
+            bytes,
+            len: len as u8,
+            truncated: len < query.len(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+
+    /// Whether the text was longer than what was recorded
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl Debug for StatementText {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl Display for StatementText {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())?;
+        if self.truncated {
+            f.write_str("...")?;
+        }
+        Ok(())
+    }
+}
+
+/// What happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A sub-transaction was begun at transaction nesting level `depth` (or taken over with
+    /// [`SubTransaction::from_raw`](crate::subtxn::SubTransaction::from_raw))
+    SubTransactionBegin {
+        id: pg_sys::SubTransactionId,
+        depth: i32,
+    },
+    SubTransactionCommit {
+        id: pg_sys::SubTransactionId,
+        depth: i32,
+    },
+    SubTransactionRollback {
+        id: pg_sys::SubTransactionId,
+        depth: i32,
+    },
+    /// A checked command started executing
+    StatementStart { query: StatementText },
+    /// The last checked command to start finished, successfully or not
+    StatementFinish { duration: Duration, succeeded: bool },
+    /// A checked command caught an error Postgres raised
+    ErrorCaught { sqlstate: SqlState },
+    /// A failed command is attempted again, for the `attempt`th time
+    Retry { attempt: u32 },
+    /// A sub-transaction begun by other means than this crate, such as a PL/pgSQL block with an `EXCEPTION` clause or
+    /// `SAVEPOINT`, ended at transaction nesting level `depth`, released or rolled back
+    SavepointCycle {
+        id: pg_sys::SubTransactionId,
+        depth: i32,
+        released: bool,
+    },
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventKind::SubTransactionBegin { id, depth } => {
+                write!(f, "begin sub-transaction {} (depth {})", id, depth)
+            }
+            EventKind::SubTransactionCommit { id, depth } => {
+                write!(f, "commit sub-transaction {} (depth {})", id, depth)
+            }
+            EventKind::SubTransactionRollback { id, depth } => {
+                write!(f, "roll back sub-transaction {} (depth {})", id, depth)
+            }
+            EventKind::StatementStart { query } => write!(f, "execute: {}", query),
+            EventKind::StatementFinish {
+                duration,
+                succeeded,
+            } => write!(
+                f,
+                "{} after {:.3} ms",
+                if *succeeded { "succeeded" } else { "failed" },
+                duration.as_secs_f64() * 1000.0
+            ),
+            EventKind::ErrorCaught { sqlstate } => write!(f, "caught error {}", sqlstate.as_str()),
+            EventKind::Retry { attempt } => write!(f, "retry (attempt {})", attempt),
+            EventKind::SavepointCycle {
+                id,
+                depth,
+                released,
+            } => write!(
+                f,
+                "{} savepoint sub-transaction {} (depth {})",
+                if *released { "release" } else { "roll back" },
+                id,
+                depth
+            ),
+        }
+    }
+}
+
+/// Event of the timeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// Sequence number, starting from 1 when the timeline is enabled
+    pub seq: u64,
+    pub at: Instant,
+    pub kind: EventKind,
+}
+
+struct Ring {
+    events: Vec<Event>,
+    capacity: usize,
+    /// Events recorded since the timeline was enabled, including those overwritten since
+    recorded: u64,
+    enabled_at: Instant,
+}
+
+impl Ring {
+    fn push(&mut self, kind: EventKind) {
+        self.recorded += 1;
+        let event = Event {
+            seq: self.recorded,
+            at: Instant::now(),
+            kind,
+        };
+        if self.events.len() < self.capacity {
+            self.events.push(event);
+        } else {
+            // Overwrite the oldest event
+            let oldest = ((self.recorded - 1) % self.capacity as u64) as usize;
+            self.events[oldest] = event;
+        }
+    }
+
+    /// Events, oldest first
+    fn iter(&self) -> impl Iterator<Item = &Event> {
+        let oldest = if self.events.len() < self.capacity {
+            0
+        } else {
+            (self.recorded % self.capacity as u64) as usize
+        };
+        self.events[oldest..]
+            .iter()
+            .chain(self.events[..oldest].iter())
+    }
+}
+
+thread_local! {
+    static TIMELINE: RefCell<Option<Ring>> = const { RefCell::new(None) };
+}
+
+static REGISTER_CALLBACK: Once = Once::new();
+
+/// Records the end of sub-transactions begun by other means than this crate
+#[pg_guard]
+unsafe extern "C" fn subxact_callback(
+    event: pg_sys::SubXactEvent,
+    subid: pg_sys::SubTransactionId,
+    _parent: pg_sys::SubTransactionId,
+    _arg: *mut c_void,
+) {
+    let released = match event {
+        pg_sys::SubXactEvent_SUBXACT_EVENT_COMMIT_SUB => true,
+        pg_sys::SubXactEvent_SUBXACT_EVENT_ABORT_SUB => false,
+        _ => return,
+    };
+    if !subtxn::is_ending_own(subid) {
+        record(|| EventKind::SavepointCycle {
+            id: subid,
+            depth: pg_sys::GetCurrentTransactionNestLevel(),
+            released,
+        });
+    }
+}
+
+/// Record the last `capacity` events of this backend from now on, forgetting those recorded so far
+///
+/// Panics if `capacity` is 0.
+pub fn enable(capacity: usize) {
+    assert!(capacity > 0, "timeline capacity must be positive");
+    let ring = Ring {
+        events: Vec::with_capacity(capacity),
+        capacity,
+        recorded: 0,
+        enabled_at: Instant::now(),
+    };
+    TIMELINE.with(|timeline| *timeline.borrow_mut() = Some(ring));
+    REGISTER_CALLBACK.call_once(|| unsafe {
+        pg_sys::RegisterSubXactCallback(Some(subxact_callback), std::ptr::null_mut());
+    });
+}
+
+/// Stop recording events, forgetting those recorded so far
+pub fn disable() {
+    TIMELINE.with(|timeline| timeline.borrow_mut().take());
+}
+
+pub fn is_enabled() -> bool {
+    TIMELINE.with(|timeline| timeline.borrow().is_some())
+}
+
+/// Recorded events, oldest first (empty if the timeline is disabled)
+pub fn snapshot() -> Vec<Event> {
+    TIMELINE.with(|timeline| {
+        timeline
+            .borrow()
+            .as_ref()
+            .map_or(vec![], |ring| ring.iter().copied().collect())
+    })
+}
+
+/// The last `count` recorded events, one per line, with their sequence number and the time since the timeline was
+/// enabled
+///
+/// Empty if the timeline is disabled.
+pub fn render(count: usize) -> String {
+    TIMELINE.with(|timeline| {
+        let timeline = timeline.borrow();
+        let ring = match timeline.as_ref() {
+            Some(ring) => ring,
+            None => return String::new(),
+        };
+        let skip = ring.events.len().saturating_sub(count);
+        ring.iter()
+            .skip(skip)
+            .map(|event| {
+                format!(
+                    "#{} +{:.3}ms {}",
+                    event.seq,
+                    event.at.duration_since(ring.enabled_at).as_secs_f64() * 1000.0,
+                    event.kind
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+}
+
+/// Append the last [`ATTACHED_EVENTS`] events to the detail of `err` for the server log
+///
+/// Does nothing if the timeline is disabled or no event was recorded.
+pub fn attach_to(err: &mut OwnedPostgresError) {
+    let rendered = render(ATTACHED_EVENTS);
+    if rendered.is_empty() {
+        return;
+    }
+    let rendered = format!("timeline:\n{}", rendered);
+    err.detail_log = Some(match err.detail_log.take() {
+        Some(detail_log) => format!("{}\n{}", detail_log, rendered),
+        None => rendered,
+    });
+}
+
+/// Record an event, made by `kind` only if the timeline is enabled
+pub(crate) fn record<F: FnOnce() -> EventKind>(kind: F) {
+    TIMELINE.with(|timeline| {
+        // Postgres may call back into this module (ending a sub-transaction) while the timeline is borrowed, in which
+        // case the event is dropped rather than panicking in the callback
+        if let Ok(mut timeline) = timeline.try_borrow_mut() {
+            if let Some(ring) = timeline.as_mut() {
+                ring.push(kind());
+            }
+        }
+    });
+}
+
+/// Record the start of a checked command
+pub(crate) fn record_start(query: &str) {
+    record(|| EventKind::StatementStart {
+        query: StatementText::new(query),
+    });
+}
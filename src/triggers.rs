@@ -0,0 +1,174 @@
+//! Suspending a table's triggers for the duration of an operation
+//!
+//! Table names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier). Disabling triggers requires owning the
+//! table.
+
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{pg_sys, IntoDatum, PgBuiltInOids, SpiClient};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::{report, Error, PostgresErrorExt};
+use crate::quote::*;
+use crate::rewrite;
+use crate::subtxn::SubTransaction;
+
+/// Trigger suspension error
+#[derive(Debug)]
+pub enum TriggerError {
+    /// The current role doesn't own the table (SQLSTATE `42501`)
+    NotOwner(CaughtError),
+    /// The command failed otherwise
+    Query(Error),
+}
+
+impl Display for TriggerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TriggerError::NotOwner(err) => write!(f, "{}", report(err).message()),
+            TriggerError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for TriggerError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Caught(err, _) if report(&err).sqlstate().as_str() == "42501" => {
+                TriggerError::NotOwner(err)
+            }
+            err => TriggerError::Query(err),
+        }
+    }
+}
+
+/// User triggers of a table, disabled until dropped or until the sub-transaction that disabled them is rolled back
+///
+/// The state of every trigger (`pg_trigger.tgenabled`) is recorded before they are disabled, and restored exactly
+/// rather than just enabled, so triggers that fire always, only on replicas or never keep doing so. If the
+/// sub-transaction that disabled them rolls back, Postgres restores them itself, and dropping the guard does nothing.
+/// Otherwise, dropping it restores them (in a sub-transaction of its own), warning about errors;
+/// [`TriggerSuspension::restore`] returns them instead.
+#[derive(Debug)]
+pub struct TriggerSuspension {
+    /// Quoted name of the table
+    name: String,
+    table: pg_sys::Oid,
+    /// Name and recorded state of every user trigger
+    triggers: Vec<(String, String)>,
+    /// Sub-transaction the triggers were disabled in
+    disabled_in: pg_sys::SubTransactionId,
+    restored: bool,
+}
+
+impl TriggerSuspension {
+    /// Disable all user triggers of `table` in `xact` (`ALTER TABLE ... DISABLE TRIGGER USER`)
+    ///
+    /// Internally generated triggers, such as those enforcing foreign keys, are left as they are.
+    pub fn disable_user_triggers<Parent, const COMMIT: bool>(
+        xact: &mut SubTransaction<Parent, COMMIT>,
+        table: &str,
+    ) -> Result<Self, TriggerError> {
+        let table = quote_qualified_identifier(table);
+        let oid = rewrite::exempt(|| {
+            xact.select(
+                "SELECT $1::regclass::oid",
+                Some(1),
+                Some(vec![(
+                    PgBuiltInOids::TEXTOID.oid(),
+                    table.as_str().into_datum(),
+                )]),
+            )
+        })?
+        .first()
+        .get_one::<pg_sys::Oid>()
+        .expect("table has no OID");
+        let triggers = trigger_states(oid)?;
+        xact.update(
+            &format!("ALTER TABLE {} DISABLE TRIGGER USER", table),
+            None,
+            None,
+        )?;
+        Ok(Self {
+            name: table,
+            table: oid,
+            triggers,
+            disabled_in: xact.id(),
+            restored: false,
+        })
+    }
+
+    /// Names of the suspended triggers
+    pub fn triggers(&self) -> impl Iterator<Item = &str> {
+        self.triggers.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Restore the triggers, returning an error if one occurred
+    pub fn restore(mut self) -> Result<(), Error> {
+        self.restored = true;
+        self.restore_changed()
+    }
+
+    fn restore_changed(&self) -> Result<(), Error> {
+        let mut changed = self.triggers.clone();
+        // Once the disabling sub-transaction is over, the triggers are only disabled if it was committed
+        if !unsafe { pg_sys::SubTransactionIsActive(self.disabled_in) } {
+            let current = trigger_states(self.table)?;
+            changed.retain(|trigger| {
+                current.iter().any(|(name, _)| *name == trigger.0) && !current.contains(trigger)
+            });
+        }
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let actions: Vec<String> = changed
+            .iter()
+            .map(|(name, state)| {
+                let action = match state.as_str() {
+                    "D" => "DISABLE TRIGGER",
+                    "R" => "ENABLE REPLICA TRIGGER",
+                    "A" => "ENABLE ALWAYS TRIGGER",
+                    _ => "ENABLE TRIGGER",
+                };
+                format!("{} {}", action, quote_identifier(name))
+            })
+            .collect();
+        (&mut SpiClient).checked_update(
+            &format!("ALTER TABLE {} {}", self.name, actions.join(", ")),
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for TriggerSuspension {
+    fn drop(&mut self) {
+        if self.restored || std::thread::panicking() {
+            return;
+        }
+        if let Err(err) = self.restore_changed() {
+            pgx::warning!("failed to restore triggers of {}: {}", self.name, err);
+        }
+    }
+}
+
+/// Name and state (`tgenabled`) of every user trigger of a table
+fn trigger_states(table: pg_sys::Oid) -> Result<Vec<(String, String)>, Error> {
+    Ok(rewrite::exempt(|| {
+        (&SpiClient).checked_select(
+            "SELECT tgname::text, tgenabled::text FROM pg_trigger WHERE tgrelid = $1 AND NOT tgisinternal \
+             ORDER BY tgname",
+            None,
+            Some(vec![(PgBuiltInOids::OIDOID.oid(), table.into_datum())]),
+        )
+    })?
+    .map(|row| {
+        (
+            row.by_ordinal(1).unwrap().value().unwrap_or_default(),
+            row.by_ordinal(2).unwrap().value().unwrap_or_default(),
+        )
+    })
+    .collect())
+}
@@ -0,0 +1,173 @@
+//! Checked promotion of unlogged tables to logged ones, and back
+//!
+//! `ALTER TABLE ... SET LOGGED` (or `SET UNLOGGED`) rewrites the whole table and its indexes while holding an
+//! `ACCESS EXCLUSIVE` lock, which blocks every other session using the table until the enclosing transaction ends. The
+//! table is only crash-safe once that transaction commits. Table names are possibly schema-qualified (`schema.name`)
+//! and quoted with [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier).
+
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{IntoDatum, PgBuiltInOids, SpiClient};
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use crate::checked::*;
+use crate::error::{report, Error, PostgresErrorExt};
+use crate::guc::with_local;
+use crate::limits::timeout_setting;
+use crate::quote::*;
+use crate::rewrite;
+
+/// Options of [`promote`] and [`demote`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PromoteOpts {
+    /// How long to wait for the table's lock before giving up with [`PromoteError::LockTimeout`] (rounded up to a
+    /// millisecond), instead of the `lock_timeout` setting
+    pub lock_timeout: Option<Duration>,
+}
+
+/// Report of [`promote`] and [`demote`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromoteReport {
+    /// Time taken, including waiting for the lock
+    pub duration: Duration,
+    /// Size of the rewritten table, along with its indexes and TOAST table, in bytes
+    pub bytes_rewritten: i64,
+}
+
+/// Error returned by [`promote`] and [`demote`]
+#[derive(Debug)]
+pub enum PromoteError {
+    /// The table's lock couldn't be acquired within the lock timeout
+    LockTimeout(CaughtError),
+    /// [`promote`] was called on a table that is already logged
+    NotUnlogged(String),
+    /// [`demote`] was called on a table that is already unlogged
+    NotLogged(String),
+    /// The table is temporary, which is neither logged nor unlogged
+    Temporary(String),
+    /// [`demote`] was called without acknowledging that the table's contents are lost on a crash
+    DataLossNotAcknowledged,
+    /// The command failed for another reason
+    Query(Error),
+}
+
+impl Display for PromoteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PromoteError::LockTimeout(err) => write!(f, "{}", report(err).message()),
+            PromoteError::NotUnlogged(table) => write!(f, "table {} is not unlogged", table),
+            PromoteError::NotLogged(table) => write!(f, "table {} is not logged", table),
+            PromoteError::Temporary(table) => write!(f, "table {} is temporary", table),
+            PromoteError::DataLossNotAcknowledged => write!(
+                f,
+                "making a table unlogged loses its contents on a crash, which wasn't acknowledged"
+            ),
+            PromoteError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for PromoteError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Caught(err, _) if report(&err).sqlstate().as_str() == "55P03" => {
+                PromoteError::LockTimeout(err)
+            }
+            err => PromoteError::Query(err),
+        }
+    }
+}
+
+/// Make an unlogged table logged, so that its contents survive a crash once the transaction commits
+///
+/// The table is rewritten in a sub-transaction of its own, with `lock_timeout` set for the duration of the command
+/// if `opts` has one. Returns [`PromoteError::NotUnlogged`] without changing anything if it is already logged.
+pub fn promote(
+    client: &mut SpiClient,
+    table: &str,
+    opts: PromoteOpts,
+) -> Result<PromoteReport, PromoteError> {
+    set_persistence(client, table, true, opts)
+}
+
+/// Make a logged table unlogged, which is truncated on a crash from then on
+///
+/// `i_understand_data_loss_on_crash` must be `true`, or [`PromoteError::DataLossNotAcknowledged`] is returned. The
+/// table is rewritten as by [`promote`], and [`PromoteError::NotLogged`] is returned if it is already unlogged.
+pub fn demote(
+    client: &mut SpiClient,
+    table: &str,
+    opts: PromoteOpts,
+    i_understand_data_loss_on_crash: bool,
+) -> Result<PromoteReport, PromoteError> {
+    if !i_understand_data_loss_on_crash {
+        return Err(PromoteError::DataLossNotAcknowledged);
+    }
+    set_persistence(client, table, false, opts)
+}
+
+/// Whether `table` is unlogged
+pub fn is_unlogged(table: &str) -> Result<bool, Error> {
+    Ok(persistence(&SpiClient, table)? == "u")
+}
+
+/// `relpersistence` of `table`: `p` (logged), `u` (unlogged) or `t` (temporary)
+fn persistence(client: &SpiClient, table: &str) -> Result<String, Error> {
+    Ok(rewrite::exempt(|| {
+        client.checked_select(
+            "SELECT relpersistence::text FROM pg_class WHERE oid = $1::regclass",
+            Some(1),
+            Some(vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                quote_qualified_identifier(table).into_datum(),
+            )]),
+        )
+    })?
+    .first()
+    .get_one::<String>()
+    .unwrap_or_default())
+}
+
+fn set_persistence(
+    client: &SpiClient,
+    table: &str,
+    logged: bool,
+    opts: PromoteOpts,
+) -> Result<PromoteReport, PromoteError> {
+    match (persistence(client, table)?.as_str(), logged) {
+        ("t", _) => return Err(PromoteError::Temporary(table.to_string())),
+        ("p", true) => return Err(PromoteError::NotUnlogged(table.to_string())),
+        ("u", false) => return Err(PromoteError::NotLogged(table.to_string())),
+        _ => {}
+    }
+    let table = quote_qualified_identifier(table);
+    let query = format!(
+        "ALTER TABLE {} SET {}",
+        table,
+        if logged { "LOGGED" } else { "UNLOGGED" }
+    );
+    let lock_timeout = opts.lock_timeout.map(timeout_setting);
+    let started = Instant::now();
+    let bytes_rewritten = checked_sub_transaction(move |client| {
+        let rewrite = |client: &mut SpiClient| {
+            client.update(&query, None, None);
+        };
+        match lock_timeout {
+            Some(timeout) => with_local(client, "lock_timeout", &timeout, rewrite),
+            None => rewrite(client),
+        }
+        client
+            .select(
+                "SELECT pg_total_relation_size($1::regclass)",
+                None,
+                Some(vec![(PgBuiltInOids::TEXTOID.oid(), table.into_datum())]),
+            )
+            .first()
+            .get_one::<i64>()
+            .unwrap_or_default()
+    })?;
+    Ok(PromoteReport {
+        duration: started.elapsed(),
+        bytes_rewritten,
+    })
+}
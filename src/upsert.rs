@@ -0,0 +1,140 @@
+//! Checked bulk upserts
+//!
+//! Table names are possibly schema-qualified (`schema.name`) and quoted with
+//! [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier), column names are quoted with
+//! [`quote_identifier`](crate::quote::quote_identifier). Values are always passed as parameters.
+
+use pgx::{pg_sys::Datum, PgOid, SpiClient};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::quote::*;
+use crate::validate;
+
+pub use crate::validate::MAX_PARAMETERS;
+
+/// Outcome of [`checked_upsert`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpsertStats {
+    /// Rows that were inserted
+    pub inserted: u64,
+    /// Existing rows that were updated
+    pub updated: u64,
+    /// Number of `INSERT` commands executed
+    pub batches: u64,
+}
+
+/// Insert `rows` into `table`, updating `update_columns` of the rows that conflict on `conflict_target` instead
+///
+/// Every row holds a value (along with its type) for each of `columns`, in order. The rows are inserted with
+/// multi-row `INSERT ... ON CONFLICT` commands, as many rows per command as fit in [`MAX_PARAMETERS`] parameters.
+/// All the commands execute in one sub-transaction, so either all the rows are upserted or, if an error is caught,
+/// none are.
+///
+/// If `update_columns` is empty, conflicting rows are left as they are (`DO NOTHING`), and counted neither as
+/// inserted nor updated; `conflict_target` may then be empty too, to skip rows conflicting on any constraint. As with
+/// any `ON CONFLICT DO UPDATE`, a command fails if two of its rows conflict with each other.
+///
+/// The table and column names are checked with [`validate`] before anything is executed, as is the number of
+/// columns, which can't exceed [`MAX_PARAMETERS`] (that of rows can, they are split into more commands).
+///
+/// Panics if there are no columns, or if a row doesn't have as many values as there are columns.
+pub fn checked_upsert(
+    client: &mut SpiClient,
+    table: &str,
+    columns: &[&str],
+    conflict_target: &[&str],
+    rows: Vec<Vec<(PgOid, Option<Datum>)>>,
+    update_columns: &[&str],
+) -> Result<UpsertStats, Error> {
+    assert!(!columns.is_empty(), "there must be at least one column");
+    if let Some(row) = rows.iter().position(|row| row.len() != columns.len()) {
+        panic!(
+            "row {} has {} values, expected {}",
+            row,
+            rows[row].len(),
+            columns.len()
+        );
+    }
+    validate::qualified_identifier(table)?;
+    for column in columns.iter().chain(conflict_target).chain(update_columns) {
+        validate::identifier(column)?;
+    }
+    validate::param_count(columns.len())?;
+    if rows.is_empty() {
+        return Ok(UpsertStats::default());
+    }
+    let prefix = format!(
+        "INSERT INTO {} ({}) VALUES ",
+        quote_qualified_identifier(table),
+        quote_identifiers(columns)
+    );
+    let target = if conflict_target.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", quote_identifiers(conflict_target))
+    };
+    let action = if update_columns.is_empty() {
+        "DO NOTHING".to_string()
+    } else {
+        format!(
+            "DO UPDATE SET {}",
+            update_columns
+                .iter()
+                .map(|column| {
+                    let column = quote_identifier(column);
+                    format!("{} = EXCLUDED.{}", column, column)
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    };
+    let suffix = format!(" ON CONFLICT{} {} RETURNING xmax = 0", target, action);
+    let rows_per_batch = MAX_PARAMETERS / columns.len();
+    let columns = columns.len();
+    checked_sub_transaction_of(client, move |client| {
+        let mut stats = UpsertStats::default();
+        let mut rows = rows.into_iter().peekable();
+        while rows.peek().is_some() {
+            let batch: Vec<_> = rows.by_ref().take(rows_per_batch).collect();
+            let query = format!("{}{}{}", prefix, values_list(batch.len(), columns), suffix);
+            let table = client.update(&query, None, Some(batch.into_iter().flatten().collect()));
+            for row in table {
+                if row
+                    .by_ordinal(1)
+                    .unwrap()
+                    .value::<bool>()
+                    .unwrap_or_default()
+                {
+                    stats.inserted += 1;
+                } else {
+                    stats.updated += 1;
+                }
+            }
+            stats.batches += 1;
+        }
+        stats
+    })
+}
+
+fn quote_identifiers(identifiers: &[&str]) -> String {
+    identifiers
+        .iter()
+        .map(|identifier| quote_identifier(identifier))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `VALUES` list of `rows` rows of `columns` positional parameters each, as in `($1, $2), ($3, $4)`
+fn values_list(rows: usize, columns: usize) -> String {
+    (0..rows)
+        .map(|row| {
+            let parameters = (1..=columns)
+                .map(|column| format!("${}", row * columns + column))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("({})", parameters)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,429 @@
+use pgx::pg_sys::panic::CaughtError;
+use pgx::{pg_sys, PgList, PgOid, PgTryBuilder, SpiClient};
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::checked_sub_transaction;
+use crate::error::{report, Error};
+use crate::quote::quote_identifier;
+use crate::scan::{line_column, Scanner, Token};
+use crate::subtxn::*;
+
+/// Kind of a validated command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandType {
+    Select,
+    Insert,
+    Update,
+    Delete,
+    Merge,
+    /// Utility command, identified by its first keyword (uppercased)
+    Utility(String),
+}
+
+/// Output column of a validated query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnShape {
+    pub name: String,
+    pub type_oid: PgOid,
+}
+
+/// Shape of a validated query, obtained without executing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryShape {
+    pub command: CommandType,
+    /// Output columns (empty if the command produces no output)
+    pub columns: Vec<ColumnShape>,
+    /// Number of positional parameters referenced by the query
+    pub params: usize,
+}
+
+/// Position in the validated query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourcePosition {
+    /// Byte offset
+    pub offset: usize,
+    /// 1-based line
+    pub line: usize,
+    /// 1-based column (in characters)
+    pub column: usize,
+}
+
+/// Query validation error
+#[derive(Debug)]
+pub enum ValidationError {
+    /// Postgres rejected the query
+    Invalid {
+        error: CaughtError,
+        /// Position of the error in the query, if it could be determined
+        position: Option<SourcePosition>,
+    },
+    /// More than one statement was supplied
+    MultipleStatements { count: usize },
+    /// No statement was supplied
+    Empty,
+}
+
+/// Validate a query without executing it
+///
+/// The query is parsed and prepared (but never executed) with `args` as parameter types, inside of a sub-transaction
+/// that is always rolled back, so that any side effects of preparing it are discarded.
+pub fn validate_query(
+    client: &SpiClient,
+    query: &str,
+    args: &[PgOid],
+) -> Result<QueryShape, ValidationError> {
+    let arg_types: Vec<pg_sys::Oid> = args.iter().map(|oid| oid.value()).collect();
+    client
+        .sub_transaction(|xact| {
+            let xact = xact.rollback_on_drop();
+            PgTryBuilder::new(move || {
+                let shape = unsafe { describe(query, arg_types) };
+                drop(xact);
+                Ok(shape)
+            })
+            .catch_others(|e| Err(e))
+            .execute()
+        })
+        .unwrap_or_else(|error| {
+            Err(ValidationError::Invalid {
+                position: error_position(query, &error),
+                error,
+            })
+        })
+}
+
+unsafe fn describe(
+    query: &str,
+    mut arg_types: Vec<pg_sys::Oid>,
+) -> Result<QueryShape, ValidationError> {
+    let c_query = CString::new(query).expect("query contains a NUL byte");
+    let statements = PgList::<pg_sys::RawStmt>::from_pg(pg_sys::pg_parse_query(c_query.as_ptr()));
+    let raw = match statements.len() {
+        0 => return Err(ValidationError::Empty),
+        1 => statements.get_ptr(0).unwrap(),
+        count => return Err(ValidationError::MultipleStatements { count }),
+    };
+    let command = command_type((*raw).stmt, query);
+
+    let plan = pg_sys::SPI_prepare(
+        c_query.as_ptr(),
+        arg_types.len() as i32,
+        arg_types.as_mut_ptr(),
+    );
+    if plan.is_null() {
+        let code = pg_sys::SPI_result;
+        panic!("SPI_prepare failed with code {}", code);
+    }
+    let sources =
+        PgList::<pg_sys::CachedPlanSource>::from_pg(pg_sys::SPI_plan_get_plan_sources(plan));
+    let desc = sources
+        .head()
+        .map_or(std::ptr::null_mut(), |source| (*source).resultDesc);
+    let columns = if desc.is_null() {
+        vec![]
+    } else {
+        (1..=(*desc).natts)
+            .map(|i| ColumnShape {
+                name: CStr::from_ptr(pg_sys::SPI_fname(desc, i))
+                    .to_string_lossy()
+                    .into_owned(),
+                type_oid: PgOid::from(pg_sys::SPI_gettypeid(desc, i)),
+            })
+            .collect()
+    };
+    pg_sys::SPI_freeplan(plan);
+
+    let params = Scanner::new(query)
+        .filter_map(|(_, token)| match token {
+            Token::Param(n) => Some(n as usize),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    Ok(QueryShape {
+        command,
+        columns,
+        params,
+    })
+}
+
+unsafe fn command_type(stmt: *mut pg_sys::Node, query: &str) -> CommandType {
+    match (*stmt).type_ {
+        pg_sys::NodeTag::T_SelectStmt => CommandType::Select,
+        pg_sys::NodeTag::T_InsertStmt => CommandType::Insert,
+        pg_sys::NodeTag::T_UpdateStmt => CommandType::Update,
+        pg_sys::NodeTag::T_DeleteStmt => CommandType::Delete,
+        #[cfg(feature = "pg15")]
+        pg_sys::NodeTag::T_MergeStmt => CommandType::Merge,
+        _ => CommandType::Utility(
+            Scanner::new(query)
+                .find_map(|(_, token)| match token {
+                    Token::Word(word) => Some(word.to_uppercase()),
+                    _ => None,
+                })
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+/// Recovers the position of the error in the query
+///
+/// Pgx does not expose the error's cursor position, so it is recovered from the `at or near "..."`
+/// (or `at end of input`) part of the message.
+fn error_position(query: &str, error: &CaughtError) -> Option<SourcePosition> {
+    let message = report(error).message();
+    let offset = if message.ends_with("at end of input") {
+        query.trim_end().len()
+    } else {
+        const NEAR: &str = "at or near \"";
+        let start = message.find(NEAR)? + NEAR.len();
+        let token = message[start..].strip_suffix('"')?;
+        Scanner::new(query)
+            .find(|(offset, _)| query[*offset..].starts_with(token))
+            .map(|(offset, _)| offset)?
+    };
+    let (line, column) = line_column(query, offset);
+    Some(SourcePosition {
+        offset,
+        line,
+        column,
+    })
+}
+
+/// Maximum length of an identifier in bytes, longer ones being truncated by Postgres
+pub const MAX_IDENTIFIER_BYTES: usize = pg_sys::NAMEDATALEN as usize - 1;
+
+/// Maximum number of parameters of a single command (the wire protocol counts them with 16 bits)
+pub const MAX_PARAMETERS: usize = 65535;
+
+/// Identifier checked by [`identifier`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidIdent(String);
+
+impl ValidIdent {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    /// Quote the identifier, see [`quote_identifier`](crate::quote::quote_identifier)
+    pub fn quote(&self) -> String {
+        quote_identifier(&self.0)
+    }
+}
+
+/// Identifier rejected by [`identifier`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentError {
+    /// Postgres rejects zero-length quoted identifiers
+    Empty,
+    /// Postgres would silently truncate the identifier to [`MAX_IDENTIFIER_BYTES`], possibly referencing another
+    /// object
+    WouldTruncate { ident: String, byte_len: usize },
+    /// The identifier has characters that have no equivalent in the server encoding
+    NotInServerEncoding { ident: String },
+    /// The identifier contains a NUL byte, which can't be part of a command
+    Nul { ident: String },
+    /// The column name is not qualified with its table
+    UnqualifiedColumn { name: String },
+}
+
+impl Display for IdentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentError::Empty => write!(f, "zero-length identifier"),
+            IdentError::WouldTruncate { ident, byte_len } => write!(
+                f,
+                "identifier \"{}\" is {} bytes long, it would be truncated to {}",
+                ident, byte_len, MAX_IDENTIFIER_BYTES
+            ),
+            IdentError::NotInServerEncoding { ident } => write!(
+                f,
+                "identifier \"{}\" has characters that have no equivalent in the server encoding",
+                ident
+            ),
+            IdentError::Nul { ident } => {
+                write!(f, "identifier {:?} contains a NUL byte", ident)
+            }
+            IdentError::UnqualifiedColumn { name } => {
+                write!(
+                    f,
+                    "column name \"{}\" is not qualified with its table",
+                    name
+                )
+            }
+        }
+    }
+}
+
+/// Literal rejected by [`literal`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LiteralError {
+    /// The literal contains a NUL byte at `offset`, which text values can't contain
+    Nul { literal: String, offset: usize },
+}
+
+impl Display for LiteralError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LiteralError::Nul { literal, offset } => write!(
+                f,
+                "literal {:?} contains a NUL byte at offset {}",
+                literal, offset
+            ),
+        }
+    }
+}
+
+/// A command would have more than [`MAX_PARAMETERS`] parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyParams {
+    pub count: usize,
+    pub max: usize,
+}
+
+impl Display for TooManyParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} parameters were given, at most {} can be passed",
+            self.count, self.max
+        )
+    }
+}
+
+/// Value rejected before anything was executed, see [`Error::Preflight`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreflightError {
+    Identifier(IdentError),
+    Literal(LiteralError),
+    TooManyParams(TooManyParams),
+}
+
+impl Display for PreflightError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreflightError::Identifier(err) => write!(f, "{}", err),
+            PreflightError::Literal(err) => write!(f, "{}", err),
+            PreflightError::TooManyParams(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<IdentError> for PreflightError {
+    fn from(err: IdentError) -> Self {
+        PreflightError::Identifier(err)
+    }
+}
+
+impl From<LiteralError> for PreflightError {
+    fn from(err: LiteralError) -> Self {
+        PreflightError::Literal(err)
+    }
+}
+
+impl From<TooManyParams> for PreflightError {
+    fn from(err: TooManyParams) -> Self {
+        PreflightError::TooManyParams(err)
+    }
+}
+
+impl From<IdentError> for Error {
+    fn from(err: IdentError) -> Self {
+        Error::Preflight(err.into())
+    }
+}
+
+impl From<LiteralError> for Error {
+    fn from(err: LiteralError) -> Self {
+        Error::Preflight(err.into())
+    }
+}
+
+impl From<TooManyParams> for Error {
+    fn from(err: TooManyParams) -> Self {
+        Error::Preflight(err.into())
+    }
+}
+
+/// Check that an identifier is used by Postgres as it is
+///
+/// Its length is that of its encoding in the server encoding, which is the length of its UTF-8 encoding unless the
+/// database encoding is not UTF-8 and the identifier is not ASCII. Converting it then requires a transaction.
+pub fn identifier(ident: &str) -> Result<ValidIdent, IdentError> {
+    if ident.is_empty() {
+        return Err(IdentError::Empty);
+    } else if ident.contains('\0') {
+        return Err(IdentError::Nul {
+            ident: ident.to_string(),
+        });
+    }
+    let byte_len = server_byte_len(ident).ok_or_else(|| IdentError::NotInServerEncoding {
+        ident: ident.to_string(),
+    })?;
+    if byte_len > MAX_IDENTIFIER_BYTES {
+        Err(IdentError::WouldTruncate {
+            ident: ident.to_string(),
+            byte_len,
+        })
+    } else {
+        Ok(ValidIdent(ident.to_string()))
+    }
+}
+
+/// Length of a string without NUL bytes in the server encoding, `None` if it can't be converted to it
+fn server_byte_len(text: &str) -> Option<usize> {
+    // Every server encoding is a superset of ASCII
+    if text.is_ascii() || unsafe { pg_sys::GetDatabaseEncoding() } == pg_sys::pg_enc_PG_UTF8 as i32
+    {
+        return Some(text.len());
+    }
+    let text = CString::new(text).expect("text contains a NUL byte");
+    // The conversion raises an error for characters that have no equivalent
+    checked_sub_transaction(move |_| unsafe {
+        let converted = pg_sys::pg_any_to_server(
+            text.as_ptr(),
+            text.as_bytes().len() as i32,
+            pg_sys::pg_enc_PG_UTF8 as i32,
+        );
+        let len = CStr::from_ptr(converted).to_bytes().len();
+        if converted as *const _ != text.as_ptr() {
+            pg_sys::pfree(converted.cast());
+        }
+        len
+    })
+    .ok()
+}
+
+/// Check every part of a possibly schema-qualified name (`schema.name`) with [`identifier`], as
+/// [`quote_qualified_identifier`](crate::quote::quote_qualified_identifier) quotes them
+pub fn qualified_identifier(name: &str) -> Result<Vec<ValidIdent>, IdentError> {
+    name.split('.').map(identifier).collect()
+}
+
+/// Check that a command can be passed `count` parameters
+pub fn param_count(count: usize) -> Result<(), TooManyParams> {
+    if count > MAX_PARAMETERS {
+        Err(TooManyParams {
+            count,
+            max: MAX_PARAMETERS,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that a string can be used as a text literal or argument
+pub fn literal(literal: &str) -> Result<(), LiteralError> {
+    match literal.find('\0') {
+        Some(offset) => Err(LiteralError::Nul {
+            literal: literal.to_string(),
+            offset,
+        }),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,369 @@
+//! Reading written rows back to detect changes made to them behind the command's back
+//!
+//! Triggers and rules can silently change the rows a command writes. A verified command executes, then reads back
+//! every row it returned, in the same sub-transaction, and compares the values with the ones it wrote, rolling the
+//! sub-transaction back on the first mismatch. Values are compared as text, as their types' output functions produce
+//! it.
+//!
+//! The values a command wrote are those it returns, except for the columns it sets directly to one of its parameters
+//! (`INSERT ... (columns) VALUES ($1, ...)` without `ON CONFLICT`, `UPDATE ... SET column = $1`), which are expected to
+//! have that parameter's value: the output of a command already reflects what `BEFORE` triggers changed, so only the
+//! parameters tell those changes. Such parameters should have the type of their column, so that their text is the
+//! same.
+
+use pgx::{
+    pg_sys, pg_sys::Datum, IntoDatum, PgBuiltInOids, PgList, PgOid, SpiClient, SpiTupleTable,
+};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt::{Display, Formatter};
+
+use crate::checked::*;
+use crate::error::Error;
+use crate::quote::*;
+use crate::rewrite;
+use crate::row::column_names;
+use crate::subtxn::*;
+
+/// How the rows written by a command are read back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Select each row from the command's target table by its primary key, whose columns the command must return
+    PrimaryKey,
+    /// Execute `query` for each row the command returns, binding the output columns named by `params` to `$1`, `$2`,
+    /// ..., in order; its first row is the row as it was stored
+    Query { query: String, params: Vec<String> },
+}
+
+/// Verification of a command's writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifySpec {
+    pub mode: VerifyMode,
+    /// Columns that aren't compared, such as those triggers legitimately set (`updated_at`, ...)
+    pub ignore: Vec<String>,
+}
+
+impl VerifySpec {
+    /// Read rows back by primary key
+    pub fn primary_key() -> Self {
+        VerifySpec {
+            mode: VerifyMode::PrimaryKey,
+            ignore: vec![],
+        }
+    }
+
+    /// Read rows back with `query`, bound to the output columns named by `params`
+    pub fn query(query: &str, params: &[&str]) -> Self {
+        VerifySpec {
+            mode: VerifyMode::Query {
+                query: query.to_string(),
+                params: params.iter().map(|param| param.to_string()).collect(),
+            },
+            ignore: vec![],
+        }
+    }
+
+    /// Don't compare `column`
+    pub fn ignore(mut self, column: &str) -> Self {
+        self.ignore.push(column.to_string());
+        self
+    }
+}
+
+/// Verified command error
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The value of `column` of the `row`th (zero-based) row the command returned was changed from `expected` to
+    /// `actual` (`None` is NULL)
+    Mismatch {
+        column: String,
+        expected: Option<String>,
+        actual: Option<String>,
+        row: usize,
+    },
+    /// The `row`th (zero-based) row the command returned couldn't be read back
+    Missing { row: usize },
+    /// The command's writes can't be verified as specified, for the given reason; nothing was kept
+    Unsupported(String),
+    /// The command or the reading back failed
+    Query(Error),
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let text = |value: &Option<String>| value.as_deref().unwrap_or("NULL").to_string();
+        match self {
+            VerifyError::Mismatch {
+                column,
+                expected,
+                actual,
+                row,
+            } => write!(
+                f,
+                "column \"{}\" of row {} was written as {} but reads back as {}",
+                column,
+                row,
+                text(expected),
+                text(actual)
+            ),
+            VerifyError::Missing { row } => write!(f, "row {} can't be read back", row),
+            VerifyError::Unsupported(reason) => write!(f, "can't verify writes: {}", reason),
+            VerifyError::Query(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for VerifyError {
+    fn from(err: Error) -> Self {
+        VerifyError::Query(err)
+    }
+}
+
+/// Execute an `INSERT` or `UPDATE` with a `RETURNING` clause, read back every row it returned as `verify` specifies,
+/// and return its output if they all have the values it wrote, or an error.
+///
+/// Everything executes in one sub-transaction, which is rolled back if a value doesn't match
+/// ([`VerifyError::Mismatch`]), a row can't be read back ([`VerifyError::Missing`]) or an error is caught. Columns that
+/// aren't both written and read back, or that `verify` ignores, aren't compared. A query that can't be parsed fails
+/// with [`VerifyError::Query`].
+pub fn checked_update_verified(
+    query: &str,
+    args: Option<Vec<(PgOid, Option<Datum>)>>,
+    verify: VerifySpec,
+) -> Result<SpiTupleTable, VerifyError> {
+    check_entry(query)?;
+    let target = checked_sub_transaction(|_| unsafe { analyze(query) })??;
+    SpiClient.sub_transaction(|xact| {
+        let mut xact = xact.rollback_on_drop();
+        let reread = match verify.mode {
+            VerifyMode::PrimaryKey => primary_key_query(&xact, &target.relation)?,
+            VerifyMode::Query { query, params } => (query, params),
+        };
+        let mut table = xact.update(query, None, args.clone())?;
+        let columns = column_names(&table);
+        let types: Vec<_> = (1..=columns.len())
+            .map(|ordinal| table.column_type_oid(ordinal).unwrap_or(PgOid::InvalidOid))
+            .collect();
+        let param_ordinals = reread
+            .1
+            .iter()
+            .map(|param| {
+                columns
+                    .iter()
+                    .position(|column| column == param)
+                    .map(|index| index + 1)
+                    .ok_or_else(|| {
+                        VerifyError::Unsupported(format!(
+                            "the command doesn't return column \"{}\"",
+                            param
+                        ))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let args = args.unwrap_or_default();
+        for (row, tuple) in table.by_ref().enumerate() {
+            let datum = |ordinal: usize| {
+                tuple
+                    .by_ordinal(ordinal)
+                    .ok()
+                    .and_then(|entry| entry.value::<Datum>())
+            };
+            // Values returned by the command, overridden by the parameters it set columns to
+            let mut expected: HashMap<&str, Option<String>> = columns
+                .iter()
+                .zip(&types)
+                .enumerate()
+                .map(|(index, (column, type_oid))| {
+                    (column.as_str(), output_text(*type_oid, datum(index + 1)))
+                })
+                .collect();
+            for (column, param) in target.assignments(row) {
+                if let Some((type_oid, value)) = args.get(param - 1) {
+                    expected.insert(column, output_text(*type_oid, *value));
+                }
+            }
+            let reread_args = param_ordinals
+                .iter()
+                .map(|ordinal| (types[ordinal - 1], datum(*ordinal)))
+                .collect();
+            let mut actual = xact.select(&reread.0, Some(1), Some(reread_args))?;
+            let actual_tuple = match actual.next() {
+                Some(tuple) => tuple,
+                None => return Err(VerifyError::Missing { row }),
+            };
+            for (index, column) in column_names(&actual).iter().enumerate() {
+                let expected = match expected.get(column.as_str()) {
+                    Some(expected) if !verify.ignore.contains(column) => expected,
+                    _ => continue,
+                };
+                let actual_value = actual_tuple
+                    .by_ordinal(index + 1)
+                    .ok()
+                    .and_then(|entry| entry.value::<Datum>());
+                let type_oid = actual
+                    .column_type_oid(index + 1)
+                    .unwrap_or(PgOid::InvalidOid);
+                let actual_value = output_text(type_oid, actual_value);
+                if *expected != actual_value {
+                    // Dropping the sub-transaction rolls it back
+                    return Err(VerifyError::Mismatch {
+                        column: column.clone(),
+                        expected: expected.clone(),
+                        actual: actual_value,
+                        row,
+                    });
+                }
+            }
+        }
+        xact.commit();
+        Ok(table.first())
+    })
+}
+
+/// Target of a command that can be verified
+struct Target {
+    /// Qualified, quoted table
+    relation: String,
+    /// Columns set to a parameter (by its number), for every row of an `INSERT`'s `VALUES` list, or for every row an
+    /// `UPDATE` changes
+    assignments: Vec<Vec<(String, usize)>>,
+    update: bool,
+}
+
+impl Target {
+    /// Columns set to a parameter in the `row`th row the command wrote
+    fn assignments(&self, row: usize) -> impl Iterator<Item = (&str, usize)> {
+        let row = if self.update { 0 } else { row };
+        self.assignments
+            .get(row)
+            .into_iter()
+            .flatten()
+            .map(|(column, param)| (column.as_str(), *param))
+    }
+}
+
+/// Parse `query`, finding its target table and the columns it sets to parameters
+unsafe fn analyze(query: &str) -> Result<Target, VerifyError> {
+    let unsupported = |reason: &str| Err(VerifyError::Unsupported(reason.to_string()));
+    let c_query = CString::new(query).expect("query contains a NUL byte");
+    let statements = PgList::<pg_sys::RawStmt>::from_pg(pg_sys::pg_parse_query(c_query.as_ptr()));
+    if statements.len() != 1 {
+        return unsupported("only a single statement is supported");
+    }
+    let stmt = (*statements.get_ptr(0).unwrap()).stmt;
+    let param = |node: *mut pg_sys::Node| {
+        (!node.is_null() && (*node).type_ == pg_sys::NodeTag::T_ParamRef)
+            .then(|| (*(node as *mut pg_sys::ParamRef)).number as usize)
+    };
+    let name =
+        |ptr: *const std::os::raw::c_char| CStr::from_ptr(ptr).to_string_lossy().into_owned();
+    let (relation, returning, assignments, update) = match (*stmt).type_ {
+        pg_sys::NodeTag::T_InsertStmt => {
+            let stmt = stmt as *mut pg_sys::InsertStmt;
+            let select = (*stmt).selectStmt as *mut pg_sys::SelectStmt;
+            let columns: Vec<_> = PgList::<pg_sys::ResTarget>::from_pg((*stmt).cols)
+                .iter_ptr()
+                .map(|target| name((*target).name))
+                .collect();
+            let assignments = if select.is_null()
+                || (*select).valuesLists.is_null()
+                || !(*stmt).onConflictClause.is_null()
+            {
+                vec![]
+            } else {
+                PgList::<pg_sys::List>::from_pg((*select).valuesLists)
+                    .iter_ptr()
+                    .map(|values| {
+                        columns
+                            .iter()
+                            .zip(PgList::<pg_sys::Node>::from_pg(values).iter_ptr())
+                            .filter_map(|(column, value)| Some((column.clone(), param(value)?)))
+                            .collect()
+                    })
+                    .collect()
+            };
+            ((*stmt).relation, (*stmt).returningList, assignments, false)
+        }
+        pg_sys::NodeTag::T_UpdateStmt => {
+            let stmt = stmt as *mut pg_sys::UpdateStmt;
+            let assignments = PgList::<pg_sys::ResTarget>::from_pg((*stmt).targetList)
+                .iter_ptr()
+                .filter_map(|target| Some((name((*target).name), param((*target).val)?)))
+                .collect();
+            (
+                (*stmt).relation,
+                (*stmt).returningList,
+                vec![assignments],
+                true,
+            )
+        }
+        pg_sys::NodeTag::T_DeleteStmt => return unsupported("DELETE leaves no rows to read back"),
+        _ => return unsupported("only INSERT and UPDATE are supported"),
+    };
+    if returning.is_null() {
+        return unsupported("the command has no RETURNING clause");
+    }
+    let schema = (*relation).schemaname;
+    let relation = if schema.is_null() {
+        quote_identifier(&name((*relation).relname))
+    } else {
+        format!(
+            "{}.{}",
+            quote_identifier(&name(schema)),
+            quote_identifier(&name((*relation).relname))
+        )
+    };
+    Ok(Target {
+        relation,
+        assignments,
+        update,
+    })
+}
+
+/// Query selecting a row of `relation` by its primary key, and the key columns it's bound to
+fn primary_key_query<Parent>(
+    xact: &SubTransaction<Parent, false>,
+    relation: &str,
+) -> Result<(String, Vec<String>), VerifyError> {
+    let key_columns: Vec<String> = rewrite::exempt(|| {
+        xact.select(
+            "SELECT a.attname::text FROM pg_index i \
+             JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY (i.indkey) \
+             WHERE i.indrelid = $1::regclass AND i.indisprimary \
+             ORDER BY array_position(i.indkey::int2[], a.attnum)",
+            None,
+            Some(vec![(PgBuiltInOids::TEXTOID.oid(), relation.into_datum())]),
+        )
+    })?
+    .filter_map(|row| row.by_ordinal(1).ok()?.value::<String>())
+    .collect();
+    if key_columns.is_empty() {
+        return Err(VerifyError::Unsupported(format!(
+            "{} has no primary key",
+            relation
+        )));
+    }
+    let key = key_columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| format!("{} = ${}", quote_identifier(column), i + 1))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    Ok((
+        format!("SELECT * FROM {} WHERE {}", relation, key),
+        key_columns,
+    ))
+}
+
+/// Text of a value of type `type_oid`, as its output function produces it, or `None` if it's NULL
+fn output_text(type_oid: PgOid, datum: Option<Datum>) -> Option<String> {
+    let datum = datum?;
+    let (mut function, mut is_varlena) = (pg_sys::InvalidOid, false);
+    unsafe {
+        pg_sys::getTypeOutputInfo(type_oid.value(), &mut function, &mut is_varlena);
+        let text = pg_sys::OidOutputFunctionCall(function, datum);
+        let value = CStr::from_ptr(text).to_string_lossy().into_owned();
+        pg_sys::pfree(text.cast());
+        Some(value)
+    }
+}
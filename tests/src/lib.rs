@@ -2,12 +2,27 @@ use pgx::prelude::*;
 
 pgx::pg_module_magic!();
 
+/// Sub-transactions opened by `pgx-contrib-spiext` that are still open, for diagnosing incidents from psql
+#[pg_extern]
+fn spiext_debug_dump() -> String {
+    pgx_contrib_spiext::subtxn::debug_dump()
+}
+
+/// Counters of `pgx-contrib-spiext` in this backend, for scraping by monitoring
+#[pg_extern]
+fn spiext_metrics() -> TableIterator<'static, (name!(name, String), name!(value, i64))> {
+    TableIterator::new(
+        pgx_contrib_spiext::metrics::srf_rows().map(|row| (row.name.to_string(), row.value)),
+    )
+}
+
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
     use pgx::pg_sys::submodules::panic::CaughtError;
     use pgx::prelude::*;
     use pgx::SpiClient;
+    use pgx_contrib_spiext::error::Error;
     use pgx_contrib_spiext::*;
 
     #[pg_test]
@@ -16,19 +31,21 @@ mod tests {
         Spi::execute(|mut c| {
             c.update("CREATE TABLE a (v INTEGER)", None, None);
             let c = c.sub_transaction(|mut xact| {
-                xact.update("INSERT INTO a VALUES (0)", None, None);
+                xact.update("INSERT INTO a VALUES (0)", None, None).unwrap();
                 assert_eq!(
                     0,
                     xact.select("SELECT v FROM a", Some(1), None)
+                        .unwrap()
                         .first()
                         .get_datum::<i32>(1)
                         .unwrap()
                 );
                 let xact = xact.sub_transaction(|mut xact| {
-                    xact.update("INSERT INTO a VALUES (1)", None, None);
+                    xact.update("INSERT INTO a VALUES (1)", None, None).unwrap();
                     assert_eq!(
                         2,
                         xact.select("SELECT COUNT(*) FROM a", Some(1), None)
+                            .unwrap()
                             .first()
                             .get_datum::<i32>(1)
                             .unwrap()
@@ -70,7 +87,7 @@ mod tests {
                     .unwrap()
             );
             let c = SpiClient.sub_transaction(|mut xact| {
-                xact.update("INSERT INTO a VALUES (0)", None, None);
+                xact.update("INSERT INTO a VALUES (0)", None, None).unwrap();
                 xact.rollback()
             });
             // The above transaction will be rolled back (as explicitly requested)
@@ -93,7 +110,7 @@ mod tests {
             let result = c.checked_select("SLECT 1", None, None);
             assert!(matches!(
                 result,
-                Err(CaughtError::PostgresError(error)) if error.message() == "syntax error at or near \"SLECT\""
+                Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message() == "syntax error at or near \"SLECT\""
             ));
         });
     }
@@ -116,7 +133,7 @@ mod tests {
             let result = c.checked_update("CREAT TABLE x()", None, None);
             assert!(matches!(
                 result,
-                Err(CaughtError::PostgresError(error)) if error.message() == "syntax error at or near \"CREAT\""
+                Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message() == "syntax error at or near \"CREAT\""
             ));
         });
     }
@@ -131,7 +148,7 @@ mod tests {
                 let result = xact.checked_select("SLECT 1", None, None);
                 assert!(matches!(
                     result,
-                    Err(CaughtError::PostgresError(error)) if error.message() == "syntax error at or near \"SLECT\""
+                    Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message() == "syntax error at or near \"SLECT\""
                 ));
             });
         });
@@ -149,11 +166,6675 @@ mod tests {
                 let result = xact.checked_update("INSER INTO a VALUES ()", None, None);
                 assert!(matches!(
                     result,
-                    Err(CaughtError::PostgresError(error)) if error.message() == "syntax error at or near \"INSER\""
+                    Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message() == "syntax error at or near \"INSER\""
+                ));
+            });
+        });
+    }
+
+    #[pg_test]
+    fn test_strict_select_reports_null() {
+        use row::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE n (id INTEGER, v TEXT)", None, None);
+            c.update(
+                "INSERT INTO n SELECT i, CASE WHEN i = 57 THEN NULL ELSE i::text END FROM generate_series(0, 99) i",
+                None,
+                None,
+            );
+            let result = c.checked_select_strict::<(i32, String)>(
+                "SELECT id, v FROM n ORDER BY id",
+                None,
+                None,
+            );
+            assert!(matches!(
+                result,
+                Err(StrictSelectError::Null(NullViolation { ref column, row: 57, query: Some(_) })) if column == "v"
+            ));
+            let table = c.select("SELECT id, v FROM n ORDER BY id", None, None);
+            assert!(matches!(
+                table.assert_no_nulls(&["id", "v"]),
+                Err(NullViolation { ref column, row: 57, query: None }) if column == "v"
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_strict_select_non_null() {
+        use row::*;
+        Spi::execute(|c| {
+            let rows = c
+                .checked_select_strict::<(i32, String)>(
+                    "SELECT i, i::text FROM generate_series(1, 10) i",
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert_eq!(10, rows.len());
+            assert_eq!((10, "10".to_string()), rows[9]);
+            assert!(c
+                .select("SELECT i FROM generate_series(1, 10) i", None, None)
+                .assert_no_nulls(&["i"])
+                .is_ok());
+        });
+    }
+
+    static ABORTED_RELEASES: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+
+    #[pg_guard]
+    unsafe extern "C" fn count_aborted_releases(
+        _phase: pg_sys::ResourceReleasePhase,
+        is_commit: bool,
+        _is_top_level: bool,
+        _arg: *mut std::os::raw::c_void,
+    ) {
+        if !is_commit {
+            ABORTED_RELEASES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[pg_test]
+    fn test_sub_txn_begin_with() {
+        use std::sync::atomic::Ordering;
+        use subtxn::*;
+        let register = |raw: &SubTransactionRaw| unsafe {
+            assert_eq!(raw.id(), pg_sys::GetCurrentSubTransactionId());
+            let current_owner = pg_sys::CurrentResourceOwner;
+            assert_ne!(raw.resource_owner(), current_owner);
+            pg_sys::RegisterResourceReleaseCallback(
+                Some(count_aborted_releases),
+                std::ptr::null_mut(),
+            );
+        };
+        let unregister = || unsafe {
+            pg_sys::UnregisterResourceReleaseCallback(
+                Some(count_aborted_releases),
+                std::ptr::null_mut(),
+            );
+        };
+        Spi::execute(|c| {
+            let xact: SubTransaction<_> =
+                SubTransaction::begin_with(SpiClientWrapper::from(c), register).unwrap();
+            let c = xact.commit();
+            unregister();
+            assert_eq!(0, ABORTED_RELEASES.load(Ordering::SeqCst));
+
+            let xact: SubTransaction<_> = SubTransaction::begin_with(c, register).unwrap();
+            let c = xact.rollback();
+            unregister();
+            assert!(ABORTED_RELEASES.load(Ordering::SeqCst) > 0);
+
+            let result: Result<SubTransaction<_>, _> = SubTransaction::begin_with(c, |_| {
+                pgx::error!("setup failed");
+            });
+            assert!(matches!(
+                result,
+                Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message() == "setup failed"
+            ));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_validate_select() {
+        use validate::*;
+        Spi::execute(|c| {
+            let shape = validate_query(
+                &c,
+                "SELECT 1::int AS a, $1::text AS b",
+                &[PgBuiltInOids::TEXTOID.oid()],
+            )
+            .unwrap();
+            assert_eq!(CommandType::Select, shape.command);
+            assert_eq!(1, shape.params);
+            assert_eq!(
+                vec![
+                    ColumnShape {
+                        name: "a".into(),
+                        type_oid: PgBuiltInOids::INT4OID.oid()
+                    },
+                    ColumnShape {
+                        name: "b".into(),
+                        type_oid: PgBuiltInOids::TEXTOID.oid()
+                    }
+                ],
+                shape.columns
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_validate_syntax_error() {
+        use validate::*;
+        Spi::execute(|c| {
+            let result = validate_query(&c, "SELECT 1\nFROM x\n  WHER y", &[]);
+            assert!(matches!(
+                result,
+                Err(ValidationError::Invalid {
+                    position: Some(SourcePosition {
+                        line: 3,
+                        column: 3,
+                        ..
+                    }),
+                    ..
+                })
+            ));
+            assert!(matches!(
+                validate_query(&c, "SELECT 1; SELECT 2", &[]),
+                Err(ValidationError::MultipleStatements { count: 2 })
+            ));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_validate_insert_does_not_execute() {
+        use validate::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE v (i INTEGER)", None, None);
+            let shape = validate_query(&c, "INSERT INTO v VALUES (1) RETURNING i", &[]).unwrap();
+            assert_eq!(CommandType::Insert, shape.command);
+            assert_eq!(1, shape.columns.len());
+            assert_eq!(
+                0,
+                c.select("SELECT COUNT(*) FROM v", None, None)
+                    .first()
+                    .get_datum::<i64>(1)
+                    .unwrap()
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_transient_function_persist() {
+        use ddl::*;
+        Spi::execute(|mut c| {
+            let f = TransientFunction::create(
+                &mut c,
+                "transient_add",
+                "a integer, b integer",
+                "integer",
+                "sql",
+                "SELECT a + b",
+            )
+            .unwrap();
+            assert!(f.oid().unwrap().is_some());
+            assert_eq!("transient_add", f.name());
+            f.persist();
+            assert_eq!(
+                Some(3),
+                c.select("SELECT transient_add(1, 2)", None, None)
+                    .first()
+                    .get_one::<i32>()
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_transient_function_drop() {
+        use ddl::*;
+        Spi::execute(|mut c| {
+            let f = TransientFunction::create(
+                &mut c,
+                "transient_fn",
+                "",
+                "text",
+                "plpgsql",
+                "BEGIN RETURN 'it''s $$ quoted'; END",
+            )
+            .unwrap();
+            drop(f);
+            assert_eq!(
+                None,
+                c.select("SELECT to_regprocedure('transient_fn()')::oid", None, None)
+                    .first()
+                    .get_one::<pg_sys::Oid>()
+            );
+            let result = TransientFunction::create(
+                &mut c,
+                "transient_broken",
+                "",
+                "integer",
+                "plpgsql",
+                "BEGIN RETURN 1 END",
+            );
+            assert!(matches!(
+                result,
+                Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message().contains("syntax error")
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_assert_no_writes() {
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE w (i INTEGER)", None, None);
+            c.update("CREATE TEMPORARY TABLE tw (i INTEGER)", None, None);
+            let count = assert_no_writes(&c, |c| {
+                c.select("SELECT COUNT(*) FROM w", None, None)
+                    .first()
+                    .get_one::<i64>()
+            })
+            .unwrap();
+            assert_eq!(Some(0), count);
+
+            let violation = assert_no_writes(&c, |c| {
+                c.update("INSERT INTO w VALUES (1)", None, None);
+                42
+            })
+            .unwrap_err();
+            assert_eq!(42, violation.value);
+            assert!(violation
+                .heuristics
+                .contains(&WriteHeuristic::TransactionIdAssigned));
+            assert!(!violation.temp_only);
+
+            let insert_temp = |c: &mut SpiClient| {
+                c.update("INSERT INTO tw VALUES (1)", None, None);
+            };
+            let violation = assert_no_writes(&c, insert_temp).unwrap_err();
+            assert!(violation.temp_only);
+            assert!(assert_no_writes_with(
+                &c,
+                WriteCheck {
+                    allow_temp_tables: true
+                },
+                insert_temp
+            )
+            .is_ok());
+
+            // Everything was rolled back
+            assert_eq!(
+                Some(0),
+                c.select(
+                    "SELECT (SELECT COUNT(*) FROM w) + (SELECT COUNT(*) FROM tw)",
+                    None,
+                    None
+                )
+                .first()
+                .get_one::<i64>()
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_checked_select_progress() {
+        use cursor::*;
+        use std::ops::ControlFlow;
+        Spi::execute(|c| {
+            let mut calls = vec![];
+            let summary = c
+                .checked_select_progress(
+                    "SELECT i FROM generate_series(1, 1000) i",
+                    None,
+                    100,
+                    |info| {
+                        calls.push(info.rows_so_far);
+                        ControlFlow::Continue(())
+                    },
+                )
+                .unwrap();
+            assert_eq!(SelectOutcome::Completed, summary.outcome);
+            assert_eq!(1000, summary.rows);
+            assert_eq!(10, summary.batches);
+            assert_eq!((1..=10).map(|i| i * 100).collect::<Vec<u64>>(), calls);
+
+            let summary = c
+                .checked_select_progress(
+                    "SELECT i FROM generate_series(1, 1000) i",
+                    None,
+                    100,
+                    |info| {
+                        if info.batches == 2 {
+                            ControlFlow::Break(())
+                        } else {
+                            ControlFlow::Continue(())
+                        }
+                    },
+                )
+                .unwrap();
+            assert_eq!(SelectOutcome::Cancelled, summary.outcome);
+            assert_eq!(200, summary.rows);
+            assert_eq!(2, summary.batches);
+
+            let result =
+                c.checked_select_progress("SELECT 1 / 0", None, 100, |_| ControlFlow::Continue(()));
+            assert!(matches!(
+                result,
+                Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message() == "division by zero"
+            ));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_checked_select_column_stream() {
+        use stream::*;
+
+        let allocated =
+            || unsafe { pg_sys::MemoryContextMemAllocated(pg_sys::TopMemoryContext, true) };
+
+        // Records the most memory allocated by Postgres at the time of any write
+        struct Chunks<F: Fn() -> usize> {
+            data: Vec<u8>,
+            largest_write: usize,
+            allocated: F,
+            peak: usize,
+        }
+
+        impl<F: Fn() -> usize> std::io::Write for Chunks<F> {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.largest_write = self.largest_write.max(buf.len());
+                self.peak = self.peak.max((self.allocated)());
+                self.data.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE blobs (id int, b bytea)", None, None);
+            // Stored uncompressed, so that slices are read without decompressing what precedes them
+            c.update("ALTER TABLE blobs ALTER b SET STORAGE EXTERNAL", None, None);
+            c.update(
+                "INSERT INTO blobs SELECT 1, string_agg(md5(i::text), '')::bytea FROM generate_series(1, 163840) i",
+                None,
+                None,
+            );
+            c.update("INSERT INTO blobs VALUES (2, NULL)", None, None);
+
+            let before = allocated();
+            let mut writer = Chunks {
+                data: vec![],
+                largest_write: 0,
+                allocated,
+                peak: before,
+            };
+            let stats = c
+                .checked_select_column_stream(
+                    "SELECT id, b FROM blobs ORDER BY id",
+                    None,
+                    2,
+                    &mut writer,
+                )
+                .unwrap();
+            assert_eq!(2, stats.rows);
+            assert_eq!(1, stats.nulls);
+            assert_eq!(5 * 1024 * 1024, stats.bytes);
+            assert_eq!(stats.bytes as usize, writer.data.len());
+            assert!(writer.largest_write <= DEFAULT_CHUNK_SIZE);
+            // The value is 5MB, the chunks detoasted at a time a small fraction of it
+            assert!(
+                writer.peak - before < 1024 * 1024,
+                "{}",
+                writer.peak - before
+            );
+
+            let matches = c
+                .select(
+                    "SELECT md5(b) = md5($1) FROM blobs WHERE id = 1",
+                    None,
+                    Some(vec![(
+                        PgBuiltInOids::BYTEAOID.oid(),
+                        writer.data.into_datum(),
+                    )]),
+                )
+                .first()
+                .get_one::<bool>();
+            assert_eq!(Some(true), matches);
+
+            let result =
+                c.checked_select_column_stream("SELECT id FROM blobs", None, 1, &mut vec![]);
+            assert!(matches!(
+                result,
+                Err(StreamError::NotVarlena { column: 1, .. })
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_checked_empty_query() {
+        use checked::*;
+        Spi::execute(|mut c| {
+            for query in [
+                "",
+                "   ;",
+                "; ;\n",
+                "-- nothing here",
+                "/* outer /* nested */ still a comment */ ;",
+            ] {
+                assert!(matches!(
+                    (&c).checked_select(query, None, None),
+                    Err(Error::EmptyQuery)
+                ));
+                assert!(matches!(
+                    (&mut c).checked_update(query, None, None),
+                    Err(Error::EmptyQuery)
+                ));
+            }
+            let value = (&c)
+                .checked_select("-- leading comment\n/* and another */ SELECT 1", None, None)
+                .unwrap()
+                .first()
+                .get_one::<i32>();
+            assert_eq!(Some(1), value);
+        });
+    }
+
+    #[pg_test]
+    fn test_stage_and_verify() {
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE candidates (v INTEGER)", None, None);
+            let count = |c: &SpiClient| {
+                c.select("SELECT COUNT(*) FROM candidates", None, None)
+                    .first()
+                    .get_one::<i64>()
+                    .unwrap()
+            };
+            let stage = |xact: &mut SubTransaction<SpiClientWrapper, false>| {
+                xact.update("INSERT INTO candidates VALUES (1), (2), (-1)", None, None)
+                    .unwrap();
+                Ok(3)
+            };
+
+            // Verification failure
+            let result = stage_and_verify(&mut c, stage, |_: &i32, xact| {
+                match xact
+                    .select("SELECT COUNT(*) FROM candidates WHERE v < 0", None, None)
+                    .unwrap()
+                    .first()
+                    .get_one::<i64>()
+                {
+                    Some(0) => Ok(()),
+                    _ => Err("negative values"),
+                }
+            });
+            assert!(matches!(
+                result,
+                Err(StageError::Verify(PhaseError::Failed("negative values")))
+            ));
+            assert_eq!(0, count(&c));
+
+            // Postgres error during verification
+            let result: Result<i32, StageError<&str>> =
+                stage_and_verify(&mut c, stage, |_, xact| {
+                    xact.select_unchecked("SELECT 1 / 0", None, None);
+                    Ok(())
+                });
+            assert!(matches!(
+                result,
+                Err(StageError::Verify(PhaseError::Caught(Error::Caught(CaughtError::PostgresError(error), _))))
+                    if error.message() == "division by zero"
+            ));
+            assert_eq!(0, count(&c));
+
+            // Success
+            let result: Result<i32, StageError<&str>> =
+                stage_and_verify(&mut c, stage, |staged, xact| {
+                    assert_eq!(*staged as i64, count(xact));
+                    Ok(())
+                });
+            assert_eq!(3, result.unwrap());
+            assert_eq!(3, count(&c));
+        });
+    }
+
+    #[pg_test]
+    fn test_sub_txn_update_error_keeps_parent() {
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE a (v INTEGER PRIMARY KEY)", None, None);
+            let c = c.sub_transaction(|mut xact| {
+                xact.update("INSERT INTO a VALUES (0)", None, None).unwrap();
+                let result = xact.update("INSERT INTO a VALUES (0)", None, None);
+                assert!(matches!(
+                    result,
+                    Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message().contains("duplicate key")
+                ));
+                xact.update("INSERT INTO a VALUES (1)", None, None).unwrap();
+                xact.commit()
+            });
+            assert_eq!(
+                Some(2),
+                c.select("SELECT COUNT(*) FROM a", None, None)
+                    .first()
+                    .get_one::<i64>()
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_error_remediation_hints() {
+        use checked::*;
+        use error::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE a (v INTEGER PRIMARY KEY)", None, None);
+            c.update("INSERT INTO a VALUES (1)", None, None);
+            let error = (&mut c)
+                .checked_update("INSERT INTO a VALUES (1)", None, None)
+                .unwrap_err();
+            let report = error.report().unwrap();
+            assert_eq!("23505", report.sqlstate().as_str());
+            assert_eq!(
+                Some("a row with the same key already exists; consider INSERT ... ON CONFLICT"),
+                report.remediation()
+            );
+            assert!(error.display_with_hints().to_string().ends_with(
+                "\nHINT: a row with the same key already exists; consider INSERT ... ON CONFLICT"
+            ));
+
+            assert_eq!(None, hints::lookup(SqlState::parse("ZZ999").unwrap()));
+
+            hints::register("22012", "don't divide by zero");
+            let error = (&c).checked_select("SELECT 1 / 0", None, None).unwrap_err();
+            assert_eq!(
+                Some("don't divide by zero"),
+                error.report().unwrap().remediation()
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_txn_memo() {
+        use checked::*;
+        use memo::*;
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            let computed = Rc::new(Cell::new(0));
+            let counter = computed.clone();
+            let memo = TxnMemo::new(move |client| {
+                counter.set(counter.get() + 1);
+                Ok(client
+                    .checked_select(
+                        "SELECT COUNT(*) FROM pg_class WHERE relname LIKE 'memo_%'",
+                        None,
+                        None,
+                    )?
+                    .first()
+                    .get_one::<i64>()
+                    .unwrap())
+            });
+            assert_eq!(0, *memo.get(&c).unwrap());
+            assert_eq!(0, *memo.get(&c).unwrap());
+            assert_eq!(1, computed.get());
+
+            c.update("CREATE TABLE memo_a ()", None, None);
+            assert_eq!(1, *memo.get(&c).unwrap());
+            assert_eq!(2, computed.get());
+            assert_eq!(1, *memo.get(&c).unwrap());
+            assert_eq!(2, computed.get());
+
+            // Rolling back a sub-transaction doesn't discard the value
+            SpiClient.sub_transaction(|xact| {
+                let xact = xact.rollback_on_drop();
+                assert_eq!(1, *memo.get(&xact).unwrap());
+            });
+            assert_eq!(1, *memo.get(&c).unwrap());
+            assert_eq!(2, computed.get());
+        });
+    }
+
+    /// Background worker memoizing a value across two transactions, reporting how many times it was computed in its
+    /// `application_name`
+    #[cfg(feature = "full")]
+    #[pg_guard]
+    #[no_mangle]
+    pub extern "C" fn spiext_memo_test_worker(_arg: pg_sys::Datum) {
+        use memo::*;
+        use pgx::bgworkers::*;
+        use std::cell::Cell;
+        use std::panic::AssertUnwindSafe;
+        use std::rc::Rc;
+
+        BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGTERM);
+        BackgroundWorker::connect_worker_to_spi(Some("pgx_tests"), None);
+        let computed = Rc::new(Cell::new(0));
+        let counter = computed.clone();
+        let memo = TxnMemo::new(move |client| {
+            counter.set(counter.get() + 1);
+            Ok(client
+                .select("SELECT 1", None, None)
+                .first()
+                .get_one::<i32>())
+        });
+        for _ in 0..2 {
+            let memo = AssertUnwindSafe(&memo);
+            BackgroundWorker::transaction(move || {
+                Spi::execute(move |c| {
+                    memo.get(&c).unwrap();
+                    memo.get(&c).unwrap();
+                })
+            });
+        }
+        let report = format!("SET application_name = 'memo computed {}'", computed.get());
+        BackgroundWorker::transaction(move || Spi::run(&report));
+        while BackgroundWorker::wait_latch(Some(std::time::Duration::from_millis(100))) {}
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_txn_memo_new_transaction() {
+        use pgx::bgworkers::*;
+        let worker = BackgroundWorkerBuilder::new("spiext memo test")
+            .set_library("tests")
+            .set_function("spiext_memo_test_worker")
+            .enable_spi_access()
+            .set_notify_pid(unsafe { pg_sys::MyProcPid })
+            .load_dynamic();
+        let pid = worker.wait_for_startup().unwrap();
+        Spi::execute(|c| {
+            let started = std::time::Instant::now();
+            let name = loop {
+                // Activity is read once per transaction unless the snapshot is cleared
+                c.select("SELECT pg_stat_clear_snapshot()", None, None);
+                let name = c
+                    .select(
+                        "SELECT application_name FROM pg_stat_activity WHERE pid = $1",
+                        None,
+                        Some(vec![(PgBuiltInOids::INT4OID.oid(), pid.into_datum())]),
+                    )
+                    .first()
+                    .get_one::<String>();
+                match name {
+                    Some(name) if name.starts_with("memo computed") => break name,
+                    _ => {
+                        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                    }
+                }
+            };
+            // Computed once in each of the two transactions
+            assert_eq!("memo computed 2", name);
+            c.select(
+                "SELECT pg_terminate_backend($1)",
+                None,
+                Some(vec![(PgBuiltInOids::INT4OID.oid(), pid.into_datum())]),
+            );
+        });
+        worker.wait_for_shutdown().unwrap();
+    }
+
+    #[pg_test]
+    fn test_guard_gucs() {
+        use guc::*;
+        use subtxn::*;
+        Spi::execute(|c| {
+            let setting = |c: &SpiClient| {
+                c.select("SELECT current_setting('work_mem')", None, None)
+                    .first()
+                    .get_one::<String>()
+                    .unwrap()
+            };
+            let before = setting(&c);
+
+            // Plain SET survives the sub-transaction's commit, but not the guard
+            let c = c.sub_transaction(|mut xact| {
+                let guard = xact.guard_gucs(&["work_mem"]).unwrap();
+                xact.update("SET work_mem = '7MB'", None, None).unwrap();
+                let xact = xact.commit();
+                assert_eq!(
+                    vec![("work_mem".to_string(), before.clone(), "7MB".to_string())],
+                    guard.all_changed()
+                );
+                drop(guard);
+                xact
+            });
+            assert_eq!(before, setting(&c));
+
+            // SET LOCAL is already reverted by the rollback
+            let c = SpiClient.sub_transaction(|mut xact| {
+                let guard = xact.guard_gucs(&["work_mem"]).unwrap();
+                xact.update("SET LOCAL work_mem = '9MB'", None, None)
+                    .unwrap();
+                let xact = xact.rollback();
+                assert!(guard.all_changed().is_empty());
+                xact
+            });
+            assert_eq!(before, setting(&c));
+
+            SpiClient.sub_transaction(|xact| {
+                assert!(matches!(
+                    xact.guard_gucs(&["work_mem", "no_such.setting"]),
+                    Err(GucError::UnknownSetting(name)) if name == "no_such.setting"
                 ));
             });
         });
     }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_checked_call() {
+        use call::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE FUNCTION add_one(v integer) RETURNS integer LANGUAGE sql AS 'SELECT v + 1'",
+                None,
+                None,
+            );
+            c.update("CREATE TYPE pair AS (a integer, b text)", None, None);
+            c.update(
+                "CREATE FUNCTION make_pair(v integer) RETURNS pair LANGUAGE sql AS 'SELECT v, v::text'",
+                None,
+                None,
+            );
+            c.update(
+                "CREATE FUNCTION split(v integer, OUT half integer, OUT rest integer) \
+                 LANGUAGE sql AS 'SELECT v / 2, v % 2'",
+                None,
+                None,
+            );
+            c.update(
+                "CREATE PROCEDURE bump(INOUT v integer) LANGUAGE plpgsql AS 'BEGIN v := v + 1; END'",
+                None,
+                None,
+            );
+            c.update(
+                "CREATE PROCEDURE try_commit() LANGUAGE plpgsql AS 'BEGIN COMMIT; END'",
+                None,
+                None,
+            );
+            let arg = |v: i32| vec![(PgBuiltInOids::INT4OID.oid(), v.into_datum())];
+
+            let result = c.checked_call("add_one", arg(1)).unwrap();
+            assert_eq!(Some(2), result.single::<i32>());
+
+            let result = c.checked_call("make_pair", arg(7)).unwrap();
+            let fields = result.fields();
+            assert_eq!(
+                vec!["a", "b"],
+                fields.iter().map(|f| f.name()).collect::<Vec<_>>()
+            );
+            assert_eq!(PgBuiltInOids::TEXTOID.oid(), fields[1].type_oid());
+            assert_eq!(Some(7), fields[0].value::<i32>());
+            assert_eq!(Some("7".to_string()), fields[1].value::<String>());
+
+            let result = c.checked_call("split", arg(7)).unwrap();
+            assert_eq!(Some(3), result.field("half").unwrap().value::<i32>());
+            assert_eq!(Some(1), result.field("rest").unwrap().value::<i32>());
+
+            let result = c.checked_call_procedure("bump", arg(1)).unwrap();
+            assert_eq!(Some(2), result.single::<i32>());
+
+            assert!(matches!(
+                c.checked_call_procedure("try_commit", vec![]),
+                Err(CallError::TransactionControl(_))
+            ));
+
+            assert!(matches!(
+                c.checked_call("no_such_function", arg(1)),
+                Err(CallError::UnknownFunction { candidates, .. }) if candidates.is_empty()
+            ));
+            assert!(matches!(
+                c.checked_call("add_one", vec![]),
+                Err(CallError::UnknownFunction { candidates, .. }) if candidates == vec!["add_one(integer)"]
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_parallel_mode() {
+        use checked::*;
+        use subtxn::*;
+        Spi::execute(|c| {
+            // Same as what the leader of a parallel query (e.g. under `force_parallel_mode`) does
+            unsafe { pg_sys::EnterParallelMode() };
+            assert!(matches!(
+                SpiClient.try_sub_transaction(|_| ()),
+                Err(Error::ParallelModeActive)
+            ));
+            assert!(matches!(
+                (&c).checked_select("SELECT 1", None, None),
+                Err(Error::ParallelModeActive)
+            ));
+            let value = c
+                .checked_select_no_subtxn("SELECT 1", None)
+                .unwrap()
+                .first()
+                .get_one::<i32>();
+            unsafe { pg_sys::ExitParallelMode() };
+            assert_eq!(Some(1), value);
+            assert!(SpiClient.try_sub_transaction(|_| ()).is_ok());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_checked_select_bounded() {
+        use cursor::*;
+        Spi::execute(|c| {
+            let result =
+                c.checked_select_bounded("SELECT i FROM generate_series(1, 5000) i", None, 2500, 0);
+            assert!(matches!(
+                result,
+                Err(BoundedSelectError::RowLimitExceeded { rows: 2501, .. })
+            ));
+
+            let result = c.checked_select_bounded(
+                "SELECT repeat('x', 10000) FROM generate_series(1, 100)",
+                None,
+                0,
+                100_000,
+            );
+            assert!(matches!(
+                result,
+                Err(BoundedSelectError::ByteLimitExceeded { rows, bytes }) if rows < 100 && bytes > 100_000
+            ));
+
+            let rows = c
+                .checked_select_bounded(
+                    "SELECT i, repeat('x', i) AS s FROM generate_series(1, 2500) i",
+                    None,
+                    2500,
+                    10_000_000,
+                )
+                .unwrap();
+            assert_eq!(2500, rows.len());
+            assert_eq!("s", rows.columns()[1].name);
+            let last = rows.row(2499).unwrap();
+            assert_eq!(Some(2500), last.get::<i32>("i"));
+            assert_eq!(Some("x".repeat(2500)), last.get::<String>("s"));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_transaction_info() {
+        use subtxn::*;
+        let info = transaction_info();
+        assert_eq!(IsolationLevel::ReadCommitted, info.isolation);
+        assert!(!info.read_only);
+        assert!(!info.deferrable);
+
+        // The isolation level can't be changed once the transaction has run a query, requiring more is reported
+        let requirements = TxnRequirements {
+            min_isolation: Some(IsolationLevel::Serializable),
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(RequirementError::IsolationTooLow {
+                required: IsolationLevel::Serializable,
+                actual: IsolationLevel::ReadCommitted
+            }),
+            SpiClient.sub_transaction_requiring(requirements, |_| ())
+        );
+
+        Spi::execute(|mut c| {
+            let requirements = TxnRequirements {
+                read_write: true,
+                not_in_recovery: true,
+                ..Default::default()
+            };
+            assert_eq!(
+                Ok(()),
+                SpiClient.sub_transaction_requiring(requirements, |_| ())
+            );
+            c.update("SET TRANSACTION READ ONLY", None, None);
+            assert!(transaction_info().read_only);
+            assert_eq!(
+                Err(RequirementError::ReadOnly),
+                SpiClient.sub_transaction_requiring(requirements, |_| ())
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_run_checked_round_trip() {
+        let value = run_checked(|session| {
+            session.update("CREATE TABLE run_checked_a (v int)", None, None)?;
+            session.update("INSERT INTO run_checked_a VALUES (42)", None, None)?;
+            session.get_one::<i32>("SELECT v FROM run_checked_a", None)
+        });
+        assert_eq!(value.unwrap(), Some(42));
+        assert_eq!(run(|_| 1), 1);
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_run_checked_error_rolls_back() {
+        let result = run_checked(|session| {
+            session.update("CREATE TABLE run_checked_b (v int)", None, None)?;
+            session.update("INSERT INTO run_checked_b VALUES ('x')", None, None)?;
+            Ok(())
+        });
+        assert!(matches!(
+            result,
+            Err(Error::Caught(CaughtError::PostgresError(_), _))
+        ));
+        let exists = Spi::get_one::<bool>("SELECT to_regclass('run_checked_b') IS NOT NULL");
+        assert_eq!(exists, Some(false));
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_run_checked_nested_sub_transaction() {
+        let count = run_checked(|session| {
+            session.update("CREATE TABLE run_checked_c (v int)", None, None)?;
+            let nested = session.sub_transaction(|session| {
+                session.update("INSERT INTO run_checked_c VALUES (1)", None, None)?;
+                Err::<(), _>(Error::EmptyQuery)
+            });
+            assert!(matches!(nested, Err(Error::EmptyQuery)));
+            session.sub_transaction(|session| {
+                session.update("INSERT INTO run_checked_c VALUES (2)", None, None)?;
+                Ok(())
+            })?;
+            session.get_one::<i64>("SELECT sum(v) FROM run_checked_c", None)
+        });
+        assert_eq!(count.unwrap(), Some(2));
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_checked_update_with_info() {
+        use info::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE info_a (v int)", None, None);
+            let (_, info) = c
+                .checked_update_with_info(
+                    "INSERT INTO info_a SELECT generate_series(1, 3)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert_eq!(3, info.rows);
+            assert!(matches!(
+                c.checked_update_with_info("INSERT INTO info_a VALUES ('x')", None, None),
+                Err(Error::Caught(CaughtError::PostgresError(_), _))
+            ));
+        });
+    }
+
+    #[cfg(any(feature = "pg14", feature = "pg15"))]
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_checked_select_with_info_query_id() {
+        use info::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE EXTENSION IF NOT EXISTS pg_stat_statements",
+                None,
+                None,
+            );
+            let (_, info) = c
+                .checked_select_with_info("SELECT 1 AS info_probe", None, None)
+                .unwrap();
+            assert_eq!(1, info.rows);
+            let query_id = info.query_id.expect("query id is computed");
+            let query = c
+                .select(
+                    "SELECT query FROM pg_stat_statements WHERE queryid = $1",
+                    None,
+                    Some(vec![(
+                        PgBuiltInOids::INT8OID.oid(),
+                        (query_id as i64).into_datum(),
+                    )]),
+                )
+                .first()
+                .get_one::<String>();
+            assert_eq!(Some("SELECT $1 AS info_probe"), query.as_deref());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_partitions() {
+        use partitions::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE part_parent (v int) PARTITION BY RANGE (v)",
+                None,
+                None,
+            );
+            let range = |from: &str, to: &str| PartitionBounds::Range {
+                from: vec![from.to_string()],
+                to: vec![to.to_string()],
+            };
+            checked_create_partition(&mut c, "part_parent", "part_a", &range("0", "10")).unwrap();
+            checked_create_partition(&mut c, "part_parent", "part_b", &range("10", "20")).unwrap();
+            assert!(matches!(
+                checked_create_partition(&mut c, "part_parent", "part_c", &range("5", "15")),
+                Err(PartitionError::OverlappingBounds(_))
+            ));
+
+            c.update("CREATE TABLE part_plain (v int)", None, None);
+            assert!(matches!(
+                checked_attach(&mut c, "part_plain", "part_a", &range("0", "10")),
+                Err(PartitionError::NotPartitionedTable(_))
+            ));
+
+            let partitions = list_partitions(&c, "part_parent").unwrap();
+            assert_eq!(
+                vec![
+                    PartitionInfo {
+                        name: "part_a".to_string(),
+                        bounds: "FOR VALUES FROM (0) TO (10)".to_string(),
+                        partitioned: false,
+                    },
+                    PartitionInfo {
+                        name: "part_b".to_string(),
+                        bounds: "FOR VALUES FROM (10) TO (20)".to_string(),
+                        partitioned: false,
+                    },
+                ],
+                partitions
+            );
+
+            #[cfg(any(feature = "pg14", feature = "pg15"))]
+            assert!(matches!(
+                checked_detach(&mut c, "part_parent", "part_b", true),
+                Err(PartitionError::ConcurrentlyInTransaction(_))
+            ));
+            checked_detach(&mut c, "part_parent", "part_b", false).unwrap();
+            let partitions = list_partitions(&c, "part_parent").unwrap();
+            assert_eq!(1, partitions.len());
+            assert_eq!("part_a", partitions[0].name);
+
+            checked_attach(&mut c, "part_parent", "part_b", &range("10", "20")).unwrap();
+            assert_eq!(2, list_partitions(&c, "part_parent").unwrap().len());
+        });
+    }
+
+    #[pg_extern]
+    fn spiext_recurse(n: i32) -> i32 {
+        subtxn::recursion_guard("spiext_recurse", 5, || {
+            Spi::get_one::<i32>(&format!("SELECT tests.spiext_recurse({})", n + 1)).unwrap()
+        })
+        .unwrap_or_else(|limit| {
+            assert_eq!("spiext_recurse", limit.key);
+            limit.depth as i32
+        })
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_recursion_guard() {
+        assert_eq!(
+            Some(5),
+            Spi::get_one::<i32>("SELECT tests.spiext_recurse(0)")
+        );
+        // The depth is back to zero once the recursion unwinds
+        assert_eq!(
+            Some(5),
+            Spi::get_one::<i32>("SELECT tests.spiext_recurse(0)")
+        );
+
+        let result = subtxn::recursion_guard("test_recursion_guard", 1, || {
+            subtxn::recursion_guard("test_recursion_guard", 1, || ())
+        });
+        assert_eq!(
+            Ok(Err(subtxn::RecursionLimit {
+                key: "test_recursion_guard",
+                depth: 1
+            })),
+            result
+        );
+
+        // The depth is decreased when the guarded code unwinds, too
+        let result = run_checked(|_| {
+            subtxn::recursion_guard("test_recursion_guard", 1, || {
+                SpiClient.select("SELECT 1 / 0", None, None);
+            })
+            .unwrap();
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            Ok(()),
+            subtxn::recursion_guard("test_recursion_guard", 1, || ())
+        );
+    }
+
+    #[pg_test]
+    fn test_owned_postgres_error() {
+        use pgx_contrib_spiext::checked::*;
+        use pgx_contrib_spiext::error::*;
+        fn assert_send<T: Send + Clone + std::error::Error>() {}
+        assert_send::<OwnedPostgresError>();
+
+        fn fails(c: &SpiClient) -> Result<(), OwnedPostgresError> {
+            c.checked_select("SELECT 1 / 0", None, None)?;
+            Ok(())
+        }
+        Spi::execute(|mut c| {
+            // The error outlives the sub-transaction it was raised in
+            let error = fails(&c).unwrap_err();
+            c.update("CREATE TABLE owned_error (v int)", None, None);
+            assert_eq!("22012", error.sqlstate.as_str());
+            assert_eq!("division by zero", error.message);
+            assert_eq!("division by zero", error.clone().to_string());
+
+            let error = OwnedPostgresError::from(c.checked_select("", None, None).unwrap_err());
+            assert_eq!("42601", error.sqlstate.as_str());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_plan_asserts() {
+        use pgx_contrib_spiext::assert_plan;
+        use plan_asserts::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE plan_a (id int, v text)", None, None);
+            c.update("CREATE INDEX plan_a_id ON plan_a (id)", None, None);
+            c.update("SET LOCAL enable_seqscan = off", None, None);
+            let query = "SELECT v FROM plan_a WHERE id = $1";
+            let args = || Some(vec![(PgBuiltInOids::INT4OID.oid(), 1.into_datum())]);
+            assert_uses_index(&c, query, args(), "plan_a_id").unwrap();
+            assert_uses_index(&c, query, args(), "public.plan_a_id").unwrap();
+            assert_no_seqscan(&c, query, args(), "plan_a").unwrap();
+            assert_plan!(&c, query, args(), uses_index = "plan_a_id");
+
+            c.update("DROP INDEX plan_a_id", None, None);
+            match assert_uses_index(&c, query, args(), "plan_a_id") {
+                Err(PlanAssertError::IndexNotUsed { index, plan }) => {
+                    assert_eq!("plan_a_id", index);
+                    assert!(plan.text.contains("Seq Scan"));
+                    assert!(plan.total_cost > 0.0);
+                }
+                result => panic!("unexpected result: {:?}", result),
+            }
+            assert!(matches!(
+                assert_no_seqscan(&c, query, args(), "plan_a"),
+                Err(PlanAssertError::SeqScan { .. })
+            ));
+            assert!(matches!(
+                assert_no_seqscan(&c, "SELECT FROM plan_missing", None, "plan_missing"),
+                Err(PlanAssertError::Query(_))
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_command_counter() {
+        use checked::NoCciCommands;
+        use subtxn::*;
+        let count_no_cci = || {
+            SpiClient
+                .checked_select_no_cci("SELECT count(*) FROM cci_a", None, None)
+                .unwrap()
+                .row(0)
+                .unwrap()
+                .get_by_ordinal::<i64>(1)
+        };
+        let count = || Spi::get_one::<i64>("SELECT count(*) FROM cci_a");
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE cci_a (v int)", None, None);
+            c.sub_transaction(|mut xact| {
+                let command_id = current_command_id();
+                xact.update("INSERT INTO cci_a VALUES (1)", None, None)
+                    .unwrap();
+                xact.advance_command_counter();
+                assert!(current_command_id() > command_id);
+                assert_eq!(Some(1), count());
+                // The row was inserted after the snapshot of the test function was taken
+                assert_eq!(Some(0), count_no_cci());
+
+                // Nested sub-transactions see the same
+                xact.sub_transaction(|mut nested| {
+                    nested
+                        .update("INSERT INTO cci_a VALUES (2)", None, None)
+                        .unwrap();
+                    assert_eq!(Some(2), count());
+                    assert_eq!(Some(0), count_no_cci());
+                    nested.commit()
+                })
+                .commit();
+            });
+            assert_eq!(Some(2), count());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_remote_session() {
+        use remote::*;
+        let connections = || Spi::get_one::<Vec<String>>("SELECT dblink_get_connections()");
+        Spi::execute(|mut c| {
+            c.update("CREATE EXTENSION IF NOT EXISTS dblink", None, None);
+            let connstr = Spi::get_one::<String>(
+                "SELECT format('host=localhost port=%s dbname=%s', current_setting('port'), current_database())",
+            )
+            .unwrap();
+            let session = RemoteSession::connect(&mut c, &connstr).unwrap();
+            assert_eq!(Some(vec![session.name().to_string()]), connections());
+
+            let table = session
+                .checked_remote_select("SELECT 1, 'one'", "id int, name text")
+                .unwrap();
+            assert_eq!(
+                (Some(1), Some("one".to_string())),
+                table.first().get_two::<i32, String>()
+            );
+            assert_eq!(
+                "SET",
+                session
+                    .checked_remote_exec("SET search_path = public")
+                    .unwrap()
+            );
+
+            let err = session.checked_remote_exec("SLECT 1").unwrap_err();
+            assert_eq!(
+                Some("42601"),
+                err.remote_sqlstate().as_ref().map(|s| s.as_str())
+            );
+            assert!(err.remote_message().unwrap().contains("syntax error"));
+
+            drop(session);
+            assert_eq!(None, connections());
+        });
+    }
+
+    #[pg_test]
+    fn test_error_provenance() {
+        use pgx_contrib_spiext::error::was_handled;
+        use subtxn::state_is_clean;
+
+        Spi::execute(|mut c| {
+            let err = (&mut c)
+                .checked_update("SELECT 1 / 0", None, None)
+                .unwrap_err();
+            assert!(was_handled(&err));
+            assert!(state_is_clean());
+
+            c.sub_transaction(|xact| {
+                assert!(state_is_clean());
+                let err = PgTryBuilder::new(|| {
+                    unsafe { pg_sys::BeginInternalSubTransaction(std::ptr::null()) };
+                    pgx::error!("raw error")
+                })
+                .catch_others(|e| e)
+                .execute();
+                assert!(!was_handled(&Error::from(err)));
+                // The sub-transaction started directly is still open
+                assert!(!state_is_clean());
+                unsafe { pg_sys::RollbackAndReleaseCurrentSubTransaction() };
+                assert!(state_is_clean());
+                xact.rollback();
+            });
+            assert!(state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_frozen_time() {
+        use subtxn::*;
+        use time::*;
+        let at = Spi::get_one::<TimestampWithTimeZone>(
+            "SELECT '2000-01-01 12:00:00.123456+00'::timestamptz",
+        )
+        .unwrap();
+        Spi::execute(|c| {
+            (&c).sub_transaction(|xact| {
+                let frozen = xact.freeze_time(at).unwrap();
+                assert_eq!(at, txn_now(&xact).unwrap());
+                assert_eq!(at, clock_now(&xact).unwrap());
+                // Nested sub-transactions inherit the freeze
+                let xact = xact.sub_transaction(|nested| {
+                    assert_eq!(at, txn_now(&nested).unwrap());
+                    nested.commit()
+                });
+                drop(frozen);
+                assert_ne!(at, txn_now(&xact).unwrap());
+                xact.rollback();
+            });
+            assert_ne!(at, txn_now(&c).unwrap());
+            assert_ne!(at, clock_now(&c).unwrap());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_upsert() {
+        use error::PostgresErrorExt;
+        use upsert::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE upsert_a (id int PRIMARY KEY, v text)",
+                None,
+                None,
+            );
+            c.update(
+                "INSERT INTO upsert_a VALUES (1, 'one'), (2, 'two')",
+                None,
+                None,
+            );
+            let row = |id: i32, v: &str| {
+                vec![
+                    (PgBuiltInOids::INT4OID.oid(), id.into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), v.into_datum()),
+                ]
+            };
+            let stats = checked_upsert(
+                &mut c,
+                "upsert_a",
+                &["id", "v"],
+                &["id"],
+                vec![row(2, "TWO"), row(3, "three"), row(4, "four")],
+                &["v"],
+            )
+            .unwrap();
+            assert_eq!(
+                UpsertStats {
+                    inserted: 2,
+                    updated: 1,
+                    batches: 1,
+                },
+                stats
+            );
+            assert_eq!(
+                Some("TWO".to_string()),
+                c.select("SELECT v FROM upsert_a WHERE id = 2", None, None)
+                    .first()
+                    .get_one::<String>()
+            );
+
+            // Two parameters per row, so 32767 rows fit in a batch
+            let rows = (1..=40000).map(|id| row(id, "many")).collect();
+            let stats =
+                checked_upsert(&mut c, "upsert_a", &["id", "v"], &["id"], rows, &["v"]).unwrap();
+            assert_eq!(
+                UpsertStats {
+                    inserted: 39996,
+                    updated: 4,
+                    batches: 2,
+                },
+                stats
+            );
+
+            let err = checked_upsert(
+                &mut c,
+                "upsert_a",
+                &["id", "v"],
+                &["v"],
+                vec![row(1, "one"), row(50000, "new")],
+                &["v"],
+            )
+            .unwrap_err();
+            assert_eq!("42P10", err.report().unwrap().sqlstate().as_str());
+            // Nothing was inserted
+            assert_eq!(
+                Some(40000),
+                c.select("SELECT count(*) FROM upsert_a", None, None)
+                    .first()
+                    .get_one::<i64>()
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_debug_dump() {
+        use subtxn::*;
+        assert_eq!("", debug_dump());
+        SpiClient.sub_transaction(|xact| {
+            let xact = xact.rollback_on_drop();
+            let xact = xact.sub_transaction(|nested| {
+                let info = debug_info();
+                assert_eq!(2, info.len());
+                assert!(info[0].current && info[0].active && info[0].commit_on_drop);
+                assert!(!info[1].current && info[1].active && !info[1].commit_on_drop);
+                assert_eq!(info[1].depth + 1, info[0].depth);
+                let dump = Spi::get_one::<String>("SELECT spiext_debug_dump()").unwrap();
+                let lines: Vec<_> = dump.lines().collect();
+                assert_eq!(2, lines.len());
+                assert!(lines[0].contains(&format!("subxid={} ", info[0].id)));
+                assert!(lines[0].contains("on_drop=commit"));
+                assert!(lines[1].contains(&format!("depth={} ", info[1].depth)));
+                assert!(lines[1].contains("on_drop=rollback"));
+                nested.commit()
+            });
+            assert_eq!(1, debug_info().len());
+            drop(xact);
+        });
+        assert_eq!("", debug_dump());
+        assert!(debug_info().is_empty());
+    }
+
+    #[pg_test]
+    fn test_spi_error_codes() {
+        use checked::*;
+        use error::SpiErrorCode;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE spi_codes (v int)", None, None);
+            let allowed = allow_transaction_control();
+            for query in ["COMMIT", "BEGIN"] {
+                let err = (&mut c).checked_update(query, None, None).unwrap_err();
+                assert!(matches!(err, Error::Spi(SpiErrorCode::Transaction)));
+                assert_eq!(
+                    "transaction control statements are not allowed here (SPI_ERROR_TRANSACTION)",
+                    err.to_string()
+                );
+            }
+            drop(allowed);
+            assert!(matches!(
+                (&mut c).checked_update("COPY spi_codes FROM STDIN", None, None),
+                Err(Error::Spi(SpiErrorCode::Copy))
+            ));
+            assert!(subtxn::state_is_clean());
+
+            (&mut c)
+                .checked_update("INSERT INTO spi_codes VALUES (1)", None, None)
+                .unwrap();
+            assert_eq!(
+                Some(1),
+                c.select("SELECT count(*) FROM spi_codes", None, None)
+                    .first()
+                    .get_one::<i64>()
+            );
+        });
+        assert_eq!(Some(SpiErrorCode::Transaction), SpiErrorCode::from_code(-8));
+        assert_eq!(None, SpiErrorCode::from_code(1));
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_enums() {
+        use enums::*;
+        Spi::execute(|mut c| {
+            checked_create_enum(&mut c, "mood", &["sad", "it's fine", "happy"]).unwrap();
+            checked_add_enum_value(&mut c, "mood", "ok", EnumPosition::Before("happy")).unwrap();
+            checked_add_enum_value(&mut c, "mood", "ecstatic", EnumPosition::Last).unwrap();
+            assert_eq!(
+                vec!["sad", "it's fine", "ok", "happy", "ecstatic"],
+                enum_labels(&c, "mood").unwrap()
+            );
+            assert!(matches!(
+                checked_add_enum_value(&mut c, "mood", "sad", EnumPosition::After("happy")),
+                Err(EnumError::DuplicateLabel(_))
+            ));
+            assert!(matches!(
+                checked_create_enum(&mut c, "mood2", &["a", "a"]),
+                Err(EnumError::DuplicateLabel(_))
+            ));
+            assert!(matches!(
+                enum_labels(&c, "missing_enum"),
+                Err(Error::Caught(..))
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_budget() {
+        use budget::*;
+        use std::time::Duration;
+        use subtxn::*;
+        let count = || Spi::get_one::<i64>("SELECT count(*) FROM budget_a");
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE budget_a (v int)", None, None);
+            let budget = Budget::new(Duration::from_millis(300));
+            c.checked_update_within(&budget, "INSERT INTO budget_a VALUES (1)", None, None)
+                .unwrap();
+            let err = c
+                .checked_update_within(&budget, "SELECT pg_sleep(5)", None, None)
+                .unwrap_err();
+            assert!(matches!(err, Error::BudgetExceeded { .. }));
+            assert!(budget.elapsed() < Duration::from_secs(5));
+            assert!(matches!(
+                c.checked_update_within(&budget, "INSERT INTO budget_a VALUES (3)", None, None),
+                Err(Error::BudgetExceeded { .. })
+            ));
+        });
+        assert_eq!(Some(1), count());
+
+        // Nested sub-transactions share the budget attached to their parent
+        Spi::execute(|c| {
+            let budget = Budget::new(Duration::from_millis(300));
+            c.sub_transaction(|mut xact| {
+                xact.set_budget(budget.clone());
+                xact.update("INSERT INTO budget_a VALUES (4)", None, None)
+                    .unwrap();
+                xact.sub_transaction(|mut nested| {
+                    assert!(matches!(
+                        nested.select("SELECT pg_sleep(5)", None, None),
+                        Err(Error::BudgetExceeded { .. })
+                    ));
+                    assert!(matches!(
+                        nested.update("INSERT INTO budget_a VALUES (5)", None, None),
+                        Err(Error::BudgetExceeded { .. })
+                    ));
+                    nested.commit()
+                })
+                .commit();
+            });
+            assert!(budget.is_exhausted());
+            assert_eq!(Some(2), count());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_reconcile() {
+        use reconcile::*;
+        let objects = |view: &str| {
+            vec![
+                DesiredObject::new(
+                    ObjectKind::View,
+                    "rec_v",
+                    &format!("CREATE OR REPLACE VIEW rec_v AS {}", view),
+                )
+                .with_dependencies(&["rec_t", "rec_f(integer)"]),
+                DesiredObject::new(
+                    ObjectKind::Index,
+                    "rec_t_v",
+                    "CREATE INDEX IF NOT EXISTS rec_t_v ON rec_t (v)",
+                )
+                .with_dependencies(&["rec_t"]),
+                DesiredObject::new(
+                    ObjectKind::Function,
+                    "rec_f(integer)",
+                    "CREATE OR REPLACE FUNCTION rec_f(integer) RETURNS integer LANGUAGE sql AS 'SELECT $1 + 1'",
+                ),
+                DesiredObject::new(
+                    ObjectKind::Table,
+                    "rec_t",
+                    "CREATE TABLE IF NOT EXISTS rec_t (v integer)",
+                ),
+            ]
+        };
+        Spi::execute(|mut c| {
+            let report = apply(&mut c, objects("SELECT rec_f(v) AS w FROM rec_t")).unwrap();
+            assert!(report.is_success());
+            let names: Vec<_> = report
+                .outcomes
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect();
+            assert_eq!(
+                vec!["rec_f(integer)", "rec_t", "rec_v", "rec_t_v"][..],
+                names[..]
+            );
+            assert!(report
+                .outcomes
+                .iter()
+                .all(|(_, outcome)| matches!(outcome, Outcome::Created)));
+
+            let report = apply(&mut c, objects("SELECT rec_f(v)   AS w FROM rec_t")).unwrap();
+            assert!(report
+                .outcomes
+                .iter()
+                .all(|(_, outcome)| matches!(outcome, Outcome::Unchanged)));
+
+            let report = apply(&mut c, objects("SELECT rec_missing(v) FROM rec_t")).unwrap();
+            assert!(matches!(report.outcome("rec_v"), Some(Outcome::Failed(_))));
+            assert!(matches!(report.outcome("rec_t"), Some(Outcome::Unchanged)));
+            assert_eq!(1, report.failed().count());
+
+            let partial = DesiredObject::new(
+                ObjectKind::Index,
+                "rec_t_v",
+                "CREATE INDEX IF NOT EXISTS rec_t_v ON rec_t (v) WHERE v > 0",
+            );
+            let report = apply(&mut c, vec![partial]).unwrap();
+            assert!(!report.is_success());
+            let (name, current, desired) = report.drifted().next().unwrap();
+            assert_eq!("rec_t_v", name);
+            assert!(!current.contains("WHERE"));
+            assert!(desired.ends_with("WHERE (v > 0)"));
+            assert_eq!(
+                Some(current.to_string()),
+                Spi::get_one::<String>("SELECT pg_get_indexdef('rec_t_v'::regclass)")
+            );
+
+            let cyclic = vec![
+                DesiredObject::new(ObjectKind::View, "rec_a", "").with_dependencies(&["rec_b"]),
+                DesiredObject::new(ObjectKind::View, "rec_b", "").with_dependencies(&["rec_a"]),
+            ];
+            assert_eq!(
+                Err(ReconcileError::DependencyCycle(vec![
+                    "rec_a".to_string(),
+                    "rec_b".to_string()
+                ])),
+                apply(&mut c, cyclic).map(|_| ())
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_select_fold() {
+        use checked::*;
+        let share_locks = || {
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM pg_locks WHERE relation = 'fold_a'::regclass \
+                 AND pid = pg_backend_pid() AND mode = 'AccessShareLock'",
+            )
+        };
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE fold_a (v int)", None, None);
+            c.update(
+                "INSERT INTO fold_a SELECT generate_series(1, 100)",
+                None,
+                None,
+            );
+
+            let (sum, count) = c
+                .checked_select_fold(
+                    "SELECT v FROM fold_a WHERE v > $1",
+                    Some(vec![(PgBuiltInOids::INT4OID.oid(), 10.into_datum())]),
+                    (0i64, 0i64),
+                    |(sum, count), row| {
+                        let v = row.by_ordinal(1).unwrap().value::<i32>().unwrap();
+                        (sum + v as i64, count + 1)
+                    },
+                )
+                .unwrap();
+            // The lock taken by the query was released along with the sub-transaction
+            assert_eq!(Some(0), share_locks());
+            assert_eq!(
+                Some(0),
+                Spi::get_one::<i64>("SELECT count(*) FROM pg_cursors")
+            );
+            assert_eq!(
+                Some(sum),
+                Spi::get_one::<i64>("SELECT sum(v) FROM fold_a WHERE v > 10")
+            );
+            assert_eq!(
+                Some(count),
+                Spi::get_one::<i64>("SELECT count(*) FROM fold_a WHERE v > 10")
+            );
+
+            let result = c.checked_select_fold("SELECT v FROM fold_a", None, 0, |acc, _| {
+                if acc == 50 {
+                    panic!("folder panicked");
+                }
+                acc + 1
+            });
+            assert!(matches!(
+                result,
+                Err(Error::Caught(CaughtError::RustPanic { .. }, _))
+            ));
+        });
+    }
+
+    /// Message of the error `query` raises in a checked command as UTF-8, followed by a line listing the fields that
+    /// were converted lossily, for tests running it in a database of another encoding
+    #[pg_extern]
+    fn spiext_caught_message(query: &str) -> Vec<u8> {
+        use checked::*;
+        let mut message = vec![];
+        Spi::execute(|c| {
+            let err = c.checked_update(query, None, None).unwrap_err();
+            message = format!(
+                "{}\n{}",
+                err.message().unwrap(),
+                err.captured().unwrap().lossy_fields().join(",")
+            )
+            .into_bytes();
+        });
+        message
+    }
+
+    #[pg_test]
+    fn test_error_text_encoding() {
+        let text = |value: &str| (PgBuiltInOids::TEXTOID.oid(), value.into_datum());
+        Spi::execute(|mut c| {
+            c.update("CREATE EXTENSION IF NOT EXISTS dblink", None, None);
+            let connstr = |dbname: &str| {
+                c.select(
+                    "SELECT format('host=localhost port=%s dbname=%s', current_setting('port'), $1)",
+                    None,
+                    Some(vec![text(dbname)]),
+                )
+                .first()
+                .get_one::<String>()
+                .unwrap()
+            };
+            let (admin, latin1) = (connstr("postgres"), connstr("spiext_latin1"));
+            // Databases can't be created in the test's transaction, so they are created through another session
+            let exec = |connstr: &str, command: &str| {
+                c.select(
+                    "SELECT dblink_exec($1, $2)",
+                    None,
+                    Some(vec![text(connstr), text(command)]),
+                );
+            };
+            exec(&admin, "DROP DATABASE IF EXISTS spiext_latin1");
+            exec(
+                &admin,
+                "CREATE DATABASE spiext_latin1 ENCODING 'LATIN1' LC_COLLATE 'C' LC_CTYPE 'C' TEMPLATE template0",
+            );
+            exec(&latin1, "CREATE EXTENSION tests");
+            exec(
+                &latin1,
+                "CREATE FUNCTION accented() RETURNS void LANGUAGE plpgsql AS $$BEGIN RAISE 'café crème'; END$$",
+            );
+            let message = c
+                .select(
+                    "SELECT message FROM dblink($1, 'SELECT tests.spiext_caught_message(''SELECT accented()'')') \
+                     AS t(message bytea)",
+                    None,
+                    Some(vec![text(&latin1)]),
+                )
+                .first()
+                .get_one::<Vec<u8>>()
+                .unwrap();
+            exec(&admin, "DROP DATABASE spiext_latin1");
+            // Converted from LATIN1 rather than replaced, so nothing was lossy
+            assert_eq!("café crème\n", String::from_utf8(message).unwrap());
+        });
+    }
+
+    #[pg_test]
+    fn test_error_field_truncation() {
+        use checked::*;
+        use error::*;
+        Spi::execute(|mut c| {
+            // The literal is part of the query EXECUTE fails to parse, which is the error's internal query
+            let err = (&mut c)
+                .checked_update(
+                    "DO $$BEGIN EXECUTE 'SELECT ''' || repeat('x', 200000) || ''' +'; END$$",
+                    None,
+                    None,
+                )
+                .unwrap_err();
+            assert_eq!("42601", err.sqlstate().unwrap().as_str());
+            let query = err.captured().unwrap().internal_query().unwrap();
+            assert!(query.truncated);
+            assert_eq!(DEFAULT_FIELD_CAP, query.value.len());
+            assert!(query.value.starts_with("SELECT 'xxx"));
+            assert_eq!(Some(query.value.as_str()), err.internal_query());
+            let context = err.captured().unwrap().context().unwrap();
+            assert!(!context.truncated);
+            assert!(context.value.contains("at EXECUTE"));
+
+            set_field_cap(100);
+            let err = (&mut c)
+                .checked_update(
+                    "DO $$BEGIN EXECUTE 'SELECT ''' || repeat('é', 1000) || ''' +'; END$$",
+                    None,
+                    None,
+                )
+                .unwrap_err();
+            set_field_cap(DEFAULT_FIELD_CAP);
+            let query = err.internal_query().unwrap();
+            // Cut at a character boundary
+            assert!(query.len() <= 100 && query.len() >= 99, "{}", query.len());
+            assert!(query.ends_with('é'));
+            assert!(err.captured().unwrap().lossy_fields().is_empty());
+        });
+    }
+
+    #[pg_test]
+    fn test_custom_sqlstate() {
+        use checked::*;
+        use error::SqlState;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE FUNCTION raise_custom(code text) RETURNS int LANGUAGE plpgsql AS $$
+                 BEGIN
+                     RAISE EXCEPTION 'insufficient funds' USING ERRCODE = code, HINT = 'top up first',
+                         DETAIL = 'balance is 0';
+                 END $$",
+                None,
+                None,
+            );
+            let err = (&c)
+                .checked_select("SELECT raise_custom('P0T01')", None, None)
+                .unwrap_err();
+            let sqlstate = err.sqlstate().unwrap();
+            assert_eq!("P0T01", sqlstate.as_str());
+            assert!(sqlstate.matches("P0___"));
+            assert!(sqlstate.matches("P0T01"));
+            assert!(!sqlstate.matches("23___"));
+            assert!(!sqlstate.matches("P0"));
+            assert!(!sqlstate.is_user_defined());
+            assert_eq!(Some("top up first"), err.hint());
+            assert_eq!(Some("balance is 0"), err.detail());
+            assert_eq!(
+                "insufficient funds\nDETAIL: balance is 0\nHINT: top up first",
+                err.to_string()
+            );
+            let owned = error::OwnedPostgresError::from(err);
+            assert_eq!("P0T01", owned.sqlstate.as_str());
+            assert_eq!(Some("top up first"), owned.hint.as_deref());
+
+            let err = (&mut c)
+                .checked_update("SELECT raise_custom('ZZ001')", None, None)
+                .unwrap_err();
+            let sqlstate = err.sqlstate().unwrap();
+            assert_eq!("ZZ001", sqlstate.as_str());
+            assert!(sqlstate.is_user_defined());
+            assert!(sqlstate.matches("ZZ___"));
+            assert!(subtxn::state_is_clean());
+        });
+        assert!(!SqlState::parse("23505").unwrap().is_user_defined());
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_throttle() {
+        use std::time::{Duration, Instant};
+        use subtxn::*;
+        use throttle::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE throttled (v int)", None, None);
+            let throttle = Throttle::new(10.0, 1);
+            let start = Instant::now();
+            for _ in 0..20 {
+                c.checked_update_throttled(&throttle, "INSERT INTO throttled VALUES (1)", None)
+                    .unwrap();
+            }
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(1800), "{:?}", elapsed);
+            assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+            let stats = throttle.stats();
+            assert_eq!(20, stats.ops);
+            assert!(stats.total_wait >= Duration::from_millis(1700));
+
+            // A cancel during the wait is raised again promptly, without executing the command
+            let throttle = Throttle::new(0.1, 1);
+            c.checked_update_throttled(&throttle, "INSERT INTO throttled VALUES (2)", None)
+                .unwrap();
+            unsafe {
+                pg_sys::InterruptPending = true;
+                pg_sys::QueryCancelPending = true;
+            }
+            let start = Instant::now();
+            let raised = SpiClient.sub_transaction(|mut xact| {
+                let raised = xact.shielded(assert_shield_safe(|_| {
+                    let _ = (&mut SpiClient).checked_update_throttled(
+                        &throttle,
+                        "INSERT INTO throttled VALUES (3)",
+                        None,
+                    );
+                }));
+                xact.commit();
+                raised
+            });
+            assert!(start.elapsed() < Duration::from_secs(1));
+            assert_eq!("57014", raised.unwrap_err().sqlstate().unwrap().as_str());
+            assert_eq!(1, throttle.stats().ops);
+            assert_eq!(
+                Some(21),
+                c.select("SELECT count(*) FROM throttled", None, None)
+                    .first()
+                    .get_one::<i64>()
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_cas_update() {
+        use cas::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE cas_a (id int PRIMARY KEY, v text, version int NOT NULL)",
+                None,
+                None,
+            );
+            c.update("INSERT INTO cas_a VALUES (1, 'one', 3)", None, None);
+            let key = |id: i32| [("id", (PgBuiltInOids::INT4OID.oid(), id.into_datum()))];
+            let set = |v: &str| [("v", (PgBuiltInOids::TEXTOID.oid(), v.into_datum()))];
+            assert_eq!(
+                CasOutcome::Updated(4),
+                cas_update(&mut c, "cas_a", &key(1), "version", 3, &set("uno")).unwrap()
+            );
+            assert_eq!(
+                CasOutcome::Conflict(4),
+                cas_update(&mut c, "cas_a", &key(1), "version", 3, &set("eins")).unwrap()
+            );
+            assert_eq!(
+                CasOutcome::NotFound,
+                cas_update(&mut c, "cas_a", &key(2), "version", 3, &set("two")).unwrap()
+            );
+            assert_eq!(
+                Some("uno".to_string()),
+                c.select("SELECT v FROM cas_a WHERE id = 1", None, None)
+                    .first()
+                    .get_one::<String>()
+            );
+            assert!(cas_update(&mut c, "cas_a", &key(1), "missing", 4, &set("x")).is_err());
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_temp_index() {
+        use subtxn::*;
+        use temp_index::*;
+        let exists = |name: &str| {
+            Spi::get_one::<bool>(&format!("SELECT to_regclass('{}') IS NOT NULL", name)).unwrap()
+        };
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE temp_idx (a int, b text)", None, None);
+
+            // Committed sub-transaction: the guard drops the index
+            let index = (&c).sub_transaction(|mut xact| {
+                let index = TempIndex::create(&mut xact, "temp_idx", "(a, b)").unwrap();
+                assert!(exists(&index.qualified_name()));
+                xact.commit();
+                index
+            });
+            let name = index.qualified_name();
+            assert!(exists(&name));
+            drop(index);
+            assert!(!exists(&name));
+
+            // Rolled back sub-transaction: the index is already gone
+            let index = (&c).sub_transaction(|mut xact| {
+                let index = TempIndex::create(&mut xact, "temp_idx", "USING hash (a)").unwrap();
+                assert!(exists(&index.qualified_name()));
+                xact.rollback();
+                index
+            });
+            let name = index.qualified_name();
+            assert!(!exists(&name));
+            index.drop_index().unwrap();
+            assert!(subtxn::state_is_clean());
+
+            (&c).sub_transaction(|mut xact| {
+                assert!(matches!(
+                    TempIndex::create(&mut xact, "temp_idx", "CONCURRENTLY (a)"),
+                    Err(TempIndexError::Concurrently)
+                ));
+                assert!(matches!(
+                    TempIndex::create(&mut xact, "temp_idx_missing", "(a)"),
+                    Err(TempIndexError::Query(_))
+                ));
+                xact.commit();
+            });
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_hash_join() {
+        use join::*;
+        use owned::OwnedRows;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE join_l (id int, name text); \
+                 INSERT INTO join_l VALUES (1, 'a'), (2, 'b'), (2, 'b2'), (3, 'c'), (NULL, 'n'); \
+                 CREATE TABLE join_r (lid bigint, v text, amount numeric); \
+                 INSERT INTO join_r VALUES (2, 'x', 1), (2, 'y', 2), (3, 'z', 3), (4, 'w', 4), (NULL, 'm', 5)",
+                None,
+                None,
+            );
+            let rows = |query: &str| OwnedRows::from_table(c.select(query, None, None));
+            let sql = |query: &str| {
+                let mut rows: Vec<_> = c
+                    .select(query, None, None)
+                    .map(|row| {
+                        (
+                            row.by_ordinal(1).unwrap().value::<String>(),
+                            row.by_ordinal(2).unwrap().value::<String>(),
+                        )
+                    })
+                    .collect();
+                rows.sort();
+                rows
+            };
+            let rust = |joined: Vec<JoinedRow>| {
+                let mut rows: Vec<_> = joined
+                    .iter()
+                    .map(|row| {
+                        (
+                            row.get::<String>(Side::Left, "name"),
+                            row.get::<String>(Side::Right, "v"),
+                        )
+                    })
+                    .collect();
+                rows.sort();
+                rows
+            };
+            for (kind, sql_join) in [
+                (JoinKind::Inner, "JOIN"),
+                (JoinKind::LeftOuter, "LEFT JOIN"),
+            ] {
+                let joined = hash_join(
+                    rows("SELECT * FROM join_l"),
+                    rows("SELECT * FROM join_r"),
+                    &["id"],
+                    &["lid"],
+                    kind,
+                )
+                .unwrap();
+                let expected = sql(&format!(
+                    "SELECT name, v FROM join_l {} join_r ON id = lid",
+                    sql_join
+                ));
+                assert_eq!(expected, rust(joined));
+            }
+            // The larger side is the left one here, so the hash table is built from the right one
+            let joined = hash_join(
+                rows("SELECT * FROM join_l"),
+                rows("SELECT * FROM join_r WHERE lid = 2"),
+                &["id"],
+                &["lid"],
+                JoinKind::LeftOuter,
+            )
+            .unwrap();
+            assert_eq!(
+                sql("SELECT name, v FROM join_l LEFT JOIN join_r ON id = lid AND lid = 2"),
+                rust(joined)
+            );
+
+            let err = hash_join(
+                rows("SELECT * FROM join_r"),
+                rows("SELECT * FROM join_r"),
+                &["amount"],
+                &["amount"],
+                JoinKind::Inner,
+            )
+            .err()
+            .unwrap();
+            assert_eq!(
+                JoinError::UnsupportedKeyType {
+                    side: Side::Left,
+                    column: "amount".to_string(),
+                    type_oid: PgBuiltInOids::NUMERICOID.oid(),
+                },
+                err
+            );
+            assert!(matches!(
+                hash_join(
+                    rows("SELECT * FROM join_l"),
+                    rows("SELECT * FROM join_r"),
+                    &["name"],
+                    &["lid"],
+                    JoinKind::Inner,
+                ),
+                Err(JoinError::IncompatibleKeyTypes { position: 0, .. })
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_drop_policy() {
+        use checked::*;
+        use subtxn::*;
+        let count = || Spi::get_one::<i64>("SELECT count(*) FROM drop_policy_a");
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE drop_policy_a (v int)", None, None);
+
+            let insert = || {
+                SpiClient.sub_transaction(|mut xact| {
+                    xact.update("INSERT INTO drop_policy_a VALUES (1)", None, None)
+                        .unwrap();
+                })
+            };
+
+            assert_eq!(DropPolicy::Silent, drop_policy());
+            insert();
+            assert_eq!(Some(1), count());
+            {
+                let _policy = set_drop_policy(DropPolicy::Warn);
+                insert();
+                assert_eq!(Some(2), count());
+                {
+                    let _policy = set_drop_policy(DropPolicy::Error);
+                    insert();
+                    assert_eq!(Some(2), count());
+                    // Explicit commits, and sub-transactions rolling back on drop, are not affected
+                    SpiClient.sub_transaction(|mut xact| {
+                        xact.update("INSERT INTO drop_policy_a VALUES (1)", None, None)
+                            .unwrap();
+                        xact.commit();
+                    });
+                    assert_eq!(Some(3), count());
+                    SpiClient.sub_transaction(|xact| {
+                        let mut xact = xact.rollback_on_drop();
+                        xact.update("INSERT INTO drop_policy_a VALUES (1)", None, None)
+                            .unwrap();
+                    });
+                    assert_eq!(Some(3), count());
+                    // Neither are checked commands
+                    (&mut SpiClient)
+                        .checked_update("INSERT INTO drop_policy_a VALUES (1)", None, None)
+                        .unwrap();
+                    assert_eq!(Some(4), count());
+                }
+                assert_eq!(DropPolicy::Warn, drop_policy());
+            }
+            assert_eq!(DropPolicy::Silent, drop_policy());
+            insert();
+            assert_eq!(Some(5), count());
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[pg_test]
+    fn test_held_locks() {
+        use locks::*;
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE held_a (v int); INSERT INTO held_a VALUES (1)",
+                None,
+                None,
+            );
+            let row_share = |locks: &[HeldLock]| {
+                locks.iter().any(|lock| {
+                    lock.relation.as_deref() == Some("held_a") && lock.mode == LockMode::RowShare
+                })
+            };
+            (&c).sub_transaction(|mut xact| {
+                assert_eq!(None, xact.xid_if_assigned());
+                assert!(!row_share(&xact.held_locks().unwrap()));
+                xact.update_unchecked("SELECT * FROM held_a FOR UPDATE", None, None);
+                let xid = xact.xid_if_assigned().unwrap();
+                let locks = xact.held_locks().unwrap();
+                assert!(row_share(&locks));
+                assert!(locks.iter().any(|lock| {
+                    lock.transaction_id == Some(xid)
+                        && lock.of_sub_transaction
+                        && lock.mode == LockMode::Exclusive
+                }));
+                xact.rollback();
+            });
+            (&c).sub_transaction(|xact| {
+                let locks = xact.held_locks().unwrap();
+                assert!(!row_share(&locks));
+                assert!(!locks.iter().any(|lock| lock.of_sub_transaction));
+                xact.commit();
+            });
+            assert_eq!(Vec::<WaitEdge>::new(), wait_graph(&c).unwrap());
+            assert!(!LockMode::RowShare.is_self_exclusive());
+            assert_eq!(
+                LockMode::Other("SIReadLock".to_string()),
+                "SIReadLock".parse().unwrap()
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_comments() {
+        use ddl::*;
+        use validate::{IdentError, PreflightError};
+        Spi::execute(|mut c| {
+            c.update("CREATE SCHEMA commented", None, None);
+            CheckedCreate::table("commented.t", "id int PRIMARY KEY, v text")
+                .with_comment("it's a table\nwith \\ two lines")
+                .with_column_comment("v", "the \"value\"")
+                .execute(&mut c)
+                .unwrap();
+            assert_eq!(
+                Some("it's a table\nwith \\ two lines".to_string()),
+                get_comment(&c, ObjectKind::Table, "commented.t").unwrap()
+            );
+            assert_eq!(
+                Some("the \"value\"".to_string()),
+                get_comment(&c, ObjectKind::Column, "commented.t.v").unwrap()
+            );
+            assert_eq!(
+                None,
+                get_comment(&c, ObjectKind::Column, "commented.t.id").unwrap()
+            );
+
+            assert!(matches!(
+                checked_comment_on(&mut c, ObjectKind::Column, "id", "key"),
+                Err(Error::Preflight(PreflightError::Identifier(
+                    IdentError::UnqualifiedColumn { .. }
+                )))
+            ));
+            checked_comment_on(&mut c, ObjectKind::Column, "commented.t.id", "key").unwrap();
+            assert_eq!(
+                Some("key".to_string()),
+                get_comment(&c, ObjectKind::Column, "commented.t.id").unwrap()
+            );
+            c.update(
+                "CREATE FUNCTION commented.f(a int, b text) RETURNS int LANGUAGE sql AS 'SELECT a'",
+                None,
+                None,
+            );
+            checked_comment_on(
+                &mut c,
+                ObjectKind::Function,
+                "commented.f(int, text)",
+                "'f'",
+            )
+            .unwrap();
+            assert_eq!(
+                Some("'f'".to_string()),
+                get_comment(&c, ObjectKind::Function, "commented.f(integer, text)").unwrap()
+            );
+
+            CheckedCreate::index("t_v", "commented.t", "(v)")
+                .with_comment("index")
+                .execute(&mut c)
+                .unwrap();
+            assert_eq!(
+                Some("index".to_string()),
+                get_comment(&c, ObjectKind::Index, "commented.t_v").unwrap()
+            );
+
+            // Neither the table nor any of its comments are created if one of them fails
+            assert!(CheckedCreate::table("commented.u", "id int")
+                .with_comment("created")
+                .with_column_comment("missing", "fails")
+                .execute(&mut c)
+                .is_err());
+            assert_eq!(
+                None,
+                c.select("SELECT to_regclass('commented.u')::oid", None, None)
+                    .first()
+                    .get_one::<pg_sys::Oid>()
+            );
+            assert!(CheckedCreate::table("commented.w", "id int")
+                .with_security_label("no_such_provider", "secret")
+                .execute(&mut c)
+                .is_err());
+            assert_eq!(
+                None,
+                c.select("SELECT to_regclass('commented.w')::oid", None, None)
+                    .first()
+                    .get_one::<pg_sys::Oid>()
+            );
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_row_stream() {
+        use stream::*;
+        let allocated =
+            || unsafe { pg_sys::MemoryContextMemAllocated(pg_sys::TopMemoryContext, true) };
+        Spi::execute(|c| {
+            let query = "SELECT i, md5(i::text) AS h FROM generate_series(1, 500000) i";
+            let before = allocated();
+            let mut peak = before;
+            let mut stream = c.checked_select_stream(query, None, 1000).unwrap();
+            let (mut sum, mut length, mut rows) = (0i64, 0i64, 0u64);
+            while let Some(row) = stream.next_row().unwrap() {
+                rows += 1;
+                sum += row.by_ordinal(1).unwrap().value::<i32>().unwrap() as i64;
+                length += row.by_name("h").unwrap().value::<String>().unwrap().len() as i64;
+                if rows % 1000 == 0 {
+                    peak = peak.max(allocated());
+                }
+            }
+            assert_eq!(500000, stream.rows());
+            assert!(stream.next_row().unwrap().is_none());
+            drop(stream);
+            assert_eq!(
+                Some(sum),
+                c.select(
+                    "SELECT sum(i)::bigint FROM generate_series(1, 500000) i",
+                    None,
+                    None
+                )
+                .first()
+                .get_one::<i64>()
+            );
+            assert_eq!(500000 * 32, length);
+            // The whole result takes tens of megabytes, a batch well under one
+            assert!(peak - before < 8 * 1024 * 1024, "{}", peak - before);
+            assert!(subtxn::state_is_clean());
+
+            let result = c
+                .checked_select_stream("SELECT 1/0", None, 10)
+                .and_then(|mut stream| {
+                    let row = stream.next_row()?.is_some();
+                    Ok(row)
+                });
+            assert!(matches!(result, Err(Error::Caught(..))));
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    // Built with and without the `full` feature (`cargo pgx test --no-default-features --features pg13`), this spells
+    // out the types of the core API so that any difference between the two fails to compile
+    #[pg_test]
+    fn test_minimal_api() {
+        use checked::*;
+        use pgx::SpiTupleTable;
+        use subtxn::*;
+        Spi::execute(|c| {
+            let xact: SubTransaction<SpiClientWrapper, true> = c.sub_transaction(|xact| xact);
+            let id: pg_sys::SubTransactionId = xact.id();
+            assert_ne!(0, id);
+            let xact: SubTransaction<SpiClientWrapper, false> = xact.rollback_on_drop();
+            let result: Result<(SpiTupleTable, SubTransaction<SpiClientWrapper, false>), Error> =
+                xact.checked_update("SELECT 1", None, None);
+            let (_, xact) = result.unwrap();
+            let result: Result<(SpiTupleTable, SubTransaction<SpiClientWrapper, false>), Error> =
+                xact.checked_select("SELECT 1", None, None);
+            let (table, mut xact) = result.unwrap();
+            assert_eq!(Some(1), table.first().get_one::<i32>());
+            let result: Result<SpiTupleTable, Error> = xact.update("SELECT 1", None, None);
+            assert!(result.is_ok());
+            let result: Result<SpiTupleTable, Error> =
+                (&SpiClient).checked_select("SELECT 1", None, None);
+            assert!(result.is_ok());
+            let _: SpiClientWrapper = xact.commit();
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_rls() {
+        use rls::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE ROLE rls_owner", None, None);
+            c.update("CREATE ROLE rls_reader", None, None);
+            c.update(
+                "CREATE TABLE rls_a (id int, reader text); \
+                 INSERT INTO rls_a VALUES (1, 'rls_reader'), (2, 'someone else'); \
+                 ALTER TABLE rls_a OWNER TO rls_owner; \
+                 ALTER TABLE rls_a ENABLE ROW LEVEL SECURITY; \
+                 CREATE POLICY own_rows ON rls_a FOR SELECT TO rls_reader USING (reader = current_user); \
+                 CREATE POLICY owner_rows ON rls_a TO rls_owner USING (true); \
+                 GRANT SELECT ON rls_a TO rls_reader",
+                None,
+                None,
+            );
+            let ids = |mode| {
+                SpiClient
+                    .checked_select_rls("SELECT id FROM rls_a ORDER BY id", None, mode)
+                    .map(|table| {
+                        table
+                            .map(|row| row.by_ordinal(1).unwrap().value::<i32>().unwrap())
+                            .collect::<Vec<_>>()
+                    })
+            };
+
+            // The superuser running the tests bypasses policies anyway
+            assert!(matches!(
+                ids(RlsMode::Force),
+                Err(RlsError::BypassNotPossible)
+            ));
+            assert_eq!(vec![1, 2], ids(RlsMode::Bypass).unwrap());
+
+            c.update("SET LOCAL ROLE rls_reader", None, None);
+            assert_eq!(vec![1], ids(RlsMode::Force).unwrap());
+            assert!(matches!(
+                ids(RlsMode::Bypass),
+                Err(RlsError::InsufficientPrivilege(_))
+            ));
+            // `row_security` is back to what it was
+            assert_eq!(
+                Some("on".to_string()),
+                Spi::get_one::<String>("SELECT current_setting('row_security')")
+            );
+            let policies = policies_applied(&SpiClient, "rls_a").unwrap();
+            assert_eq!(1, policies.len());
+            assert_eq!("own_rows", policies[0].name);
+            assert!(policies[0].permissive);
+            assert_eq!("SELECT", policies[0].command);
+            assert_eq!(vec!["rls_reader".to_string()], policies[0].roles);
+            assert_eq!(None, policies[0].with_check);
+
+            c.update("SET LOCAL ROLE rls_owner", None, None);
+            assert_eq!(vec![1, 2], ids(RlsMode::Bypass).unwrap());
+            assert_eq!(vec![1, 2], ids(RlsMode::Force).unwrap());
+            let policies = policies_applied(&SpiClient, "rls_a").unwrap();
+            assert_eq!(
+                vec!["owner_rows"],
+                policies.iter().map(|p| p.name.as_str()).collect::<Vec<_>>()
+            );
+            c.update("RESET ROLE", None, None);
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_resource_limits() {
+        use limits::*;
+        let settings = || {
+            Spi::get_one::<String>(
+                "SELECT concat_ws(' ', current_setting('work_mem'), current_setting('temp_file_limit'), \
+                 current_setting('max_parallel_workers_per_gather'))",
+            )
+        };
+        let before = settings();
+        let query =
+            "SELECT count(*) FROM (SELECT i FROM generate_series(1, 1000000) i ORDER BY i DESC) s";
+
+        Spi::execute(|c| {
+            // A sort spilling to disk outgrows a tiny temp_file_limit
+            let limits = ResourceLimits {
+                work_mem: Some("64kB"),
+                temp_file_limit: Some("64kB"),
+                max_parallel_workers_per_gather: Some(0),
+            };
+            let err = c.checked_select_limited(query, None, limits).unwrap_err();
+            assert!(
+                matches!(err, LimitsError::TempFileLimitExceeded(_)),
+                "{}",
+                err
+            );
+            assert_eq!(before, settings());
+
+            // Generous limits don't get in the way, and don't leak either
+            let limits = ResourceLimits {
+                work_mem: Some("256 MB"),
+                temp_file_limit: Some("1GB"),
+                max_parallel_workers_per_gather: None,
+            };
+            let count = c
+                .checked_select_limited(query, None, limits)
+                .unwrap()
+                .first()
+                .get_one::<i64>();
+            assert_eq!(Some(1000000), count);
+            assert_eq!(before, settings());
+
+            // Invalid values are rejected before executing anything
+            for limits in [
+                ResourceLimits {
+                    work_mem: Some("-1MB"),
+                    ..Default::default()
+                },
+                ResourceLimits {
+                    temp_file_limit: Some("1 parsec"),
+                    ..Default::default()
+                },
+                ResourceLimits {
+                    max_parallel_workers_per_gather: Some(-2),
+                    ..Default::default()
+                },
+            ] {
+                assert!(matches!(
+                    c.checked_select_limited("SELECT 1/0", None, limits),
+                    Err(LimitsError::InvalidLimit(..))
+                ));
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_rewrite_hooks() {
+        use rewrite::*;
+        use std::borrow::Cow;
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE tenant_rows (tenant_id int, v int); \
+                 INSERT INTO tenant_rows VALUES (1, 10), (1, 11), (2, 20)",
+                None,
+                None,
+            );
+            let count = |raw: bool| {
+                let query = "SELECT count(*) FROM tenant_rows";
+                let table = if raw {
+                    c.checked_select_raw(query, None, None)
+                } else {
+                    (&c).checked_select(query, None, None)
+                };
+                table.unwrap().first().get_one::<i64>()
+            };
+
+            let tenant = register(Box::new(|query: &str| {
+                Cow::Owned(
+                    query.replace("FROM tenant_rows", "FROM tenant_rows WHERE tenant_id = 1"),
+                )
+            }));
+            let comment = register(Box::new(|query: &str| {
+                Cow::Owned(format!("/* trace_id=42 */ {}", query))
+            }));
+            assert_eq!(Some(2), count(false));
+            assert_eq!(Some(3), count(true));
+
+            // Errors are raised for the rewritten text, and remember the original one
+            let redirect = register(Box::new(|query: &str| {
+                Cow::Owned(query.replace("shadowed", "shadow.shadowed"))
+            }));
+            let err = (&c)
+                .checked_select("SELECT * FROM shadowed", None, None)
+                .unwrap_err();
+            assert!(err.report().unwrap().message().contains("shadow.shadowed"));
+            assert_eq!(
+                Some("SELECT * FROM shadowed"),
+                err.original_query().as_deref()
+            );
+            let err = c
+                .checked_select_raw("SELECT * FROM shadowed", None, None)
+                .unwrap_err();
+            assert_eq!(None, err.original_query());
+
+            // Catalog queries the crate executes on its own behalf are not rewritten
+            let breaking = register(Box::new(|query: &str| {
+                Cow::Owned(format!("{} broken", query))
+            }));
+            assert!((&c).checked_select("SELECT 1", None, None).is_err());
+            (&c).sub_transaction(|xact| {
+                xact.held_locks().unwrap();
+                xact.commit();
+            });
+            assert!(unregister(breaking));
+
+            // A panicking hook is unregistered, and the others still apply
+            let panicking = register(Box::new(|_: &str| -> Cow<str> { panic!("hook failure") }));
+            assert_eq!(Some(2), count(false));
+            assert!(!unregister(panicking));
+
+            for id in [tenant, comment, redirect] {
+                assert!(unregister(id));
+            }
+            assert_eq!(Some(3), count(false));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_rewrite_hooks_activity() {
+        use config::*;
+        use rewrite::*;
+        use std::borrow::Cow;
+        // The activity reported for a tagged command is its text as executed, so the hook's comment shows up in
+        // pg_stat_activity while it sleeps
+        Spi::execute(|c| {
+            let comment = register(Box::new(|query: &str| {
+                Cow::Owned(format!("/* trace_id=42 */ {}", query))
+            }));
+            set_statement_tag("traced");
+            (&c).checked_select("SELECT pg_sleep(0.1)", None, None)
+                .unwrap();
+            let pid = unsafe { pg_sys::MyProcPid };
+            assert_eq!(
+                Some("/* spiext:traced */ /* trace_id=42 */ SELECT pg_sleep(0.1)"),
+                tagged_activity(&c)
+                    .unwrap()
+                    .into_iter()
+                    .find(|row| row.pid == pid)
+                    .map(|row| row.query)
+                    .as_deref()
+            );
+            clear_statement_tag();
+            assert!(unregister(comment));
+        });
+    }
+
+    #[cfg(all(
+        any(feature = "pg13", feature = "pg14", feature = "pg15"),
+        feature = "full"
+    ))]
+    #[pg_test]
+    fn test_execution_resource_usage() {
+        use info::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE usage_a (v int)", None, None);
+            let (_, insert) = c
+                .checked_update_with_info(
+                    "INSERT INTO usage_a SELECT generate_series(1, 1000)",
+                    None,
+                    None,
+                )
+                .unwrap();
+            let insert = insert.resources.unwrap();
+            assert!(insert.wal_records >= 1000);
+            assert!(insert.wal_bytes > 0);
+
+            let (_, select) = c
+                .checked_select_with_info("SELECT count(*) FROM usage_a", None, None)
+                .unwrap();
+            let select = select.resources.unwrap();
+            assert!(select.shared_blks_hit > 0);
+            assert_eq!(0, select.wal_bytes);
+            assert!(insert.wal_bytes > select.wal_bytes);
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_array_and_composite_args() {
+        use args::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE args_a (id bigint PRIMARY KEY); \
+                 INSERT INTO args_a SELECT generate_series(1, 20000); \
+                 CREATE TYPE args_pair AS (name text, n int)",
+                None,
+                None,
+            );
+
+            let ids: Vec<i64> = (1..=10000).map(|id| id * 2).collect();
+            let count = (&c)
+                .checked_select(
+                    "SELECT count(*) FROM args_a WHERE id = ANY($1)",
+                    None,
+                    Some(vec![array_arg(&ids)]),
+                )
+                .unwrap()
+                .first()
+                .get_one::<i64>();
+            assert_eq!(Some(10000), count);
+
+            let with_nulls = (&c)
+                .checked_select(
+                    "SELECT $1::text, array_length($1, 1)",
+                    None,
+                    Some(vec![array_arg(&[Some(1i32), None, Some(3)])]),
+                )
+                .unwrap()
+                .first()
+                .get_two::<String, i32>();
+            assert_eq!((Some("{1,NULL,3}".to_string()), Some(3)), with_nulls);
+
+            let pair = |name: &str, n: i32| {
+                composite_arg(
+                    "args_pair",
+                    vec![
+                        (PgBuiltInOids::TEXTOID.oid(), name.into_datum()),
+                        (PgBuiltInOids::INT4OID.oid(), n.into_datum()),
+                    ],
+                )
+            };
+            let equal = (&c)
+                .checked_select(
+                    "SELECT $1 = ROW('one', 1)::args_pair",
+                    None,
+                    Some(vec![pair("one", 1).unwrap()]),
+                )
+                .unwrap()
+                .first()
+                .get_one::<bool>();
+            assert_eq!(Some(true), equal);
+
+            // Dropped attributes are skipped
+            c.update(
+                "ALTER TYPE args_pair DROP ATTRIBUTE n, ADD ATTRIBUTE m int",
+                None,
+                None,
+            );
+            let text = (&c)
+                .checked_select("SELECT $1::text", None, Some(vec![pair("two", 2).unwrap()]))
+                .unwrap()
+                .first()
+                .get_one::<String>();
+            assert_eq!(Some("(two,2)".to_string()), text);
+
+            assert!(matches!(
+                composite_arg(
+                    "args_pair",
+                    vec![(PgBuiltInOids::TEXTOID.oid(), "x".into_datum())]
+                ),
+                Err(ArgError::FieldCountMismatch {
+                    expected: 2,
+                    given: 1,
+                    ..
+                })
+            ));
+            assert!(matches!(
+                composite_arg(
+                    "args_pair",
+                    vec![
+                        (PgBuiltInOids::TEXTOID.oid(), "x".into_datum()),
+                        (PgBuiltInOids::INT8OID.oid(), 1i64.into_datum()),
+                    ]
+                ),
+                Err(ArgError::FieldTypeMismatch { attribute, .. }) if attribute == "m"
+            ));
+            assert!(matches!(
+                composite_arg("args_missing", vec![]),
+                Err(ArgError::UnknownType(_))
+            ));
+            assert!(matches!(
+                composite_arg("int4", vec![]),
+                Err(ArgError::NotComposite(_))
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_spi_stack_corruption() {
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE spi_stack (v int)", None, None);
+            let depth = spi_depth();
+            assert!(depth >= 1);
+
+            // Nesting through this crate's APIs keeps the depth
+            SpiClient.sub_transaction(|xact| {
+                let xact = xact.sub_transaction(|mut xact| {
+                    assert_eq!(Some(1), Spi::get_one::<i32>("SELECT 1"));
+                    xact.update("INSERT INTO spi_stack VALUES (1)", None, None)
+                        .unwrap();
+                    xact.commit()
+                });
+                assert_eq!(depth, spi_depth());
+                xact.commit()
+            });
+
+            // A connection left open is detected before executing anything
+            SpiClient.sub_transaction(|mut xact| {
+                assert_eq!(pg_sys::SPI_OK_CONNECT as i32, unsafe {
+                    pg_sys::SPI_connect()
+                });
+                assert_eq!(depth + 1, spi_depth());
+                let err = xact
+                    .update("INSERT INTO spi_stack VALUES (2)", None, None)
+                    .unwrap_err();
+                assert!(matches!(
+                    err,
+                    Error::SpiStackCorruption { expected, actual }
+                        if expected == depth && actual == depth + 1
+                ));
+                // Rolling back undoes the connection
+                xact.rollback()
+            });
+            assert_eq!(depth, spi_depth());
+
+            // Committing such a sub-transaction rolls it back and raises the error
+            let committed = PgTryBuilder::new(|| {
+                SpiClient.sub_transaction(|mut xact| {
+                    xact.update("INSERT INTO spi_stack VALUES (3)", None, None)
+                        .unwrap();
+                    unsafe { pg_sys::SPI_connect() };
+                    xact.commit();
+                });
+                true
+            })
+            .catch_others(|_| false)
+            .execute();
+            assert!(!committed);
+            assert_eq!(depth, spi_depth());
+            assert_eq!(
+                Some(1),
+                Spi::get_one::<i32>("SELECT count(*)::int FROM spi_stack")
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_deferred() {
+        use checked::*;
+        use deferred::*;
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE deferred (n serial, v text)", None, None);
+
+            // Deferred work runs in order, after the sub-transaction's own commands
+            SpiClient.sub_transaction(|mut xact| {
+                xact.defer("INSERT INTO deferred (v) VALUES ('first')", None);
+                xact.defer_fn("second", |client| {
+                    client.update("INSERT INTO deferred (v) VALUES ('second')", None, None);
+                    Ok(())
+                });
+                xact.update("INSERT INTO deferred (v) VALUES ('own')", None, None)
+                    .unwrap();
+                xact.commit()
+            });
+            let rows: Vec<String> = (&c)
+                .checked_select("SELECT v FROM deferred ORDER BY n", None, None)
+                .unwrap()
+                .map(|row| row.by_ordinal(1).unwrap().value().unwrap())
+                .collect();
+            assert_eq!(vec!["own", "first", "second"], rows);
+
+            // Nothing deferred runs on rollback, including that of a nested sub-transaction
+            SpiClient.sub_transaction(|mut xact| {
+                xact.defer("INSERT INTO deferred (v) VALUES ('rolled back')", None);
+                let mut xact = xact.sub_transaction(|mut xact| {
+                    xact.defer("INSERT INTO deferred (v) VALUES ('nested')", None);
+                    xact.rollback()
+                });
+                xact.defer("INSERT INTO deferred (v) VALUES ('also rolled back')", None);
+                xact.rollback()
+            });
+            assert_eq!(
+                Some(3),
+                (&c).checked_select("SELECT count(*)::int FROM deferred", None, None)
+                    .unwrap()
+                    .first()
+                    .get_one::<i32>()
+            );
+
+            // A failing item rolls back the whole sub-transaction and is reported by its index
+            let (_, result) = SpiClient.sub_transaction(|mut xact| {
+                xact.update("INSERT INTO deferred (v) VALUES ('aborted')", None, None)
+                    .unwrap();
+                xact.defer("INSERT INTO deferred (v) VALUES ('fine')", None);
+                xact.defer("INSERT INTO deferred (n) VALUES ('not a number')", None);
+                xact.defer("INSERT INTO deferred (v) VALUES ('never')", None);
+                xact.checked_commit()
+            });
+            match result {
+                Err(CommitError::Deferred { index, item, .. }) => {
+                    assert_eq!(1, index);
+                    assert_eq!("INSERT INTO deferred (n) VALUES ('not a number')", item);
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+            assert_eq!(
+                Some(3),
+                (&c).checked_select("SELECT count(*)::int FROM deferred", None, None)
+                    .unwrap()
+                    .first()
+                    .get_one::<i32>()
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_load_model() {
+        use error::PostgresErrorExt;
+        use model::*;
+        use owned::OwnedRows;
+        use std::collections::HashMap;
+
+        #[derive(Debug)]
+        struct Config {
+            settings: Vec<(String, String)>,
+            users: Vec<String>,
+            flags: Vec<String>,
+        }
+
+        impl LoadModel for Config {
+            fn queries() -> Vec<(&'static str, &'static str)> {
+                vec![
+                    (
+                        "settings",
+                        "SELECT name, value FROM model_settings ORDER BY name",
+                    ),
+                    ("users", "SELECT name FROM model_users ORDER BY name"),
+                    ("flags", "SELECT setting FROM model_flags ORDER BY setting"),
+                ]
+            }
+
+            fn build(results: HashMap<&'static str, OwnedRows>) -> Result<Self, String> {
+                let settings: Vec<(String, String)> = results["settings"]
+                    .iter()
+                    .map(|row| (row.get("name").unwrap(), row.get("value").unwrap()))
+                    .collect();
+                let users = results["users"]
+                    .iter()
+                    .map(|row| row.get("name").unwrap())
+                    .collect();
+                let flags: Vec<String> = results["flags"]
+                    .iter()
+                    .map(|row| row.get("setting").unwrap())
+                    .collect();
+                if let Some(flag) = flags
+                    .iter()
+                    .find(|flag| !settings.iter().any(|(name, _)| name == *flag))
+                {
+                    return Err(format!("flag for unknown setting \"{}\"", flag));
+                }
+                Ok(Config {
+                    settings,
+                    users,
+                    flags,
+                })
+            }
+        }
+
+        struct Broken;
+
+        impl LoadModel for Broken {
+            fn queries() -> Vec<(&'static str, &'static str)> {
+                vec![
+                    ("users", "SELECT name FROM model_users"),
+                    ("missing", "SELECT * FROM model_missing"),
+                ]
+            }
+
+            fn build(_: HashMap<&'static str, OwnedRows>) -> Result<Self, String> {
+                Ok(Broken)
+            }
+        }
+
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE model_settings (name text, value text); \
+                 CREATE TABLE model_users (name text); \
+                 CREATE TABLE model_flags (setting text); \
+                 INSERT INTO model_settings VALUES ('a', '1'), ('b', '2'); \
+                 INSERT INTO model_users VALUES ('alice'); \
+                 INSERT INTO model_flags VALUES ('a')",
+                None,
+                None,
+            );
+            // The queries use the active snapshot, taken here as a statement would
+            unsafe {
+                pg_sys::CommandCounterIncrement();
+                pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot());
+            }
+            // Changes made after the snapshot was taken are seen by none of the queries
+            c.update(
+                "INSERT INTO model_settings VALUES ('c', '3'); \
+                 INSERT INTO model_users VALUES ('bob'); \
+                 INSERT INTO model_flags VALUES ('missing')",
+                None,
+                None,
+            );
+            let config: Config = checked_load_model(&c).unwrap();
+            assert_eq!(
+                vec![
+                    ("a".to_string(), "1".to_string()),
+                    ("b".to_string(), "2".to_string())
+                ],
+                config.settings
+            );
+            assert_eq!(vec!["alice"], config.users);
+            assert_eq!(vec!["a"], config.flags);
+            unsafe { pg_sys::PopActiveSnapshot() };
+
+            // With the flag for an unknown setting visible, the model rejects the rows
+            unsafe {
+                pg_sys::CommandCounterIncrement();
+                pg_sys::PushActiveSnapshot(pg_sys::GetTransactionSnapshot());
+            }
+            match checked_load_model::<Config>(&c) {
+                Err(LoadError::Build(message)) => {
+                    assert_eq!("flag for unknown setting \"missing\"", message)
+                }
+                result => panic!("unexpected result: {:?}", result.map(|_| ())),
+            }
+            unsafe { pg_sys::PopActiveSnapshot() };
+
+            // A failed query names its slot
+            match checked_load_model::<Broken>(&c) {
+                Err(LoadError::Slot { slot, error }) => {
+                    assert_eq!("missing", slot);
+                    assert_eq!("42P01", error.report().unwrap().sqlstate().as_str());
+                }
+                result => panic!("unexpected result: {:?}", result.map(|_| ())),
+            }
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_trigger_suspension() {
+        use checked::*;
+        use subtxn::*;
+        use triggers::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE trig_t (v int); \
+                 CREATE TABLE trig_log (name text); \
+                 CREATE FUNCTION trig_log() RETURNS trigger LANGUAGE plpgsql AS \
+                 $$ BEGIN INSERT INTO trig_log VALUES (TG_NAME); RETURN NEW; END $$; \
+                 CREATE TRIGGER trig_origin AFTER INSERT ON trig_t FOR EACH ROW EXECUTE FUNCTION trig_log(); \
+                 CREATE TRIGGER trig_always AFTER INSERT ON trig_t FOR EACH ROW EXECUTE FUNCTION trig_log(); \
+                 ALTER TABLE trig_t ENABLE ALWAYS TRIGGER trig_always",
+                None,
+                None,
+            );
+            let fired = || {
+                (&c).checked_select("SELECT count(*)::int FROM trig_log", None, None)
+                    .unwrap()
+                    .first()
+                    .get_one::<i32>()
+                    .unwrap()
+            };
+            let states = || {
+                (&c).checked_select(
+                    "SELECT string_agg(tgname || '=' || tgenabled, ',' ORDER BY tgname) \
+                         FROM pg_trigger WHERE tgrelid = 'trig_t'::regclass",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .first()
+                .get_one::<String>()
+                .unwrap()
+            };
+            assert_eq!("trig_always=A,trig_origin=O", states());
+
+            (&c).sub_transaction(|mut xact| {
+                let suspension =
+                    TriggerSuspension::disable_user_triggers(&mut xact, "trig_t").unwrap();
+                assert_eq!(
+                    vec!["trig_always", "trig_origin"],
+                    suspension.triggers().collect::<Vec<_>>()
+                );
+                xact.update("INSERT INTO trig_t VALUES (1)", None, None)
+                    .unwrap();
+                assert_eq!(0, fired());
+                drop(suspension);
+                // Each trigger gets back its own state
+                assert_eq!("trig_always=A,trig_origin=O", states());
+                xact.update("INSERT INTO trig_t VALUES (2)", None, None)
+                    .unwrap();
+                assert_eq!(2, fired());
+                xact.commit()
+            });
+
+            // If the sub-transaction rolls back, so does disabling, and dropping the guard does nothing
+            let suspension = (&c).sub_transaction(|mut xact| {
+                let suspension =
+                    TriggerSuspension::disable_user_triggers(&mut xact, "trig_t").unwrap();
+                assert_eq!("trig_always=D,trig_origin=D", states());
+                xact.rollback();
+                suspension
+            });
+            assert_eq!("trig_always=A,trig_origin=O", states());
+            suspension.restore().unwrap();
+            assert_eq!("trig_always=A,trig_origin=O", states());
+
+            // A missing table is reported as it is
+            (&c).sub_transaction(|mut xact| {
+                assert!(matches!(
+                    TriggerSuspension::disable_user_triggers(&mut xact, "trig_missing"),
+                    Err(TriggerError::Query(_))
+                ));
+                xact.rollback()
+            });
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_row_images() {
+        use images::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE images (id int, v text); \
+                 INSERT INTO images VALUES (1, 'a'), (2, 'b'), (3, 'c')",
+                None,
+                None,
+            );
+            let values = |rows: &owned::OwnedRows| -> Vec<(i32, String)> {
+                rows.iter()
+                    .map(|row| (row.get("id").unwrap(), row.get("v").unwrap()))
+                    .collect()
+            };
+
+            let (table, images) = c
+                .checked_update_with_images(
+                    "UPDATE images AS i SET v = upper(v) WHERE i.id >= $1 -- trailing comment",
+                    Some(vec![(PgBuiltInOids::INT4OID.oid(), 2.into_datum())]),
+                    Some("id"),
+                )
+                .unwrap();
+            assert_eq!(2, table.len());
+            assert_eq!(
+                vec![(2, "b".to_string()), (3, "c".to_string())],
+                values(&images.old)
+            );
+            assert_eq!(
+                vec![(2, "B".to_string()), (3, "C".to_string())],
+                values(&images.new)
+            );
+
+            // DELETE only has old images
+            let (_, images) = c
+                .checked_update_with_images("DELETE FROM images WHERE id = 3;", None, None)
+                .unwrap();
+            assert_eq!(vec![(3, "C".to_string())], values(&images.old));
+            assert!(images.new.is_empty());
+            assert_eq!(
+                Some(2),
+                Spi::get_one::<i64>("SELECT count(*) FROM images").map(|count| count as i32)
+            );
+
+            // Commands joining other tables aren't analyzed, and nothing is executed
+            c.update("CREATE TABLE images_src (id int, v text)", None, None);
+            match c.checked_update_with_images(
+                "UPDATE images SET v = s.v FROM images_src s WHERE images.id = s.id",
+                None,
+                None,
+            ) {
+                Err(ImageError::UnsupportedStatement(reason)) => assert!(reason.contains("FROM")),
+                result => panic!("unexpected result: {:?}", result.map(|_| ())),
+            }
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_configured_client() {
+        use checked::*;
+        use configured::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE configured (v int); \
+                 INSERT INTO configured SELECT generate_series(1, 10); \
+                 CREATE SEQUENCE configured_attempts; \
+                 CREATE FUNCTION configured_flaky() RETURNS SETOF int LANGUAGE plpgsql AS $$ \
+                 BEGIN \
+                     IF nextval('configured_attempts') < 3 THEN \
+                         RAISE EXCEPTION 'could not serialize access' USING ERRCODE = '40001'; \
+                     END IF; \
+                     RETURN QUERY SELECT v FROM configured ORDER BY v; \
+                 END $$",
+                None,
+                None,
+            );
+            let config = SpiExt::builder()
+                .with_default_limit(3)
+                .with_retry(RetryPolicy::on_serialization_failure(3))
+                .with_log_level(LogLevel::Debug1);
+            let mut client = SpiExt::with(&c, config);
+
+            // The third attempt succeeds, and returns the default number of rows
+            let table = (&client)
+                .checked_select("SELECT * FROM configured_flaky()", None, None)
+                .unwrap();
+            assert_eq!(3, table.len());
+            // Sequences aren't rolled back with the failed attempts
+            assert_eq!(
+                Some(3),
+                Spi::get_one::<i64>("SELECT last_value FROM configured_attempts")
+            );
+
+            // Overrides win over the configuration, and an explicit limit over the default one
+            let table = client
+                .call()
+                .override_limit(Some(5))
+                .select("SELECT v FROM configured", None)
+                .unwrap();
+            assert_eq!(5, table.len());
+            let table = client
+                .call()
+                .override_limit(None)
+                .select("SELECT v FROM configured", None)
+                .unwrap();
+            assert_eq!(10, table.len());
+            let table = (&client)
+                .checked_select("SELECT v FROM configured", Some(7), None)
+                .unwrap();
+            assert_eq!(7, table.len());
+
+            // Running out of attempts returns the last error; without retries, the first one
+            client
+                .call()
+                .update("ALTER SEQUENCE configured_attempts RESTART", None)
+                .unwrap();
+            let err = client
+                .call()
+                .override_retry(Some(RetryPolicy::on_serialization_failure(2)))
+                .select("SELECT * FROM configured_flaky()", None)
+                .unwrap_err();
+            assert_eq!("40001", err.sqlstate().unwrap().as_str());
+            client
+                .call()
+                .update("ALTER SEQUENCE configured_attempts RESTART", None)
+                .unwrap();
+            assert!(client
+                .call()
+                .override_retry(None)
+                .select("SELECT * FROM configured_flaky()", None)
+                .is_err());
+
+            // Nested clients share the configuration
+            let rows = client
+                .sub_transaction(|client| {
+                    (&mut *client).checked_update(
+                        "INSERT INTO configured VALUES (11)",
+                        None,
+                        None,
+                    )?;
+                    Ok((&*client)
+                        .checked_select("SELECT v FROM configured", None, None)?
+                        .len())
+                })
+                .unwrap();
+            assert_eq!(3, rows);
+
+            // An unconfigured client behaves as `SpiClient` does
+            let unconfigured = SpiExt::with(&c, SpiExt::builder());
+            assert_eq!(
+                11,
+                (&unconfigured)
+                    .checked_select("SELECT v FROM configured", None, None)
+                    .unwrap()
+                    .len()
+            );
+            assert!(matches!(
+                (&unconfigured).checked_select(" ; ", None, None),
+                Err(Error::EmptyQuery)
+            ));
+            let err = (&unconfigured)
+                .checked_select("SELECT 1/0", None, None)
+                .unwrap_err();
+            let expected = (&c).checked_select("SELECT 1/0", None, None).unwrap_err();
+            assert_eq!(
+                expected
+                    .sqlstate()
+                    .map(|sqlstate| sqlstate.as_str().to_string()),
+                err.sqlstate().map(|sqlstate| sqlstate.as_str().to_string())
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_error_context_frames() {
+        use pgx_contrib_spiext::checked::*;
+        use pgx_contrib_spiext::error::context::*;
+        use pgx_contrib_spiext::error::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE FUNCTION ctx_f3() RETURNS void LANGUAGE plpgsql AS $$BEGIN\n  RAISE 'innermost';\nEND$$",
+                None,
+                None,
+            );
+            c.update(
+                "CREATE FUNCTION ctx_f2() RETURNS void LANGUAGE plpgsql AS $$BEGIN\n\n  PERFORM ctx_f3();\nEND$$",
+                None,
+                None,
+            );
+            c.update(
+                "CREATE FUNCTION ctx_f1() RETURNS void LANGUAGE plpgsql AS $$BEGIN\n  PERFORM ctx_f2();\nEND$$",
+                None,
+                None,
+            );
+            let err = (&c)
+                .checked_select("SELECT ctx_f1()", None, None)
+                .unwrap_err();
+            let functions: Vec<(String, Option<u32>)> = err
+                .context_frames()
+                .into_iter()
+                .filter_map(|frame| match frame {
+                    ContextFrame::PlPgSqlFunction { name, line, .. } => Some((name, line)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                vec![
+                    ("ctx_f3()".to_string(), Some(2)),
+                    ("ctx_f2()".to_string(), Some(3)),
+                    ("ctx_f1()".to_string(), Some(2)),
+                ],
+                functions
+            );
+            let owned = OwnedPostgresError::from(err);
+            assert!(matches!(
+                owned.deepest_user_function(),
+                Some(ContextFrame::PlPgSqlFunction { name, .. }) if name == "ctx_f3()"
+            ));
+            assert!(owned.context_frames().iter().any(|frame| matches!(
+                frame,
+                ContextFrame::SqlStatement { statement, .. } if statement == "SELECT ctx_f3()"
+            )));
+
+            // Unrecognized lines are kept as they are
+            let frames = parse(
+                "PL/pgSQL function f(integer) line 7 at RETURN\nsomething else\nCOPY t, line 3",
+            );
+            assert_eq!(
+                Some(7),
+                match &frames[0] {
+                    ContextFrame::PlPgSqlFunction { line, .. } => *line,
+                    _ => None,
+                }
+            );
+            assert_eq!(ContextFrame::Raw("something else".to_string()), frames[1]);
+            assert_eq!("COPY t, line 3", frames[2].raw());
+            assert!(deepest_user_function(&frames[1..]).is_none());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_delete_in_batches() {
+        use purge::*;
+        use std::ops::ControlFlow;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE purge_a AS SELECT i FROM generate_series(1, 12000) AS i",
+                None,
+                None,
+            );
+            let count = |c: &SpiClient| {
+                c.select("SELECT count(*) FROM purge_a", None, None)
+                    .first()
+                    .get_one::<i64>()
+                    .unwrap()
+            };
+            let args = || Some(vec![(PgBuiltInOids::INT4OID.oid(), 10000.into_datum())]);
+
+            let mut reported = vec![];
+            let summary =
+                checked_delete_in_batches(&mut c, "purge_a", "i <= $1", args(), 1000, |progress| {
+                    reported.push(progress);
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            assert_eq!(10000, summary.deleted);
+            assert_eq!(10, summary.batches);
+            assert_eq!(DeleteOutcome::Completed, summary.outcome);
+            assert_eq!(10, reported.len());
+            assert_eq!(
+                BatchProgress {
+                    deleted_so_far: 3000,
+                    batches: 3,
+                    last_batch: 1000
+                },
+                reported[2]
+            );
+            assert_eq!(2000, count(&c));
+
+            // Batches deleted before stopping stay deleted
+            let summary =
+                checked_delete_in_batches(&mut c, "purge_a", "i > $1", args(), 500, |progress| {
+                    if progress.batches == 2 {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    }
+                })
+                .unwrap();
+            assert_eq!(1000, summary.deleted);
+            assert_eq!(DeleteOutcome::Cancelled, summary.outcome);
+            assert_eq!(1000, count(&c));
+
+            // Nothing to delete
+            let mut called = false;
+            let summary = checked_delete_in_batches(&mut c, "purge_a", "i < 0", None, 1000, |_| {
+                called = true;
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+            assert!(!called);
+            assert_eq!(0, summary.deleted);
+            assert_eq!(0, summary.batches);
+            assert_eq!(DeleteOutcome::Completed, summary.outcome);
+            assert!(!summary.vacuum_recommended);
+            assert_eq!(1000, count(&c));
+        });
+    }
+
+    #[pg_test]
+    fn test_foreign_sub_transactions() {
+        use deferred::CommitError;
+        use pgx::PgMemoryContexts;
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE foreign_subtxn (v int)", None, None);
+            let count = || Spi::get_one::<i32>("SELECT count(*)::int FROM foreign_subtxn");
+            let begin_raw = || unsafe {
+                let ctx = PgMemoryContexts::CurrentMemoryContext.value();
+                let owner = pg_sys::CurrentResourceOwner;
+                pg_sys::BeginInternalSubTransaction(std::ptr::null());
+                let raw = SubTransactionRaw::new(ctx, owner, pg_sys::GetCurrentSubTransactionId());
+                PgMemoryContexts::For(ctx).set_as_current();
+                raw
+            };
+
+            // Handed over, then rolled back directly
+            let (_, raw) = SpiClient.sub_transaction(|mut xact| {
+                xact.update("INSERT INTO foreign_subtxn VALUES (1)", None, None)
+                    .unwrap();
+                xact.into_raw()
+            });
+            assert_eq!(raw.id(), unsafe { pg_sys::GetCurrentSubTransactionId() });
+            assert!(state_is_clean());
+            unsafe {
+                pg_sys::RollbackAndReleaseCurrentSubTransaction();
+                pg_sys::CurrentResourceOwner = raw.resource_owner();
+            }
+            PgMemoryContexts::For(raw.memory_context()).set_as_current();
+            assert_eq!(Some(0), count());
+
+            // Taken over, then rolled back or committed
+            let mut xact: SubTransaction<_, false> =
+                unsafe { SubTransaction::from_raw(SpiClientWrapper::from(SpiClient), begin_raw()) }
+                    .unwrap();
+            xact.update("INSERT INTO foreign_subtxn VALUES (2)", None, None)
+                .unwrap();
+            xact.rollback();
+            assert_eq!(Some(0), count());
+            let mut xact: SubTransaction<_> =
+                unsafe { SubTransaction::from_raw(SpiClientWrapper::from(SpiClient), begin_raw()) }
+                    .unwrap();
+            xact.update("INSERT INTO foreign_subtxn VALUES (3)", None, None)
+                .unwrap();
+            xact.commit();
+            assert_eq!(Some(1), count());
+
+            // Only the current sub-transaction can be taken over
+            let ended = raw.id();
+            let result: Result<SubTransaction<_>, _> =
+                unsafe { SubTransaction::from_raw(SpiClientWrapper::from(SpiClient), raw) };
+            assert!(matches!(
+                result,
+                Err(Error::SubTransactionMismatch { expected, .. }) if expected == ended
+            ));
+
+            // A sub-transaction begun on top of one of this crate's and left open is detected
+            SpiClient.sub_transaction(|mut xact| {
+                xact.update("INSERT INTO foreign_subtxn VALUES (4)", None, None)
+                    .unwrap();
+                let foreign = begin_raw();
+                let err = xact
+                    .update("INSERT INTO foreign_subtxn VALUES (5)", None, None)
+                    .unwrap_err();
+                assert!(matches!(
+                    err,
+                    Error::SubTransactionMismatch { expected, actual }
+                        if expected == xact.id() && actual == foreign.id()
+                ));
+                // Both are rolled back instead of committed
+                let (_, result) = xact.checked_commit();
+                assert!(matches!(
+                    result,
+                    Err(CommitError::Query(Error::SubTransactionMismatch { .. }))
+                ));
+                assert!(!unsafe { pg_sys::SubTransactionIsActive(foreign.id()) });
+            });
+            assert!(state_is_clean());
+            assert_eq!(Some(1), count());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_table_schema() {
+        use introspect::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE introspect_parent (a int, b text, PRIMARY KEY (a, b))",
+                None,
+                None,
+            );
+            c.update(
+                "CREATE TABLE introspect_child (\
+                 id int NOT NULL DEFAULT 1, \
+                 pa int, \
+                 pb varchar(10), \
+                 created timestamptz DEFAULT now(), \
+                 touched timestamptz DEFAULT clock_timestamp(), \
+                 dropped int, \
+                 CONSTRAINT positive CHECK (id > 0), \
+                 CONSTRAINT parent FOREIGN KEY (pb, pa) REFERENCES introspect_parent (b, a) ON DELETE CASCADE)",
+                None,
+                None,
+            );
+            c.update(
+                "ALTER TABLE introspect_child DROP COLUMN dropped",
+                None,
+                None,
+            );
+
+            let schema = table_schema(&c, "public", "introspect_child").unwrap();
+            let names: Vec<&str> = schema.columns.iter().map(|c| c.name.as_str()).collect();
+            assert_eq!(vec!["id", "pa", "pb", "created", "touched"], names);
+            let id = &schema.columns[0];
+            assert_eq!(PgBuiltInOids::INT4OID.value(), id.type_oid);
+            assert!(id.not_null);
+            assert_eq!(Some("1"), id.default_expr.as_deref());
+            assert!(!id.default_is_volatile);
+            assert!(!schema.columns[1].not_null);
+            assert_eq!(None, schema.columns[1].default_expr);
+            assert_eq!(14, schema.columns[2].typmod);
+            assert_eq!(Some("now()"), schema.columns[3].default_expr.as_deref());
+            assert!(!schema.columns[3].default_is_volatile);
+            assert!(schema.columns[4].default_is_volatile);
+            assert!(schema.columns.iter().all(|column| !column.generated));
+
+            assert_eq!(
+                vec![CheckDef {
+                    name: "positive".to_string(),
+                    definition: "CHECK ((id > 0))".to_string()
+                }],
+                schema.checks
+            );
+            assert_eq!(
+                vec![FkDef {
+                    name: "parent".to_string(),
+                    columns: vec!["pb".to_string(), "pa".to_string()],
+                    ref_table: "introspect_parent".to_string(),
+                    ref_columns: vec!["b".to_string(), "a".to_string()],
+                    on_delete: FkAction::Cascade,
+                    on_update: FkAction::NoAction,
+                }],
+                schema.foreign_keys
+            );
+
+            #[cfg(not(feature = "pg11"))]
+            {
+                c.update(
+                    "CREATE TABLE introspect_generated (a int, b int GENERATED ALWAYS AS (a * 2) STORED)",
+                    None,
+                    None,
+                );
+                let schema = table_schema(&c, "public", "introspect_generated").unwrap();
+                assert!(!schema.columns[0].generated);
+                assert!(schema.columns[1].generated);
+                assert_eq!(Some("(a * 2)"), schema.columns[1].default_expr.as_deref());
+            }
+
+            assert!(matches!(
+                table_schema(&c, "public", "introspect_missing"),
+                Err(IntrospectError::TableNotFound { .. })
+            ));
+        });
+    }
+
+    #[pg_test]
+    fn test_fault_injection() {
+        use error::SqlState;
+        use faults::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE faults_a (v int)", None, None);
+            let count = || Spi::get_one::<i32>("SELECT count(*)::int FROM faults_a");
+
+            inject(
+                QueryMatcher::contains("faults_a"),
+                InjectedError::new(SqlState::parse("P0T01").unwrap(), "injected")
+                    .with_detail("some detail")
+                    .with_hint("some hint"),
+                1,
+            );
+            assert_eq!(1, pending());
+            // Other queries are not affected
+            (&c).checked_select("SELECT 1", None, None).unwrap();
+            let err = (&mut c)
+                .checked_update("INSERT INTO faults_a VALUES (1)", None, None)
+                .unwrap_err();
+            assert_eq!("P0T01", err.sqlstate().unwrap().as_str());
+            assert_eq!("injected", err.report().unwrap().message());
+            assert_eq!(Some("some detail"), err.detail());
+            assert_eq!(Some("some hint"), err.hint());
+            assert!(err.was_handled());
+            assert_eq!(0, pending());
+            (&mut c)
+                .checked_update("INSERT INTO faults_a VALUES (1)", None, None)
+                .unwrap();
+            assert_eq!(Some(1), count());
+
+            inject(
+                QueryMatcher::predicate(|query| query.starts_with("INSERT")),
+                InjectedError::new(SqlState::parse("40001").unwrap(), "injected"),
+                5,
+            );
+            reset();
+            assert_eq!(0, pending());
+            (&mut c)
+                .checked_update("INSERT INTO faults_a VALUES (2)", None, None)
+                .unwrap();
+            assert_eq!(Some(2), count());
+
+            #[cfg(feature = "full")]
+            {
+                use configured::*;
+                use std::cell::Cell;
+                use std::rc::Rc;
+                inject(
+                    QueryMatcher::contains("faults_a"),
+                    InjectedError::new(SqlState::parse("40001").unwrap(), "injected"),
+                    3,
+                );
+                let retried = Rc::new(Cell::new(0));
+                let counter = retried.clone();
+                let retry = RetryPolicy::new(5, move |err| {
+                    counter.set(counter.get() + 1);
+                    err.sqlstate()
+                        .map_or(false, |sqlstate| sqlstate.as_str() == "40001")
+                });
+                let mut client = SpiExt::with(&c, SpiExt::builder().with_retry(retry));
+                (&mut client)
+                    .checked_update("INSERT INTO faults_a VALUES (3)", None, None)
+                    .unwrap();
+                assert_eq!(3, retried.get());
+                assert_eq!(Some(3), count());
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_transaction_control_rejected() {
+        use checked::*;
+        use error::SpiErrorCode;
+        Spi::execute(|mut c| {
+            for (query, kind) in [
+                ("BEGIN", "BEGIN"),
+                (
+                    "start transaction isolation level serializable",
+                    "START TRANSACTION",
+                ),
+                ("COMMIT", "COMMIT"),
+                ("commit prepared 'x'", "COMMIT PREPARED"),
+                ("End", "END"),
+                ("ROLLBACK", "ROLLBACK"),
+                ("rollback to savepoint s", "ROLLBACK"),
+                ("ROLLBACK PREPARED 'x'", "ROLLBACK PREPARED"),
+                ("abort", "ABORT"),
+                ("SAVEPOINT s", "SAVEPOINT"),
+                ("release savepoint s", "RELEASE"),
+                ("PREPARE TRANSACTION 'x'", "PREPARE TRANSACTION"),
+                ("-- finish\n/* the transaction */ COMMIT", "COMMIT"),
+                ("SELECT 1; COMMIT", "COMMIT"),
+            ] {
+                let err = (&mut c).checked_update(query, None, None).unwrap_err();
+                assert!(
+                    matches!(err, Error::TransactionControlNotAllowed { statement_kind } if statement_kind == kind),
+                    "{}",
+                    query
+                );
+                assert_eq!(
+                    format!("{} is not allowed in checked commands", kind),
+                    err.to_string()
+                );
+            }
+            assert!(matches!(
+                (&c).checked_select("commit", None, None),
+                Err(Error::TransactionControlNotAllowed {
+                    statement_kind: "COMMIT"
+                })
+            ));
+            assert!(subtxn::state_is_clean());
+
+            // Only the first keywords of statements are classified
+            (&mut c)
+                .checked_update(
+                    "SELECT 'COMMIT', 1 AS begin; PREPARE p AS SELECT 1",
+                    None,
+                    None,
+                )
+                .unwrap();
+            #[cfg(any(feature = "pg14", feature = "pg15"))]
+            (&mut c)
+                .checked_update(
+                    "CREATE FUNCTION atomic_fn() RETURNS int LANGUAGE sql \
+                     BEGIN ATOMIC SELECT CASE WHEN true THEN 1 END; SELECT 2; END",
+                    None,
+                    None,
+                )
+                .unwrap();
+
+            let allowed = allow_transaction_control();
+            let nested = allow_transaction_control();
+            drop(nested);
+            assert!(matches!(
+                (&mut c).checked_update("-- finish\nCOMMIT", None, None),
+                Err(Error::Spi(SpiErrorCode::Transaction))
+            ));
+            drop(allowed);
+            assert!(matches!(
+                (&mut c).checked_update("COMMIT", None, None),
+                Err(Error::TransactionControlNotAllowed { .. })
+            ));
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_plan_cache() {
+        use checked::*;
+        use config::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE plan_cached (v int); INSERT INTO plan_cached VALUES (1), (2); \
+                 CREATE SCHEMA plan_other; CREATE TABLE plan_other.plan_cached (v int); \
+                 INSERT INTO plan_other.plan_cached VALUES (5)",
+                None,
+                None,
+            );
+            assert_eq!(None, plan_cache_stats());
+            enable_plan_cache(4);
+            let query = "SELECT sum(v)::int FROM plan_cached WHERE v > $1";
+            let sum = |c: &SpiClient| {
+                c.checked_select(
+                    query,
+                    None,
+                    Some(vec![(PgBuiltInOids::INT4OID.oid(), 0.into_datum())]),
+                )
+                .unwrap()
+                .first()
+                .get_one::<i32>()
+            };
+            for _ in 0..3 {
+                assert_eq!(Some(3), sum(&c));
+            }
+            let stats = plan_cache_stats().unwrap();
+            assert_eq!((2, 1, 1), (stats.hits, stats.misses, stats.entries));
+            #[cfg(not(any(feature = "pg11", feature = "pg12")))]
+            assert!(stats.memory_bytes.unwrap() > 0);
+
+            // Limits apply to cached plans too, while other commands are executed without them
+            for _ in 0..2 {
+                assert_eq!(
+                    1,
+                    (&mut c)
+                        .checked_update("SELECT v FROM plan_cached ORDER BY v", Some(1), None)
+                        .unwrap()
+                        .len()
+                );
+                assert_eq!(
+                    2,
+                    (&mut c)
+                        .checked_update("UPDATE plan_cached SET v = v", None, None)
+                        .unwrap()
+                        .len()
+                );
+            }
+            let stats = plan_cache_stats().unwrap();
+            assert_eq!((3, 3, 3), (stats.hits, stats.misses, stats.entries));
+
+            // Another search path resolves the query anew
+            c.update("SET LOCAL search_path TO plan_other", None, None);
+            assert_eq!(Some(5), sum(&c));
+            c.update("RESET search_path", None, None);
+            assert_eq!(Some(3), sum(&c));
+            let stats = plan_cache_stats().unwrap();
+            assert_eq!((4, 4, 4), (stats.hits, stats.misses, stats.entries));
+
+            c.update("ALTER TABLE plan_cached ADD COLUMN w int", None, None);
+            assert_eq!(Some(3), sum(&c));
+            let altered = plan_cache_stats().unwrap();
+            assert_eq!((4, 5), (altered.hits, altered.misses));
+            assert!(altered.invalidations > 0);
+            assert_eq!(Some(3), sum(&c));
+            assert_eq!(5, plan_cache_stats().unwrap().hits);
+
+            disable_plan_cache();
+            assert_eq!(None, plan_cache_stats());
+            assert_eq!(Some(3), sum(&c));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_auto_explain() {
+        use checked::*;
+        use config::*;
+        use std::time::Duration;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE slow_plans (logged_at timestamptz, query text, duration interval, plan text)",
+                None,
+                None,
+            );
+            auto_explain(
+                Duration::from_millis(50),
+                AutoExplainTarget::Table {
+                    name: "slow_plans".to_string(),
+                },
+            );
+            let count = |c: &SpiClient| {
+                c.select("SELECT count(*) FROM slow_plans", None, None)
+                    .first()
+                    .get_one::<i64>()
+            };
+            (&c).checked_select("SELECT 1", None, None).unwrap();
+            assert_eq!(Some(0), count(&c));
+
+            let slow = "SELECT (SELECT 1 FROM pg_sleep(0.1)) + $1";
+            (&mut c)
+                .checked_update(
+                    slow,
+                    None,
+                    Some(vec![(PgBuiltInOids::INT4OID.oid(), 1.into_datum())]),
+                )
+                .unwrap();
+            assert_eq!(Some(1), count(&c));
+            let (query, plan, slower) = c
+                .select(
+                    "SELECT query, plan, duration >= interval '50 ms' FROM slow_plans",
+                    None,
+                    None,
+                )
+                .first()
+                .get_three::<String, String, bool>();
+            assert_eq!(Some(slow), query.as_deref());
+            assert!(plan.unwrap().contains("pg_sleep"));
+            assert_eq!(Some(true), slower);
+
+            // Failing to report the plan doesn't fail the command
+            auto_explain(
+                Duration::ZERO,
+                AutoExplainTarget::Table {
+                    name: "missing_slow_plans".to_string(),
+                },
+            );
+            (&c).checked_select("SELECT 1", None, None).unwrap();
+            auto_explain(Duration::ZERO, AutoExplainTarget::ServerLog);
+            (&c).checked_select("SELECT 1", None, None).unwrap();
+
+            disable_auto_explain();
+            (&c).checked_select(
+                slow,
+                None,
+                Some(vec![(PgBuiltInOids::INT4OID.oid(), 1.into_datum())]),
+            )
+            .unwrap();
+            assert_eq!(Some(1), count(&c));
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_large_objects() {
+        use largeobject::*;
+        use std::io::SeekFrom;
+        use subtxn::*;
+        let data: Vec<u8> = (0..3 * 1024 * 1024)
+            .map(|i: u32| (i * 7 % 251) as u8)
+            .collect();
+        Spi::execute(|c| {
+            let oid = (&c).sub_transaction(|mut xact| {
+                let oid = LargeObject::import_from_bytes(&mut xact, data.chunks(100_000)).unwrap();
+                xact.commit();
+                oid
+            });
+            let same = c
+                .select(
+                    "SELECT md5(lo_get($1)) = md5($2)",
+                    None,
+                    Some(vec![
+                        (PgBuiltInOids::OIDOID.oid(), oid.into_datum()),
+                        (PgBuiltInOids::BYTEAOID.oid(), data.as_slice().into_datum()),
+                    ]),
+                )
+                .first()
+                .get_one::<bool>();
+            assert_eq!(Some(true), same);
+
+            let object = (&c).sub_transaction(|mut xact| {
+                let mut object = LargeObject::open(&mut xact, oid, LargeObjectMode::Read)
+                    .unwrap()
+                    .with_chunk_size(64 * 1024);
+                let mut exported = Vec::new();
+                assert_eq!(
+                    data.len() as u64,
+                    object.export_to_writer(&mut exported).unwrap()
+                );
+                assert!(exported == data);
+
+                assert_eq!(1000, object.seek(SeekFrom::Start(1000)).unwrap());
+                let mut buf = [0; 100];
+                assert_eq!(100, object.read(&mut buf).unwrap());
+                assert_eq!(&data[1000..1100], &buf[..]);
+                assert_eq!(1100, object.tell().unwrap());
+                assert_eq!(
+                    data.len() as u64 - 10,
+                    object.seek(SeekFrom::End(-10)).unwrap()
+                );
+                assert_eq!(10, object.read(&mut buf).unwrap());
+                assert_eq!(&data[data.len() - 10..], &buf[..10]);
+                xact.commit();
+                object
+            });
+            // The descriptor can't be used once its sub-transaction is over
+            assert!(matches!(object.tell(), Err(LargeObjectError::Closed(_))));
+            drop(object);
+
+            (&c).sub_transaction(|mut xact| {
+                let mut object =
+                    LargeObject::open(&mut xact, oid, LargeObjectMode::ReadWrite).unwrap();
+                object.truncate(5).unwrap();
+                assert_eq!(5, object.seek(SeekFrom::End(0)).unwrap());
+                assert_eq!(3, object.write(b"abc").unwrap());
+                object.seek(SeekFrom::Start(0)).unwrap();
+                let mut buf = [0; 16];
+                assert_eq!(8, object.read(&mut buf).unwrap());
+                assert_eq!(b"abc", &buf[5..8]);
+                object.unlink().unwrap();
+
+                assert!(matches!(
+                    LargeObject::open(&mut xact, oid, LargeObjectMode::Read),
+                    Err(LargeObjectError::NotFound(missing)) if missing == oid
+                ));
+                xact.commit();
+            });
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_partitioned_run() {
+        use checked::*;
+        use keyspace::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE run_keys (id int); INSERT INTO run_keys SELECT generate_series(1, 1000); \
+                 CREATE TABLE run_done (id int)",
+                None,
+                None,
+            );
+            let mut calls = 0;
+            let mut failed_keys = Vec::new();
+            let report =
+                partitioned_run(&mut c, "SELECT id FROM run_keys", None, 10, |xact, keys| {
+                    let ids: Vec<i32> = keys
+                        .iter()
+                        .map(|row| row.get::<i32>("id").unwrap())
+                        .collect();
+                    xact.update(
+                        "INSERT INTO run_done SELECT unnest($1)",
+                        None,
+                        Some(vec![(
+                            PgBuiltInOids::INT4ARRAYOID.oid(),
+                            ids.clone().into_datum(),
+                        )]),
+                    )?;
+                    calls += 1;
+                    if calls == 5 {
+                        failed_keys = ids;
+                        xact.update("SELECT 1 / 0", None, None)?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(10, calls);
+            assert_eq!(9, report.succeeded_partitions);
+            assert_eq!(1, report.failed.len());
+            assert_eq!(4, report.failed[0].0);
+            assert_eq!(
+                Some("22012"),
+                report.failed[0].1.sqlstate().as_ref().map(|s| s.as_str())
+            );
+            assert!(!failed_keys.is_empty());
+            assert_eq!(1000 - failed_keys.len() as u64, report.keys_processed);
+
+            let done = |c: &SpiClient, query: &str| {
+                c.select(
+                    query,
+                    None,
+                    Some(vec![(
+                        PgBuiltInOids::INT4ARRAYOID.oid(),
+                        failed_keys.clone().into_datum(),
+                    )]),
+                )
+                .first()
+                .get_one::<i64>()
+            };
+            assert_eq!(
+                Some(0),
+                done(&c, "SELECT count(*) FROM run_done WHERE id = ANY($1)")
+            );
+            assert_eq!(
+                Some(report.keys_processed as i64),
+                done(
+                    &c,
+                    "SELECT count(DISTINCT id) FROM run_done WHERE id <> ALL($1)"
+                )
+            );
+
+            // The same keys end up in the same partitions
+            let mut sizes = Vec::new();
+            partitioned_run(&mut c, "SELECT id FROM run_keys", None, 10, |_, keys| {
+                sizes.push(keys.len());
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(failed_keys.len(), sizes[4]);
+            assert!(matches!(
+                partitioned_run(
+                    &mut c,
+                    "SELECT id FROM missing_run_keys",
+                    None,
+                    10,
+                    |_, _| Ok(())
+                ),
+                Err(Error::Caught(..))
+            ));
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_schema_scope() {
+        use search_path::*;
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE SCHEMA \"Scoped One\"; CREATE SCHEMA scoped_two; \
+                 CREATE TABLE scoped_t (v text); INSERT INTO scoped_t VALUES ('public'); \
+                 CREATE TABLE \"Scoped One\".scoped_t (v text); INSERT INTO \"Scoped One\".scoped_t VALUES ('one'); \
+                 CREATE TABLE scoped_two.scoped_t (v text); INSERT INTO scoped_two.scoped_t VALUES ('two')",
+                None,
+                None,
+            );
+            let original = current();
+            assert_eq!(vec!["$user".to_string(), "public".to_string()], original);
+            let resolved = |xact: &SubTransaction<_, false>| {
+                xact.select("SELECT v FROM scoped_t", None, None)
+                    .unwrap()
+                    .first()
+                    .get_one::<String>()
+                    .unwrap()
+            };
+
+            (&c).sub_transaction(|xact| {
+                let mut xact = xact.rollback_on_drop();
+                let scope = SchemaScope::enter(&mut xact, &["Scoped One", "public"]).unwrap();
+                assert_eq!(
+                    vec!["Scoped One".to_string(), "public".to_string()],
+                    current()
+                );
+                assert_eq!("one", resolved(&xact));
+                drop(scope);
+                assert_eq!(original, current());
+                assert_eq!("public", resolved(&xact));
+
+                assert!(matches!(
+                    SchemaScope::enter(&mut xact, &["scoped_two", "scoped_missing"]),
+                    Err(SchemaScopeError::SchemaNotFound(schema)) if schema == "scoped_missing"
+                ));
+                assert_eq!(original, current());
+
+                // Nested scopes are restored in order, even when a panic unwinds past them
+                let outer = SchemaScope::enter(&mut xact, &["scoped_two"]).unwrap();
+                let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    let _inner = SchemaScope::enter(&mut xact, &["Scoped One"]).unwrap();
+                    assert_eq!("one", resolved(&xact));
+                    panic!("inner scope");
+                }));
+                assert!(panicked.is_err());
+                assert_eq!(vec!["scoped_two".to_string()], current());
+                assert_eq!("two", resolved(&xact));
+                drop(outer);
+                assert_eq!(original, current());
+                xact.commit();
+            });
+
+            // Rolling back the sub-transaction restores the search path, and the scope leaves it alone
+            let scope = (&c).sub_transaction(|xact| {
+                let mut xact = xact.rollback_on_drop();
+                let scope = SchemaScope::enter(&mut xact, &["scoped_two"]).unwrap();
+                xact.rollback();
+                scope
+            });
+            assert_eq!(original, current());
+            drop(scope);
+            assert_eq!(original, current());
+
+            let table =
+                checked_select_in_schema(&c, "scoped_two", "SELECT v FROM scoped_t", None).unwrap();
+            assert_eq!(Some("two".to_string()), table.first().get_one::<String>());
+            assert!(matches!(
+                checked_select_in_schema(&c, "scoped_missing", "SELECT 1", None),
+                Err(SchemaScopeError::SchemaNotFound(_))
+            ));
+            assert_eq!(original, current());
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_statement_tags() {
+        use checked::*;
+        use config::*;
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            set_statement_tag("ops*/ DROP TABLE x; /*");
+            assert_eq!(Some("opsDROPTABLEx"), statement_tag().as_deref());
+
+            set_statement_tag("nightly-report");
+            (&c).checked_select("SELECT pg_sleep(0.1)", None, None)
+                .unwrap();
+            let pid = unsafe { pg_sys::MyProcPid };
+            let activity = tagged_activity(&c).unwrap();
+            let row = activity.iter().find(|row| row.pid == pid).unwrap();
+            assert_eq!("nightly-report", row.tag);
+            assert_eq!(
+                "/* spiext:nightly-report */ SELECT pg_sleep(0.1)",
+                row.query
+            );
+            assert!(row.query_start.is_some());
+
+            // The innermost sub-transaction's tag applies while it is open
+            SpiClient.sub_transaction(|xact| {
+                xact.set_tag("inner");
+                assert_eq!(Some("inner"), statement_tag().as_deref());
+                let (_, xact) = xact.checked_update("SELECT 1", None, None).unwrap();
+                assert_eq!(
+                    Some("/* spiext:inner */ SELECT 1"),
+                    tagged_activity(&SpiClient)
+                        .unwrap()
+                        .into_iter()
+                        .find(|row| row.pid == pid)
+                        .map(|row| row.query)
+                        .as_deref()
+                );
+                xact.rollback()
+            });
+            assert_eq!(Some("nightly-report"), statement_tag().as_deref());
+
+            clear_statement_tag();
+            assert_eq!(None, statement_tag());
+            (&mut c).checked_update("SELECT 1", None, None).unwrap();
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_severity_classes() {
+        use checked::*;
+        use configured::*;
+        use error::*;
+        Spi::execute(|mut c| {
+            for (sqlstate, class) in [
+                ("40001", SeverityClass::Transient),
+                ("40P01", SeverityClass::Transient),
+                ("40002", SeverityClass::Permanent),
+                ("40003", SeverityClass::Permanent),
+                ("55P03", SeverityClass::Transient),
+                ("53100", SeverityClass::ResourceExhaustion),
+                ("53200", SeverityClass::ResourceExhaustion),
+                ("53300", SeverityClass::ResourceExhaustion),
+                ("54001", SeverityClass::ResourceExhaustion),
+                ("42601", SeverityClass::Permanent),
+                ("42P01", SeverityClass::Permanent),
+                ("23505", SeverityClass::Permanent),
+            ] {
+                let err = (&mut c)
+                    .checked_update(
+                        &format!("DO $$ BEGIN RAISE EXCEPTION 'synthesized' USING ERRCODE = '{}'; END $$", sqlstate),
+                        None,
+                        None,
+                    )
+                    .unwrap_err();
+                assert_eq!(Some(class), err.severity_class(), "{}", sqlstate);
+                assert_eq!(class, OwnedPostgresError::from(err).severity_class());
+            }
+            assert_eq!(None, Error::EmptyQuery.severity_class());
+
+            // Running out of disk space isn't retried, unless asked for
+            c.update(
+                "CREATE SEQUENCE disk_full_attempts; \
+                 CREATE FUNCTION disk_full() RETURNS int LANGUAGE plpgsql AS $$ BEGIN \
+                 PERFORM nextval('disk_full_attempts'); \
+                 RAISE EXCEPTION 'could not extend file' USING ERRCODE = 'disk_full'; END $$",
+                None,
+                None,
+            );
+            let attempts = || Spi::get_one::<i64>("SELECT last_value FROM disk_full_attempts");
+            let client = SpiExt::with(
+                &c,
+                SpiExt::builder().with_retry(RetryPolicy::new(3, |_| true)),
+            );
+            let err = (&client)
+                .checked_select("SELECT disk_full()", None, None)
+                .unwrap_err();
+            assert_eq!("53100", err.sqlstate().unwrap().as_str());
+            assert_eq!(Some(1), attempts());
+            assert!((&client)
+                .call()
+                .override_retry(Some(
+                    RetryPolicy::new(3, |_| true).retry_resource_exhaustion()
+                ))
+                .select("SELECT disk_full()", None)
+                .is_err());
+            assert_eq!(Some(4), attempts());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_health_probe() {
+        Spi::execute(|c| {
+            let report = health::probe(&c);
+            assert!(report.is_healthy(), "{:?}", report);
+            assert!(report.can_select);
+            assert_eq!(Some(true), report.can_write);
+            assert!(report.database_size.unwrap() > 0);
+            assert_eq!(None, report.size_headroom);
+            // The table was rolled back
+            assert_eq!(
+                Some(0),
+                Spi::get_one::<i64>(
+                    "SELECT count(*) FROM pg_class WHERE relname LIKE 'spiext\\_health\\_probe%'"
+                )
+            );
+
+            health::set_max_database_size(Some(1));
+            let report = health::probe(&c);
+            assert!(!report.is_healthy());
+            assert!(report.size_headroom.unwrap() < 0);
+            health::set_max_database_size(None);
+        });
+    }
+
+    #[pg_test]
+    fn test_row_order_and_positions() {
+        use checked::*;
+        use owned::OwnedRows;
+        use row::*;
+        use subtxn::*;
+        Spi::execute(|c| {
+            // The order differs from the one the rows are generated in
+            let query =
+                "SELECT i, i * 7919 % 10007 AS k FROM generate_series(1, 10000) i ORDER BY k";
+            let mut expected: Vec<i32> = (1..=10000).collect();
+            expected.sort_by_key(|i| i * 7919 % 10007);
+
+            let rows = SpiClient.sub_transaction(|xact| {
+                let rows = OwnedRows::from_table(xact.select(query, None, None).unwrap());
+                xact.commit();
+                rows
+            });
+            let order: Vec<i32> = rows.iter().map(|row| row.get("i").unwrap()).collect();
+            assert_eq!(expected, order);
+            let folded = c
+                .checked_select_fold(query, None, Vec::new(), |mut order, row| {
+                    order.push(row.by_ordinal(1).unwrap().value::<i32>().unwrap());
+                    order
+                })
+                .unwrap();
+            assert_eq!(expected, folded);
+
+            let row = rows.row(0).unwrap();
+            assert_eq!(vec!["i", "k"], row.columns());
+            assert_eq!(Ok(row.get::<i32>("i")), row.by_ordinal_1based(1));
+            assert_eq!(Ok(row.get::<i32>("k")), row.by_index_0based(1));
+            for (result, requested) in [
+                (row.by_ordinal_1based::<i32>(0), 0),
+                (row.by_ordinal_1based::<i32>(3), 3),
+                (row.by_index_0based::<i32>(2), 2),
+            ] {
+                assert_eq!(
+                    Err(ColumnOutOfRange {
+                        requested,
+                        available: 2
+                    }),
+                    result
+                );
+            }
+
+            struct Positions {
+                columns: Vec<String>,
+                first: Option<String>,
+                second: Option<String>,
+                out_of_range: ColumnOutOfRange,
+            }
+            impl FromSpiRow for Positions {
+                fn from_spi_row(row: &SpiRow) -> Result<Self, RowConversionError> {
+                    Ok(Positions {
+                        columns: row.columns().to_vec(),
+                        first: row.by_ordinal_1based(1).unwrap(),
+                        second: row.by_index_0based(1).unwrap(),
+                        out_of_range: row.by_index_0based::<String>(2).unwrap_err(),
+                    })
+                }
+            }
+            let rows = c
+                .checked_select_strict::<Positions>("SELECT 'a' AS x, 'b' AS y", None, None)
+                .unwrap();
+            assert_eq!(vec!["x", "y"], rows[0].columns);
+            assert_eq!(Some("a"), rows[0].first.as_deref());
+            assert_eq!(Some("b"), rows[0].second.as_deref());
+            assert_eq!(
+                ColumnOutOfRange {
+                    requested: 2,
+                    available: 2
+                },
+                rows[0].out_of_range
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_requirements() {
+        use requirements::*;
+        Spi::execute(|mut c| {
+            c.update("SELECT set_config('work_mem', '64MB', true)", None, None);
+            let met = [
+                Requirement::GucEquals("work_mem", "64MB"),
+                // Compared across units
+                Requirement::GucAtLeast("work_mem", "32768kB"),
+                Requirement::GucAtLeast("work_mem", "64MB"),
+                Requirement::GucAtLeast("max_worker_processes", "1"),
+                Requirement::ExtensionInstalled("plpgsql", Some("1.0")),
+                Requirement::ServerVersionAtLeast(110000),
+            ];
+            check(&c, &met).unwrap();
+
+            let failures = check(
+                &c,
+                &[
+                    Requirement::GucAtLeast("work_mem", "1GB"),
+                    Requirement::ExtensionInstalled("plpgsql", None),
+                    Requirement::ExtensionInstalled("no_such_extension", None),
+                    Requirement::GucEquals("spiext.no_such_setting", "on"),
+                    // Not comparable with a memory setting
+                    Requirement::GucAtLeast("work_mem", "1s"),
+                ],
+            )
+            .unwrap_err();
+            assert_eq!(4, failures.len());
+            assert_eq!(
+                Requirement::GucAtLeast("work_mem", "1GB"),
+                failures[0].requirement
+            );
+            assert!(matches!(&failures[0].observed, Observed::Value(value) if value == "64MB"));
+            assert_eq!(
+                "work_mem >= 1GB is required, but found 64MB",
+                failures[0].to_string()
+            );
+            assert!(matches!(failures[1].observed, Observed::Missing));
+            assert_eq!(
+                "run CREATE EXTENSION no_such_extension",
+                failures[1].remediation()
+            );
+            assert!(matches!(failures[2].observed, Observed::Missing));
+            assert_eq!(
+                Requirement::GucAtLeast("work_mem", "1s"),
+                failures[3].requirement
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_select_opts() {
+        use checked::*;
+        Spi::execute(|mut c| {
+            c.update("CREATE TABLE select_opts_audit (v int)", None, None);
+            let query = "WITH audit AS (INSERT INTO select_opts_audit VALUES (1) RETURNING v) SELECT v FROM audit";
+            let count = || Spi::get_one::<i64>("SELECT count(*) FROM select_opts_audit");
+
+            let read_only = SelectOpts {
+                read_only: true,
+                ..Default::default()
+            };
+            let err = c
+                .checked_select_opts(query, None, None, read_only)
+                .unwrap_err();
+            assert!(matches!(err, Error::ReadOnlyViolation(..)), "{}", err);
+            assert_eq!("0A000", err.sqlstate().unwrap().as_str());
+            assert_eq!(Some(0), count());
+
+            // The default executes it as `checked_select` does
+            let table = c
+                .checked_select_opts(query, None, None, SelectOpts::default())
+                .unwrap();
+            assert_eq!(Some(1), table.first().get_one::<i32>());
+            assert_eq!(Some(1), count());
+
+            // Read-only commands see the changes made before them, unless they use the caller's snapshot
+            for (snapshot, expected) in [(SnapshotMode::Fresh, 1), (SnapshotMode::Caller, 0)] {
+                let table = c
+                    .checked_select_opts(
+                        "SELECT count(*) FROM select_opts_audit",
+                        None,
+                        None,
+                        SelectOpts {
+                            read_only: true,
+                            snapshot,
+                        },
+                    )
+                    .unwrap();
+                assert_eq!(Some(expected), table.first().get_one::<i64>());
+            }
+
+            #[cfg(feature = "full")]
+            {
+                use configured::*;
+                let client = SpiExt::with(&c, SpiExt::builder().with_select_opts(read_only));
+                assert!(matches!(
+                    (&client).checked_select(query, None, None),
+                    Err(Error::ReadOnlyViolation(..))
+                ));
+                assert_eq!(Some(1), count());
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_get_one_fast() {
+        use checked::*;
+        Spi::execute(|c| {
+            let built = tuple_tables_built();
+            let int8 = PgBuiltInOids::INT8OID.oid();
+            assert_eq!(
+                Some(Some(42)),
+                c.checked_get_one_fast::<i64>(
+                    "SELECT $1 + 1",
+                    Some(vec![(int8, 41i64.into_datum())])
+                )
+                .unwrap()
+            );
+            // Detoasted and copied before the rows are freed
+            let long = c
+                .checked_get_one_fast::<String>("SELECT repeat('x', 100000)", None)
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(100000, long.len());
+            assert_eq!(
+                Some(Some("a".to_string())),
+                c.checked_get_one_fast::<String>("SELECT 'a' UNION ALL SELECT 'b'", None)
+                    .unwrap()
+            );
+            assert_eq!(
+                Some(None),
+                c.checked_get_one_fast::<i64>("SELECT NULL::int8", None)
+                    .unwrap()
+            );
+            assert_eq!(
+                None,
+                c.checked_get_one_fast::<i64>("SELECT 1::int8 WHERE false", None)
+                    .unwrap()
+            );
+            assert!(c.checked_get_one_fast::<i64>("SELECT 1/0", None).is_err());
+            // SPI builds a tuple table for every command returning rows (even none), but none is counted for the error
+            assert_eq!(built + 5, tuple_tables_built());
+
+            // Same values as the regular path, which builds tuple tables (but doesn't tell no rows from NULL)
+            for query in [
+                "SELECT 42::int8",
+                "SELECT NULL::int8",
+                "SELECT 1::int8 WHERE false",
+            ] {
+                let regular = (&c)
+                    .checked_select(query, Some(1), None)
+                    .unwrap()
+                    .first()
+                    .get_one::<i64>();
+                let fast = c.checked_get_one_fast::<i64>(query, None).unwrap();
+                assert_eq!(regular, fast.flatten());
+            }
+            for query in [
+                "SELECT 'text'",
+                "SELECT NULL::text",
+                "SELECT 'text' WHERE false",
+            ] {
+                let regular = (&c)
+                    .checked_select(query, Some(1), None)
+                    .unwrap()
+                    .first()
+                    .get_one::<String>();
+                let fast = c.checked_get_one_fast::<String>(query, None).unwrap();
+                assert_eq!(regular, fast.flatten());
+            }
+            assert_eq!(built + 5 + 12, tuple_tables_built());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_outbox() {
+        use outbox::*;
+        use subtxn::*;
+        Spi::execute(|mut c| {
+            let outbox = Outbox::new("public.outbox");
+            outbox.ensure_schema(&mut c).unwrap();
+            // Idempotent
+            outbox.ensure_schema(&mut c).unwrap();
+
+            // Rolled back with the sub-transaction it was enqueued in
+            SpiClient.sub_transaction(|xact| {
+                let mut xact = xact.rollback_on_drop();
+                outbox
+                    .enqueue(&mut xact, "orders", r#"{"order": 1}"#)
+                    .unwrap();
+            });
+            assert!(outbox.claim(&mut c, "orders", 10).unwrap().is_empty());
+
+            assert!(matches!(
+                SpiClient.sub_transaction(|xact| {
+                    let mut xact = xact.rollback_on_drop();
+                    outbox.enqueue(&mut xact, "orders", "{not json")
+                }),
+                Err(OutboxError::InvalidPayload(_))
+            ));
+
+            let id = SpiClient.sub_transaction(|xact| {
+                let mut xact = xact.commit_on_drop();
+                outbox
+                    .enqueue(&mut xact, "orders", r#"{"order": 2}"#)
+                    .unwrap()
+            });
+            SpiClient.sub_transaction(|xact| {
+                let mut xact = xact.commit_on_drop();
+                outbox.enqueue(&mut xact, "invoices", "[]").unwrap()
+            });
+
+            // Claimed exactly once
+            let claimed = outbox.claim(&mut c, "orders", 10).unwrap();
+            assert_eq!(vec![id], claimed.iter().map(|m| m.id).collect::<Vec<_>>());
+            assert_eq!("orders", claimed[0].topic);
+            assert_eq!(r#"{"order": 2}"#, claimed[0].payload);
+            assert!(outbox.claim(&mut c, "orders", 10).unwrap().is_empty());
+
+            // Processed messages are no longer claimed, even by another outbox of the same table
+            assert_eq!(1, outbox.mark_processed(&mut c, &[id]).unwrap());
+            assert_eq!(0, outbox.mark_processed(&mut c, &[id]).unwrap());
+            let other = Outbox::new("public.outbox");
+            assert!(other.claim(&mut c, "orders", 10).unwrap().is_empty());
+            assert_eq!(1, other.claim(&mut c, "invoices", 10).unwrap().len());
+        });
+    }
+
+    #[pg_test]
+    fn test_sql_value() {
+        use checked::*;
+        use owned::*;
+        use row::*;
+        Spi::execute(|c| {
+            let value = c
+                .checked_get_one_tristate::<i64>("SELECT 42::int8", None)
+                .unwrap();
+            let null = c
+                .checked_get_one_tristate::<i64>("SELECT NULL::int8", None)
+                .unwrap();
+            let no_row = c
+                .checked_get_one_tristate::<i64>("SELECT 1::int8 WHERE false", None)
+                .unwrap();
+            assert_eq!(SqlValue::Value(42), value);
+            assert_eq!(SqlValue::Null, null);
+            assert_eq!(SqlValue::NoRow, no_row);
+            assert!(c
+                .checked_get_one_tristate::<i64>("SELECT 1/0", None)
+                .is_err());
+
+            assert_eq!(Ok(42), value.require());
+            assert_eq!(Err(MissingValue::Null), null.require());
+            assert_eq!(Err(MissingValue::NoRow), no_row.require());
+            // Only a missing row is defaulted
+            assert_eq!(Some(42), value.or_default(7));
+            assert_eq!(None, null.or_default(7));
+            assert_eq!(Some(7), no_row.or_default(7));
+            assert_eq!(Some(Some(42)), Option::<Option<i64>>::from(value));
+            assert_eq!(Some(None), Option::<Option<i64>>::from(null));
+            assert_eq!(None, Option::<Option<i64>>::from(no_row));
+
+            let rows =
+                OwnedRows::from_table(c.select("SELECT 1::int8 AS a, NULL::int8 AS b", None, None));
+            let row = rows.row(0).unwrap();
+            assert_eq!(Ok(SqlValue::Value(1)), row.try_get::<i64>("a"));
+            assert_eq!(Ok(SqlValue::Null), row.try_get::<i64>("b"));
+            assert_eq!(
+                Err(ColumnError {
+                    column: "c".to_string()
+                }),
+                row.try_get::<i64>("c")
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_extern]
+    fn spiext_open_pages(name: &str) -> bool {
+        use cursor::*;
+        run(|c| {
+            c.checked_open_named_cursor(
+                name,
+                "SELECT i::int8 FROM generate_series(1, 10) i",
+                None,
+                false,
+            )
+            .is_ok()
+        })
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_extern]
+    fn spiext_fetch_page(name: &str, absolute: i64, backward: i64) -> Vec<i64> {
+        use cursor::*;
+        run(|c| {
+            let mut values = vec![];
+            for direction in [
+                FetchDirection::Absolute(absolute),
+                FetchDirection::Backward(backward),
+            ] {
+                let rows = c.checked_fetch_named(name, direction).unwrap();
+                values.extend(rows.iter().map(|row| row.get::<i64>("i").unwrap()));
+            }
+            values
+        })
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_named_cursors() {
+        use cursor::*;
+        use subtxn::*;
+        // Opened and fetched from by different calls
+        assert_eq!(
+            Some(true),
+            Spi::get_one::<bool>("SELECT tests.spiext_open_pages('pages')")
+        );
+        assert_eq!(
+            Some(false),
+            Spi::get_one::<bool>("SELECT tests.spiext_open_pages('pages')")
+        );
+        assert_eq!(
+            Some(vec![5, 4, 3]),
+            Spi::get_one::<Vec<i64>>("SELECT tests.spiext_fetch_page('pages', 5, 2)")
+        );
+        Spi::execute(|c| {
+            let rows = c
+                .checked_fetch_named("pages", FetchDirection::Forward(2))
+                .unwrap();
+            assert_eq!(
+                vec![4, 5],
+                rows.iter()
+                    .map(|row| row.get::<i64>("i").unwrap())
+                    .collect::<Vec<_>>()
+            );
+            assert!(matches!(
+                c.checked_open_named_cursor("pages", "SELECT 1", None, false),
+                Err(NamedCursorError::PortalNameInUse(name)) if name == "pages"
+            ));
+            c.checked_close_named("pages").unwrap();
+            assert!(matches!(
+                c.checked_fetch_named("pages", FetchDirection::Forward(1)),
+                Err(NamedCursorError::PortalNotFound(_))
+            ));
+            assert!(matches!(
+                c.checked_close_named("pages"),
+                Err(NamedCursorError::PortalNotFound(_))
+            ));
+
+            for name in ["", "bad name", "x".repeat(64).as_str()] {
+                assert!(matches!(
+                    c.checked_open_named_cursor(name, "SELECT 1", None, false),
+                    Err(NamedCursorError::InvalidName(_))
+                ));
+            }
+
+            // Gone with the sub-transaction it was opened in
+            (&c).sub_transaction(|xact| {
+                let _xact = xact.rollback_on_drop();
+                c.checked_open_named_cursor("rolled_back", "SELECT 1", None, false)
+                    .unwrap();
+            });
+            assert!(matches!(
+                c.checked_fetch_named("rolled_back", FetchDirection::Forward(1)),
+                Err(NamedCursorError::PortalNotFound(_))
+            ));
+
+            // Aggregates can't scan backward
+            c.checked_open_named_cursor(
+                "forward_only",
+                "SELECT count(*) FROM generate_series(1, 10)",
+                None,
+                false,
+            )
+            .unwrap();
+            assert_eq!(
+                1,
+                c.checked_fetch_named("forward_only", FetchDirection::Absolute(1))
+                    .unwrap()
+                    .len()
+            );
+            assert!(matches!(
+                c.checked_fetch_named("forward_only", FetchDirection::Backward(1)),
+                Err(NamedCursorError::ScrollNotSupported(_))
+            ));
+            assert!(matches!(
+                c.checked_fetch_named("forward_only", FetchDirection::Absolute(1)),
+                Err(NamedCursorError::ScrollNotSupported(_))
+            ));
+            // Still usable
+            assert_eq!(
+                0,
+                c.checked_fetch_named("forward_only", FetchDirection::Forward(1))
+                    .unwrap()
+                    .len()
+            );
+            c.checked_close_named("forward_only").unwrap();
+        });
+    }
+
+    #[pg_test]
+    fn test_derive_from_spi_row() {
+        use row::*;
+        #[derive(Debug, PartialEq, FromSpiRow)]
+        struct Address {
+            city: String,
+            zip: Option<String>,
+        }
+        #[derive(Debug, PartialEq, FromSpiRow)]
+        struct Customer {
+            id: i64,
+            #[spiext(column = "full_name")]
+            name: String,
+            email: Option<String>,
+            #[spiext(default)]
+            tier: i32,
+            #[spiext(flatten)]
+            address: Address,
+            active: bool,
+        }
+        Spi::execute(|c| {
+            let customers = c
+                .checked_select_strict::<Customer>(
+                    "SELECT 1::int8 AS id, 'Ada' AS full_name, NULL::text AS email, 'Paris' AS city, \
+                     '75001' AS zip, true AS active",
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert_eq!(
+                vec![Customer {
+                    id: 1,
+                    name: "Ada".to_string(),
+                    email: None,
+                    tier: 0,
+                    address: Address {
+                        city: "Paris".to_string(),
+                        zip: Some("75001".to_string()),
+                    },
+                    active: true,
+                }],
+                customers
+            );
+            // The default only applies to a missing column
+            let customers = c
+                .checked_select_strict::<Customer>(
+                    "SELECT 2::int8 AS id, 'Bo' AS full_name, 'bo@example.com' AS email, 3 AS tier, 'Oslo' AS city, \
+                     NULL AS zip, false AS active",
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert_eq!(3, customers[0].tier);
+            assert_eq!(None, customers[0].address.zip);
+
+            let null = c.checked_select_strict::<Customer>(
+                "SELECT i::int8 AS id, CASE WHEN i = 2 THEN NULL ELSE 'x' END AS full_name, NULL AS email, \
+                 'Rome' AS city, NULL AS zip, true AS active FROM generate_series(0, 3) i ORDER BY i",
+                None,
+                None,
+            );
+            assert!(matches!(
+                null,
+                Err(StrictSelectError::Conversion(RowConversionError::NullField {
+                    type_name: "Customer",
+                    field: "name",
+                    ref column,
+                    row: 2,
+                })) if column == "full_name"
+            ));
+            let missing = c.checked_select_strict::<Customer>(
+                "SELECT 1::int8 AS id, 'x' AS full_name, NULL AS email, NULL AS zip, true AS active",
+                None,
+                None,
+            );
+            assert!(matches!(
+                missing,
+                Err(StrictSelectError::Conversion(RowConversionError::MissingColumn {
+                    type_name: "Address",
+                    field: "city",
+                    ref column,
+                    row: 0,
+                })) if column == "city"
+            ));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_shadow_reads() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE SCHEMA shadow_v1; CREATE SCHEMA shadow_v2; \
+                 CREATE TABLE shadow_v1.items (id int, name text); \
+                 CREATE TABLE shadow_v2.items (id int, name text); \
+                 INSERT INTO shadow_v1.items VALUES (1, 'a'), (2, 'b'), (3, 'c'); \
+                 INSERT INTO shadow_v2.items VALUES (1, 'a'), (2, 'b'), (3, 'c')",
+                None,
+                None,
+            );
+            let divergences = Rc::new(RefCell::new(vec![]));
+            let sink = divergences.clone();
+            let rewrite = |query: &str| query.replace("shadow_v1.", "shadow_v2.");
+            shadow::enable(rewrite, move |divergence| {
+                sink.borrow_mut().push(divergence)
+            });
+            assert!(shadow::is_enabled());
+            let count = |query: &str| {
+                (&SpiClient)
+                    .checked_select(query, None, None)
+                    .unwrap()
+                    .len()
+            };
+
+            assert_eq!(3, count("SELECT * FROM shadow_v1.items ORDER BY id"));
+            assert!(divergences.borrow().is_empty());
+
+            c.update("INSERT INTO shadow_v2.items VALUES (4, 'd')", None, None);
+            assert_eq!(3, count("SELECT * FROM shadow_v1.items ORDER BY id"));
+            {
+                let divergences = divergences.borrow();
+                assert_eq!(1, divergences.len());
+                assert_eq!(
+                    "SELECT * FROM shadow_v1.items ORDER BY id",
+                    divergences[0].query
+                );
+                assert_eq!(
+                    "SELECT * FROM shadow_v2.items ORDER BY id",
+                    divergences[0].rewritten
+                );
+                assert_eq!(3, divergences[0].left_rows);
+                assert_eq!(Some(4), divergences[0].right_rows);
+                assert_eq!(Some(3), divergences[0].first_mismatch);
+                assert!(divergences[0].error.is_none());
+            }
+            divergences.borrow_mut().clear();
+
+            // Row order only matters when comparing in order
+            shadow::enable(
+                |query: &str| {
+                    query
+                        .replace("shadow_v1.", "shadow_v2.")
+                        .replace("ASC", "DESC")
+                },
+                {
+                    let sink = divergences.clone();
+                    move |divergence| sink.borrow_mut().push(divergence)
+                },
+            );
+            let ordered = "SELECT * FROM shadow_v1.items WHERE id < 4 ORDER BY id ASC";
+            count(ordered);
+            assert_eq!(Some(0), divergences.borrow()[0].first_mismatch);
+            shadow::set_order_insensitive(true);
+            count(ordered);
+            assert_eq!(1, divergences.borrow().len());
+            shadow::set_order_insensitive(false);
+            divergences.borrow_mut().clear();
+
+            // Shadow failures are reported, never raised
+            shadow::enable(
+                |query: &str| query.replace("shadow_v1.items", "shadow_v2.missing"),
+                {
+                    let sink = divergences.clone();
+                    move |divergence| sink.borrow_mut().push(divergence)
+                },
+            );
+            assert_eq!(3, count("SELECT * FROM shadow_v1.items ORDER BY id"));
+            {
+                let divergences = divergences.borrow();
+                assert_eq!(1, divergences.len());
+                assert_eq!(None, divergences[0].right_rows);
+                assert_eq!(
+                    Some("42P01"),
+                    divergences[0]
+                        .error
+                        .as_ref()
+                        .and_then(|err| err.sqlstate())
+                        .as_ref()
+                        .map(|sqlstate| sqlstate.as_str())
+                );
+            }
+
+            shadow::disable();
+            assert!(!shadow::is_enabled());
+            assert_eq!(3, count("SELECT * FROM shadow_v1.items ORDER BY id"));
+            assert_eq!(1, divergences.borrow().len());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_per_backend_init() {
+        use once::*;
+        use std::cell::Cell;
+        use subtxn::*;
+        Spi::execute(|c| {
+            let runs = Cell::new(0);
+            let init = |xact: &mut subtxn::SubTransaction<_, false>| -> Result<(), Error> {
+                runs.set(runs.get() + 1);
+                xact.update("CREATE TEMP TABLE once_state (v int)", None, None)?;
+                Ok(())
+            };
+            // Two entry points
+            let entry_a = || per_backend("test_once", init);
+            let entry_b = || per_backend("test_once", init);
+            entry_a().unwrap();
+            entry_b().unwrap();
+            entry_a().unwrap();
+            assert_eq!(1, runs.get());
+            assert_eq!(
+                Some(0),
+                Spi::get_one::<i64>("SELECT count(*) FROM once_state")
+            );
+
+            let fail = Cell::new(true);
+            let runs = Cell::new(0);
+            let init = |xact: &mut subtxn::SubTransaction<_, false>| -> Result<(), Error> {
+                runs.set(runs.get() + 1);
+                xact.update("CREATE TEMP TABLE once_failing (v int)", None, None)?;
+                if fail.get() {
+                    xact.update("SELECT 1/0", None, None)?;
+                }
+                Ok(())
+            };
+            assert!(matches!(
+                per_backend("test_once_failing", init),
+                Err(InitError::Failed(err)) if err.sqlstate().unwrap().as_str() == "22012"
+            ));
+            fail.set(false);
+            assert!(matches!(
+                per_backend("test_once_failing", init),
+                Err(InitError::PreviouslyFailed(err)) if err.sqlstate().unwrap().as_str() == "22012"
+            ));
+            assert_eq!(1, runs.get());
+            // Rolled back along with the failure
+            assert_eq!(
+                Some(0),
+                Spi::get_one::<i64>("SELECT count(*) FROM pg_class WHERE relname = 'once_failing'")
+            );
+            assert!(retry_failed("test_once_failing"));
+            assert!(!retry_failed("test_once_failing"));
+            per_backend("test_once_failing", init).unwrap();
+            assert_eq!(2, runs.get());
+            per_backend("test_once_failing", init).unwrap();
+            assert_eq!(2, runs.get());
+
+            let inner = Cell::new(None);
+            per_backend("test_once_reentrant", |_| {
+                inner.set(Some(per_backend("test_once_reentrant", |_| Ok(()))));
+                Ok(())
+            })
+            .unwrap();
+            assert!(matches!(
+                inner.take(),
+                Some(Err(InitError::Reentrant("test_once_reentrant")))
+            ));
+            per_backend("test_once_reentrant", |_| unreachable!()).unwrap();
+
+            // Forgotten along with the sub-transaction it was called in, but not with one that committed into it
+            let runs = Cell::new(0);
+            let init = |_: &mut subtxn::SubTransaction<_, false>| -> Result<(), Error> {
+                runs.set(runs.get() + 1);
+                Ok(())
+            };
+            (&c).sub_transaction(|xact| {
+                per_backend("test_once_rolled_back", init).unwrap();
+                xact.rollback()
+            });
+            per_backend("test_once_rolled_back", init).unwrap();
+            assert_eq!(2, runs.get());
+            (&c).sub_transaction(|xact| {
+                xact.sub_transaction(|xact| {
+                    per_backend("test_once_rolled_back_outer", init).unwrap();
+                    xact.commit()
+                })
+                .rollback()
+            });
+            per_backend("test_once_rolled_back_outer", init).unwrap();
+            assert_eq!(4, runs.get());
+            (&c).sub_transaction(|xact| {
+                per_backend("test_once_committed", init).unwrap();
+                xact.commit()
+            });
+            per_backend("test_once_committed", init).unwrap();
+            assert_eq!(5, runs.get());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_replication() {
+        use replication::*;
+        use std::time::Duration;
+        for text in ["0/0", "16/B374D848", "FFFFFFFF/FFFFFFFF"] {
+            assert_eq!(text.parse::<Lsn>().unwrap().to_string(), text);
+        }
+        assert_eq!("1/0".parse::<Lsn>(), Ok(Lsn(1 << 32)));
+        for text in ["", "0", "0/", "/0", "G/0", "100000000/0", "0/0/0"] {
+            assert_eq!(text.parse::<Lsn>(), Err(InvalidLsn(text.to_string())));
+        }
+        Spi::execute(|c| {
+            // The test server is a primary without standbys
+            match status(&c).unwrap() {
+                ReplicationStatus::Primary {
+                    current_lsn,
+                    standbys,
+                } => {
+                    assert!(current_lsn > Lsn(0));
+                    assert!(standbys.is_empty());
+                }
+                status => panic!("unexpected status {:?}", status),
+            }
+            let lsn = (&c)
+                .checked_select("SELECT pg_current_wal_lsn()::text", None, None)
+                .unwrap()
+                .first()
+                .get_one::<String>()
+                .unwrap();
+            assert!(lsn.parse::<Lsn>().is_ok());
+            // Without standbys, nothing lags
+            wait_for_lag_below(
+                &c,
+                Duration::from_millis(1),
+                Duration::ZERO,
+                Duration::from_millis(10),
+            )
+            .unwrap();
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_checked_update_verified() {
+        use verify::*;
+        Spi::execute(|mut c| {
+            c.update(
+                "CREATE TABLE verified (id int PRIMARY KEY, v text, touched int NOT NULL DEFAULT 0); \
+                 CREATE FUNCTION verified_touch() RETURNS trigger LANGUAGE plpgsql AS $$ \
+                 BEGIN \
+                     NEW.touched := NEW.touched + 1; \
+                     IF NEW.v = 'bad' THEN NEW.v := 'BAD'; END IF; \
+                     RETURN NEW; \
+                 END $$; \
+                 CREATE TRIGGER verified_touch BEFORE INSERT OR UPDATE ON verified \
+                 FOR EACH ROW EXECUTE FUNCTION verified_touch()",
+                None,
+                None,
+            );
+            let int = |v: i32| (PgBuiltInOids::INT4OID.oid(), v.into_datum());
+            let text = |v: &str| (PgBuiltInOids::TEXTOID.oid(), v.into_datum());
+            let count = || Spi::get_one::<i64>("SELECT count(*) FROM verified").unwrap();
+
+            // The trigger sets a column the command doesn't
+            let table = checked_update_verified(
+                "INSERT INTO verified (id, v) VALUES ($1, $2), ($3, $4) RETURNING *",
+                Some(vec![int(1), text("a"), int(2), text("b")]),
+                VerifySpec::primary_key(),
+            )
+            .unwrap();
+            assert_eq!(2, table.len());
+            assert_eq!(2, count());
+
+            // The trigger changes a value passed as a parameter
+            match checked_update_verified(
+                "INSERT INTO verified (id, v) VALUES ($1, $2) RETURNING id",
+                Some(vec![int(3), text("bad")]),
+                VerifySpec::primary_key(),
+            ) {
+                Err(VerifyError::Mismatch {
+                    column,
+                    expected,
+                    actual,
+                    row,
+                }) => {
+                    assert_eq!("v", column);
+                    assert_eq!(Some("bad".to_string()), expected);
+                    assert_eq!(Some("BAD".to_string()), actual);
+                    assert_eq!(0, row);
+                }
+                result => panic!("unexpected result {:?}", result.map(|table| table.len())),
+            }
+            assert_eq!(2, count());
+
+            // Unless the column is ignored
+            let update = "UPDATE verified SET touched = $1 WHERE id = $2 RETURNING id";
+            match checked_update_verified(
+                update,
+                Some(vec![int(5), int(1)]),
+                VerifySpec::query("SELECT * FROM verified WHERE id = $1", &["id"]),
+            ) {
+                Err(VerifyError::Mismatch { column, .. }) => assert_eq!("touched", column),
+                result => panic!("unexpected result {:?}", result.map(|table| table.len())),
+            }
+            checked_update_verified(
+                update,
+                Some(vec![int(5), int(1)]),
+                VerifySpec::query("SELECT * FROM verified WHERE id = $1", &["id"])
+                    .ignore("touched"),
+            )
+            .unwrap();
+            assert_eq!(
+                Some(6),
+                Spi::get_one::<i32>("SELECT touched FROM verified WHERE id = 1")
+            );
+
+            assert!(matches!(
+                checked_update_verified(
+                    "UPDATE verified SET v = 'c' RETURNING v",
+                    None,
+                    VerifySpec::primary_key(),
+                ),
+                Err(VerifyError::Unsupported(_))
+            ));
+            assert!(matches!(
+                checked_update_verified(
+                    "UPDATE verified SET v = 'c'",
+                    None,
+                    VerifySpec::primary_key(),
+                ),
+                Err(VerifyError::Unsupported(_))
+            ));
+            assert!(matches!(
+                checked_update_verified("INSERT INTO verified VALUES (", None, VerifySpec::primary_key()),
+                Err(VerifyError::Query(err)) if err.sqlstate().unwrap().as_str() == "42601"
+            ));
+            assert_eq!(
+                Some("a".to_string()),
+                Spi::get_one::<String>("SELECT v FROM verified WHERE id = 1")
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_shielded() {
+        use subtxn::*;
+        Spi::execute(|c| {
+            (&c).sub_transaction(|mut xact| {
+                xact.update("CREATE TABLE shielded (v int)", None, None)
+                    .unwrap();
+                let mut dispatched: Vec<&str> = vec![];
+
+                // An error raised directly is caught, rolling back only what the closure did
+                let result = xact.shielded(assert_shield_safe(|child| {
+                    child
+                        .update("INSERT INTO shielded VALUES (1)", None, None)
+                        .unwrap();
+                    pgx::error!("plugin failed");
+                    #[allow(unreachable_code)]
+                    dispatched.push("failing");
+                }));
+                assert!(matches!(
+                    result,
+                    Err(Error::Caught(CaughtError::PostgresError(error), _)) if error.message() == "plugin failed"
+                ));
+                assert!(dispatched.is_empty());
+
+                // The parent is still usable
+                let value = xact.shielded(assert_shield_safe(|child| {
+                    child
+                        .update("INSERT INTO shielded VALUES (2)", None, None)
+                        .unwrap();
+                    dispatched.push("succeeding");
+                    42
+                }));
+                assert_eq!(42, value.unwrap());
+                assert_eq!(vec!["succeeding"], dispatched);
+                assert_eq!(
+                    Some(2),
+                    xact.select("SELECT sum(v)::int FROM shielded", None, None)
+                        .unwrap()
+                        .first()
+                        .get_one::<i32>()
+                );
+
+                // Rust panics propagate, with their own payload
+                let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    let _ = xact.shielded(|_| panic!("plugin bug"));
+                }));
+                assert_eq!(
+                    Some(&"plugin bug"),
+                    panicked.unwrap_err().downcast_ref::<&str>()
+                );
+                xact.rollback();
+            });
+            assert!(state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    thread_local! {
+        static CAPTURED_WARNINGS: std::cell::RefCell<Vec<String>> = std::cell::RefCell::new(Vec::new());
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_guard]
+    unsafe extern "C" fn capture_warning(edata: *mut pg_sys::ErrorData) {
+        if (*edata).elevel == pg_sys::WARNING as i32 {
+            let message = std::ffi::CStr::from_ptr((*edata).message)
+                .to_string_lossy()
+                .into_owned();
+            CAPTURED_WARNINGS.with(|warnings| warnings.borrow_mut().push(message));
+        }
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_subtxn_duration_warning() {
+        use std::thread::sleep;
+        use std::time::Duration;
+        use subtxn::*;
+        let warnings = || CAPTURED_WARNINGS.with(|warnings| warnings.take());
+        let previous_hook = unsafe { pg_sys::emit_log_hook };
+        unsafe { pg_sys::emit_log_hook = Some(capture_warning) };
+
+        // Nothing is reported unless configured
+        SpiClient.sub_transaction(|xact| {
+            sleep(Duration::from_millis(5));
+            assert!(xact.elapsed() >= Duration::from_millis(5));
+            xact.commit();
+        });
+        assert!(warnings().is_empty());
+
+        config::set_subtxn_duration_warning(Duration::from_millis(1));
+        SpiClient.sub_transaction(|xact| {
+            sleep(Duration::from_millis(5));
+            xact.rollback();
+        });
+        let captured = warnings();
+        assert_eq!(1, captured.len());
+        assert!(captured[0].contains("was held open for"));
+        config::disable_subtxn_duration_warnings();
+
+        // Heartbeats reset the no-progress timer
+        config::set_subtxn_no_progress_warning(Duration::from_millis(200));
+        SpiClient.sub_transaction(|xact| {
+            for _ in 0..4 {
+                sleep(Duration::from_millis(80));
+                xact.heartbeat();
+            }
+            assert!(xact.since_heartbeat() < Duration::from_millis(80));
+            assert!(xact.elapsed() >= Duration::from_millis(320));
+            xact.commit();
+        });
+        assert!(warnings().is_empty());
+        SpiClient.sub_transaction(|xact| {
+            sleep(Duration::from_millis(250));
+            xact.commit();
+        });
+        let captured = warnings();
+        assert_eq!(1, captured.len());
+        assert!(captured[0].contains("made no progress"));
+        config::disable_subtxn_duration_warnings();
+
+        unsafe { pg_sys::emit_log_hook = previous_hook };
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_script() {
+        use script::*;
+        Spi::execute(|mut c| {
+            let count = || Spi::get_one::<i64>("SELECT count(*) FROM script_items").unwrap();
+            let report = Script::new()
+                .stmt("create", "CREATE TABLE script_items (v int PRIMARY KEY)")
+                .stmt_with_args(
+                    "insert",
+                    "INSERT INTO script_items SELECT generate_series(1, $1)",
+                    vec![(PgBuiltInOids::INT4OID.oid(), 3.into_datum())],
+                )
+                .stmt("update", "UPDATE script_items SET v = v + 10 WHERE v > 1")
+                .run(&mut c)
+                .unwrap();
+            let statements: Vec<_> = report
+                .statements
+                .iter()
+                .map(|outcome| (outcome.label.as_str(), outcome.rows))
+                .collect();
+            assert_eq!(
+                vec![("create", 0), ("insert", 3), ("update", 2)],
+                statements
+            );
+            assert_eq!(0, report.failures().count());
+            assert_eq!(3, count());
+
+            // A failure rolls back everything
+            let result = Script::new()
+                .stmt("first", "INSERT INTO script_items VALUES (4)")
+                .stmt("duplicate", "INSERT INTO script_items VALUES (1)")
+                .stmt("never", "INSERT INTO script_items VALUES (5)")
+                .run(&mut c);
+            match result {
+                Err(ScriptError::Statement {
+                    label,
+                    index,
+                    sql,
+                    error,
+                }) => {
+                    assert_eq!("duplicate", label);
+                    assert_eq!(1, index);
+                    assert_eq!("INSERT INTO script_items VALUES (1)", sql);
+                    assert_eq!("23505", error.sqlstate().unwrap().as_str());
+                }
+                result => panic!("unexpected result {:?}", result),
+            }
+            assert_eq!(3, count());
+
+            // Unless the failing statement is best-effort
+            let report = Script::new()
+                .stmt("first", "INSERT INTO script_items VALUES (4)")
+                .stmt("duplicate", "INSERT INTO script_items VALUES (1), (6)")
+                .stmt("last", "INSERT INTO script_items VALUES (5)")
+                .continue_on_error(&["duplicate"])
+                .run(&mut c)
+                .unwrap();
+            let failures: Vec<_> = report
+                .failures()
+                .map(|outcome| outcome.label.as_str())
+                .collect();
+            assert_eq!(vec!["duplicate"], failures);
+            assert_eq!(5, count());
+            assert_eq!(
+                Some(0),
+                Spi::get_one::<i64>("SELECT count(*) FROM script_items WHERE v = 6")
+            );
+
+            assert!(matches!(
+                Script::new()
+                    .stmt("a", "SELECT 1")
+                    .continue_on_error(&["b"])
+                    .run(&mut c),
+                Err(ScriptError::UnknownLabel(label)) if label == "b"
+            ));
+        });
+    }
+
+    /// Capabilities of this build of `pgx-contrib-spiext`, for operators
+    #[cfg(feature = "full")]
+    #[pg_extern]
+    fn spiext_capabilities() -> pgx::Json {
+        capabilities::current().to_json()
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_capabilities() {
+        use capabilities::*;
+        let current = current();
+        // The features this crate enables
+        assert!(
+            current.dblink && current.decimal && current.derive && current.json && current.testing
+        );
+        assert_eq!(Ok(()), require(Capability::Json));
+        let line = current.to_string();
+        assert!(line.starts_with("pgx-contrib-spiext "));
+        assert!(line.ends_with("features: dblink, decimal, derive, json, testing"));
+
+        let without_json = Capabilities {
+            json: false,
+            ..current.clone()
+        };
+        let err = without_json.require(Capability::Json).unwrap_err();
+        assert_eq!(MissingCapability(Capability::Json), err);
+        assert!(err.to_string().contains("\"json\" cargo feature"));
+
+        let json = Spi::get_one::<pgx::Json>("SELECT tests.spiext_capabilities()").unwrap();
+        assert_eq!(
+            Some(true),
+            json.0.get("dblink").and_then(|value| value.as_bool())
+        );
+        assert_eq!(
+            Some(current.max_parameters as u64),
+            json.0
+                .get("max_parameters")
+                .and_then(|value| value.as_u64())
+        );
+
+        Spi::execute(|c| {
+            let version = server_version_num(&c).unwrap();
+            assert_eq!(current.pg_version_num / 10000, version as u32 / 10000);
+            // Cached
+            assert_eq!(version, server_version_num(&c).unwrap());
+        });
+    }
+
+    #[pg_test]
+    fn test_checked_select_freed() {
+        use checked::*;
+        // The SPI connection's memory context is a child of the transaction's
+        let allocated =
+            || unsafe { pg_sys::MemoryContextMemAllocated(pg_sys::TopTransactionContext, true) };
+        let query = "SELECT i, md5(i::text) AS h FROM generate_series(1, 10) i";
+        Spi::execute(|c| {
+            let before = allocated();
+            for _ in 0..10_000 {
+                let table = c.checked_select_freed(query, None, None).unwrap();
+                assert_eq!(10, table.len());
+            }
+            let freed = allocated() - before;
+            let before = allocated();
+            for _ in 0..1_000 {
+                (&c).checked_select(query, None, None).unwrap();
+            }
+            let kept = allocated() - before;
+            // Every table has a context of its own, so ten times as many freed ones take less memory than kept ones
+            assert!(
+                freed < kept,
+                "{} bytes for freed tables, {} for kept ones",
+                freed,
+                kept
+            );
+        });
+
+        Spi::execute(|mut c| {
+            let plain = (&c).checked_select(query, None, None).unwrap();
+            let freed = c.checked_select_freed(query, None, None).unwrap();
+            assert_eq!(plain.len(), freed.len());
+            assert_eq!(
+                plain.first().get_two::<i32, String>(),
+                freed.first().get_two::<i32, String>()
+            );
+            let plain = (&c).checked_select(query, None, None).unwrap();
+            let freed = c.checked_select_freed(query, None, None).unwrap();
+            let values =
+                |row: pgx::SpiHeapTupleData| row.by_ordinal(2).unwrap().value::<String>().unwrap();
+            assert_eq!(
+                plain.map(values).collect::<Vec<_>>(),
+                freed.map(values).collect::<Vec<_>>()
+            );
+            c.update("CREATE TABLE freed_a (v int)", None, None);
+            let inserted = c
+                .checked_update_freed(
+                    "INSERT INTO freed_a VALUES (1), (2) RETURNING v",
+                    None,
+                    None,
+                )
+                .unwrap();
+            assert_eq!(2, inserted.len());
+
+            // Tables SPI freed along with a rolled back sub-transaction, or will free along with the connection, are
+            // left to it
+            let xact = SpiClient.sub_transaction(|xact| xact.rollback_on_drop());
+            let table = c.checked_select_freed(query, None, None).unwrap();
+            xact.rollback();
+            drop(table);
+            let xact = SpiClient.sub_transaction(|xact| xact.rollback_on_drop());
+            let table = c.checked_select_freed(query, None, None).unwrap();
+            xact.commit();
+            drop(table);
+            // Rows copied out outlive the table
+            let xact = SpiClient.sub_transaction(|xact| xact.rollback_on_drop());
+            let rows = c
+                .checked_select_freed(query, None, None)
+                .unwrap()
+                .into_owned_rows();
+            xact.rollback();
+            assert_eq!(10, rows.len());
+            assert_eq!(Some(10), rows.row(9).unwrap().get::<i32>("i"));
+            // A table held past a rolled back sub-transaction that began after it is still freed
+            let table = c.checked_select_freed(query, None, None).unwrap();
+            SpiClient
+                .sub_transaction(|xact| xact.rollback_on_drop())
+                .rollback();
+            assert_eq!(10, table.len());
+            drop(table);
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_checked_select_freed_shadowed() {
+        use checked::*;
+        let allocated =
+            || unsafe { pg_sys::MemoryContextMemAllocated(pg_sys::TopTransactionContext, true) };
+        let query = "SELECT i, md5(i::text) AS h FROM generate_series(1, 10) i";
+        // The shadow command executes after the checked one, so SPI's last table is not the one returned
+        shadow::enable(|query| query.to_string(), |_| {});
+        Spi::execute(|c| {
+            let before = allocated();
+            for _ in 0..1_000 {
+                let table = c.checked_select_freed(query, None, None).unwrap();
+                assert_eq!(10, table.len());
+            }
+            let freed = allocated() - before;
+            let before = allocated();
+            for _ in 0..100 {
+                (&c).checked_select(query, None, None).unwrap();
+            }
+            let kept = allocated() - before;
+            assert!(
+                freed < kept,
+                "{} bytes for freed tables, {} for kept ones",
+                freed,
+                kept
+            );
+        });
+        shadow::disable();
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_sample() {
+        use sample::*;
+        Spi::run("CREATE TABLE sample_a AS SELECT i FROM generate_series(1, 100000) i");
+        Spi::run("CREATE VIEW sample_v AS SELECT i FROM sample_a");
+        let ids = |rows: &owned::OwnedRows| {
+            rows.iter()
+                .map(|row| row.get::<i32>("i").unwrap())
+                .collect::<Vec<_>>()
+        };
+        Spi::execute(|c| {
+            let first =
+                rows_repeatable(&c, "sample_a", 1000, SampleMethod::Bernoulli(1.0), 42).unwrap();
+            let second =
+                rows_repeatable(&c, "sample_a", 1000, SampleMethod::Bernoulli(1.0), 42).unwrap();
+            assert!(!first.is_empty());
+            assert_eq!(ids(&first), ids(&second));
+            let system = rows_repeatable(&c, "sample_a", 10, SampleMethod::System(5.0), 7).unwrap();
+            assert_eq!(
+                ids(&system),
+                ids(&rows_repeatable(&c, "sample_a", 10, SampleMethod::System(5.0), 7).unwrap())
+            );
+
+            let sampled = rows(&c, "sample_a", 500, SampleMethod::Reservoir).unwrap();
+            let mut distinct = ids(&sampled);
+            distinct.sort_unstable();
+            distinct.dedup();
+            assert_eq!(500, distinct.len());
+            let seeded = |seed| {
+                ids(&rows_repeatable(&c, "sample_v", 50, SampleMethod::Reservoir, seed).unwrap())
+            };
+            assert_eq!(seeded(1), seeded(1));
+            assert_ne!(seeded(1), seeded(2));
+            assert_eq!(
+                3,
+                rows(&c, "sample_a", 3, SampleMethod::TopN).unwrap().len()
+            );
+
+            assert!(matches!(
+                rows(&c, "sample_v", 10, SampleMethod::Bernoulli(1.0)),
+                Err(SampleError::View(table)) if table == "sample_v"
+            ));
+            assert!(matches!(
+                rows(&c, "sample_missing", 10, SampleMethod::Reservoir),
+                Err(SampleError::NoSuchTable(_))
+            ));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_singleton() {
+        use singleton::*;
+        let advisory_locks = || {
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM pg_locks WHERE locktype = 'advisory' AND pid = pg_backend_pid()",
+            )
+        };
+        Spi::execute(|mut c| {
+            Singleton::ensure_schema(&mut c).unwrap();
+            let vacuum = Singleton::acquire(&mut c, "vacuum", "nightly run").unwrap();
+            let reindex = Singleton::acquire(&mut c, "reindex", "weekly run").unwrap();
+            assert_eq!(lock_key("reindex"), reindex.key());
+            let busy = match Singleton::acquire(&mut c, "vacuum", "second run") {
+                Err(SingletonError::Busy(busy)) => busy,
+                _ => panic!("expected the singleton to be busy"),
+            };
+            assert_eq!("vacuum", busy.name);
+            let holder = busy.holder.unwrap();
+            assert_eq!(
+                Spi::get_one::<i32>("SELECT pg_backend_pid()"),
+                Some(holder.pid)
+            );
+            assert_eq!(Some("nightly run"), holder.metadata.as_deref());
+            assert!(holder.backend_start.is_some() && holder.acquired_at.is_some());
+            assert_eq!(Some(2), advisory_locks());
+
+            drop(vacuum);
+            reindex.release().unwrap();
+            assert_eq!(Some(0), advisory_locks());
+            Singleton::acquire(&mut c, "vacuum", "another run").unwrap();
+
+            // Left behind by a backend that exited
+            c.update(
+                "INSERT INTO spiext_singletons (name, lock_key, pid, metadata) VALUES ('stale', 0, 999999, 'gone')",
+                None,
+                None,
+            );
+            let guard = Singleton::acquire(&mut c, "stale", "fresh run").unwrap();
+            assert_eq!(
+                Some("fresh run".to_string()),
+                Spi::get_one::<String>(
+                    "SELECT metadata FROM spiext_singletons WHERE name = 'stale' AND pid = pg_backend_pid()"
+                )
+            );
+            drop(guard);
+            assert_eq!(
+                Some(0),
+                Spi::get_one::<i64>("SELECT count(*) FROM spiext_singletons")
+            );
+            assert_eq!(Some(0), advisory_locks());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_copy_out() {
+        use copy::*;
+        Spi::run(
+            "CREATE TABLE copy_a AS SELECT i AS id, 'row ' || i AS label, i * 1.5::float8 AS score \
+             FROM generate_series(1, 100) i",
+        );
+        Spi::execute(|c| {
+            let mut out = Vec::new();
+            let stats = c
+                .checked_copy_out_binary(CopySource::Table("copy_a"), &mut out)
+                .unwrap();
+            assert_eq!(100, stats.rows);
+            assert_eq!(out.len() as u64, stats.bytes);
+            // Header: signature, flags and header extension length
+            assert_eq!(b"PGCOPY\n\xff\r\n\0", &out[..11]);
+            let int32 = |at: usize| i32::from_be_bytes(out[at..at + 4].try_into().unwrap());
+            let mut at = 19 + int32(15) as usize;
+            let mut rows = 0;
+            loop {
+                let fields = i16::from_be_bytes(out[at..at + 2].try_into().unwrap());
+                at += 2;
+                if fields == -1 {
+                    break;
+                }
+                assert_eq!(3, fields);
+                for _ in 0..fields {
+                    at += 4 + int32(at).max(0) as usize;
+                }
+                rows += 1;
+            }
+            assert_eq!(100, rows);
+            assert_eq!(out.len(), at);
+
+            Spi::run("CREATE TABLE copy_b (id int, note text)");
+            Spi::run(r#"INSERT INTO copy_b VALUES (1, 'he said "hi"; twice'), (2, NULL)"#);
+            let mut out = Vec::new();
+            let options = CopyOptions::csv().delimiter(';').null("-").header(true);
+            let stats = c
+                .checked_copy_out(
+                    CopySource::Query("SELECT * FROM copy_b ORDER BY id"),
+                    &options,
+                    &mut out,
+                )
+                .unwrap();
+            assert_eq!(2, stats.rows);
+            assert_eq!(
+                "id;note\n1;\"he said \"\"hi\"\"; twice\"\n2;-\n",
+                String::from_utf8(out).unwrap()
+            );
+
+            let mut out = Vec::new();
+            let result = c.checked_copy_out(
+                CopySource::Query("SELECT i, 1 / (i - 500) FROM generate_series(1, 1000) i"),
+                &CopyOptions::text(),
+                &mut out,
+            );
+            match result {
+                Err(CopyError::PartialOutput { error, bytes }) => {
+                    assert_eq!("22012", error.sqlstate().unwrap().as_str());
+                    assert_eq!(out.len() as u64, bytes);
+                    assert_eq!(499, out.iter().filter(|byte| **byte == b'\n').count());
+                }
+                _ => panic!("expected a partial output"),
+            }
+            assert!(matches!(
+                c.checked_copy_out_binary(
+                    CopySource::Query("SELECT 1) TO '/tmp/copy_out'; COPY (SELECT 1"),
+                    &mut Vec::new()
+                ),
+                Err(CopyError::InvalidQuery)
+            ));
+            assert!(subtxn::state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_execute_many_adaptive() {
+        use batch::*;
+        use std::ops::ControlFlow;
+        use std::time::Duration;
+        Spi::run("CREATE TABLE many_a (i int PRIMARY KEY)");
+        Spi::run("CREATE TABLE many_b (i int PRIMARY KEY CHECK (i <> 50))");
+        let int4 = PgBuiltInOids::INT4OID.oid();
+        let items: Vec<_> = (1..=1200)
+            .map(|i: i32| vec![(int4, i.into_datum())])
+            .collect();
+        let adaptive = BatchPolicy::Adaptive {
+            target_batch_duration: Duration::from_millis(20),
+            min: 1,
+            max: 200,
+        };
+        Spi::execute(|mut c| {
+            // Items 401 to 600 are slow
+            let report = checked_execute_many(
+                &mut c,
+                "INSERT INTO many_a SELECT $1 \
+                 FROM pg_sleep(CASE WHEN $1 BETWEEN 401 AND 600 THEN 0.002 ELSE 0 END)",
+                &items,
+                adaptive,
+                ErrorIsolation::PerBatch,
+            )
+            .unwrap();
+            assert_eq!(1200, report.executed);
+            let mut position = 0;
+            let sizes: Vec<_> = report
+                .batches
+                .iter()
+                .map(|batch| {
+                    position += batch.size;
+                    (position - batch.size, batch.size)
+                })
+                .collect();
+            assert_eq!(1200, position);
+            let largest = |range: std::ops::Range<usize>| {
+                sizes
+                    .iter()
+                    .filter(|(start, _)| range.contains(start))
+                    .map(|(_, size)| *size)
+                    .max()
+                    .unwrap()
+            };
+            let smallest_slow = sizes
+                .iter()
+                .filter(|(start, _)| (450..550).contains(start))
+                .map(|(_, size)| *size)
+                .min()
+                .unwrap();
+            assert!(smallest_slow < largest(0..400), "{:?}", sizes);
+            assert!(smallest_slow < largest(600..1200), "{:?}", sizes);
+            assert_eq!(
+                Some(1200),
+                Spi::get_one::<i64>("SELECT count(DISTINCT i) FROM many_a")
+            );
+
+            // The failing item is retried alone before its failure is returned
+            let result = checked_execute_many(
+                &mut c,
+                "INSERT INTO many_b VALUES ($1)",
+                &items[..100],
+                adaptive,
+                ErrorIsolation::PerBatch,
+            );
+            match result {
+                Err(ExecuteManyError::Failed { index, report, .. }) => {
+                    assert_eq!(49, index);
+                    assert_eq!(49, report.executed);
+                    assert_eq!(
+                        Some(&BatchInfo {
+                            size: 1,
+                            duration: report.batches.last().unwrap().duration,
+                            outcome: BatchOutcome::RolledBack
+                        }),
+                        report.batches.last()
+                    );
+                }
+                _ => panic!("expected item 49 to fail"),
+            }
+            assert_eq!(Some(49), Spi::get_one::<i64>("SELECT count(*) FROM many_b"));
+            let report = checked_execute_many(
+                &mut c,
+                "INSERT INTO many_b VALUES ($1) ON CONFLICT DO NOTHING",
+                &items[..100],
+                BatchPolicy::Fixed(30),
+                ErrorIsolation::PerRow,
+            )
+            .unwrap();
+            assert_eq!(
+                vec![49],
+                report
+                    .failed
+                    .iter()
+                    .map(|(index, _)| *index)
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(4, report.batches.len());
+            assert_eq!(Some(99), Spi::get_one::<i64>("SELECT count(*) FROM many_b"));
+
+            let summary =
+                purge::checked_delete_with_policy(&mut c, "many_a", "true", None, adaptive, |_| {
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            assert_eq!(1200, summary.deleted);
+            assert_eq!(
+                summary
+                    .history
+                    .iter()
+                    .map(|batch| batch.size as u64)
+                    .sum::<u64>(),
+                summary.deleted + summary.history.last().unwrap().size as u64
+            );
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_preflight() {
+        use ddl::*;
+        use error::Error;
+        use upsert::*;
+        use validate::*;
+        Spi::execute(|mut c| {
+            // 35 two-byte characters
+            let long = "é".repeat(35);
+            assert_eq!(
+                Err(IdentError::WouldTruncate {
+                    ident: long.clone(),
+                    byte_len: 70,
+                }),
+                identifier(&long)
+            );
+            assert_eq!(
+                "é".repeat(31),
+                identifier(&"é".repeat(31)).unwrap().as_str()
+            );
+            assert!(matches!(
+                CheckedCreate::table(&format!("public.{}", long), "id int").execute(&mut c),
+                Err(Error::Preflight(PreflightError::Identifier(
+                    IdentError::WouldTruncate { byte_len: 70, .. }
+                )))
+            ));
+
+            // One parameter per row, so 70000 rows take two commands
+            c.update("CREATE TABLE preflight (id int PRIMARY KEY)", None, None);
+            let rows = (1..=70000)
+                .map(|id: i32| vec![(PgBuiltInOids::INT4OID.oid(), id.into_datum())])
+                .collect();
+            let stats = checked_upsert(&mut c, "preflight", &["id"], &["id"], rows, &[]).unwrap();
+            assert_eq!(
+                UpsertStats {
+                    inserted: 70000,
+                    updated: 0,
+                    batches: 2,
+                },
+                stats
+            );
+            assert_eq!(
+                Err(TooManyParams {
+                    count: 70000,
+                    max: MAX_PARAMETERS,
+                }),
+                param_count(70000)
+            );
+
+            assert_eq!(
+                Err(LiteralError::Nul {
+                    literal: "a\0b".to_string(),
+                    offset: 1,
+                }),
+                literal("a\0b")
+            );
+            let err =
+                checked_comment_on(&mut c, ObjectKind::Table, "preflight", "a\0b").unwrap_err();
+            assert!(matches!(
+                err,
+                Error::Preflight(PreflightError::Literal(LiteralError::Nul { offset: 1, .. }))
+            ));
+            assert_eq!(
+                None,
+                get_comment(&c, ObjectKind::Table, "preflight").unwrap()
+            );
+        });
+    }
+
+    #[pg_test]
+    fn test_metrics() {
+        use std::collections::HashMap;
+        // Not executed by checked commands, so not counted
+        let read = |c: &SpiClient| -> HashMap<String, i64> {
+            c.select("SELECT name, value FROM spiext_metrics()", None, None)
+                .map(|row| {
+                    (
+                        row.by_ordinal(1).unwrap().value().unwrap(),
+                        row.by_ordinal(2).unwrap().value().unwrap(),
+                    )
+                })
+                .collect()
+        };
+        Spi::execute(|c| {
+            metrics::reset();
+            #[cfg(feature = "full")]
+            config::enable_plan_cache(8);
+            (&c).checked_select("SELECT 1", None, None).unwrap();
+            (&c).checked_select("SELECT 1", None, None).unwrap();
+            #[cfg(feature = "full")]
+            config::disable_plan_cache();
+            (&c).checked_select("SELECT 1 / 0", None, None).unwrap_err();
+            SpiClient.sub_transaction(|xact| {
+                (&mut SpiClient)
+                    .checked_update("CREATE TEMP TABLE metrics_t (v int)", None, None)
+                    .unwrap();
+                xact.commit();
+            });
+
+            let metrics = read(&c);
+            let plan_cache = if cfg!(feature = "full") { 1 } else { 0 };
+            for (name, value) in [
+                ("checked_select_calls", 3),
+                ("checked_update_calls", 1),
+                ("errors_transient", 0),
+                ("errors_resource_exhaustion", 0),
+                ("errors_permanent", 1),
+                ("subtransactions_begun", 5),
+                ("subtransactions_committed", 4),
+                ("subtransactions_rolled_back", 1),
+                ("subtransaction_peak_depth", 2),
+                ("plan_cache_hits", plan_cache),
+                ("plan_cache_misses", plan_cache),
+            ] {
+                assert_eq!(Some(&value), metrics.get(name), "{}", name);
+            }
+            assert!(metrics.contains_key("checked_execution_us"));
+
+            metrics::reset();
+            let metrics = read(&c);
+            assert_eq!(12, metrics.len());
+            assert!(metrics.values().all(|value| *value == 0));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_names() {
+        use names::*;
+        use std::collections::HashSet;
+        Spi::execute(|_| {
+            SpiClient.sub_transaction(|xact| {
+                let mut generator = NameGenerator::for_subtxn(&xact);
+                let mut seen = HashSet::new();
+                for _ in 0..10000 {
+                    let name = generator.reserve("tbl").into_string();
+                    assert!(name.len() <= 63, "{}", name);
+                    assert!(name.starts_with("spiext_tbl_"), "{}", name);
+                    assert!(was_generated_by_us(&name), "{}", name);
+                    assert!(seen.insert(name));
+                }
+
+                // Another generator in the same sub-transaction doesn't reuse names
+                let mut long = NameGenerator::for_subtxn(&xact).with_prefix(&"é".repeat(40));
+                let name = long.reserve("scratch").into_string();
+                assert!(name.len() <= 63, "{}", name);
+                assert!(name.starts_with('é'));
+                assert!(name.contains("_scratch_"));
+                assert!(was_generated_by_us(&name));
+                assert!(!seen.contains(&name));
+                // Its suffix is whole: the same pid, transaction and sub-transaction as with a short prefix
+                let short = generator.reserve("scratch").into_string();
+                let suffix = |name: &str| name.rsplitn(6, '_').skip(2).take(3).collect::<Vec<_>>();
+                assert_eq!(suffix(&short), suffix(&name));
+
+                assert!(!was_generated_by_us("orders"));
+                assert!(!was_generated_by_us("spiext_tbl_1_x2_3_4"));
+                assert!(!was_generated_by_us("spiext_tbl_1_x2_3_4_0000000"));
+                let mut tampered = short.clone().into_bytes();
+                let i = short.find("_scratch_").unwrap() + "_scratch_".len();
+                tampered[i] = if tampered[i] == b'1' { b'2' } else { b'1' };
+                assert!(!was_generated_by_us(&String::from_utf8(tampered).unwrap()));
+                assert!(!was_generated_by_us(&short.to_uppercase()));
+                xact.commit();
+            });
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_exact_values() {
+        use checked::*;
+        use exact::*;
+        use row::StrictCommands;
+        use std::time::Duration;
+        Spi::execute(|mut c| {
+            // 30 significant digits round-trip as text
+            let digits = "123456789012345.678901234567890";
+            let numeric = c
+                .checked_get_one_fast::<PgNumeric>(&format!("SELECT '{}'::numeric", digits), None)
+                .unwrap()
+                .flatten()
+                .unwrap();
+            assert_eq!(digits, numeric.as_str());
+            assert_eq!(
+                Ok((123456789012345678901234567890, 15)),
+                numeric.to_mantissa_scale()
+            );
+            assert!((numeric.lossy() - 123456789012345.6).abs() < 0.1);
+            assert!(numeric.to_decimal().is_err());
+            assert_eq!(
+                Some(true),
+                c.checked_get_one_fast::<bool>(
+                    &format!("SELECT $1 = '{}'::numeric", digits),
+                    Some(vec![(
+                        PgBuiltInOids::NUMERICOID.oid(),
+                        numeric.into_datum()
+                    )]),
+                )
+                .unwrap()
+                .flatten()
+            );
+            let small = c
+                .checked_get_one_fast::<PgNumeric>("SELECT -12.340::numeric", None)
+                .unwrap()
+                .flatten()
+                .unwrap();
+            assert_eq!(Ok((-12340, 3)), small.to_mantissa_scale());
+            assert_eq!("-12.340", small.to_decimal().unwrap().to_string());
+
+            // numeric(60,0) doesn't fit in an i128
+            let huge = c
+                .checked_get_one_fast::<PgNumeric>("SELECT repeat('9', 60)::numeric(60,0)", None)
+                .unwrap()
+                .flatten()
+                .unwrap();
+            assert_eq!(
+                Err(NumericOverflow {
+                    value: "9".repeat(60)
+                }),
+                huge.to_mantissa_scale()
+            );
+            let nan = c
+                .checked_get_one_fast::<PgNumeric>("SELECT 'NaN'::numeric", None)
+                .unwrap()
+                .flatten()
+                .unwrap();
+            assert!(!nan.is_finite() && nan.to_mantissa_scale().is_err() && nan.lossy().is_nan());
+
+            let interval = |text: &str| {
+                c.checked_get_one_fast::<PgInterval>(&format!("SELECT '{}'::interval", text), None)
+                    .unwrap()
+                    .flatten()
+                    .unwrap()
+            };
+            let month = interval("1 mon 2 days");
+            assert_eq!(
+                PgInterval {
+                    months: 1,
+                    days: 2,
+                    micros: 0,
+                },
+                month
+            );
+            assert_eq!(
+                Err(NotDurationConvertible::Months(month)),
+                month.to_duration()
+            );
+            assert_eq!(32.0 * 86400.0, month.lossy());
+            assert_eq!(
+                Ok(Duration::from_secs(3 * 86400 + 4 * 3600 + 5 * 60 + 6)),
+                interval("3 days 04:05:06").to_duration()
+            );
+            assert!(matches!(
+                interval("-1 second").to_duration(),
+                Err(NotDurationConvertible::Negative(_))
+            ));
+            assert_eq!(
+                Some(true),
+                c.checked_get_one_fast::<bool>(
+                    "SELECT $1 = interval '90 seconds'",
+                    Some(vec![(
+                        PgBuiltInOids::INTERVALOID.oid(),
+                        PgInterval::from(Duration::from_secs(90)).into_datum(),
+                    )]),
+                )
+                .unwrap()
+                .flatten()
+            );
+
+            // Typed rows
+            c.update("SET LOCAL lc_monetary = 'C'", None, None);
+            let (money, numeric) = c
+                .checked_select_strict::<(PgMoney, PgNumeric)>(
+                    "SELECT 1234.56::money, 0.1::numeric",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .remove(0);
+            assert_eq!(123456, money.cents());
+            assert_eq!(1234.56, money.lossy());
+            assert_eq!("0.1", numeric.as_str());
+        });
+    }
+
+    #[pg_test]
+    fn test_recovery_scope() {
+        use std::cell::RefCell;
+        use std::panic::AssertUnwindSafe;
+        use subtxn::{recovery_scope, state_is_clean};
+
+        Spi::execute(|c| {
+            let entry = unsafe { pg_sys::GetCurrentSubTransactionId() };
+            let kept = RefCell::new(None);
+            let kept_ref = AssertUnwindSafe(&kept);
+            let recovered = recovery_scope(move || {
+                // The runtime keeps the handles, so they aren't dropped while unwinding
+                let xact = SpiClient.sub_transaction(|xact| xact.sub_transaction(|xact| xact));
+                kept_ref.replace(Some(xact));
+                panic!("runtime error")
+            })
+            .unwrap_err();
+            assert_eq!(2, recovered.rolled_back_levels);
+            assert_eq!(
+                Some(&"runtime error"),
+                recovered.payload.downcast_ref::<&str>()
+            );
+            assert_eq!(entry, unsafe { pg_sys::GetCurrentSubTransactionId() });
+            assert!(state_is_clean());
+
+            // The poisoned handles end nothing when dropped
+            drop(kept.take());
+            assert_eq!(entry, unsafe { pg_sys::GetCurrentSubTransactionId() });
+            assert!(c.checked_select("SELECT 1", None, None).is_ok());
+
+            // Nested scopes recover to their own entry state
+            let outer = recovery_scope(|| {
+                SpiClient.sub_transaction(|xact| {
+                    let inner = recovery_scope(|| {
+                        std::mem::forget(SpiClient.sub_transaction(|xact| xact));
+                        panic!("inner")
+                    });
+                    assert_eq!(1, inner.unwrap_err().rolled_back_levels);
+                    assert_eq!(xact.id(), unsafe { pg_sys::GetCurrentSubTransactionId() });
+                    std::mem::forget(xact);
+                    panic!("outer")
+                })
+            });
+            assert_eq!(1, outer.unwrap_err().rolled_back_levels);
+            assert_eq!(entry, unsafe { pg_sys::GetCurrentSubTransactionId() });
+            assert!(state_is_clean());
+
+            // Ending a poisoned handle explicitly reports that it was rolled back
+            let kept = RefCell::new(Vec::new());
+            let kept_ref = AssertUnwindSafe(&kept);
+            recovery_scope(move || {
+                let xact = SpiClient.sub_transaction(|xact| xact);
+                let nested = SpiClient.sub_transaction(|xact| xact);
+                kept_ref.borrow_mut().extend([xact, nested]);
+                panic!("runtime error")
+            })
+            .unwrap_err();
+            let (nested, xact) = {
+                let mut kept = kept.borrow_mut();
+                (kept.pop().unwrap(), kept.pop().unwrap())
+            };
+            assert!(matches!(
+                nested.checked_commit(),
+                (_, Err(deferred::CommitError::Poisoned))
+            ));
+            assert!(std::panic::catch_unwind(AssertUnwindSafe(|| xact.rollback())).is_err());
+            assert_eq!(entry, unsafe { pg_sys::GetCurrentSubTransactionId() });
+            assert!(state_is_clean());
+
+            // Postgres errors are raised again once the sub-transactions left open are rolled back
+            SpiClient.sub_transaction(|mut xact| {
+                let raised = xact
+                    .shielded(|_| {
+                        recovery_scope(|| {
+                            std::mem::forget(SpiClient.sub_transaction(|xact| xact));
+                            pgx::error!("raised in the scope")
+                        })
+                    })
+                    .unwrap_err();
+                assert_eq!(Some("raised in the scope"), raised.message());
+                assert_eq!(xact.id(), unsafe { pg_sys::GetCurrentSubTransactionId() });
+                xact.rollback();
+            });
+            assert_eq!(entry, unsafe { pg_sys::GetCurrentSubTransactionId() });
+            assert!(state_is_clean());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_unlogged_promote() {
+        use std::time::Duration;
+        use unlogged::*;
+
+        Spi::execute(|mut c| {
+            c.update("CREATE UNLOGGED TABLE staged (id int)", None, None);
+            c.update(
+                "INSERT INTO staged SELECT generate_series(1, 100)",
+                None,
+                None,
+            );
+            assert!(is_unlogged("staged").unwrap());
+
+            let opts = PromoteOpts {
+                lock_timeout: Some(Duration::from_secs(1)),
+            };
+            let report = promote(&mut c, "staged", opts).unwrap();
+            assert!(report.bytes_rewritten > 0);
+            let persistence = c
+                .select(
+                    "SELECT relpersistence::text FROM pg_class WHERE oid = 'staged'::regclass",
+                    None,
+                    None,
+                )
+                .first()
+                .get_one::<String>();
+            assert_eq!(Some("p".to_string()), persistence);
+            assert!(!is_unlogged("staged").unwrap());
+            // The lock timeout was only set for the command
+            let lock_timeout = c
+                .select("SHOW lock_timeout", None, None)
+                .first()
+                .get_one::<String>();
+            assert_eq!(Some("0".to_string()), lock_timeout);
+
+            match promote(&mut c, "staged", opts) {
+                Err(PromoteError::NotUnlogged(table)) => assert_eq!("staged", table),
+                other => panic!("expected NotUnlogged, got {:?}", other),
+            }
+
+            assert!(matches!(
+                demote(&mut c, "staged", opts, false),
+                Err(PromoteError::DataLossNotAcknowledged)
+            ));
+            assert!(!is_unlogged("staged").unwrap());
+            demote(&mut c, "staged", opts, true).unwrap();
+            assert!(is_unlogged("staged").unwrap());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_yield_points() {
+        use bgworker::*;
+
+        // In a regular backend, yield points do nothing
+        assert_eq!(Ok(()), yield_point());
+        assert!(!shutdown_requested());
+
+        Spi::execute(|mut c| {
+            let worker = SimulatedWorker::start();
+            let mut iterations = 0;
+            let stopped_at = loop {
+                iterations += 1;
+                if iterations == 3 {
+                    worker.sigterm();
+                }
+                if yield_point().is_err() {
+                    break iterations;
+                }
+                c.update("SELECT 1", None, None);
+            };
+            assert_eq!(3, stopped_at);
+            // Shutdown stays requested
+            assert_eq!(Err(ShutdownRequested), yield_point());
+            drop(worker);
+
+            c.update("CREATE TABLE yielding (id int)", None, None);
+            let items: Vec<_> = (1..=4)
+                .map(|id| vec![(PgBuiltInOids::INT4OID.oid(), id.into_datum())])
+                .collect();
+            let worker = SimulatedWorker::start();
+            let yield_points = with_yield_points();
+            worker.sigterm();
+            match batch::checked_execute_many(
+                &mut c,
+                "INSERT INTO yielding VALUES ($1)",
+                &items,
+                batch::BatchPolicy::Fixed(2),
+                batch::ErrorIsolation::PerBatch,
+            ) {
+                Err(batch::ExecuteManyError::ShutdownRequested { report }) => {
+                    assert_eq!(0, report.executed)
+                }
+                other => panic!("expected ShutdownRequested, got {:?}", other),
+            }
+            drop(yield_points);
+            drop(worker);
+
+            // A SIGTERM read by `shutdown_requested` is still reported by yield points, and the other way around
+            let worker = SimulatedWorker::start();
+            assert!(!shutdown_requested());
+            worker.sigterm();
+            assert!(shutdown_requested());
+            assert_eq!(Err(ShutdownRequested), yield_point());
+            assert!(shutdown_requested());
+            drop(worker);
+            let worker = SimulatedWorker::start();
+            worker.sigterm();
+            assert_eq!(Err(ShutdownRequested), yield_point());
+            assert!(shutdown_requested());
+            drop(worker);
+
+            // A SIGHUP reloads the configuration files once, at the next yield point
+            let worker = SimulatedWorker::start();
+            assert_eq!(Ok(()), yield_point());
+            assert_eq!(0, worker.reloads());
+            worker.sighup();
+            assert!(!shutdown_requested());
+            assert_eq!(Ok(()), yield_point());
+            assert_eq!(1, worker.reloads());
+            assert_eq!(Ok(()), yield_point());
+            assert_eq!(1, worker.reloads());
+        });
+    }
+
+    #[pg_test]
+    fn test_timeline() {
+        use pgx_contrib_spiext::error::OwnedPostgresError;
+        use timeline::*;
+
+        let describe = |events: &[Event]| -> Vec<String> {
+            events
+                .iter()
+                .map(|event| match event.kind {
+                    EventKind::SubTransactionBegin { .. } => "begin".to_string(),
+                    EventKind::SubTransactionCommit { .. } => "commit".to_string(),
+                    EventKind::SubTransactionRollback { .. } => "rollback".to_string(),
+                    EventKind::StatementStart { query } => format!("start {}", query),
+                    EventKind::StatementFinish { succeeded, .. } => format!("finish {}", succeeded),
+                    EventKind::ErrorCaught { sqlstate } => format!("error {}", sqlstate.as_str()),
+                    EventKind::Retry { attempt } => format!("retry {}", attempt),
+                    EventKind::SavepointCycle { released, .. } => format!("savepoint {}", released),
+                })
+                .collect()
+        };
+
+        Spi::execute(|mut c| {
+            timeline::enable(16);
+            (&c).checked_select("SELECT 1", None, None).unwrap();
+            let err = (&mut c)
+                .checked_update("SELECT 1 / 0", None, None)
+                .unwrap_err();
+            let events = snapshot();
+            assert_eq!(
+                vec![
+                    "begin",
+                    "start SELECT 1",
+                    "finish true",
+                    "commit",
+                    "begin",
+                    "start SELECT 1 / 0",
+                    "rollback",
+                    "finish false",
+                    "error 22012"
+                ],
+                describe(&events)
+            );
+            assert_eq!(
+                (1..=9).collect::<Vec<u64>>(),
+                events.iter().map(|event| event.seq).collect::<Vec<_>>()
+            );
+            match (events[0].kind, events[3].kind) {
+                (
+                    EventKind::SubTransactionBegin { id: begun, .. },
+                    EventKind::SubTransactionCommit { id: committed, .. },
+                ) => assert_eq!(begun, committed),
+                other => panic!("unexpected events {:?}", other),
+            }
+
+            // The rendering lands in the detail for the server log of the rethrown error
+            let mut owned = OwnedPostgresError::from(err);
+            attach_to(&mut owned);
+            let detail_log = owned.detail_log.clone().unwrap();
+            assert!(detail_log.starts_with("timeline:\n#1 "));
+            assert!(detail_log.ends_with("caught error 22012"));
+            (&c).sub_transaction(|mut xact| {
+                let rethrown = xact.shielded(|_| owned.rethrow()).unwrap_err();
+                assert_eq!(Some("division by zero"), rethrown.message());
+                assert_eq!(Some(detail_log.as_str()), rethrown.detail_log());
+                xact.rollback();
+            });
+
+            // Sub-transactions begun by other means are recorded when they end
+            timeline::enable(16);
+            (&mut c)
+                .checked_update(
+                    "DO $$ BEGIN \
+                     BEGIN PERFORM 1; EXCEPTION WHEN others THEN NULL; END; \
+                     BEGIN PERFORM 1 / 0; EXCEPTION WHEN division_by_zero THEN NULL; END; \
+                     END $$",
+                    None,
+                    None,
+                )
+                .unwrap();
+            let events = snapshot();
+            assert_eq!(
+                vec!["savepoint true", "savepoint false", "finish true", "commit"],
+                describe(&events[2..])
+            );
+            match (events[0].kind, events[2].kind) {
+                (
+                    EventKind::SubTransactionBegin { depth: outer, .. },
+                    EventKind::SavepointCycle { depth, .. },
+                ) => assert_eq!(outer + 1, depth),
+                other => panic!("unexpected events {:?}", other),
+            }
+
+            // Once full, the ring keeps the last events
+            timeline::enable(3);
+            let long = format!("SELECT '{}'", "x".repeat(100));
+            (&c).checked_select("SELECT 1", None, None).unwrap();
+            (&c).checked_select(&long, None, None).unwrap();
+            let events = snapshot();
+            assert_eq!(
+                vec![6, 7, 8],
+                events.iter().map(|event| event.seq).collect::<Vec<_>>()
+            );
+            match events[0].kind {
+                EventKind::StatementStart { query } => {
+                    assert!(query.is_truncated());
+                    assert_eq!(&long[..STATEMENT_TEXT_LEN], query.as_str());
+                }
+                other => panic!("unexpected event {:?}", other),
+            }
+            assert_eq!(vec!["finish true", "commit"], describe(&events[1..]));
+
+            timeline::disable();
+            assert!(snapshot().is_empty());
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_merge_sorted() {
+        use cursor::{CheckedCursor, SelectOutcome};
+        use merge::*;
+        use std::ops::ControlFlow;
+        Spi::execute(|c| {
+            let left =
+                "SELECT g / 2 AS k, 'l' AS src, g FROM generate_series(1, 10000) g ORDER BY k, g";
+            let right =
+                "SELECT g / 3 AS k, 'r' AS src, g FROM generate_series(1, 10000) g ORDER BY k, g";
+            let open = |query: &str| CheckedCursor::open(&c, query, None).unwrap();
+
+            let mut merged = vec![];
+            let stats = sorted(
+                open(left),
+                open(right),
+                ("k", "k"),
+                SortOrder::Ascending,
+                |row| {
+                    merged.push((
+                        row.row.get::<i32>("k").unwrap(),
+                        row.row.get::<String>("src").unwrap(),
+                        row.row.get::<i32>("g").unwrap(),
+                    ));
+                    ControlFlow::Continue(())
+                },
+            )
+            .unwrap();
+            assert_eq!(stats.left_rows, 10000);
+            assert_eq!(stats.right_rows, 10000);
+            assert_eq!(stats.outcome, SelectOutcome::Completed);
+            let expected: Vec<_> = c
+                .select(
+                    &format!(
+                        "SELECT * FROM ({}) l UNION ALL SELECT * FROM ({}) r ORDER BY k, src, g",
+                        left, right
+                    ),
+                    None,
+                    None,
+                )
+                .map(|row| {
+                    (
+                        row.by_ordinal(1).unwrap().value::<i32>().unwrap(),
+                        row.by_ordinal(2).unwrap().value::<String>().unwrap(),
+                        row.by_ordinal(3).unwrap().value::<i32>().unwrap(),
+                    )
+                })
+                .collect();
+            assert_eq!(merged, expected);
+
+            let open_cursors = || {
+                c.select("SELECT count(*) FROM pg_cursors", None, None)
+                    .first()
+                    .get_one::<i64>()
+            };
+            assert_eq!(open_cursors(), Some(0));
+
+            // Stopping early closes both cursors
+            let mut seen = 0;
+            let stats = sorted(
+                open(left),
+                open(right),
+                ("k", "k"),
+                SortOrder::Ascending,
+                |_| {
+                    seen += 1;
+                    if seen == 5 {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    }
+                },
+            )
+            .unwrap();
+            assert_eq!(stats.left_rows + stats.right_rows, 5);
+            assert_eq!(stats.outcome, SelectOutcome::Cancelled);
+            assert_eq!(open_cursors(), Some(0));
+
+            // Out-of-order input is reported with its side and row number, after merging the rows of the batches before
+            // it (both sides' first 1000) but none of its own
+            let unsorted =
+                "SELECT CASE WHEN g = 1500 THEN 0 ELSE g END AS k FROM generate_series(1, 3000) g";
+            let mut seen = 0;
+            let err = sorted(
+                open("SELECT g AS k FROM generate_series(1, 3000) g"),
+                open(unsorted),
+                ("k", "k"),
+                SortOrder::Ascending,
+                |_| {
+                    seen += 1;
+                    ControlFlow::Continue(())
+                },
+            )
+            .unwrap_err();
+            assert!(matches!(
+                err,
+                MergeError::InputNotSorted {
+                    side: Side::Right,
+                    row: 1500
+                }
+            ));
+            assert_eq!(seen, 2000);
+            assert_eq!(open_cursors(), Some(0));
+
+            // Descending order
+            let err = sorted(
+                open("SELECT 3 - g AS k FROM generate_series(1, 3) g"),
+                open("SELECT g AS k FROM generate_series(1, 3) g"),
+                ("k", "k"),
+                SortOrder::Descending,
+                |_| ControlFlow::Continue(()),
+            )
+            .unwrap_err();
+            assert!(matches!(
+                err,
+                MergeError::InputNotSorted {
+                    side: Side::Right,
+                    row: 2
+                }
+            ));
+        });
+    }
+
+    #[cfg(feature = "full")]
+    #[pg_test]
+    fn test_type_ddl() {
+        use ddl::*;
+        use std::time::Duration;
+        Spi::execute(|mut c| {
+            checked_create_domain(
+                &mut c,
+                "ddl_amount",
+                "numeric(10,2)",
+                &["CHECK (VALUE > 0)"],
+                Some("1"),
+            )
+            .unwrap();
+            assert_eq!(
+                c.select("SELECT 2.5::ddl_amount::text", None, None)
+                    .first()
+                    .get_one::<String>(),
+                Some("2.50".to_string())
+            );
+            assert!((&c)
+                .checked_select("SELECT (-1)::ddl_amount", None, None)
+                .is_err());
+            assert!(matches!(
+                checked_create_domain(&mut c, "ddl_amount", "int", &[], None),
+                Err(TypeDdlError::AlreadyExists(_))
+            ));
+            assert!(matches!(
+                checked_create_domain(&mut c, "ddl_other", "no_such_type", &[], None),
+                Err(TypeDdlError::InvalidType { .. })
+            ));
+
+            checked_create_composite(
+                &mut c,
+                "ddl_item",
+                &[("name", "text"), ("price", "ddl_amount")],
+            )
+            .unwrap();
+            checked_add_composite_field(
+                &mut c,
+                "ddl_item",
+                "tags",
+                "text[]",
+                AlterTypeOpts {
+                    lock_timeout: Some(Duration::from_secs(1)),
+                    cascade: false,
+                },
+            )
+            .unwrap();
+            assert!(matches!(
+                checked_add_composite_field(&mut c, "ddl_item", "tags", "text", Default::default()),
+                Err(TypeDdlError::AlreadyExists(_))
+            ));
+            assert_eq!(
+                c.select("SELECT (ROW('a', 1, '{x}')::ddl_item).tags[1]", None, None)
+                    .first()
+                    .get_one::<String>(),
+                Some("x".to_string())
+            );
+
+            // Objects using the types are listed without dropping anything
+            c.update("CREATE TABLE ddl_items OF ddl_item", None, None);
+            let objects =
+                match checked_drop_type(&mut c, "ddl_item", DropBehavior::ListDependentsFirst) {
+                    Err(TypeDdlError::WouldDrop { objects }) => objects,
+                    other => panic!("unexpected result {:?}", other),
+                };
+            let dependents = |objects: &[DependentObject]| {
+                objects
+                    .iter()
+                    .map(|object| (object.kind.clone(), object.identity.clone()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                dependents(&objects),
+                vec![("table".to_string(), "public.ddl_items".to_string())]
+            );
+            assert_eq!(objects[0].description, "table ddl_items");
+            match checked_drop_type(&mut c, "ddl_amount", DropBehavior::ListDependentsFirst) {
+                Err(TypeDdlError::WouldDrop { objects }) => assert_eq!(
+                    dependents(&objects),
+                    vec![(
+                        "composite type column".to_string(),
+                        "public.ddl_item.price".to_string()
+                    )]
+                ),
+                other => panic!("unexpected result {:?}", other),
+            }
+            assert!(matches!(
+                checked_drop_type(&mut c, "ddl_item", DropBehavior::Restrict),
+                Err(TypeDdlError::DependentObjectsExist(_))
+            ));
+            assert_eq!(
+                c.select("SELECT to_regclass('ddl_items') IS NOT NULL", None, None)
+                    .first()
+                    .get_one::<bool>(),
+                Some(true)
+            );
+
+            // Dropping the composite type drops the table along with it
+            let report = checked_drop_type(&mut c, "ddl_item", DropBehavior::Cascade).unwrap();
+            assert_eq!(report.dropped, objects);
+            assert_eq!(
+                c.select(
+                    "SELECT to_regtype('ddl_item') IS NULL AND to_regclass('ddl_items') IS NULL",
+                    None,
+                    None
+                )
+                .first()
+                .get_one::<bool>(),
+                Some(true)
+            );
+            assert!(matches!(
+                checked_drop_type(&mut c, "ddl_item", DropBehavior::Cascade),
+                Err(TypeDdlError::DoesNotExist(_))
+            ));
+            // Nothing depends on the domain anymore
+            let report =
+                checked_drop_type(&mut c, "ddl_amount", DropBehavior::ListDependentsFirst).unwrap();
+            assert!(report.dropped.is_empty());
+        });
+    }
 }
 
 #[cfg(test)]
@@ -164,6 +6845,14 @@ pub mod pg_test {
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
         // return any postgresql.conf settings that are required for your tests
-        vec![]
+        if cfg!(any(feature = "pg14", feature = "pg15")) {
+            vec![
+                "shared_preload_libraries = 'pg_stat_statements'",
+                "compute_query_id = on",
+                "pg_stat_statements.track = all",
+            ]
+        } else {
+            vec![]
+        }
     }
 }